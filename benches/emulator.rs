@@ -0,0 +1,230 @@
+//! Benchmarks for parsing output, reading back a screen, scrolling, reflowing and drawing a
+//! frame, run with `cargo bench`. Pass names to only run the benchmarks containing them, e.g.
+//! `cargo bench -- parse`.
+//!
+//! Results are printed at the end, after any warnings the emulator logged while running.
+//!
+//! The corpus is real output: `ls --color -la` over a couple of large directories, and a vim
+//! session with syntax highlighting paging through a source file
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use eframe::egui;
+use termie::{
+    config::Config,
+    gui::TerminalWidget,
+    terminal_emulator::{ReadResponse, SnapshotItem, TermIo, TermIoErr, TerminalEmulator},
+};
+
+const LS_COLOR: &[u8] = include_bytes!("corpus/ls_color.txt");
+const VIM: &[u8] = include_bytes!("corpus/vim.txt");
+
+/// How long each benchmark is run for after warming up
+const MEASUREMENT_TIME: Duration = Duration::from_secs(3);
+const WARM_UP_ITERATIONS: usize = 3;
+const MIN_ITERATIONS: usize = 10;
+
+/// Hands out `data` in the chunk sizes a pty would, and swallows replies to queries
+struct CorpusIo {
+    data: &'static [u8],
+}
+
+impl TermIo for CorpusIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
+        if self.data.is_empty() {
+            return Ok(ReadResponse::Empty);
+        }
+        let len = buf.len().min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Ok(ReadResponse::Success(len))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
+        Ok(buf.len())
+    }
+
+    fn set_win_size(&mut self, _width: usize, _height: usize) -> Result<(), TermIoErr> {
+        Ok(())
+    }
+}
+
+fn emulator(data: &'static [u8], width: usize, height: usize) -> TerminalEmulator<CorpusIo> {
+    TerminalEmulator::with_io(CorpusIo { data }, width, height, "recordings".into())
+}
+
+/// Whether `name` is one of the benchmarks asked for on the command line
+fn selected(name: &str) -> bool {
+    let filters: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    filters.is_empty() || filters.iter().any(|filter| name.contains(filter.as_str()))
+}
+
+/// An empty terminal with `lines` short lines of scrollback above the screen
+fn long_scrollback_emulator(
+    lines: usize,
+    width: usize,
+    height: usize,
+) -> TerminalEmulator<CorpusIo> {
+    let empty = emulator(b"", width, height);
+    let mut snapshot = empty
+        .snapshot()
+        .expect("failed to snapshot terminal")
+        .into_map()
+        .expect("snapshot is not a map");
+    let mut buffer = snapshot
+        .remove("terminal_buffer")
+        .expect("snapshot has no buffer")
+        .into_map()
+        .expect("buffer is not a map");
+    let buf = (0..lines + height)
+        .flat_map(|i| [char::from(b'0' + (i % 10) as u8), '\n'])
+        .map(|c| SnapshotItem::Int(u32::from(c).into()))
+        .collect();
+    buffer.insert("buf".to_string(), SnapshotItem::Array(buf));
+    snapshot.insert("terminal_buffer".to_string(), SnapshotItem::Map(buffer));
+    TerminalEmulator::from_snapshot(SnapshotItem::Map(snapshot), CorpusIo { data: b"" })
+        .expect("failed to load snapshot")
+}
+
+/// Run `f` repeatedly and add the time per iteration to `results`. `setup` runs before every
+/// iteration and is not timed
+fn bench<T>(
+    results: &mut Vec<String>,
+    name: &str,
+    mut setup: impl FnMut() -> T,
+    mut f: impl FnMut(&mut T),
+) {
+    if !selected(name) {
+        return;
+    }
+
+    for _ in 0..WARM_UP_ITERATIONS {
+        f(&mut setup());
+    }
+
+    let mut times = Vec::new();
+    let start = Instant::now();
+    while times.len() < MIN_ITERATIONS || start.elapsed() < MEASUREMENT_TIME {
+        let mut input = setup();
+        let iteration_start = Instant::now();
+        f(&mut input);
+        times.push(iteration_start.elapsed());
+        black_box(input);
+    }
+
+    times.sort();
+    let mean = times.iter().sum::<Duration>() / times.len() as u32;
+    results.push(format!(
+        "{name:<24} median {:>12?}  mean {:>12?}  min {:>12?}  ({} iterations)",
+        times[times.len() / 2],
+        mean,
+        times[0],
+        times.len()
+    ));
+}
+
+fn main() {
+    termie::log::init();
+    let mut results = Vec::new();
+
+    // Parsing and applying the output of a program, starting from an empty terminal
+    bench(
+        &mut results,
+        "parse_ls_color",
+        || emulator(LS_COLOR, 120, 40),
+        |emulator| {
+            emulator.read();
+        },
+    );
+    bench(
+        &mut results,
+        "parse_vim",
+        || emulator(VIM, 120, 40),
+        |emulator| {
+            emulator.read();
+        },
+    );
+
+    // Everything a frame reads back from a full, styled screen
+    let mut full_screen = emulator(LS_COLOR, 120, 40);
+    full_screen.read();
+    bench(
+        &mut results,
+        "read_full_screen",
+        || (),
+        |_| {
+            black_box(full_screen.data());
+            black_box(full_screen.format_data());
+            black_box(full_screen.line_sizes());
+            for row in full_screen.rows() {
+                black_box(row.cells().count());
+            }
+        },
+    );
+
+    // Lines scrolling off a small screen into a growing scrollback
+    let lines: &'static [u8] = (0..5_000)
+        .flat_map(|i| format!("\x1b[3{}mline {i}\x1b[m\r\n", i % 8).into_bytes())
+        .collect::<Vec<_>>()
+        .leak();
+    bench(
+        &mut results,
+        "scroll_scrollback",
+        || emulator(lines, 80, 24),
+        |emulator| {
+            emulator.read();
+        },
+    );
+
+    // Rewrapping the scrollback and the screen for a narrower and then a wider window
+    bench(
+        &mut results,
+        "reflow_resize",
+        || {
+            let mut emulator = emulator(LS_COLOR, 120, 40);
+            emulator.read();
+            emulator
+        },
+        |emulator| {
+            emulator
+                .set_win_size(61, 40)
+                .expect("failed to set window size");
+            emulator
+                .set_win_size(120, 40)
+                .expect("failed to set window size");
+        },
+    );
+
+    // A frame of the GUI at the bottom of a million lines of scrollback, after the first one
+    // found where its rows are. Printing that many lines takes too long, the lines are put
+    // into a snapshot instead
+    if selected("render_long_scrollback") {
+        let mut long_scrollback = long_scrollback_emulator(1_000_000, 80, 24);
+        let ctx = egui::Context::default();
+        let mut widget = TerminalWidget::new(&ctx, &Config::default(), false);
+        let mut frame = || {
+            let input = egui::RawInput {
+                screen_rect: Some(egui::Rect::from_min_size(
+                    egui::Pos2::ZERO,
+                    egui::vec2(800.0, 600.0),
+                )),
+                ..Default::default()
+            };
+            black_box(ctx.run(input, |ctx| {
+                egui::CentralPanel::default().show(ctx, |ui| widget.show(ui, &mut long_scrollback));
+            }));
+        };
+        frame();
+        bench(&mut results, "render_long_scrollback", || (), |_| frame());
+    }
+
+    for result in results {
+        println!("{result}");
+    }
+}
@@ -1,6 +1,11 @@
 use std::{fs::OpenOptions, io::BufWriter, path::Path, process::Command};
 
 fn main() {
+    // The terminfo entry is only installed for Unix ptys
+    if std::env::var_os("CARGO_CFG_UNIX").is_none() {
+        return;
+    }
+
     let out_dir = std::env::var("OUT_DIR").expect("no out dir");
     let out_dir = Path::new(&out_dir);
     let terminfo_out_dir = out_dir.join("terminfo");
@@ -0,0 +1,1812 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use eframe::egui::{Event, Key, Modifiers};
+use regex::Regex;
+use thiserror::Error;
+use tinyjson::JsonValue;
+
+use crate::terminal_emulator::{
+    parse_hex_rgb, parse_hex_rgba, ColorScheme, EnterSequence, InvalidUtf8, Rgb, Rgba,
+    SessionLogFormat, DEFAULT_MAX_LINE_LENGTH,
+};
+
+mod config_keys {
+    pub const CONFIRM_CLOSE: &str = "confirm_close";
+    pub const SEARCH_WRAP_AROUND: &str = "search_wrap_around";
+    pub const MACROS: &str = "macros";
+    pub const COPY_TRIM_TRAILING_WHITESPACE: &str = "copy_trim_trailing_whitespace";
+    pub const CURSOR_TRAIL: &str = "cursor_trail";
+    pub const CURSOR_BLINK: &str = "cursor_blink";
+    pub const CURSOR_BLINK_PAUSE_MS: &str = "cursor_blink_pause_ms";
+    pub const HIDE_POINTER_WHILE_TYPING: &str = "hide_pointer_while_typing";
+    pub const LINK_MODIFIER: &str = "link_modifier";
+    pub const SCROLL_ON_OUTPUT: &str = "scroll_on_output";
+    pub const SCROLL_ON_INPUT: &str = "scroll_on_input";
+    pub const CLIPBOARD_COPY_COMMAND: &str = "clipboard_copy_command";
+    pub const CLIPBOARD_PASTE_COMMAND: &str = "clipboard_paste_command";
+    pub const BUILTIN_BOX_DRAWING: &str = "builtin_box_drawing";
+    pub const CURSOR_TEXT_COLOR: &str = "cursor_text_color";
+    pub const LOGIN_SHELL: &str = "login_shell";
+    pub const ENTER_SENDS: &str = "enter_sends";
+    pub const THEME: &str = "theme";
+    pub const COLOR_SCHEMES: &str = "color_schemes";
+    pub const COLOR_SCHEME: &str = "color_scheme";
+    pub const BACKGROUND_OPACITY: &str = "background_opacity";
+    pub const BACKGROUND_IMAGE: &str = "background_image";
+    pub const REMOTE_HOST_BANNER: &str = "remote_host_banner";
+    pub const REMOTE_HOST_COLORS: &str = "remote_host_colors";
+    pub const IDLE_TIMEOUT_MS: &str = "idle_timeout_ms";
+    pub const REPAINT_COALESCE_MS: &str = "repaint_coalesce_ms";
+    pub const REDUCE_MOTION: &str = "reduce_motion";
+    pub const NOTIFICATIONS: &str = "notifications";
+    pub const NOTIFICATION_INTERVAL_MS: &str = "notification_interval_ms";
+    pub const MONITOR_ACTIVITY: &str = "monitor_activity";
+    pub const MAX_LINE_LENGTH: &str = "max_line_length";
+    pub const INVALID_UTF8: &str = "invalid_utf8";
+    pub const CLEAR_BUFFER_SHORTCUT: &str = "clear_buffer_shortcut";
+    pub const SESSION_LOG_FORMAT: &str = "session_log_format";
+    pub const RESTART_SHELL_SHORTCUT: &str = "restart_shell_shortcut";
+    pub const RESTART_KEEPS_SCROLLBACK: &str = "restart_keeps_scrollback";
+    pub const LOCAL_ECHO: &str = "local_echo";
+    pub const RECORDING_SPLIT_AFTER_MS: &str = "recording_split_after_ms";
+    pub const WRAP_INDICATOR: &str = "wrap_indicator";
+    pub const WRAP_INDICATOR_GLYPH: &str = "wrap_indicator_glyph";
+    pub const PROFILES: &str = "profiles";
+    pub const SEARCH_SCROLLBACK: &str = "search_scrollback";
+}
+
+mod remote_host_color_keys {
+    pub const HOST: &str = "host";
+    pub const COLOR: &str = "color";
+}
+
+mod background_image_keys {
+    pub const PATH: &str = "path";
+    pub const OPACITY: &str = "opacity";
+    pub const SCALING: &str = "scaling";
+    pub const DARKEN: &str = "darken";
+}
+
+mod profile_keys {
+    pub const NAME: &str = "name";
+    pub const PROCESS: &str = "process";
+    pub const TITLE: &str = "title";
+    pub const FONT_SIZE: &str = "font_size";
+    pub const COLOR_SCHEME: &str = "color_scheme";
+}
+
+mod color_scheme_keys {
+    pub const LIGHT: &str = "light";
+    pub const DARK: &str = "dark";
+    pub const FOREGROUND: &str = "foreground";
+    pub const BACKGROUND: &str = "background";
+    pub const CURSOR: &str = "cursor";
+    pub const PALETTE: &str = "palette";
+    pub const SELECTION: &str = "selection";
+    pub const SEARCH_MATCH: &str = "search_match";
+    pub const SEARCH_CURRENT_MATCH: &str = "search_current_match";
+    pub const WRAP_INDICATOR: &str = "wrap_indicator";
+}
+
+#[derive(Debug, Error)]
+enum LoadConfigErrorKind {
+    #[error("failed to read config file")]
+    Read(#[source] std::io::Error),
+    #[error("failed to parse config as json")]
+    Parse(#[source] tinyjson::JsonParseError),
+    #[error("root item is not an object")]
+    RootNotObject,
+    #[error("{0} is not a {1}")]
+    WrongType(&'static str, &'static str),
+    #[error("{0} has an invalid value {1:?}")]
+    InvalidValue(&'static str, String),
+    #[error("color scheme {0} is not an object")]
+    ColorSchemeNotObject(String),
+    #[error("{1} in color scheme {0} is not a #rrggbb color")]
+    InvalidColor(String, String),
+    #[error("palette in color scheme {0} is not an array of at most 256 colors")]
+    InvalidPalette(String),
+    #[error("remote_host_colors entry {0} needs a host pattern and a #rrggbb color")]
+    InvalidRemoteHostColor(usize),
+    #[error("profiles entry {0} needs a name, string patterns and a font_size from 1 to 100")]
+    InvalidProfile(usize),
+    #[error("profile {0} uses unknown color scheme {1}")]
+    ProfileColorScheme(String, String),
+    #[error("{1} pattern of profile {0} is not a valid regex")]
+    ProfilePattern(String, &'static str, #[source] regex::Error),
+    #[error("background image has no path")]
+    BackgroundImagePathMissing,
+    #[error("invalid key combo for macro")]
+    MacroKeyCombo(#[source] ParseKeyComboError),
+    #[error("macro for {0} is not a string")]
+    MacroNotString(String),
+    #[error("invalid macro for {0}")]
+    MacroPayload(String, #[source] UnescapeError),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct LoadConfigError(#[from] LoadConfigErrorKind);
+
+#[derive(Debug, Error)]
+pub enum ParseKeyComboError {
+    #[error("key combo is empty")]
+    Empty,
+    #[error("unknown modifier {0}")]
+    UnknownModifier(String),
+    #[error("unknown key {0}")]
+    UnknownKey(String),
+}
+
+/// A key plus modifiers, written like "Ctrl+Shift+T" or "F5"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyCombo {
+    pub key: Key,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl KeyCombo {
+    /// Whether `event` is a press of exactly this combination
+    pub fn matches(&self, event: &Event) -> bool {
+        match event {
+            Event::Key {
+                key,
+                pressed: true,
+                modifiers,
+                ..
+            } => {
+                *key == self.key
+                    && modifiers.ctrl == self.ctrl
+                    && modifiers.shift == self.shift
+                    && modifiers.alt == self.alt
+            }
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let modifiers = [
+            (self.ctrl, "Ctrl+"),
+            (self.shift, "Shift+"),
+            (self.alt, "Alt+"),
+        ];
+        for (_, name) in modifiers.iter().filter(|(set, _)| *set) {
+            f.write_str(name)?;
+        }
+        f.write_str(self.key.name())
+    }
+}
+
+impl FromStr for KeyCombo {
+    type Err = ParseKeyComboError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<&str> = s.split('+').map(str::trim).collect();
+        let key_name = parts.pop().filter(|v| !v.is_empty());
+        let key_name = key_name.ok_or(ParseKeyComboError::Empty)?;
+
+        // egui names letter keys in upper case, accept either
+        let key = Key::from_name(key_name)
+            .or_else(|| Key::from_name(&key_name.to_ascii_uppercase()))
+            .ok_or_else(|| ParseKeyComboError::UnknownKey(key_name.to_string()))?;
+
+        let mut combo = KeyCombo {
+            key,
+            ctrl: false,
+            shift: false,
+            alt: false,
+        };
+
+        for modifier in parts {
+            match modifier.to_ascii_lowercase().as_str() {
+                "ctrl" | "control" => combo.ctrl = true,
+                "shift" => combo.shift = true,
+                "alt" => combo.alt = true,
+                _ => return Err(ParseKeyComboError::UnknownModifier(modifier.to_string())),
+            }
+        }
+
+        Ok(combo)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum UnescapeError {
+    #[error("string ends with a lone backslash")]
+    TrailingBackslash,
+    #[error("unknown escape \\{0}")]
+    UnknownEscape(char),
+    #[error("invalid hex escape")]
+    InvalidHex,
+}
+
+/// Resolve backslash escapes on top of the ones json already provides, so that control
+/// characters can be written readably. Supports \e (escape), \n, \r, \t, \\ and \xHH
+fn unescape_macro(s: &str) -> Result<Vec<u8>, UnescapeError> {
+    let mut ret = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0u8; 4];
+            ret.extend(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next().ok_or(UnescapeError::TrailingBackslash)? {
+            'e' => ret.push(0x1b),
+            'n' => ret.push(b'\n'),
+            'r' => ret.push(b'\r'),
+            't' => ret.push(b'\t'),
+            '\\' => ret.push(b'\\'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 {
+                    return Err(UnescapeError::InvalidHex);
+                }
+                let b = u8::from_str_radix(&hex, 16).map_err(|_| UnescapeError::InvalidHex)?;
+                ret.push(b);
+            }
+            c => return Err(UnescapeError::UnknownEscape(c)),
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Bytes sent to the terminal in place of the normal encoding for a key combination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMacro {
+    pub combo: KeyCombo,
+    pub payload: Vec<u8>,
+}
+
+fn take_macros(
+    root: &mut HashMap<String, JsonValue>,
+    out: &mut Vec<KeyMacro>,
+) -> Result<(), LoadConfigErrorKind> {
+    use LoadConfigErrorKind::*;
+
+    let macros = match root.remove(config_keys::MACROS) {
+        Some(JsonValue::Object(v)) => v,
+        Some(_) => Err(WrongType(config_keys::MACROS, "object"))?,
+        None => return Ok(()),
+    };
+
+    for (combo_s, payload) in macros {
+        let combo = combo_s.parse().map_err(MacroKeyCombo)?;
+        let JsonValue::String(payload) = payload else {
+            Err(MacroNotString(combo_s))?
+        };
+        let payload = unescape_macro(&payload).map_err(|e| MacroPayload(combo_s, e))?;
+        out.push(KeyMacro { combo, payload });
+    }
+
+    Ok(())
+}
+
+fn take_bool(
+    root: &mut HashMap<String, JsonValue>,
+    key: &'static str,
+    out: &mut bool,
+) -> Result<(), LoadConfigErrorKind> {
+    match root.remove(key) {
+        Some(JsonValue::Boolean(v)) => *out = v,
+        Some(_) => Err(LoadConfigErrorKind::WrongType(key, "bool"))?,
+        None => (),
+    }
+    Ok(())
+}
+
+fn take_parsed<T: FromStr>(
+    root: &mut HashMap<String, JsonValue>,
+    key: &'static str,
+    out: &mut T,
+) -> Result<(), LoadConfigErrorKind> {
+    match root.remove(key) {
+        Some(JsonValue::String(v)) => {
+            *out = v
+                .parse()
+                .map_err(|_| LoadConfigErrorKind::InvalidValue(key, v))?;
+        }
+        Some(_) => Err(LoadConfigErrorKind::WrongType(key, "string"))?,
+        None => (),
+    }
+    Ok(())
+}
+
+/// A program and its arguments, as a non-empty array of strings
+fn take_command(
+    root: &mut HashMap<String, JsonValue>,
+    key: &'static str,
+    out: &mut Option<Vec<String>>,
+) -> Result<(), LoadConfigErrorKind> {
+    let wrong_type = LoadConfigErrorKind::WrongType(key, "non-empty array of strings");
+    match root.remove(key) {
+        Some(JsonValue::Array(v)) if !v.is_empty() => {
+            let argv = v
+                .into_iter()
+                .map(|arg| match arg {
+                    JsonValue::String(arg) => Some(arg),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or(wrong_type)?;
+            *out = Some(argv);
+        }
+        Some(_) => Err(wrong_type)?,
+        None => (),
+    }
+    Ok(())
+}
+
+/// A non-negative number of milliseconds
+fn take_millis(
+    root: &mut HashMap<String, JsonValue>,
+    key: &'static str,
+    out: &mut Duration,
+) -> Result<(), LoadConfigErrorKind> {
+    match root.remove(key) {
+        Some(JsonValue::Number(v)) => {
+            *out = Duration::try_from_secs_f64(v / 1000.0)
+                .map_err(|_| LoadConfigErrorKind::InvalidValue(key, v.to_string()))?;
+        }
+        Some(_) => Err(LoadConfigErrorKind::WrongType(key, "number"))?,
+        None => (),
+    }
+    Ok(())
+}
+
+/// A whole number of at least 1
+fn take_count(
+    root: &mut HashMap<String, JsonValue>,
+    key: &'static str,
+    out: &mut usize,
+) -> Result<(), LoadConfigErrorKind> {
+    match root.remove(key) {
+        Some(JsonValue::Number(v)) => {
+            if v < 1.0 || v.fract() != 0.0 || v > usize::MAX as f64 {
+                Err(LoadConfigErrorKind::InvalidValue(key, v.to_string()))?;
+            }
+            *out = v as usize;
+        }
+        Some(_) => Err(LoadConfigErrorKind::WrongType(key, "number"))?,
+        None => (),
+    }
+    Ok(())
+}
+
+/// A number from 0.0 to 1.0
+fn take_fraction(
+    root: &mut HashMap<String, JsonValue>,
+    key: &'static str,
+    out: &mut f32,
+) -> Result<(), LoadConfigErrorKind> {
+    match root.remove(key) {
+        Some(JsonValue::Number(v)) => {
+            if !(0.0..=1.0).contains(&v) {
+                Err(LoadConfigErrorKind::InvalidValue(key, v.to_string()))?;
+            }
+            *out = v as f32;
+        }
+        Some(_) => Err(LoadConfigErrorKind::WrongType(key, "number"))?,
+        None => (),
+    }
+    Ok(())
+}
+
+fn parse_color(scheme: &str, name: &str, value: &JsonValue) -> Result<Rgb, LoadConfigErrorKind> {
+    let invalid = || LoadConfigErrorKind::InvalidColor(scheme.to_string(), name.to_string());
+    match value {
+        JsonValue::String(v) => parse_hex_rgb(v).ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+/// Colors drawn over the text may be translucent, `#rrggbbaa`
+fn parse_overlay_color(
+    scheme: &str,
+    name: &str,
+    value: &JsonValue,
+) -> Result<Rgba, LoadConfigErrorKind> {
+    let invalid = || LoadConfigErrorKind::InvalidColor(scheme.to_string(), name.to_string());
+    match value {
+        JsonValue::String(v) => parse_hex_rgba(v).ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+/// Apply the colors given in `value` on top of `out`, so that a scheme only has to list what it
+/// changes
+fn parse_color_scheme(
+    name: &str,
+    value: JsonValue,
+    out: &mut ColorScheme,
+) -> Result<(), LoadConfigErrorKind> {
+    use LoadConfigErrorKind::*;
+
+    let JsonValue::Object(mut scheme) = value else {
+        Err(ColorSchemeNotObject(name.to_string()))?
+    };
+
+    let fields = [
+        (color_scheme_keys::FOREGROUND, &mut out.foreground),
+        (color_scheme_keys::BACKGROUND, &mut out.background),
+        (color_scheme_keys::CURSOR, &mut out.cursor),
+    ];
+    for (key, field) in fields {
+        if let Some(v) = scheme.remove(key) {
+            *field = parse_color(name, key, &v)?;
+        }
+    }
+
+    let overlay_fields = [
+        (color_scheme_keys::SELECTION, &mut out.selection),
+        (color_scheme_keys::SEARCH_MATCH, &mut out.search_match),
+        (
+            color_scheme_keys::SEARCH_CURRENT_MATCH,
+            &mut out.search_current_match,
+        ),
+        (color_scheme_keys::WRAP_INDICATOR, &mut out.wrap_indicator),
+    ];
+    for (key, field) in overlay_fields {
+        if let Some(v) = scheme.remove(key) {
+            *field = parse_overlay_color(name, key, &v)?;
+        }
+    }
+
+    match scheme.remove(color_scheme_keys::PALETTE) {
+        Some(JsonValue::Array(palette)) if palette.len() <= out.palette.len() => {
+            for (i, v) in palette.iter().enumerate() {
+                out.palette[i] = parse_color(name, &format!("palette entry {i}"), v)?;
+            }
+        }
+        Some(_) => Err(InvalidPalette(name.to_string()))?,
+        None => (),
+    }
+
+    for key in scheme.keys() {
+        warn!("Ignoring unknown key {key} in color scheme {name}");
+    }
+
+    Ok(())
+}
+
+fn take_color_schemes(
+    root: &mut HashMap<String, JsonValue>,
+    config: &mut Config,
+) -> Result<(), LoadConfigErrorKind> {
+    let mut schemes = match root.remove(config_keys::COLOR_SCHEMES) {
+        Some(JsonValue::Object(v)) => v,
+        Some(_) => Err(LoadConfigErrorKind::WrongType(
+            config_keys::COLOR_SCHEMES,
+            "object",
+        ))?,
+        None => return Ok(()),
+    };
+
+    for (name, out) in [
+        (color_scheme_keys::LIGHT, &mut config.light_colors),
+        (color_scheme_keys::DARK, &mut config.dark_colors),
+    ] {
+        if let Some(v) = schemes.remove(name) {
+            parse_color_scheme(name, v, out)?;
+        }
+    }
+
+    // Custom schemes build on the dark one after its own overrides were applied
+    for (name, v) in schemes {
+        let mut scheme = config.dark_colors.clone();
+        parse_color_scheme(&name, v, &mut scheme)?;
+        config.custom_colors.insert(name, scheme);
+    }
+
+    Ok(())
+}
+
+fn take_selected_color_scheme(
+    root: &mut HashMap<String, JsonValue>,
+    config: &mut Config,
+) -> Result<(), LoadConfigErrorKind> {
+    let key = config_keys::COLOR_SCHEME;
+    match root.remove(key) {
+        Some(JsonValue::String(v)) => {
+            if config.named_color_scheme(&v).is_none() {
+                return Err(LoadConfigErrorKind::InvalidValue(key, v));
+            }
+            config.color_scheme = Some(v);
+        }
+        Some(_) => Err(LoadConfigErrorKind::WrongType(key, "string"))?,
+        None => (),
+    }
+    Ok(())
+}
+
+fn take_background_image(
+    root: &mut HashMap<String, JsonValue>,
+    out: &mut Option<BackgroundImage>,
+) -> Result<(), LoadConfigErrorKind> {
+    use LoadConfigErrorKind::*;
+
+    let mut image = match root.remove(config_keys::BACKGROUND_IMAGE) {
+        Some(JsonValue::Object(v)) => v,
+        Some(_) => Err(WrongType(config_keys::BACKGROUND_IMAGE, "object"))?,
+        None => return Ok(()),
+    };
+
+    let path = match image.remove(background_image_keys::PATH) {
+        Some(JsonValue::String(v)) => PathBuf::from(v),
+        Some(_) => Err(WrongType(background_image_keys::PATH, "string"))?,
+        None => Err(BackgroundImagePathMissing)?,
+    };
+
+    let mut ret = BackgroundImage::new(path);
+    take_fraction(&mut image, background_image_keys::OPACITY, &mut ret.opacity)?;
+    take_parsed(&mut image, background_image_keys::SCALING, &mut ret.scaling)?;
+    take_fraction(&mut image, background_image_keys::DARKEN, &mut ret.darken)?;
+
+    for key in image.keys() {
+        warn!("Ignoring unknown background image key {key}");
+    }
+
+    *out = Some(ret);
+    Ok(())
+}
+
+/// Banner color for hosts matching `host`, a pattern where `*` matches any run of characters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteHostColor {
+    pub host: String,
+    pub color: Rgb,
+}
+
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcard at all
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+fn take_remote_host_colors(
+    root: &mut HashMap<String, JsonValue>,
+    out: &mut Vec<RemoteHostColor>,
+) -> Result<(), LoadConfigErrorKind> {
+    use LoadConfigErrorKind::*;
+
+    let rules = match root.remove(config_keys::REMOTE_HOST_COLORS) {
+        Some(JsonValue::Array(v)) => v,
+        Some(_) => Err(WrongType(config_keys::REMOTE_HOST_COLORS, "array"))?,
+        None => return Ok(()),
+    };
+
+    for (i, rule) in rules.into_iter().enumerate() {
+        let JsonValue::Object(mut rule) = rule else {
+            Err(InvalidRemoteHostColor(i))?
+        };
+        let host = rule.remove(remote_host_color_keys::HOST);
+        let color = rule.remove(remote_host_color_keys::COLOR);
+        let (Some(JsonValue::String(host)), Some(JsonValue::String(color))) = (host, color) else {
+            Err(InvalidRemoteHostColor(i))?
+        };
+        let color = parse_hex_rgb(&color).ok_or(InvalidRemoteHostColor(i))?;
+        out.push(RemoteHostColor { host, color });
+    }
+
+    Ok(())
+}
+
+/// A regex from the config, compiled when it is loaded. Two patterns are equal when they were
+/// written the same
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    /// Whether the pattern matches anywhere in `text`
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = regex::Error;
+
+    fn from_str(s: &str) -> Result<Pattern, regex::Error> {
+        Regex::new(s).map(Pattern)
+    }
+}
+
+/// Settings that take over while a program runs, or while picked from the menu. The patterns
+/// are regexes that match anywhere in the text unless anchored, e.g. "^vim$"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// Pattern for the name of the foreground process, e.g. "vim"
+    pub process: Option<Pattern>,
+    /// Pattern for the window title
+    pub title: Option<Pattern>,
+    pub font_size: Option<f32>,
+    /// Name of a color scheme, see [`Config::named_color_scheme`]
+    pub color_scheme: Option<String>,
+}
+
+impl Profile {
+    /// Whether the profile applies to the window. Profiles without patterns are only picked by
+    /// hand
+    pub fn matches(&self, process: Option<&str>, title: &str) -> bool {
+        if self.process.is_none() && self.title.is_none() {
+            return false;
+        }
+        let process_matches = match (&self.process, process) {
+            (Some(pattern), Some(process)) => pattern.is_match(process),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let title_matches = self
+            .title
+            .as_ref()
+            .is_none_or(|pattern| pattern.is_match(title));
+        process_matches && title_matches
+    }
+}
+
+fn take_profiles(
+    root: &mut HashMap<String, JsonValue>,
+    config: &mut Config,
+) -> Result<(), LoadConfigErrorKind> {
+    use LoadConfigErrorKind::*;
+
+    let profiles = match root.remove(config_keys::PROFILES) {
+        Some(JsonValue::Array(v)) => v,
+        Some(_) => Err(WrongType(config_keys::PROFILES, "array"))?,
+        None => return Ok(()),
+    };
+
+    for (i, profile) in profiles.into_iter().enumerate() {
+        let JsonValue::Object(mut profile) = profile else {
+            Err(InvalidProfile(i))?
+        };
+        let mut string = |key| match profile.remove(key) {
+            Some(JsonValue::String(v)) => Ok(Some(v)),
+            Some(_) => Err(InvalidProfile(i)),
+            None => Ok(None),
+        };
+        let Some(name) = string(profile_keys::NAME)? else {
+            Err(InvalidProfile(i))?
+        };
+        let process = string(profile_keys::PROCESS)?;
+        let title = string(profile_keys::TITLE)?;
+        let color_scheme = string(profile_keys::COLOR_SCHEME)?;
+        let pattern = |pattern: Option<String>, key| {
+            pattern
+                .map(|pattern| pattern.parse())
+                .transpose()
+                .map_err(|e| ProfilePattern(name.clone(), key, e))
+        };
+        let process = pattern(process, profile_keys::PROCESS)?;
+        let title = pattern(title, profile_keys::TITLE)?;
+        if let Some(scheme) = &color_scheme {
+            if config.named_color_scheme(scheme).is_none() {
+                Err(ProfileColorScheme(name.clone(), scheme.clone()))?
+            }
+        }
+        let font_size = match profile.remove(profile_keys::FONT_SIZE) {
+            Some(JsonValue::Number(v)) if (1.0..=100.0).contains(&v) => Some(v as f32),
+            Some(_) => Err(InvalidProfile(i))?,
+            None => None,
+        };
+
+        for key in profile.keys() {
+            warn!("Ignoring unknown key {key} in profile {name}");
+        }
+        config.profiles.push(Profile {
+            name,
+            process,
+            title,
+            font_size,
+            color_scheme,
+        });
+    }
+
+    Ok(())
+}
+
+/// How a background image is fitted to the terminal area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageScaling {
+    /// Cover the whole area, cropping whatever does not fit
+    Fill,
+    /// Show the whole image, leaving bars where the aspect ratio differs
+    Fit,
+    /// Cover the whole area, distorting the image if needed
+    Stretch,
+    /// Unscaled at the center
+    Center,
+}
+
+impl FromStr for ImageScaling {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "fill" => ImageScaling::Fill,
+            "fit" => ImageScaling::Fit,
+            "stretch" => ImageScaling::Stretch,
+            "center" => ImageScaling::Center,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackgroundImage {
+    /// A PNG file
+    pub path: PathBuf,
+    /// How much of the image shows over the default background, from 0.0 to 1.0
+    pub opacity: f32,
+    /// One of "fill", "fit", "stretch" or "center"
+    pub scaling: ImageScaling,
+    /// How strongly the image is darkened so that text on top stays readable, from 0.0 to 1.0
+    pub darken: f32,
+}
+
+impl BackgroundImage {
+    fn new(path: PathBuf) -> BackgroundImage {
+        BackgroundImage {
+            path,
+            opacity: 1.0,
+            scaling: ImageScaling::Fill,
+            darken: 0.0,
+        }
+    }
+}
+
+/// Color of the character under the block cursor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorTextColor {
+    /// The background color of its cell, as if the cell was inverted
+    Inverted,
+    Fixed(Rgb),
+}
+
+impl FromStr for CursorTextColor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "invert" => Ok(CursorTextColor::Inverted),
+            _ => parse_hex_rgb(s).map(CursorTextColor::Fixed).ok_or(()),
+        }
+    }
+}
+
+/// Modifier to hold while clicking a link to open it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkModifier {
+    Ctrl,
+    /// The command key on macOS
+    Cmd,
+    Alt,
+    Shift,
+    /// Plain clicks open links
+    None,
+}
+
+impl LinkModifier {
+    pub fn is_held(&self, modifiers: &Modifiers) -> bool {
+        match self {
+            LinkModifier::Ctrl => modifiers.ctrl,
+            LinkModifier::Cmd => modifiers.mac_cmd,
+            LinkModifier::Alt => modifiers.alt,
+            LinkModifier::Shift => modifiers.shift,
+            LinkModifier::None => true,
+        }
+    }
+}
+
+impl FromStr for LinkModifier {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "ctrl" => LinkModifier::Ctrl,
+            "cmd" => LinkModifier::Cmd,
+            "alt" => LinkModifier::Alt,
+            "shift" => LinkModifier::Shift,
+            "none" => LinkModifier::None,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
+/// Whether to use the light or dark color scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    /// Follow the system appearance
+    Auto,
+    Light,
+    Dark,
+}
+
+impl FromStr for Theme {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "auto" => Theme::Auto,
+            "light" => Theme::Light,
+            "dark" => Theme::Dark,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
+/// User settings, loaded from a json file. Missing keys keep their default value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Ask before closing the window while a job other than the shell is in the foreground
+    pub confirm_close: bool,
+    /// Whether next/previous in the search bar wrap past the last/first match
+    pub search_wrap_around: bool,
+    pub macros: Vec<KeyMacro>,
+    /// Strip trailing whitespace from each line of copied text
+    pub copy_trim_trailing_whitespace: bool,
+    /// Briefly leave a faint cursor behind at positions the cursor moved away from
+    pub cursor_trail: bool,
+    /// Blink the cursor. It never blinks when reduce motion is on
+    pub cursor_blink: bool,
+    /// "cursor_blink_pause_ms", how long the cursor stays solid after a key press before it
+    /// blinks again
+    pub cursor_blink_pause: Duration,
+    /// Hide the mouse pointer over the terminal text when a key is pressed, until the mouse
+    /// moves
+    pub hide_pointer_while_typing: bool,
+    /// What to hold while clicking a URL to open it, one of "ctrl", "cmd", "alt", "shift" or
+    /// "none". Defaults to cmd on macOS and ctrl elsewhere
+    pub link_modifier: LinkModifier,
+    /// Jump to the bottom when the program writes output while the view is scrolled up. At the
+    /// bottom the view always follows the output
+    pub scroll_on_output: bool,
+    /// Jump to the bottom when typing while the view is scrolled up
+    pub scroll_on_input: bool,
+    /// Program and arguments that copied text is piped to instead of egui's clipboard, e.g.
+    /// ["wl-copy"]. Text programs copy with OSC 52 goes there as well
+    pub clipboard_copy_command: Option<Vec<String>>,
+    /// Program and arguments whose output is pasted instead of egui's clipboard, e.g.
+    /// ["wl-paste", "-n"]
+    pub clipboard_paste_command: Option<Vec<String>>,
+    /// Draw box-drawing, block and Powerline separator characters instead of using the font's
+    /// glyphs, which can leave gaps between cells
+    pub builtin_box_drawing: bool,
+    /// Either "invert" to draw the character under the cursor in its background color, or a
+    /// fixed #rrggbb color
+    pub cursor_text_color: CursorTextColor,
+    /// Start the shell with `-l` so that it reads login dotfiles. Defaults to true on macOS,
+    /// where terminals conventionally start login shells, and false elsewhere
+    pub login_shell: bool,
+    /// What the Enter key sends, one of "cr", "lf" or "crlf". Applications that enable
+    /// line feed/new line mode always get CR LF
+    pub enter_sends: EnterSequence,
+    /// One of "auto", "light" or "dark". Applies to both the terminal colors and the rest of
+    /// the window
+    pub theme: Theme,
+    /// Terminal colors for the light theme. Configured under "color_schemes", where "light" and
+    /// "dark" hold any of "foreground", "background", "cursor" and "palette" as #rrggbb strings
+    pub light_colors: ColorScheme,
+    /// Terminal colors for the dark theme
+    pub dark_colors: ColorScheme,
+    /// Any other name under "color_schemes" defines a scheme of its own, starting from the dark
+    /// colors. These are only used when selected with [`Config::color_scheme`]
+    pub custom_colors: BTreeMap<String, ColorScheme>,
+    /// Name of the color scheme the terminal uses regardless of the theme, e.g. a red tinted
+    /// scheme for a window logged into production. Can be "light", "dark" or a custom scheme
+    pub color_scheme: Option<String>,
+    /// Opacity of the default terminal background, from 0.0 (fully transparent) to 1.0. Below
+    /// 1.0 the window is created with a transparent framebuffer so that the desktop shows through.
+    /// Text and explicitly colored cells stay opaque
+    pub background_opacity: f32,
+    /// Image drawn behind the terminal text. Configured under "background_image" with "path",
+    /// and optionally "opacity", "scaling" and "darken"
+    pub background_image: Option<BackgroundImage>,
+    /// Show which host the terminal is connected to while ssh is in the foreground
+    pub remote_host_banner: bool,
+    /// Colors for the remote host banner and a border around the terminal, picked by the first
+    /// rule whose host pattern matches. Configured as an array of {"host", "color"} objects
+    pub remote_host_colors: Vec<RemoteHostColor>,
+    /// How long after the last input or output the terminal keeps redrawing every frame. After
+    /// that only input, new output, and the slower title refresh wake it up. Configured in
+    /// milliseconds as "idle_timeout_ms"
+    pub idle_timeout: Duration,
+    /// How long output is collected before a repaint is requested for it, so that output
+    /// arriving in many small chunks does not cost a frame each. In milliseconds as
+    /// "repaint_coalesce_ms", 0 repaints for every chunk
+    pub repaint_coalesce: Duration,
+    /// Turn off animations. When not set, the "reduce motion" preference of the desktop is
+    /// followed where it can be detected, see `gui::motion`
+    pub reduce_motion: Option<bool>,
+    /// Let programs post desktop notifications with OSC 9 and OSC 777. When disabled they ring
+    /// the bell instead
+    pub notifications: bool,
+    /// Minimum time between two notifications, ones in between are dropped. Configured in
+    /// milliseconds as "notification_interval_ms"
+    pub notification_interval: Duration,
+    /// Mark the window title when there is output while the window is not focused. The bell
+    /// always marks it. Can also be toggled per window
+    pub monitor_activity: bool,
+    /// Lines longer than this many cells are broken into several, so that output without
+    /// newlines cannot grow a single line without bound
+    pub max_line_length: usize,
+    /// What output that is not valid UTF-8 turns into, "replace" for U+FFFD or "latin1" to read
+    /// it as Latin-1 from legacy programs
+    pub invalid_utf8: InvalidUtf8,
+    /// Clears the screen and scrollback without involving the shell
+    pub clear_buffer_shortcut: KeyCombo,
+    /// What the log given with --logfile holds, "plain" for just the text or "raw" for the
+    /// output including escape sequences
+    pub session_log_format: SessionLogFormat,
+    /// Replaces the shell with a new one in the same directory, e.g. after it exited
+    pub restart_shell_shortcut: KeyCombo,
+    /// Whether the output of the old shell can still be scrolled back to after a restart
+    pub restart_keeps_scrollback: bool,
+    /// Show typed input on the screen, for serial consoles and other programs that do not echo
+    /// it. With a normal shell everything would show up twice
+    pub local_echo: bool,
+    /// Once output resumes after stopping for this long, the recording is saved and continues
+    /// in a new file named after the time. In milliseconds as "recording_split_after_ms", 0
+    /// keeps a single file
+    pub recording_split_after: Duration,
+    /// Mark rows that soft wrap onto the next one, so that they can be told apart from lines
+    /// that ended. The marker is drawn in a column of its own past the right edge of the grid,
+    /// in the color scheme's "wrap_indicator" color
+    pub wrap_indicator: bool,
+    /// Text of the wrap indicator
+    pub wrap_indicator_glyph: String,
+    /// Font size and color scheme overrides for particular programs. Configured as an array of
+    /// objects with a "name", optional "process" and "title" regexes, and the settings to
+    /// override. The first profile that matches is used
+    pub profiles: Vec<Profile>,
+    /// Search the scrollback along with the screen, on by default. While a full screen program
+    /// shows the alternate screen only that screen is searched either way, the main screen and
+    /// its scrollback behind it are not shown
+    pub search_scrollback: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            confirm_close: false,
+            search_wrap_around: true,
+            macros: Vec::new(),
+            copy_trim_trailing_whitespace: true,
+            cursor_trail: false,
+            cursor_blink: false,
+            cursor_blink_pause: Duration::from_millis(500),
+            hide_pointer_while_typing: false,
+            link_modifier: if cfg!(target_os = "macos") {
+                LinkModifier::Cmd
+            } else {
+                LinkModifier::Ctrl
+            },
+            scroll_on_output: false,
+            scroll_on_input: true,
+            clipboard_copy_command: None,
+            clipboard_paste_command: None,
+            builtin_box_drawing: false,
+            cursor_text_color: CursorTextColor::Inverted,
+            login_shell: cfg!(target_os = "macos"),
+            enter_sends: EnterSequence::Cr,
+            theme: Theme::Dark,
+            light_colors: ColorScheme::light(),
+            dark_colors: ColorScheme::default(),
+            custom_colors: BTreeMap::new(),
+            color_scheme: None,
+            background_opacity: 1.0,
+            background_image: None,
+            remote_host_banner: false,
+            remote_host_colors: Vec::new(),
+            idle_timeout: Duration::from_secs(3),
+            repaint_coalesce: Duration::from_millis(8),
+            reduce_motion: None,
+            notifications: false,
+            notification_interval: Duration::from_secs(2),
+            monitor_activity: true,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            invalid_utf8: InvalidUtf8::Replace,
+            clear_buffer_shortcut: KeyCombo {
+                key: Key::K,
+                ctrl: true,
+                shift: true,
+                alt: false,
+            },
+            session_log_format: SessionLogFormat::Plain,
+            restart_shell_shortcut: KeyCombo {
+                key: Key::R,
+                ctrl: true,
+                shift: true,
+                alt: false,
+            },
+            restart_keeps_scrollback: true,
+            local_echo: false,
+            recording_split_after: Duration::ZERO,
+            wrap_indicator: false,
+            wrap_indicator_glyph: "\u{21b5}".to_string(),
+            profiles: Vec::new(),
+            search_scrollback: true,
+        }
+    }
+}
+
+impl Config {
+    /// $XDG_CONFIG_HOME/termie/config.json, falling back to ~/.config/termie/config.json
+    pub fn default_path() -> Option<PathBuf> {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+        Some(config_dir.join("termie").join("config.json"))
+    }
+
+    /// The color scheme called `name`, see [`Config::color_scheme`]
+    pub fn named_color_scheme(&self, name: &str) -> Option<&ColorScheme> {
+        match name {
+            color_scheme_keys::LIGHT => Some(&self.light_colors),
+            color_scheme_keys::DARK => Some(&self.dark_colors),
+            _ => self.custom_colors.get(name),
+        }
+    }
+
+    /// Names of all color schemes, built in ones first
+    pub fn color_scheme_names(&self) -> impl Iterator<Item = &str> {
+        [color_scheme_keys::LIGHT, color_scheme_keys::DARK]
+            .into_iter()
+            .chain(self.custom_colors.keys().map(String::as_str))
+    }
+
+    /// Color for the remote host `destination`, `[user@]host` as given to ssh
+    pub fn remote_host_color(&self, destination: &str) -> Option<Rgb> {
+        let host = destination.rsplit('@').next().unwrap_or(destination);
+        self.remote_host_colors
+            .iter()
+            .find(|rule| wildcard_match(&rule.host, host))
+            .map(|rule| rule.color)
+    }
+
+    /// The first profile that applies to a window running `process` with `title`
+    pub fn matching_profile(&self, process: Option<&str>, title: &str) -> Option<&Profile> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.matches(process, title))
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|profile| profile.name == name)
+    }
+
+    /// Whether the window needs a transparent framebuffer for [`Config::background_opacity`]
+    pub fn transparent_background(&self) -> bool {
+        self.background_opacity < 1.0
+    }
+
+    /// Load the config at `path`. A missing file is not an error and results in the default
+    /// config
+    pub fn load(path: &Path) -> Result<Config, LoadConfigError> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Config::default());
+            }
+            Err(e) => Err(LoadConfigErrorKind::Read(e))?,
+        };
+
+        Config::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> Result<Config, LoadConfigError> {
+        use LoadConfigErrorKind::*;
+
+        let json: JsonValue = content.parse().map_err(Parse)?;
+        let JsonValue::Object(mut root) = json else {
+            Err(RootNotObject)?
+        };
+
+        let mut config = Config::default();
+        take_bool(
+            &mut root,
+            config_keys::CONFIRM_CLOSE,
+            &mut config.confirm_close,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::SEARCH_WRAP_AROUND,
+            &mut config.search_wrap_around,
+        )?;
+        take_macros(&mut root, &mut config.macros)?;
+        take_bool(
+            &mut root,
+            config_keys::COPY_TRIM_TRAILING_WHITESPACE,
+            &mut config.copy_trim_trailing_whitespace,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::CURSOR_TRAIL,
+            &mut config.cursor_trail,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::CURSOR_BLINK,
+            &mut config.cursor_blink,
+        )?;
+        take_millis(
+            &mut root,
+            config_keys::CURSOR_BLINK_PAUSE_MS,
+            &mut config.cursor_blink_pause,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::HIDE_POINTER_WHILE_TYPING,
+            &mut config.hide_pointer_while_typing,
+        )?;
+        take_parsed(
+            &mut root,
+            config_keys::LINK_MODIFIER,
+            &mut config.link_modifier,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::SCROLL_ON_OUTPUT,
+            &mut config.scroll_on_output,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::SCROLL_ON_INPUT,
+            &mut config.scroll_on_input,
+        )?;
+        take_command(
+            &mut root,
+            config_keys::CLIPBOARD_COPY_COMMAND,
+            &mut config.clipboard_copy_command,
+        )?;
+        take_command(
+            &mut root,
+            config_keys::CLIPBOARD_PASTE_COMMAND,
+            &mut config.clipboard_paste_command,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::BUILTIN_BOX_DRAWING,
+            &mut config.builtin_box_drawing,
+        )?;
+        take_parsed(
+            &mut root,
+            config_keys::CURSOR_TEXT_COLOR,
+            &mut config.cursor_text_color,
+        )?;
+        take_bool(&mut root, config_keys::LOGIN_SHELL, &mut config.login_shell)?;
+        take_parsed(&mut root, config_keys::ENTER_SENDS, &mut config.enter_sends)?;
+        take_parsed(&mut root, config_keys::THEME, &mut config.theme)?;
+        take_color_schemes(&mut root, &mut config)?;
+        take_selected_color_scheme(&mut root, &mut config)?;
+        take_fraction(
+            &mut root,
+            config_keys::BACKGROUND_OPACITY,
+            &mut config.background_opacity,
+        )?;
+        take_background_image(&mut root, &mut config.background_image)?;
+        take_bool(
+            &mut root,
+            config_keys::REMOTE_HOST_BANNER,
+            &mut config.remote_host_banner,
+        )?;
+        take_remote_host_colors(&mut root, &mut config.remote_host_colors)?;
+        take_millis(
+            &mut root,
+            config_keys::IDLE_TIMEOUT_MS,
+            &mut config.idle_timeout,
+        )?;
+        take_millis(
+            &mut root,
+            config_keys::REPAINT_COALESCE_MS,
+            &mut config.repaint_coalesce,
+        )?;
+        match root.remove(config_keys::REDUCE_MOTION) {
+            Some(JsonValue::Boolean(v)) => config.reduce_motion = Some(v),
+            Some(_) => Err(LoadConfigErrorKind::WrongType(
+                config_keys::REDUCE_MOTION,
+                "bool",
+            ))?,
+            None => (),
+        }
+        take_bool(
+            &mut root,
+            config_keys::NOTIFICATIONS,
+            &mut config.notifications,
+        )?;
+        take_millis(
+            &mut root,
+            config_keys::NOTIFICATION_INTERVAL_MS,
+            &mut config.notification_interval,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::MONITOR_ACTIVITY,
+            &mut config.monitor_activity,
+        )?;
+        take_count(
+            &mut root,
+            config_keys::MAX_LINE_LENGTH,
+            &mut config.max_line_length,
+        )?;
+        take_parsed(
+            &mut root,
+            config_keys::INVALID_UTF8,
+            &mut config.invalid_utf8,
+        )?;
+        take_parsed(
+            &mut root,
+            config_keys::CLEAR_BUFFER_SHORTCUT,
+            &mut config.clear_buffer_shortcut,
+        )?;
+        take_parsed(
+            &mut root,
+            config_keys::SESSION_LOG_FORMAT,
+            &mut config.session_log_format,
+        )?;
+        take_parsed(
+            &mut root,
+            config_keys::RESTART_SHELL_SHORTCUT,
+            &mut config.restart_shell_shortcut,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::RESTART_KEEPS_SCROLLBACK,
+            &mut config.restart_keeps_scrollback,
+        )?;
+        take_bool(&mut root, config_keys::LOCAL_ECHO, &mut config.local_echo)?;
+        take_millis(
+            &mut root,
+            config_keys::RECORDING_SPLIT_AFTER_MS,
+            &mut config.recording_split_after,
+        )?;
+        take_bool(
+            &mut root,
+            config_keys::WRAP_INDICATOR,
+            &mut config.wrap_indicator,
+        )?;
+        take_parsed(
+            &mut root,
+            config_keys::WRAP_INDICATOR_GLYPH,
+            &mut config.wrap_indicator_glyph,
+        )?;
+        take_profiles(&mut root, &mut config)?;
+        take_bool(
+            &mut root,
+            config_keys::SEARCH_SCROLLBACK,
+            &mut config.search_scrollback,
+        )?;
+
+        for key in root.keys() {
+            warn!("Ignoring unknown config key {key}");
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_defaults() {
+        let config = Config::parse("{}").expect("failed to parse config");
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn test_parse_confirm_close() {
+        let config = Config::parse(r#"{"confirm_close": true}"#).expect("failed to parse config");
+        assert!(config.confirm_close);
+    }
+
+    #[test]
+    fn test_parse_search_wrap_around() {
+        let config =
+            Config::parse(r#"{"search_wrap_around": false}"#).expect("failed to parse config");
+        assert!(!config.search_wrap_around);
+        assert!(!config.confirm_close);
+    }
+
+    #[test]
+    fn test_parse_copy_trim_trailing_whitespace() {
+        assert!(Config::default().copy_trim_trailing_whitespace);
+        let config = Config::parse(r#"{"copy_trim_trailing_whitespace": false}"#)
+            .expect("failed to parse config");
+        assert!(!config.copy_trim_trailing_whitespace);
+    }
+
+    #[test]
+    fn test_parse_cursor_trail() {
+        let config = Config::parse(r#"{"cursor_trail": true}"#).expect("failed to parse config");
+        assert!(config.cursor_trail);
+    }
+
+    #[test]
+    fn test_parse_cursor_blink() {
+        assert!(!Config::default().cursor_blink);
+        let config = Config::parse(r#"{"cursor_blink": true, "cursor_blink_pause_ms": 250}"#)
+            .expect("failed to parse config");
+        assert!(config.cursor_blink);
+        assert_eq!(config.cursor_blink_pause, Duration::from_millis(250));
+        assert!(Config::parse(r#"{"cursor_blink_pause_ms": -1}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_scroll_on() {
+        let config = Config::default();
+        assert!(!config.scroll_on_output);
+        assert!(config.scroll_on_input);
+
+        let config = Config::parse(r#"{"scroll_on_output": true, "scroll_on_input": false}"#)
+            .expect("failed to parse config");
+        assert!(config.scroll_on_output);
+        assert!(!config.scroll_on_input);
+    }
+
+    #[test]
+    fn test_parse_restart_shell() {
+        let config = Config::default();
+        assert_eq!(config.restart_shell_shortcut.to_string(), "Ctrl+Shift+R");
+        assert!(config.restart_keeps_scrollback);
+
+        let config =
+            Config::parse(r#"{"restart_shell_shortcut": "F5", "restart_keeps_scrollback": false}"#)
+                .expect("failed to parse config");
+        assert_eq!(config.restart_shell_shortcut.key, Key::F5);
+        assert!(!config.restart_keeps_scrollback);
+    }
+
+    #[test]
+    fn test_parse_local_echo() {
+        assert!(!Config::default().local_echo);
+        let config = Config::parse(r#"{"local_echo": true}"#).expect("failed to parse config");
+        assert!(config.local_echo);
+    }
+
+    #[test]
+    fn test_parse_recording_split_after() {
+        assert!(Config::default().recording_split_after.is_zero());
+        let config = Config::parse(r#"{"recording_split_after_ms": 300000}"#)
+            .expect("failed to parse config");
+        assert_eq!(config.recording_split_after, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_parse_wrap_indicator() {
+        assert!(!Config::default().wrap_indicator);
+        let config = Config::parse(
+            r##"{
+                "wrap_indicator": true,
+                "wrap_indicator_glyph": "»",
+                "color_schemes": {"dark": {"wrap_indicator": "#ff000080"}}
+            }"##,
+        )
+        .expect("failed to parse config");
+        assert!(config.wrap_indicator);
+        assert_eq!(config.wrap_indicator_glyph, "»");
+        assert_eq!(config.dark_colors.wrap_indicator, (255, 0, 0, 0x80));
+        assert!(Config::parse(r#"{"wrap_indicator_glyph": 1}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_profiles() {
+        let config = Config::parse(
+            r#"{"profiles": [
+                {"name": "editor", "process": "^n?vim$", "font_size": 16},
+                {"name": "production", "title": "\\.prod\\.", "color_scheme": "light"},
+                {"name": "presenting", "font_size": 24}
+            ]}"#,
+        )
+        .expect("failed to parse config");
+        assert_eq!(config.profiles.len(), 3);
+        assert_eq!(config.profiles[0].font_size, Some(16.0));
+
+        let matching = |process, title| {
+            config
+                .matching_profile(process, title)
+                .map(|profile| profile.name.as_str())
+        };
+        assert_eq!(matching(Some("nvim"), "notes.txt"), Some("editor"));
+        assert_eq!(matching(Some("vimdiff"), "notes.txt"), None);
+        assert_eq!(
+            matching(Some("bash"), "db.prod.example.com"),
+            Some("production")
+        );
+        assert_eq!(matching(None, "~"), None);
+        // Only picked by hand
+        assert_eq!(matching(Some("bash"), "presenting"), None);
+        assert!(config.profile("presenting").is_some());
+
+        assert!(Config::parse(r#"{"profiles": [{"process": "vim"}]}"#).is_err());
+        assert!(Config::parse(r#"{"profiles": [{"name": "a", "font_size": 0}]}"#).is_err());
+        assert!(Config::parse(r#"{"profiles": [{"name": "a", "color_scheme": "x"}]}"#).is_err());
+        let err = Config::parse(r#"{"profiles": [{"name": "a", "title": "*prod*"}]}"#)
+            .expect_err("invalid pattern loaded");
+        assert_eq!(
+            err.to_string(),
+            "title pattern of profile a is not a valid regex"
+        );
+    }
+
+    #[test]
+    fn test_parse_search_scrollback() {
+        assert!(Config::default().search_scrollback);
+        let config =
+            Config::parse(r#"{"search_scrollback": false}"#).expect("failed to parse config");
+        assert!(!config.search_scrollback);
+        assert!(Config::parse(r#"{"search_scrollback": "no"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_clipboard_commands() {
+        let config = Config::parse(
+            r#"{"clipboard_copy_command": ["wl-copy"], "clipboard_paste_command": ["wl-paste", "-n"]}"#,
+        )
+        .expect("failed to parse config");
+        assert_eq!(config.clipboard_copy_command, Some(vec!["wl-copy".into()]));
+        assert_eq!(
+            config.clipboard_paste_command,
+            Some(vec!["wl-paste".into(), "-n".into()])
+        );
+        assert_eq!(Config::default().clipboard_copy_command, None);
+
+        for invalid in [r#"[]"#, r#""wl-copy""#, r#"["wl-copy", 1]"#] {
+            let content = format!(r#"{{"clipboard_copy_command": {invalid}}}"#);
+            assert!(Config::parse(&content).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_parse_link_modifier() {
+        let config = Config::parse(r#"{"link_modifier": "none"}"#).expect("failed to parse config");
+        assert_eq!(config.link_modifier, LinkModifier::None);
+        assert!(config.link_modifier.is_held(&Modifiers::NONE));
+        assert!(LinkModifier::Alt.is_held(&Modifiers::ALT));
+        assert!(!LinkModifier::Ctrl.is_held(&Modifiers::SHIFT));
+        assert!(Config::parse(r#"{"link_modifier": "super"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_hide_pointer_while_typing() {
+        let config = Config::parse(r#"{"hide_pointer_while_typing": true}"#)
+            .expect("failed to parse config");
+        assert!(config.hide_pointer_while_typing);
+    }
+
+    #[test]
+    fn test_parse_builtin_box_drawing() {
+        let config =
+            Config::parse(r#"{"builtin_box_drawing": true}"#).expect("failed to parse config");
+        assert!(config.builtin_box_drawing);
+    }
+
+    #[test]
+    fn test_parse_cursor_text_color() {
+        assert_eq!(
+            Config::default().cursor_text_color,
+            CursorTextColor::Inverted
+        );
+        let config =
+            Config::parse(r##"{"cursor_text_color": "#102030"}"##).expect("failed to parse config");
+        assert_eq!(
+            config.cursor_text_color,
+            CursorTextColor::Fixed((16, 32, 48))
+        );
+        assert!(Config::parse(r#"{"cursor_text_color": "black"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_login_shell() {
+        let config = Config::parse(r#"{"login_shell": true}"#).expect("failed to parse config");
+        assert!(config.login_shell);
+        let config = Config::parse(r#"{"login_shell": false}"#).expect("failed to parse config");
+        assert!(!config.login_shell);
+    }
+
+    #[test]
+    fn test_parse_enter_sends() {
+        assert_eq!(Config::default().enter_sends, EnterSequence::Cr);
+        let config = Config::parse(r#"{"enter_sends": "crlf"}"#).expect("failed to parse config");
+        assert_eq!(config.enter_sends, EnterSequence::CrLf);
+        assert!(Config::parse(r#"{"enter_sends": "\r"}"#).is_err());
+        assert!(Config::parse(r#"{"enter_sends": true}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_session_log_format() {
+        let config =
+            Config::parse(r#"{"session_log_format": "raw"}"#).expect("failed to parse config");
+        assert_eq!(config.session_log_format, SessionLogFormat::Raw);
+        assert!(Config::parse(r#"{"session_log_format": "html"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_theme() {
+        let config = Config::parse(r#"{"theme": "auto"}"#).expect("failed to parse config");
+        assert_eq!(config.theme, Theme::Auto);
+        assert!(Config::parse(r#"{"theme": "sepia"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_background_opacity() {
+        let config =
+            Config::parse(r#"{"background_opacity": 0.8}"#).expect("failed to parse config");
+        assert_eq!(config.background_opacity, 0.8);
+        assert!(Config::parse(r#"{"background_opacity": 1.5}"#).is_err());
+        assert!(Config::parse(r#"{"background_opacity": "0.5"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_background_image() {
+        let config = Config::parse(
+            r#"{"background_image": {"path": "/tmp/bg.png", "scaling": "fit", "darken": 0.5}}"#,
+        )
+        .expect("failed to parse config");
+        let image = config.background_image.expect("background image missing");
+        assert_eq!(image.path, PathBuf::from("/tmp/bg.png"));
+        assert_eq!(image.opacity, 1.0);
+        assert_eq!(image.scaling, ImageScaling::Fit);
+        assert_eq!(image.darken, 0.5);
+
+        assert!(Config::parse(r#"{"background_image": {"scaling": "fit"}}"#).is_err());
+        assert!(
+            Config::parse(r#"{"background_image": {"path": "a.png", "scaling": "tile"}}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("db1", "db1"));
+        assert!(!wildcard_match("db1", "db10"));
+        assert!(wildcard_match("*.prod.example.com", "web.prod.example.com"));
+        assert!(!wildcard_match(
+            "*.prod.example.com",
+            "web.staging.example.com"
+        ));
+        assert!(wildcard_match("prod-*-db*", "prod-eu-db2"));
+        assert!(!wildcard_match("ab*ba", "aba"));
+        assert!(wildcard_match("*", ""));
+    }
+
+    #[test]
+    fn test_parse_remote_host_colors() {
+        let config = Config::parse(
+            r##"{
+                "remote_host_banner": true,
+                "remote_host_colors": [
+                    {"host": "*.prod.example.com", "color": "#ff0000"},
+                    {"host": "*", "color": "#0000ff"}
+                ]
+            }"##,
+        )
+        .expect("failed to parse config");
+        assert!(config.remote_host_banner);
+        assert_eq!(
+            config.remote_host_color("root@db.prod.example.com"),
+            Some((255, 0, 0))
+        );
+        assert_eq!(config.remote_host_color("laptop"), Some((0, 0, 255)));
+
+        assert!(Config::parse(r#"{"remote_host_colors": [{"host": "*"}]}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_idle_timeout() {
+        let config = Config::parse(r#"{"idle_timeout_ms": 500}"#).expect("failed to parse config");
+        assert_eq!(config.idle_timeout, Duration::from_millis(500));
+        assert!(Config::parse(r#"{"idle_timeout_ms": -1}"#).is_err());
+        assert!(Config::parse(r#"{"idle_timeout_ms": "1s"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_repaint_coalesce() {
+        assert_eq!(Config::default().repaint_coalesce, Duration::from_millis(8));
+        let config =
+            Config::parse(r#"{"repaint_coalesce_ms": 0}"#).expect("failed to parse config");
+        assert_eq!(config.repaint_coalesce, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_parse_reduce_motion() {
+        let config = Config::parse(r#"{"reduce_motion": false}"#).expect("failed to parse config");
+        assert_eq!(config.reduce_motion, Some(false));
+        assert!(Config::parse(r#"{"reduce_motion": "yes"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_notifications() {
+        let config = Config::parse(r#"{"notifications": true, "notification_interval_ms": 0}"#)
+            .expect("failed to parse config");
+        assert!(config.notifications);
+        assert_eq!(config.notification_interval, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_parse_max_line_length() {
+        let config = Config::parse(r#"{"max_line_length": 4096}"#).expect("failed to parse config");
+        assert_eq!(config.max_line_length, 4096);
+        assert!(Config::parse(r#"{"max_line_length": 0}"#).is_err());
+        assert!(Config::parse(r#"{"max_line_length": 1.5}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_utf8() {
+        assert_eq!(Config::default().invalid_utf8, InvalidUtf8::Replace);
+        let config =
+            Config::parse(r#"{"invalid_utf8": "latin1"}"#).expect("failed to parse config");
+        assert_eq!(config.invalid_utf8, InvalidUtf8::Latin1);
+        assert!(Config::parse(r#"{"invalid_utf8": "cp1252"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_color_schemes() {
+        let config = Config::parse(
+            r##"{"color_schemes": {
+                "light": {"background": "#ffffee", "palette": ["#010203", "#040506"]},
+                "dark": {"cursor": "#00ff00", "selection": "#3050a080", "search_match": "#806000"}
+            }}"##,
+        )
+        .expect("failed to parse config");
+
+        let mut light = ColorScheme::light();
+        light.background = (0xff, 0xff, 0xee);
+        light.palette[0] = (1, 2, 3);
+        light.palette[1] = (4, 5, 6);
+        assert_eq!(config.light_colors, light);
+        assert_eq!(config.dark_colors.cursor, (0, 255, 0));
+        assert_eq!(config.dark_colors.selection, (0x30, 0x50, 0xa0, 0x80));
+        assert_eq!(config.dark_colors.search_match, (0x80, 0x60, 0, 255));
+        assert_eq!(
+            config.dark_colors.foreground,
+            ColorScheme::default().foreground
+        );
+
+        assert!(Config::parse(r#"{"color_schemes": {"dark": {"cursor": "green"}}}"#).is_err());
+        assert!(
+            Config::parse(r##"{"color_schemes": {"dark": {"cursor": "#00ff0080"}}}"##).is_err()
+        );
+        assert!(Config::parse(r##"{"color_schemes": {"dark": {"palette": "#000000"}}}"##).is_err());
+    }
+
+    #[test]
+    fn test_parse_custom_color_scheme() {
+        let config = Config::parse(
+            r##"{
+                "color_schemes": {
+                    "dark": {"foreground": "#eeeeee"},
+                    "production": {"background": "#400000"}
+                },
+                "color_scheme": "production"
+            }"##,
+        )
+        .expect("failed to parse config");
+
+        let production = config
+            .named_color_scheme("production")
+            .expect("production scheme missing");
+        assert_eq!(production.background, (0x40, 0, 0));
+        assert_eq!(production.foreground, (0xee, 0xee, 0xee));
+        assert_eq!(config.color_scheme.as_deref(), Some("production"));
+        assert_eq!(
+            config.color_scheme_names().collect::<Vec<_>>(),
+            ["light", "dark", "production"]
+        );
+
+        assert!(Config::parse(r#"{"color_scheme": "dark"}"#).is_ok());
+        assert!(Config::parse(r#"{"color_scheme": "staging"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_key_combo() {
+        let combo: KeyCombo = "Ctrl+Shift+t".parse().expect("failed to parse combo");
+        assert_eq!(
+            combo,
+            KeyCombo {
+                key: Key::T,
+                ctrl: true,
+                shift: true,
+                alt: false,
+            }
+        );
+
+        let combo: KeyCombo = "F5".parse().expect("failed to parse combo");
+        assert_eq!(combo.key, Key::F5);
+        assert!(!combo.ctrl && !combo.shift && !combo.alt);
+
+        assert_eq!(combo.to_string(), "F5");
+        let combo: KeyCombo = "shift+ctrl+k".parse().expect("failed to parse combo");
+        assert_eq!(combo.to_string(), "Ctrl+Shift+K");
+        assert!("Hyper+A".parse::<KeyCombo>().is_err());
+        assert!("Ctrl+".parse::<KeyCombo>().is_err());
+        assert!("Ctrl+NotAKey".parse::<KeyCombo>().is_err());
+    }
+
+    #[test]
+    fn test_unescape_macro() {
+        assert_eq!(
+            unescape_macro(r"cargo test\n").expect("valid"),
+            b"cargo test\n"
+        );
+        assert_eq!(
+            unescape_macro(r"\e[A\x03\\").expect("valid"),
+            b"\x1b[A\x03\\"
+        );
+        assert!(unescape_macro(r"\").is_err());
+        assert!(unescape_macro(r"\q").is_err());
+        assert!(unescape_macro(r"\x3").is_err());
+    }
+
+    #[test]
+    fn test_parse_macros() {
+        let config = Config::parse(r#"{"macros": {"F5": "cargo test\n", "Alt+Up": "\\e[1;3A"}}"#)
+            .expect("failed to parse config");
+
+        let mut macros = config.macros;
+        macros.sort_by_key(|m| m.payload.clone());
+        assert_eq!(macros.len(), 2);
+        assert_eq!(macros[0].combo.key, Key::ArrowUp);
+        assert!(macros[0].combo.alt);
+        assert_eq!(macros[0].payload, b"\x1b[1;3A");
+        assert_eq!(macros[1].combo.key, Key::F5);
+        assert_eq!(macros[1].payload, b"cargo test\n");
+
+        assert!(Config::parse(r#"{"macros": {"F5": 1}}"#).is_err());
+        assert!(Config::parse(r#"{"macros": {"Bogus": "a"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Config::parse("[]").is_err());
+        assert!(Config::parse("{").is_err());
+        assert!(Config::parse(r#"{"confirm_close": 1}"#).is_err());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        let temp_dir = tempfile::TempDir::new().expect("failed to create tmp dir");
+        let config =
+            Config::load(&temp_dir.path().join("config.json")).expect("failed to load config");
+        assert_eq!(config, Config::default());
+    }
+}
@@ -0,0 +1,461 @@
+//! Keeping a session running without a window. The daemon runs the shell and an emulator of its
+//! own, and listens on a Unix socket. A window started with `--attach` picks up from a snapshot
+//! of the daemon's terminal and follows the output from there, and closing it leaves the shell
+//! running for the next one. One window can be attached at a time, and one that stops reading is
+//! dropped once it falls too far behind rather than holding up the shell
+//!
+//! While a window is attached its emulator answers the child's queries, so the daemon's own
+//! answers are dropped instead of being sent twice
+
+use crate::{
+    error::backtraced_err,
+    terminal_emulator::{
+        InvalidSessionMessage, Pty, PtyIo, ReadResponse, SessionDecoder, SessionMessage,
+        ShellOptions, TermIo, TermIoErr, TerminalEmulator, TERMINAL_HEIGHT, TERMINAL_WIDTH,
+    },
+};
+
+use thiserror::Error;
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{Read, Write},
+    os::unix::{
+        fs::FileTypeExt,
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long output is still collected after the child exited, see [`crate::headless`]
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+/// Output a client may fall behind by before it is dropped. The daemon never waits on a
+/// client, so that a stalled window cannot stop the shell
+const MAX_CLIENT_BACKLOG: usize = 4 * 1024 * 1024;
+
+#[derive(Default)]
+struct Forwarded {
+    // Child output the emulator has not read yet
+    output: Vec<u8>,
+    // What the emulator wrote back
+    replies: Vec<u8>,
+}
+
+/// Stands in for the pty so that the daemon sees everything that goes through the emulator
+struct ForwardIo(Rc<RefCell<Forwarded>>);
+
+impl TermIo for ForwardIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
+        let output = &mut self.0.borrow_mut().output;
+        let len = buf.len().min(output.len());
+        buf[..len].copy_from_slice(&output[..len]);
+        output.drain(..len);
+        match len {
+            0 => Ok(ReadResponse::Empty),
+            v => Ok(ReadResponse::Success(v)),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
+        self.0.borrow_mut().replies.extend(buf);
+        Ok(buf.len())
+    }
+
+    /// The daemon resizes the pty itself
+    fn set_win_size(&mut self, _width: usize, _height: usize) -> Result<(), TermIoErr> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+enum ClientError {
+    #[error("failed to set client socket as non-blocking")]
+    SetNonblock(#[source] std::io::Error),
+    #[error("failed to write to client")]
+    Write(#[source] std::io::Error),
+    #[error("failed to read from client")]
+    Read(#[source] std::io::Error),
+    #[error("client hung up")]
+    Closed,
+    #[error("client sent an invalid message")]
+    InvalidMessage(#[source] InvalidSessionMessage),
+    #[error("client fell more than {MAX_CLIENT_BACKLOG} bytes behind")]
+    FellBehind,
+}
+
+struct Client {
+    // Non-blocking, the daemon's loop only ever does what the socket has room for
+    stream: UnixStream,
+    decoder: SessionDecoder,
+    // Encoded messages the socket had no room for yet, the snapshot first
+    outgoing: VecDeque<u8>,
+    // How much of `outgoing` is still the snapshot, which does not count as falling behind
+    snapshot_len: usize,
+}
+
+impl Client {
+    /// Queue the snapshot and start serving `stream`
+    fn attach(
+        stream: UnixStream,
+        terminal_emulator: &TerminalEmulator<ForwardIo>,
+    ) -> Option<Client> {
+        let snapshot = match terminal_emulator.snapshot() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Failed to snapshot terminal: {}", backtraced_err(&e));
+                return None;
+            }
+        };
+        // Accepted sockets do not reliably inherit the listener's mode
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("{}", backtraced_err(&ClientError::SetNonblock(e)));
+            return None;
+        }
+
+        let message = SessionMessage::Snapshot(snapshot.to_bytes()).encode();
+        let mut client = Client {
+            stream,
+            decoder: SessionDecoder::default(),
+            snapshot_len: message.len(),
+            outgoing: message.into(),
+        };
+        if let Err(e) = client.flush() {
+            error!("Failed to send snapshot: {}", backtraced_err(&e));
+            return None;
+        }
+        info!("Client attached");
+        Some(client)
+    }
+
+    /// Queue `message` and send as much as the socket takes
+    fn send(&mut self, message: &SessionMessage) -> Result<(), ClientError> {
+        self.outgoing.extend(message.encode());
+        if self.outgoing.len() - self.snapshot_len > MAX_CLIENT_BACKLOG {
+            return Err(ClientError::FellBehind);
+        }
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<(), ClientError> {
+        while !self.outgoing.is_empty() {
+            let (front, _) = self.outgoing.as_slices();
+            match self.stream.write(front) {
+                Ok(0) => return Err(ClientError::Closed),
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                    self.snapshot_len = self.snapshot_len.saturating_sub(written);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(ClientError::Write(e)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The messages that arrived since the last call
+    fn receive(&mut self) -> Result<Vec<SessionMessage>, ClientError> {
+        let mut buf = vec![0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(ClientError::Closed),
+                Ok(len) => self.decoder.push(&buf[..len]),
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(ClientError::Read(e)),
+            }
+        }
+
+        let mut ret = Vec::new();
+        while let Some(message) = self.decoder.next().map_err(ClientError::InvalidMessage)? {
+            ret.push(message);
+        }
+        Ok(ret)
+    }
+}
+
+/// Write the pty's pending output into the emulator and on to the client
+fn forward_output(pty: &mut PtyIo, forwarded: &RefCell<Forwarded>, client: &mut Option<Client>) {
+    let mut buf = vec![0; 4096];
+    loop {
+        let len = match pty.read(&mut buf) {
+            Ok(ReadResponse::Success(len)) => len,
+            Ok(ReadResponse::Empty) => break,
+            Err(e) => {
+                error!("Failed to read from child: {}", backtraced_err(&*e));
+                break;
+            }
+        };
+        forwarded.borrow_mut().output.extend(&buf[..len]);
+        if let Some(attached) = client {
+            let message = SessionMessage::Output(buf[..len].to_vec());
+            if let Err(e) = attached.send(&message) {
+                info!("Client detached: {}", backtraced_err(&e));
+                *client = None;
+            }
+        }
+    }
+}
+
+fn write_to_pty(pty: &mut PtyIo, mut buf: &[u8]) {
+    while !buf.is_empty() {
+        match pty.write(buf) {
+            Ok(written) => buf = &buf[written..],
+            Err(e) => {
+                error!("Failed to write to child: {}", backtraced_err(&*e));
+                return;
+            }
+        }
+    }
+}
+
+fn handle_message(
+    message: SessionMessage,
+    pty: &mut PtyIo,
+    terminal_emulator: &mut TerminalEmulator<ForwardIo>,
+) {
+    match message {
+        SessionMessage::Input(data) => write_to_pty(pty, &data),
+        SessionMessage::Resize { width, height } => {
+            let (width, height) = (usize::from(width), usize::from(height));
+            if let Err(e) = terminal_emulator.set_win_size(width, height) {
+                error!("Failed to resize terminal: {}", backtraced_err(&*e));
+            }
+            if let Err(e) = pty.set_win_size(width, height) {
+                error!("Failed to resize pty: {}", backtraced_err(&*e));
+            }
+        }
+        message => warn!("Ignoring unexpected message from client: {message:?}"),
+    }
+}
+
+/// Run the shell from `shell_options` until it exits, serving clients on `socket_path`. Returns
+/// the exit code of the shell
+pub fn run(socket_path: &Path, shell_options: &ShellOptions) -> i32 {
+    // A socket file that nothing answers on is left over from a daemon that did not get to
+    // clean up
+    if UnixStream::connect(socket_path).is_ok() {
+        error!("A daemon is already listening on {}", socket_path.display());
+        return 1;
+    }
+    let is_socket = std::fs::symlink_metadata(socket_path)
+        .is_ok_and(|metadata| metadata.file_type().is_socket());
+    if is_socket {
+        let _ = std::fs::remove_file(socket_path);
+    }
+    let listener = match UnixListener::bind(socket_path) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "Failed to listen on {}: {}",
+                socket_path.display(),
+                backtraced_err(&e)
+            );
+            return 1;
+        }
+    };
+    if let Err(e) = listener.set_nonblocking(true) {
+        error!(
+            "Failed to set listener as non-blocking: {}",
+            backtraced_err(&e)
+        );
+        return 1;
+    }
+
+    let exit_code = serve(&listener, shell_options);
+    if let Err(e) = std::fs::remove_file(socket_path) {
+        warn!("Failed to remove {}: {e}", socket_path.display());
+    }
+    exit_code
+}
+
+fn serve(listener: &UnixListener, shell_options: &ShellOptions) -> i32 {
+    let mut pty = match PtyIo::spawn(shell_options) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to start shell: {}", backtraced_err(&e));
+            return 1;
+        }
+    };
+    if let Err(e) = pty.set_win_size(TERMINAL_WIDTH, TERMINAL_HEIGHT) {
+        error!("Failed to set initial window size: {}", backtraced_err(&*e));
+    }
+
+    let forwarded = Rc::new(RefCell::new(Forwarded::default()));
+    let mut terminal_emulator = TerminalEmulator::with_io(
+        ForwardIo(Rc::clone(&forwarded)),
+        TERMINAL_WIDTH,
+        TERMINAL_HEIGHT,
+        "recordings".into(),
+    );
+    let mut client: Option<Client> = None;
+
+    let exit_code = loop {
+        match listener.accept() {
+            Ok((stream, _)) if client.is_none() => {
+                // Whatever arrived since the last read belongs before the snapshot
+                forward_output(&mut pty, &forwarded, &mut client);
+                terminal_emulator.read();
+                forwarded.borrow_mut().replies.clear();
+                client = Client::attach(stream, &terminal_emulator);
+            }
+            Ok(_) => warn!("Refusing a second client"),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => (),
+            Err(e) => error!("Failed to accept client: {}", backtraced_err(&e)),
+        }
+
+        forward_output(&mut pty, &forwarded, &mut client);
+        terminal_emulator.read();
+        let replies = std::mem::take(&mut forwarded.borrow_mut().replies);
+        if client.is_none() {
+            write_to_pty(&mut pty, &replies);
+        }
+
+        if let Some(attached) = &mut client {
+            match attached.flush().and_then(|_| attached.receive()) {
+                Ok(messages) => {
+                    for message in messages {
+                        handle_message(message, &mut pty, &mut terminal_emulator);
+                    }
+                }
+                Err(e) => {
+                    info!("Client detached: {}", backtraced_err(&e));
+                    client = None;
+                }
+            }
+        }
+
+        match pty.child_exit_status() {
+            Ok(Some(code)) => break code,
+            Ok(None) => (),
+            Err(e) => {
+                error!("failed to check child status: {}", backtraced_err(&e));
+                break 1;
+            }
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    // The client should see the last of the output before the daemon hangs up on it
+    let drain_start = Instant::now();
+    while !pty.is_output_closed() && drain_start.elapsed() < DRAIN_TIMEOUT {
+        forward_output(&mut pty, &forwarded, &mut client);
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    forward_output(&mut pty, &forwarded, &mut client);
+    // along with what its socket had no room for yet, as long as it keeps reading
+    if let Some(attached) = &mut client {
+        let flush_start = Instant::now();
+        while !attached.outgoing.is_empty() && flush_start.elapsed() < DRAIN_TIMEOUT {
+            if attached.flush().is_err() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+    exit_code
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::terminal_emulator::{cells_to_string, AttachIo};
+
+    fn read_until(terminal_emulator: &mut TerminalEmulator<AttachIo>, text: &str) {
+        let start = Instant::now();
+        loop {
+            terminal_emulator.read();
+            let screen = cells_to_string(terminal_emulator.data().visible);
+            if screen.contains(text) {
+                return;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{text:?} did not show up, screen is {screen:?}"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_daemon_attach() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let socket_path = dir.path().join("session");
+        let shell_options = ShellOptions {
+            command: Some("printf 'first\\n'; read line; echo \"got $line\"".to_string()),
+            ..Default::default()
+        };
+        let daemon = {
+            let socket_path = socket_path.clone();
+            std::thread::spawn(move || run(&socket_path, &shell_options))
+        };
+
+        let start = Instant::now();
+        let mut io = loop {
+            match AttachIo::spawn(&socket_path) {
+                Ok(io) => break io,
+                Err(_) if start.elapsed() < Duration::from_secs(5) => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(e) => panic!("failed to attach: {}", backtraced_err(&e)),
+            }
+        };
+        let snapshot = io.take_snapshot().expect("no snapshot");
+        let mut terminal_emulator =
+            TerminalEmulator::from_snapshot(snapshot, io).expect("failed to load snapshot");
+        read_until(&mut terminal_emulator, "first");
+
+        terminal_emulator
+            .write_bytes(b"typed\r")
+            .expect("failed to write");
+        read_until(&mut terminal_emulator, "got typed");
+        assert_eq!(daemon.join().expect("daemon panicked"), 0);
+        assert!(!socket_path.exists());
+    }
+
+    #[test]
+    fn test_stalled_client_is_dropped() {
+        let (stream, _peer) = UnixStream::pair().expect("failed to create socket pair");
+        let terminal_emulator =
+            TerminalEmulator::with_io(ForwardIo(Default::default()), 10, 3, "recordings".into());
+        let mut client = Client::attach(stream, &terminal_emulator).expect("failed to attach");
+
+        // Sending never waits for the peer, that never reads, until it is too far behind
+        let output = SessionMessage::Output(vec![b'x'; 64 * 1024]);
+        let mut sent = 0;
+        let err = loop {
+            match client.send(&output) {
+                Ok(()) => sent += 64 * 1024,
+                Err(e) => break e,
+            }
+            assert!(sent <= 2 * MAX_CLIENT_BACKLOG, "backlog is not bounded");
+        };
+        assert!(matches!(err, ClientError::FellBehind), "{err:?}");
+        assert!(sent >= MAX_CLIENT_BACKLOG);
+
+        // A client that reads gets the snapshot first
+        let (stream, mut peer) = UnixStream::pair().expect("failed to create socket pair");
+        let mut client = Client::attach(stream, &terminal_emulator).expect("failed to attach");
+        client
+            .send(&SessionMessage::Output(b"hi".to_vec()))
+            .expect("failed to send");
+        let mut decoder = SessionDecoder::default();
+        let mut messages = Vec::new();
+        let mut buf = vec![0; 4096];
+        while messages.len() < 2 {
+            client.flush().expect("failed to flush");
+            let len = peer.read(&mut buf).expect("failed to read");
+            decoder.push(&buf[..len]);
+            while let Some(message) = decoder.next().expect("failed to decode") {
+                messages.push(message);
+            }
+        }
+        assert!(matches!(messages[0], SessionMessage::Snapshot(_)));
+        assert_eq!(messages[1], SessionMessage::Output(b"hi".to_vec()));
+    }
+}
@@ -0,0 +1,71 @@
+//! Marking the window title when the terminal produced output or rang the bell while the window
+//! was not focused, like the activity and bell monitoring of tmux and screen. The marks go away
+//! once the window is focused again
+
+/// Title prefixes for the marks, the bell wins over plain activity
+const BELL_MARK: &str = "🔔 ";
+const ACTIVITY_MARK: &str = "● ";
+
+pub struct ActivityMonitor {
+    /// Whether output marks the title, the bell always does
+    pub monitor_output: bool,
+    activity: bool,
+    bell: bool,
+}
+
+impl ActivityMonitor {
+    pub fn new(monitor_output: bool) -> ActivityMonitor {
+        ActivityMonitor {
+            monitor_output,
+            activity: false,
+            bell: false,
+        }
+    }
+
+    /// Record what happened this frame. Returns whether the title prefix changed
+    pub fn update(&mut self, focused: bool, output: bool, bell: bool) -> bool {
+        let before = self.title_prefix();
+        if focused {
+            self.activity = false;
+            self.bell = false;
+        } else {
+            self.activity |= output && self.monitor_output;
+            self.bell |= bell;
+        }
+        before != self.title_prefix()
+    }
+
+    pub fn title_prefix(&self) -> &'static str {
+        if self.bell {
+            BELL_MARK
+        } else if self.activity {
+            ACTIVITY_MARK
+        } else {
+            ""
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_activity_monitor() {
+        let mut monitor = ActivityMonitor::new(true);
+        assert!(!monitor.update(true, true, true));
+        assert_eq!(monitor.title_prefix(), "");
+
+        assert!(monitor.update(false, true, false));
+        assert_eq!(monitor.title_prefix(), ACTIVITY_MARK);
+        assert!(monitor.update(false, false, true));
+        assert_eq!(monitor.title_prefix(), BELL_MARK);
+        assert!(!monitor.update(false, true, false));
+
+        assert!(monitor.update(true, false, false));
+        assert_eq!(monitor.title_prefix(), "");
+
+        monitor.monitor_output = false;
+        assert!(!monitor.update(false, true, false));
+    }
+}
@@ -0,0 +1,115 @@
+//! Optional image drawn between the default terminal background and the text
+
+use crate::{
+    config::{BackgroundImage, ImageScaling},
+    error::backtraced_err,
+};
+use eframe::egui::{
+    Color32, ColorImage, Context, Pos2, Rect, TextureHandle, TextureOptions, Ui, Vec2,
+};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+#[error("failed to load {0}")]
+struct LoadBackgroundImageError(PathBuf, #[source] image::ImageError);
+
+fn load_texture(ctx: &Context, path: &Path) -> Result<TextureHandle, LoadBackgroundImageError> {
+    let image = image::open(path)
+        .map_err(|e| LoadBackgroundImageError(path.to_path_buf(), e))?
+        .to_rgba8();
+    let size = [image.width() as usize, image.height() as usize];
+    let image = ColorImage::from_rgba_unmultiplied(size, image.as_raw());
+    Ok(ctx.load_texture("background_image", image, TextureOptions::LINEAR))
+}
+
+/// Where an image of `image_size` is drawn for `area`. The result can be larger than `area` and
+/// has to be clipped to it
+fn image_rect(scaling: ImageScaling, image_size: Vec2, area: Rect) -> Rect {
+    let x_scale = area.width() / image_size.x;
+    let y_scale = area.height() / image_size.y;
+    let size = match scaling {
+        ImageScaling::Stretch => return area,
+        ImageScaling::Center => image_size,
+        ImageScaling::Fill => image_size * x_scale.max(y_scale),
+        ImageScaling::Fit => image_size * x_scale.min(y_scale),
+    };
+    Rect::from_center_size(area.center(), size)
+}
+
+pub struct BackgroundImageRenderer {
+    config: Option<BackgroundImage>,
+    // Loaded on the first frame. A failed load is remembered as None so that it is not retried
+    // and logged every frame
+    texture: Option<Option<TextureHandle>>,
+}
+
+impl BackgroundImageRenderer {
+    pub fn new(config: Option<BackgroundImage>) -> BackgroundImageRenderer {
+        BackgroundImageRenderer {
+            config,
+            texture: None,
+        }
+    }
+
+    pub fn render(&mut self, ui: &Ui, area: Rect) {
+        let Some(config) = &self.config else {
+            return;
+        };
+
+        let texture =
+            self.texture
+                .get_or_insert_with(|| match load_texture(ui.ctx(), &config.path) {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        error!("Failed to load background image: {}", backtraced_err(&e));
+                        None
+                    }
+                });
+        let Some(texture) = texture else {
+            return;
+        };
+
+        let image_size = texture.size_vec2();
+        if image_size.x == 0.0 || image_size.y == 0.0 {
+            return;
+        }
+
+        let painter = ui.painter().with_clip_rect(area);
+        painter.image(
+            texture.id(),
+            image_rect(config.scaling, image_size, area),
+            Rect::from_min_max(Pos2::ZERO, Pos2::new(1.0, 1.0)),
+            Color32::WHITE.gamma_multiply(config.opacity),
+        );
+
+        if config.darken > 0.0 {
+            painter.rect_filled(area, 0.0, Color32::BLACK.gamma_multiply(config.darken));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_image_rect() {
+        let area = Rect::from_min_size(Pos2::new(10.0, 10.0), Vec2::new(200.0, 100.0));
+        let image_size = Vec2::new(100.0, 100.0);
+
+        assert_eq!(image_rect(ImageScaling::Stretch, image_size, area), area);
+        assert_eq!(
+            image_rect(ImageScaling::Fill, image_size, area),
+            Rect::from_min_size(Pos2::new(10.0, -40.0), Vec2::new(200.0, 200.0))
+        );
+        assert_eq!(
+            image_rect(ImageScaling::Fit, image_size, area),
+            Rect::from_min_size(Pos2::new(60.0, 10.0), Vec2::new(100.0, 100.0))
+        );
+        assert_eq!(
+            image_rect(ImageScaling::Center, Vec2::new(20.0, 10.0), area),
+            Rect::from_min_size(Pos2::new(100.0, 55.0), Vec2::new(20.0, 10.0))
+        );
+    }
+}
@@ -0,0 +1,223 @@
+//! Box-drawing, block and Powerline separator glyphs drawn with the painter. Font glyphs for
+//! these rarely fill their cell exactly, and rounding leaves hairline gaps between neighbouring
+//! cells. Drawn shapes reach the cell edges, which are snapped to pixels so that neighbours
+//! share them
+
+use eframe::egui::{self, Color32, Pos2, Rect, Shape, Stroke};
+
+/// Arm weights from the cell center as multiples of the light line width, in the order up,
+/// right, down, left
+type Arms = [u8; 4];
+
+enum BuiltinGlyph {
+    Lines(Arms),
+    // Rounded corner, joining the arms in the horizontal and vertical direction given as -1 or 1
+    Arc { dx: f32, dy: f32 },
+    // Part of the cell as fractions of it
+    Block(Rect),
+    // Powerline separator pointing right or left, either solid or just the outline
+    Separator { right: bool, solid: bool },
+}
+
+fn builtin_glyph(c: char) -> Option<BuiltinGlyph> {
+    use BuiltinGlyph::*;
+
+    let block =
+        |min: (f32, f32), max: (f32, f32)| Block(Rect::from_min_max(min.into(), max.into()));
+
+    let ret = match c {
+        '─' => Lines([0, 1, 0, 1]),
+        '━' => Lines([0, 2, 0, 2]),
+        '│' => Lines([1, 0, 1, 0]),
+        '┃' => Lines([2, 0, 2, 0]),
+        '┌' => Lines([0, 1, 1, 0]),
+        '┏' => Lines([0, 2, 2, 0]),
+        '┐' => Lines([0, 0, 1, 1]),
+        '┓' => Lines([0, 0, 2, 2]),
+        '└' => Lines([1, 1, 0, 0]),
+        '┗' => Lines([2, 2, 0, 0]),
+        '┘' => Lines([1, 0, 0, 1]),
+        '┛' => Lines([2, 0, 0, 2]),
+        '├' => Lines([1, 1, 1, 0]),
+        '┣' => Lines([2, 2, 2, 0]),
+        '┤' => Lines([1, 0, 1, 1]),
+        '┫' => Lines([2, 0, 2, 2]),
+        '┬' => Lines([0, 1, 1, 1]),
+        '┳' => Lines([0, 2, 2, 2]),
+        '┴' => Lines([1, 1, 0, 1]),
+        '┻' => Lines([2, 2, 0, 2]),
+        '┼' => Lines([1, 1, 1, 1]),
+        '╋' => Lines([2, 2, 2, 2]),
+        '╴' => Lines([0, 0, 0, 1]),
+        '╵' => Lines([1, 0, 0, 0]),
+        '╶' => Lines([0, 1, 0, 0]),
+        '╷' => Lines([0, 0, 1, 0]),
+        '╸' => Lines([0, 0, 0, 2]),
+        '╹' => Lines([2, 0, 0, 0]),
+        '╺' => Lines([0, 2, 0, 0]),
+        '╻' => Lines([0, 0, 2, 0]),
+        '╭' => Arc { dx: 1.0, dy: 1.0 },
+        '╮' => Arc { dx: -1.0, dy: 1.0 },
+        '╯' => Arc { dx: -1.0, dy: -1.0 },
+        '╰' => Arc { dx: 1.0, dy: -1.0 },
+        '█' => block((0.0, 0.0), (1.0, 1.0)),
+        '▀' => block((0.0, 0.0), (1.0, 0.5)),
+        '▄' => block((0.0, 0.5), (1.0, 1.0)),
+        '▌' => block((0.0, 0.0), (0.5, 1.0)),
+        '▐' => block((0.5, 0.0), (1.0, 1.0)),
+        '\u{e0b0}' => Separator {
+            right: true,
+            solid: true,
+        },
+        '\u{e0b1}' => Separator {
+            right: true,
+            solid: false,
+        },
+        '\u{e0b2}' => Separator {
+            right: false,
+            solid: true,
+        },
+        '\u{e0b3}' => Separator {
+            right: false,
+            solid: false,
+        },
+        _ => return None,
+    };
+    Some(ret)
+}
+
+/// Whether `c` is drawn by [`glyph_shapes`] instead of the font
+pub fn is_builtin(c: char) -> bool {
+    builtin_glyph(c).is_some()
+}
+
+/// Shapes that draw `c` in `cell`, or None if the font has to draw it
+pub fn glyph_shapes(
+    c: char,
+    cell: Rect,
+    color: Color32,
+    pixels_per_point: f32,
+) -> Option<Vec<Shape>> {
+    let snap = |v: f32| (v * pixels_per_point).round() / pixels_per_point;
+    let cell = Rect::from_min_max(
+        egui::pos2(snap(cell.left()), snap(cell.top())),
+        egui::pos2(snap(cell.right()), snap(cell.bottom())),
+    );
+    let light = (cell.width() / 8.0 * pixels_per_point).round().max(1.0) / pixels_per_point;
+    let center = egui::pos2(snap(cell.center().x), snap(cell.center().y));
+    let x_at = |fraction: f32| snap(cell.left() + cell.width() * fraction);
+    let y_at = |fraction: f32| snap(cell.top() + cell.height() * fraction);
+
+    let shapes = match builtin_glyph(c)? {
+        BuiltinGlyph::Lines(arms) => {
+            // Every arm starts on the far side of the thickest one, so that junctions are filled
+            let overlap = f32::from(*arms.iter().max().unwrap_or(&0)) * light / 2.0;
+            let [up, right, down, left] = arms.map(|weight| f32::from(weight) * light / 2.0);
+            let horizontal = |half: f32, x: (f32, f32)| {
+                Rect::from_min_max(
+                    egui::pos2(x.0, center.y - half),
+                    egui::pos2(x.1, center.y + half),
+                )
+            };
+            let vertical = |half: f32, y: (f32, f32)| {
+                Rect::from_min_max(
+                    egui::pos2(center.x - half, y.0),
+                    egui::pos2(center.x + half, y.1),
+                )
+            };
+            [
+                (up, vertical(up, (cell.top(), center.y + overlap))),
+                (right, horizontal(right, (center.x - overlap, cell.right()))),
+                (down, vertical(down, (center.y - overlap, cell.bottom()))),
+                (left, horizontal(left, (cell.left(), center.x + overlap))),
+            ]
+            .into_iter()
+            .filter(|(half, _)| *half > 0.0)
+            .map(|(_, rect)| Shape::rect_filled(rect, 0.0, color))
+            .collect()
+        }
+        BuiltinGlyph::Arc { dx, dy } => {
+            const ARC_SEGMENTS: usize = 8;
+            let radius = (cell.width() / 2.0).min(cell.height() / 2.0);
+            let arc_center = center + egui::vec2(dx * radius, dy * radius);
+            let x_edge = if dx > 0.0 { cell.right() } else { cell.left() };
+            let y_edge = if dy > 0.0 { cell.bottom() } else { cell.top() };
+
+            let arc = (0..=ARC_SEGMENTS).map(|i| {
+                let angle = i as f32 / ARC_SEGMENTS as f32 * std::f32::consts::FRAC_PI_2;
+                arc_center - egui::vec2(dx * radius * angle.cos(), dy * radius * angle.sin())
+            });
+            let points = std::iter::once(egui::pos2(center.x, y_edge))
+                .chain(arc)
+                .chain(std::iter::once(egui::pos2(x_edge, center.y)))
+                .collect();
+            vec![Shape::line(points, Stroke::new(light, color))]
+        }
+        BuiltinGlyph::Block(part) => {
+            let rect = Rect::from_min_max(
+                egui::pos2(x_at(part.min.x), y_at(part.min.y)),
+                egui::pos2(x_at(part.max.x), y_at(part.max.y)),
+            );
+            vec![Shape::rect_filled(rect, 0.0, color)]
+        }
+        BuiltinGlyph::Separator { right, solid } => {
+            let (base, tip) = if right {
+                (cell.left(), cell.right())
+            } else {
+                (cell.right(), cell.left())
+            };
+            let points: Vec<Pos2> = vec![
+                egui::pos2(base, cell.top()),
+                egui::pos2(tip, center.y),
+                egui::pos2(base, cell.bottom()),
+            ];
+            if solid {
+                vec![Shape::convex_polygon(points, color, Stroke::NONE)]
+            } else {
+                vec![Shape::line(points, Stroke::new(light, color))]
+            }
+        }
+    };
+    Some(shapes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glyph_shapes() {
+        let cell = Rect::from_min_size(egui::pos2(8.3, 16.0), egui::vec2(8.4, 16.0));
+        assert!(glyph_shapes('a', cell, Color32::WHITE, 1.0).is_none());
+        assert!(!is_builtin('a'));
+
+        // Lines reach the snapped cell edges so that neighbours join
+        let rects = |c| {
+            glyph_shapes(c, cell, Color32::WHITE, 1.0)
+                .expect("not builtin")
+                .into_iter()
+                .map(|shape| shape.visual_bounding_rect())
+                .collect::<Vec<_>>()
+        };
+        let horizontal = rects('─');
+        assert_eq!(horizontal.len(), 2);
+        assert_eq!(horizontal[0].right(), 17.0);
+        assert_eq!(horizontal[1].left(), 8.0);
+        assert!(horizontal.iter().all(|r| r.height() == 1.0));
+
+        let cross = rects('╋');
+        assert_eq!(cross.len(), 4);
+        assert_eq!(cross[0].top(), 16.0);
+        assert_eq!(cross[2].bottom(), 32.0);
+        assert!(cross.iter().all(|r| r.width().min(r.height()) == 2.0));
+
+        assert_eq!(
+            rects('▄'),
+            [Rect::from_min_max(
+                egui::pos2(8.0, 24.0),
+                egui::pos2(17.0, 32.0)
+            )]
+        );
+        assert!(is_builtin('\u{e0b0}'));
+    }
+}
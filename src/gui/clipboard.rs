@@ -0,0 +1,142 @@
+//! Where copied text goes and pasted text comes from. egui's clipboard is the default. Commands
+//! like `wl-copy` or a script that forwards to a remote machine can take its place where that
+//! one does not work
+
+use eframe::egui::Context;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use crate::config::Config;
+
+pub trait Clipboard {
+    fn copy(&mut self, ctx: &Context, text: String);
+
+    /// Text to paste for the paste shortcut, given what egui read from its own clipboard
+    fn paste(&mut self, egui_text: String) -> String;
+}
+
+pub struct EguiClipboard;
+
+impl Clipboard for EguiClipboard {
+    fn copy(&mut self, ctx: &Context, text: String) {
+        ctx.copy_text(text);
+    }
+
+    fn paste(&mut self, egui_text: String) -> String {
+        egui_text
+    }
+}
+
+/// Runs the configured commands, falling back to egui's clipboard for the one that is not set.
+/// egui only reports the paste shortcut while its own clipboard has text, so the paste command
+/// cannot be used without one
+pub struct CommandClipboard {
+    copy_command: Option<Vec<String>>,
+    paste_command: Option<Vec<String>>,
+}
+
+fn command(argv: &[String]) -> Command {
+    let mut command = Command::new(&argv[0]);
+    command.args(&argv[1..]);
+    command
+}
+
+/// Run `argv` with `text` on its standard input
+fn run_copy_command(argv: &[String], text: &str) -> std::io::Result<()> {
+    let mut child = command(argv).stdin(Stdio::piped()).spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let res = stdin.write_all(text.as_bytes());
+    // Closing stdin is how the command knows that the text is complete
+    drop(stdin);
+    let status = child.wait()?;
+    res?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("exited with {status}")));
+    }
+    Ok(())
+}
+
+fn run_paste_command(argv: &[String]) -> std::io::Result<String> {
+    let output = command(argv).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "exited with {}",
+            output.status
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n"))
+}
+
+impl Clipboard for CommandClipboard {
+    fn copy(&mut self, ctx: &Context, text: String) {
+        let Some(argv) = self.copy_command.clone() else {
+            ctx.copy_text(text);
+            return;
+        };
+
+        // Some commands, like xclip, stay around to serve the clipboard, so this must not block
+        // a frame
+        std::thread::spawn(move || {
+            if let Err(e) = run_copy_command(&argv, &text) {
+                warn!("Clipboard copy command failed: {e}");
+            }
+        });
+    }
+
+    fn paste(&mut self, egui_text: String) -> String {
+        let Some(argv) = &self.paste_command else {
+            return egui_text;
+        };
+
+        match run_paste_command(argv) {
+            Ok(text) => text,
+            Err(e) => {
+                warn!("Clipboard paste command failed, pasting egui's clipboard instead: {e}");
+                egui_text
+            }
+        }
+    }
+}
+
+/// The clipboard the config asks for
+pub fn from_config(config: &Config) -> Box<dyn Clipboard> {
+    if config.clipboard_copy_command.is_none() && config.clipboard_paste_command.is_none() {
+        return Box::new(EguiClipboard);
+    }
+
+    Box::new(CommandClipboard {
+        copy_command: config.clipboard_copy_command.clone(),
+        paste_command: config.clipboard_paste_command.clone(),
+    })
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    fn argv(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_clipboard_commands() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("clipboard");
+        let copy = argv(&["sh", "-c", &format!("cat > '{}'", path.display())]);
+        run_copy_command(&copy, "copied").expect("copy command failed");
+        assert_eq!(std::fs::read(&path).expect("failed to read"), b"copied");
+        assert!(run_copy_command(&argv(&["false"]), "copied").is_err());
+
+        let mut clipboard = CommandClipboard {
+            copy_command: None,
+            paste_command: Some(argv(&["printf", "a\\r\\nb"])),
+        };
+        assert_eq!(clipboard.paste("egui".to_string()), "a\nb");
+        clipboard.paste_command = Some(argv(&["false"]));
+        assert_eq!(clipboard.paste("egui".to_string()), "egui");
+        clipboard.paste_command = None;
+        assert_eq!(clipboard.paste("egui".to_string()), "egui");
+    }
+}
@@ -0,0 +1,81 @@
+//! Blinking of the cursor. Typing restarts the blink with the cursor shown and holds it still for
+//! a moment, so that the cursor does not vanish while it is being moved around
+
+use std::time::{Duration, Instant};
+
+/// How long the cursor stays on, and then off, while it blinks
+pub const BLINK_INTERVAL: Duration = Duration::from_millis(600);
+
+pub struct CursorBlink {
+    pub enable: bool,
+    /// How long the cursor stays solid after a keystroke
+    pause: Duration,
+    // Last keystroke, the blink is timed from it
+    phase_start: Instant,
+}
+
+impl CursorBlink {
+    pub fn new(enable: bool, pause: Duration, now: Instant) -> CursorBlink {
+        CursorBlink {
+            enable,
+            pause,
+            phase_start: now,
+        }
+    }
+
+    pub fn key_pressed(&mut self, now: Instant) {
+        self.phase_start = now;
+    }
+
+    /// The cursor is held on for the pause and the first interval after it, then alternates
+    fn phase(&self, now: Instant) -> (bool, Duration) {
+        let since = now.saturating_duration_since(self.phase_start);
+        if since < self.pause {
+            return (true, self.pause - since + BLINK_INTERVAL);
+        }
+
+        let interval = BLINK_INTERVAL.as_nanos();
+        let blinking = (since - self.pause).as_nanos();
+        let visible = (blinking / interval).is_multiple_of(2);
+        let remaining = Duration::from_nanos((interval - blinking % interval) as u64);
+        (visible, remaining)
+    }
+
+    pub fn is_visible(&self, now: Instant) -> bool {
+        !self.enable || self.phase(now).0
+    }
+
+    /// Time until the cursor turns on or off, or `None` when it does not blink
+    pub fn next_change(&self, now: Instant) -> Option<Duration> {
+        self.enable.then(|| self.phase(now).1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cursor_blink() {
+        let start = Instant::now();
+        let pause = Duration::from_millis(500);
+        let mut blink = CursorBlink::new(true, pause, start);
+        assert!(blink.is_visible(start));
+        assert_eq!(blink.next_change(start), Some(pause + BLINK_INTERVAL));
+
+        let off = start + pause + BLINK_INTERVAL;
+        assert!(blink.is_visible(off - Duration::from_millis(1)));
+        assert!(!blink.is_visible(off));
+        assert_eq!(blink.next_change(off), Some(BLINK_INTERVAL));
+        assert!(blink.is_visible(off + BLINK_INTERVAL));
+
+        // Typing brings the cursor back and starts the pause over
+        blink.key_pressed(off);
+        assert!(blink.is_visible(off));
+        assert!(blink.is_visible(off + pause));
+
+        blink.enable = false;
+        assert!(blink.is_visible(start + pause + BLINK_INTERVAL));
+        assert_eq!(blink.next_change(start), None);
+    }
+}
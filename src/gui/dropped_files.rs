@@ -0,0 +1,127 @@
+//! Files dropped onto the window. Their paths are typed at the cursor, or with Shift held what is
+//! in them is pasted instead
+
+use crate::error::backtraced_err;
+use eframe::egui::DroppedFile;
+use std::path::Path;
+
+/// Quote `arg` for a POSIX shell. Arguments made only of characters that are never special are
+/// left as they are
+#[cfg(unix)]
+fn shell_quote(arg: &[u8]) -> Vec<u8> {
+    let is_plain = |b: &u8| b.is_ascii_alphanumeric() || b"_-+=.,:/@%".contains(b);
+    if !arg.is_empty() && arg.iter().all(is_plain) {
+        return arg.to_vec();
+    }
+
+    // Nothing is special inside single quotes, single quotes themselves have to be closed,
+    // escaped and reopened
+    let mut ret = vec![b'\''];
+    for b in arg {
+        match b {
+            b'\'' => ret.extend(b"'\\''"),
+            b => ret.push(*b),
+        }
+    }
+    ret.push(b'\'');
+    ret
+}
+
+#[cfg(unix)]
+fn quoted_path(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    shell_quote(path.as_os_str().as_bytes())
+}
+
+/// cmd.exe has no escaping inside double quotes, but paths cannot contain them either
+#[cfg(windows)]
+fn quoted_path(path: &Path) -> Vec<u8> {
+    let path = path.to_string_lossy();
+    if path
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b"_-.:\\/".contains(&b))
+    {
+        return path.into_owned().into_bytes();
+    }
+    format!("\"{path}\"").into_bytes()
+}
+
+/// The quoted paths of `files` separated by spaces
+pub fn paths_payload(files: &[DroppedFile]) -> Vec<u8> {
+    let paths: Vec<_> = files
+        .iter()
+        .filter_map(|file| file.path.as_ref())
+        .map(|path| quoted_path(path))
+        .collect();
+    paths.join(&b' ')
+}
+
+/// What is in `files`, one after another. Files that cannot be read are skipped
+pub fn contents_payload(files: &[DroppedFile]) -> Vec<u8> {
+    let mut ret = Vec::new();
+    for file in files {
+        if let Some(bytes) = &file.bytes {
+            ret.extend(bytes.iter());
+            continue;
+        }
+
+        let Some(path) = &file.path else {
+            continue;
+        };
+        match std::fs::read(path) {
+            Ok(contents) => ret.extend(contents),
+            Err(e) => error!(
+                "Failed to read dropped file {}: {}",
+                path.display(),
+                backtraced_err(&e)
+            ),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn dropped(path: &str) -> DroppedFile {
+        DroppedFile {
+            path: Some(PathBuf::from(path)),
+            ..Default::default()
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote(b"/tmp/file-1.txt"), b"/tmp/file-1.txt");
+        assert_eq!(shell_quote(b"/tmp/my file"), b"'/tmp/my file'");
+        assert_eq!(shell_quote(b"it's"), b"'it'\\''s'");
+        assert_eq!(shell_quote(b"$HOME"), b"'$HOME'");
+        assert_eq!(shell_quote(b""), b"''");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_paths_payload() {
+        let files = [dropped("/a/b"), dropped("/c d"), DroppedFile::default()];
+        assert_eq!(paths_payload(&files), b"/a/b '/c d'");
+    }
+
+    #[test]
+    fn test_contents_payload() {
+        let temp_dir = tempfile::TempDir::new().expect("failed to create tmp dir");
+        let first = temp_dir.path().join("first");
+        let second = temp_dir.path().join("second");
+        std::fs::write(&first, "one\n").expect("failed to write file");
+        std::fs::write(&second, "two\n").expect("failed to write file");
+
+        let files = [
+            dropped(first.to_str().expect("path should be utf8")),
+            dropped("/does/not/exist"),
+            dropped(second.to_str().expect("path should be utf8")),
+        ];
+        assert_eq!(contents_payload(&files), b"one\ntwo\n");
+    }
+}
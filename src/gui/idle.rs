@@ -0,0 +1,58 @@
+//! Polling for terminal output only while something is happening. Output is read when a frame is
+//! drawn, so frames are requested at a steady rate after input or output, and no longer once the
+//! terminal has been quiet for a while. egui still repaints on input events by itself
+
+use eframe::egui;
+use std::time::{Duration, Instant};
+
+/// How often output is polled while the terminal is active
+pub const ACTIVE_POLL_INTERVAL: Duration = Duration::from_millis(16);
+
+pub struct IdleTracker {
+    timeout: Duration,
+    last_activity: Option<Instant>,
+}
+
+impl IdleTracker {
+    pub fn new(timeout: Duration) -> IdleTracker {
+        IdleTracker {
+            timeout,
+            last_activity: None,
+        }
+    }
+
+    pub fn record_activity(&mut self, now: Instant) {
+        self.last_activity = Some(now);
+    }
+
+    pub fn is_idle(&self, now: Instant) -> bool {
+        self.last_activity
+            .is_none_or(|last| now - last >= self.timeout)
+    }
+
+    /// Ask for the next frame, unless the terminal went idle
+    pub fn schedule_poll(&self, ctx: &egui::Context, now: Instant) {
+        if !self.is_idle(now) {
+            ctx.request_repaint_after(ACTIVE_POLL_INTERVAL);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_idle_tracker() {
+        let start = Instant::now();
+        let mut tracker = IdleTracker::new(Duration::from_secs(2));
+        assert!(tracker.is_idle(start));
+
+        tracker.record_activity(start);
+        assert!(!tracker.is_idle(start + Duration::from_secs(1)));
+        assert!(tracker.is_idle(start + Duration::from_secs(2)));
+
+        tracker.record_activity(start + Duration::from_secs(3));
+        assert!(!tracker.is_idle(start + Duration::from_secs(4)));
+    }
+}
@@ -0,0 +1,229 @@
+//! Recent keystrokes for the debug panel, along with the bytes each of them was sent to the child
+//! as, to see how keys are encoded. Keys that are only inspected are encoded the same way and
+//! listed as not sent
+
+use eframe::egui::{self, Event};
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Older keystrokes are dropped
+const MAX_KEYSTROKES: usize = 100;
+/// Anything longer, e.g. a paste, is cut short in the list
+const MAX_SHOWN_BYTES: usize = 32;
+
+struct Keystroke {
+    // Milliseconds since the epoch
+    time: u64,
+    label: String,
+    sent: Vec<u8>,
+    // False for keys that were only inspected
+    delivered: bool,
+}
+
+pub struct KeystrokeHistory {
+    keystrokes: VecDeque<Keystroke>,
+}
+
+impl KeystrokeHistory {
+    pub fn new() -> KeystrokeHistory {
+        KeystrokeHistory {
+            keystrokes: VecDeque::new(),
+        }
+    }
+
+    /// Remember that `event` was encoded as `sent`, and whether the child got it. A key press that
+    /// sends nothing itself is left out when `next` is the text it typed, like egui sends for
+    /// every character
+    pub fn push(&mut self, event: &Event, next: Option<&Event>, sent: Vec<u8>, delivered: bool) {
+        let Some(label) = describe_event(event) else {
+            return;
+        };
+        if sent.is_empty()
+            && matches!(event, Event::Key { .. })
+            && matches!(next, Some(Event::Text(_)))
+        {
+            return;
+        }
+
+        if self.keystrokes.len() == MAX_KEYSTROKES {
+            self.keystrokes.pop_front();
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        self.keystrokes.push_back(Keystroke {
+            time,
+            label,
+            sent,
+            delivered,
+        });
+    }
+
+    /// The most recent keystrokes, newest at the bottom
+    pub fn show(&self, ui: &mut egui::Ui) {
+        if self.keystrokes.is_empty() {
+            ui.label("No keystrokes yet");
+            return;
+        }
+
+        egui::ScrollArea::vertical()
+            .max_height(300.0)
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for keystroke in &self.keystrokes {
+                    ui.monospace(format!(
+                        "{} {}",
+                        format_time(keystroke.time),
+                        describe_keystroke(keystroke)
+                    ));
+                }
+            });
+    }
+}
+
+fn describe_keystroke(keystroke: &Keystroke) -> String {
+    let mut ret = format!("{} → {}", keystroke.label, describe_bytes(&keystroke.sent));
+    if !keystroke.delivered {
+        ret.push_str(" (not sent)");
+    }
+    ret
+}
+
+/// The key with its modifiers, e.g. Ctrl+Shift+Up, or the text typed. None for events that are
+/// not keystrokes
+fn describe_event(event: &Event) -> Option<String> {
+    match event {
+        Event::Text(text) => Some(format!("{text:?}")),
+        Event::Key {
+            key,
+            pressed: true,
+            modifiers,
+            ..
+        } => {
+            let mut label = String::new();
+            for (held, name) in [
+                (modifiers.ctrl, "Ctrl+"),
+                (modifiers.alt, "Alt+"),
+                (modifiers.shift, "Shift+"),
+                (modifiers.mac_cmd, "Cmd+"),
+            ] {
+                if held {
+                    label.push_str(name);
+                }
+            }
+            label.push_str(key.name());
+            Some(label)
+        }
+        Event::Copy => Some("Copy".to_string()),
+        Event::Paste(_) => Some("Paste".to_string()),
+        _ => None,
+    }
+}
+
+/// `sent` the way escape codes are usually written down, ESC[A for the up arrow and 0x03 for
+/// ctrl+c. Controls other than ESC are set apart by spaces
+fn describe_bytes(sent: &[u8]) -> String {
+    if sent.is_empty() {
+        return "nothing".to_string();
+    }
+
+    let shown = &sent[..sent.len().min(MAX_SHOWN_BYTES)];
+    let mut ret = String::new();
+    for c in String::from_utf8_lossy(shown).chars() {
+        match c {
+            '\x1b' => ret.push_str("ESC"),
+            '\0'..='\x1f' | '\x7f' => {
+                if !ret.is_empty() && !ret.ends_with(' ') {
+                    ret.push(' ');
+                }
+                ret.push_str(&format!("0x{:02x} ", c as u32));
+            }
+            c => ret.push(c),
+        }
+    }
+    let mut ret = ret.trim_end().to_string();
+    if sent.len() > shown.len() {
+        ret.push_str(&format!("… ({} bytes)", sent.len()));
+    }
+    ret
+}
+
+/// Time of day of `time`, in UTC since there are no time zones to go by
+fn format_time(time: u64) -> String {
+    let millis = time % 1000;
+    let secs = time / 1000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        millis
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use eframe::egui::{Key, Modifiers};
+
+    fn key(key: Key, modifiers: Modifiers) -> Event {
+        Event::Key {
+            key,
+            physical_key: None,
+            pressed: true,
+            repeat: false,
+            modifiers,
+        }
+    }
+
+    #[test]
+    fn test_keystroke_history() {
+        let mut history = KeystrokeHistory::new();
+        let typed = Event::Text("a".to_string());
+        history.push(
+            &key(Key::A, Modifiers::NONE),
+            Some(&typed),
+            Vec::new(),
+            true,
+        );
+        history.push(&typed, None, b"a".to_vec(), true);
+        history.push(&key(Key::C, Modifiers::CTRL), None, b"\x03".to_vec(), true);
+        history.push(
+            &key(Key::ArrowUp, Modifiers::SHIFT),
+            None,
+            b"\x1b[1;2A".to_vec(),
+            false,
+        );
+        history.push(&key(Key::F13, Modifiers::NONE), None, Vec::new(), true);
+
+        let shown: Vec<String> = history.keystrokes.iter().map(describe_keystroke).collect();
+        assert_eq!(
+            shown,
+            [
+                "\"a\" → a",
+                "Ctrl+C → 0x03",
+                "Shift+Up → ESC[1;2A (not sent)",
+                "F13 → nothing"
+            ]
+        );
+
+        for _ in 0..MAX_KEYSTROKES {
+            history.push(&typed, None, b"a".to_vec(), true);
+        }
+        assert_eq!(history.keystrokes.len(), MAX_KEYSTROKES);
+    }
+
+    #[test]
+    fn test_describe_bytes() {
+        assert_eq!(describe_bytes(b"\r"), "0x0d");
+        assert_eq!(describe_bytes(b"\x1ba\x7f"), "ESCa 0x7f");
+        assert_eq!(describe_bytes("é\x00b".as_bytes()), "é 0x00 b");
+        assert_eq!(
+            describe_bytes(&[b'x'; 40]),
+            format!("{}… (40 bytes)", "x".repeat(32))
+        );
+        assert_eq!(format_time(3_723_004), "01:02:03.004");
+    }
+}
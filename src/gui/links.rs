@@ -0,0 +1,111 @@
+//! URLs in the output, opened by clicking them with the configured modifier held
+
+use crate::terminal_emulator::WIDE_CHAR_SPACER;
+use std::ops::Range;
+
+const SCHEMES: [&str; 3] = ["https://", "http://", "file://"];
+
+fn is_url_char(c: char) -> bool {
+    // Non-ASCII characters are allowed, URLs in the wild are often not percent encoded. Wide
+    // ones come with a spacer
+    c == WIDE_CHAR_SPACER
+        || !c.is_ascii_whitespace() && !c.is_ascii_control() && !"<>\"'`".contains(c)
+}
+
+fn starts_with_scheme(cells: &[char], scheme: &str) -> bool {
+    scheme.chars().count() <= cells.len() && scheme.chars().zip(cells).all(|(a, b)| a == *b)
+}
+
+/// Characters that end sentences are not part of a URL at its end. A closing parenthesis only is
+/// when the URL opened one, like in Wikipedia links
+fn trim_url_end(url: &[char]) -> usize {
+    let mut end = url.len();
+    loop {
+        match url[..end].last() {
+            Some('.' | ',' | ';' | ':' | '!' | '?') => end -= 1,
+            Some(')') => {
+                let opened = url[..end].iter().filter(|c| **c == '(').count();
+                let closed = url[..end].iter().filter(|c| **c == ')').count();
+                if closed <= opened {
+                    return end;
+                }
+                end -= 1;
+            }
+            _ => return end,
+        }
+    }
+}
+
+/// Range of the URL in `data` that `pos` is on. Soft wrapped lines have no newline in the buffer,
+/// so URLs that wrapped over several rows are found whole
+pub fn url_at(data: &[char], pos: usize) -> Option<Range<usize>> {
+    if pos >= data.len() {
+        return None;
+    }
+    let line_start = data[..pos]
+        .iter()
+        .rposition(|c| *c == '\n')
+        .map_or(0, |i| i + 1);
+    let line_end = data[pos..]
+        .iter()
+        .position(|c| *c == '\n')
+        .map_or(data.len(), |i| pos + i);
+    let line = &data[line_start..line_end];
+
+    let mut search_start = 0;
+    while search_start < line.len() {
+        let start = (search_start..line.len()).find(|i| {
+            SCHEMES
+                .iter()
+                .any(|scheme| starts_with_scheme(&line[*i..], scheme))
+        })?;
+        let len = line[start..]
+            .iter()
+            .position(|c| !is_url_char(*c))
+            .unwrap_or(line.len() - start);
+        let end = start + trim_url_end(&line[start..start + len]);
+
+        let range = line_start + start..line_start + end;
+        if range.contains(&pos) {
+            return Some(range);
+        }
+        search_start = start + len.max(1);
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::terminal_emulator::{cells_to_string, str_to_cells};
+
+    fn url_text(data: &str, pos: usize) -> Option<String> {
+        let data = str_to_cells(data);
+        url_at(&data, pos).map(|range| cells_to_string(&data[range]))
+    }
+
+    #[test]
+    fn test_url_at() {
+        let data = "see https://example.com/a?b=c. and http://x.org\nnext";
+        assert_eq!(url_text(data, 4), Some("https://example.com/a?b=c".into()));
+        assert_eq!(url_text(data, 20), Some("https://example.com/a?b=c".into()));
+        assert_eq!(url_text(data, 29), None);
+        assert_eq!(url_text(data, 40), Some("http://x.org".into()));
+        assert_eq!(url_text(data, 2), None);
+        assert_eq!(url_text(data, 49), None);
+        assert_eq!(url_text(data, 100), None);
+
+        let data = "(https://en.wikipedia.org/wiki/Rust_(language)) <file:///tmp/a b>";
+        assert_eq!(
+            url_text(data, 10),
+            Some("https://en.wikipedia.org/wiki/Rust_(language)".into())
+        );
+        assert_eq!(url_text(data, 52), Some("file:///tmp/a".into()));
+
+        // Wide characters take up two cells
+        let data = "go https://例.jp/漢字 now";
+        assert_eq!(url_text(data, 12), Some("https://例.jp/漢字".into()));
+        assert_eq!(url_text(data, 20), Some("https://例.jp/漢字".into()));
+        assert_eq!(url_text(data, 21), None);
+    }
+}
@@ -1,115 +1,238 @@
 use crate::{
+    config::{Config, KeyCombo},
     error::backtraced_err,
     terminal_emulator::{
-        ControlAction, LoadRecordingError, LoadSnapshotError, PtyIo, Recording, RecordingHandle,
-        ReplayControl, ReplayIo, TerminalEmulator,
+        Progress, Pty, RecordingHandle, Replay, ReplayControl, Rgb, TerminalEmulator,
     },
 };
+use activity::ActivityMonitor;
 use eframe::egui::{self, CentralPanel};
-use terminal::TerminalWidget;
+use idle::IdleTracker;
+use notify::Notifier;
+use profile::ProfileTracker;
+use resize::{ResizeDebouncer, RESIZE_SETTLE_TIME};
+use theme::ThemeTracker;
 use thiserror::Error;
 
-use std::path::{Path, PathBuf};
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+pub use terminal::TerminalWidget;
 
+const TITLE_REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const PROGRESS_BAR_HEIGHT: f32 = 3.0;
+const VISUAL_BELL_DURATION: Duration = Duration::from_millis(100);
+/// How long the segment of an indeterminate progress bar takes to cross the window
+const INDETERMINATE_PROGRESS_PERIOD: f64 = 1.5;
+
+mod activity;
+mod background_image;
+mod box_drawing;
+mod clipboard;
+mod cursor_blink;
+mod dropped_files;
+mod idle;
+mod keystrokes;
+mod links;
+mod motion;
+mod notify;
+mod profile;
+mod resize;
+mod save_text;
+mod search;
+mod selection;
 mod terminal;
+mod theme;
 
-fn set_egui_options(ctx: &egui::Context) {
+fn set_egui_options(ctx: &egui::Context, reduce_motion: bool) {
     ctx.options_mut(|options| {
         options.zoom_with_keyboard = true;
     });
 
-    let mut style = (*ctx.style()).clone();
-    style.visuals.window_rounding = 8.0.into();
-    style.visuals.window_shadow.blur = 16.0;
-    ctx.set_style(style);
+    // Both the light and dark style, the theme can change while running
+    ctx.all_styles_mut(|style| {
+        style.visuals.window_rounding = 8.0.into();
+        style.visuals.window_shadow.blur = 16.0;
+    });
+
+    if reduce_motion {
+        motion::disable_animations(ctx);
+    }
+}
+
+/// Bytes per second to play recordings without timestamps at, before the speed multiplier
+const UNTIMED_REPLAY_RATE: f32 = 2000.0;
+
+/// Terminal size given on the command line
+#[derive(Clone, Copy, Debug)]
+pub struct GridSize {
+    pub columns: usize,
+    pub rows: usize,
+    /// Keep the size when the window is resized
+    pub locked: bool,
 }
 
-struct LoadReplayResponse {
-    terminal_emulator: TerminalEmulator<ReplayIo>,
-    replay_control: ReplayControl,
+/// How a replay starts out
+pub struct ReplayOptions {
+    pub autoplay: bool,
+    /// Playback speed as a multiple of real time
+    pub speed: f32,
 }
 
-#[derive(Debug, Error)]
-enum LoadReplayError {
-    #[error("failed to load recording")]
-    Recording(LoadRecordingError),
-    #[error("failed to construct terminal emulator")]
-    CreateTerminalEmulator(LoadSnapshotError),
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            autoplay: false,
+            speed: 1.0,
+        }
+    }
 }
 
-fn load_replay(path: &Path) -> Result<LoadReplayResponse, LoadReplayError> {
-    let recording = Recording::load(path).map_err(LoadReplayError::Recording)?;
-    let mut replay_control = ReplayControl::new(recording);
-    let io_handle = replay_control.io_handle();
-    let snapshot = replay_control.initial_state();
-    let terminal_emulator = TerminalEmulator::from_snapshot(snapshot, io_handle)
-        .map_err(LoadReplayError::CreateTerminalEmulator)?;
-    Ok(LoadReplayResponse {
-        terminal_emulator,
-        replay_control,
-    })
+struct ReplayPlayback {
+    playing: bool,
+    speed: f32,
+    /// Current position in recording time
+    clock: Duration,
+    last_update: Option<Instant>,
+    /// Bytes owed to recordings without timestamps, carried between frames
+    untimed_budget: f32,
+    /// Set when the slider moved, the clock has to jump to the new position
+    seeked: bool,
+}
+
+impl ReplayPlayback {
+    fn new(options: &ReplayOptions) -> ReplayPlayback {
+        ReplayPlayback {
+            playing: options.autoplay,
+            speed: options.speed,
+            clock: Duration::ZERO,
+            last_update: None,
+            untimed_budget: 0.0,
+            seeked: false,
+        }
+    }
+
+    /// Continue from wherever the replay currently is, e.g. after seeking
+    fn resync(&mut self, replay_control: &ReplayControl) {
+        if let Some(time) = replay_control.next_step_time() {
+            self.clock = time;
+        }
+        self.last_update = None;
+        self.untimed_budget = 0.0;
+    }
+
+    /// How many steps to take to catch up with the clock
+    fn advance(&mut self, replay_control: &ReplayControl, now: Instant) -> usize {
+        let elapsed = self.last_update.map_or(Duration::ZERO, |last| now - last);
+        self.last_update = Some(now);
+
+        if !replay_control.is_timed() {
+            self.untimed_budget += elapsed.as_secs_f32() * UNTIMED_REPLAY_RATE * self.speed;
+            let steps = self.untimed_budget.floor();
+            self.untimed_budget -= steps;
+            return steps as usize;
+        }
+
+        self.clock += elapsed.mul_f32(self.speed);
+        match replay_control.next_step_time() {
+            Some(time) if time <= self.clock => 1,
+            _ => 0,
+        }
+    }
 }
 
 struct ReplayTermieGui {
-    terminal_emulator: TerminalEmulator<ReplayIo>,
+    replay: Replay,
     terminal_widget: TerminalWidget,
-    replay_path: PathBuf,
-    replay_control: ReplayControl,
     slider_pos: usize,
+    // Whether the slider covers the recording's duration instead of its steps. Only possible for
+    // timed recordings
+    seek_by_time: bool,
+    playback: ReplayPlayback,
+    config: Config,
+    theme: ThemeTracker,
 }
 
 impl ReplayTermieGui {
     fn new(
         cc: &eframe::CreationContext<'_>,
-        replay_path: PathBuf,
-        terminal_emulator: TerminalEmulator<ReplayIo>,
-        replay_control: ReplayControl,
+        replay: Replay,
+        config: &Config,
+        options: &ReplayOptions,
     ) -> Self {
-        set_egui_options(&cc.egui_ctx);
+        let reduce_motion = motion::reduce_motion(config);
+        set_egui_options(&cc.egui_ctx, reduce_motion);
+
+        let mut playback = ReplayPlayback::new(options);
+        playback.resync(replay.control());
+        let seek_by_time = replay.control().is_timed();
 
         ReplayTermieGui {
-            terminal_emulator,
-            terminal_widget: TerminalWidget::new(&cc.egui_ctx),
-            replay_path,
-            replay_control,
+            replay,
+            terminal_widget: TerminalWidget::new(&cc.egui_ctx, config, reduce_motion),
             slider_pos: 0,
+            seek_by_time,
+            playback,
+            config: config.clone(),
+            theme: ThemeTracker::new(config),
         }
     }
 
-    fn step_replay(&mut self) {
-        let action = self.replay_control.next();
-        match action {
-            ControlAction::Resize { width, height } => {
-                if let Err(e) = self.terminal_emulator.set_win_size(width, height) {
-                    error!("failed to set window size: {}", backtraced_err(&*e));
+    fn play(&mut self, ctx: &egui::Context) {
+        if !self.playback.playing {
+            return;
+        }
+
+        let now = Instant::now();
+        // Timed recordings report one step at a time, since each step can move the next item
+        // into view
+        loop {
+            let steps = self.playback.advance(self.replay.control(), now);
+            if steps == 0 || self.replay.control().is_finished() {
+                break;
+            }
+            for _ in 0..steps {
+                if self.replay.control().is_finished() {
+                    break;
                 }
+                self.replay.step();
+                self.slider_pos += 1;
             }
-            ControlAction::None => (),
+            if !self.replay.control().is_timed() {
+                break;
+            }
+        }
+
+        if self.replay.control().is_finished() {
+            self.playback.playing = false;
+        } else {
+            ctx.request_repaint();
         }
     }
 }
 
 impl eframe::App for ReplayTermieGui {
+    fn clear_color(&self, visuals: &egui::Visuals) -> [f32; 4] {
+        window_clear_color(&self.config, visuals)
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        let current_pos = self.replay_control.current_pos();
-        if current_pos > self.slider_pos {
-            match load_replay(&self.replay_path) {
-                Ok(response) => {
-                    self.terminal_emulator = response.terminal_emulator;
-                    self.replay_control = response.replay_control;
-                }
-                Err(e) => {
-                    error!("failed to reload replay: {}", backtraced_err(&e));
-                }
-            }
+        if self.replay.control().current_pos() > self.slider_pos {
+            // The terminal is started over and needs its colors again
+            self.theme.invalidate();
+        }
+        if let Err(e) = self.replay.seek(self.slider_pos) {
+            error!("failed to seek replay: {}", backtraced_err(&e));
         }
 
-        let current_pos = self.replay_control.current_pos();
-        if current_pos < self.slider_pos {
-            for _ in 0..self.slider_pos - current_pos {
-                self.step_replay();
-            }
+        if std::mem::take(&mut self.playback.seeked) {
+            self.playback.resync(self.replay.control());
         }
+        self.play(ctx);
+        self.theme
+            .update(ctx, &self.config, self.replay.terminal_emulator_mut());
 
         egui::TopBottomPanel::top("header")
             .frame(
@@ -120,10 +243,39 @@ impl eframe::App for ReplayTermieGui {
                 .inner_margin(8.0),
             )
             .show(ctx, |ui| {
-                if ui.button("next").clicked() {
-                    self.step_replay();
-                    self.slider_pos += 1;
-                }
+                ui.horizontal(|ui| {
+                    let play_label = if self.playback.playing {
+                        "pause"
+                    } else {
+                        "play"
+                    };
+                    if ui.button(play_label).clicked() {
+                        self.playback.playing = !self.playback.playing;
+                        self.playback.resync(self.replay.control());
+                    }
+                    if ui.button("next").clicked() {
+                        self.replay.step();
+                        self.slider_pos += 1;
+                    }
+                    ui.label("Speed:");
+                    ui.add(
+                        egui::DragValue::new(&mut self.playback.speed)
+                            .range(0.1..=64.0)
+                            .speed(0.1)
+                            .suffix("x"),
+                    );
+
+                    let control = self.replay.control();
+                    if let Some(duration) = control.duration() {
+                        ui.checkbox(&mut self.seek_by_time, "Seek by time");
+                        let time = control.time_at_pos(self.slider_pos).unwrap_or_default();
+                        ui.label(format!(
+                            "{:.1}s / {:.1}s",
+                            time.as_secs_f32(),
+                            duration.as_secs_f32()
+                        ));
+                    }
+                });
             });
 
         egui::TopBottomPanel::bottom("seek")
@@ -136,45 +288,474 @@ impl eframe::App for ReplayTermieGui {
             )
             .show(ctx, |ui| {
                 ui.style_mut().spacing.slider_width = ui.available_width();
-                let slider =
-                    egui::Slider::new(&mut self.slider_pos, 0..=self.replay_control.len() - 1)
-                        .show_value(false)
-                        .clamping(egui::SliderClamping::Always);
-                ui.add(slider);
+                let control = self.replay.control();
+                match control.duration() {
+                    Some(duration) if self.seek_by_time => {
+                        // Dragging moves through the session evenly in time, landing on the last
+                        // step recorded before the chosen time
+                        let mut time = control
+                            .time_at_pos(self.slider_pos)
+                            .unwrap_or_default()
+                            .as_secs_f64();
+                        let slider = egui::Slider::new(&mut time, 0.0..=duration.as_secs_f64())
+                            .show_value(false)
+                            .clamping(egui::SliderClamping::Always);
+                        if ui.add(slider).changed() {
+                            self.slider_pos = control.pos_at_time(Duration::from_secs_f64(time));
+                            self.playback.seeked = true;
+                        }
+                    }
+                    _ => {
+                        let slider = egui::Slider::new(&mut self.slider_pos, 0..=control.len() - 1)
+                            .show_value(false)
+                            .clamping(egui::SliderClamping::Always);
+                        if ui.add(slider).changed() {
+                            self.playback.seeked = true;
+                        }
+                    }
+                }
             });
 
-        let panel_response = CentralPanel::default().show(ctx, |ui| {
-            self.terminal_widget.show(ui, &mut self.terminal_emulator);
+        let panel_response = terminal_panel(ctx, &self.config).show(ctx, |ui| {
+            self.terminal_widget
+                .show(ui, self.replay.terminal_emulator_mut());
         });
 
         panel_response.response.context_menu(|ui| {
             self.terminal_widget.show_options(ui);
+            self.theme.show_options(ui, &self.config);
         });
     }
 }
 
-struct TermieGui {
-    terminal_emulator: TerminalEmulator<PtyIo>,
+/// The panel holding the terminal. With a translucent background the panel is left unfilled, the
+/// terminal paints its own background and anything more would hide the desktop behind the window
+fn terminal_panel(ctx: &egui::Context, config: &Config) -> CentralPanel {
+    let panel = CentralPanel::default();
+    if !config.transparent_background() {
+        return panel;
+    }
+    panel.frame(egui::Frame::central_panel(&ctx.style()).fill(egui::Color32::TRANSPARENT))
+}
+
+/// Clear color for a window whose framebuffer may be transparent
+fn window_clear_color(config: &Config, visuals: &egui::Visuals) -> [f32; 4] {
+    if config.transparent_background() {
+        egui::Rgba::TRANSPARENT.to_array()
+    } else {
+        visuals.panel_fill.to_normalized_gamma_f32()
+    }
+}
+
+/// Names the host ssh is connected to at the top of the terminal. Hosts with a configured color
+/// also get a border in that color, so that the window stands out as a whole
+fn paint_remote_host_banner(ui: &egui::Ui, host: &str, color: Option<Rgb>) {
+    let rect = ui.max_rect();
+    let painter = ui.painter();
+    let fill = match color {
+        Some((r, g, b)) => {
+            let color = egui::Color32::from_rgb(r, g, b);
+            painter.rect_stroke(rect, 0.0, egui::Stroke::new(2.0, color));
+            color
+        }
+        None => egui::Color32::from_gray(90),
+    };
+
+    let galley = painter.layout_no_wrap(
+        format!("ssh {host}"),
+        egui::FontId::monospace(12.0),
+        egui::Color32::WHITE,
+    );
+    let banner_rect = egui::Rect::from_center_size(
+        egui::pos2(rect.center().x, rect.top() + galley.size().y / 2.0 + 6.0),
+        galley.size() + egui::vec2(12.0, 4.0),
+    );
+    painter.rect_filled(banner_rect, 4.0, fill);
+    painter.galley(
+        banner_rect.center() - galley.size() / 2.0,
+        galley,
+        egui::Color32::WHITE,
+    );
+}
+
+/// Says that the shell exited or the connection was lost, along with the way to start over.
+/// Returns whether the button for that was clicked
+fn show_session_ended(ui: &egui::Ui, restart_shortcut: &KeyCombo) -> bool {
+    egui::Area::new(egui::Id::new("session_ended"))
+        .anchor(egui::Align2::CENTER_BOTTOM, [0.0, -12.0])
+        .show(ui.ctx(), |ui| {
+            egui::Frame::popup(ui.style())
+                .show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label("Session ended");
+                        ui.button(format!("Restart ({restart_shortcut})")).clicked()
+                    })
+                    .inner
+                })
+                .inner
+        })
+        .inner
+}
+
+/// Thin bar along the top of the terminal for progress reported with OSC 9;4. Indeterminate
+/// progress is a segment moving across, or a dimmed full bar when motion is reduced
+fn paint_progress_bar(ui: &egui::Ui, progress: Progress, reduce_motion: bool) {
+    let rect = ui.max_rect();
+    let bar = egui::Rect::from_min_size(
+        rect.left_top(),
+        egui::vec2(rect.width(), PROGRESS_BAR_HEIGHT),
+    );
+    let accent = ui.visuals().selection.bg_fill;
+    let painter = ui.painter();
+
+    let fraction = |percent: u8| f32::from(percent) / 100.0;
+    let (filled, color) = match progress {
+        Progress::Normal(percent) => (fraction(percent), accent),
+        Progress::Error(percent) => (fraction(percent), ui.visuals().error_fg_color),
+        Progress::Paused(percent) => (fraction(percent), ui.visuals().warn_fg_color),
+        Progress::Indeterminate if reduce_motion => (1.0, accent.gamma_multiply(0.5)),
+        Progress::Indeterminate => {
+            let phase = (ui.input(|i| i.time) / INDETERMINATE_PROGRESS_PERIOD).fract() as f32;
+            let segment_width = bar.width() / 4.0;
+            let left = bar.left() + phase * (bar.width() + segment_width) - segment_width;
+            let segment = egui::Rect::from_x_y_ranges(left..=left + segment_width, bar.y_range())
+                .intersect(bar);
+            painter.rect_filled(segment, 0.0, accent);
+            ui.ctx().request_repaint();
+            return;
+        }
+    };
+
+    painter.rect_filled(bar, 0.0, color.gamma_multiply(0.25));
+    let mut done = bar;
+    done.set_width(bar.width() * filled);
+    painter.rect_filled(done, 0.0, color);
+}
+
+fn paint_visual_bell(ui: &egui::Ui) {
+    let color = ui.visuals().text_color().gamma_multiply(0.15);
+    ui.painter().rect_filled(ui.max_rect(), 0.0, color);
+}
+
+fn paint_recording_indicator(ui: &egui::Ui) {
+    let rect = ui.max_rect();
+    ui.painter().text(
+        rect.right_top() + egui::vec2(-8.0, 8.0),
+        egui::Align2::RIGHT_TOP,
+        "● REC",
+        egui::FontId::monospace(14.0),
+        egui::Color32::RED,
+    );
+}
+
+/// Repaint whenever the shell writes something
+fn wake_on_output<P: Pty>(
+    terminal_emulator: &TerminalEmulator<P>,
+    ctx: &egui::Context,
+    config: &Config,
+) {
+    let ctx = ctx.clone();
+    terminal_emulator.set_output_waker(
+        Box::new(move || ctx.request_repaint()),
+        config.repaint_coalesce,
+    );
+}
+
+/// The window for a shell, or for whatever else [`Pty`] can connect to
+struct TermieGui<P: Pty> {
+    terminal_emulator: TerminalEmulator<P>,
+    // What the shell was started with, for restarting it
+    shell_options: P::Options,
     terminal_widget: TerminalWidget,
     recording_handle: Option<RecordingHandle>,
     show_debug_panel: bool,
+    config: Config,
+    show_close_confirmation: bool,
+    // Set once the user has confirmed, so the next close request goes through
+    close_confirmed: bool,
+    title: String,
+    last_title_refresh: Option<Instant>,
+    theme: ThemeTracker,
+    // Matched along with the title refresh
+    profiles: ProfileTracker,
+    // Refreshed along with the title
+    remote_host: Option<String>,
+    resize: ResizeDebouncer,
+    idle: IdleTracker,
+    reduce_motion: bool,
+    notifier: Notifier,
+    // Set while the visual bell is shown
+    bell_flash_until: Option<Instant>,
+    activity: ActivityMonitor,
+    // Prefix of the title that was last sent, see `title`
+    title_prefix: &'static str,
+    // Set until the window was asked to fit the size given on the command line
+    fit_window_to: Option<(usize, usize)>,
+    // The terminal keeps its size while the window is resized
+    lock_size: bool,
+    // Path given with --logfile, kept so that logging can be turned back on
+    session_log_path: Option<PathBuf>,
+    // Size of the window before it went fullscreen, restored when it leaves fullscreen
+    windowed_size: Option<egui::Vec2>,
 }
 
-impl TermieGui {
-    fn new(cc: &eframe::CreationContext<'_>, terminal_emulator: TerminalEmulator<PtyIo>) -> Self {
-        set_egui_options(&cc.egui_ctx);
+impl<P: Pty> TermieGui<P> {
+    fn new(
+        cc: &eframe::CreationContext<'_>,
+        terminal_emulator: TerminalEmulator<P>,
+        shell_options: P::Options,
+        recording_handle: Option<RecordingHandle>,
+        config: Config,
+        grid_size: Option<GridSize>,
+        kiosk: bool,
+    ) -> Self {
+        let reduce_motion = motion::reduce_motion(&config);
+        set_egui_options(&cc.egui_ctx, reduce_motion);
+
+        wake_on_output(&terminal_emulator, &cc.egui_ctx, &config);
+        let session_log_path = terminal_emulator.session_log_path().map(PathBuf::from);
 
         TermieGui {
             terminal_emulator,
-            terminal_widget: TerminalWidget::new(&cc.egui_ctx),
-            recording_handle: None,
-            show_debug_panel: true,
+            shell_options,
+            terminal_widget: TerminalWidget::new(&cc.egui_ctx, &config, reduce_motion),
+            recording_handle,
+            show_debug_panel: !kiosk,
+            theme: ThemeTracker::new(&config),
+            profiles: ProfileTracker::new(),
+            idle: IdleTracker::new(config.idle_timeout),
+            notifier: Notifier::new(config.notifications, config.notification_interval),
+            bell_flash_until: None,
+            activity: ActivityMonitor::new(config.monitor_activity),
+            title_prefix: "",
+            config,
+            show_close_confirmation: false,
+            close_confirmed: false,
+            title: "Termie".to_string(),
+            last_title_refresh: None,
+            remote_host: None,
+            resize: match grid_size {
+                Some(grid) => ResizeDebouncer::with_applied((grid.columns, grid.rows)),
+                None => ResizeDebouncer::new(),
+            },
+            reduce_motion,
+            // A fullscreen window has no size to fit
+            fit_window_to: grid_size
+                .filter(|_| !kiosk)
+                .map(|grid| (grid.columns, grid.rows)),
+            lock_size: grid_size.is_some_and(|grid| grid.locked),
+            session_log_path,
+            windowed_size: None,
+        }
+    }
+
+    /// Replace the shell with a new one, see [`TerminalEmulator::restart`]
+    fn restart_shell(&mut self, ctx: &egui::Context) {
+        let keep_scrollback = self.config.restart_keeps_scrollback;
+        if let Err(e) = self
+            .terminal_emulator
+            .restart(&self.shell_options, keep_scrollback)
+        {
+            error!("Failed to restart shell: {}", backtraced_err(&e));
+            return;
+        }
+        info!("Restarted shell");
+        wake_on_output(&self.terminal_emulator, ctx, &self.config);
+        // Show the new shell's name right away
+        self.last_title_refresh = None;
+    }
+
+    /// A running job is the most useful thing to show. With only the shell in the foreground,
+    /// prefer whatever title the shell set, then the shell's name
+    fn compute_title(&self) -> Option<String> {
+        let has_foreground_job = self.terminal_emulator.has_foreground_job().unwrap_or(false);
+        let process_name = self.terminal_emulator.foreground_process_name();
+
+        if has_foreground_job && process_name.is_some() {
+            return process_name;
+        }
+
+        self.terminal_emulator
+            .window_title()
+            .filter(|title| !title.is_empty())
+            .map(ToString::to_string)
+            .or(process_name)
+    }
+
+    fn refresh_title(&mut self, ctx: &egui::Context) {
+        let now = Instant::now();
+        if let Some(last_refresh) = self.last_title_refresh {
+            if now - last_refresh < TITLE_REFRESH_INTERVAL {
+                return;
+            }
+        }
+        self.last_title_refresh = Some(now);
+        ctx.request_repaint_after(TITLE_REFRESH_INTERVAL);
+
+        if self.config.remote_host_banner {
+            self.remote_host = self.terminal_emulator.remote_host();
+        }
+
+        let title = self.compute_title().unwrap_or_else(|| "Termie".to_string());
+        self.update_profile(&title);
+        let prefix = self.activity.title_prefix();
+        if title != self.title || prefix != self.title_prefix {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Title(format!("{prefix}{title}")));
+            self.title = title;
+            self.title_prefix = prefix;
+        }
+    }
+
+    /// Switch the widget and colors over when a different profile applies
+    fn update_profile(&mut self, title: &str) {
+        if self.config.profiles.is_empty() {
+            return;
+        }
+        let process = self.terminal_emulator.foreground_process_name();
+        if !self
+            .profiles
+            .update(&self.config, process.as_deref(), title)
+        {
+            return;
+        }
+
+        let profile = self.profiles.active(&self.config);
+        self.terminal_widget
+            .set_profile_font_size(profile.and_then(|profile| profile.font_size));
+        self.theme
+            .set_profile_color_scheme(profile.and_then(|profile| profile.color_scheme.clone()));
+    }
+
+    /// Post the notifications the terminal received, and ring the bell for the ones that cannot
+    /// be posted. Returns whether the bell rang
+    fn handle_notifications(&mut self, ctx: &egui::Context, now: Instant) -> bool {
+        let mut bell = self.terminal_emulator.take_bell();
+        for notification in self.terminal_emulator.take_notifications() {
+            if !self.notifier.notify(&notification, &self.title, now) {
+                bell = true;
+            }
+        }
+
+        if !bell {
+            return false;
+        }
+
+        self.bell_flash_until = Some(now + VISUAL_BELL_DURATION);
+        ctx.request_repaint();
+        if ctx.input(|i| i.viewport().focused) == Some(false) {
+            ctx.send_viewport_cmd(egui::ViewportCommand::RequestUserAttention(
+                egui::UserAttentionType::Informational,
+            ));
+        }
+        true
+    }
+
+    fn should_confirm_close(&self) -> bool {
+        if !self.config.confirm_close || self.close_confirmed {
+            return false;
+        }
+
+        match self.terminal_emulator.has_foreground_job() {
+            Ok(v) => v,
+            Err(e) => {
+                error!("failed to check for foreground job: {}", backtraced_err(&e));
+                false
+            }
         }
     }
+
+    /// F11 switches between fullscreen and the window as it was before
+    fn handle_fullscreen_key(&mut self, ctx: &egui::Context) {
+        // Consumed so that the key does not also reach the shell
+        if !ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, egui::Key::F11)) {
+            return;
+        }
+
+        let (fullscreen, inner_rect) = ctx.input(|i| {
+            let viewport = i.viewport();
+            (viewport.fullscreen.unwrap_or(false), viewport.inner_rect)
+        });
+        if fullscreen {
+            ctx.send_viewport_cmd(egui::ViewportCommand::Fullscreen(false));
+            // Kiosk windows start out without decorations, which would leave no way to move
+            // the window
+            ctx.send_viewport_cmd(egui::ViewportCommand::Decorations(true));
+            if let Some(size) = self.windowed_size.take() {
+                ctx.send_viewport_cmd(egui::ViewportCommand::InnerSize(size));
+            }
+        } else {
+            self.windowed_size = inner_rect.map(|rect| rect.size());
+            ctx.send_viewport_cmd(egui::ViewportCommand::Fullscreen(true));
+        }
+    }
+
+    /// Dropped files are pasted as their paths, or as their contents with Shift held
+    fn handle_dropped_files(&mut self, ctx: &egui::Context) {
+        let (files, contents) = ctx.input(|i| (i.raw.dropped_files.clone(), i.modifiers.shift));
+        if files.is_empty() {
+            return;
+        }
+
+        let payload = match contents {
+            true => dropped_files::contents_payload(&files),
+            false => dropped_files::paths_payload(&files),
+        };
+        if payload.is_empty() {
+            return;
+        }
+        if let Err(e) = self.terminal_emulator.paste(&payload) {
+            error!("failed to paste dropped files: {}", backtraced_err(&*e));
+        }
+    }
+
+    fn handle_close_request(&mut self, ctx: &egui::Context) {
+        if ctx.input(|i| i.viewport().close_requested()) && self.should_confirm_close() {
+            ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+            self.show_close_confirmation = true;
+        }
+
+        if !self.show_close_confirmation {
+            return;
+        }
+
+        egui::Window::new("Close termie?")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label("A process is still running in the terminal. Closing will kill it.");
+                ui.horizontal(|ui| {
+                    if ui.button("Close").clicked() {
+                        self.close_confirmed = true;
+                        self.show_close_confirmation = false;
+                        ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.show_close_confirmation = false;
+                    }
+                });
+            });
+    }
 }
 
-impl eframe::App for TermieGui {
+impl<P: Pty> eframe::App for TermieGui<P> {
+    fn clear_color(&self, visuals: &egui::Visuals) -> [f32; 4] {
+        window_clear_color(&self.config, visuals)
+    }
+
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+        let now = Instant::now();
+        if ctx.input(|i| !i.events.is_empty()) {
+            self.idle.record_activity(now);
+        }
+
+        self.handle_close_request(ctx);
+        self.handle_fullscreen_key(ctx);
+        self.handle_dropped_files(ctx);
+        self.refresh_title(ctx);
+        self.theme
+            .update(ctx, &self.config, &mut self.terminal_emulator);
+
         if self.show_debug_panel {
             egui::SidePanel::right("debug_panel")
                 .default_width(200.0)
@@ -182,89 +763,145 @@ impl eframe::App for TermieGui {
                 .max_width(600.0)
                 .resizable(true)
                 .show(ctx, |ui| {
-                    ui.centered_and_justified(|ui| {
-                        ui.vertical_centered(|ui| {
-                            ui.add_space(20.0);
-
-                            if let Some(last_input) = self.terminal_widget.last_keystroke() {
-                                ui.add_space(10.0);
-                                ui.monospace(
-                                    egui::RichText::new("┌─────────────┐")
-                                        .size(16.0)
-                                        .color(egui::Color32::from_rgb(100, 150, 200)),
-                                );
-                                ui.monospace(
-                                    egui::RichText::new("│  Keyboard   │")
-                                        .size(16.0)
-                                        .color(egui::Color32::from_rgb(100, 150, 200)),
-                                );
-                                ui.monospace(
-                                    egui::RichText::new("└─────────────┘")
-                                        .size(16.0)
-                                        .color(egui::Color32::from_rgb(100, 150, 200)),
-                                );
-
-                                ui.add_space(8.0);
-                                ui.monospace(
-                                    egui::RichText::new("      ↓")
-                                        .size(20.0)
-                                        .color(egui::Color32::from_rgb(150, 200, 150)),
-                                );
-                                ui.add_space(8.0);
-
-                                ui.monospace(
-                                    egui::RichText::new(format!("   [{}]", last_input))
-                                        .size(18.0)
-                                        .color(egui::Color32::from_rgb(255, 200, 100))
-                                        .strong(),
-                                );
-
-                                ui.add_space(8.0);
-                                ui.monospace(
-                                    egui::RichText::new("      ↓")
-                                        .size(20.0)
-                                        .color(egui::Color32::from_rgb(150, 200, 150)),
-                                );
-                                ui.add_space(8.0);
-
-                                ui.monospace(
-                                    egui::RichText::new("┌─────────────┐")
-                                        .size(16.0)
-                                        .color(egui::Color32::from_rgb(200, 100, 150)),
-                                );
-                                ui.monospace(
-                                    egui::RichText::new("│     PTY     │")
-                                        .size(16.0)
-                                        .color(egui::Color32::from_rgb(200, 100, 150)),
-                                );
-                                ui.monospace(
-                                    egui::RichText::new("└─────────────┘")
-                                        .size(16.0)
-                                        .color(egui::Color32::from_rgb(200, 100, 150)),
-                                );
-                            } else {
-                                ui.add_space(40.0);
-                            }
-                        });
-                    });
+                    ui.add_space(20.0);
+                    self.terminal_widget.show_debug_options(ui);
                 });
         }
 
-        let panel_response = CentralPanel::default().show(ctx, |ui| {
-            let (width_chars, height_chars) = self.terminal_widget.calculate_available_size(ui);
+        let mut received_output = false;
+        let panel_response = terminal_panel(ctx, &self.config).show(ctx, |ui| {
+            if let Some(grid) = self.fit_window_to.take() {
+                let missing = self.terminal_widget.grid_area_size(ctx, grid) - ui.available_size();
+                ctx.send_viewport_cmd(egui::ViewportCommand::InnerSize(
+                    ctx.screen_rect().size() + missing,
+                ));
+            }
 
-            if let Err(e) = self
-                .terminal_emulator
-                .set_win_size(width_chars, height_chars)
-            {
-                error!("failed to set window size {}", backtraced_err(&*e));
+            let size = self.terminal_widget.calculate_available_size(ui);
+            let interactive = self.terminal_emulator.has_foreground_job().unwrap_or(false);
+            // Applied before the widget reads more output, so output is always parsed against
+            // the size the child was last told about
+            let new_size = if self.lock_size {
+                None
+            } else {
+                self.resize.update(size, Instant::now(), interactive)
+            };
+            if let Some((width_chars, height_chars)) = new_size {
+                if let Err(e) = self
+                    .terminal_emulator
+                    .set_win_size(width_chars, height_chars)
+                {
+                    error!("failed to set window size {}", backtraced_err(&*e));
+                }
+            }
+            if self.resize.is_pending() {
+                ctx.request_repaint_after(RESIZE_SETTLE_TIME);
+            }
+
+            received_output = self.terminal_widget.show(ui, &mut self.terminal_emulator);
+            if received_output {
+                self.idle.record_activity(Instant::now());
+            }
+
+            if self.recording_handle.is_some() {
+                paint_recording_indicator(ui);
+            }
+
+            match self.bell_flash_until {
+                Some(until) if now < until => {
+                    paint_visual_bell(ui);
+                    ctx.request_repaint_after(until - now);
+                }
+                _ => self.bell_flash_until = None,
+            }
+
+            if let Some(progress) = self.terminal_emulator.progress() {
+                paint_progress_bar(ui, progress, self.reduce_motion);
+            }
+
+            if let Some(host) = &self.remote_host {
+                paint_remote_host_banner(ui, host, self.config.remote_host_color(host));
             }
 
-            self.terminal_widget.show(ui, &mut self.terminal_emulator);
+            if self.terminal_emulator.is_output_closed()
+                && show_session_ended(ui, &self.config.restart_shell_shortcut)
+            {
+                self.terminal_widget.request_restart_shell();
+            }
         });
 
         panel_response.response.context_menu(|ui| {
             self.terminal_widget.show_options(ui);
+            self.theme.show_options(ui, &self.config);
+            if self.profiles.show_options(ui, &self.config) {
+                // Switch right away instead of at the next title refresh
+                self.last_title_refresh = None;
+            }
+
+            if ui.button("Reset terminal").clicked() {
+                self.terminal_emulator.reset();
+                ui.close_menu();
+            }
+            if ui
+                .button(format!(
+                    "Restart shell ({})",
+                    self.config.restart_shell_shortcut
+                ))
+                .clicked()
+            {
+                self.terminal_widget.request_restart_shell();
+                ui.close_menu();
+            }
+
+            let mut sanitize = self.terminal_emulator.sanitize_output();
+            if ui
+                .checkbox(&mut sanitize, "Sanitize output")
+                .on_hover_text("Show control characters instead of acting on them")
+                .changed()
+            {
+                if sanitize {
+                    // Undo whatever the output that made this necessary did to the terminal
+                    self.terminal_emulator.reset();
+                }
+                self.terminal_emulator.set_sanitize_output(sanitize);
+            }
+
+            let mut local_echo = self.terminal_emulator.local_echo();
+            if ui
+                .checkbox(&mut local_echo, "Local echo")
+                .on_hover_text("Show typed input for programs that do not echo it")
+                .changed()
+            {
+                self.terminal_emulator.set_local_echo(local_echo);
+            }
+
+            ui.checkbox(
+                &mut self.activity.monitor_output,
+                "Mark title on output in background",
+            );
+            ui.checkbox(&mut self.lock_size, "Lock terminal size")
+                .on_hover_text("Keep the number of columns and rows when the window is resized");
+
+            if let Some(path) = &self.session_log_path {
+                let mut logging = self.terminal_emulator.session_log_path().is_some();
+                if ui
+                    .checkbox(&mut logging, "Log output")
+                    .on_hover_text(format!("Append output to {}", path.display()))
+                    .changed()
+                {
+                    if logging {
+                        let format = self.config.session_log_format;
+                        if let Err(e) = self
+                            .terminal_emulator
+                            .start_session_log(path.clone(), format)
+                        {
+                            error!("failed to start session log: {}", backtraced_err(&e));
+                        }
+                    } else {
+                        self.terminal_emulator.stop_session_log();
+                    }
+                }
+            }
 
             ui.separator();
             ui.checkbox(&mut self.show_debug_panel, "Show Debug Panel");
@@ -284,49 +921,165 @@ impl eframe::App for TermieGui {
                 }
             }
         });
+
+        if self.terminal_widget.take_restart_shell_request() {
+            self.restart_shell(ctx);
+        }
+
+        let bell = self.handle_notifications(ctx, now);
+        let focused = ctx.input(|i| i.viewport().focused).unwrap_or(true);
+        if self.activity.update(focused, received_output, bell) {
+            // Show the change right away instead of with the next title refresh
+            self.last_title_refresh = None;
+            ctx.request_repaint();
+        }
+        self.idle.schedule_poll(ctx, now);
     }
 }
 
-pub fn run_replay(replay_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-    let native_options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default()
-            .with_inner_size([1040.0, 720.0])
-            .with_min_inner_size([400.0, 300.0]),
-        ..Default::default()
-    };
+#[derive(Debug, Error)]
+enum RunGuiErrorKind {
+    #[error(
+        "no display to open a window on. Set DISPLAY or WAYLAND_DISPLAY, or connect with ssh -X \
+         when running remotely"
+    )]
+    NoDisplay(#[source] eframe::Error),
+    #[error(
+        "failed to initialize OpenGL, even with software rendering. Installing a software \
+         OpenGL driver such as Mesa's llvmpipe may help"
+    )]
+    Graphics(#[source] eframe::Error),
+    #[error("failed to create window")]
+    Window(#[source] eframe::Error),
+}
 
-    let LoadReplayResponse {
-        terminal_emulator,
-        replay_control,
-    } = load_replay(&replay_path)?;
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct RunGuiError(#[from] RunGuiErrorKind);
 
-    eframe::run_native(
-        "Termie",
-        native_options,
-        Box::new(move |cc| {
-            Ok(Box::new(ReplayTermieGui::new(
-                cc,
-                replay_path,
-                terminal_emulator,
-                replay_control,
-            )))
-        }),
+fn is_graphics_error(err: &eframe::Error) -> bool {
+    matches!(
+        err,
+        eframe::Error::Glutin(_) | eframe::Error::NoGlutinConfigs(..) | eframe::Error::OpenGL(_)
+    )
+}
+
+/// Windowing on unix (other than macOS) goes through X11 or Wayland, which both need a server to
+/// connect to
+fn has_display() -> bool {
+    if cfg!(all(unix, not(target_os = "macos"))) {
+        ["DISPLAY", "WAYLAND_DISPLAY"]
+            .iter()
+            .any(|var| std::env::var_os(var).is_some_and(|v| !v.is_empty()))
+    } else {
+        true
+    }
+}
+
+fn classify_run_error(err: eframe::Error) -> RunGuiErrorKind {
+    match err {
+        eframe::Error::Winit(_) | eframe::Error::WinitEventLoop(_) if !has_display() => {
+            RunGuiErrorKind::NoDisplay(err)
+        }
+        err if is_graphics_error(&err) => RunGuiErrorKind::Graphics(err),
+        err => RunGuiErrorKind::Window(err),
+    }
+}
+
+/// Open the termie window. If OpenGL cannot be initialized, try once more with hardware
+/// acceleration disabled so that a software renderer can be picked up. `state` is handed to
+/// `create_app` once the window exists, which lets it survive a failed first attempt. `kiosk`
+/// windows start fullscreen and without decorations
+fn run_window<T>(
+    state: T,
+    transparent: bool,
+    kiosk: bool,
+    create_app: impl Fn(&eframe::CreationContext<'_>, T) -> Box<dyn eframe::App>,
+) -> Result<(), RunGuiError> {
+    let mut state = Some(state);
+    let mut hardware_acceleration = eframe::HardwareAcceleration::Preferred;
+
+    loop {
+        let native_options = eframe::NativeOptions {
+            viewport: egui::ViewportBuilder::default()
+                .with_inner_size([1040.0, 720.0])
+                .with_min_inner_size([400.0, 300.0])
+                .with_transparent(transparent)
+                .with_fullscreen(kiosk)
+                .with_decorations(!kiosk),
+            hardware_acceleration,
+            ..Default::default()
+        };
+
+        let res = eframe::run_native(
+            "Termie",
+            native_options,
+            Box::new(|cc| {
+                let state = state.take().expect("app should only be created once");
+                Ok(create_app(cc, state))
+            }),
+        );
+
+        let Err(e) = res else {
+            return Ok(());
+        };
+
+        let can_retry = state.is_some()
+            && hardware_acceleration != eframe::HardwareAcceleration::Off
+            && is_graphics_error(&e);
+        if !can_retry {
+            return Err(classify_run_error(e).into());
+        }
+
+        warn!(
+            "failed to initialize OpenGL, retrying with software rendering: {}",
+            backtraced_err(&e)
+        );
+        hardware_acceleration = eframe::HardwareAcceleration::Off;
+    }
+}
+
+pub fn run_replay(
+    replay_path: PathBuf,
+    config: Config,
+    options: ReplayOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let replay = Replay::load(&replay_path)?;
+
+    run_window(
+        replay,
+        config.transparent_background(),
+        false,
+        |cc, replay| Box::new(ReplayTermieGui::new(cc, replay, &config, &options)),
     )?;
 
     Ok(())
 }
 
-pub fn run(terminal_emulator: TerminalEmulator<PtyIo>) -> Result<(), Box<dyn std::error::Error>> {
-    let native_options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default()
-            .with_inner_size([1040.0, 720.0])
-            .with_min_inner_size([400.0, 300.0]),
-        ..Default::default()
-    };
-    eframe::run_native(
-        "Termie",
-        native_options,
-        Box::new(move |cc| Ok(Box::new(TermieGui::new(cc, terminal_emulator)))),
+pub fn run<P: Pty + 'static>(
+    terminal_emulator: TerminalEmulator<P>,
+    shell_options: P::Options,
+    recording_handle: Option<RecordingHandle>,
+    config: Config,
+    grid_size: Option<GridSize>,
+    kiosk: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let transparent = config.transparent_background();
+    run_window(
+        (terminal_emulator, shell_options, recording_handle, config),
+        transparent,
+        kiosk,
+        |cc, (terminal_emulator, shell_options, recording_handle, config)| {
+            Box::new(TermieGui::new(
+                cc,
+                terminal_emulator,
+                shell_options,
+                recording_handle,
+                config,
+                grid_size,
+                kiosk,
+            ))
+        },
     )?;
     Ok(())
 }
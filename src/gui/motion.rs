@@ -0,0 +1,87 @@
+//! Honoring the desktop's "reduce motion" accessibility preference. Neither egui nor winit
+//! expose it, so it is read once at startup from the desktop settings: the GNOME
+//! `enable-animations` setting on Linux and the universal access `reduceMotion` default on
+//! macOS. Anything else, including the setting not being readable, counts as no preference.
+//! The "reduce_motion" config key overrides the detection in both directions
+
+use crate::config::Config;
+use eframe::egui::{self, style::ScrollAnimation};
+#[cfg(unix)]
+use std::process::Command;
+
+/// Reads the preference from "enable-animations", where false means reduce motion
+#[cfg(all(unix, not(target_os = "macos")))]
+fn system_prefers_reduced_motion() -> Option<bool> {
+    let output = Command::new("gsettings")
+        .args(["get", "org.gnome.desktop.interface", "enable-animations"])
+        .output()
+        .ok()?;
+    parse_enable_animations(&String::from_utf8_lossy(&output.stdout)).map(|v| !v)
+}
+
+#[cfg(target_os = "macos")]
+fn system_prefers_reduced_motion() -> Option<bool> {
+    let output = Command::new("defaults")
+        .args(["read", "com.apple.universalaccess", "reduceMotion"])
+        .output()
+        .ok()?;
+    parse_reduce_motion_default(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(not(unix))]
+fn system_prefers_reduced_motion() -> Option<bool> {
+    None
+}
+
+#[cfg_attr(target_os = "macos", allow(dead_code))]
+fn parse_enable_animations(output: &str) -> Option<bool> {
+    match output.trim() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+fn parse_reduce_motion_default(output: &str) -> Option<bool> {
+    match output.trim() {
+        "1" => Some(true),
+        "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Whether animations should be turned off, from the config or else the desktop
+pub fn reduce_motion(config: &Config) -> bool {
+    config
+        .reduce_motion
+        .or_else(system_prefers_reduced_motion)
+        .unwrap_or(false)
+}
+
+/// Turn off egui's own animations, e.g. animated scrolling to search results and menus
+/// expanding
+pub fn disable_animations(ctx: &egui::Context) {
+    ctx.all_styles_mut(|style| {
+        style.animation_time = 0.0;
+        style.scroll_animation = ScrollAnimation::none();
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_system_preference() {
+        assert_eq!(parse_enable_animations("false\n"), Some(false));
+        assert_eq!(parse_enable_animations("true\n"), Some(true));
+        assert_eq!(parse_enable_animations(""), None);
+        assert_eq!(parse_reduce_motion_default("1\n"), Some(true));
+        assert_eq!(parse_reduce_motion_default("0\n"), Some(false));
+        assert_eq!(
+            parse_reduce_motion_default("The domain/default pair does not exist\n"),
+            None
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! Desktop notifications that programs ask for with OSC 9 and OSC 777. They are posted through
+//! the desktop's own tool, `notify-send` on Linux and `osascript` on macOS, so that no
+//! notification library is needed
+
+use crate::terminal_emulator::Notification;
+use std::{
+    process::Command,
+    time::{Duration, Instant},
+};
+
+fn notification_command(title: &str, body: &str) -> Option<Command> {
+    if cfg!(target_os = "macos") {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        let mut command = Command::new("osascript");
+        command.args([
+            "-e",
+            &format!(
+                "display notification {} with title {}",
+                quote(body),
+                quote(title)
+            ),
+        ]);
+        Some(command)
+    } else if cfg!(unix) {
+        let mut command = Command::new("notify-send");
+        command.args(["--app-name=Termie", "--", title, body]);
+        Some(command)
+    } else {
+        None
+    }
+}
+
+pub struct Notifier {
+    enabled: bool,
+    min_interval: Duration,
+    last_shown: Option<Instant>,
+}
+
+impl Notifier {
+    pub fn new(enabled: bool, min_interval: Duration) -> Notifier {
+        Notifier {
+            enabled,
+            min_interval,
+            last_shown: None,
+        }
+    }
+
+    /// Whether a notification may be shown now. Ones that come too quickly after the last one
+    /// are dropped, so that a program cannot flood the desktop
+    fn take_slot(&mut self, now: Instant) -> bool {
+        if self
+            .last_shown
+            .is_some_and(|last| now - last < self.min_interval)
+        {
+            return false;
+        }
+        self.last_shown = Some(now);
+        true
+    }
+
+    /// Post `notification`, titled `default_title` if it has no title of its own. Returns false
+    /// if notifications are disabled or not supported, in which case the caller rings the bell
+    /// instead
+    pub fn notify(
+        &mut self,
+        notification: &Notification,
+        default_title: &str,
+        now: Instant,
+    ) -> bool {
+        if !self.enabled {
+            return false;
+        }
+
+        let title = notification.title.as_deref().unwrap_or(default_title);
+        let Some(mut command) = notification_command(title, &notification.body) else {
+            return false;
+        };
+
+        if !self.take_slot(now) {
+            debug!(
+                "Dropping notification {:?}, too soon after the last one",
+                notification.body
+            );
+            return true;
+        }
+
+        // Waited for on a thread of its own so that the child is reaped without blocking a
+        // frame
+        std::thread::spawn(move || match command.status() {
+            Ok(status) if !status.success() => {
+                warn!("Notification command failed with {status}");
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Failed to run notification command: {e}"),
+        });
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_notification_rate_limit() {
+        let start = Instant::now();
+        let mut notifier = Notifier::new(true, Duration::from_secs(2));
+        assert!(notifier.take_slot(start));
+        assert!(!notifier.take_slot(start + Duration::from_secs(1)));
+        assert!(notifier.take_slot(start + Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,99 @@
+use crate::config::{Config, Profile};
+use eframe::egui;
+
+/// Which of the configured profiles applies to the window. Unless one was picked from the menu,
+/// the first profile matching the foreground process and title is used
+pub struct ProfileTracker {
+    // Picked from the menu, matching is off while this is set
+    picked: Option<String>,
+    active: Option<String>,
+}
+
+impl ProfileTracker {
+    pub fn new() -> ProfileTracker {
+        ProfileTracker {
+            picked: None,
+            active: None,
+        }
+    }
+
+    pub fn active<'a>(&self, config: &'a Config) -> Option<&'a Profile> {
+        self.active.as_deref().and_then(|name| config.profile(name))
+    }
+
+    /// Match the profiles against what runs in the window. Returns whether the active profile
+    /// changed
+    pub fn update(&mut self, config: &Config, process: Option<&str>, title: &str) -> bool {
+        let active = match &self.picked {
+            Some(name) => Some(name.clone()),
+            None => config
+                .matching_profile(process, title)
+                .map(|profile| profile.name.clone()),
+        };
+        if active == self.active {
+            return false;
+        }
+        info!(
+            "Switching to profile {}",
+            active.as_deref().unwrap_or("none")
+        );
+        self.active = active;
+        true
+    }
+
+    /// Pick a profile by hand. Returns whether the pick changed, the profile is switched to on
+    /// the next [`Self::update`]
+    pub fn show_options(&mut self, ui: &mut egui::Ui, config: &Config) -> bool {
+        if config.profiles.is_empty() {
+            return false;
+        }
+
+        let mut changed = false;
+        ui.menu_button("Profile", |ui| {
+            let mut picked = self.picked.clone();
+            ui.radio_value(&mut picked, None, "Automatic");
+            for profile in &config.profiles {
+                ui.radio_value(&mut picked, Some(profile.name.clone()), &profile.name);
+            }
+
+            if picked != self.picked {
+                self.picked = picked;
+                changed = true;
+                ui.close_menu();
+            }
+        });
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_profile_tracker() {
+        let config = Config::parse(
+            r#"{"profiles": [
+                {"name": "editor", "process": "vim", "font_size": 16},
+                {"name": "presenting", "font_size": 24}
+            ]}"#,
+        )
+        .expect("failed to parse config");
+        let mut tracker = ProfileTracker::new();
+        assert!(!tracker.update(&config, Some("bash"), "~"));
+        assert!(tracker.update(&config, Some("vim"), "~"));
+        assert_eq!(
+            tracker.active(&config).map(|profile| profile.font_size),
+            Some(Some(16.0))
+        );
+        assert!(!tracker.update(&config, Some("vim"), "notes.txt"));
+
+        // A profile picked by hand stays whatever runs
+        tracker.picked = Some("presenting".to_string());
+        assert!(tracker.update(&config, Some("vim"), "~"));
+        assert!(!tracker.update(&config, Some("bash"), "~"));
+        tracker.picked = None;
+        assert!(tracker.update(&config, Some("bash"), "~"));
+        assert!(tracker.active(&config).is_none());
+    }
+}
@@ -0,0 +1,123 @@
+//! Debouncing of window resizes. Every size change reflows the buffer and signals the child, and
+//! doing that on every frame of a drag has programs redraw for sizes that are already gone by the
+//! time their output arrives
+
+use std::time::{Duration, Instant};
+
+/// How long the size has to stay the same before it is applied
+pub const RESIZE_SETTLE_TIME: Duration = Duration::from_millis(50);
+
+type Size = (usize, usize);
+
+pub struct ResizeDebouncer {
+    applied: Option<Size>,
+    last_applied_at: Option<Instant>,
+    // Size the window currently has room for, and since when
+    pending: Option<(Size, Instant)>,
+}
+
+impl ResizeDebouncer {
+    pub fn new() -> ResizeDebouncer {
+        ResizeDebouncer {
+            applied: None,
+            last_applied_at: None,
+            pending: None,
+        }
+    }
+
+    /// For a terminal that was already given `size`, so that the window has to settle on a
+    /// different size before it is changed
+    pub fn with_applied(size: Size) -> ResizeDebouncer {
+        ResizeDebouncer {
+            applied: Some(size),
+            ..ResizeDebouncer::new()
+        }
+    }
+
+    /// Whether a size is waiting to settle. The caller has to schedule another update for it
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// The size to hand to the terminal now, if any. The first size is applied right away.
+    /// Interactive programs, e.g. an editor that is being resized, are kept roughly in step
+    /// during a drag instead of only seeing the final size
+    pub fn update(&mut self, size: Size, now: Instant, interactive: bool) -> Option<Size> {
+        if self.applied == Some(size) {
+            self.pending = None;
+            return None;
+        }
+
+        let since = match self.pending {
+            Some((pending, since)) if pending == size => since,
+            _ => now,
+        };
+        self.pending = Some((size, since));
+
+        let settled = now - since >= RESIZE_SETTLE_TIME;
+        let interactive_due = interactive
+            && self
+                .last_applied_at
+                .is_none_or(|t| now - t >= RESIZE_SETTLE_TIME);
+        if self.applied.is_some() && !settled && !interactive_due {
+            return None;
+        }
+
+        self.applied = Some(size);
+        self.last_applied_at = Some(now);
+        self.pending = None;
+        Some(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resize_debounce() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = ResizeDebouncer::new();
+
+        assert_eq!(debouncer.update((80, 24), at(0), false), Some((80, 24)));
+        assert_eq!(debouncer.update((80, 24), at(10), false), None);
+
+        // Sizes during a drag wait until the last one settles
+        assert_eq!(debouncer.update((90, 24), at(20), false), None);
+        assert_eq!(debouncer.update((100, 24), at(40), false), None);
+        assert!(debouncer.is_pending());
+        assert_eq!(debouncer.update((100, 24), at(80), false), None);
+        assert_eq!(debouncer.update((100, 24), at(90), false), Some((100, 24)));
+        assert!(!debouncer.is_pending());
+
+        // Going back to the applied size drops the pending one
+        assert_eq!(debouncer.update((110, 24), at(100), false), None);
+        assert_eq!(debouncer.update((100, 24), at(110), false), None);
+        assert!(!debouncer.is_pending());
+    }
+
+    #[test]
+    fn test_resize_debounce_with_applied() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = ResizeDebouncer::with_applied((80, 24));
+
+        // The window has not been resized to fit yet
+        assert_eq!(debouncer.update((100, 40), at(0), false), None);
+        assert_eq!(debouncer.update((80, 24), at(10), false), None);
+        assert!(!debouncer.is_pending());
+    }
+
+    #[test]
+    fn test_resize_debounce_interactive() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut debouncer = ResizeDebouncer::new();
+
+        assert_eq!(debouncer.update((80, 24), at(0), true), Some((80, 24)));
+        assert_eq!(debouncer.update((90, 24), at(10), true), None);
+        assert_eq!(debouncer.update((100, 24), at(60), true), Some((100, 24)));
+        assert_eq!(debouncer.update((110, 24), at(70), true), None);
+    }
+}
@@ -0,0 +1,124 @@
+//! Saving the scrollback and the screen to a file. There is no native file dialog, so the path is
+//! typed into a small window instead
+
+use eframe::egui::{self, Context, Key};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A new file in the home directory, named after the current time so that saving twice does
+/// not overwrite the first one
+fn default_path() -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let dir = std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    dir.join(format!("termie-{secs}.txt"))
+}
+
+pub struct SaveTextDialog {
+    path: String,
+    // Why the last attempt failed, shown until the next one
+    error: Option<String>,
+    focus_requested: bool,
+}
+
+impl SaveTextDialog {
+    pub fn new() -> SaveTextDialog {
+        SaveTextDialog {
+            path: default_path().to_string_lossy().into_owned(),
+            error: None,
+            focus_requested: true,
+        }
+    }
+
+    /// Write to the chosen file with `write`. Returns whether it worked, otherwise the error is
+    /// shown in the dialog
+    fn save(&mut self, write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>) -> bool {
+        let res = File::create(&self.path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            write(&mut writer)?;
+            writer.flush()
+        });
+        match res {
+            Ok(()) => {
+                info!("Saved terminal text to {}", self.path);
+                true
+            }
+            Err(e) => {
+                self.error = Some(format!("Failed to save: {e}"));
+                false
+            }
+        }
+    }
+
+    /// Returns whether the dialog stays open
+    pub fn show(
+        &mut self,
+        ctx: &Context,
+        write: impl FnOnce(&mut dyn Write) -> std::io::Result<()>,
+    ) -> bool {
+        let mut save = false;
+        let mut cancel = false;
+        egui::Window::new("Save scrollback")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+            .show(ctx, |ui| {
+                ui.label("Save the scrollback and the screen as text to:");
+                let response =
+                    ui.add(egui::TextEdit::singleline(&mut self.path).desired_width(320.0));
+                if std::mem::take(&mut self.focus_requested) {
+                    response.request_focus();
+                }
+                if response.lost_focus() {
+                    save = ui.input(|i| i.key_pressed(Key::Enter));
+                    cancel = ui.input(|i| i.key_pressed(Key::Escape));
+                }
+
+                if let Some(error) = &self.error {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+                ui.horizontal(|ui| {
+                    save |= ui.button("Save").clicked();
+                    cancel |= ui.button("Cancel").clicked();
+                });
+            });
+
+        if cancel {
+            return false;
+        }
+        !(save && self.save(write))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let path = dir.path().join("out.txt");
+        let mut dialog = SaveTextDialog::new();
+        assert!(dialog.path.ends_with(".txt"));
+
+        dialog.path = path.to_string_lossy().into_owned();
+        assert!(dialog.save(|out| out.write_all(b"saved\n")));
+        assert_eq!(std::fs::read(&path).expect("failed to read"), b"saved\n");
+        assert!(dialog.error.is_none());
+
+        dialog.path = dir
+            .path()
+            .join("missing/out.txt")
+            .to_string_lossy()
+            .into_owned();
+        assert!(!dialog.save(|out| out.write_all(b"saved\n")));
+        assert!(dialog.error.is_some());
+    }
+}
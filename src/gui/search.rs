@@ -0,0 +1,268 @@
+use crate::terminal_emulator::str_to_cells;
+use std::ops::Range;
+
+/// Find all non-overlapping occurrences of `needle` in `haystack`
+fn find_matches<T: PartialEq>(haystack: &[T], needle: &[T]) -> Vec<Range<usize>> {
+    let mut ret = Vec::new();
+    if needle.is_empty() || needle.len() > haystack.len() {
+        return ret;
+    }
+
+    let mut i = 0;
+    while i + needle.len() <= haystack.len() {
+        if &haystack[i..i + needle.len()] == needle {
+            ret.push(i..i + needle.len());
+            i += needle.len();
+        } else {
+            i += 1;
+        }
+    }
+
+    ret
+}
+
+/// State of the scrollback search bar. Matches are cell ranges into the full terminal buffer
+/// (scrollback followed by the visible area) of whichever screen is shown
+pub struct TerminalSearch {
+    pub query: String,
+    searched_query: String,
+    matches: Vec<Range<usize>>,
+    current: Option<usize>,
+    wrap_around: bool,
+    include_scrollback: bool,
+    // Which screen the matches are on
+    alternate_screen: bool,
+    scroll_requested: bool,
+}
+
+impl TerminalSearch {
+    pub fn new(wrap_around: bool, include_scrollback: bool) -> TerminalSearch {
+        TerminalSearch {
+            query: String::new(),
+            searched_query: String::new(),
+            matches: Vec::new(),
+            current: None,
+            wrap_around,
+            include_scrollback,
+            alternate_screen: false,
+            scroll_requested: false,
+        }
+    }
+
+    /// Re-run the search against the latest terminal contents. Called every frame while the
+    /// search bar is open so that new output is picked up. The current match is preserved where
+    /// possible so that output arriving does not yank the user away from what they were looking
+    /// at. Switching between the main and the alternate screen starts over like a new query
+    pub fn update(&mut self, scrollback: &[char], visible: &[char], alternate_screen: bool) {
+        let query_changed =
+            self.query != self.searched_query || alternate_screen != self.alternate_screen;
+        let previous_start = match query_changed {
+            true => None,
+            false => self.current_match().map(|m| m.start),
+        };
+
+        let query = str_to_cells(&self.query);
+        self.matches = match self.include_scrollback {
+            true => find_matches(&[scrollback, visible].concat(), &query),
+            false => find_matches(visible, &query)
+                .into_iter()
+                .map(|m| m.start + scrollback.len()..m.end + scrollback.len())
+                .collect(),
+        };
+        self.searched_query.clone_from(&self.query);
+        self.alternate_screen = alternate_screen;
+
+        let Some(last) = self.matches.len().checked_sub(1) else {
+            self.current = None;
+            return;
+        };
+
+        self.current = match previous_start {
+            Some(start) => Some(
+                self.matches
+                    .iter()
+                    .rposition(|m| m.start <= start)
+                    .unwrap_or(0),
+            ),
+            // Most recent output is the most likely target, start from the bottom
+            None => Some(last),
+        };
+
+        if query_changed {
+            self.scroll_requested = true;
+        }
+    }
+
+    pub fn matches(&self) -> &[Range<usize>] {
+        &self.matches
+    }
+
+    pub fn current_match(&self) -> Option<&Range<usize>> {
+        self.current.and_then(|idx| self.matches.get(idx))
+    }
+
+    /// e.g. "3/27", or "0/0" with no matches
+    pub fn counter_text(&self) -> String {
+        match self.current {
+            Some(idx) => format!("{}/{}", idx + 1, self.matches.len()),
+            None => format!("0/{}", self.matches.len()),
+        }
+    }
+
+    fn select(&mut self, idx: usize) {
+        self.current = Some(idx);
+        self.scroll_requested = true;
+    }
+
+    pub fn next(&mut self) {
+        let Some(current) = self.current else {
+            return;
+        };
+
+        if current + 1 < self.matches.len() {
+            self.select(current + 1);
+        } else if self.wrap_around {
+            self.select(0);
+        }
+    }
+
+    pub fn prev(&mut self) {
+        let Some(current) = self.current else {
+            return;
+        };
+
+        if current > 0 {
+            self.select(current - 1);
+        } else if self.wrap_around {
+            self.select(self.matches.len() - 1);
+        }
+    }
+
+    pub fn first(&mut self) {
+        if !self.matches.is_empty() {
+            self.select(0);
+        }
+    }
+
+    pub fn last(&mut self) {
+        if let Some(last) = self.matches.len().checked_sub(1) {
+            self.select(last);
+        }
+    }
+
+    /// Whether the view should scroll to the current match. Resets once read
+    pub fn take_scroll_request(&mut self) -> bool {
+        std::mem::take(&mut self.scroll_requested)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::cells;
+
+    #[test]
+    fn test_find_matches() {
+        assert_eq!(find_matches(b"abcabc", b"bc"), &[1..3, 4..6]);
+        assert_eq!(find_matches(b"aaaa", b"aa"), &[0..2, 2..4]);
+        assert!(find_matches(b"abc", b"").is_empty());
+        assert!(find_matches(b"ab", b"abc").is_empty());
+    }
+
+    #[test]
+    fn test_wide_chars() {
+        let mut search = TerminalSearch::new(false, true);
+        search.query = "漢".to_string();
+        search.update(&[], &str_to_cells("a漢b漢"), false);
+        assert_eq!(search.matches(), &[1..3, 4..6]);
+    }
+
+    #[test]
+    fn test_navigation() {
+        let mut search = TerminalSearch::new(false, true);
+        search.query = "a".to_string();
+        search.update(&cells(""), &cells("a a a"), false);
+
+        // Starts at the most recent match
+        assert_eq!(search.counter_text(), "3/3");
+        assert!(search.take_scroll_request());
+        assert!(!search.take_scroll_request());
+
+        search.next();
+        assert_eq!(search.counter_text(), "3/3");
+        search.prev();
+        assert_eq!(search.counter_text(), "2/3");
+        search.first();
+        assert_eq!(search.counter_text(), "1/3");
+        search.prev();
+        assert_eq!(search.counter_text(), "1/3");
+        search.last();
+        assert_eq!(search.current_match(), Some(&(4..5)));
+    }
+
+    #[test]
+    fn test_wrap_around() {
+        let mut search = TerminalSearch::new(true, true);
+        search.query = "a".to_string();
+        search.update(&cells(""), &cells("a a a"), false);
+
+        search.next();
+        assert_eq!(search.counter_text(), "1/3");
+        search.prev();
+        assert_eq!(search.counter_text(), "3/3");
+    }
+
+    #[test]
+    fn test_no_matches() {
+        let mut search = TerminalSearch::new(true, true);
+        search.query = "x".to_string();
+        search.update(&cells(""), &cells("a a a"), false);
+        assert_eq!(search.counter_text(), "0/0");
+        search.next();
+        search.prev();
+        search.first();
+        search.last();
+        assert_eq!(search.current_match(), None);
+    }
+
+    #[test]
+    fn test_incremental_update() {
+        let mut search = TerminalSearch::new(true, true);
+        search.query = "a".to_string();
+        search.update(&cells(""), &cells("a a a"), false);
+        search.prev();
+        assert_eq!(search.counter_text(), "2/3");
+        search.take_scroll_request();
+
+        // New output keeps the selected match and grows the count
+        search.update(&cells(""), &cells("a a a a a"), false);
+        assert_eq!(search.counter_text(), "2/5");
+        assert!(!search.take_scroll_request());
+
+        // Changing the query starts over from the bottom
+        search.query = "a a".to_string();
+        search.update(&cells(""), &cells("a a a a a"), false);
+        assert_eq!(search.counter_text(), "2/2");
+    }
+
+    #[test]
+    fn test_screens() {
+        let mut search = TerminalSearch::new(true, true);
+        search.query = "a".to_string();
+        search.update(&cells("a\n"), &cells("a a"), false);
+        assert_eq!(search.matches(), &[0..1, 2..3, 4..5]);
+        search.first();
+        search.take_scroll_request();
+
+        // The alternate screen starts over from its own bottom
+        search.update(&cells(""), &cells("a a"), true);
+        assert_eq!(search.current_match(), Some(&(2..3)));
+        assert!(search.take_scroll_request());
+
+        // Matches keep pointing into the full buffer without the scrollback
+        let mut search = TerminalSearch::new(true, false);
+        search.query = "a".to_string();
+        search.update(&cells("a\n"), &cells("a a"), false);
+        assert_eq!(search.matches(), &[2..3, 4..5]);
+    }
+}
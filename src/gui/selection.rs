@@ -0,0 +1,174 @@
+use crate::terminal_emulator::{cells_to_string, WIDE_CHAR_SPACER};
+use eframe::egui::Rangef;
+use std::{
+    ops::Range,
+    time::{Duration, Instant},
+};
+
+/// Points per second the view scrolls once a dragged selection reaches past its edge
+const AUTO_SCROLL_BASE_SPEED: f32 = 200.0;
+/// Speed added for every point the pointer is past the edge
+const AUTO_SCROLL_ACCELERATION: f32 = 20.0;
+/// Longest time one step accounts for, so that a slow frame does not jump far
+const AUTO_SCROLL_MAX_STEP: Duration = Duration::from_millis(100);
+
+/// Convert a character index into the text laid out from `data` back into a cell offset into
+/// `data`. The spacers of wide characters are not laid out
+pub fn char_idx_to_cell(data: &[char], char_idx: usize) -> usize {
+    data.iter()
+        .enumerate()
+        .filter(|(_, c)| **c != WIDE_CHAR_SPACER)
+        .nth(char_idx)
+        .map_or(data.len(), |(i, _)| i)
+}
+
+/// Text for the clipboard. Hard newlines are kept as is, soft wrapped lines have no newline in the
+/// buffer and so come out joined. Trimming only happens at hard newlines, so spaces that happened
+/// to land at a wrap point are kept
+pub fn selected_text(data: &[char], trim_trailing_whitespace: bool) -> String {
+    let text = cells_to_string(data);
+    if !trim_trailing_whitespace {
+        return text;
+    }
+
+    text.split('\n')
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// A mouse selection. Positions are cell offsets into the full terminal buffer (scrollback
+/// followed by the visible area), pointing between characters
+pub struct TerminalSelection {
+    anchor: usize,
+    head: usize,
+}
+
+impl TerminalSelection {
+    pub fn new(pos: usize) -> TerminalSelection {
+        TerminalSelection {
+            anchor: pos,
+            head: pos,
+        }
+    }
+
+    /// Move the end being dragged, the position the selection started from stays put
+    pub fn extend_to(&mut self, pos: usize) {
+        self.head = pos;
+    }
+
+    pub fn range(&self) -> Range<usize> {
+        self.anchor.min(self.head)..self.anchor.max(self.head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.anchor == self.head
+    }
+}
+
+/// Scrolling the view while a selection is dragged past its top or bottom, faster the farther out
+/// the pointer is
+pub struct AutoScroll {
+    last_step: Option<Instant>,
+}
+
+impl AutoScroll {
+    pub fn new() -> AutoScroll {
+        AutoScroll { last_step: None }
+    }
+
+    /// How many points to scroll down, or up if negative, with the pointer at `pointer_y` and
+    /// the view covering `view`. Nothing while the pointer is inside the view
+    pub fn step(&mut self, view: Rangef, pointer_y: f32, now: Instant) -> f32 {
+        let distance = if pointer_y < view.min {
+            pointer_y - view.min
+        } else if pointer_y > view.max {
+            pointer_y - view.max
+        } else {
+            self.stop();
+            return 0.0;
+        };
+
+        // The first step only starts the clock
+        let elapsed = self.last_step.map_or(Duration::ZERO, |last| {
+            (now - last).min(AUTO_SCROLL_MAX_STEP)
+        });
+        self.last_step = Some(now);
+        let speed = AUTO_SCROLL_BASE_SPEED + distance.abs() * AUTO_SCROLL_ACCELERATION;
+        distance.signum() * speed * elapsed.as_secs_f32()
+    }
+
+    pub fn stop(&mut self) {
+        self.last_step = None;
+    }
+
+    pub fn is_scrolling(&self) -> bool {
+        self.last_step.is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::terminal_emulator::str_to_cells;
+    use crate::test_util::cells;
+
+    #[test]
+    fn test_char_idx_to_cell() {
+        assert_eq!(char_idx_to_cell(&cells("abc"), 0), 0);
+        assert_eq!(char_idx_to_cell(&cells("abc"), 2), 2);
+        assert_eq!(char_idx_to_cell(&cells("abc"), 10), 3);
+        assert_eq!(char_idx_to_cell(&cells("aéb"), 2), 2);
+
+        // The spacer after 漢 is not laid out
+        let data = str_to_cells("a漢b");
+        assert_eq!(char_idx_to_cell(&data, 2), 3);
+        assert_eq!(char_idx_to_cell(&data, 3), 4);
+    }
+
+    #[test]
+    fn test_selected_text() {
+        let data = &cells("ls   \nfoo bar   \n  \nbaz");
+        assert_eq!(selected_text(data, true), "ls\nfoo bar\n\nbaz");
+        assert_eq!(selected_text(data, false), "ls   \nfoo bar   \n  \nbaz");
+
+        // "abc d" wrapped at a width of 4, the space before the wrap is part of the line
+        assert_eq!(selected_text(&cells("abc d  \n"), true), "abc d\n");
+        assert_eq!(selected_text(&str_to_cells("漢字 "), true), "漢字");
+    }
+
+    #[test]
+    fn test_selection_range() {
+        let mut selection = TerminalSelection::new(10);
+        assert!(selection.is_empty());
+        selection.extend_to(4);
+        assert_eq!(selection.range(), 4..10);
+        selection.extend_to(12);
+        assert_eq!(selection.range(), 10..12);
+        assert!(!selection.is_empty());
+    }
+
+    #[test]
+    fn test_auto_scroll() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let view = Rangef::new(100.0, 500.0);
+        let mut auto_scroll = AutoScroll::new();
+
+        assert_eq!(auto_scroll.step(view, 300.0, at(0)), 0.0);
+        assert!(!auto_scroll.is_scrolling());
+
+        assert_eq!(auto_scroll.step(view, 90.0, at(0)), 0.0);
+        assert!(auto_scroll.is_scrolling());
+        let near = auto_scroll.step(view, 90.0, at(100));
+        assert!(near < 0.0);
+        let far = auto_scroll.step(view, 40.0, at(200));
+        assert!(far < near);
+
+        // A stall does not turn into a jump
+        assert_eq!(auto_scroll.step(view, 510.0, at(5000)), -near);
+
+        assert_eq!(auto_scroll.step(view, 400.0, at(5100)), 0.0);
+        assert!(!auto_scroll.is_scrolling());
+    }
+}
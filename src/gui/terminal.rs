@@ -1,131 +1,374 @@
+use super::{
+    background_image::BackgroundImageRenderer,
+    box_drawing,
+    clipboard::{self, Clipboard},
+    cursor_blink::CursorBlink,
+    keystrokes::KeystrokeHistory,
+    links,
+    save_text::SaveTextDialog,
+    search::TerminalSearch,
+    selection::{self, AutoScroll, TerminalSelection},
+};
+use crate::config::{Config, CursorTextColor, KeyCombo, KeyMacro, LinkModifier};
 use crate::error::backtraced_err;
 use crate::terminal_emulator::{
-    CursorPos, FormatTag, TermIo, TerminalColor, TerminalEmulator, TerminalInput,
+    cells_to_string, ColorScheme, CursorPos, EnterSequence, FormatTag, KeyCode, KeyEvent,
+    KeyEventKind, KeyModifiers, LineSizeTag, Rgb, Rgba, TermIo, TerminalData, TerminalEmulator,
+    TerminalInput, UnderlineStyle, WIDE_CHAR_SPACER,
 };
 use eframe::egui::{
-    self, text::LayoutJob, Color32, Context, DragValue, Event, FontData, FontDefinitions,
-    FontFamily, FontId, InputState, Key, Modifiers, Rect, TextFormat, TextStyle, Ui,
+    self,
+    text::{CCursor, LayoutJob, LayoutSection},
+    Color32, Context, DragValue, Event, FontData, FontDefinitions, FontFamily, FontId, Galley,
+    InputState, Key, Modifiers, Pos2, Rangef, Rect, Sense, Shape, Stroke, TextFormat, TextStyle,
+    Ui, UiBuilder,
 };
 
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 const REGULAR_FONT_NAME: &str = "firacode-nerd";
 const BOLD_FONT_NAME: &str = "firacode-nerd-bold";
 
+const CURSOR_TRAIL_DURATION: Duration = Duration::from_millis(200);
+/// How long the line that navigation jumped to stays highlighted
+const JUMP_FLASH_DURATION: Duration = Duration::from_millis(700);
+const GRID_OVERLAY_COLOR: Color32 = Color32::from_rgba_premultiplied(90, 90, 30, 90);
+const GRID_OVERLAY_COLUMNS: usize = 8;
+const GRID_OVERLAY_ROWS: usize = 5;
+/// Scrollback rows laid out above and below the viewport, so that scrolling does not uncover
+/// rows that are not there yet
+const SCROLLBACK_OVERSCAN_ROWS: usize = 10;
+
+/// Ctrl+Shift+F, plain Ctrl+F belongs to the shell
+fn is_search_shortcut(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Key {
+            key: Key::F,
+            pressed: true,
+            modifiers,
+            ..
+        } if modifiers.ctrl && modifiers.shift
+    )
+}
+
+/// Ctrl+Shift+O, copies the output of the last command
+fn is_copy_command_output_shortcut(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Key {
+            key: Key::O,
+            pressed: true,
+            modifiers,
+            ..
+        } if modifiers.ctrl && modifiers.shift
+    )
+}
+
+/// Ctrl+Shift+C, plain Ctrl+C is sent to the shell as an interrupt. egui turns both into a copy
+/// event, so tell them apart by the modifiers
+fn is_copy_shortcut(event: &Event, modifiers: &Modifiers) -> bool {
+    matches!(event, Event::Copy) && modifiers.shift
+}
+
+fn is_paste_shortcut(event: &Event, modifiers: &Modifiers) -> bool {
+    matches!(event, Event::Paste(_)) && modifiers.shift
+}
+
+/// The key for the key encodings of the terminal, keys that type text as the character they type
+/// without shift
+fn key_code(key: Key) -> Option<KeyCode> {
+    let code = match key {
+        Key::Escape => KeyCode::Escape,
+        Key::Enter => KeyCode::Enter,
+        Key::Tab => KeyCode::Tab,
+        Key::Backspace => KeyCode::Backspace,
+        Key::Insert => KeyCode::Insert,
+        Key::Delete => KeyCode::Delete,
+        Key::ArrowLeft => KeyCode::ArrowLeft,
+        Key::ArrowRight => KeyCode::ArrowRight,
+        Key::ArrowUp => KeyCode::ArrowUp,
+        Key::ArrowDown => KeyCode::ArrowDown,
+        Key::PageUp => KeyCode::PageUp,
+        Key::PageDown => KeyCode::PageDown,
+        Key::Home => KeyCode::Home,
+        Key::End => KeyCode::End,
+        Key::F1 => KeyCode::F(1),
+        Key::F2 => KeyCode::F(2),
+        Key::F3 => KeyCode::F(3),
+        Key::F4 => KeyCode::F(4),
+        Key::F5 => KeyCode::F(5),
+        Key::F6 => KeyCode::F(6),
+        Key::F7 => KeyCode::F(7),
+        Key::F8 => KeyCode::F(8),
+        Key::F9 => KeyCode::F(9),
+        Key::F10 => KeyCode::F(10),
+        Key::F11 => KeyCode::F(11),
+        Key::F12 => KeyCode::F(12),
+        Key::Space => KeyCode::Char(' '),
+        Key::Colon => KeyCode::Char(':'),
+        Key::Comma => KeyCode::Char(','),
+        Key::Backslash => KeyCode::Char('\\'),
+        Key::Slash => KeyCode::Char('/'),
+        Key::Pipe => KeyCode::Char('|'),
+        Key::Questionmark => KeyCode::Char('?'),
+        Key::OpenBracket => KeyCode::Char('['),
+        Key::CloseBracket => KeyCode::Char(']'),
+        Key::Backtick => KeyCode::Char('`'),
+        Key::Minus => KeyCode::Char('-'),
+        Key::Period => KeyCode::Char('.'),
+        Key::Plus => KeyCode::Char('+'),
+        Key::Equals => KeyCode::Char('='),
+        Key::Semicolon => KeyCode::Char(';'),
+        Key::Quote => KeyCode::Char('\''),
+        // Letters and digits are named after themselves
+        _ => match key.name().as_bytes() {
+            [c] if c.is_ascii_alphanumeric() => KeyCode::Char(c.to_ascii_lowercase() as char),
+            _ => return None,
+        },
+    };
+    Some(code)
+}
+
+/// Send `event` with the key encoding the program asked for, see
+/// [`TerminalEmulator::write_key`]. Returns whether it was taken care of, otherwise the legacy
+/// encoding is used
+fn write_key_event<Io: TermIo>(
+    terminal_emulator: &mut TerminalEmulator<Io>,
+    event: &Event,
+    modifiers: &Modifiers,
+) -> bool {
+    let (key, kind, modifiers) = match event {
+        Event::Key {
+            key,
+            pressed,
+            repeat,
+            modifiers,
+            ..
+        } => {
+            let kind = match (pressed, repeat) {
+                (false, _) => KeyEventKind::Release,
+                (true, false) => KeyEventKind::Press,
+                (true, true) => KeyEventKind::Repeat,
+            };
+            (key_code(*key), kind, modifiers)
+        }
+        // egui reports Ctrl+C and Ctrl+V as clipboard events instead of keys
+        Event::Copy => (Some(KeyCode::Char('c')), KeyEventKind::Press, modifiers),
+        Event::Paste(_) => (Some(KeyCode::Char('v')), KeyEventKind::Press, modifiers),
+        _ => return false,
+    };
+    let Some(key) = key else {
+        return false;
+    };
+
+    let event = KeyEvent {
+        key,
+        modifiers: KeyModifiers {
+            shift: modifiers.shift,
+            alt: modifiers.alt,
+            ctrl: modifiers.ctrl,
+            super_key: modifiers.mac_cmd,
+        },
+        kind,
+    };
+    match terminal_emulator.write_key(&event) {
+        Ok(written) => written,
+        Err(e) => {
+            error!(
+                "Failed to write key to terminal emulator: {}",
+                backtraced_err(&*e)
+            );
+            true
+        }
+    }
+}
+
+/// Send the input events to the child, or with `send` off only encode them. Returns what each of
+/// the events was encoded as, in order. `own_shortcuts` are acted on by the widget and not sent
 fn write_input_to_terminal<Io: TermIo>(
     input: &InputState,
     terminal_emulator: &mut TerminalEmulator<Io>,
-) {
+    clipboard: &mut dyn Clipboard,
+    macros: &[KeyMacro],
+    own_shortcuts: &[&KeyCombo],
+    enter_sends: EnterSequence,
+    send: bool,
+) -> Vec<Vec<u8>> {
+    // A key press that produces text is followed by a matching text event, which should not be
+    // sent if the key press triggered a macro
+    let mut suppress_text = false;
+
+    let mut sent = Vec::with_capacity(input.raw.events.len());
     for event in &input.raw.events {
-        let inputs: Cow<'static, [TerminalInput]> = match event {
-            Event::Text(text) => text
-                .as_bytes()
-                .iter()
-                .map(|c| TerminalInput::Ascii(*c))
-                .collect::<Vec<_>>()
-                .into(),
-            Event::Key {
-                key: Key::Enter,
-                pressed: true,
-                ..
-            } => [TerminalInput::Enter].as_ref().into(),
-            // https://github.com/emilk/egui/issues/3653
-            Event::Copy => {
-                // NOTE: Technically not correct if we were on a mac, but also we are using linux
-                // syscalls so we'd have to solve that before this is a problem
-                [TerminalInput::Ctrl(b'c')].as_ref().into()
-            }
-            Event::Key {
-                key,
-                pressed: true,
-                modifiers: Modifiers { ctrl: true, .. },
-                ..
-            } => {
-                if *key >= Key::A && *key <= Key::Z {
-                    let name = key.name();
-                    assert!(name.len() == 1);
-                    let name_c = name.as_bytes()[0];
-                    vec![TerminalInput::Ctrl(name_c)].into()
-                } else if *key == Key::OpenBracket {
-                    [TerminalInput::Ctrl(b'[')].as_ref().into()
-                } else if *key == Key::CloseBracket {
-                    [TerminalInput::Ctrl(b']')].as_ref().into()
-                } else if *key == Key::Backslash {
-                    [TerminalInput::Ctrl(b'\\')].as_ref().into()
-                } else {
-                    info!("Unexpected ctrl key: {}", key.name());
-                    continue;
+        terminal_emulator.tap_input(send);
+        'event: {
+            if is_search_shortcut(event)
+                || is_copy_shortcut(event, &input.modifiers)
+                || is_copy_command_output_shortcut(event)
+                || own_shortcuts.iter().any(|combo| combo.matches(event))
+            {
+                break 'event;
+            }
+
+            if let Some(key_macro) = macros.iter().find(|m| m.combo.matches(event)) {
+                if let Err(e) = terminal_emulator.write_bytes(&key_macro.payload) {
+                    error!(
+                        "Failed to write macro to terminal emulator: {}",
+                        backtraced_err(&*e)
+                    );
                 }
+                suppress_text = true;
+                break 'event;
             }
-            Event::Key {
-                key: Key::Backspace,
-                pressed: true,
-                ..
-            } => [TerminalInput::Backspace].as_ref().into(),
-            Event::Key {
-                key: Key::ArrowUp,
-                pressed: true,
-                ..
-            } => [TerminalInput::ArrowUp].as_ref().into(),
-            Event::Key {
-                key: Key::ArrowDown,
-                pressed: true,
-                ..
-            } => [TerminalInput::ArrowDown].as_ref().into(),
-            Event::Key {
-                key: Key::ArrowLeft,
-                pressed: true,
-                ..
-            } => [TerminalInput::ArrowLeft].as_ref().into(),
-            Event::Key {
-                key: Key::ArrowRight,
-                pressed: true,
-                ..
-            } => [TerminalInput::ArrowRight].as_ref().into(),
-            Event::Key {
-                key: Key::Home,
-                pressed: true,
-                ..
-            } => [TerminalInput::Home].as_ref().into(),
-            Event::Key {
-                key: Key::End,
-                pressed: true,
-                ..
-            } => [TerminalInput::End].as_ref().into(),
-            Event::Key {
-                key: Key::Delete,
-                pressed: true,
-                ..
-            } => [TerminalInput::Delete].as_ref().into(),
-            Event::Key {
-                key: Key::Insert,
-                pressed: true,
-                ..
-            } => [TerminalInput::Insert].as_ref().into(),
-            Event::Key {
-                key: Key::PageUp,
-                pressed: true,
-                ..
-            } => [TerminalInput::PageUp].as_ref().into(),
-            Event::Key {
-                key: Key::PageDown,
-                pressed: true,
-                ..
-            } => [TerminalInput::PageDown].as_ref().into(),
-            _ => {
-                continue;
+
+            if let (Event::Paste(text), true) = (event, is_paste_shortcut(event, &input.modifiers))
+            {
+                let text = clipboard.paste(text.clone());
+                if let Err(e) = terminal_emulator.paste(text.as_bytes()) {
+                    error!("Failed to paste: {}", backtraced_err(&*e));
+                }
+                break 'event;
             }
-        };
 
-        for input in inputs.as_ref() {
-            if let Err(e) = terminal_emulator.write(input.clone()) {
-                error!(
-                    "Failed to write input to terminal emulator: {}",
-                    backtraced_err(&*e)
-                );
+            if let Event::Text(_) = event {
+                if std::mem::take(&mut suppress_text) {
+                    break 'event;
+                }
+            } else if let Event::Key { pressed: true, .. } = event {
+                suppress_text = false;
+            }
+
+            if write_key_event(terminal_emulator, event, &input.modifiers) {
+                // When all keys are reported, the text of a key is in its escape code already
+                if let Event::Key { pressed: true, .. } = event {
+                    suppress_text = true;
+                }
+                break 'event;
+            }
+
+            let inputs: Cow<'static, [TerminalInput]> = match event {
+                Event::Text(text) => text
+                    .as_bytes()
+                    .iter()
+                    .map(|c| TerminalInput::Ascii(*c))
+                    .collect::<Vec<_>>()
+                    .into(),
+                Event::Key {
+                    key: Key::Enter,
+                    pressed: true,
+                    ..
+                } => vec![TerminalInput::Enter(enter_sends)].into(),
+                // https://github.com/emilk/egui/issues/3653
+                Event::Copy => {
+                    // NOTE: Technically not correct if we were on a mac, but also we are using linux
+                    // syscalls so we'd have to solve that before this is a problem
+                    [TerminalInput::Ctrl(b'c')].as_ref().into()
+                }
+                // Same as copy, only ctrl+shift+v pastes
+                Event::Paste(_) => [TerminalInput::Ctrl(b'v')].as_ref().into(),
+                Event::Key {
+                    key,
+                    pressed: true,
+                    modifiers: Modifiers { ctrl: true, .. },
+                    ..
+                } => {
+                    if *key >= Key::A && *key <= Key::Z {
+                        let name = key.name();
+                        assert!(name.len() == 1);
+                        let name_c = name.as_bytes()[0];
+                        vec![TerminalInput::Ctrl(name_c)].into()
+                    } else if *key == Key::OpenBracket {
+                        [TerminalInput::Ctrl(b'[')].as_ref().into()
+                    } else if *key == Key::CloseBracket {
+                        [TerminalInput::Ctrl(b']')].as_ref().into()
+                    } else if *key == Key::Backslash {
+                        [TerminalInput::Ctrl(b'\\')].as_ref().into()
+                    } else {
+                        info!("Unexpected ctrl key: {}", key.name());
+                        break 'event;
+                    }
+                }
+                Event::Key {
+                    key: Key::Backspace,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::Backspace].as_ref().into(),
+                Event::Key {
+                    key: Key::ArrowUp,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::ArrowUp].as_ref().into(),
+                Event::Key {
+                    key: Key::ArrowDown,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::ArrowDown].as_ref().into(),
+                Event::Key {
+                    key: Key::ArrowLeft,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::ArrowLeft].as_ref().into(),
+                Event::Key {
+                    key: Key::ArrowRight,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::ArrowRight].as_ref().into(),
+                Event::Key {
+                    key: Key::Home,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::Home].as_ref().into(),
+                Event::Key {
+                    key: Key::End,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::End].as_ref().into(),
+                Event::Key {
+                    key: Key::Delete,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::Delete].as_ref().into(),
+                Event::Key {
+                    key: Key::Insert,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::Insert].as_ref().into(),
+                Event::Key {
+                    key: Key::PageUp,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::PageUp].as_ref().into(),
+                Event::Key {
+                    key: Key::PageDown,
+                    pressed: true,
+                    ..
+                } => [TerminalInput::PageDown].as_ref().into(),
+                _ => {
+                    break 'event;
+                }
+            };
+
+            for input in inputs.as_ref() {
+                if let Err(e) = terminal_emulator.write(input.clone()) {
+                    error!(
+                        "Failed to write input to terminal emulator: {}",
+                        backtraced_err(&*e)
+                    );
+                }
             }
         }
+        sent.push(terminal_emulator.take_tapped_input());
     }
+    sent
 }
 
 fn get_char_size(ctx: &egui::Context, font_size: f32) -> (f32, f32) {
@@ -174,20 +417,173 @@ fn paint_cursor(
     label_rect: Rect,
     character_size: &(f32, f32),
     cursor_pos: &CursorPos,
+    color: Color32,
     ui: &mut Ui,
-) {
+) -> Rect {
     let painter = ui.painter();
 
     let y_offset = cursor_pos.y as f32 * character_size.1;
     let x_offset = cursor_pos.x as f32 * character_size.0;
-    painter.rect_filled(
-        Rect::from_min_size(
-            egui::pos2(label_rect.left() + x_offset, label_rect.top() + y_offset),
-            egui::vec2(character_size.0, character_size.1),
-        ),
-        0.0,
-        Color32::GRAY,
+    let rect = Rect::from_min_size(
+        egui::pos2(label_rect.left() + x_offset, label_rect.top() + y_offset),
+        egui::vec2(character_size.0, character_size.1),
     );
+    painter.rect_filled(rect, 0.0, color);
+    rect
+}
+
+/// Draw the character under the cursor at `cursor_rect` again on top of the cursor, which
+/// would hide it otherwise
+fn paint_cursor_text(
+    ui: &Ui,
+    canvas: &RenderedTerminalData,
+    cursor_rect: Rect,
+    text_color: CursorTextColor,
+    text_style: &TerminalTextStyle,
+) {
+    let origin = canvas.response.rect.min;
+    let center = cursor_rect.center() - origin.to_vec2();
+    let Some((row, glyph)) = canvas
+        .galley
+        .rows
+        .iter()
+        .filter(|row| row.rect.y_range().contains(center.y))
+        .find_map(|row| {
+            let glyph = row
+                .glyphs
+                .iter()
+                .find(|glyph| (glyph.pos.x..glyph.max_x()).contains(&center.x))?;
+            Some((row, glyph))
+        })
+    else {
+        return;
+    };
+    if glyph.chr.is_whitespace() {
+        return;
+    }
+
+    let format = &canvas.galley.job.sections[glyph.section_index as usize].format;
+    let color = match text_color {
+        CursorTextColor::Inverted if format.background == Color32::TRANSPARENT => {
+            rgb_to_egui(text_style.color_scheme.background)
+        }
+        CursorTextColor::Inverted => format.background,
+        CursorTextColor::Fixed(rgb) => rgb_to_egui(rgb),
+    };
+
+    let painter = ui.painter();
+    let cell = Rect::from_x_y_ranges(glyph.pos.x..=glyph.max_x(), row.rect.y_range())
+        .translate(origin.to_vec2());
+    let builtin_shapes = text_style
+        .builtin_box_drawing
+        .then(|| box_drawing::glyph_shapes(glyph.chr, cell, color, ui.ctx().pixels_per_point()))
+        .flatten();
+    if let Some(shapes) = builtin_shapes {
+        painter.extend(shapes);
+        return;
+    }
+
+    let galley = ui
+        .fonts(|fonts| fonts.layout_no_wrap(glyph.chr.to_string(), format.font_id.clone(), color));
+    painter.galley(origin + glyph.logical_rect().min.to_vec2(), galley, color);
+}
+
+/// A position the cursor recently moved away from
+struct CursorTrailPoint {
+    pos: CursorPos,
+    left_at: Instant,
+}
+
+/// Faint copies of the cursor at its previous positions, which makes a fast moving cursor easier
+/// to follow
+struct CursorTrail {
+    enable: bool,
+    last_pos: Option<CursorPos>,
+    points: Vec<CursorTrailPoint>,
+}
+
+impl CursorTrail {
+    fn new(enable: bool) -> CursorTrail {
+        CursorTrail {
+            enable,
+            last_pos: None,
+            points: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, pos: &CursorPos, now: Instant) {
+        self.points
+            .retain(|p| now.duration_since(p.left_at) < CURSOR_TRAIL_DURATION);
+
+        if !self.enable {
+            self.points.clear();
+        } else if let Some(last_pos) = self.last_pos.take().filter(|p| p != pos) {
+            self.points.push(CursorTrailPoint {
+                pos: last_pos,
+                left_at: now,
+            });
+        }
+
+        self.last_pos = Some(pos.clone());
+    }
+
+    fn paint(
+        &self,
+        label_rect: Rect,
+        character_size: &(f32, f32),
+        color: Color32,
+        ui: &mut Ui,
+        now: Instant,
+    ) {
+        for point in &self.points {
+            let age = now.duration_since(point.left_at).as_secs_f32();
+            let remaining = 1.0 - age / CURSOR_TRAIL_DURATION.as_secs_f32();
+            let color = color.gamma_multiply(0.4 * remaining.max(0.0));
+            paint_cursor(label_rect, character_size, &point.pos, color, ui);
+        }
+
+        if !self.points.is_empty() {
+            ui.ctx().request_repaint();
+        }
+    }
+}
+
+/// Briefly highlights the line the view jumped to, so that the eye can find it
+struct JumpFlash {
+    range: Range<usize>,
+    start: Instant,
+}
+
+impl JumpFlash {
+    /// Flash the line of `data` that `pos` is on
+    fn new(data: &[char], pos: usize, now: Instant) -> JumpFlash {
+        let pos = pos.min(data.len());
+        let start = data[..pos]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |i| i + 1);
+        let end = data[pos..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(data.len(), |i| pos + i);
+        JumpFlash {
+            range: start..end,
+            start: now,
+        }
+    }
+
+    /// How strong the highlight is, fading from 1 to 0. Without animations it stays at full
+    /// strength until it ends. `None` once it is over
+    fn strength(&self, now: Instant, reduce_motion: bool) -> Option<f32> {
+        let age = now.saturating_duration_since(self.start);
+        if age >= JUMP_FLASH_DURATION {
+            return None;
+        }
+        if reduce_motion {
+            return Some(1.0);
+        }
+        Some(1.0 - age.as_secs_f32() / JUMP_FLASH_DURATION.as_secs_f32())
+    }
 }
 
 fn setup_fonts(ctx: &egui::Context) {
@@ -243,75 +639,54 @@ impl TerminalFonts {
     }
 }
 
-fn ansi_256_to_rgb(idx: u8) -> (u8, u8, u8) {
-    match idx {
-        0..=15 => {
-            // Basic 16 colors
-            match idx {
-                0 => (0, 0, 0),
-                1 => (205, 0, 0),
-                2 => (0, 205, 0),
-                3 => (205, 205, 0),
-                4 => (0, 0, 238),
-                5 => (205, 0, 205),
-                6 => (0, 205, 205),
-                7 => (229, 229, 229),
-                8 => (127, 127, 127),
-                9 => (255, 0, 0),
-                10 => (0, 255, 0),
-                11 => (255, 255, 0),
-                12 => (92, 92, 255),
-                13 => (255, 0, 255),
-                14 => (0, 255, 255),
-                15 => (255, 255, 255),
-                _ => unreachable!(),
-            }
-        }
-        16..=231 => {
-            // 216-color cube: 16 + 36 × r + 6 × g + b (r, g, b in 0..6)
-            let idx = idx - 16;
-            let r = idx / 36;
-            let g = (idx % 36) / 6;
-            let b = idx % 6;
-            let to_rgb = |v| if v == 0 { 0 } else { 55 + v * 40 };
-            (to_rgb(r), to_rgb(g), to_rgb(b))
-        }
-        232..=255 => {
-            // Grayscale: 8 + 10 × level (level in 0..24)
-            let gray = 8 + (idx - 232) * 10;
-            (gray, gray, gray)
-        }
-    }
-}
-
-fn terminal_color_to_egui(default_color: &Color32, color: &TerminalColor) -> Color32 {
-    match color {
-        TerminalColor::Default => *default_color,
-        TerminalColor::Black => Color32::BLACK,
-        TerminalColor::Red => Color32::RED,
-        TerminalColor::Green => Color32::GREEN,
-        TerminalColor::Yellow => Color32::YELLOW,
-        TerminalColor::Blue => Color32::BLUE,
-        TerminalColor::Magenta => Color32::from_rgb(255, 0, 255),
-        TerminalColor::Cyan => Color32::from_rgb(0, 255, 255),
-        TerminalColor::White => Color32::WHITE,
-        TerminalColor::Indexed256(idx) => {
-            let (r, g, b) = ansi_256_to_rgb(*idx);
-            Color32::from_rgb(r, g, b)
-        }
-        TerminalColor::Rgb(r, g, b) => Color32::from_rgb(*r, *g, *b),
+fn rgb_to_egui((r, g, b): Rgb) -> Color32 {
+    Color32::from_rgb(r, g, b)
+}
+
+fn rgba_to_egui((r, g, b, a): Rgba) -> Color32 {
+    Color32::from_rgba_unmultiplied(r, g, b, a)
+}
+
+/// Text of terminal cells as it is laid out, along with where each cell is in it. The spacers of
+/// wide characters have no text
+struct CellText {
+    text: String,
+    // Byte offset into `text` of each cell, followed by the length of `text`
+    offsets: Vec<usize>,
+}
+
+impl CellText {
+    fn new(cells: &[char]) -> CellText {
+        let mut text = String::with_capacity(cells.len());
+        let mut offsets = Vec::with_capacity(cells.len() + 1);
+        for c in cells {
+            offsets.push(text.len());
+            if *c != WIDE_CHAR_SPACER {
+                text.push(*c);
+            }
+        }
+        offsets.push(text.len());
+        CellText { text, offsets }
+    }
+
+    /// Byte range of the text of `cells`, None if there is none
+    fn byte_range(&self, cells: Range<usize>) -> Option<Range<usize>> {
+        let num_cells = self.offsets.len() - 1;
+        let start = self.offsets[cells.start.min(num_cells)];
+        let end = self.offsets[cells.end.min(num_cells)];
+        (start < end).then_some(start..end)
     }
 }
 
 fn create_terminal_output_layout_job(
     style: &egui::Style,
     width: f32,
-    data: &[u8],
-) -> (LayoutJob, TextFormat, String) {
+    data: &[char],
+) -> (LayoutJob, TextFormat, CellText) {
     let text_style = &style.text_styles[&TextStyle::Monospace];
-    let data_utf8 = String::from_utf8_lossy(data).to_string();
+    let cell_text = CellText::new(data);
     let mut job = egui::text::LayoutJob::simple(
-        data_utf8.clone(),
+        cell_text.text.clone(),
         text_style.clone(),
         style.visuals.text_color(),
         width,
@@ -320,44 +695,401 @@ fn create_terminal_output_layout_job(
     job.wrap.max_width = f32::INFINITY;
     let textformat = job.sections[0].format.clone();
     job.sections.clear();
-    (job, textformat, data_utf8)
+    (job, textformat, cell_text)
 }
 
-fn byte_range_to_valid_byte_range(data: &[u8], string: &str, start: usize, end: usize) -> Option<std::ops::Range<usize>> {
-    let end = end.min(data.len());
-    let start = start.min(data.len());
+/// Background color applied on top of the regular formatting, e.g. for search matches. Ranges
+/// are cell offsets into the terminal data
+#[derive(Clone, Debug)]
+struct Highlight {
+    range: Range<usize>,
+    color: Color32,
+}
 
-    if start >= end {
-        return None;
+fn split_highlights_for_scrollback(
+    highlights: Vec<Highlight>,
+    scrollback_split: usize,
+) -> TerminalData<Vec<Highlight>> {
+    let scrollback = highlights
+        .iter()
+        .filter(|h| h.range.start < scrollback_split)
+        .cloned()
+        .map(|mut h| {
+            h.range.end = h.range.end.min(scrollback_split);
+            h
+        })
+        .collect();
+
+    let visible = highlights
+        .into_iter()
+        .filter(|h| h.range.end > scrollback_split)
+        .map(|mut h| {
+            h.range.start = h.range.start.saturating_sub(scrollback_split);
+            h.range.end -= scrollback_split;
+            h
+        })
+        .collect();
+
+    TerminalData {
+        scrollback,
+        visible,
     }
+}
 
-    let prefix = &data[..start];
-    let segment = &data[start..end];
+/// Split layout sections at highlight boundaries so that highlighted text gets its background.
+/// Both inputs must be sorted and non-overlapping, with ranges into the same string
+fn apply_highlights(sections: Vec<LayoutSection>, highlights: &[Highlight]) -> Vec<LayoutSection> {
+    split_sections(
+        sections,
+        highlights,
+        |h| &h.range,
+        |format, h| format.background = h.color,
+    )
+}
 
-    let prefix_str = String::from_utf8_lossy(prefix);
-    let segment_str = String::from_utf8_lossy(segment);
+/// Space to put in front of the glyphs at byte offsets into `cell_text`, so that the glyphs
+/// after a wide character start two cells after it, however wide its own glyph is. A wide
+/// character that did not fit on the end of its row is pushed past it, so that the galley wraps
+/// it onto the next row like the grid does
+fn wide_char_padding(
+    data: &[char],
+    cell_text: &CellText,
+    columns: usize,
+    char_width: f32,
+    glyph_width: impl Fn(char) -> f32,
+) -> Vec<(usize, f32)> {
+    let mut padding = Vec::new();
+    for row in row_ranges(data, columns) {
+        let pushed_to_next_row =
+            row.len() + 1 == columns && data.get(row.end + 1) == Some(&WIDE_CHAR_SPACER);
+        if pushed_to_next_row {
+            padding.push((cell_text.offsets[row.end], char_width));
+        }
 
-    let byte_start = prefix_str.len();
-    let byte_end = byte_start + segment_str.len();
+        for i in row {
+            if data[i] != WIDE_CHAR_SPACER || i == 0 || i + 1 >= data.len() {
+                continue;
+            }
+            let space = (2.0 * char_width - glyph_width(data[i - 1])).max(0.0);
+            padding.push((cell_text.offsets[i + 1], space));
+        }
+    }
+    padding
+}
 
-    if byte_end > string.len() {
-        return None;
+/// Split `sections` so that each offset of `padding` starts one, with the space in front of it
+fn add_leading_space(sections: Vec<LayoutSection>, padding: &[(usize, f32)]) -> Vec<LayoutSection> {
+    if padding.is_empty() {
+        return sections;
     }
 
-    Some(byte_start..byte_end)
+    let mut ret = Vec::with_capacity(sections.len() + padding.len());
+    for section in sections {
+        let mut start = section.byte_range.start;
+        let mut leading_space = section.leading_space;
+        let first = padding.partition_point(|(pos, _)| *pos < start);
+        for (pos, space) in &padding[first..] {
+            if *pos >= section.byte_range.end {
+                break;
+            }
+            if *pos > start {
+                ret.push(LayoutSection {
+                    leading_space,
+                    byte_range: start..*pos,
+                    format: section.format.clone(),
+                });
+                start = *pos;
+                leading_space = 0.0;
+            }
+            leading_space += space;
+        }
+        ret.push(LayoutSection {
+            leading_space,
+            byte_range: start..section.byte_range.end,
+            format: section.format,
+        });
+    }
+    ret
+}
+
+/// Split layout sections at the boundaries of the ranges of `items`, and let `apply` adjust the
+/// format of the parts covered by an item. Items must be sorted and non-overlapping
+fn split_sections<T>(
+    sections: Vec<LayoutSection>,
+    items: &[T],
+    item_range: impl Fn(&T) -> &Range<usize>,
+    apply: impl Fn(&mut TextFormat, &T),
+) -> Vec<LayoutSection> {
+    if items.is_empty() {
+        return sections;
+    }
+
+    let mut ret = Vec::with_capacity(sections.len());
+    for section in sections {
+        let mut start = section.byte_range.start;
+        let end = section.byte_range.end;
+
+        while start < end {
+            let idx = items.partition_point(|item| item_range(item).end <= start);
+            let mut format = section.format.clone();
+
+            let segment_end = match items.get(idx) {
+                Some(item) if item_range(item).start <= start => {
+                    apply(&mut format, item);
+                    item_range(item).end.min(end)
+                }
+                Some(item) => item_range(item).start.min(end),
+                None => end,
+            };
+
+            ret.push(LayoutSection {
+                leading_space: 0.0,
+                byte_range: start..segment_end,
+                format,
+            });
+            start = segment_end;
+        }
+    }
+
+    ret
+}
+
+/// Draw `top` over `highlights`, cutting away the parts of existing highlights it covers so that
+/// the result stays sorted and non-overlapping
+fn overlay_highlight(highlights: Vec<Highlight>, top: Highlight) -> Vec<Highlight> {
+    let mut ret = Vec::with_capacity(highlights.len() + 2);
+    for h in highlights {
+        if h.range.start < top.range.start {
+            ret.push(Highlight {
+                range: h.range.start..h.range.end.min(top.range.start),
+                color: h.color,
+            });
+        }
+        if h.range.end > top.range.end {
+            ret.push(Highlight {
+                range: h.range.start.max(top.range.end)..h.range.end,
+                color: h.color,
+            });
+        }
+    }
+    ret.push(top);
+    ret.sort_by_key(|h| h.range.start);
+    ret
+}
+
+/// Draw `bottom` under `highlights`, only where none of them are
+fn underlay_highlight(highlights: Vec<Highlight>, bottom: Highlight) -> Vec<Highlight> {
+    let mut ret = Vec::with_capacity(highlights.len() * 2 + 1);
+    let mut uncovered = bottom.range.start;
+    for h in highlights {
+        if h.range.start > uncovered && uncovered < bottom.range.end {
+            ret.push(Highlight {
+                range: uncovered..h.range.start.min(bottom.range.end),
+                color: bottom.color,
+            });
+        }
+        uncovered = uncovered.max(h.range.end);
+        ret.push(h);
+    }
+    if uncovered < bottom.range.end {
+        ret.push(Highlight {
+            range: uncovered..bottom.range.end,
+            color: bottom.color,
+        });
+    }
+    ret.sort_by_key(|h| h.range.start);
+    ret
+}
+
+#[derive(Clone, Copy)]
+enum StyledCopyFormat {
+    Html,
+    Ansi,
+}
+
+/// Underlines are drawn by hand since egui only knows straight ones. `range` is a byte range
+/// into the rendered text
+struct Underline {
+    range: Range<usize>,
+    style: UnderlineStyle,
+    color: Color32,
+}
+
+/// Horizontal extents of each underline on each row of `galley`, in galley coordinates. Rows
+/// come back as the y position to draw at
+fn underline_segments<'a>(
+    galley: &Galley,
+    underlines: &'a [Underline],
+) -> Vec<(&'a Underline, f32, Range<f32>)> {
+    let mut ret = Vec::new();
+    if underlines.is_empty() {
+        return ret;
+    }
+
+    let mut byte_offset = 0;
+    for row in &galley.rows {
+        let y = row.rect.bottom();
+        let mut current: Option<(usize, Range<f32>)> = None;
+        for glyph in &row.glyphs {
+            let idx = underlines.partition_point(|u| u.range.end <= byte_offset);
+            let idx = underlines
+                .get(idx)
+                .filter(|u| u.range.start <= byte_offset)
+                .map(|_| idx);
+            byte_offset += glyph.chr.len_utf8();
+
+            match (&mut current, idx) {
+                (Some((current_idx, x)), Some(idx)) if *current_idx == idx => {
+                    x.end = glyph.max_x();
+                }
+                (_, idx) => {
+                    if let Some((current_idx, x)) = current.take() {
+                        ret.push((&underlines[current_idx], y, x));
+                    }
+                    current = idx.map(|idx| (idx, glyph.pos.x..glyph.max_x()));
+                }
+            }
+        }
+        if let Some((current_idx, x)) = current {
+            ret.push((&underlines[current_idx], y, x));
+        }
+        if row.ends_with_newline {
+            byte_offset += 1;
+        }
+    }
+
+    ret
+}
+
+fn paint_underlines(ui: &Ui, origin: Pos2, galley: &Galley, underlines: &[Underline]) {
+    let painter = ui.painter();
+    let thickness = 1.0;
+
+    for (underline, y, x) in underline_segments(galley, underlines) {
+        let y = origin.y + y - 2.0 * thickness;
+        let x = origin.x + x.start..origin.x + x.end;
+        let stroke = Stroke::new(thickness, underline.color);
+        let line = [egui::pos2(x.start, y), egui::pos2(x.end, y)];
+
+        match underline.style {
+            UnderlineStyle::None => (),
+            UnderlineStyle::Single => {
+                painter.line_segment(line, stroke);
+            }
+            UnderlineStyle::Double => {
+                let offset = egui::vec2(0.0, 2.0 * thickness);
+                painter.line_segment([line[0] - offset, line[1] - offset], stroke);
+                painter.line_segment(line, stroke);
+            }
+            UnderlineStyle::Curly => {
+                // Phase follows the absolute x position so that neighbouring runs line up
+                const PERIOD: f32 = 6.0;
+                let amplitude = 1.5 * thickness;
+                let points = std::iter::successors(Some(x.start), |px| Some(px + 1.0))
+                    .take_while(|px| *px < x.end)
+                    .chain(std::iter::once(x.end))
+                    .map(|px| {
+                        let phase = px / PERIOD * std::f32::consts::TAU;
+                        egui::pos2(px, y - amplitude * phase.sin())
+                    })
+                    .collect();
+                painter.add(Shape::line(points, stroke));
+            }
+            UnderlineStyle::Dotted => {
+                painter.extend(Shape::dotted_line(
+                    &line,
+                    underline.color,
+                    3.0 * thickness,
+                    thickness / 2.0,
+                ));
+            }
+            UnderlineStyle::Dashed => {
+                painter.extend(Shape::dashed_line(
+                    &line,
+                    stroke,
+                    4.0 * thickness,
+                    2.0 * thickness,
+                ));
+            }
+        }
+    }
+}
+
+/// A character drawn by [`box_drawing`], in the color its text would have had
+struct BuiltinGlyph {
+    range: Range<usize>,
+    color: Color32,
+}
+
+/// Characters in `text` that are drawn with the painter, with colors from `sections`
+fn find_builtin_glyphs(text: &str, sections: &[LayoutSection]) -> Vec<BuiltinGlyph> {
+    text.char_indices()
+        .filter(|(_, c)| box_drawing::is_builtin(*c))
+        .filter_map(|(pos, c)| {
+            let idx = sections.partition_point(|s| s.byte_range.end <= pos);
+            let section = sections.get(idx).filter(|s| s.byte_range.start <= pos)?;
+            Some(BuiltinGlyph {
+                range: pos..pos + c.len_utf8(),
+                color: section.format.color,
+            })
+        })
+        .collect()
+}
+
+fn paint_builtin_glyphs(ui: &Ui, origin: Pos2, galley: &Galley, glyphs: &[BuiltinGlyph]) {
+    if glyphs.is_empty() {
+        return;
+    }
+
+    let painter = ui.painter();
+    let pixels_per_point = ui.ctx().pixels_per_point();
+    let mut byte_offset = 0;
+    for row in &galley.rows {
+        for glyph in &row.glyphs {
+            let idx = glyphs.partition_point(|g| g.range.end <= byte_offset);
+            if let Some(builtin) = glyphs.get(idx).filter(|g| g.range.start == byte_offset) {
+                let cell = Rect::from_x_y_ranges(glyph.pos.x..=glyph.max_x(), row.rect.y_range())
+                    .translate(origin.to_vec2());
+                painter.extend(
+                    box_drawing::glyph_shapes(glyph.chr, cell, builtin.color, pixels_per_point)
+                        .unwrap_or_default(),
+                );
+            }
+            byte_offset += glyph.chr.len_utf8();
+        }
+        if row.ends_with_newline {
+            byte_offset += 1;
+        }
+    }
+}
+
+/// How terminal text is drawn, shared by the scrollback and the canvas
+struct TerminalTextStyle<'a> {
+    font_size: f32,
+    color_scheme: &'a ColorScheme,
+    builtin_box_drawing: bool,
+    /// Width of the grid, rows longer than this wrap onto the next one
+    columns: usize,
+    /// Drawn past the last column of rows that wrap, see [`Config::wrap_indicator`]
+    wrap_indicator: Option<&'a str>,
 }
 
 fn add_terminal_data_to_ui(
     ui: &mut Ui,
-    data: &[u8],
+    data: &[char],
     format_data: &[FormatTag],
-    font_size: f32,
-) -> egui::Response {
-    let (mut job, mut textformat, data_utf8) =
+    line_sizes: &[LineSizeTag],
+    highlights: &[Highlight],
+    scroll_to: Option<Range<usize>>,
+    text_style: &TerminalTextStyle,
+) -> RenderedTerminalData {
+    let font_size = text_style.font_size;
+    let color_scheme = text_style.color_scheme;
+    let (mut job, mut textformat, cell_text) =
         create_terminal_output_layout_job(ui.style(), ui.available_width(), data);
 
-    let default_color = textformat.color;
     let terminal_fonts = TerminalFonts::new();
+    let mut underlines = Vec::new();
 
     for tag in format_data {
         let mut range = tag.start..tag.end;
@@ -383,17 +1115,28 @@ fn add_terminal_data_to_ui(
             range.end = data.len();
         }
 
-        let valid_range = match byte_range_to_valid_byte_range(data, &data_utf8, range.start, range.end) {
-            Some(r) => r,
-            None => {
-                debug!("Skipping invalid format range");
-                continue;
-            }
+        let Some(valid_range) = cell_text.byte_range(range) else {
+            debug!("Skipping invalid format range");
+            continue;
         };
 
         textformat.font_id.family = terminal_fonts.get_family(tag.bold);
         textformat.font_id.size = font_size;
-        textformat.color = terminal_color_to_egui(&default_color, &color);
+        textformat.color = rgb_to_egui(color_scheme.resolve_foreground(color));
+        // The default background is left to whatever is behind the terminal
+        textformat.background = color_scheme
+            .resolve(tag.background)
+            .map_or(Color32::TRANSPARENT, rgb_to_egui);
+
+        if tag.underline != UnderlineStyle::None {
+            underlines.push(Underline {
+                range: valid_range.clone(),
+                style: tag.underline,
+                color: color_scheme
+                    .resolve(tag.underline_color)
+                    .map_or(textformat.color, rgb_to_egui),
+            });
+        }
 
         job.sections.push(egui::text::LayoutSection {
             leading_space: 0.0f32,
@@ -402,60 +1145,525 @@ fn add_terminal_data_to_ui(
         });
     }
 
-    ui.label(job)
+    let highlights: Vec<Highlight> = highlights
+        .iter()
+        .filter_map(|h| {
+            Some(Highlight {
+                range: cell_text.byte_range(h.range.clone())?,
+                color: h.color,
+            })
+        })
+        .collect();
+    job.sections = apply_highlights(std::mem::take(&mut job.sections), &highlights);
+
+    // Double width lines are drawn with regular glyphs spaced out to two cells each
+    let double_width_lines: Vec<Range<usize>> = line_sizes
+        .iter()
+        .filter(|tag| tag.size.is_double_width())
+        .filter_map(|tag| {
+            let line_end = data
+                .iter()
+                .skip(tag.line_start)
+                .position(|c| *c == '\n')
+                .map_or(data.len(), |len| tag.line_start + len);
+            cell_text.byte_range(tag.line_start..line_end)
+        })
+        .collect();
+    let char_width = get_char_size(ui.ctx(), font_size).0;
+    // Soft wrapped lines have no newline to break them up, so their rows break where the grid
+    // does. Half a cell of slack keeps rounding from breaking a full row early
+    job.wrap.max_width = text_style.columns as f32 * char_width + char_width / 2.0;
+    job.wrap.break_anywhere = true;
+    job.sections = split_sections(
+        std::mem::take(&mut job.sections),
+        &double_width_lines,
+        |range| range,
+        |format, _| format.extra_letter_spacing = char_width,
+    );
+
+    // The font still lays these out so that they take up their cell, but they are not visible
+    let builtin_glyphs = if text_style.builtin_box_drawing {
+        find_builtin_glyphs(&cell_text.text, &job.sections)
+    } else {
+        Vec::new()
+    };
+    job.sections = split_sections(
+        std::mem::take(&mut job.sections),
+        &builtin_glyphs,
+        |glyph| &glyph.range,
+        |format, _| format.color = Color32::TRANSPARENT,
+    );
+
+    let font_id = FontId::new(font_size, terminal_fonts.get_family(false));
+    let padding = ui.fonts(|fonts| {
+        wide_char_padding(data, &cell_text, text_style.columns, char_width, |c| {
+            fonts.glyph_width(&font_id, c)
+        })
+    });
+    job.sections = add_leading_space(std::mem::take(&mut job.sections), &padding);
+
+    let galley = ui.fonts(|fonts| fonts.layout_job(job));
+    // Selection is handled by the terminal widget so that it works in buffer positions, not
+    // with egui's label selection
+    let response = ui.add(
+        egui::Label::new(galley.clone())
+            .selectable(false)
+            .sense(Sense::click_and_drag()),
+    );
+    paint_builtin_glyphs(ui, response.rect.min, &galley, &builtin_glyphs);
+    paint_underlines(ui, response.rect.min, &galley, &underlines);
+
+    let scroll_to = scroll_to.and_then(|range| cell_text.byte_range(range));
+    if let Some(range) = scroll_to {
+        let start = CCursor::new(cell_text.text[..range.start].chars().count());
+        let end = CCursor::new(cell_text.text[..range.end].chars().count());
+        let rect = galley
+            .pos_from_ccursor(start)
+            .union(galley.pos_from_ccursor(end))
+            .translate(response.rect.min.to_vec2());
+        ui.scroll_to_rect(rect, Some(egui::Align::Center));
+    }
+
+    RenderedTerminalData {
+        response,
+        galley,
+        offset: 0,
+    }
+}
+
+struct RenderedTerminalData {
+    response: egui::Response,
+    galley: Arc<Galley>,
+    // Where the rendered part starts in the data, only part of the scrollback is rendered
+    offset: usize,
+}
+
+impl RenderedTerminalData {
+    /// Offset into `data` (the data this was rendered from) closest to `pos`
+    fn buf_offset_at(&self, data: &[char], pos: Pos2) -> usize {
+        let cursor = self.galley.cursor_from_pos(pos - self.response.rect.min);
+        self.offset + selection::char_idx_to_cell(&data[self.offset..], cursor.ccursor.index)
+    }
 }
 
 struct TerminalOutputRenderResponse {
+    scrollback: RenderedTerminalData,
+    canvas: RenderedTerminalData,
+    // Space taken up by the whole scrollback, including the rows that were not rendered
     scrollback_area: Rect,
-    canvas_area: Rect,
+    // Set while scrolled away from the bottom, see [`ScrollbackViewport::update_new_rows`]
+    new_rows: Option<usize>,
+}
+
+impl TerminalOutputRenderResponse {
+    /// Offset into the full terminal buffer under `pos`. Positions outside of the output snap to
+    /// the nearest character
+    fn buf_offset_at(&self, data: &TerminalData<&[char]>, pos: Pos2) -> usize {
+        if data.scrollback.is_empty() || pos.y >= self.canvas.response.rect.top() {
+            data.scrollback.len() + self.canvas.buf_offset_at(data.visible, pos)
+        } else {
+            self.scrollback.buf_offset_at(data.scrollback, pos)
+        }
+    }
+}
+
+/// Where the rows of `data` are, without their newlines. Lines longer than `columns`
+/// cells wrap onto as many rows as they need, the way the galley lays them out
+fn row_ranges(data: &[char], columns: usize) -> impl Iterator<Item = Range<usize>> + '_ {
+    let columns = columns.max(1);
+    let mut line_start = 0;
+    data.split(|c| *c == '\n').flat_map(move |line| {
+        let start = line_start;
+        line_start += line.len() + 1;
+        let mut row_start = Some(0);
+        std::iter::from_fn(move || {
+            let this_row = row_start?;
+            row_start = (this_row + columns < line.len()).then(|| {
+                // A wide character that does not fit on the end of a row starts the next one
+                this_row
+                    + match line[this_row + columns] == WIDE_CHAR_SPACER && columns > 1 {
+                        true => columns - 1,
+                        false => columns,
+                    }
+            });
+            Some(start + this_row..start + row_start.unwrap_or(line.len()))
+        })
+    })
+}
+
+/// Where the rows of the scrollback start, see [`row_ranges`]. Kept from frame to frame, so
+/// that only the lines added since the last one have to be looked at. The last line can still
+/// grow, its rows are found again on every frame
+#[derive(Default)]
+struct ScrollbackRows {
+    columns: usize,
+    // Start of every row up to `scanned`, which is just past a newline
+    starts: Vec<usize>,
+    scanned: usize,
+}
+
+impl ScrollbackRows {
+    /// Catch up with `data` at `columns`. Anything but lines being appended since the last call,
+    /// like clearing the scrollback or a reflow, starts over
+    fn update(&mut self, data: &[char], columns: usize) {
+        let appended = self.columns == columns
+            && self.scanned <= data.len()
+            && (self.scanned == 0 || data[self.scanned - 1] == '\n');
+        if !appended {
+            self.columns = columns;
+            self.starts.clear();
+            self.scanned = 0;
+        }
+
+        let Some(end) = data.iter().rposition(|c| *c == '\n') else {
+            return;
+        };
+        if end < self.scanned {
+            return;
+        }
+        let scanned = self.scanned;
+        self.starts
+            .extend(row_ranges(&data[scanned..end], columns).map(|row| scanned + row.start));
+        self.scanned = end + 1;
+    }
+
+    /// Rows of the last line, which has not been scanned yet
+    fn last_line_rows<'a>(&self, data: &'a [char]) -> impl Iterator<Item = Range<usize>> + 'a {
+        let scanned = self.scanned;
+        row_ranges(&data[scanned..], self.columns)
+            .map(move |row| scanned + row.start..scanned + row.end)
+    }
+
+    /// Number of rows in `data`, the data of the last [`Self::update`]
+    fn count(&self, data: &[char]) -> usize {
+        if data.is_empty() {
+            return 0;
+        }
+        self.starts.len() + self.last_line_rows(data).count()
+    }
+
+    /// Where `row` starts, if `data` has that many rows
+    fn start(&self, data: &[char], row: usize) -> Option<usize> {
+        match self.starts.get(row) {
+            Some(start) => Some(*start),
+            None => self
+                .last_line_rows(data)
+                .nth(row - self.starts.len())
+                .map(|row| row.start),
+        }
+    }
+
+    /// Where `rows` are in `data`, without the newline after the last one
+    fn cell_range(&self, data: &[char], rows: Range<usize>) -> Range<usize> {
+        let start = self.start(data, rows.start).unwrap_or(data.len());
+        if rows.end <= rows.start {
+            return start..start;
+        }
+        let end = match self.start(data, rows.end) {
+            Some(next) if data[next - 1] == '\n' => next - 1,
+            Some(next) => next,
+            None => data.len(),
+        };
+        start..end
+    }
+
+    /// Row that `pos` in `data` is on
+    fn row_at(&self, data: &[char], pos: usize) -> usize {
+        let scanned_rows = self.starts.partition_point(|start| *start <= pos);
+        let last_line_rows = match pos < self.scanned {
+            true => 0,
+            false => self
+                .last_line_rows(data)
+                .take_while(|row| row.start <= pos)
+                .count(),
+        };
+        (scanned_rows + last_line_rows).saturating_sub(1)
+    }
+}
+
+/// Rows of `num_rows` rows of `row_height` that have to be laid out for `viewport` to be covered
+fn visible_rows(viewport: Rangef, row_height: f32, num_rows: usize) -> Range<usize> {
+    let first = (viewport.min / row_height).floor().max(0.0) as usize;
+    let last = (viewport.max / row_height).ceil().max(0.0) as usize;
+    let first = first.saturating_sub(SCROLLBACK_OVERSCAN_ROWS).min(num_rows);
+    let last = last.saturating_add(SCROLLBACK_OVERSCAN_ROWS).min(num_rows);
+    first..last.max(first)
+}
+
+/// Draw the wrap indicator past the last column of the rows of `rendered` that wrap onto the
+/// next row. Whether the last one does depends on what comes after the rendered data
+fn paint_wrap_indicators(
+    ui: &Ui,
+    rendered: &RenderedTerminalData,
+    last_row_wraps: bool,
+    text_style: &TerminalTextStyle,
+) {
+    let Some(glyph) = text_style.wrap_indicator else {
+        return;
+    };
+    let char_width = get_char_size(ui.ctx(), text_style.font_size).0;
+    let x = rendered.response.rect.left() + text_style.columns as f32 * char_width;
+    let font = FontId::monospace(text_style.font_size);
+    let color = rgba_to_egui(text_style.color_scheme.wrap_indicator);
+    let rows = &rendered.galley.rows;
+    for (i, row) in rows.iter().enumerate() {
+        let wraps = match rows.get(i + 1) {
+            Some(_) => !row.ends_with_newline,
+            None => last_row_wraps,
+        };
+        if wraps {
+            let y = rendered.response.rect.top() + row.rect.top();
+            ui.painter().text(
+                egui::pos2(x, y),
+                egui::Align2::LEFT_TOP,
+                glyph,
+                font.clone(),
+                color,
+            );
+        }
+    }
+}
+
+/// `range` of the scrollback cut out of `data`, along with its format, line sizes and highlights
+/// moved to positions in the cut out part
+fn slice_terminal_data<'a>(
+    data: &'a [char],
+    format_data: &[FormatTag],
+    line_sizes: &[LineSizeTag],
+    highlights: &[Highlight],
+    range: Range<usize>,
+) -> (&'a [char], Vec<FormatTag>, Vec<LineSizeTag>, Vec<Highlight>) {
+    let clip = |r: Range<usize>| {
+        let start = r.start.max(range.start);
+        let end = r.end.min(range.end);
+        (start < end).then(|| start - range.start..end - range.start)
+    };
+
+    let format_data = format_data
+        .iter()
+        .filter_map(|tag| {
+            let clipped = clip(tag.start..tag.end)?;
+            Some(FormatTag {
+                start: clipped.start,
+                end: clipped.end,
+                ..tag.clone()
+            })
+        })
+        .collect();
+    let line_sizes = line_sizes
+        .iter()
+        .filter(|tag| range.contains(&tag.line_start))
+        .map(|tag| LineSizeTag {
+            line_start: tag.line_start - range.start,
+            ..tag.clone()
+        })
+        .collect();
+    let highlights = highlights
+        .iter()
+        .filter_map(|h| {
+            Some(Highlight {
+                range: clip(h.range.clone())?,
+                color: h.color,
+            })
+        })
+        .collect();
+
+    (&data[range], format_data, line_sizes, highlights)
+}
+
+/// State of the scroll area the output is shown in
+struct ScrollbackViewport {
+    // Keys egui's own scroll state, so that each widget keeps its position while another one is
+    // shown in its place
+    id: egui::Id,
+    // Where the scrollback was scrolled to before a program switched to the alternate screen,
+    // so that leaving it puts the view back
+    offset: f32,
+    alternate_screen: bool,
+    // Area the output was last shown in
+    rect: Rect,
+    // Requested by dragging a selection past the edge, applied on the next render
+    scroll_by: f32,
+    // Requested from the new output indicator or by input and output, see
+    // [`Config::scroll_on_input`], applied on the next render
+    scroll_to_bottom: bool,
+    // Scrollback rows there were when the view left the bottom, `None` while it is there
+    rows_when_scrolled_away: Option<usize>,
+    rows: ScrollbackRows,
+}
+
+impl ScrollbackViewport {
+    /// Rows that scrolled into the scrollback since the view left the bottom, `None` while it
+    /// is at the bottom
+    fn update_new_rows(&mut self, at_bottom: bool, num_rows: usize) -> Option<usize> {
+        if at_bottom {
+            self.rows_when_scrolled_away = None;
+            return None;
+        }
+        let start = *self.rows_when_scrolled_away.get_or_insert(num_rows);
+        Some(num_rows.saturating_sub(start))
+    }
+}
+
+fn new_output_label(new_rows: usize) -> String {
+    match new_rows {
+        0 => "\u{2193} Back to bottom".to_string(),
+        1 => "\u{2193} 1 new line".to_string(),
+        n => format!("\u{2193} {n} new lines"),
+    }
 }
 
 fn render_terminal_output<Io: TermIo>(
     ui: &mut egui::Ui,
     terminal_emulator: &TerminalEmulator<Io>,
-    font_size: f32,
+    highlights: Vec<Highlight>,
+    scroll_to: Option<Range<usize>>,
+    text_style: &TerminalTextStyle,
+    viewport: &mut ScrollbackViewport,
 ) -> TerminalOutputRenderResponse {
     let terminal_data = terminal_emulator.data();
+    let scrollback_len = terminal_data.scrollback.len();
+    let highlights = split_highlights_for_scrollback(highlights, scrollback_len);
+    let scroll_to = scroll_to.map(|range| {
+        if range.start < scrollback_len {
+            TerminalData {
+                scrollback: Some(range),
+                visible: None,
+            }
+        } else {
+            TerminalData {
+                scrollback: None,
+                visible: Some(range.start - scrollback_len..range.end - scrollback_len),
+            }
+        }
+    });
     let mut scrollback_data = terminal_data.scrollback;
     let mut canvas_data = terminal_data.visible;
     let mut format_data = terminal_emulator.format_data();
+    let line_sizes = terminal_emulator.line_sizes();
 
     // Arguably incorrect. Scrollback does end with a newline, and that newline causes a blank
     // space between widgets. Should we strip it here, or in the terminal emulator output?
-    if scrollback_data.ends_with(b"\n") {
+    // Without the newline the last scrollback row continues on the screen
+    let scrollback_wraps = !scrollback_data.ends_with(&['\n']);
+    if scrollback_data.ends_with(&['\n']) {
         scrollback_data = &scrollback_data[0..scrollback_data.len() - 1];
         if let Some(last_tag) = format_data.scrollback.last_mut() {
             last_tag.end = last_tag.end.min(scrollback_data.len());
         }
     }
 
-    if canvas_data.ends_with(b"\n") {
+    if canvas_data.ends_with(&['\n']) {
         canvas_data = &canvas_data[0..canvas_data.len() - 1];
     }
 
-    let response = egui::ScrollArea::new([false, true])
+    // The alternate screen has no scrollback, the wheel must not scroll the main screen's
+    // scrollback from under it
+    let alternate_screen = terminal_emulator.is_alternate_screen();
+    let mut scroll_area = egui::ScrollArea::new([false, true])
+        .id_salt(viewport.id)
         .auto_shrink([false, false])
         .stick_to_bottom(true)
-        .show(ui, |ui| {
-            let scrollback_area = add_terminal_data_to_ui(
-                ui,
-                scrollback_data,
-                &format_data.scrollback,
-                font_size,
-            ).rect;
-            let canvas_area = add_terminal_data_to_ui(
-                ui,
-                canvas_data,
-                &format_data.visible,
-                font_size,
-            ).rect;
-            TerminalOutputRenderResponse {
-                scrollback_area,
-                canvas_area,
-            }
-        });
+        .enable_scrolling(!alternate_screen);
+    if std::mem::take(&mut viewport.scroll_to_bottom) {
+        // Clamped to the end, where the area sticks again
+        scroll_area = scroll_area.vertical_scroll_offset(f32::INFINITY);
+    } else if viewport.alternate_screen && !alternate_screen {
+        scroll_area = scroll_area.vertical_scroll_offset(viewport.offset);
+    }
+
+    let scroll_by = std::mem::take(&mut viewport.scroll_by);
+    let columns = text_style.columns;
+    viewport.rows.update(scrollback_data, columns);
+    let scrollback_rows = &viewport.rows;
+    let num_rows = scrollback_rows.count(scrollback_data);
+    let response = scroll_area.show_viewport(ui, |ui, visible| {
+        if scroll_by != 0.0 {
+            ui.scroll_with_delta_animation(
+                egui::vec2(0.0, -scroll_by),
+                egui::style::ScrollAnimation::none(),
+            );
+        }
+
+        // Only the scrollback rows around the viewport are laid out, the rest is empty space of
+        // the same height. Where the rows are is kept in `scrollback_rows`, so the scrollback is
+        // only looked through again for the lines added since the last frame
+        let row_height = get_char_size(ui.ctx(), text_style.font_size).1;
+        let (scrollback_area, _) = ui.allocate_exact_size(
+            egui::vec2(ui.available_width(), num_rows as f32 * row_height),
+            Sense::hover(),
+        );
+
+        let rows = visible_rows(visible.y_range(), row_height, num_rows);
+        let cell_range = scrollback_rows.cell_range(scrollback_data, rows.clone());
+        let (rows_data, rows_format_data, rows_line_sizes, rows_highlights) = slice_terminal_data(
+            scrollback_data,
+            &format_data.scrollback,
+            &line_sizes.scrollback,
+            &highlights.scrollback,
+            cell_range.clone(),
+        );
+        let mut rows_ui = ui.new_child(UiBuilder::new().max_rect(
+            scrollback_area.with_min_y(scrollback_area.top() + rows.start as f32 * row_height),
+        ));
+        let scrollback = RenderedTerminalData {
+            offset: cell_range.start,
+            ..add_terminal_data_to_ui(
+                &mut rows_ui,
+                rows_data,
+                &rows_format_data,
+                &rows_line_sizes,
+                &rows_highlights,
+                None,
+                text_style,
+            )
+        };
+        let last_row_wraps = match scrollback_data.get(cell_range.end) {
+            Some(c) => *c != '\n',
+            None => scrollback_wraps && !scrollback_data.is_empty(),
+        };
+        paint_wrap_indicators(ui, &scrollback, last_row_wraps, text_style);
+
+        // The row might not be laid out, so scroll to where it will be
+        if let Some(range) = scroll_to.as_ref().and_then(|v| v.scrollback.clone()) {
+            let row = scrollback_rows.row_at(scrollback_data, range.start);
+            let top = scrollback_area.top() + row as f32 * row_height;
+            ui.scroll_to_rect(
+                Rect::from_x_y_ranges(scrollback_area.x_range(), top..=top + row_height),
+                Some(egui::Align::Center),
+            );
+        }
 
-    response.inner
+        let canvas = add_terminal_data_to_ui(
+            ui,
+            canvas_data,
+            &format_data.visible,
+            &line_sizes.visible,
+            &highlights.visible,
+            scroll_to.as_ref().and_then(|v| v.visible.clone()),
+            text_style,
+        );
+        paint_wrap_indicators(ui, &canvas, false, text_style);
+        TerminalOutputRenderResponse {
+            scrollback,
+            canvas,
+            scrollback_area,
+            new_rows: None,
+        }
+    });
+
+    if !alternate_screen {
+        viewport.offset = response.state.offset.y;
+    }
+    viewport.alternate_screen = alternate_screen;
+    viewport.rect = response.inner_rect;
+
+    let max_offset = response.content_size.y - response.inner_rect.height();
+    let at_bottom = alternate_screen || response.state.offset.y >= max_offset - 0.5;
+    TerminalOutputRenderResponse {
+        new_rows: viewport.update_new_rows(at_bottom, num_rows),
+        ..response.inner
+    }
 }
 
 struct DebugRenderer {
@@ -477,34 +1685,612 @@ impl DebugRenderer {
     }
 }
 
+/// Where the ruler lines of the grid overlay go, as cell offsets from the top left. The first
+/// column and row are the edge of the grid and get no line
+fn grid_ruler_offsets(cells: usize, step: usize) -> impl Iterator<Item = usize> {
+    (step..cells).step_by(step)
+}
+
+/// Ruler lines with their cell numbers every few columns and rows, to see where cells land
+struct GridOverlay {
+    enable: bool,
+}
+
+impl GridOverlay {
+    fn new() -> GridOverlay {
+        GridOverlay { enable: false }
+    }
+
+    fn render(&self, ui: &mut Ui, rect: Rect, character_size: &(f32, f32), grid: (usize, usize)) {
+        if !self.enable {
+            return;
+        }
+
+        let painter = ui.painter();
+        let stroke = Stroke::new(1.0, GRID_OVERLAY_COLOR);
+        let font = FontId::monospace(character_size.1 * 0.5);
+        let (width, height) = grid;
+
+        for x in grid_ruler_offsets(width, GRID_OVERLAY_COLUMNS) {
+            let pos_x = rect.left() + x as f32 * character_size.0;
+            painter.vline(pos_x, rect.y_range(), stroke);
+            painter.text(
+                egui::pos2(pos_x + 1.0, rect.top()),
+                egui::Align2::LEFT_TOP,
+                x,
+                font.clone(),
+                GRID_OVERLAY_COLOR,
+            );
+        }
+
+        for y in grid_ruler_offsets(height, GRID_OVERLAY_ROWS) {
+            let pos_y = rect.top() + y as f32 * character_size.1;
+            painter.hline(rect.x_range(), pos_y, stroke);
+            painter.text(
+                egui::pos2(rect.left() + 1.0, pos_y),
+                egui::Align2::LEFT_TOP,
+                y,
+                font.clone(),
+                GRID_OVERLAY_COLOR,
+            );
+        }
+    }
+}
+
 pub struct TerminalWidget {
+    // Distinguishes the widget's egui state from that of other widgets
+    id: egui::Id,
     font_size: f32,
+    // From the active profile, used instead of `font_size`
+    profile_font_size: Option<f32>,
     debug_renderer: DebugRenderer,
-    last_keystroke: Option<String>,
+    grid_overlay: GridOverlay,
+    keystrokes: KeystrokeHistory,
+    // Keys are only encoded for the keystroke history, the child does not get them
+    inspect_keys: bool,
+    search: Option<TerminalSearch>,
+    search_wrap_around: bool,
+    search_scrollback: bool,
+    search_has_focus: bool,
+    search_focus_requested: bool,
+    macros: Vec<KeyMacro>,
+    enter_sends: EnterSequence,
+    selection: Option<TerminalSelection>,
+    copy_trim_trailing_whitespace: bool,
+    // Requested from the options menu, handled on the next frame
+    styled_copy_requested: Option<StyledCopyFormat>,
+    command_output_copy_requested: bool,
+    copy_all_requested: bool,
+    // Open while a path to save the scrollback to is asked for
+    save_text_dialog: Option<SaveTextDialog>,
+    clear_buffer_shortcut: KeyCombo,
+    clear_buffer_requested: bool,
+    restart_shell_shortcut: KeyCombo,
+    // Only the owner of the pty can act on this, see `take_restart_shell_request`
+    restart_shell_requested: bool,
+    cursor_trail: CursorTrail,
+    cursor_blink: CursorBlink,
+    // Set for a moment after search or copying the last output moved the view
+    jump_flash: Option<JumpFlash>,
+    reduce_motion: bool,
+    hide_pointer_while_typing: bool,
+    link_modifier: LinkModifier,
+    clipboard: Box<dyn Clipboard>,
+    scroll_on_output: bool,
+    scroll_on_input: bool,
+    // Set by typing, until the mouse moves again
+    pointer_hidden: bool,
+    builtin_box_drawing: bool,
+    wrap_indicator: bool,
+    wrap_indicator_glyph: String,
+    cursor_text_color: CursorTextColor,
+    background_opacity: f32,
+    background_image: BackgroundImageRenderer,
+    viewport: ScrollbackViewport,
+    auto_scroll: AutoScroll,
 }
 
 impl TerminalWidget {
-    pub fn new(ctx: &Context) -> TerminalWidget {
+    /// Nothing blinks with `reduce_motion`
+    pub fn new(ctx: &Context, config: &Config, reduce_motion: bool) -> TerminalWidget {
         setup_fonts(ctx);
 
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let id = egui::Id::new(("terminal_widget", NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+
         TerminalWidget {
+            id,
             font_size: 14.0,
+            profile_font_size: None,
             debug_renderer: DebugRenderer::new(),
-            last_keystroke: None,
+            grid_overlay: GridOverlay::new(),
+            keystrokes: KeystrokeHistory::new(),
+            inspect_keys: false,
+            search: None,
+            search_wrap_around: config.search_wrap_around,
+            search_scrollback: config.search_scrollback,
+            search_has_focus: false,
+            search_focus_requested: false,
+            macros: config.macros.clone(),
+            enter_sends: config.enter_sends,
+            selection: None,
+            copy_trim_trailing_whitespace: config.copy_trim_trailing_whitespace,
+            styled_copy_requested: None,
+            command_output_copy_requested: false,
+            copy_all_requested: false,
+            save_text_dialog: None,
+            clear_buffer_shortcut: config.clear_buffer_shortcut.clone(),
+            clear_buffer_requested: false,
+            restart_shell_shortcut: config.restart_shell_shortcut.clone(),
+            restart_shell_requested: false,
+            cursor_trail: CursorTrail::new(config.cursor_trail),
+            cursor_blink: CursorBlink::new(
+                config.cursor_blink && !reduce_motion,
+                config.cursor_blink_pause,
+                Instant::now(),
+            ),
+            jump_flash: None,
+            reduce_motion,
+            hide_pointer_while_typing: config.hide_pointer_while_typing,
+            link_modifier: config.link_modifier,
+            clipboard: clipboard::from_config(config),
+            scroll_on_output: config.scroll_on_output,
+            scroll_on_input: config.scroll_on_input,
+            pointer_hidden: false,
+            builtin_box_drawing: config.builtin_box_drawing,
+            wrap_indicator: config.wrap_indicator,
+            wrap_indicator_glyph: config.wrap_indicator_glyph.clone(),
+            cursor_text_color: config.cursor_text_color,
+            background_opacity: config.background_opacity,
+            background_image: BackgroundImageRenderer::new(config.background_image.clone()),
+            viewport: ScrollbackViewport {
+                id: id.with("scrollback"),
+                offset: 0.0,
+                alternate_screen: false,
+                rect: Rect::NOTHING,
+                scroll_by: 0.0,
+                scroll_to_bottom: false,
+                rows_when_scrolled_away: None,
+                rows: ScrollbackRows::default(),
+            },
+            auto_scroll: AutoScroll::new(),
+        }
+    }
+
+    fn update_selection<Io: TermIo>(
+        &mut self,
+        ui: &Ui,
+        output: &TerminalOutputRenderResponse,
+        terminal_emulator: &TerminalEmulator<Io>,
+    ) {
+        let responses = [&output.scrollback.response, &output.canvas.response];
+        let data = terminal_emulator.data();
+
+        if responses.iter().any(|r| r.drag_started()) {
+            if let Some(pos) = ui.input(|i| i.pointer.press_origin()) {
+                let offset = output.buf_offset_at(&data, pos);
+                self.selection = Some(TerminalSelection::new(offset));
+            }
+        } else if responses.iter().any(|r| r.dragged()) {
+            let pos = ui.input(|i| i.pointer.interact_pos());
+            if let (Some(selection), Some(pos)) = (&mut self.selection, pos) {
+                // Past the edge the view scrolls, and the selection follows whatever row is at
+                // the edge
+                let view = self.viewport.rect.y_range();
+                self.viewport.scroll_by = self.auto_scroll.step(view, pos.y, Instant::now());
+                if self.auto_scroll.is_scrolling() {
+                    ui.ctx().request_repaint();
+                }
+                let pos = egui::pos2(pos.x, pos.y.clamp(view.min, (view.max - 1.0).max(view.min)));
+                selection.extend_to(output.buf_offset_at(&data, pos));
+            }
+        } else if responses.iter().any(|r| r.clicked()) {
+            self.selection = None;
+            let (pos, modifiers) = ui.input(|i| (i.pointer.interact_pos(), i.modifiers));
+            if let Some(pos) = pos.filter(|_| self.link_modifier.is_held(&modifiers)) {
+                let offset = output.buf_offset_at(&data, pos);
+                let data = [data.scrollback, data.visible].concat();
+                if let Some(range) = links::url_at(&data, offset) {
+                    let url = cells_to_string(&data[range]);
+                    info!("Opening {url}");
+                    ui.ctx().open_url(egui::OpenUrl::new_tab(url));
+                }
+            }
+        }
+
+        if !responses.iter().any(|r| r.dragged()) {
+            self.auto_scroll.stop();
+        }
+    }
+
+    fn copy_selection<Io: TermIo>(
+        &mut self,
+        ctx: &Context,
+        terminal_emulator: &TerminalEmulator<Io>,
+    ) {
+        let Some(selection) = &self.selection else {
+            return;
+        };
+
+        let data = terminal_emulator.data();
+        let data = [data.scrollback, data.visible].concat();
+        let range = selection.range();
+        let Some(selected) = data.get(range.start..range.end.min(data.len())) else {
+            return;
+        };
+
+        let text = selection::selected_text(selected, self.copy_trim_trailing_whitespace);
+        self.clipboard.copy(ctx, text);
+    }
+
+    /// Copies the whole scrollback and the screen as plain text
+    fn copy_all<Io: TermIo>(&mut self, ctx: &Context, terminal_emulator: &TerminalEmulator<Io>) {
+        let mut text = Vec::new();
+        if let Err(e) = terminal_emulator.write_text(&mut text, self.copy_trim_trailing_whitespace)
+        {
+            error!("failed to copy scrollback: {}", backtraced_err(&e));
+            return;
+        }
+        self.clipboard
+            .copy(ctx, String::from_utf8_lossy(&text).into_owned());
+    }
+
+    /// Selects and copies the output of the last command, so that it is visible what was copied
+    fn copy_last_command_output<Io: TermIo>(
+        &mut self,
+        ctx: &Context,
+        terminal_emulator: &TerminalEmulator<Io>,
+    ) {
+        let Some(range) = terminal_emulator.last_command_output() else {
+            info!("No command output found to copy");
+            return;
+        };
+
+        let mut selection = TerminalSelection::new(range.start);
+        selection.extend_to(range.end);
+        self.selection = Some(selection);
+        self.flash_line(terminal_emulator, range.start, Instant::now());
+        self.copy_selection(ctx, terminal_emulator);
+    }
+
+    fn flash_line<Io: TermIo>(
+        &mut self,
+        terminal_emulator: &TerminalEmulator<Io>,
+        pos: usize,
+        now: Instant,
+    ) {
+        let data = terminal_emulator.data();
+        let data = [data.scrollback, data.visible].concat();
+        self.jump_flash = Some(JumpFlash::new(&data, pos, now));
+    }
+
+    /// Copies the selection if there is one, otherwise the visible screen
+    fn copy_styled<Io: TermIo>(
+        &mut self,
+        ctx: &Context,
+        terminal_emulator: &TerminalEmulator<Io>,
+        format: StyledCopyFormat,
+    ) {
+        let selection = self
+            .selection
+            .as_ref()
+            .filter(|s| !s.is_empty())
+            .map(TerminalSelection::range);
+        let text = match (format, selection) {
+            (StyledCopyFormat::Html, Some(range)) => terminal_emulator.range_to_html(range),
+            (StyledCopyFormat::Html, None) => terminal_emulator.to_html(),
+            (StyledCopyFormat::Ansi, Some(range)) => {
+                String::from_utf8_lossy(&terminal_emulator.range_to_ansi(range)).into_owned()
+            }
+            (StyledCopyFormat::Ansi, None) => {
+                String::from_utf8_lossy(&terminal_emulator.to_ansi()).into_owned()
+            }
+        };
+        self.clipboard.copy(ctx, text);
+    }
+
+    fn open_search(&mut self) {
+        if self.search.is_none() {
+            self.search = Some(TerminalSearch::new(
+                self.search_wrap_around,
+                self.search_scrollback,
+            ));
         }
+        self.search_focus_requested = true;
+    }
+
+    fn show_search_bar(&mut self, ctx: &Context, top_right: Pos2) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+
+        let mut close = false;
+        egui::Area::new(self.id.with("search_bar"))
+            .fixed_pos(top_right)
+            .pivot(egui::Align2::RIGHT_TOP)
+            .order(egui::Order::Foreground)
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        let response = ui.add(
+                            egui::TextEdit::singleline(&mut search.query)
+                                .desired_width(160.0)
+                                .hint_text("Search"),
+                        );
+
+                        if std::mem::take(&mut self.search_focus_requested) {
+                            response.request_focus();
+                        }
+
+                        if response.lost_focus() {
+                            let (enter, shift, escape) = ui.input(|i| {
+                                (
+                                    i.key_pressed(Key::Enter),
+                                    i.modifiers.shift,
+                                    i.key_pressed(Key::Escape),
+                                )
+                            });
+
+                            if enter {
+                                if shift {
+                                    search.prev();
+                                } else {
+                                    search.next();
+                                }
+                                response.request_focus();
+                            } else if escape {
+                                close = true;
+                            }
+                        }
+
+                        ui.label(search.counter_text());
+                        if ui.button("⏮").on_hover_text("First match").clicked() {
+                            search.first();
+                        }
+                        if ui
+                            .button("⏶")
+                            .on_hover_text("Previous match (Shift+Enter)")
+                            .clicked()
+                        {
+                            search.prev();
+                        }
+                        if ui.button("⏷").on_hover_text("Next match (Enter)").clicked() {
+                            search.next();
+                        }
+                        if ui.button("⏭").on_hover_text("Last match").clicked() {
+                            search.last();
+                        }
+                        if ui.button("✕").on_hover_text("Close (Escape)").clicked() {
+                            close = true;
+                        }
+
+                        self.search_has_focus = ui.memory(|m| m.has_focus(response.id));
+                    });
+                });
+            });
+
+        if close {
+            self.search = None;
+            self.search_has_focus = false;
+        }
+    }
+
+    /// Update search results against the latest output. Returns the highlights to draw, and the
+    /// match to scroll to if navigation happened
+    fn update_search<Io: TermIo>(
+        &mut self,
+        terminal_emulator: &TerminalEmulator<Io>,
+    ) -> (Vec<Highlight>, Option<Range<usize>>) {
+        let Some(search) = &mut self.search else {
+            return (Vec::new(), None);
+        };
+
+        let data = terminal_emulator.data();
+        search.update(
+            data.scrollback,
+            data.visible,
+            terminal_emulator.is_alternate_screen(),
+        );
+
+        let current = search.current_match().cloned();
+        let color_scheme = terminal_emulator.color_scheme();
+        let highlights = search
+            .matches()
+            .iter()
+            .map(|range| Highlight {
+                range: range.clone(),
+                color: if Some(range) == current.as_ref() {
+                    rgba_to_egui(color_scheme.search_current_match)
+                } else {
+                    rgba_to_egui(color_scheme.search_match)
+                },
+            })
+            .collect();
+
+        let scroll_to = search.take_scroll_request().then_some(current).flatten();
+        (highlights, scroll_to)
+    }
+
+    /// Shown while the view is scrolled away from the bottom, clicking it goes back there
+    fn show_new_output_indicator(&mut self, ctx: &Context, new_rows: usize) {
+        let clicked = egui::Area::new(self.id.with("new_output_indicator"))
+            .order(egui::Order::Foreground)
+            .pivot(egui::Align2::CENTER_BOTTOM)
+            .fixed_pos(self.viewport.rect.center_bottom() - egui::vec2(0.0, 12.0))
+            .show(ctx, |ui| {
+                let button = egui::Button::new(new_output_label(new_rows)).rounding(10.0);
+                ui.add(button).clicked()
+            })
+            .inner;
+        if clicked {
+            self.viewport.scroll_to_bottom = true;
+            ctx.request_repaint();
+        }
+    }
+
+    fn font_size(&self) -> f32 {
+        self.profile_font_size.unwrap_or(self.font_size)
+    }
+
+    /// Use `font_size` while a profile asks for it, going back to the window's own size after
+    pub fn set_profile_font_size(&mut self, font_size: Option<f32>) {
+        self.profile_font_size = font_size;
     }
 
     pub fn calculate_available_size(&self, ui: &mut Ui) -> (usize, usize) {
-        let character_size = get_char_size(ui.ctx(), self.font_size);
+        let character_size = get_char_size(ui.ctx(), self.font_size());
         let width_chars = (ui.available_width() / character_size.0).floor() as usize;
+        // The wrap indicator goes in a column of its own
+        let width_chars = width_chars.saturating_sub(usize::from(self.wrap_indicator));
         let height_chars = (ui.available_height() / character_size.1).floor() as usize;
         (width_chars, height_chars)
     }
 
-    pub fn show<Io: TermIo>(&mut self, ui: &mut Ui, terminal_emulator: &mut TerminalEmulator<Io>) {
-        let character_size = get_char_size(ui.ctx(), self.font_size);
+    /// Space needed to show a terminal of `grid` columns and rows
+    pub fn grid_area_size(&self, ctx: &Context, (columns, rows): (usize, usize)) -> egui::Vec2 {
+        let character_size = get_char_size(ctx, self.font_size());
+        // A little extra so that rounding never costs a column or row
+        let columns = columns + usize::from(self.wrap_indicator);
+        egui::vec2(
+            columns as f32 * character_size.0,
+            rows as f32 * character_size.1,
+        ) + egui::Vec2::splat(0.5)
+    }
+
+    /// Returns whether the child process produced output since the last frame
+    pub fn show<Io: TermIo>(
+        &mut self,
+        ui: &mut Ui,
+        terminal_emulator: &mut TerminalEmulator<Io>,
+    ) -> bool {
+        let character_size = get_char_size(ui.ctx(), self.font_size());
+
+        let received_output = terminal_emulator.read();
+        if let Some(text) = terminal_emulator.take_clipboard_request() {
+            self.clipboard
+                .copy(ui.ctx(), String::from_utf8_lossy(&text).into_owned());
+        }
+
+        // Programs can change the background at runtime, so it cannot be left to the panel. Only
+        // the default background is translucent, cells with their own color are painted over it
+        ui.painter().rect_filled(
+            ui.clip_rect(),
+            0.0,
+            rgb_to_egui(terminal_emulator.color_scheme().background)
+                .gamma_multiply(self.background_opacity),
+        );
+        self.background_image.render(ui, ui.clip_rect());
+
+        // Take the cursor from the state that is about to be rendered. Nothing below parses more
+        // output, so the cursor is always drawn on the grid it belongs to, never a frame behind
+        let cursor_pos = terminal_emulator.cursor_pos();
+        let cursor_line_size = terminal_emulator.cursor_line_size();
+        let now = Instant::now();
+        self.cursor_trail.update(&cursor_pos, now);
+
+        // Keys typed into the search bar must not also reach the shell, including the Enter or
+        // Escape that made it lose focus this frame
+        let search_had_focus = self.search_has_focus;
+        let open_search = ui.input(|i| i.raw.events.iter().any(is_search_shortcut));
+        if open_search {
+            self.open_search();
+        }
+        self.show_search_bar(ui.ctx(), ui.max_rect().right_top() + egui::vec2(-8.0, 8.0));
+        if let Some(dialog) = &mut self.save_text_dialog {
+            let trim = self.copy_trim_trailing_whitespace;
+            let open = dialog.show(ui.ctx(), |mut out| {
+                terminal_emulator.write_text(&mut out, trim)
+            });
+            if !open {
+                self.save_text_dialog = None;
+            }
+        }
+        let forward_input = !open_search
+            && !search_had_focus
+            && !self.search_has_focus
+            && self.save_text_dialog.is_none();
+        let clear_buffer_pressed = forward_input
+            && ui.input(|i| {
+                i.raw
+                    .events
+                    .iter()
+                    .any(|e| self.clear_buffer_shortcut.matches(e))
+            });
+        if std::mem::take(&mut self.clear_buffer_requested) || clear_buffer_pressed {
+            terminal_emulator.clear_buffer();
+            // Offsets into the old contents mean nothing anymore
+            self.selection = None;
+        }
+        if forward_input
+            && ui.input(|i| {
+                i.raw
+                    .events
+                    .iter()
+                    .any(|e| self.restart_shell_shortcut.matches(e))
+            })
+        {
+            self.request_restart_shell();
+        }
+        let (mut highlights, scroll_to) = self.update_search(terminal_emulator);
+        if let Some(range) = &scroll_to {
+            self.flash_line(terminal_emulator, range.start, now);
+        }
+        if let Some(selection) = self.selection.as_ref().filter(|s| !s.is_empty()) {
+            highlights = overlay_highlight(
+                highlights,
+                Highlight {
+                    range: selection.range(),
+                    color: rgba_to_egui(terminal_emulator.color_scheme().selection),
+                },
+            );
+        }
+        let flash_strength = self
+            .jump_flash
+            .as_ref()
+            .and_then(|flash| flash.strength(now, self.reduce_motion));
+        match (&self.jump_flash, flash_strength) {
+            (Some(flash), Some(strength)) => {
+                let color = rgb_to_egui(terminal_emulator.color_scheme().foreground)
+                    .gamma_multiply(0.3 * strength);
+                highlights = underlay_highlight(
+                    highlights,
+                    Highlight {
+                        range: flash.range.clone(),
+                        color,
+                    },
+                );
+                if self.reduce_motion {
+                    let age = now.saturating_duration_since(flash.start);
+                    ui.ctx()
+                        .request_repaint_after(JUMP_FLASH_DURATION.saturating_sub(age));
+                } else {
+                    ui.ctx().request_repaint();
+                }
+            }
+            _ => self.jump_flash = None,
+        }
 
-        terminal_emulator.read();
+        let copy_requested = ui.input(|i| {
+            i.raw
+                .events
+                .iter()
+                .any(|e| is_copy_shortcut(e, &i.modifiers))
+        });
+        if copy_requested {
+            self.copy_selection(ui.ctx(), terminal_emulator);
+        }
+        if let Some(format) = self.styled_copy_requested.take() {
+            self.copy_styled(ui.ctx(), terminal_emulator, format);
+        }
+        let command_output_copy_requested =
+            ui.input(|i| i.raw.events.iter().any(is_copy_command_output_shortcut));
+        if std::mem::take(&mut self.command_output_copy_requested) || command_output_copy_requested
+        {
+            self.copy_last_command_output(ui.ctx(), terminal_emulator);
+        }
+        if std::mem::take(&mut self.copy_all_requested) {
+            self.copy_all(ui.ctx(), terminal_emulator);
+        }
 
         let frame_response = egui::Frame::none().show(ui, |ui| {
             let (width_chars, height_chars) = terminal_emulator.get_win_size();
@@ -514,60 +2300,693 @@ impl TerminalWidget {
             ui.set_width(width_chars * character_size.0);
             ui.set_height(height_chars * character_size.1);
 
-            ui.input(|input_state| {
+            let typed = ui.input(|input_state| {
+                let mut typed = false;
                 for event in &input_state.raw.events {
-                    if let Event::Text(text) = event {
-                        self.last_keystroke = Some(format!("Text: {}", text));
-                    } else if let Event::Key { key, pressed: true, modifiers, .. } = event {
-                        self.last_keystroke = Some(format!("Key: {:?} (ctrl: {}, alt: {}, shift: {})",
-                            key, modifiers.ctrl, modifiers.alt, modifiers.shift));
+                    typed |= matches!(event, Event::Text(_) | Event::Key { pressed: true, .. });
+                }
+                if forward_input {
+                    let sent = write_input_to_terminal(
+                        input_state,
+                        terminal_emulator,
+                        &mut *self.clipboard,
+                        &self.macros,
+                        &[&self.clear_buffer_shortcut, &self.restart_shell_shortcut],
+                        self.enter_sends,
+                        !self.inspect_keys,
+                    );
+                    let events = &input_state.raw.events;
+                    for (i, sent) in sent.into_iter().enumerate() {
+                        self.keystrokes.push(
+                            &events[i],
+                            events.get(i + 1),
+                            sent,
+                            !self.inspect_keys,
+                        );
                     }
                 }
-                write_input_to_terminal(input_state, terminal_emulator);
+                typed && forward_input
             });
+            if (typed && self.scroll_on_input) || (received_output && self.scroll_on_output) {
+                self.viewport.scroll_to_bottom = true;
+            }
+            if typed {
+                self.cursor_blink.key_pressed(now);
+                self.pointer_hidden = self.hide_pointer_while_typing;
+            } else if ui.input(|i| i.pointer.is_moving()) {
+                self.pointer_hidden = false;
+            }
 
-            let output_response = render_terminal_output(ui, terminal_emulator, self.font_size);
-            self.debug_renderer
-                .render(ui, output_response.canvas_area, Color32::BLUE);
+            // Cloned so that the style does not hold on to self
+            let wrap_indicator = self
+                .wrap_indicator
+                .then(|| self.wrap_indicator_glyph.clone());
+            let text_style = TerminalTextStyle {
+                font_size: self.font_size(),
+                color_scheme: terminal_emulator.color_scheme(),
+                builtin_box_drawing: self.builtin_box_drawing,
+                columns: terminal_emulator.get_win_size().0,
+                wrap_indicator: wrap_indicator.as_deref(),
+            };
+            let output_response = render_terminal_output(
+                ui,
+                terminal_emulator,
+                highlights,
+                scroll_to,
+                &text_style,
+                &mut self.viewport,
+            );
+            self.update_selection(ui, &output_response, terminal_emulator);
+            if let Some(new_rows) = output_response.new_rows {
+                self.show_new_output_indicator(ui.ctx(), new_rows);
+            }
+            let text_hovered = [
+                &output_response.scrollback.response,
+                &output_response.canvas.response,
+            ]
+            .iter()
+            .any(|response| response.hovered());
+            if text_hovered {
+                ui.ctx().set_cursor_icon(match self.pointer_hidden {
+                    true => egui::CursorIcon::None,
+                    false => egui::CursorIcon::Text,
+                });
+            }
 
+            let scrollback_area = output_response.scrollback_area;
+            let canvas_area = output_response.canvas.response.rect;
+            self.debug_renderer.render(ui, canvas_area, Color32::BLUE);
             self.debug_renderer
-                .render(ui, output_response.scrollback_area, Color32::YELLOW);
+                .render(ui, scrollback_area, Color32::YELLOW);
 
-            let canvas_start_y = if output_response.scrollback_area.height() > 0.0 {
-                output_response.scrollback_area.bottom()
+            let canvas_start_y = if scrollback_area.height() > 0.0 {
+                scrollback_area.bottom()
             } else {
-                output_response.canvas_area.top()
+                canvas_area.top()
             };
 
             let cursor_base_rect = Rect::from_min_size(
-                egui::pos2(output_response.canvas_area.left(), canvas_start_y),
+                egui::pos2(canvas_area.left(), canvas_start_y),
                 egui::vec2(
                     terminal_emulator.get_win_size().0 as f32 * character_size.0,
                     terminal_emulator.get_win_size().1 as f32 * character_size.1,
                 ),
             );
 
-            paint_cursor(
+            let cursor_color = rgb_to_egui(terminal_emulator.color_scheme().cursor);
+            self.cursor_trail
+                .paint(cursor_base_rect, &character_size, cursor_color, ui, now);
+
+            // Cells on a double width line are twice as wide, and so is the cursor
+            let cursor_cell_size = if cursor_line_size.is_double_width() {
+                (character_size.0 * 2.0, character_size.1)
+            } else {
+                character_size
+            };
+            // An unfocused window keeps a solid cursor rather than waking up to blink it
+            let focused = ui.input(|i| i.focused);
+            if focused {
+                if let Some(next_change) = self.cursor_blink.next_change(now) {
+                    ui.ctx().request_repaint_after(next_change);
+                }
+            }
+            if !focused || self.cursor_blink.is_visible(now) {
+                let cursor_rect = paint_cursor(
+                    cursor_base_rect,
+                    &cursor_cell_size,
+                    &cursor_pos,
+                    cursor_color,
+                    ui,
+                );
+                paint_cursor_text(
+                    ui,
+                    &output_response.canvas,
+                    cursor_rect,
+                    self.cursor_text_color,
+                    &text_style,
+                );
+            }
+
+            self.grid_overlay.render(
+                ui,
                 cursor_base_rect,
                 &character_size,
-                &terminal_emulator.cursor_pos(),
-                ui,
+                terminal_emulator.get_win_size(),
             );
         });
 
         self.debug_renderer
             .render(ui, frame_response.response.rect, Color32::RED);
+
+        received_output
     }
 
-    pub fn last_keystroke(&self) -> Option<&str> {
-        self.last_keystroke.as_deref()
+    /// Whether the restart shell shortcut was pressed since the last call
+    pub fn take_restart_shell_request(&mut self) -> bool {
+        std::mem::take(&mut self.restart_shell_requested)
+    }
+
+    pub fn request_restart_shell(&mut self) {
+        self.restart_shell_requested = true;
+        // Like for clearing the buffer, the old offsets may be gone
+        self.selection = None;
+    }
+
+    /// Diagnostics that belong in the debug panel
+    pub fn show_debug_options(&mut self, ui: &mut Ui) {
+        ui.checkbox(&mut self.grid_overlay.enable, "Grid overlay");
+        ui.add_space(10.0);
+        ui.checkbox(
+            &mut self.inspect_keys,
+            "Inspect keys instead of sending them",
+        );
+        ui.label("Sent to the child:");
+        self.keystrokes.show(ui);
     }
 
     pub fn show_options(&mut self, ui: &mut Ui) {
         ui.horizontal(|ui| {
             ui.label("Font size:");
-            ui.add(DragValue::new(&mut self.font_size).range(1.0..=100.0));
+            // Changes the profile's size while one is active
+            let font_size = self
+                .profile_font_size
+                .as_mut()
+                .unwrap_or(&mut self.font_size);
+            ui.add(DragValue::new(font_size).range(1.0..=100.0));
         });
         ui.checkbox(&mut self.debug_renderer.enable, "Debug render");
+        ui.checkbox(&mut self.cursor_trail.enable, "Cursor trail");
+        ui.checkbox(&mut self.cursor_blink.enable, "Blink cursor");
+        ui.checkbox(&mut self.builtin_box_drawing, "Draw box characters");
+        ui.checkbox(&mut self.wrap_indicator, "Mark wrapped lines");
+        if ui.button("Search (Ctrl+Shift+F)").clicked() {
+            self.open_search();
+            ui.close_menu();
+        }
+        // The emulator is not available here, copy on the next frame instead
+        if ui.button("Copy as HTML").clicked() {
+            self.styled_copy_requested = Some(StyledCopyFormat::Html);
+            ui.close_menu();
+        }
+        if ui.button("Copy with colors (ANSI)").clicked() {
+            self.styled_copy_requested = Some(StyledCopyFormat::Ansi);
+            ui.close_menu();
+        }
+        if ui
+            .button("Copy last command output (Ctrl+Shift+O)")
+            .clicked()
+        {
+            self.command_output_copy_requested = true;
+            ui.close_menu();
+        }
+        if ui.button("Copy all text").clicked() {
+            self.copy_all_requested = true;
+            ui.close_menu();
+        }
+        if ui.button("Save all text…").clicked() {
+            self.save_text_dialog = Some(SaveTextDialog::new());
+            ui.close_menu();
+        }
+        if ui
+            .button(format!(
+                "Clear screen and scrollback ({})",
+                self.clear_buffer_shortcut
+            ))
+            .clicked()
+        {
+            self.clear_buffer_requested = true;
+            ui.close_menu();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::terminal_emulator::{str_to_cells, LineSize, TerminalColor};
+    use crate::test_util::cells;
+
+    fn section(range: Range<usize>) -> LayoutSection {
+        LayoutSection {
+            leading_space: 0.0,
+            byte_range: range,
+            format: TextFormat::default(),
+        }
+    }
+
+    #[test]
+    fn test_apply_highlights() {
+        let sections = vec![section(0..4), section(4..10)];
+        let highlights = [
+            Highlight {
+                range: 2..6,
+                color: Color32::RED,
+            },
+            Highlight {
+                range: 8..9,
+                color: Color32::BLUE,
+            },
+        ];
+
+        let result = apply_highlights(sections, &highlights);
+        let ranges: Vec<_> = result
+            .iter()
+            .map(|s| (s.byte_range.clone(), s.format.background))
+            .collect();
+
+        assert_eq!(
+            ranges,
+            &[
+                (0..2, Color32::TRANSPARENT),
+                (2..4, Color32::RED),
+                (4..6, Color32::RED),
+                (6..8, Color32::TRANSPARENT),
+                (8..9, Color32::BLUE),
+                (9..10, Color32::TRANSPARENT),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_highlights_for_scrollback() {
+        let highlights = vec![
+            Highlight {
+                range: 0..2,
+                color: Color32::RED,
+            },
+            Highlight {
+                range: 4..7,
+                color: Color32::RED,
+            },
+            Highlight {
+                range: 8..9,
+                color: Color32::RED,
+            },
+        ];
+
+        let split = split_highlights_for_scrollback(highlights, 5);
+        let scrollback: Vec<_> = split.scrollback.iter().map(|h| h.range.clone()).collect();
+        let visible: Vec<_> = split.visible.iter().map(|h| h.range.clone()).collect();
+        assert_eq!(scrollback, &[0..2, 4..5]);
+        assert_eq!(visible, &[0..2, 3..4]);
+    }
+
+    #[test]
+    fn test_new_rows() {
+        let mut viewport = ScrollbackViewport {
+            id: egui::Id::new("test"),
+            offset: 0.0,
+            alternate_screen: false,
+            rect: Rect::NOTHING,
+            scroll_by: 0.0,
+            scroll_to_bottom: false,
+            rows_when_scrolled_away: None,
+            rows: ScrollbackRows::default(),
+        };
+        assert_eq!(viewport.update_new_rows(true, 10), None);
+        assert_eq!(viewport.update_new_rows(false, 10), Some(0));
+        assert_eq!(viewport.update_new_rows(false, 13), Some(3));
+        // Clearing the buffer leaves fewer rows than there were
+        assert_eq!(viewport.update_new_rows(false, 2), Some(0));
+        assert_eq!(viewport.update_new_rows(true, 20), None);
+        assert_eq!(viewport.update_new_rows(false, 20), Some(0));
+
+        assert_eq!(new_output_label(0), "\u{2193} Back to bottom");
+        assert_eq!(new_output_label(1), "\u{2193} 1 new line");
+        assert_eq!(new_output_label(5), "\u{2193} 5 new lines");
+    }
+
+    #[test]
+    fn test_visible_scrollback_rows() {
+        let rows = |min, max| visible_rows(Rangef::new(min, max), 10.0, 1_000_000);
+        assert_eq!(rows(0.0, 100.0), 0..20);
+        assert_eq!(rows(5000.0, 5100.0), 490..520);
+        assert_eq!(rows(9_999_950.0, 10_000_050.0), 999_985..1_000_000);
+        assert_eq!(visible_rows(Rangef::new(0.0, 100.0), 10.0, 0), 0..0);
+
+        let rows_of = |data: &[char], columns| {
+            let mut rows = ScrollbackRows::default();
+            rows.update(data, columns);
+            rows
+        };
+
+        let data = &cells("zero\none\ntwo\nthree");
+        let rows = rows_of(data, 80);
+        assert_eq!(rows.count(data), 4);
+        assert_eq!(rows_of(&[], 80).count(&[]), 0);
+        assert_eq!(&data[rows.cell_range(data, 0..1)], cells("zero"));
+        assert_eq!(&data[rows.cell_range(data, 1..3)], cells("one\ntwo"));
+        assert_eq!(&data[rows.cell_range(data, 3..4)], cells("three"));
+        assert_eq!(rows.cell_range(data, 2..2), 9..9);
+        assert_eq!(rows.cell_range(data, 4..6), 18..18);
+        assert_eq!(rows.row_at(data, 0), 0);
+        assert_eq!(rows.row_at(data, 8), 1);
+        assert_eq!(rows.row_at(data, 16), 3);
+
+        // Soft wrapped lines take up a row per grid width
+        let data = &cells("abcdefghij\n\n\u{e9}\u{e9}\u{e9}\u{e9}\u{e9}");
+        let rows = rows_of(data, 4);
+        assert_eq!(rows.count(data), 6);
+        assert_eq!(&data[rows.cell_range(data, 1..2)], cells("efgh"));
+        assert_eq!(&data[rows.cell_range(data, 2..4)], cells("ij\n"));
+        assert_eq!(&data[rows.cell_range(data, 5..6)], cells("\u{e9}"));
+        assert_eq!(rows.row_at(data, 9), 2);
+        assert_eq!(rows.row_at(data, 17), 5);
+
+        // A wide character that does not fit on the end of a row starts the next one
+        let data = &str_to_cells("abc漢de");
+        assert_eq!(row_ranges(data, 4).collect::<Vec<_>>(), [0..3, 3..7]);
+        assert_eq!(rows_of(data, 4).count(data), 2);
+    }
+
+    #[test]
+    fn test_scrollback_rows_kept_between_frames() {
+        // Lines appended one frame at a time, the last one growing while it wraps, end up with
+        // the rows of the whole scrollback found at once
+        let data = cells("abcdefghij\n\nklm\nnopqrstuvwxyz");
+        let mut rows = ScrollbackRows::default();
+        for end in 0..=data.len() {
+            rows.update(&data[..end], 4);
+        }
+        let expected: Vec<_> = row_ranges(&data, 4).map(|row| row.start).collect();
+        assert_eq!(rows.starts, expected[..5]);
+        assert_eq!(rows.count(&data), 9);
+        for (row, start) in expected.iter().enumerate() {
+            assert_eq!(rows.start(&data, row), Some(*start));
+        }
+
+        // A narrower grid or a cleared scrollback does not use the rows from before
+        rows.update(&data, 3);
+        assert_eq!(rows.count(&data), 11);
+        let data = cells("ab\ncd");
+        rows.update(&data, 3);
+        assert_eq!(rows.starts, [0]);
+        assert_eq!(rows.count(&data), 2);
+    }
+
+    #[test]
+    fn test_wide_char_padding() {
+        let data = str_to_cells("a漢b\nxyz漢");
+        let cell_text = CellText::new(&data);
+        assert_eq!(cell_text.text, "a漢b\nxyz漢");
+        assert_eq!(cell_text.byte_range(1..3), Some(1..4));
+        assert_eq!(cell_text.byte_range(2..3), None);
+
+        // The glyph after 漢 makes up for the 4 its glyph is short of two cells, and the 漢
+        // that only fits on the next row is pushed past the end of its row
+        let padding = wide_char_padding(&data, &cell_text, 4, 10.0, |_| 16.0);
+        assert_eq!(padding, [(4, 4.0), (9, 10.0)]);
+
+        let sections = add_leading_space(vec![section(0..5), section(5..12)], &padding);
+        let ranges: Vec<_> = sections.iter().map(|s| s.byte_range.clone()).collect();
+        assert_eq!(ranges, [0..4, 4..5, 5..9, 9..12]);
+        let leading_space: Vec<_> = sections.iter().map(|s| s.leading_space).collect();
+        assert_eq!(leading_space, [0.0, 4.0, 0.0, 10.0]);
+    }
+
+    #[test]
+    fn test_wrap_indicator() {
+        let ctx = Context::default();
+        setup_fonts(&ctx);
+        let color_scheme = ColorScheme::default();
+        let text_style = TerminalTextStyle {
+            font_size: 12.0,
+            color_scheme: &color_scheme,
+            builtin_box_drawing: false,
+            columns: 4,
+            wrap_indicator: Some("\u{bb}"),
+        };
+        let tag = FormatTag {
+            start: 0,
+            end: usize::MAX,
+            color: TerminalColor::Default,
+            bold: false,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+        };
+        let mut rows = 0;
+        let output = ctx.run(Default::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                let rendered = add_terminal_data_to_ui(
+                    ui,
+                    &cells("abcdefghij\nxy"),
+                    std::slice::from_ref(&tag),
+                    &[],
+                    &[],
+                    None,
+                    &text_style,
+                );
+                rows = rendered.galley.rows.len();
+                paint_wrap_indicators(ui, &rendered, false, &text_style);
+            });
+        });
+
+        assert_eq!(rows, 4);
+        let indicators = output
+            .shapes
+            .into_iter()
+            .filter(|clipped| match &clipped.shape {
+                Shape::Text(text) => text.galley.text() == "\u{bb}",
+                _ => false,
+            })
+            .count();
+        assert_eq!(indicators, 2);
+    }
+
+    #[test]
+    fn test_slice_terminal_data() {
+        let tag = |start, end| FormatTag {
+            start,
+            end,
+            color: TerminalColor::Default,
+            bold: false,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+        };
+        let format_data = [tag(0, 6), tag(6, 12), tag(12, usize::MAX)];
+        let line_sizes = [
+            LineSizeTag {
+                line_start: 4,
+                size: LineSize::DoubleWidth,
+            },
+            LineSizeTag {
+                line_start: 8,
+                size: LineSize::DoubleWidth,
+            },
+        ];
+        let highlights = [Highlight {
+            range: 2..9,
+            color: Color32::RED,
+        }];
+
+        let data = cells("abc\ndef\nghi\njkl");
+        let (data, format_data, line_sizes, highlights) =
+            slice_terminal_data(&data, &format_data, &line_sizes, &highlights, 4..11);
+        assert_eq!(data, cells("def\nghi"));
+        let format_ranges: Vec<_> = format_data.iter().map(|t| t.start..t.end).collect();
+        assert_eq!(format_ranges, [0..2, 2..7]);
+        assert_eq!(line_sizes.len(), 2);
+        assert_eq!(line_sizes[1].line_start, 4);
+        assert_eq!(highlights[0].range, 0..5);
+    }
+
+    #[test]
+    fn test_cursor_trail() {
+        let start = Instant::now();
+        let mut trail = CursorTrail::new(true);
+        trail.update(&CursorPos { x: 0, y: 0 }, start);
+        assert!(trail.points.is_empty());
+
+        trail.update(&CursorPos { x: 1, y: 0 }, start);
+        trail.update(&CursorPos { x: 1, y: 0 }, start);
+        assert_eq!(trail.points.len(), 1);
+        assert_eq!(trail.points[0].pos, CursorPos { x: 0, y: 0 });
+
+        trail.update(&CursorPos { x: 1, y: 0 }, start + CURSOR_TRAIL_DURATION);
+        assert!(trail.points.is_empty());
+
+        let mut trail = CursorTrail::new(false);
+        trail.update(&CursorPos { x: 0, y: 0 }, start);
+        trail.update(&CursorPos { x: 1, y: 0 }, start);
+        assert!(trail.points.is_empty());
+    }
+
+    #[test]
+    fn test_overlay_highlight() {
+        let highlights = vec![
+            Highlight {
+                range: 0..4,
+                color: Color32::RED,
+            },
+            Highlight {
+                range: 5..6,
+                color: Color32::RED,
+            },
+            Highlight {
+                range: 8..12,
+                color: Color32::RED,
+            },
+        ];
+
+        let res = overlay_highlight(
+            highlights,
+            Highlight {
+                range: 2..10,
+                color: Color32::BLUE,
+            },
+        );
+        let ranges: Vec<_> = res.iter().map(|h| h.range.clone()).collect();
+        assert_eq!(ranges, &[0..2, 2..10, 10..12]);
+        assert_eq!(res[1].color, Color32::BLUE);
+    }
+
+    #[test]
+    fn test_underlay_highlight() {
+        let highlights = vec![
+            Highlight {
+                range: 0..4,
+                color: Color32::RED,
+            },
+            Highlight {
+                range: 5..6,
+                color: Color32::RED,
+            },
+            Highlight {
+                range: 12..14,
+                color: Color32::RED,
+            },
+        ];
+
+        let res = underlay_highlight(
+            highlights,
+            Highlight {
+                range: 2..10,
+                color: Color32::BLUE,
+            },
+        );
+        let ranges: Vec<_> = res.iter().map(|h| h.range.clone()).collect();
+        assert_eq!(ranges, &[0..4, 4..5, 5..6, 6..10, 12..14]);
+        assert_eq!(res[1].color, Color32::BLUE);
+        assert_eq!(res[3].color, Color32::BLUE);
+    }
+
+    #[test]
+    fn test_jump_flash() {
+        let start = Instant::now();
+        let flash = JumpFlash::new(&cells("one\ntwo\nthree"), 5, start);
+        assert_eq!(flash.range, 4..7);
+        assert_eq!(JumpFlash::new(&cells("one\ntwo"), 20, start).range, 4..7);
+
+        assert_eq!(flash.strength(start, false), Some(1.0));
+        let half = start + JUMP_FLASH_DURATION / 2;
+        assert!(flash.strength(half, false).is_some_and(|s| s < 1.0));
+        assert_eq!(flash.strength(half, true), Some(1.0));
+        assert_eq!(flash.strength(start + JUMP_FLASH_DURATION, false), None);
+    }
+
+    #[test]
+    fn test_grid_ruler_offsets() {
+        let columns: Vec<_> = grid_ruler_offsets(20, GRID_OVERLAY_COLUMNS).collect();
+        assert_eq!(columns, [8, 16]);
+        let rows: Vec<_> = grid_ruler_offsets(10, GRID_OVERLAY_ROWS).collect();
+        assert_eq!(rows, [5]);
+        assert_eq!(grid_ruler_offsets(3, GRID_OVERLAY_ROWS).count(), 0);
+    }
+
+    #[test]
+    fn test_underline_segments() {
+        let ctx = Context::default();
+        let mut galley = None;
+        let _ = ctx.run(Default::default(), |ctx| {
+            let job = LayoutJob::single_section(
+                "abcd\nefgh".to_string(),
+                TextFormat::simple(FontId::monospace(12.0), Color32::WHITE),
+            );
+            galley = Some(ctx.fonts(|fonts| fonts.layout_job(job)));
+        });
+        let galley = galley.unwrap();
+
+        let underlines = [
+            Underline {
+                range: 1..3,
+                style: UnderlineStyle::Curly,
+                color: Color32::RED,
+            },
+            Underline {
+                range: 3..7,
+                style: UnderlineStyle::Dotted,
+                color: Color32::RED,
+            },
+        ];
+        let segments = underline_segments(&galley, &underlines);
+        let glyph_x = |row: usize, idx: usize| galley.rows[row].glyphs[idx].pos.x;
+        let glyph_max_x = |row: usize, idx: usize| galley.rows[row].glyphs[idx].max_x();
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].0.style, UnderlineStyle::Curly);
+        assert_eq!(segments[0].2.start, glyph_x(0, 1));
+        assert_eq!(segments[0].2.end, glyph_max_x(0, 2));
+        assert_eq!(segments[1].0.style, UnderlineStyle::Dotted);
+        assert_eq!(segments[1].2.start, glyph_x(0, 3));
+        assert_eq!(segments[2].0.style, UnderlineStyle::Dotted);
+        assert_eq!(segments[2].2.start, glyph_x(1, 0));
+        assert_eq!(segments[2].2.end, glyph_max_x(1, 1));
+        assert!(segments[2].1 > segments[1].1);
+    }
+
+    fn underline_colors(format_data: &[FormatTag]) -> Vec<Color32> {
+        let ctx = Context::default();
+        setup_fonts(&ctx);
+        let color_scheme = ColorScheme::default();
+        let text_style = TerminalTextStyle {
+            font_size: 12.0,
+            color_scheme: &color_scheme,
+            builtin_box_drawing: false,
+            columns: 80,
+            wrap_indicator: None,
+        };
+        let output = ctx.run(Default::default(), |ctx| {
+            egui::CentralPanel::default().show(ctx, |ui| {
+                add_terminal_data_to_ui(ui, &cells("ab"), format_data, &[], &[], None, &text_style);
+            });
+        });
+
+        output
+            .shapes
+            .into_iter()
+            .filter_map(|clipped| match clipped.shape {
+                Shape::LineSegment { stroke, .. } => match stroke.color {
+                    egui::epaint::ColorMode::Solid(color) => Some(color),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_underline_color() {
+        let mut tag = FormatTag {
+            start: 0,
+            end: usize::MAX,
+            color: TerminalColor::Default,
+            bold: false,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::Single,
+            underline_color: TerminalColor::Red,
+        };
+        let scheme = ColorScheme::default();
+        assert_eq!(
+            underline_colors(std::slice::from_ref(&tag)),
+            [rgb_to_egui(scheme.palette[1])]
+        );
+
+        tag.underline_color = TerminalColor::Default;
+        assert_eq!(
+            underline_colors(std::slice::from_ref(&tag)),
+            [rgb_to_egui(scheme.foreground)]
+        );
     }
 }
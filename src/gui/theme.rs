@@ -0,0 +1,144 @@
+use crate::{
+    config::{Config, Theme},
+    terminal_emulator::{ColorScheme, TermIo, TerminalEmulator},
+};
+use eframe::egui;
+
+/// The egui theme that `theme` resolves to, given what the system currently uses
+fn resolve_theme(theme: Theme, system_theme: Option<egui::Theme>) -> egui::Theme {
+    match theme {
+        Theme::Auto => system_theme.unwrap_or(egui::Theme::Dark),
+        Theme::Light => egui::Theme::Light,
+        Theme::Dark => egui::Theme::Dark,
+    }
+}
+
+/// The terminal colors for `theme`, unless a scheme was selected by name
+fn color_scheme_for<'a>(
+    config: &'a Config,
+    theme: egui::Theme,
+    selected: Option<&str>,
+) -> &'a ColorScheme {
+    if let Some(scheme) = selected.and_then(|name| config.named_color_scheme(name)) {
+        return scheme;
+    }
+    match theme {
+        egui::Theme::Light => &config.light_colors,
+        egui::Theme::Dark => &config.dark_colors,
+    }
+}
+
+/// Keeps egui's visuals and the terminal colors in line with the configured theme, switching
+/// both when the system appearance changes
+pub struct ThemeTracker {
+    applied: Option<egui::Theme>,
+    // Terminal colors picked for this window, overriding the theme's
+    color_scheme: Option<String>,
+    // From the active profile, overriding both
+    profile_color_scheme: Option<String>,
+}
+
+impl ThemeTracker {
+    pub fn new(config: &Config) -> ThemeTracker {
+        ThemeTracker {
+            applied: None,
+            color_scheme: config.color_scheme.clone(),
+            profile_color_scheme: None,
+        }
+    }
+
+    /// Use `color_scheme` while a profile asks for it
+    pub fn set_profile_color_scheme(&mut self, color_scheme: Option<String>) {
+        if color_scheme != self.profile_color_scheme {
+            self.profile_color_scheme = color_scheme;
+            self.invalidate();
+        }
+    }
+
+    /// Apply the theme again on the next update, e.g. after the terminal emulator was replaced
+    pub fn invalidate(&mut self) {
+        self.applied = None;
+    }
+
+    pub fn update<Io: TermIo>(
+        &mut self,
+        ctx: &egui::Context,
+        config: &Config,
+        terminal_emulator: &mut TerminalEmulator<Io>,
+    ) {
+        let theme = resolve_theme(config.theme, ctx.system_theme());
+        if self.applied == Some(theme) {
+            return;
+        }
+
+        ctx.set_theme(theme);
+        let selected = self
+            .profile_color_scheme
+            .as_deref()
+            .or(self.color_scheme.as_deref());
+        let color_scheme = color_scheme_for(config, theme, selected);
+        terminal_emulator.set_color_scheme(color_scheme.clone());
+        self.applied = Some(theme);
+    }
+
+    /// Pick the window's color scheme. The window itself keeps following the theme
+    pub fn show_options(&mut self, ui: &mut egui::Ui, config: &Config) {
+        ui.menu_button("Color scheme", |ui| {
+            let mut selected = self.color_scheme.clone();
+            ui.radio_value(&mut selected, None, "Follow theme");
+            for name in config.color_scheme_names() {
+                ui.radio_value(&mut selected, Some(name.to_string()), name);
+            }
+
+            if selected != self.color_scheme {
+                self.color_scheme = selected;
+                self.invalidate();
+                ui.close_menu();
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve_theme() {
+        assert_eq!(
+            resolve_theme(Theme::Auto, Some(egui::Theme::Light)),
+            egui::Theme::Light
+        );
+        assert_eq!(resolve_theme(Theme::Auto, None), egui::Theme::Dark);
+        assert_eq!(
+            resolve_theme(Theme::Dark, Some(egui::Theme::Light)),
+            egui::Theme::Dark
+        );
+        assert_eq!(resolve_theme(Theme::Light, None), egui::Theme::Light);
+    }
+
+    #[test]
+    fn test_color_scheme_for() {
+        let mut config = Config::default();
+        let production = ColorScheme {
+            background: (64, 0, 0),
+            ..Default::default()
+        };
+        config
+            .custom_colors
+            .insert("production".to_string(), production.clone());
+
+        assert_eq!(
+            color_scheme_for(&config, egui::Theme::Light, None),
+            &config.light_colors
+        );
+        assert_eq!(
+            color_scheme_for(&config, egui::Theme::Light, Some("production")),
+            &production
+        );
+        assert_eq!(
+            color_scheme_for(&config, egui::Theme::Light, Some("dark")),
+            &config.dark_colors
+        );
+    }
+}
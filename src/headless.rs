@@ -0,0 +1,45 @@
+use crate::{
+    error::backtraced_err,
+    terminal_emulator::{Pty, RecordingHandle, TerminalEmulator},
+};
+
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How long output is still collected after the child exited. Background jobs of the child can
+/// keep the pty open after it is gone, so this does not wait for the pty to close indefinitely
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Drive the terminal emulator without a window until the child process exits. Returns the exit
+/// code of the child
+pub fn run<P: Pty>(
+    mut terminal_emulator: TerminalEmulator<P>,
+    recording_handle: Option<RecordingHandle>,
+) -> i32 {
+    let exit_code = loop {
+        terminal_emulator.read();
+
+        match terminal_emulator.child_exit_status() {
+            Ok(Some(code)) => break code,
+            Ok(None) => (),
+            Err(e) => {
+                error!("failed to check child status: {}", backtraced_err(&e));
+                break 1;
+            }
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    };
+
+    // The reader thread may not have handed over everything the child wrote before exiting
+    let drain_start = Instant::now();
+    while !terminal_emulator.is_output_closed() && drain_start.elapsed() < DRAIN_TIMEOUT {
+        terminal_emulator.read();
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    terminal_emulator.read();
+
+    // Recordings are flushed when the last handle goes away
+    drop(recording_handle);
+    exit_code
+}
@@ -0,0 +1,11 @@
+#[macro_use]
+pub mod log;
+pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod error;
+pub mod gui;
+pub mod headless;
+pub mod terminal_emulator;
+#[cfg(test)]
+mod test_util;
@@ -1,24 +1,83 @@
 use std::path::PathBuf;
-use terminal_emulator::TerminalEmulator;
+use termie::{
+    config::Config,
+    error, gui, headless, log,
+    terminal_emulator::{Pty, PtyIo, ShellOptions, TerminalEmulator},
+};
 
-#[macro_use]
-mod log;
-mod error;
-mod gui;
-mod terminal_emulator;
+#[cfg(unix)]
+use termie::{
+    daemon,
+    terminal_emulator::{AttachIo, NetIo, NetOptions, SerialIo, SerialOptions},
+};
+
+/// Whether to start recording as soon as the terminal launches, and where to write to
+enum RecordArg {
+    /// Use the next free path in the recording directory
+    Default,
+    Path(PathBuf),
+}
 
 struct Args {
     recording_path: PathBuf,
     replay: Option<PathBuf>,
+    record: Option<RecordArg>,
+    command: Option<String>,
+    /// Serial device to connect to instead of running a shell
+    #[cfg(unix)]
+    serial: Option<SerialOptions>,
+    /// TCP server to connect to instead of running a shell
+    #[cfg(unix)]
+    connect: Option<NetOptions>,
+    /// Socket to serve the session on without a window
+    #[cfg(unix)]
+    daemon: Option<PathBuf>,
+    /// Socket of a daemon whose session to show
+    #[cfg(unix)]
+    attach: Option<PathBuf>,
+    config: Option<PathBuf>,
+    /// Overrides the login_shell config option
+    login_shell: Option<bool>,
+    /// Overrides the color_scheme config option
+    color_scheme: Option<String>,
+    replay_options: gui::ReplayOptions,
+    /// Fixed terminal size from --columns and --rows
+    grid_size: Option<gui::GridSize>,
+    /// Where to log output to, from --logfile
+    logfile: Option<PathBuf>,
+    /// Start fullscreen without window decorations
+    kiosk: bool,
 }
 
 impl Args {
-    fn parse<It: Iterator<Item = String>>(mut it: It) -> Args {
+    fn parse<It: Iterator<Item = String>>(it: It) -> Args {
+        let mut it = it.peekable();
         let program_name = it.next();
 
         // Default value
         let mut recording_path = "recordings".into();
         let mut replay = None;
+        let mut record = None;
+        let mut command = None;
+        #[cfg(unix)]
+        let mut serial = None;
+        #[cfg(unix)]
+        let mut connect = None;
+        #[cfg(unix)]
+        let mut telnet = false;
+        #[cfg(unix)]
+        let mut daemon = None;
+        #[cfg(unix)]
+        let mut attach = None;
+        let mut config = None;
+        let mut login_shell = None;
+        let mut color_scheme = None;
+        let mut replay_options = gui::ReplayOptions::default();
+        let mut columns = None;
+        let mut rows = None;
+        let mut lock_size = false;
+        let mut logfile = None;
+        let mut kiosk = false;
 
         while let Some(arg) = it.next() {
             match arg.as_str() {
@@ -32,6 +91,114 @@ impl Args {
                     };
                 }
                 "--replay" => replay = it.next().map(PathBuf::from),
+                "--autoplay" => replay_options.autoplay = true,
+                "--replay-speed" | "--speed" => {
+                    replay_options.speed = match it.next().map(|s| s.parse::<f32>()) {
+                        Some(Ok(speed)) if speed > 0.0 => speed,
+                        _ => {
+                            println!("--replay-speed needs a positive number");
+                            Self::help(program_name.as_deref());
+                        }
+                    };
+                }
+                "--record" => {
+                    // The path is optional, so only consume the next argument if it does not
+                    // look like another flag
+                    record = match it.next_if(|next| !next.starts_with('-')) {
+                        Some(p) => Some(RecordArg::Path(p.into())),
+                        None => Some(RecordArg::Default),
+                    };
+                }
+                "--config" => {
+                    config = match it.next() {
+                        Some(p) => Some(p.into()),
+                        None => {
+                            println!("Missing argument for --config");
+                            Self::help(program_name.as_deref());
+                        }
+                    };
+                }
+                "--color-scheme" => {
+                    color_scheme = match it.next() {
+                        Some(name) => Some(name),
+                        None => {
+                            println!("Missing argument for --color-scheme");
+                            Self::help(program_name.as_deref());
+                        }
+                    };
+                }
+                "--columns" | "--rows" => {
+                    let size = match it.next().map(|s| s.parse::<usize>()) {
+                        Some(Ok(size)) if size > 0 => size,
+                        _ => {
+                            println!("{arg} needs a positive whole number");
+                            Self::help(program_name.as_deref());
+                        }
+                    };
+                    if arg == "--columns" {
+                        columns = Some(size);
+                    } else {
+                        rows = Some(size);
+                    }
+                }
+                "--lock-size" => lock_size = true,
+                "--kiosk" => kiosk = true,
+                "--logfile" => {
+                    logfile = match it.next() {
+                        Some(p) => Some(p.into()),
+                        None => {
+                            println!("Missing argument for --logfile");
+                            Self::help(program_name.as_deref());
+                        }
+                    };
+                }
+                #[cfg(unix)]
+                "--serial" => {
+                    serial = match it.next().map(|s| s.parse::<SerialOptions>()) {
+                        Some(Ok(options)) => Some(options),
+                        _ => {
+                            println!(
+                                "--serial needs a device path, optionally followed by @<baud rate>"
+                            );
+                            Self::help(program_name.as_deref());
+                        }
+                    };
+                }
+                #[cfg(unix)]
+                "--connect" => {
+                    connect = match it.next().map(|s| s.parse::<NetOptions>()) {
+                        Some(Ok(options)) => Some(options),
+                        _ => {
+                            println!("--connect needs a host and port, e.g. localhost:23");
+                            Self::help(program_name.as_deref());
+                        }
+                    };
+                }
+                #[cfg(unix)]
+                "--telnet" => telnet = true,
+                #[cfg(unix)]
+                "--daemon" | "--attach" => {
+                    let Some(path) = it.next() else {
+                        println!("Missing socket path for {arg}");
+                        Self::help(program_name.as_deref());
+                    };
+                    if arg == "--daemon" {
+                        daemon = Some(PathBuf::from(path));
+                    } else {
+                        attach = Some(PathBuf::from(path));
+                    }
+                }
+                "--login" => login_shell = Some(true),
+                "--no-login" => login_shell = Some(false),
+                "-e" => {
+                    command = match it.next() {
+                        Some(c) => Some(c),
+                        None => {
+                            println!("Missing argument for -e");
+                            Self::help(program_name.as_deref());
+                        }
+                    };
+                }
                 _ => {
                     println!("Invalid argument {arg}");
                     Self::help(program_name.as_deref())
@@ -39,9 +206,65 @@ impl Args {
             }
         }
 
+        let grid_size = match (columns, rows) {
+            (Some(columns), Some(rows)) => Some(gui::GridSize {
+                columns,
+                rows,
+                locked: lock_size,
+            }),
+            (None, None) if !lock_size => None,
+            _ => {
+                println!("--columns and --rows have to be given together, --lock-size needs both");
+                Self::help(program_name.as_deref());
+            }
+        };
+
+        #[cfg(unix)]
+        {
+            let targets = [
+                serial.is_some(),
+                connect.is_some(),
+                attach.is_some(),
+                command.is_some(),
+            ];
+            if targets.into_iter().filter(|v| *v).count() > 1 {
+                println!("Only one of --serial, --connect, --attach and -e can be given");
+                Self::help(program_name.as_deref());
+            }
+            if daemon.is_some() && (serial.is_some() || connect.is_some() || attach.is_some()) {
+                println!("--daemon runs a shell, it cannot be combined with --serial, --connect or --attach");
+                Self::help(program_name.as_deref());
+            }
+            match &mut connect {
+                Some(connect) => connect.telnet = telnet,
+                None if telnet => {
+                    println!("--telnet needs --connect");
+                    Self::help(program_name.as_deref());
+                }
+                None => (),
+            }
+        }
+
         Args {
             recording_path,
             replay,
+            record,
+            command,
+            #[cfg(unix)]
+            serial,
+            #[cfg(unix)]
+            connect,
+            #[cfg(unix)]
+            daemon,
+            #[cfg(unix)]
+            attach,
+            config,
+            login_shell,
+            color_scheme,
+            replay_options,
+            grid_size,
+            logfile,
+            kiosk,
         }
     }
 
@@ -55,31 +278,234 @@ impl Args {
                  Args:\n\
                  --recording-path: Optional, where to output recordings to
                  --replay: Replay a recording
+                 --autoplay: Start playing the replay immediately
+                 --replay-speed, --speed <multiplier>: Replay playback speed, 1 is real time
+                 --config: Optional, config file to use instead of ~/.config/termie/config.json
+                 --record [path]: Start recording immediately, optionally to the given path
+                 --color-scheme <name>: Terminal colors to use regardless of the theme, one of the
+                     schemes under color_schemes in the config, or light or dark
+                 --columns <n>, --rows <n>: Start with a terminal of this many columns and rows,
+                     sizing the window to fit
+                 --lock-size: Keep the size from --columns and --rows when the window is resized
+                 --kiosk: Start fullscreen without window decorations. F11 leaves fullscreen
+                 --logfile <path>: Append all output to path, as plain text or raw depending on
+                     the session_log_format config option
+                 --login, --no-login: Whether to start the shell as a login shell. Defaults to
+                     the login_shell config option, which is on for macOS and off elsewhere
+                 -e <command>: Run command with $SHELL -c instead of an interactive shell. When
+                     combined with --record, runs without a window and exits with the command's
+                     exit code
+                 --serial <path>[@<baud rate>]: Connect to a serial device instead of running a
+                     shell, e.g. /dev/ttyUSB0@115200. The baud rate defaults to 115200
+                 --connect <host>:<port>: Connect to a TCP server instead of running a shell
+                 --telnet: Speak telnet with the server given to --connect
+                 --daemon <socket>: Run the shell, or the command from -e, without a window and
+                     serve it on the Unix socket at socket until it exits. Run it in the
+                     background, e.g. with &
+                 --attach <socket>: Show the session of the daemon on socket. Closing the
+                     window detaches and leaves the session running
                  "
         );
         std::process::exit(1);
     }
 }
 
+fn load_config(path: Option<PathBuf>) -> Config {
+    let Some(path) = path.or_else(Config::default_path) else {
+        return Config::default();
+    };
+
+    match Config::load(&path) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "Failed to load config from {}, using defaults: {}",
+                path.display(),
+                error::backtraced_err(&e)
+            );
+            Config::default()
+        }
+    }
+}
+
+/// Start the terminal on `options`, in a window or, when `headless`, without one
+fn run_terminal<P: Pty + 'static>(
+    options: P::Options,
+    args: Args,
+    config: Config,
+    headless: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match TerminalEmulator::<P>::new(args.recording_path.clone(), &options) {
+        Ok(terminal_emulator) => run_emulator(terminal_emulator, options, args, config, headless),
+        Err(e) => {
+            error!(
+                "Failed to create terminal emulator: {}",
+                error::backtraced_err(&e)
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Show the session of the daemon listening on `socket_path`, starting from its snapshot
+#[cfg(unix)]
+fn run_attached(
+    socket_path: PathBuf,
+    args: Args,
+    config: Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut io = match AttachIo::spawn(&socket_path) {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to attach: {}", error::backtraced_err(&e));
+            return Ok(());
+        }
+    };
+    let snapshot = io.take_snapshot()?;
+    let mut terminal_emulator = match TerminalEmulator::from_snapshot(snapshot, io) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(
+                "Failed to restore the daemon's terminal: {}",
+                error::backtraced_err(&e)
+            );
+            return Ok(());
+        }
+    };
+    terminal_emulator.set_recording_dir(args.recording_path.clone());
+    run_emulator(terminal_emulator, socket_path, args, config, false)
+}
+
+/// Apply the arguments and config to `terminal_emulator` and run it, restarting on `options`
+fn run_emulator<P: Pty + 'static>(
+    mut terminal_emulator: TerminalEmulator<P>,
+    options: P::Options,
+    args: Args,
+    config: Config,
+    headless: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    terminal_emulator.set_max_line_length(config.max_line_length);
+    terminal_emulator.set_invalid_utf8(config.invalid_utf8);
+    terminal_emulator.set_local_echo(config.local_echo);
+    let split_after = config.recording_split_after;
+    terminal_emulator.set_recording_split_after((!split_after.is_zero()).then_some(split_after));
+    if let Some(grid) = args.grid_size {
+        if let Err(e) = terminal_emulator.set_win_size(grid.columns, grid.rows) {
+            error!(
+                "Failed to set terminal size: {}",
+                error::backtraced_err(&*e)
+            );
+            return Ok(());
+        }
+    }
+
+    if let Some(path) = args.logfile {
+        if let Err(e) = terminal_emulator.start_session_log(path, config.session_log_format) {
+            error!("Failed to start session log: {}", error::backtraced_err(&e));
+            return Ok(());
+        }
+    }
+
+    let recording_handle = match args.record {
+        Some(RecordArg::Default) => Some(terminal_emulator.start_recording()),
+        Some(RecordArg::Path(path)) => Some(terminal_emulator.start_recording_at(path)),
+        None => None,
+    };
+
+    let recording_handle = match recording_handle.transpose() {
+        Ok(v) => v,
+        Err(e) => {
+            error!("Failed to start recording: {}", error::backtraced_err(&e));
+            return Ok(());
+        }
+    };
+
+    if headless {
+        let exit_code = headless::run(terminal_emulator, recording_handle);
+        std::process::exit(exit_code);
+    }
+
+    gui::run(
+        terminal_emulator,
+        options,
+        recording_handle,
+        config,
+        args.grid_size,
+        args.kiosk,
+    )
+}
+
+/// Replay a recording, or connect to whatever the arguments ask for
+fn run(mut args: Args, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(replay) = args.replay.take() {
+        return gui::run_replay(replay, config, std::mem::take(&mut args.replay_options));
+    }
+
+    #[cfg(unix)]
+    if let Some(serial) = args.serial.take() {
+        return run_terminal::<SerialIo>(serial, args, config, false);
+    }
+    #[cfg(unix)]
+    if let Some(connect) = args.connect.take() {
+        return run_terminal::<NetIo>(connect, args, config, false);
+    }
+    #[cfg(unix)]
+    if let Some(socket_path) = args.attach.take() {
+        return run_attached(socket_path, args, config);
+    }
+
+    let shell_options = ShellOptions {
+        command: args.command.take(),
+        login: args.login_shell.unwrap_or(config.login_shell),
+        cwd: None,
+    };
+    #[cfg(unix)]
+    if let Some(socket_path) = args.daemon.take() {
+        std::process::exit(daemon::run(&socket_path, &shell_options));
+    }
+    let headless = shell_options.command.is_some() && args.record.is_some();
+    run_terminal::<PtyIo>(shell_options, args, config, headless)
+}
+
 fn main() {
     log::init();
-    let args = Args::parse(std::env::args());
-    let res = if let Some(replay) = args.replay {
-        gui::run_replay(replay)
-    } else {
-        match TerminalEmulator::new(args.recording_path) {
-            Ok(v) => gui::run(v),
-            Err(e) => {
-                error!(
-                    "Failed to create terminal emulator: {}",
-                    error::backtraced_err(&e)
-                );
-                return;
-            }
+    let mut args = Args::parse(std::env::args());
+    let mut config = load_config(args.config.take());
+    if let Some(name) = args.color_scheme.take() {
+        if config.named_color_scheme(&name).is_none() {
+            error!("Unknown color scheme {name}");
+            std::process::exit(1);
         }
-    };
+        config.color_scheme = Some(name);
+    }
 
-    if let Err(e) = res {
+    if let Err(e) = run(args, config) {
         error!("Failed to run gui: {}", error::backtraced_err(&*e));
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Args {
+        Args::parse(["termie"].iter().chain(args).map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_record_path_is_optional() {
+        let args = parse(&["--record", "-e", "cmd"]);
+        assert!(matches!(args.record, Some(RecordArg::Default)));
+        assert_eq!(args.command.as_deref(), Some("cmd"));
+
+        let args = parse(&["--record", "--login"]);
+        assert!(matches!(args.record, Some(RecordArg::Default)));
+        assert_eq!(args.login_shell, Some(true));
+
+        let args = parse(&["--record", "out.rec", "-e", "cmd"]);
+        assert!(
+            matches!(args.record, Some(RecordArg::Path(path)) if path.as_os_str() == "out.rec")
+        );
+        assert_eq!(args.command.as_deref(), Some("cmd"));
+    }
+}
@@ -1,8 +1,12 @@
 use super::{
+    color_scheme::{parse_color_spec, ColorSlot, Rgb},
+    keyboard::{KeyboardFlags, SetKeyboardFlagsMode},
     recording::{NotIntOfType, NotMap},
-    Mode,
+    shell_marks::ShellMark,
+    LineSize, Mode, Notification, Progress, UnderlineStyle,
 };
 use crate::terminal_emulator::recording::SnapshotItem;
+use std::path::PathBuf;
 use thiserror::Error;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -10,6 +14,8 @@ pub enum SelectGraphicRendition {
     // NOTE: Non-exhaustive list
     Reset,
     Bold,
+    NormalIntensity,
+    Underline(UnderlineStyle),
     ForegroundBlack,
     ForegroundRed,
     ForegroundGreen,
@@ -28,16 +34,102 @@ pub enum SelectGraphicRendition {
     ForegroundBrightWhite,
     Foreground256(u8),
     ForegroundRgb(u8, u8, u8),
+    DefaultForeground,
+    BackgroundBlack,
+    BackgroundRed,
+    BackgroundGreen,
+    BackgroundYellow,
+    BackgroundBlue,
+    BackgroundMagenta,
+    BackgroundCyan,
+    BackgroundWhite,
     Background256(u8),
     BackgroundRgb(u8, u8, u8),
+    DefaultBackground,
+    UnderlineColor256(u8),
+    UnderlineColorRgb(u8, u8, u8),
+    DefaultUnderlineColor,
     Unknown(usize),
 }
 
+/// Color given by the parameters following 38, 48 or 58
+enum ExtendedColor {
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+impl ExtendedColor {
+    /// Parses `5;n` or `2;r;g;b` from the start of `params`, returning the color and how many
+    /// params it used
+    fn from_params(params: &[Option<usize>]) -> Option<(ExtendedColor, usize)> {
+        match params {
+            [Some(5), Some(idx), ..] => Some((ExtendedColor::Indexed(*idx as u8), 2)),
+            [Some(2), Some(r), Some(g), Some(b), ..] => {
+                Some((ExtendedColor::Rgb(*r as u8, *g as u8, *b as u8), 4))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parses the `:` separated form, where RGB colors may carry a color space id before the
+    /// components, e.g. `2::r:g:b`
+    fn from_sub_params(sub_params: &[Option<usize>]) -> Option<ExtendedColor> {
+        match sub_params {
+            [Some(2), _, Some(r), Some(g), Some(b)] => {
+                Some(ExtendedColor::Rgb(*r as u8, *g as u8, *b as u8))
+            }
+            _ => Self::from_params(sub_params)
+                .filter(|(_, len)| *len == sub_params.len())
+                .map(|(color, _)| color),
+        }
+    }
+
+    fn into_sgr(self, param: usize) -> SelectGraphicRendition {
+        match (param, self) {
+            (38, ExtendedColor::Indexed(idx)) => SelectGraphicRendition::Foreground256(idx),
+            (38, ExtendedColor::Rgb(r, g, b)) => SelectGraphicRendition::ForegroundRgb(r, g, b),
+            (48, ExtendedColor::Indexed(idx)) => SelectGraphicRendition::Background256(idx),
+            (48, ExtendedColor::Rgb(r, g, b)) => SelectGraphicRendition::BackgroundRgb(r, g, b),
+            (58, ExtendedColor::Indexed(idx)) => SelectGraphicRendition::UnderlineColor256(idx),
+            (58, ExtendedColor::Rgb(r, g, b)) => SelectGraphicRendition::UnderlineColorRgb(r, g, b),
+            _ => SelectGraphicRendition::Unknown(param),
+        }
+    }
+}
+
 impl SelectGraphicRendition {
+    /// Parameters with `:` separated sub-parameters, e.g. `4:3` for a curly underline
+    fn from_sub_params(val: usize, sub_params: &[Option<usize>]) -> SelectGraphicRendition {
+        let sub_param = |idx: usize| sub_params.get(idx).copied().flatten();
+        match val {
+            4 => {
+                let style = match sub_param(0).unwrap_or(1) {
+                    0 => UnderlineStyle::None,
+                    1 => UnderlineStyle::Single,
+                    2 => UnderlineStyle::Double,
+                    3 => UnderlineStyle::Curly,
+                    4 => UnderlineStyle::Dotted,
+                    5 => UnderlineStyle::Dashed,
+                    _ => return Self::Unknown(val),
+                };
+                SelectGraphicRendition::Underline(style)
+            }
+            38 | 48 | 58 => match ExtendedColor::from_sub_params(sub_params) {
+                Some(color) => color.into_sgr(val),
+                None => Self::Unknown(val),
+            },
+            _ => Self::from_usize(val),
+        }
+    }
+
     fn from_usize(val: usize) -> SelectGraphicRendition {
         match val {
             0 => SelectGraphicRendition::Reset,
             1 => SelectGraphicRendition::Bold,
+            4 => SelectGraphicRendition::Underline(UnderlineStyle::Single),
+            21 => SelectGraphicRendition::Underline(UnderlineStyle::Double),
+            22 => SelectGraphicRendition::NormalIntensity,
+            24 => SelectGraphicRendition::Underline(UnderlineStyle::None),
             30 => SelectGraphicRendition::ForegroundBlack,
             31 => SelectGraphicRendition::ForegroundRed,
             32 => SelectGraphicRendition::ForegroundGreen,
@@ -46,6 +138,17 @@ impl SelectGraphicRendition {
             35 => SelectGraphicRendition::ForegroundMagenta,
             36 => SelectGraphicRendition::ForegroundCyan,
             37 => SelectGraphicRendition::ForegroundWhite,
+            39 => SelectGraphicRendition::DefaultForeground,
+            40 => SelectGraphicRendition::BackgroundBlack,
+            41 => SelectGraphicRendition::BackgroundRed,
+            42 => SelectGraphicRendition::BackgroundGreen,
+            43 => SelectGraphicRendition::BackgroundYellow,
+            44 => SelectGraphicRendition::BackgroundBlue,
+            45 => SelectGraphicRendition::BackgroundMagenta,
+            46 => SelectGraphicRendition::BackgroundCyan,
+            47 => SelectGraphicRendition::BackgroundWhite,
+            49 => SelectGraphicRendition::DefaultBackground,
+            59 => SelectGraphicRendition::DefaultUnderlineColor,
             90 => SelectGraphicRendition::ForegroundBrightBlack,
             91 => SelectGraphicRendition::ForegroundBrightRed,
             92 => SelectGraphicRendition::ForegroundBrightGreen,
@@ -61,22 +164,134 @@ impl SelectGraphicRendition {
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum TerminalOutput {
-    SetCursorPos { x: Option<usize>, y: Option<usize> },
-    SetCursorPosRel { x: Option<i32>, y: Option<i32> },
+    SetCursorPos {
+        x: Option<usize>,
+        y: Option<usize>,
+    },
+    SetCursorPosRel {
+        x: Option<i32>,
+        y: Option<i32>,
+    },
     ClearForwards,
+    // ED 1, from the top of the screen up to and including the cursor
+    ClearBackwards,
     ClearAll,
+    // ED 3
+    ClearScrollback,
     CarriageReturn,
     ClearLineForwards,
+    // EL 1 and EL 2
+    ClearLineBackwards,
+    ClearLine,
     Newline,
     Backspace,
     InsertLines(usize),
+    // DL (8.3.32 of ecma-48)
+    DeleteLines(usize),
+    // SU and SD (8.3.147 and 8.3.113 of ecma-48), scroll the scroll region without moving the
+    // cursor
+    ScrollUp(usize),
+    ScrollDown(usize),
+    // DECSTBM, 1 based and inclusive like the sequence. `None` is the edge of the screen
+    SetScrollRegion {
+        top: Option<usize>,
+        bottom: Option<usize>,
+    },
     Delete(usize),
+    // ECH (8.3.38 of ecma-48), blank characters from the cursor on without moving the rest
+    EraseCharacters(usize),
     Sgr(SelectGraphicRendition),
     Data(Vec<u8>),
     SetMode(Mode),
     ResetMode(Mode),
     // ich (8.3.64 of ecma-48)
     InsertSpaces(usize),
+    // REP (8.3.103 of ecma-48), repeat the preceding graphic character
+    RepeatCharacter(usize),
+    // HTS, `ESC H`
+    SetTabStop,
+    // DECSC / DECRC, `ESC 7` / `ESC 8`
+    SaveCursor,
+    RestoreCursor,
+    // IND / NEL / RI, `ESC D` / `ESC E` / `ESC M`
+    Index,
+    NextLine,
+    ReverseIndex,
+    // TBC, `ESC[g` for the stop at the cursor or `ESC[3g` for all of them
+    ClearTabStop {
+        all: bool,
+    },
+    // CHT and CBT, move the cursor by this many tab stops
+    TabForward(usize),
+    TabBackward(usize),
+    // OSC 0 / OSC 2
+    SetTitle(String),
+    // OSC 7, the directory the shell is in
+    SetWorkingDirectory(PathBuf),
+    // XTPUSHTITLE / XTPOPTITLE, `ESC[22t` / `ESC[23t` for the window title
+    PushTitle,
+    PopTitle,
+    // Kitty keyboard protocol, `ESC[={flags};{mode}u`, `ESC[>{flags}u`, `ESC[<{count}u` and
+    // `ESC[?u`
+    SetKeyboardFlags {
+        flags: KeyboardFlags,
+        mode: SetKeyboardFlagsMode,
+    },
+    PushKeyboardFlags(KeyboardFlags),
+    PopKeyboardFlags(usize),
+    QueryKeyboardFlags,
+    // XTMODKEYS for modifyOtherKeys, `ESC[>4;{level}m`, and `ESC[>4n` to turn it off
+    SetModifyOtherKeys(u8),
+    // XTQMODKEYS, `ESC[?4m`
+    QueryModifyOtherKeys,
+    // Primary DA, `ESC[c`
+    RequestDeviceAttributes,
+    // Secondary DA, `ESC[>c`
+    RequestSecondaryDeviceAttributes,
+    // DSR 5, `ESC[5n`
+    RequestStatusReport,
+    // DSR 6, `ESC[6n`, answered with CPR
+    RequestCursorPosition,
+    // XTVERSION, `ESC[>q`
+    RequestTerminalVersion,
+    // DECRQM, `ESC[?{mode}$p` or `ESC[{mode}$p` for ANSI modes. The reply repeats `params`
+    RequestMode {
+        mode: Mode,
+        params: Vec<u8>,
+    },
+    // OSC 4 / OSC 10-12 with a `?` instead of a color. The reply has to end with the same
+    // terminator as the query
+    QueryColor {
+        slot: ColorSlot,
+        terminator: &'static [u8],
+    },
+    // OSC 4 / OSC 10-12
+    SetColor {
+        slot: ColorSlot,
+        rgb: Rgb,
+    },
+    // OSC 104 with indices / OSC 110-112
+    ResetColor(ColorSlot),
+    // OSC 104 without indices
+    ResetPalette,
+    // DECDHL / DECSWL / DECDWL
+    SetLineSize(LineSize),
+    // DECALN, `ESC#8`
+    ScreenAlignmentTest,
+    // RIS, `ESC c`
+    FullReset,
+    // DECSTR, `ESC[!p`
+    SoftReset,
+    // OSC 133
+    ShellMark(ShellMark),
+    // OSC 9;4, None when the program is done
+    SetProgress(Option<Progress>),
+    // OSC 9 / OSC 777;notify
+    Notify(Notification),
+    // OSC 52 with the decoded text for the clipboard. Queries with `?` are not answered
+    SetClipboard(Vec<u8>),
+    // BEL outside of a string
+    Bell,
     Invalid,
 }
 
@@ -124,9 +339,99 @@ fn split_params_into_semicolon_delimited_usize(params: &[u8]) -> Result<Vec<Opti
     params
 }
 
+/// One `;` separated SGR parameter. `4:3` has a value of 4 and sub-parameters of `[3]`
+struct SgrParam {
+    value: Option<usize>,
+    sub_params: Vec<Option<usize>>,
+}
+
+fn split_sgr_params(params: &[u8]) -> Result<Vec<SgrParam>, ()> {
+    params
+        .split(|b| *b == b';')
+        .map(|param| {
+            let mut parts = param.split(|b| *b == b':');
+            let value = parse_param_as::<usize>(parts.next().unwrap_or_default())?;
+            let sub_params = parts
+                .map(parse_param_as::<usize>)
+                .collect::<Result<_, _>>()?;
+            Ok(SgrParam { value, sub_params })
+        })
+        .collect()
+}
+
+fn push_color_osc(
+    slot: ColorSlot,
+    spec: &[u8],
+    terminator: &'static [u8],
+    output: &mut Vec<TerminalOutput>,
+) {
+    if spec == b"?" {
+        output.push(TerminalOutput::QueryColor { slot, terminator });
+        return;
+    }
+
+    match parse_color_spec(&String::from_utf8_lossy(spec)) {
+        Some(rgb) => output.push(TerminalOutput::SetColor { slot, rgb }),
+        None => debug!("Unsupported color {:?}", String::from_utf8_lossy(spec)),
+    }
+}
+
+/// Standard base64 with optional padding, as used by OSC 52
+fn decode_base64(data: &[u8]) -> Option<Vec<u8>> {
+    let data = data
+        .strip_suffix(b"==")
+        .or(data.strip_suffix(b"="))
+        .unwrap_or(data);
+    let mut out = Vec::with_capacity(data.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for b in data {
+        let value = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    // A single character left over cannot encode a byte
+    if bits >= 6 {
+        return None;
+    }
+    Some(out)
+}
+
+/// The path of a `file://host/path` URL as sent by OSC 7. The host is not checked, a directory
+/// from a remote shell is skipped later for not existing
+fn parse_file_url(url: &[u8]) -> Option<PathBuf> {
+    let rest = url.strip_prefix(b"file://")?;
+    let path = &rest[rest.iter().position(|b| *b == b'/')?..];
+
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(b) = bytes.next() {
+        if *b != b'%' {
+            decoded.push(*b);
+            continue;
+        }
+        let hex = [*bytes.next()?, *bytes.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+    Some(PathBuf::from(
+        String::from_utf8_lossy(&decoded).into_owned(),
+    ))
+}
+
 fn parse_param_as<T: std::str::FromStr>(param_bytes: &[u8]) -> Result<Option<T>, ()> {
-    let param_str =
-        std::str::from_utf8(param_bytes).expect("parameter should always be valid utf8");
+    let param_str = std::str::from_utf8(param_bytes).map_err(|_| ())?;
     if param_str.is_empty() {
         return Ok(None);
     }
@@ -134,6 +439,102 @@ fn parse_param_as<T: std::str::FromStr>(param_bytes: &[u8]) -> Result<Option<T>,
     Ok(Some(param))
 }
 
+// 8-bit C1 control codes, ECMA-48 5.3
+const C1_CSI: u8 = 0x9b;
+const C1_ST: u8 = 0x9c;
+const C1_OSC: u8 = 0x9d;
+
+/// Continuation bytes that follow `lead` in a UTF-8 sequence, 0 if it does not start one. Bytes
+/// past 0xf4 would start characters past U+10FFFF, and 0xc0 and 0xc1 only overlong ones
+fn utf8_continuation_len(lead: u8) -> u8 {
+    match lead {
+        0xc2..=0xdf => 1,
+        0xe0..=0xef => 2,
+        0xf0..=0xf4 => 3,
+        _ => 0,
+    }
+}
+
+/// Track progress through multi-byte UTF-8 sequences. Returns true if `b` is a continuation
+/// byte of a sequence in progress, in which case it is text and must not be interpreted as a C1
+/// control even though it shares the 0x80-0x9f range
+fn advance_utf8(remaining: &mut u8, b: u8) -> bool {
+    if *remaining > 0 && (0x80..=0xbf).contains(&b) {
+        *remaining -= 1;
+        return true;
+    }
+
+    *remaining = utf8_continuation_len(b);
+    false
+}
+
+/// What text bytes that are not valid UTF-8 turn into
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidUtf8 {
+    /// U+FFFD for each invalid sequence
+    Replace,
+    /// The Latin-1 character of each byte, for legacy programs. Bytes in the C1 range have no
+    /// character and are still replaced
+    Latin1,
+}
+
+impl std::str::FromStr for InvalidUtf8 {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "replace" => InvalidUtf8::Replace,
+            "latin1" => InvalidUtf8::Latin1,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
+impl InvalidUtf8 {
+    fn decode(self, bytes: &[u8], data_output: &mut Vec<u8>) {
+        if bytes.is_empty() {
+            return;
+        }
+        match self {
+            InvalidUtf8::Replace => data_output.extend(
+                char::REPLACEMENT_CHARACTER
+                    .encode_utf8(&mut [0; 4])
+                    .as_bytes(),
+            ),
+            InvalidUtf8::Latin1 => {
+                for b in bytes {
+                    let c = match b {
+                        0x80..=0x9f => char::REPLACEMENT_CHARACTER,
+                        _ => char::from(*b),
+                    };
+                    data_output.extend(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+            }
+        }
+    }
+}
+
+// Longest CSI or escape sequence we are willing to buffer before treating it as garbage
+const MAX_SEQUENCE_LEN: usize = 256;
+// OSC strings carry arbitrary payloads (titles, hyperlinks, clipboard data), so allow much more
+const MAX_OSC_LEN: usize = 64 * 1024;
+
+/// CAN and SUB abort any sequence in progress, ECMA-48 8.3.6 and 8.3.148
+fn is_sequence_cancel(b: u8) -> bool {
+    b == 0x18 || b == 0x1a
+}
+
+/// How control `b` is shown in sanitize mode, None for the ones that are still acted on
+fn sanitized_control(b: u8) -> Option<[u8; 2]> {
+    match b {
+        b'\r' | b'\n' | b'\t' | 0x08 => None,
+        0..=0x1f => Some([b'^', b + 0x40]),
+        0x7f => Some(*b"^?"),
+        _ => None,
+    }
+}
+
 fn push_data_if_non_empty(data: &mut Vec<u8>, output: &mut Vec<TerminalOutput>) {
     if !data.is_empty() {
         output.push(TerminalOutput::Data(std::mem::take(data)));
@@ -144,8 +545,16 @@ fn mode_from_params(params: &[u8]) -> Mode {
     match params {
         // https://vt100.net/docs/vt510-rm/DECCKM.html
         b"?1" => Mode::Decckm,
+        b"?6" => Mode::Decom,
+        b"?7" => Mode::Decawm,
+        // 47 and 1047 are older variants that differ in whether the cursor is saved and the
+        // screen cleared. Programs use 1049, the others get the same behavior
+        b"?47" | b"?1047" | b"?1049" => Mode::AlternateScreen,
         // Bracketed paste mode
         b"?2004" => Mode::BracketedPaste,
+        b"?2026" => Mode::SynchronizedOutput,
+        b"4" => Mode::Irm,
+        b"20" => Mode::Lnm,
         _ => Mode::Unknown(params.to_vec()),
     }
 }
@@ -369,16 +778,34 @@ mod ansi_parser_keys {
     pub const OSC_ESCAPE: &str = "osc_escape";
     pub const TYPE: &str = "type";
     pub const VAL: &str = "val";
+    pub const UTF8_REMAINING: &str = "utf8_remaining";
+    pub const UTF8_PENDING: &str = "utf8_pending";
+    pub const SEQUENCE: &str = "sequence";
 }
 
 pub struct AnsiParser {
     inner: AnsiParserInner,
+    // Continuation bytes left in the current UTF-8 sequence
+    utf8_remaining: u8,
+    // Text bytes of that sequence so far. Held back until it is complete, which may be in a later
+    // read, so that only valid UTF-8 reaches the buffer
+    utf8_pending: Vec<u8>,
+    // Raw bytes of the escape sequence in progress, excluding the leading ESC
+    sequence: Vec<u8>,
+    // Controls are shown instead of acted on, see `set_sanitize`
+    sanitize: bool,
+    invalid_utf8: InvalidUtf8,
 }
 
 impl AnsiParser {
     pub fn new() -> AnsiParser {
         AnsiParser {
             inner: AnsiParserInner::Empty,
+            utf8_remaining: 0,
+            utf8_pending: Vec::new(),
+            sequence: Vec::new(),
+            sanitize: false,
+            invalid_utf8: InvalidUtf8::Replace,
         }
     }
 
@@ -406,11 +833,49 @@ impl AnsiParser {
             }
             _ => Err(UnknownElem("type", typ))?,
         };
-        Ok(AnsiParser { inner })
+
+        // Not present in recordings made before C1 support
+        let utf8_remaining = match root.remove(ansi_parser_keys::UTF8_REMAINING) {
+            Some(v) => v
+                .into_num::<u8>()
+                .map_err(|_| WrongType(ansi_parser_keys::UTF8_REMAINING, "u8"))?,
+            None => 0,
+        };
+
+        let utf8_pending = match root.remove(ansi_parser_keys::UTF8_PENDING) {
+            Some(v) => v
+                .into_vec()
+                .map_err(|_| WrongType(ansi_parser_keys::UTF8_PENDING, "array"))?
+                .into_iter()
+                .map(|item| item.into_num::<u8>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| WrongType(ansi_parser_keys::UTF8_PENDING, "u8 array"))?,
+            None => Vec::new(),
+        };
+
+        let sequence = match root.remove(ansi_parser_keys::SEQUENCE) {
+            Some(v) => v
+                .into_vec()
+                .map_err(|_| WrongType(ansi_parser_keys::SEQUENCE, "array"))?
+                .into_iter()
+                .map(|item| item.into_num::<u8>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| WrongType(ansi_parser_keys::SEQUENCE, "u8 array"))?,
+            None => Vec::new(),
+        };
+
+        Ok(AnsiParser {
+            inner,
+            utf8_remaining,
+            utf8_pending,
+            sequence,
+            sanitize: false,
+            invalid_utf8: InvalidUtf8::Replace,
+        })
     }
 
     pub fn snapshot(&self) -> SnapshotItem {
-        match &self.inner {
+        let mut snapshot = match &self.inner {
             AnsiParserInner::Empty => SnapshotItem::Map(
                 [(
                     ansi_parser_keys::TYPE.to_string(),
@@ -449,6 +914,54 @@ impl AnsiParser {
                 ]
                 .into(),
             ),
+        };
+
+        if let SnapshotItem::Map(map) = &mut snapshot {
+            map.insert(
+                ansi_parser_keys::UTF8_REMAINING.to_string(),
+                self.utf8_remaining.into(),
+            );
+            map.insert(
+                ansi_parser_keys::UTF8_PENDING.to_string(),
+                self.utf8_pending.iter().collect(),
+            );
+            map.insert(
+                ansi_parser_keys::SEQUENCE.to_string(),
+                self.sequence.iter().collect(),
+            );
+        }
+
+        snapshot
+    }
+
+    /// Show control characters in caret notation (ESC as ^[) instead of acting on them, so that
+    /// e.g. a binary file written to the terminal cannot reconfigure it. Newlines, carriage
+    /// returns, tabs and backspaces still work. C1 controls are shown like invalid UTF-8
+    pub fn set_sanitize(&mut self, sanitize: bool) {
+        self.sanitize = sanitize;
+        if sanitize {
+            self.inner = AnsiParserInner::Empty;
+            self.sequence.clear();
+        }
+    }
+
+    pub fn sanitize(&self) -> bool {
+        self.sanitize
+    }
+
+    pub fn set_invalid_utf8(&mut self, invalid_utf8: InvalidUtf8) {
+        self.invalid_utf8 = invalid_utf8;
+    }
+
+    /// Emit the pending UTF-8 sequence, which is complete or was cut short
+    fn finish_utf8(&mut self, data_output: &mut Vec<u8>) {
+        // Complete sequences can still be overlong or encode surrogates
+        match std::str::from_utf8(&self.utf8_pending) {
+            Ok(_) => data_output.append(&mut self.utf8_pending),
+            Err(_) => {
+                self.invalid_utf8.decode(&self.utf8_pending, data_output);
+                self.utf8_pending.clear();
+            }
         }
     }
 
@@ -456,350 +969,1025 @@ impl AnsiParser {
         let mut output = Vec::new();
         let mut data_output = Vec::new();
         for b in incoming {
-            match &mut self.inner {
-                AnsiParserInner::Empty => {
-                    if *b == b'\x1b' {
-                        self.inner = AnsiParserInner::Escape;
-                        continue;
-                    }
+            self.push_byte(*b, &mut data_output, &mut output);
+        }
 
-                    if *b == b'\r' {
-                        push_data_if_non_empty(&mut data_output, &mut output);
-                        output.push(TerminalOutput::CarriageReturn);
-                        continue;
-                    }
+        if !data_output.is_empty() {
+            output.push(TerminalOutput::Data(data_output));
+        }
 
-                    if *b == b'\n' {
-                        push_data_if_non_empty(&mut data_output, &mut output);
-                        output.push(TerminalOutput::Newline);
-                        continue;
-                    }
+        output
+    }
 
-                    if *b == 0x08 {
-                        push_data_if_non_empty(&mut data_output, &mut output);
-                        output.push(TerminalOutput::Backspace);
-                        continue;
-                    }
+    /// Give up on the sequence in progress. The bytes consumed so far are emitted as literal text
+    /// so that garbage is visible instead of silently eating the text that follows it
+    fn abandon_sequence(&mut self, data_output: &mut Vec<u8>, output: &mut Vec<TerminalOutput>) {
+        warn!(
+            "Abandoning malformed escape sequence {:?}",
+            String::from_utf8_lossy(&self.sequence)
+        );
+        push_data_if_non_empty(data_output, output);
+        output.push(TerminalOutput::Invalid);
+        data_output.append(&mut self.sequence);
+        self.inner = AnsiParserInner::Empty;
+    }
 
-                    data_output.push(*b);
-                }
-                AnsiParserInner::Escape => {
-                    push_data_if_non_empty(&mut data_output, &mut output);
+    /// Interpret a completed OSC string. Window titles and colors are handled, everything else is
+    /// ignored. `terminator` is the BEL or ST that ended the string
+    fn finish_osc(&mut self, output: &mut Vec<TerminalOutput>, terminator: &'static [u8]) {
+        let payload = self.sequence.get(1..).unwrap_or_default();
+        let (command, arg) = match payload.iter().position(|b| *b == b';') {
+            Some(idx) => (&payload[..idx], &payload[idx + 1..]),
+            None => (payload, &[][..]),
+        };
 
-                    match b {
-                        b'[' => {
-                            self.inner = AnsiParserInner::Csi(CsiParser::new());
-                        }
-                        b']' => {
-                            self.inner = AnsiParserInner::Osc;
-                        }
-                        _ => {
-                            let b_utf8 = std::char::from_u32(*b as u32);
-                            warn!("Unhandled escape sequence {b_utf8:?} {b:x}");
-                            self.inner = AnsiParserInner::Empty;
+        match command {
+            b"0" | b"2" => {
+                output.push(TerminalOutput::SetTitle(
+                    String::from_utf8_lossy(arg).into_owned(),
+                ));
+            }
+            b"7" => match parse_file_url(arg) {
+                Some(path) => output.push(TerminalOutput::SetWorkingDirectory(path)),
+                None => debug!("Invalid OSC 7 URL {:?}", String::from_utf8_lossy(arg)),
+            },
+            b"4" => {
+                // Pairs of palette index and color
+                let mut args = arg.split(|b| *b == b';');
+                while let (Some(idx), Some(spec)) = (args.next(), args.next()) {
+                    let Ok(Some(idx)) = parse_param_as::<u8>(idx) else {
+                        debug!("Invalid OSC 4 index {:?}", String::from_utf8_lossy(idx));
+                        break;
+                    };
+                    push_color_osc(ColorSlot::Palette(idx), spec, terminator, output);
+                }
+            }
+            b"10" | b"11" | b"12" => {
+                // Further colors apply to the following slots, so "10;a;b" sets foreground and
+                // background
+                let slots = [
+                    ColorSlot::Foreground,
+                    ColorSlot::Background,
+                    ColorSlot::Cursor,
+                ];
+                let first = match command {
+                    b"10" => 0,
+                    b"11" => 1,
+                    _ => 2,
+                };
+                for (slot, spec) in slots[first..].iter().zip(arg.split(|b| *b == b';')) {
+                    push_color_osc(*slot, spec, terminator, output);
+                }
+            }
+            b"104" if arg.is_empty() => output.push(TerminalOutput::ResetPalette),
+            b"104" => {
+                for idx in arg.split(|b| *b == b';') {
+                    match parse_param_as::<u8>(idx) {
+                        Ok(Some(idx)) => {
+                            output.push(TerminalOutput::ResetColor(ColorSlot::Palette(idx)))
                         }
+                        _ => debug!("Invalid OSC 104 index {:?}", String::from_utf8_lossy(idx)),
                     }
                 }
-                AnsiParserInner::Osc => {
-                    if *b == 0x07 {
+            }
+            b"110" => output.push(TerminalOutput::ResetColor(ColorSlot::Foreground)),
+            b"111" => output.push(TerminalOutput::ResetColor(ColorSlot::Background)),
+            b"112" => output.push(TerminalOutput::ResetColor(ColorSlot::Cursor)),
+            b"9" if arg.starts_with(b"4;") || arg == b"4" => {
+                let mut args = arg.split(|b| *b == b';').skip(1);
+                let state = args.next().unwrap_or_default();
+                // A missing or invalid percentage counts as 0
+                let percent = args
+                    .next()
+                    .and_then(|v| parse_param_as::<u8>(v).ok().flatten())
+                    .unwrap_or(0)
+                    .min(100);
+                let progress = match state {
+                    b"" | b"0" => None,
+                    b"1" => Some(Progress::Normal(percent)),
+                    b"2" => Some(Progress::Error(percent)),
+                    b"3" => Some(Progress::Indeterminate),
+                    b"4" => Some(Progress::Paused(percent)),
+                    _ => {
+                        debug!("Unknown OSC 9;4 state {:?}", String::from_utf8_lossy(state));
                         self.inner = AnsiParserInner::Empty;
-                    } else if *b == b'\x1b' {
-                        self.inner = AnsiParserInner::OscEscape;
+                        return;
+                    }
+                };
+                output.push(TerminalOutput::SetProgress(progress));
+            }
+            b"9" => output.push(TerminalOutput::Notify(Notification {
+                title: None,
+                body: String::from_utf8_lossy(arg).into_owned(),
+            })),
+            b"777" => {
+                let mut args = arg.splitn(3, |b| *b == b';');
+                match (args.next(), args.next(), args.next()) {
+                    (Some(b"notify"), Some(title), body) => {
+                        output.push(TerminalOutput::Notify(Notification {
+                            title: Some(String::from_utf8_lossy(title).into_owned()),
+                            body: String::from_utf8_lossy(body.unwrap_or_default()).into_owned(),
+                        }))
                     }
+                    _ => debug!("Unhandled OSC 777 {:?}", String::from_utf8_lossy(arg)),
                 }
-                AnsiParserInner::OscEscape => {
-                    if *b == b'\\' {
-                        self.inner = AnsiParserInner::Empty;
-                    } else {
-                        self.inner = AnsiParserInner::Osc;
+            }
+            b"52" => {
+                // The selection, e.g. "c" or "p", is ignored since there is only one clipboard
+                let data = match arg.iter().position(|b| *b == b';') {
+                    Some(idx) => &arg[idx + 1..],
+                    None => &[][..],
+                };
+                if data == b"?" {
+                    debug!("Ignoring OSC 52 clipboard query");
+                } else {
+                    match decode_base64(data) {
+                        Some(text) => output.push(TerminalOutput::SetClipboard(text)),
+                        None => debug!("Invalid OSC 52 data {:?}", String::from_utf8_lossy(data)),
                     }
                 }
-                AnsiParserInner::Csi(parser) => {
-                    parser.push(*b);
-                    match parser.state {
-                        CsiParserState::Finished(b'A') => {
-                            let Ok(param) = parse_param_as::<i32>(&parser.params) else {
-                                warn!("Invalid cursor move up distance");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+            }
+            b"133" => {
+                // Options after the mark, like the exit status of D, are not used
+                let mark = match arg.split(|b| *b == b';').next().unwrap_or_default() {
+                    b"A" => ShellMark::PromptStart,
+                    b"B" => ShellMark::CommandStart,
+                    b"C" => ShellMark::OutputStart,
+                    b"D" => ShellMark::CommandFinished,
+                    _ => {
+                        debug!("Unhandled OSC 133 {:?}", String::from_utf8_lossy(arg));
+                        self.inner = AnsiParserInner::Empty;
+                        return;
+                    }
+                };
+                output.push(TerminalOutput::ShellMark(mark));
+            }
+            _ => {
+                debug!("Unhandled OSC {:?}", String::from_utf8_lossy(payload));
+            }
+        }
 
-                            output.push(TerminalOutput::SetCursorPosRel {
-                                x: None,
-                                y: Some(-param.unwrap_or(1)),
-                            });
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'B') => {
-                            let Ok(param) = parse_param_as::<i32>(&parser.params) else {
-                                warn!("Invalid cursor move down distance");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+        self.inner = AnsiParserInner::Empty;
+    }
 
-                            output.push(TerminalOutput::SetCursorPosRel {
-                                x: None,
-                                y: Some(param.unwrap_or(1)),
-                            });
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'C') => {
-                            let Ok(param) = parse_param_as::<i32>(&parser.params) else {
-                                warn!("Invalid cursor move right distance");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+    /// Handle a C0 control that appears in the middle of a sequence. Like other terminals we
+    /// execute it without disturbing the sequence
+    fn execute_embedded_c0(b: u8, output: &mut Vec<TerminalOutput>) {
+        match b {
+            b'\r' => output.push(TerminalOutput::CarriageReturn),
+            // VT and FF are taken as line feeds, like in other terminals
+            b'\n' | 0x0b | 0x0c => output.push(TerminalOutput::Newline),
+            b'\t' => output.push(TerminalOutput::TabForward(1)),
+            0x08 => output.push(TerminalOutput::Backspace),
+            0x07 => output.push(TerminalOutput::Bell),
+            _ => (),
+        }
+    }
 
-                            output.push(TerminalOutput::SetCursorPosRel {
-                                x: Some(param.unwrap_or(1)),
-                                y: None,
-                            });
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'D') => {
-                            let Ok(param) = parse_param_as::<i32>(&parser.params) else {
-                                warn!("Invalid cursor move left distance");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+    fn push_byte(&mut self, b: u8, data_output: &mut Vec<u8>, output: &mut Vec<TerminalOutput>) {
+        match &mut self.inner {
+            AnsiParserInner::Empty => {
+                if advance_utf8(&mut self.utf8_remaining, b) {
+                    self.utf8_pending.push(b);
+                    if self.utf8_remaining == 0 {
+                        self.finish_utf8(data_output);
+                    }
+                    return;
+                }
 
-                            output.push(TerminalOutput::SetCursorPosRel {
-                                x: Some(-param.unwrap_or(1)),
-                                y: None,
-                            });
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'H') => {
-                            let params =
-                                split_params_into_semicolon_delimited_usize(&parser.params);
+                // Anything but a continuation byte cuts the pending sequence short
+                self.finish_utf8(data_output);
+                if self.utf8_remaining > 0 {
+                    self.utf8_pending.push(b);
+                    return;
+                }
 
-                            let Ok(params) = params else {
-                                warn!("Invalid cursor set position sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+                if self.sanitize {
+                    if let Some(escaped) = sanitized_control(b) {
+                        data_output.extend(escaped);
+                        return;
+                    }
+                    if (0x80..=0x9f).contains(&b) {
+                        self.invalid_utf8.decode(&[b], data_output);
+                        return;
+                    }
+                }
 
-                            output.push(TerminalOutput::SetCursorPos {
-                                x: Some(extract_param(1, &params).unwrap_or(1)),
-                                y: Some(extract_param(0, &params).unwrap_or(1)),
-                            });
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'G') => {
-                            let Ok(param) = parse_param_as::<usize>(&parser.params) else {
-                                warn!("Invalid cursor set position sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+                if b == b'\x1b' {
+                    self.sequence.clear();
+                    self.inner = AnsiParserInner::Escape;
+                    return;
+                }
 
-                            let x_pos = param.unwrap_or(1);
+                // 8-bit introducers are recorded in their 7-bit form so that abandoned sequences
+                // emit printable text
+                if b == C1_CSI {
+                    push_data_if_non_empty(data_output, output);
+                    self.sequence = b"[".to_vec();
+                    self.inner = AnsiParserInner::Csi(CsiParser::new());
+                    return;
+                }
 
-                            output.push(TerminalOutput::SetCursorPos {
-                                x: Some(x_pos),
-                                y: None,
-                            });
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'J') => {
-                            let Ok(param) = parse_param_as::<usize>(&parser.params) else {
-                                warn!("Invalid clear command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+                if b == C1_OSC {
+                    push_data_if_non_empty(data_output, output);
+                    self.sequence = b"]".to_vec();
+                    self.inner = AnsiParserInner::Osc;
+                    return;
+                }
 
-                            let ret = match param.unwrap_or(0) {
-                                0 => TerminalOutput::ClearForwards,
-                                2 | 3 => TerminalOutput::ClearAll,
-                                _ => TerminalOutput::Invalid,
-                            };
-                            output.push(ret);
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'K') => {
-                            let Ok(param) = parse_param_as::<usize>(&parser.params) else {
-                                warn!("Invalid erase in line command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+                if b == C1_ST {
+                    // Stray string terminator, nothing to terminate
+                    return;
+                }
 
-                            // ECMA-48 8.3.39
-                            match param.unwrap_or(0) {
-                                0 => output.push(TerminalOutput::ClearLineForwards),
-                                v => {
-                                    warn!("Unsupported erase in line command ({v})");
-                                    output.push(TerminalOutput::Invalid);
-                                }
-                            }
+                if is_sequence_cancel(b) {
+                    // No sequence to cancel either
+                    return;
+                }
 
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'L') => {
-                            let Ok(param) = parse_param_as::<usize>(&parser.params) else {
-                                warn!("Invalid il command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+                if b == b'\r' {
+                    push_data_if_non_empty(data_output, output);
+                    output.push(TerminalOutput::CarriageReturn);
+                    return;
+                }
 
-                            output.push(TerminalOutput::InsertLines(param.unwrap_or(1)));
+                if b == b'\n' {
+                    push_data_if_non_empty(data_output, output);
+                    output.push(TerminalOutput::Newline);
+                    return;
+                }
 
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'P') => {
-                            let Ok(param) = parse_param_as::<usize>(&parser.params) else {
-                                warn!("Invalid del command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+                if b == 0x08 {
+                    push_data_if_non_empty(data_output, output);
+                    output.push(TerminalOutput::Backspace);
+                    return;
+                }
 
-                            output.push(TerminalOutput::Delete(param.unwrap_or(1)));
+                if b == 0x07 {
+                    push_data_if_non_empty(data_output, output);
+                    output.push(TerminalOutput::Bell);
+                    return;
+                }
 
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'm') => {
-                            let params =
-                                split_params_into_semicolon_delimited_usize(&parser.params);
+                if matches!(b, b'\t' | 0x0b | 0x0c) {
+                    push_data_if_non_empty(data_output, output);
+                    Self::execute_embedded_c0(b, output);
+                    return;
+                }
 
-                            let Ok(mut params) = params else {
-                                warn!("Invalid SGR sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+                // SO and SI switch character sets, which are not supported. The text is shown
+                // as it is
+                if b == 0x0e || b == 0x0f {
+                    return;
+                }
 
-                            if params.is_empty() {
-                                params.push(Some(0));
-                            }
+                // Stray continuation bytes and bytes that never appear in UTF-8
+                if b >= 0x80 {
+                    self.invalid_utf8.decode(&[b], data_output);
+                    return;
+                }
 
-                            if params.len() == 1 && params[0].is_none() {
-                                params[0] = Some(0);
-                            }
+                data_output.push(b);
+            }
+            AnsiParserInner::Escape => {
+                push_data_if_non_empty(data_output, output);
 
-                            let mut i = 0;
-                            while i < params.len() {
-                                let Some(param) = params[i] else {
-                                    i += 1;
-                                    continue;
-                                };
-
-                                if param == 38 && i + 2 < params.len() && params[i + 1] == Some(5) {
-                                    if let Some(color) = params[i + 2] {
-                                        output.push(TerminalOutput::Sgr(
-                                            SelectGraphicRendition::Foreground256(color as u8),
-                                        ));
-                                        i += 3;
-                                        continue;
-                                    }
-                                } else if param == 38
-                                    && i + 4 < params.len()
-                                    && params[i + 1] == Some(2)
-                                {
-                                    if let (Some(r), Some(g), Some(b)) =
-                                        (params[i + 2], params[i + 3], params[i + 4])
-                                    {
-                                        output.push(TerminalOutput::Sgr(
-                                            SelectGraphicRendition::ForegroundRgb(
-                                                r as u8, g as u8, b as u8,
-                                            ),
-                                        ));
-                                        i += 5;
-                                        continue;
-                                    }
-                                } else if param == 48
-                                    && i + 2 < params.len()
-                                    && params[i + 1] == Some(5)
-                                {
-                                    if let Some(color) = params[i + 2] {
-                                        output.push(TerminalOutput::Sgr(
-                                            SelectGraphicRendition::Background256(color as u8),
-                                        ));
-                                        i += 3;
-                                        continue;
-                                    }
-                                } else if param == 48
-                                    && i + 4 < params.len()
-                                    && params[i + 1] == Some(2)
-                                {
-                                    if let (Some(r), Some(g), Some(b)) =
-                                        (params[i + 2], params[i + 3], params[i + 4])
-                                    {
-                                        output.push(TerminalOutput::Sgr(
-                                            SelectGraphicRendition::BackgroundRgb(
-                                                r as u8, g as u8, b as u8,
-                                            ),
-                                        ));
-                                        i += 5;
-                                        continue;
-                                    }
-                                }
+                if is_sequence_cancel(b) {
+                    self.inner = AnsiParserInner::Empty;
+                    return;
+                }
 
-                                output.push(TerminalOutput::Sgr(
-                                    SelectGraphicRendition::from_usize(param),
-                                ));
-                                i += 1;
-                            }
+                if b == b'\x1b' {
+                    // Restart the escape sequence
+                    self.sequence.clear();
+                    return;
+                }
 
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'h') => {
-                            output.push(TerminalOutput::SetMode(mode_from_params(&parser.params)));
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'l') => {
-                            output
-                                .push(TerminalOutput::ResetMode(mode_from_params(&parser.params)));
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'@') => {
-                            let Ok(param) = parse_param_as::<usize>(&parser.params) else {
-                                warn!("Invalid ich command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
+                if b < 0x20 || b == 0x7f {
+                    Self::execute_embedded_c0(b, output);
+                    return;
+                }
 
-                            // ecma-48 8.3.64
-                            output.push(TerminalOutput::InsertSpaces(param.unwrap_or(1)));
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(esc) => {
-                            warn!(
-                                "Unhandled csi code: {:?} {esc:x} {}/{}",
-                                std::char::from_u32(esc as u32),
-                                esc >> 4,
-                                esc & 0xf,
-                            );
-                            output.push(TerminalOutput::Invalid);
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Invalid => {
-                            warn!("Invalid CSI sequence");
-                            output.push(TerminalOutput::Invalid);
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        _ => {}
+                if is_csi_intermediate(b) {
+                    if self.sequence.len() >= MAX_SEQUENCE_LEN {
+                        self.abandon_sequence(data_output, output);
+                        self.push_byte(b, data_output, output);
+                        return;
                     }
+                    self.sequence.push(b);
+                    return;
                 }
-            }
-        }
 
-        if !data_output.is_empty() {
-            output.push(TerminalOutput::Data(data_output));
-        }
+                if self.sequence == b"#" {
+                    let line_size = match b {
+                        b'3' => Some(LineSize::DoubleHeightTop),
+                        b'4' => Some(LineSize::DoubleHeightBottom),
+                        b'5' => Some(LineSize::SingleWidth),
+                        b'6' => Some(LineSize::DoubleWidth),
+                        _ => None,
+                    };
+
+                    if let Some(line_size) = line_size {
+                        output.push(TerminalOutput::SetLineSize(line_size));
+                        self.inner = AnsiParserInner::Empty;
+                        return;
+                    }
+                    if b == b'8' {
+                        output.push(TerminalOutput::ScreenAlignmentTest);
+                        self.inner = AnsiParserInner::Empty;
+                        return;
+                    }
+                }
 
-        output
+                if !self.sequence.is_empty() {
+                    // nF sequences such as character set designation (ESC ( B) are consumed
+                    // but otherwise ignored
+                    let sequence_utf8 = String::from_utf8_lossy(&self.sequence);
+                    warn!("Unhandled escape sequence {sequence_utf8:?} {b:x}");
+                    self.inner = AnsiParserInner::Empty;
+                    return;
+                }
+
+                match b {
+                    b'[' => {
+                        self.sequence.push(b);
+                        self.inner = AnsiParserInner::Csi(CsiParser::new());
+                    }
+                    b']' => {
+                        self.sequence.push(b);
+                        self.inner = AnsiParserInner::Osc;
+                    }
+                    b'\\' => {
+                        // Stray string terminator, nothing to terminate
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    b'c' => {
+                        output.push(TerminalOutput::FullReset);
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    b'H' => {
+                        output.push(TerminalOutput::SetTabStop);
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    b'7' | b'8' | b'D' | b'E' | b'M' => {
+                        output.push(match b {
+                            b'7' => TerminalOutput::SaveCursor,
+                            b'8' => TerminalOutput::RestoreCursor,
+                            b'D' => TerminalOutput::Index,
+                            b'E' => TerminalOutput::NextLine,
+                            _ => TerminalOutput::ReverseIndex,
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    _ => {
+                        let b_utf8 = std::char::from_u32(b as u32);
+                        warn!("Unhandled escape sequence {b_utf8:?} {b:x}");
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                }
+            }
+            AnsiParserInner::Osc => {
+                // OSC strings are often UTF-8 (e.g. window titles), so an 8-bit ST is only
+                // honored outside of a multi-byte sequence
+                let in_utf8 = advance_utf8(&mut self.utf8_remaining, b);
+
+                if !in_utf8 && b == 0x07 {
+                    push_data_if_non_empty(data_output, output);
+                    self.finish_osc(output, b"\x07");
+                } else if !in_utf8 && b == C1_ST {
+                    push_data_if_non_empty(data_output, output);
+                    self.finish_osc(output, b"\x1b\\");
+                } else if !in_utf8 && b == b'\x1b' {
+                    self.inner = AnsiParserInner::OscEscape;
+                } else if !in_utf8 && is_sequence_cancel(b) {
+                    self.inner = AnsiParserInner::Empty;
+                } else if b < 0x20 {
+                    // Like xterm, other C0 controls are dropped from the string and not executed.
+                    // A BEL is only ever the terminator here and never rings the bell
+                } else if self.sequence.len() >= MAX_OSC_LEN {
+                    self.abandon_sequence(data_output, output);
+                    self.push_byte(b, data_output, output);
+                } else {
+                    self.sequence.push(b);
+                }
+            }
+            AnsiParserInner::OscEscape => {
+                if b == b'\\' {
+                    push_data_if_non_empty(data_output, output);
+                    self.finish_osc(output, b"\x1b\\");
+                } else {
+                    // ESC that is not part of ST aborts the OSC string and starts a new sequence
+                    self.abandon_sequence(data_output, output);
+                    self.inner = AnsiParserInner::Escape;
+                    self.push_byte(b, data_output, output);
+                }
+            }
+            AnsiParserInner::Csi(parser) => {
+                if b == b'\x1b' || b == C1_CSI || b == C1_OSC {
+                    self.abandon_sequence(data_output, output);
+                    self.push_byte(b, data_output, output);
+                    return;
+                }
+
+                if is_sequence_cancel(b) {
+                    self.inner = AnsiParserInner::Empty;
+                    return;
+                }
+
+                if b < 0x20 || b == 0x7f {
+                    Self::execute_embedded_c0(b, output);
+                    return;
+                }
+
+                if self.sequence.len() >= MAX_SEQUENCE_LEN {
+                    self.abandon_sequence(data_output, output);
+                    self.push_byte(b, data_output, output);
+                    return;
+                }
+
+                parser.push(b);
+                if parser.state == CsiParserState::Invalid {
+                    // The offending byte is not part of the sequence, so it gets another chance
+                    // to be interpreted on its own
+                    self.abandon_sequence(data_output, output);
+                    self.push_byte(b, data_output, output);
+                    return;
+                }
+                self.sequence.push(b);
+
+                match parser.state {
+                    CsiParserState::Finished(b'A') => {
+                        let Ok(param) = parse_param_as::<i32>(&parser.params) else {
+                            warn!("Invalid cursor move up distance");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::SetCursorPosRel {
+                            x: None,
+                            y: Some(-param.unwrap_or(1).max(1)),
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'B') => {
+                        let Ok(param) = parse_param_as::<i32>(&parser.params) else {
+                            warn!("Invalid cursor move down distance");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::SetCursorPosRel {
+                            x: None,
+                            y: Some(param.unwrap_or(1).max(1)),
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'C') => {
+                        let Ok(param) = parse_param_as::<i32>(&parser.params) else {
+                            warn!("Invalid cursor move right distance");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::SetCursorPosRel {
+                            x: Some(param.unwrap_or(1).max(1)),
+                            y: None,
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'D') => {
+                        let Ok(param) = parse_param_as::<i32>(&parser.params) else {
+                            warn!("Invalid cursor move left distance");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::SetCursorPosRel {
+                            x: Some(-param.unwrap_or(1).max(1)),
+                            y: None,
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    // CUP and HVP
+                    CsiParserState::Finished(b'H' | b'f') => {
+                        let params = split_params_into_semicolon_delimited_usize(&parser.params);
+
+                        let Ok(params) = params else {
+                            warn!("Invalid cursor set position sequence");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::SetCursorPos {
+                            x: Some(extract_param(1, &params).unwrap_or(1)),
+                            y: Some(extract_param(0, &params).unwrap_or(1)),
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b @ (b'E' | b'F')) => {
+                        let Ok(param) = parse_param_as::<i32>(&parser.params) else {
+                            warn!("Invalid cursor next/previous line sequence");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        // ecma-48 8.3.20 and 8.3.13, CNL and CPL also return to the first column
+                        let lines = param.unwrap_or(1).max(1);
+                        output.push(TerminalOutput::SetCursorPosRel {
+                            x: None,
+                            y: Some(if b == b'E' { lines } else { -lines }),
+                        });
+                        output.push(TerminalOutput::CarriageReturn);
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'd') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid vpa sequence");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::SetCursorPos {
+                            x: None,
+                            y: Some(param.unwrap_or(1)),
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    // CHA and HPA
+                    CsiParserState::Finished(b'G' | b'`') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid cursor set position sequence");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        let x_pos = param.unwrap_or(1);
+
+                        output.push(TerminalOutput::SetCursorPos {
+                            x: Some(x_pos),
+                            y: None,
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'J') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid clear command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        let ret = match param.unwrap_or(0) {
+                            0 => TerminalOutput::ClearForwards,
+                            1 => TerminalOutput::ClearBackwards,
+                            2 => TerminalOutput::ClearAll,
+                            3 => TerminalOutput::ClearScrollback,
+                            _ => TerminalOutput::Invalid,
+                        };
+                        output.push(ret);
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'K') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid erase in line command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        // ECMA-48 8.3.39
+                        match param.unwrap_or(0) {
+                            0 => output.push(TerminalOutput::ClearLineForwards),
+                            1 => output.push(TerminalOutput::ClearLineBackwards),
+                            2 => output.push(TerminalOutput::ClearLine),
+                            v => {
+                                warn!("Unsupported erase in line command ({v})");
+                                output.push(TerminalOutput::Invalid);
+                            }
+                        }
+
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'L') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid il command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::InsertLines(param.unwrap_or(1)));
+
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'M') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid dl command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::DeleteLines(param.unwrap_or(1).max(1)));
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'X') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid ech command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::EraseCharacters(param.unwrap_or(1).max(1)));
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'r') => {
+                        let params = split_params_into_semicolon_delimited_usize(&parser.params);
+
+                        let Ok(params) = params else {
+                            warn!("Invalid set scroll region sequence");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::SetScrollRegion {
+                            top: extract_param(0, &params),
+                            bottom: extract_param(1, &params),
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'P') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid del command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::Delete(param.unwrap_or(1)));
+
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(final_byte @ (b'm' | b'n'))
+                        if matches!(parser.params.first(), Some(b'>' | b'?')) =>
+                    {
+                        // Only the modifyOtherKeys resource, 4, of the key modifier options
+                        let params = parser.params.as_slice();
+                        let ret = match (final_byte, params) {
+                            (b'm', b">4") | (b'n', b">4") => {
+                                Some(TerminalOutput::SetModifyOtherKeys(0))
+                            }
+                            (b'm', b"?4") => Some(TerminalOutput::QueryModifyOtherKeys),
+                            (b'm', _) => params
+                                .strip_prefix(b">4;")
+                                .and_then(|level| parse_param_as::<u8>(level).ok())
+                                .map(|level| {
+                                    TerminalOutput::SetModifyOtherKeys(level.unwrap_or(0).min(2))
+                                }),
+                            _ => None,
+                        };
+                        output.push(ret.unwrap_or_else(|| {
+                            warn!(
+                                "Unhandled key modifier option: {:?} {}",
+                                String::from_utf8_lossy(params),
+                                final_byte as char
+                            );
+                            TerminalOutput::Invalid
+                        }));
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'n') => {
+                        match parser.params.as_slice() {
+                            b"5" => output.push(TerminalOutput::RequestStatusReport),
+                            b"6" => output.push(TerminalOutput::RequestCursorPosition),
+                            params => {
+                                warn!(
+                                    "Unhandled device status report: {:?}",
+                                    String::from_utf8_lossy(params)
+                                );
+                                output.push(TerminalOutput::Invalid);
+                            }
+                        }
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'm') => {
+                        let Ok(sgr_params) = split_sgr_params(&parser.params) else {
+                            warn!("Invalid SGR sequence");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+                        let mut params: Vec<Option<usize>> =
+                            sgr_params.iter().map(|p| p.value).collect();
+
+                        if params.is_empty() {
+                            params.push(Some(0));
+                        }
+
+                        if params.len() == 1 && params[0].is_none() {
+                            params[0] = Some(0);
+                        }
+
+                        let mut i = 0;
+                        while i < params.len() {
+                            let Some(param) = params[i] else {
+                                i += 1;
+                                continue;
+                            };
+
+                            let sub_params = &sgr_params[i].sub_params;
+                            if !sub_params.is_empty() {
+                                output.push(TerminalOutput::Sgr(
+                                    SelectGraphicRendition::from_sub_params(param, sub_params),
+                                ));
+                                i += 1;
+                                continue;
+                            }
+
+                            if matches!(param, 38 | 48 | 58) {
+                                if let Some((color, len)) =
+                                    ExtendedColor::from_params(&params[i + 1..])
+                                {
+                                    output.push(TerminalOutput::Sgr(color.into_sgr(param)));
+                                    i += 1 + len;
+                                    continue;
+                                }
+                            }
+
+                            output.push(TerminalOutput::Sgr(SelectGraphicRendition::from_usize(
+                                param,
+                            )));
+                            i += 1;
+                        }
+
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'h') => {
+                        output.push(TerminalOutput::SetMode(mode_from_params(&parser.params)));
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'l') => {
+                        output.push(TerminalOutput::ResetMode(mode_from_params(&parser.params)));
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'c') => {
+                        match parser.params.as_slice() {
+                            b"" | b"0" => output.push(TerminalOutput::RequestDeviceAttributes),
+                            b">" | b">0" => {
+                                output.push(TerminalOutput::RequestSecondaryDeviceAttributes)
+                            }
+                            params => {
+                                warn!(
+                                    "Unhandled device attributes request: {:?}",
+                                    String::from_utf8_lossy(params)
+                                );
+                                output.push(TerminalOutput::Invalid);
+                            }
+                        }
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'q') => {
+                        match (parser.params.as_slice(), parser.intermediates.as_slice()) {
+                            (b">" | b">0", b"") => {
+                                output.push(TerminalOutput::RequestTerminalVersion)
+                            }
+                            (params, intermediates) => {
+                                warn!(
+                                    "Unhandled csi q: {:?} {:?}",
+                                    String::from_utf8_lossy(params),
+                                    String::from_utf8_lossy(intermediates)
+                                );
+                                output.push(TerminalOutput::Invalid);
+                            }
+                        }
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'u') => {
+                        let params = parser.params.as_slice();
+                        let ret = match params.split_first() {
+                            Some((b'?', b"")) => Some(TerminalOutput::QueryKeyboardFlags),
+                            Some((b'>', flags)) => parse_param_as::<u32>(flags).ok().map(|flags| {
+                                TerminalOutput::PushKeyboardFlags(KeyboardFlags::from_bits(
+                                    flags.unwrap_or(0),
+                                ))
+                            }),
+                            Some((b'<', count)) => parse_param_as::<usize>(count)
+                                .ok()
+                                .map(|count| TerminalOutput::PopKeyboardFlags(count.unwrap_or(1))),
+                            Some((b'=', args)) => {
+                                let mut args = args.split(|b| *b == b';');
+                                let flags = parse_param_as::<u32>(args.next().unwrap_or_default());
+                                let mode =
+                                    match parse_param_as::<u8>(args.next().unwrap_or_default()) {
+                                        Ok(None | Some(1)) => Some(SetKeyboardFlagsMode::Replace),
+                                        Ok(Some(2)) => Some(SetKeyboardFlagsMode::Add),
+                                        Ok(Some(3)) => Some(SetKeyboardFlagsMode::Remove),
+                                        _ => None,
+                                    };
+                                match (flags, mode) {
+                                    (Ok(flags), Some(mode)) => {
+                                        Some(TerminalOutput::SetKeyboardFlags {
+                                            flags: KeyboardFlags::from_bits(flags.unwrap_or(0)),
+                                            mode,
+                                        })
+                                    }
+                                    _ => None,
+                                }
+                            }
+                            _ => None,
+                        };
+                        output.push(ret.unwrap_or_else(|| {
+                            warn!("Unhandled csi u: {:?}", String::from_utf8_lossy(params));
+                            TerminalOutput::Invalid
+                        }));
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'p') => {
+                        match (parser.params.as_slice(), parser.intermediates.as_slice()) {
+                            (b"", b"!") => output.push(TerminalOutput::SoftReset),
+                            (params, b"$") => output.push(TerminalOutput::RequestMode {
+                                mode: mode_from_params(params),
+                                params: params.to_vec(),
+                            }),
+                            (params, intermediates) => {
+                                warn!(
+                                    "Unhandled csi p: {:?} {:?}",
+                                    String::from_utf8_lossy(params),
+                                    String::from_utf8_lossy(intermediates)
+                                );
+                                output.push(TerminalOutput::Invalid);
+                            }
+                        }
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b't') => {
+                        // Only the title stack of the window operations. The second parameter
+                        // picks the icon title (1), the window title (2) or both (0). There is
+                        // no separate icon title, so pushing only that does nothing
+                        let params = split_params_into_semicolon_delimited_usize(&parser.params);
+                        let ret = match params.as_deref() {
+                            Ok([Some(22)] | [Some(22), None | Some(0 | 2), ..]) => {
+                                Some(TerminalOutput::PushTitle)
+                            }
+                            Ok([Some(23)] | [Some(23), None | Some(0 | 2), ..]) => {
+                                Some(TerminalOutput::PopTitle)
+                            }
+                            Ok([Some(22 | 23), Some(1), ..]) => None,
+                            _ => {
+                                warn!(
+                                    "Unhandled window operation: {:?}",
+                                    String::from_utf8_lossy(&parser.params)
+                                );
+                                Some(TerminalOutput::Invalid)
+                            }
+                        };
+                        output.extend(ret);
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'@') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid ich command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        // ecma-48 8.3.64
+                        output.push(TerminalOutput::InsertSpaces(param.unwrap_or(1)));
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b @ (b'I' | b'Z')) => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid tab movement");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        let count = param.unwrap_or(1).max(1);
+                        output.push(match b {
+                            b'I' => TerminalOutput::TabForward(count),
+                            _ => TerminalOutput::TabBackward(count),
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'g') => {
+                        let ret = match parse_param_as::<usize>(&parser.params) {
+                            Ok(None | Some(0)) => TerminalOutput::ClearTabStop { all: false },
+                            Ok(Some(3)) => TerminalOutput::ClearTabStop { all: true },
+                            _ => {
+                                warn!("Invalid tab clear");
+                                TerminalOutput::Invalid
+                            }
+                        };
+                        output.push(ret);
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    // With five parameters `T` starts xterm's highlight mouse tracking
+                    CsiParserState::Finished(b'T') if parser.params.contains(&b';') => {
+                        warn!("Unsupported highlight mouse tracking");
+                        output.push(TerminalOutput::Invalid);
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b @ (b'S' | b'T')) => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid scroll command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        let num_lines = param.unwrap_or(1).max(1);
+                        output.push(match b {
+                            b'S' => TerminalOutput::ScrollUp(num_lines),
+                            _ => TerminalOutput::ScrollDown(num_lines),
+                        });
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(b'b') => {
+                        let Ok(param) = parse_param_as::<usize>(&parser.params) else {
+                            warn!("Invalid rep command");
+                            output.push(TerminalOutput::Invalid);
+                            self.inner = AnsiParserInner::Empty;
+                            return;
+                        };
+
+                        output.push(TerminalOutput::RepeatCharacter(param.unwrap_or(1).max(1)));
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    CsiParserState::Finished(esc) => {
+                        warn!(
+                            "Unhandled csi code: {:?} {esc:x} {}/{}",
+                            std::char::from_u32(esc as u32),
+                            esc >> 4,
+                            esc & 0xf,
+                        );
+                        output.push(TerminalOutput::Invalid);
+                        self.inner = AnsiParserInner::Empty;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if self.inner == AnsiParserInner::Empty {
+            self.sequence.clear();
+        }
     }
 }
 
+/// Removes escape sequences from output that arrives in chunks. Sequences split across chunks
+/// are held back until they are complete. Line feeds are kept, other controls such as carriage
+/// returns and backspaces are dropped
+pub struct AnsiStripper {
+    parser: AnsiParser,
+}
+
+impl AnsiStripper {
+    pub fn new() -> AnsiStripper {
+        AnsiStripper {
+            parser: AnsiParser::new(),
+        }
+    }
+
+    /// The text in `incoming`. This is raw bytes, since a chunk can end in the middle of a
+    /// UTF-8 character
+    pub fn push(&mut self, incoming: &[u8]) -> Vec<u8> {
+        let mut text = Vec::new();
+        for output in self.parser.push(incoming) {
+            match output {
+                TerminalOutput::Data(data) => text.extend(data),
+                TerminalOutput::Newline => text.push(b'\n'),
+                TerminalOutput::TabForward(count) => text.extend(std::iter::repeat_n(b'\t', count)),
+                _ => (),
+            }
+        }
+        text
+    }
+}
+
+impl Default for AnsiStripper {
+    fn default() -> Self {
+        AnsiStripper::new()
+    }
+}
+
+/// The text in `data` without escape sequences, see [`AnsiStripper`]. An unfinished sequence at
+/// the end is dropped
+pub fn strip_ansi(data: &[u8]) -> String {
+    String::from_utf8_lossy(&AnsiStripper::new().push(data)).into_owned()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -886,6 +2074,198 @@ mod test {
         assert!(matches!(parsed[0], TerminalOutput::ClearAll,));
     }
 
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(
+            strip_ansi(
+                b"\x1b[1;38;2;255;0;0mred\x1b[0m \x9b4m\xc3\xbc\x9bm\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x07\r\n\x1b(Bnext\x08\tline\x1b["
+            ),
+            "red ülink\nnext\tline"
+        );
+
+        // Sequences split across chunks
+        let mut stripper = AnsiStripper::new();
+        assert_eq!(stripper.push(b"a\x1b[3"), b"a");
+        assert_eq!(stripper.push(b"1mb\x1b]0;ti"), b"b");
+        assert_eq!(stripper.push(b"tle\x07c\xc3"), b"c");
+        assert_eq!(stripper.push(b"\xbc"), "ü".as_bytes());
+    }
+
+    #[test]
+    fn test_line_and_absolute_cursor_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[3E\x1b[F\x1b[0F\x1b[7G\x1b[`\x1b[4d");
+        let rel = |y| TerminalOutput::SetCursorPosRel {
+            x: None,
+            y: Some(y),
+        };
+        assert_eq!(
+            parsed,
+            &[
+                rel(3),
+                TerminalOutput::CarriageReturn,
+                rel(-1),
+                TerminalOutput::CarriageReturn,
+                rel(-1),
+                TerminalOutput::CarriageReturn,
+                TerminalOutput::SetCursorPos {
+                    x: Some(7),
+                    y: None
+                },
+                TerminalOutput::SetCursorPos {
+                    x: Some(1),
+                    y: None
+                },
+                TerminalOutput::SetCursorPos {
+                    x: None,
+                    y: Some(4)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tab_stop_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed =
+            output_buffer.push(b"\x1bH\x1b[g\x1b[0g\x1b[3g\x1b[I\x1b[3I\x1b[Z\x1b[2Z\x1b[5g");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetTabStop,
+                TerminalOutput::ClearTabStop { all: false },
+                TerminalOutput::ClearTabStop { all: false },
+                TerminalOutput::ClearTabStop { all: true },
+                TerminalOutput::TabForward(1),
+                TerminalOutput::TabForward(3),
+                TerminalOutput::TabBackward(1),
+                TerminalOutput::TabBackward(2),
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repeat_character_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"a\x1b[5b\x1b[b\x1b[0b\x1b[1;2b");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data(b"a".to_vec()),
+                TerminalOutput::RepeatCharacter(5),
+                TerminalOutput::RepeatCharacter(1),
+                TerminalOutput::RepeatCharacter(1),
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scroll_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[S\x1b[3S\x1b[0T\x1b[2T\x1b[1;2;3;4;5T\x1b[?5S");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::ScrollUp(1),
+                TerminalOutput::ScrollUp(3),
+                TerminalOutput::ScrollDown(1),
+                TerminalOutput::ScrollDown(2),
+                TerminalOutput::Invalid,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mode_requests() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[?2004$p\x1b[20$p\x1b[?1000$p");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::RequestMode {
+                    mode: Mode::BracketedPaste,
+                    params: b"?2004".to_vec(),
+                },
+                TerminalOutput::RequestMode {
+                    mode: Mode::Lnm,
+                    params: b"20".to_vec(),
+                },
+                TerminalOutput::RequestMode {
+                    mode: Mode::Unknown(b"?1000".to_vec()),
+                    params: b"?1000".to_vec(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_identification_requests() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed =
+            output_buffer.push(b"\x1b[>c\x1b[>0c\x1b[>q\x1b[c\x1b[1c\x1b[2 q\x1b[5n\x1b[6n\x1b[7n");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::RequestSecondaryDeviceAttributes,
+                TerminalOutput::RequestSecondaryDeviceAttributes,
+                TerminalOutput::RequestTerminalVersion,
+                TerminalOutput::RequestDeviceAttributes,
+                TerminalOutput::Invalid,
+                TerminalOutput::Invalid,
+                TerminalOutput::RequestStatusReport,
+                TerminalOutput::RequestCursorPosition,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clear_scrollback() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[3J");
+        assert_eq!(parsed, &[TerminalOutput::ClearScrollback]);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1bc\x1b[!p\x1b[p");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::FullReset,
+                TerminalOutput::SoftReset,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scroll_region() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[2;10r\x1b[r\x1b[;5r");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetScrollRegion {
+                    top: Some(2),
+                    bottom: Some(10)
+                },
+                TerminalOutput::SetScrollRegion {
+                    top: None,
+                    bottom: None
+                },
+                TerminalOutput::SetScrollRegion {
+                    top: None,
+                    bottom: Some(5)
+                },
+            ]
+        );
+    }
+
     #[test]
     fn test_invalid_clear() {
         let mut output_buffer = AnsiParser::new();
@@ -1016,6 +2396,192 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_line_size_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let output = output_buffer.push(b"\x1b#6a\x1b#3\x1b#4\x1b#5\x1b#8");
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::SetLineSize(LineSize::DoubleWidth),
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::SetLineSize(LineSize::DoubleHeightTop),
+                TerminalOutput::SetLineSize(LineSize::DoubleHeightBottom),
+                TerminalOutput::SetLineSize(LineSize::SingleWidth),
+                TerminalOutput::ScreenAlignmentTest,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vt100_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let output = output_buffer.push(
+            b"a\tb\x0b\x0c\x0e\x0f\x1b7\x1b8\x1bD\x1bE\x1bM\x1b[2;3f\x1b[0A\x1b[1J\x1b[1K\x1b[2K\x1b[2M\x1b[X",
+        );
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::TabForward(1),
+                TerminalOutput::Data(b"b".into()),
+                TerminalOutput::Newline,
+                TerminalOutput::Newline,
+                TerminalOutput::SaveCursor,
+                TerminalOutput::RestoreCursor,
+                TerminalOutput::Index,
+                TerminalOutput::NextLine,
+                TerminalOutput::ReverseIndex,
+                TerminalOutput::SetCursorPos {
+                    x: Some(3),
+                    y: Some(2)
+                },
+                TerminalOutput::SetCursorPosRel {
+                    x: None,
+                    y: Some(-1)
+                },
+                TerminalOutput::ClearBackwards,
+                TerminalOutput::ClearLineBackwards,
+                TerminalOutput::ClearLine,
+                TerminalOutput::DeleteLines(2),
+                TerminalOutput::EraseCharacters(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_background_color_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let output = output_buffer.push(b"\x1b[41ma\x1b[47;49m\x1b[48;5;17m");
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::Sgr(SelectGraphicRendition::BackgroundRed),
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::Sgr(SelectGraphicRendition::BackgroundWhite),
+                TerminalOutput::Sgr(SelectGraphicRendition::DefaultBackground),
+                TerminalOutput::Sgr(SelectGraphicRendition::Background256(17)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_underline_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let output = output_buffer.push(b"\x1b[4m\x1b[4:3;1m\x1b[4:5m\x1b[4:0m\x1b[21;24m");
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::Sgr(SelectGraphicRendition::Underline(UnderlineStyle::Single)),
+                TerminalOutput::Sgr(SelectGraphicRendition::Underline(UnderlineStyle::Curly)),
+                TerminalOutput::Sgr(SelectGraphicRendition::Bold),
+                TerminalOutput::Sgr(SelectGraphicRendition::Underline(UnderlineStyle::Dashed)),
+                TerminalOutput::Sgr(SelectGraphicRendition::Underline(UnderlineStyle::None)),
+                TerminalOutput::Sgr(SelectGraphicRendition::Underline(UnderlineStyle::Double)),
+                TerminalOutput::Sgr(SelectGraphicRendition::Underline(UnderlineStyle::None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_underline_color_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let output = output_buffer.push(
+            b"\x1b[58;5;9;1m\x1b[58;2;1;2;3m\x1b[58:2::4:5:6m\x1b[58:5:7;59m\x1b[38:2:1:2:3m",
+        );
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::Sgr(SelectGraphicRendition::UnderlineColor256(9)),
+                TerminalOutput::Sgr(SelectGraphicRendition::Bold),
+                TerminalOutput::Sgr(SelectGraphicRendition::UnderlineColorRgb(1, 2, 3)),
+                TerminalOutput::Sgr(SelectGraphicRendition::UnderlineColorRgb(4, 5, 6)),
+                TerminalOutput::Sgr(SelectGraphicRendition::UnderlineColor256(7)),
+                TerminalOutput::Sgr(SelectGraphicRendition::DefaultUnderlineColor),
+                TerminalOutput::Sgr(SelectGraphicRendition::ForegroundRgb(1, 2, 3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_color_osc_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let output = output_buffer.push(b"\x1b]4;3;?;5;#ff0000\x07\x1b]10;?;rgb:1/2/3\x1b\\");
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::QueryColor {
+                    slot: ColorSlot::Palette(3),
+                    terminator: b"\x07",
+                },
+                TerminalOutput::SetColor {
+                    slot: ColorSlot::Palette(5),
+                    rgb: (255, 0, 0),
+                },
+                TerminalOutput::QueryColor {
+                    slot: ColorSlot::Foreground,
+                    terminator: b"\x1b\\",
+                },
+                TerminalOutput::SetColor {
+                    slot: ColorSlot::Background,
+                    rgb: (17, 34, 51),
+                },
+            ]
+        );
+
+        let output = output_buffer.push(b"\x1b]12;?;?\x07\x1b]4;x;?\x07\x1b]11;blue\x07");
+        assert_eq!(
+            output,
+            &[TerminalOutput::QueryColor {
+                slot: ColorSlot::Cursor,
+                terminator: b"\x07",
+            }]
+        );
+
+        // An index that is not UTF-8 is dropped like any other invalid one
+        let output = output_buffer.push(b"\x1b]4;\xc3;?\x1b\\\x1b]4;1;?\x07");
+        assert_eq!(
+            output,
+            &[TerminalOutput::QueryColor {
+                slot: ColorSlot::Palette(1),
+                terminator: b"\x07",
+            }]
+        );
+    }
+
+    #[test]
+    fn test_color_reset_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let output = output_buffer.push(b"\x1b]104\x07\x1b]104;1;200\x07\x1b]110\x07\x1b]112\x07");
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::ResetPalette,
+                TerminalOutput::ResetColor(ColorSlot::Palette(1)),
+                TerminalOutput::ResetColor(ColorSlot::Palette(200)),
+                TerminalOutput::ResetColor(ColorSlot::Foreground),
+                TerminalOutput::ResetColor(ColorSlot::Cursor),
+            ]
+        );
+
+        // Indexes that are not UTF-8 are skipped, the valid ones still reset
+        let output = output_buffer.push(b"\x1b]104;\xff;2\x07");
+        assert_eq!(output, &[TerminalOutput::ResetColor(ColorSlot::Palette(2))]);
+    }
+
+    #[test]
+    fn test_default_attribute_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let output = output_buffer.push(b"\x1b[22;39m");
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::Sgr(SelectGraphicRendition::NormalIntensity),
+                TerminalOutput::Sgr(SelectGraphicRendition::DefaultForeground),
+            ]
+        );
+    }
+
     #[test]
     fn test_mode_parsing() {
         let mut output_buffer = AnsiParser::new();
@@ -1040,6 +2606,24 @@ mod test {
         let output = output_buffer.push(b"\x1b[?1h");
         assert_eq!(output.len(), 1);
         assert_eq!(output[0], TerminalOutput::SetMode(Mode::Decckm));
+
+        let output = output_buffer.push(b"\x1b[4h\x1b[4l");
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::SetMode(Mode::Irm),
+                TerminalOutput::ResetMode(Mode::Irm)
+            ]
+        );
+
+        let output = output_buffer.push(b"\x1b[?6h\x1b[?6l");
+        assert_eq!(
+            output,
+            &[
+                TerminalOutput::SetMode(Mode::Decom),
+                TerminalOutput::ResetMode(Mode::Decom)
+            ]
+        );
     }
 
     #[test]
@@ -1212,10 +2796,530 @@ mod test {
                 intermediates: vec![5, 6, 7],
             }),
         ] {
-            let parser = AnsiParser { inner };
+            let parser = AnsiParser {
+                inner,
+                utf8_remaining: 2,
+                utf8_pending: b"\xe2".to_vec(),
+                sequence: b"[12;".to_vec(),
+                sanitize: false,
+                invalid_utf8: InvalidUtf8::Replace,
+            };
             let loaded =
                 AnsiParser::from_snapshot(parser.snapshot()).expect("failed to load snapshot");
             assert_eq!(loaded.inner, parser.inner);
+            assert_eq!(loaded.utf8_remaining, parser.utf8_remaining);
+            assert_eq!(loaded.utf8_pending, parser.utf8_pending);
+            assert_eq!(loaded.sequence, parser.sequence);
+        }
+    }
+
+    #[test]
+    fn test_ansi_parser_snapshot_without_utf8_state() {
+        let snapshot = SnapshotItem::Map(
+            [(
+                ansi_parser_keys::TYPE.to_string(),
+                ansi_parser_keys::EMPTY.into(),
+            )]
+            .into(),
+        );
+        let loaded = AnsiParser::from_snapshot(snapshot).expect("failed to load snapshot");
+        assert_eq!(loaded.utf8_remaining, 0);
+    }
+
+    #[test]
+    fn test_8bit_csi() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"a\x9b32;15Hb");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::SetCursorPos {
+                    x: Some(15),
+                    y: Some(32)
+                },
+                TerminalOutput::Data(b"b".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_title() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b]0;vim main.rs\x07\x1b]2;\x1b\\\x1b]1;icon\x07");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetTitle("vim main.rs".into()),
+                TerminalOutput::SetTitle("".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_title_control_characters() {
+        let mut output_buffer = AnsiParser::new();
+        // 0x9c is ST on its own but a continuation byte in "ќ", and the line break is dropped
+        let parsed =
+            output_buffer.push("\x1b]2;a;b ü € ќ\r\n[x]\x07\x07\x1b]0;\x07\x07".as_bytes());
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetTitle("a;b ü € ќ[x]".into()),
+                TerminalOutput::Bell,
+                TerminalOutput::SetTitle("".into()),
+                TerminalOutput::Bell,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_title_stack_parsing() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed =
+            output_buffer.push(b"\x1b[22;0t\x1b[22;1t\x1b[22;2;1t\x1b[23t\x1b[23;1t\x1b[8;24;80t");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::PushTitle,
+                TerminalOutput::PushTitle,
+                TerminalOutput::PopTitle,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_shell_marks() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer
+            .push(b"\x1b]133;A\x07$ \x1b]133;B\x07\x1b]133;C\x1b\\\x1b]133;D;1\x07\x1b]133;Z\x07");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::ShellMark(ShellMark::PromptStart),
+                TerminalOutput::Data(b"$ ".to_vec()),
+                TerminalOutput::ShellMark(ShellMark::CommandStart),
+                TerminalOutput::ShellMark(ShellMark::OutputStart),
+                TerminalOutput::ShellMark(ShellMark::CommandFinished),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_progress() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(
+            b"\x1b]9;4;1;42\x07\x1b]9;4;2\x1b\\\x1b]9;4;3;0\x07\x1b]9;4;4;250\x07\x1b]9;4;7;1\x07\x1b]9;4;0\x07",
+        );
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetProgress(Some(Progress::Normal(42))),
+                TerminalOutput::SetProgress(Some(Progress::Error(0))),
+                TerminalOutput::SetProgress(Some(Progress::Indeterminate)),
+                TerminalOutput::SetProgress(Some(Progress::Paused(100))),
+                TerminalOutput::SetProgress(None),
+            ]
+        );
+
+        // A percentage that is not UTF-8 is invalid like any other
+        let parsed = output_buffer.push(b"\x1b]9;4;1;\xff\x07");
+        assert_eq!(
+            parsed,
+            &[TerminalOutput::SetProgress(Some(Progress::Normal(0)))]
+        );
+    }
+
+    #[test]
+    fn test_osc_notifications() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(
+            b"\x1b]9;build done\x07\x1b]777;notify;make;exit 0\x1b\\\x1b]777;notify;title\x07\x1b]777;other\x07ok\x07",
+        );
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Notify(Notification {
+                    title: None,
+                    body: "build done".into(),
+                }),
+                TerminalOutput::Notify(Notification {
+                    title: Some("make".into()),
+                    body: "exit 0".into(),
+                }),
+                TerminalOutput::Notify(Notification {
+                    title: Some("title".into()),
+                    body: "".into(),
+                }),
+                TerminalOutput::Data(b"ok".to_vec()),
+                TerminalOutput::Bell,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_clipboard() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(
+            b"\x1b]52;c;aGVsbG8=\x07\x1b]52;;aGk\x1b\\\x1b]52;c;?\x07\x1b]52;c;a*\x07\x1b]52;c;\x07",
+        );
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetClipboard(b"hello".to_vec()),
+                TerminalOutput::SetClipboard(b"hi".to_vec()),
+                TerminalOutput::SetClipboard(Vec::new()),
+            ]
+        );
+
+        assert_eq!(decode_base64(b"dGVybWll"), Some(b"termie".to_vec()));
+        assert_eq!(decode_base64(b"dGVybWllIQ"), Some(b"termie!".to_vec()));
+        assert_eq!(decode_base64(b"a"), None);
+    }
+
+    #[test]
+    fn test_keyboard_flags() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed =
+            output_buffer.push(b"\x1b[>1u\x1b[=3;2u\x1b[=8u\x1b[<u\x1b[<2u\x1b[?u\x1b[=1;4u\x1b[u");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::PushKeyboardFlags(KeyboardFlags::from_bits(1)),
+                TerminalOutput::SetKeyboardFlags {
+                    flags: KeyboardFlags::from_bits(3),
+                    mode: SetKeyboardFlagsMode::Add,
+                },
+                TerminalOutput::SetKeyboardFlags {
+                    flags: KeyboardFlags::from_bits(8),
+                    mode: SetKeyboardFlagsMode::Replace,
+                },
+                TerminalOutput::PopKeyboardFlags(1),
+                TerminalOutput::PopKeyboardFlags(2),
+                TerminalOutput::QueryKeyboardFlags,
+                TerminalOutput::Invalid,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_modify_other_keys() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer
+            .push(b"\x1b[>4;2m\x1b[>4;1m\x1b[>4m\x1b[>4n\x1b[?4m\x1b[>1;2m\x1b[>4;1;2m");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetModifyOtherKeys(2),
+                TerminalOutput::SetModifyOtherKeys(1),
+                TerminalOutput::SetModifyOtherKeys(0),
+                TerminalOutput::SetModifyOtherKeys(0),
+                TerminalOutput::QueryModifyOtherKeys,
+                TerminalOutput::Invalid,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_working_directory() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(
+            b"\x1b]7;file://host/home/me/my%20dir\x07\x1b]7;file:///tmp\x1b\\\x1b]7;/tmp\x07\x1b]7;file://host/a%2\x07",
+        );
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetWorkingDirectory("/home/me/my dir".into()),
+                TerminalOutput::SetWorkingDirectory("/tmp".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let mut output_buffer = AnsiParser::new();
+        output_buffer.set_sanitize(true);
+        let parsed = output_buffer.push(b"\x1b[31ma\x07\x9b2J\x7f\tb\r\n");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data("^[[31ma^G\u{fffd}2J^?".into()),
+                TerminalOutput::TabForward(1),
+                TerminalOutput::Data("b".into()),
+                TerminalOutput::CarriageReturn,
+                TerminalOutput::Newline,
+            ]
+        );
+
+        // Turning it on drops a sequence in progress
+        let mut output_buffer = AnsiParser::new();
+        output_buffer.push(b"\x1b[3");
+        output_buffer.set_sanitize(true);
+        assert_eq!(
+            output_buffer.push(b"1m"),
+            &[TerminalOutput::Data(b"1m".to_vec())]
+        );
+        output_buffer.set_sanitize(false);
+        assert_eq!(output_buffer.push(b"\x1b[2J"), &[TerminalOutput::ClearAll]);
+    }
+
+    #[test]
+    fn test_osc_terminators() {
+        for input in [
+            &b"\x1b]0;title\x07after"[..],
+            b"\x1b]0;title\x1b\\after",
+            b"\x1b]0;title\x9cafter",
+            b"\x9d0;title\x07after",
+            b"\x9d0;title\x1b\\after",
+            b"\x9d0;title\x9cafter",
+        ] {
+            let mut output_buffer = AnsiParser::new();
+            let parsed = output_buffer.push(input);
+            assert_eq!(
+                parsed,
+                &[
+                    TerminalOutput::SetTitle("title".into()),
+                    TerminalOutput::Data(b"after".into())
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn test_stray_string_terminator() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"a\x1b\\b\x9cc");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::Data(b"bc".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_c1_bytes_inside_utf8() {
+        // U+00DB is encoded as c3 9b, which must not start a CSI sequence
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push("Û[2J".as_bytes());
+        assert_eq!(parsed, &[TerminalOutput::Data("Û[2J".into())]);
+
+        // U+045C is encoded as d1 9c, which must not terminate an OSC string
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push("\x1b]0;ќ\x07after".as_bytes());
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetTitle("ќ".into()),
+                TerminalOutput::Data(b"after".into())
+            ]
+        );
+
+        // UTF-8 state persists across reads, the start of a character waits for the rest
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\xc3");
+        assert_eq!(parsed, &[]);
+        let parsed = output_buffer.push(b"\x9b");
+        assert_eq!(parsed, &[TerminalOutput::Data("Û".into())]);
+
+        // Nothing past U+10FFFF is UTF-8, so f5 does not start a character and 9b is CSI
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\xf5\x9b2J");
+        assert_eq!(parsed.last(), Some(&TerminalOutput::ClearAll));
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let mut output_buffer = AnsiParser::new();
+        // A character split over three reads
+        assert_eq!(
+            output_buffer.push("a€".as_bytes()[..2].as_ref()),
+            &[TerminalOutput::Data(b"a".into())]
+        );
+        assert_eq!(output_buffer.push(b"\x82"), &[]);
+        assert_eq!(
+            output_buffer.push(b"\xacb"),
+            &[TerminalOutput::Data("€b".into())]
+        );
+
+        // Stray continuation bytes, bytes that are never UTF-8, a sequence cut short by a control
+        // and an overlong encoding
+        let parsed = output_buffer.push(b"\xbfa\xff\xe2\x82\r\xe0\x80\x80");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data("\u{fffd}a\u{fffd}\u{fffd}".into()),
+                TerminalOutput::CarriageReturn,
+                TerminalOutput::Data("\u{fffd}".into()),
+            ]
+        );
+
+        // The last byte of a read might still be completed by the next one
+        assert_eq!(output_buffer.push(b"\xe2"), &[]);
+        assert_eq!(
+            output_buffer.push(b"x"),
+            &[TerminalOutput::Data("\u{fffd}x".into())]
+        );
+
+        output_buffer.set_invalid_utf8(InvalidUtf8::Latin1);
+        let parsed = output_buffer.push(b"caf\xe9 \xe9t\xe9\x85 \xc3\xa9");
+        assert_eq!(parsed, &[TerminalOutput::Data("café été\u{fffd} é".into())]);
+    }
+
+    #[test]
+    fn test_sequences_split_across_pushes() {
+        let input = b"a\x1b[1;31mb\x1b]0;title\x1b\\c\x9b2Jd\x1b(Be\xc3\x9bf";
+
+        // Data chunking depends on where pushes are split, so join adjacent chunks before
+        // comparing
+        fn join_data(output: Vec<TerminalOutput>) -> Vec<TerminalOutput> {
+            let mut joined: Vec<TerminalOutput> = Vec::new();
+            for item in output {
+                match (joined.last_mut(), item) {
+                    (Some(TerminalOutput::Data(prev)), TerminalOutput::Data(next)) => {
+                        prev.extend(next);
+                    }
+                    (_, item) => joined.push(item),
+                }
+            }
+            joined
+        }
+
+        let mut whole_parser = AnsiParser::new();
+        let expected = join_data(whole_parser.push(input));
+
+        let mut split_parser = AnsiParser::new();
+        let mut split_output = Vec::new();
+        for b in input {
+            split_output.extend(split_parser.push(&[*b]));
         }
+
+        assert_eq!(join_data(split_output), expected);
+        assert_eq!(
+            expected,
+            &[
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::Sgr(SelectGraphicRendition::Bold),
+                TerminalOutput::Sgr(SelectGraphicRendition::ForegroundRed),
+                TerminalOutput::Data(b"b".into()),
+                TerminalOutput::SetTitle("title".into()),
+                TerminalOutput::Data(b"c".into()),
+                TerminalOutput::ClearAll,
+                TerminalOutput::Data("deÛf".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_byte_abandons_csi() {
+        // A param after an intermediate is not valid, the consumed bytes are shown as text and
+        // the rest of the input is not swallowed
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[1$2hello");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Invalid,
+                TerminalOutput::Data(b"[1$2hello".into()),
+            ]
+        );
+
+        // Non-ascii bytes cannot appear in a CSI sequence
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push("\x1b[1Ûok".as_bytes());
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Invalid,
+                TerminalOutput::Data("[1Ûok".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_escape_restarts_sequence() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[12\x1b[2Jx");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Invalid,
+                TerminalOutput::Data(b"[12".into()),
+                TerminalOutput::ClearAll,
+                TerminalOutput::Data(b"x".into()),
+            ]
+        );
+
+        // ESC in an OSC string that is not part of ST
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b]0;ti\x1b[Hx");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Invalid,
+                TerminalOutput::Data(b"]0;ti".into()),
+                TerminalOutput::SetCursorPos {
+                    x: Some(1),
+                    y: Some(1)
+                },
+                TerminalOutput::Data(b"x".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cancel_sequence() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[12\x18a\x1b]0;title\x1ab\x18c");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::Data(b"bc".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_c0_inside_csi() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[1\r0Ax");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::CarriageReturn,
+                TerminalOutput::SetCursorPosRel {
+                    x: None,
+                    y: Some(-10)
+                },
+                TerminalOutput::Data(b"x".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlong_sequences_are_abandoned() {
+        let mut input = b"\x1b[".to_vec();
+        input.extend(std::iter::repeat_n(b'1', MAX_SEQUENCE_LEN + 10));
+        input.push(b'm');
+
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(&input);
+        assert_eq!(parsed[0], TerminalOutput::Invalid);
+        assert_eq!(parsed[1], TerminalOutput::Data(input[1..].to_vec()));
+        assert_eq!(parsed.len(), 2);
+
+        let mut input = b"\x1b]".to_vec();
+        input.extend(std::iter::repeat_n(b'a', MAX_OSC_LEN + 10));
+
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(&input);
+        assert_eq!(parsed[0], TerminalOutput::Invalid);
+        assert_eq!(parsed[1], TerminalOutput::Data(input[1..].to_vec()));
+        assert!(output_buffer.inner == AnsiParserInner::Empty);
     }
 }
@@ -0,0 +1,188 @@
+//! Export of styled rows back into a byte stream with SGR sequences, so that captured output can
+//! be replayed with `cat` in another terminal
+
+use super::{cells_to_string, CellStyle, Row, TerminalColor, UnderlineStyle};
+
+fn push_color_params(params: &mut Vec<String>, color: TerminalColor, base: u8) {
+    let param = match color {
+        TerminalColor::Default => (base + 9).to_string(),
+        TerminalColor::Black => base.to_string(),
+        TerminalColor::Red => (base + 1).to_string(),
+        TerminalColor::Green => (base + 2).to_string(),
+        TerminalColor::Yellow => (base + 3).to_string(),
+        TerminalColor::Blue => (base + 4).to_string(),
+        TerminalColor::Magenta => (base + 5).to_string(),
+        TerminalColor::Cyan => (base + 6).to_string(),
+        TerminalColor::White => (base + 7).to_string(),
+        TerminalColor::Indexed256(idx) => format!("{};5;{}", base + 8, idx),
+        TerminalColor::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b),
+    };
+    params.push(param);
+}
+
+/// SGR 58 only has the extended forms, named colors are sent as their index
+fn push_underline_color_params(params: &mut Vec<String>, color: TerminalColor) {
+    let idx = match color {
+        TerminalColor::Black => 0,
+        TerminalColor::Red => 1,
+        TerminalColor::Green => 2,
+        TerminalColor::Yellow => 3,
+        TerminalColor::Blue => 4,
+        TerminalColor::Magenta => 5,
+        TerminalColor::Cyan => 6,
+        TerminalColor::White => 7,
+        TerminalColor::Default | TerminalColor::Indexed256(_) | TerminalColor::Rgb(..) => {
+            return push_color_params(params, color, 50);
+        }
+    };
+    params.push(format!("58;5;{}", idx));
+}
+
+/// SGR sequence that takes the output from `from` to `to`, only touching attributes that differ
+fn style_transition(from: &CellStyle, to: &CellStyle) -> Vec<u8> {
+    if *to == CellStyle::default() {
+        return b"\x1b[0m".to_vec();
+    }
+
+    let mut params = Vec::new();
+    if from.bold != to.bold {
+        params.push(if to.bold { "1" } else { "22" }.to_string());
+    }
+    if from.underline != to.underline {
+        let param = match to.underline {
+            UnderlineStyle::None => "24",
+            UnderlineStyle::Single => "4",
+            UnderlineStyle::Double => "21",
+            UnderlineStyle::Curly => "4:3",
+            UnderlineStyle::Dotted => "4:4",
+            UnderlineStyle::Dashed => "4:5",
+        };
+        params.push(param.to_string());
+    }
+    if from.color != to.color {
+        push_color_params(&mut params, to.color, 30);
+    }
+    if from.background != to.background {
+        push_color_params(&mut params, to.background, 40);
+    }
+    if from.underline_color != to.underline_color {
+        push_underline_color_params(&mut params, to.underline_color);
+    }
+
+    format!("\x1b[{}m", params.join(";")).into_bytes()
+}
+
+/// Serialize `rows`, starting from and returning to default attributes. Adjacent runs with the
+/// same style share a single sequence. Wrapped rows are joined the same way copied text is
+pub fn rows_to_ansi<'a>(rows: impl Iterator<Item = Row<'a>>) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut current = CellStyle::default();
+
+    let mut first = true;
+    let mut prev_wrapped = false;
+    for row in rows {
+        if !first && !prev_wrapped {
+            out.push(b'\n');
+        }
+        first = false;
+        prev_wrapped = row.is_wrapped();
+
+        for run in row.runs() {
+            if run.text.is_empty() {
+                continue;
+            }
+            if run.style != current {
+                out.extend(style_transition(&current, &run.style));
+                current = run.style;
+            }
+            out.extend(cells_to_string(run.text).as_bytes());
+        }
+    }
+
+    if current != CellStyle::default() {
+        out.extend(b"\x1b[0m");
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::terminal_emulator::{FormatTag, LineSize};
+
+    fn tag(start: usize, end: usize, color: TerminalColor, bold: bool) -> FormatTag {
+        FormatTag {
+            start,
+            end,
+            color,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+            bold,
+        }
+    }
+
+    #[test]
+    fn test_rows_to_ansi() {
+        let buf: Vec<char> = "abcd\nef".chars().collect();
+        let tags = [
+            tag(0, 1, TerminalColor::Default, false),
+            tag(1, 3, TerminalColor::Red, false),
+            tag(3, 6, TerminalColor::Red, true),
+            tag(6, usize::MAX, TerminalColor::Indexed256(100), true),
+        ];
+        let rows = [
+            Row::new(&buf, 0..4, &tags, LineSize::SingleWidth),
+            Row::new(&buf, 5..7, &tags, LineSize::SingleWidth),
+        ];
+
+        let ansi = rows_to_ansi(rows.into_iter());
+        assert_eq!(ansi, b"a\x1b[31mbc\x1b[1md\ne\x1b[38;5;100mf\x1b[0m");
+    }
+
+    #[test]
+    fn test_style_transition() {
+        let from = CellStyle {
+            color: TerminalColor::Rgb(1, 2, 3),
+            background: TerminalColor::Blue,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+            bold: true,
+        };
+        let to = CellStyle {
+            color: TerminalColor::Default,
+            background: TerminalColor::Blue,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+            bold: false,
+        };
+        assert_eq!(style_transition(&from, &to), b"\x1b[22;39m");
+        assert_eq!(style_transition(&to, &from), b"\x1b[1;38;2;1;2;3m");
+        assert_eq!(style_transition(&from, &CellStyle::default()), b"\x1b[0m");
+
+        let underlined = CellStyle {
+            underline: UnderlineStyle::Curly,
+            underline_color: TerminalColor::Red,
+            ..to
+        };
+        assert_eq!(style_transition(&to, &underlined), b"\x1b[4:3;58;5;1m");
+        let underlined_rgb = CellStyle {
+            underline_color: TerminalColor::Rgb(4, 5, 6),
+            ..underlined
+        };
+        assert_eq!(
+            style_transition(&underlined, &underlined_rgb),
+            b"\x1b[58;2;4;5;6m"
+        );
+        assert_eq!(
+            style_transition(
+                &underlined_rgb,
+                &CellStyle {
+                    underline_color: TerminalColor::Default,
+                    ..underlined_rgb
+                }
+            ),
+            b"\x1b[59m"
+        );
+    }
+}
@@ -1,37 +1,86 @@
 use std::{num::TryFromIntError, ops::Range};
 use thiserror::Error;
+use unicode_width::UnicodeWidthChar;
 
 use super::{
     recording::{NotIntOfType, SnapshotItem},
     CursorPos, TerminalData,
 };
 
+/// Second column of a character that takes up two. It has no text of its own, the character is
+/// in the cell before it. NUL is never written as text, so it cannot be mistaken for one
+pub const WIDE_CHAR_SPACER: char = '\0';
+
+/// Columns `c` takes up. Characters without a width of their own, e.g. combining marks, still
+/// get a cell
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(1).clamp(1, 2)
+}
+
+/// Cells of `text` written from column `x` of rows that are `width` columns wide. A character
+/// taking two columns that would be cut in half by the end of a row goes to the next one, and
+/// leaves the last column of the row blank
+pub fn text_to_cells(text: &str, x: usize, width: usize) -> Vec<char> {
+    let width = width.max(1);
+    let mut cells = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        // NUL is ignored, like in other terminals
+        if c == '\0' {
+            continue;
+        }
+        let char_width = char_width(c);
+        if char_width == 2 && width > 1 && (x + cells.len()) % width == width - 1 {
+            cells.push(' ');
+        }
+        cells.push(c);
+        if char_width == 2 {
+            cells.push(WIDE_CHAR_SPACER);
+        }
+    }
+    cells
+}
+
+/// Cells of `text` on a single row that does not end, e.g. to look for it in the buffer
+pub fn str_to_cells(text: &str) -> Vec<char> {
+    text_to_cells(text, 0, usize::MAX)
+}
+
+/// Text of `cells`, without the spacers of wide characters
+pub fn cells_to_string(cells: &[char]) -> String {
+    cells.iter().filter(|c| **c != WIDE_CHAR_SPACER).collect()
+}
+
 /// Calculate the indexes of the start and end of each line in the buffer given an input width.
 /// Ranges do not include newlines. If a newline appears past the width, it does not result in an
-/// extra line
+/// extra line. A wide character that does not fit on the end of a row, which happens when the
+/// width changes, starts the next one
 ///
 /// Example
-/// ```
+/// ```text
 /// let ranges = calc_line_ranges(b"12\n1234\n12345", 4);
 /// assert_eq!(ranges, [0..2, 3..7, 8..11, 12..13]);
 /// ```
-fn calc_line_ranges(buf: &[u8], width: usize) -> Vec<Range<usize>> {
+fn calc_line_ranges(buf: &[char], width: usize) -> Vec<Range<usize>> {
     let mut ret = vec![];
 
     let mut current_start = 0;
 
     for (i, c) in buf.iter().enumerate() {
-        if *c == b'\n' {
+        if *c == '\n' {
             ret.push(current_start..i);
             current_start = i + 1;
             continue;
         }
 
-        let bytes_since_start = i - current_start;
-        assert!(bytes_since_start <= width);
-        if bytes_since_start == width {
-            ret.push(current_start..i);
-            current_start = i;
+        let cells_since_start = i - current_start;
+        assert!(cells_since_start <= width);
+        if cells_since_start == width {
+            let row_end = match *c == WIDE_CHAR_SPACER && width > 1 {
+                true => i - 1,
+                false => i,
+            };
+            ret.push(current_start..row_end);
+            current_start = row_end;
             continue;
         }
     }
@@ -50,7 +99,7 @@ struct InvalidBufPos {
 }
 
 fn buf_to_cursor_pos(
-    buf: &[u8],
+    buf: &[char],
     width: usize,
     height: usize,
     buf_pos: usize,
@@ -78,12 +127,12 @@ fn buf_to_cursor_pos(
     })
 }
 
-fn unwrapped_line_end_pos(buf: &[u8], start_pos: usize) -> usize {
+fn unwrapped_line_end_pos(buf: &[char], start_pos: usize) -> usize {
     buf.iter()
         .enumerate()
         .skip(start_pos)
         .find_map(|(i, c)| match *c {
-            b'\n' => Some(i),
+            '\n' => Some(i),
             _ => None,
         })
         .unwrap_or(buf.len())
@@ -111,7 +160,7 @@ struct PadBufferForWriteResponse {
 }
 
 fn pad_buffer_for_write(
-    buf: &mut Vec<u8>,
+    buf: &mut Vec<char>,
     width: usize,
     height: usize,
     cursor_pos: &CursorPos,
@@ -138,7 +187,7 @@ fn pad_buffer_for_write(
     }
 
     for _ in 0..vertical_padding_needed {
-        buf.push(b'\n');
+        buf.push('\n');
         let newline_pos = buf.len() - 1;
         visible_line_ranges.push(newline_pos..newline_pos);
     }
@@ -160,16 +209,12 @@ fn pad_buffer_for_write(
         padding_start_pos = Some(actual_end);
     }
 
-    let number_of_spaces = if desired_end > actual_end {
-        desired_end - actual_end
-    } else {
-        0
-    };
+    let number_of_spaces = desired_end.saturating_sub(actual_end);
 
     num_inserted_characters += number_of_spaces;
 
     for i in 0..number_of_spaces {
-        buf.insert(actual_end + i, b' ');
+        buf.insert(actual_end + i, ' ');
     }
 
     let start_buf_pos =
@@ -196,7 +241,7 @@ fn cursor_to_buf_pos_from_visible_line_ranges(
 }
 
 fn cursor_to_buf_pos(
-    buf: &[u8],
+    buf: &[char],
     cursor_pos: &CursorPos,
     width: usize,
     height: usize,
@@ -225,6 +270,24 @@ pub struct TerminalBufferInsertLineResponse {
     pub inserted_range: Range<usize>,
 }
 
+#[derive(Debug)]
+pub struct TerminalBufferScrollResponse {
+    /// Blank lines appended so that the whole screen exists before scrolling
+    pub padding: Range<usize>,
+    /// Lines that left the region, after padding
+    pub deleted_range: Range<usize>,
+    /// Blank lines that entered the region, after deletion
+    pub inserted_range: Range<usize>,
+}
+
+#[derive(Debug)]
+pub struct TerminalBufferShiftResponse {
+    /// Range of data removed from the row, e.g. pushed off its end, **before insertion**
+    pub deleted_range: Range<usize>,
+    /// Range of what was put in, e.g. the blanks that made room, after deletion
+    pub inserted_range: Range<usize>,
+}
+
 pub struct TerminalBufferSetWinSizeResponse {
     pub changed: bool,
     #[allow(dead_code)]
@@ -258,8 +321,10 @@ enum LoadSnapshotErrorKind {
     BufMissing,
     #[error("buf is not a vec")]
     BufNotVec,
-    #[error("buf element is not u8")]
-    BufElemNotU8(#[source] NotIntOfType),
+    #[error("buf element is not u32")]
+    BufElemNotU32(#[source] NotIntOfType),
+    #[error("buf element {0} is not a char")]
+    BufElemNotChar(u32),
     #[error("width missing")]
     WidthMissing,
     #[error("failed to get width as usize")]
@@ -276,7 +341,7 @@ pub struct LoadSnapshotError(#[from] LoadSnapshotErrorKind);
 
 #[derive(Eq, PartialEq, Debug)]
 pub struct TerminalBuffer {
-    buf: Vec<u8>,
+    buf: Vec<char>,
     width: usize,
     height: usize,
 }
@@ -296,8 +361,14 @@ impl TerminalBuffer {
 
         let buf = root.remove(terminal_buffer_keys::BUF).ok_or(BufMissing)?;
         let buf = buf.into_vec().map_err(|_| BufNotVec)?;
-        let buf: Result<Vec<u8>, _> = buf.into_iter().map(|x| x.into_num::<u8>()).collect();
-        let buf = buf.map_err(BufElemNotU8)?;
+        let buf: Result<Vec<char>, _> = buf
+            .into_iter()
+            .map(|x| {
+                let x = x.into_num::<u32>().map_err(BufElemNotU32)?;
+                char::from_u32(x).ok_or(BufElemNotChar(x))
+            })
+            .collect();
+        let buf = buf?;
 
         let width = root
             .remove(terminal_buffer_keys::WIDTH)
@@ -320,7 +391,7 @@ impl TerminalBuffer {
             [
                 (
                     terminal_buffer_keys::BUF.to_string(),
-                    self.buf.iter().collect(),
+                    self.buf.iter().map(|c| u32::from(*c)).collect(),
                 ),
                 (terminal_buffer_keys::WIDTH.to_string(), width_i64.into()),
                 (terminal_buffer_keys::HEIGHT.to_string(), height_i64.into()),
@@ -333,7 +404,7 @@ impl TerminalBuffer {
     pub fn insert_data(
         &mut self,
         cursor_pos: &CursorPos,
-        data: &[u8],
+        data: &[char],
     ) -> TerminalBufferInsertResponse {
         let PadBufferForWriteResponse {
             write_idx,
@@ -347,6 +418,8 @@ impl TerminalBuffer {
         );
         let write_range = write_idx..write_idx + data.len();
         self.buf[write_range.clone()].copy_from_slice(data);
+        self.blank_split_char(write_range.start);
+        self.blank_split_char(write_range.end);
         let new_cursor_pos = buf_to_cursor_pos(&self.buf, self.width, self.height, write_range.end)
             .expect("write range should be valid in buf");
         TerminalBufferInsertResponse {
@@ -376,11 +449,13 @@ impl TerminalBuffer {
 
                 // NOTE: We do the overwrite first so we don't have to worry about adjusting
                 // indices for the newly inserted data
-                self.buf[buf_pos..buf_pos + num_overwritten].fill(b' ');
+                self.buf[buf_pos..buf_pos + num_overwritten].fill(' ');
                 self.buf
-                    .splice(buf_pos..buf_pos, std::iter::repeat(b' ').take(num_inserted));
+                    .splice(buf_pos..buf_pos, std::iter::repeat_n(' ', num_inserted));
 
                 let used_spaces = num_inserted + num_overwritten;
+                self.blank_split_char(buf_pos);
+                self.blank_split_char(buf_pos + used_spaces);
                 TerminalBufferInsertResponse {
                     written_range: buf_pos..buf_pos + used_spaces,
                     insertion_range: buf_pos..buf_pos + num_inserted,
@@ -407,6 +482,35 @@ impl TerminalBuffer {
         }
     }
 
+    /// Make room for `num_chars` at the cursor by moving the rest of its row to the right. What
+    /// is pushed past the right edge is dropped, and the rows below stay as they are. Nothing
+    /// moves when there is nothing on the row from the cursor on
+    pub fn shift_right(
+        &mut self,
+        cursor_pos: &CursorPos,
+        num_chars: usize,
+    ) -> Option<TerminalBufferShiftResponse> {
+        let (buf_pos, line_range) =
+            cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height)?;
+        if buf_pos == line_range.end {
+            return None;
+        }
+
+        let num_chars = num_chars.min(self.width - cursor_pos.x);
+        let overflow = (line_range.len() + num_chars).saturating_sub(self.width);
+        let deleted_range = line_range.end - overflow..line_range.end;
+        self.buf.drain(deleted_range.clone());
+        self.blank_split_char(deleted_range.start);
+        self.buf
+            .splice(buf_pos..buf_pos, std::iter::repeat_n(' ', num_chars));
+        self.blank_split_char(buf_pos);
+        self.blank_split_char(buf_pos + num_chars);
+        Some(TerminalBufferShiftResponse {
+            deleted_range,
+            inserted_range: buf_pos..buf_pos + num_chars,
+        })
+    }
+
     pub fn insert_lines(
         &mut self,
         cursor_pos: &CursorPos,
@@ -445,13 +549,13 @@ impl TerminalBuffer {
         // Edge case, if the previous line ended in a line wrap, inserting a new line will not
         // result in an extra line being shown on screen. E.g. with a width of 5, 01234 and 01234\n
         // both look like a line of length 5. In this case we need to add another newline
-        if insertion_pos > 0 && self.buf[insertion_pos - 1] != b'\n' {
+        if insertion_pos > 0 && self.buf[insertion_pos - 1] != '\n' {
             num_lines += 1;
         }
 
         self.buf.splice(
             insertion_pos..insertion_pos,
-            std::iter::repeat(b'\n').take(num_lines),
+            std::iter::repeat_n('\n', num_lines),
         );
 
         TerminalBufferInsertLineResponse {
@@ -461,17 +565,21 @@ impl TerminalBuffer {
     }
 
     pub fn clear_forwards(&mut self, cursor_pos: &CursorPos) -> Option<usize> {
+        // A cursor waiting to wrap at the end of a full row is on its last column
+        let cursor_pos = &CursorPos {
+            x: cursor_pos.x.min(self.width.saturating_sub(1)),
+            y: cursor_pos.y,
+        };
+
         let line_ranges = calc_line_ranges(&self.buf, self.width);
         let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
 
-        let Some((buf_pos, _)) =
-            cursor_to_buf_pos_from_visible_line_ranges(cursor_pos, visible_line_ranges)
-        else {
-            return None;
-        };
+        let (buf_pos, _) =
+            cursor_to_buf_pos_from_visible_line_ranges(cursor_pos, visible_line_ranges)?;
 
         let previous_last_char = self.buf[buf_pos];
         self.buf.truncate(buf_pos);
+        self.blank_split_char(buf_pos);
 
         // If we truncate at the start of a line, and the previous line did not end with a newline,
         // the first inserted newline will not have an effect on the number of visible lines. This
@@ -481,15 +589,15 @@ impl TerminalBuffer {
         // to compensate
         //
         // If we truncated a newline it's the same situation
-        if cursor_pos.x == 0 && buf_pos > 0 && self.buf[buf_pos - 1] != b'\n'
-            || previous_last_char == b'\n'
+        if cursor_pos.x == 0 && buf_pos > 0 && self.buf[buf_pos - 1] != '\n'
+            || previous_last_char == '\n'
         {
-            self.buf.push(b'\n');
+            self.buf.push('\n');
         }
 
         for line in visible_line_ranges {
             if line.end > buf_pos {
-                self.buf.push(b'\n');
+                self.buf.push('\n');
             }
         }
 
@@ -505,22 +613,214 @@ impl TerminalBuffer {
                 pos
             });
 
-        assert_eq!(new_cursor_pos, Ok(cursor_pos.clone()));
+        if new_cursor_pos.as_ref() != Ok(cursor_pos) {
+            warn!("Clearing forwards moved the cursor from {cursor_pos:?} to {new_cursor_pos:?}");
+        }
         Some(buf_pos)
     }
 
-    pub fn clear_line_forwards(&mut self, cursor_pos: &CursorPos) -> Option<Range<usize>> {
+    pub fn clear_line_forwards(
+        &mut self,
+        cursor_pos: &CursorPos,
+    ) -> Option<TerminalBufferShiftResponse> {
         // Can return early if none, we didn't delete anything if there is nothing to delete
         let (buf_pos, line_range) =
             cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height)?;
 
-        let del_range = buf_pos..line_range.end;
-        self.buf.drain(del_range.clone());
-        Some(del_range)
+        let deleted_range = buf_pos..line_range.end;
+        // When the row wraps, what follows would move up into it, so the row gets ended instead.
+        // At the start of the row that takes a second newline if the row above wraps into it,
+        // like for insert_lines
+        let wraps =
+            !deleted_range.is_empty() && self.buf.get(line_range.end).is_some_and(|b| *b != '\n');
+        let num_newlines = match wraps {
+            false => 0,
+            true if cursor_pos.x == 0 && buf_pos > 0 && self.buf[buf_pos - 1] != '\n' => 2,
+            true => 1,
+        };
+        self.buf.splice(
+            deleted_range.clone(),
+            std::iter::repeat_n('\n', num_newlines),
+        );
+        self.blank_split_char(buf_pos);
+        Some(TerminalBufferShiftResponse {
+            deleted_range,
+            inserted_range: buf_pos..buf_pos + num_newlines,
+        })
+    }
+
+    /// Erase the visible screen, leaving scrollback alone. Returns where the screen started
+    pub fn clear_visible(&mut self) -> usize {
+        let start = self.data().scrollback.len();
+        self.buf.truncate(start);
+        if start == 0 {
+            return 0;
+        }
+
+        // The screen still has to be made of lines, otherwise scrollback would move into view.
+        // Like for insertion, a wrapped last scrollback line needs an extra newline to end it
+        if self.buf[start - 1] != '\n' {
+            self.buf.push('\n');
+        }
+        self.buf.extend(std::iter::repeat_n('\n', self.height));
+        start
     }
 
-    pub fn clear_all(&mut self) {
-        self.buf.clear();
+    /// Drop everything that scrolled off the screen. Returns the removed range
+    pub fn clear_scrollback(&mut self) -> Range<usize> {
+        let end = self.data().scrollback.len();
+        self.buf.drain(0..end);
+        0..end
+    }
+
+    /// Remove visible lines `lines`, returning the removed range
+    fn delete_visible_lines(
+        &mut self,
+        visible_line_ranges: &[Range<usize>],
+        lines: Range<usize>,
+    ) -> Range<usize> {
+        let start = visible_line_ranges[lines.start].start;
+        let mut end = visible_line_ranges
+            .get(lines.end)
+            .map_or(self.buf.len(), |range| range.start);
+
+        // If the line above wraps into the deleted ones, the newline ending the last deleted line
+        // has to stay. Otherwise an empty line following would be taken as the end of the full
+        // line above and disappear
+        if start > 0 && self.buf[start - 1] != '\n' && end > start && self.buf[end - 1] == '\n' {
+            end -= 1;
+        }
+
+        self.buf.drain(start..end);
+        start..end
+    }
+
+    /// Insert `num_lines` blank lines at `pos`, which has to be the start of a line or the end of
+    /// the buffer
+    fn insert_blank_lines(&mut self, pos: usize, mut num_lines: usize) -> Range<usize> {
+        // Same edge case as in insert_lines, a newline after a wrapped line only ends it
+        if pos > 0 && self.buf[pos - 1] != '\n' {
+            num_lines += 1;
+        }
+        self.buf
+            .splice(pos..pos, std::iter::repeat_n('\n', num_lines));
+        pos..pos + num_lines
+    }
+
+    /// Make sure every row of the screen exists, so that rows can be moved around
+    fn pad_screen(&mut self) -> Range<usize> {
+        let bottom = CursorPos {
+            x: 0,
+            y: self.height.saturating_sub(1),
+        };
+        pad_buffer_for_write(&mut self.buf, self.width, self.height, &bottom, 0).inserted_padding
+    }
+
+    /// Move the visible lines in `region` up by `num_lines`, blank lines come in at the bottom of
+    /// the region. Rows outside of it do not move. When the region starts at the top of the
+    /// screen, the lines that leave go to scrollback
+    pub fn scroll_up(
+        &mut self,
+        region: Range<usize>,
+        num_lines: usize,
+    ) -> TerminalBufferScrollResponse {
+        let to_scrollback = region.start == 0;
+        self.move_lines_up(region, num_lines, to_scrollback)
+    }
+
+    /// Remove `num_lines` lines from the top of `region`, moving the rest of it up. Unlike
+    /// [`Self::scroll_up`] the lines are gone even at the top of the screen
+    pub fn delete_lines(
+        &mut self,
+        region: Range<usize>,
+        num_lines: usize,
+    ) -> TerminalBufferScrollResponse {
+        self.move_lines_up(region, num_lines, false)
+    }
+
+    fn move_lines_up(
+        &mut self,
+        region: Range<usize>,
+        num_lines: usize,
+        to_scrollback: bool,
+    ) -> TerminalBufferScrollResponse {
+        let padding = self.pad_screen();
+        let region = region.start..region.end.min(self.height);
+        let num_lines = num_lines.min(region.len());
+        if num_lines == 0 {
+            return TerminalBufferScrollResponse {
+                padding,
+                deleted_range: 0..0,
+                inserted_range: 0..0,
+            };
+        }
+
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible_line_ranges =
+            line_ranges_to_visible_line_ranges(&line_ranges, self.height).to_vec();
+
+        let insertion_pos = visible_line_ranges
+            .get(region.end)
+            .map_or(self.buf.len(), |range| range.start);
+
+        let deleted_range = if to_scrollback {
+            0..0
+        } else {
+            self.delete_visible_lines(&visible_line_ranges, region.start..region.start + num_lines)
+        };
+
+        let inserted_range =
+            self.insert_blank_lines(insertion_pos - deleted_range.len(), num_lines);
+
+        TerminalBufferScrollResponse {
+            padding,
+            deleted_range,
+            inserted_range,
+        }
+    }
+
+    /// Move the visible lines in `region` down by `num_lines`, blank lines come in at the top of
+    /// the region. Lines pushed past the bottom of the region are removed
+    pub fn scroll_down(
+        &mut self,
+        region: Range<usize>,
+        num_lines: usize,
+    ) -> TerminalBufferScrollResponse {
+        let padding = self.pad_screen();
+        let region = region.start..region.end.min(self.height);
+        let num_lines = num_lines.min(region.len());
+        if num_lines == 0 {
+            return TerminalBufferScrollResponse {
+                padding,
+                deleted_range: 0..0,
+                inserted_range: 0..0,
+            };
+        }
+
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible_line_ranges =
+            line_ranges_to_visible_line_ranges(&line_ranges, self.height).to_vec();
+
+        let mut insertion_pos = visible_line_ranges[region.start].start;
+        let deleted_range =
+            self.delete_visible_lines(&visible_line_ranges, region.end - num_lines..region.end);
+
+        // When the whole region went and the line above wraps into it, the deletion kept a
+        // newline to end that line. The blank lines go after it
+        if deleted_range.start == insertion_pos
+            && insertion_pos > 0
+            && self.buf[insertion_pos - 1] != '\n'
+            && self.buf.get(insertion_pos) == Some(&'\n')
+        {
+            insertion_pos += 1;
+        }
+        let inserted_range = self.insert_blank_lines(insertion_pos, num_lines);
+
+        TerminalBufferScrollResponse {
+            padding,
+            deleted_range,
+            inserted_range,
+        }
     }
 
     pub fn delete_forwards(
@@ -528,25 +828,97 @@ impl TerminalBuffer {
         cursor_pos: &CursorPos,
         num_chars: usize,
     ) -> Option<Range<usize>> {
-        let Some((buf_pos, line_range)) =
-            cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height)
-        else {
-            return None;
-        };
+        let (buf_pos, line_range) =
+            cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height)?;
 
         let mut delete_range = buf_pos..buf_pos + num_chars;
 
-        if delete_range.end > line_range.end && self.buf.get(line_range.end) != Some(&b'\n') {
-            self.buf.insert(line_range.end, b'\n');
+        if delete_range.end > line_range.end && self.buf.get(line_range.end) != Some(&'\n') {
+            self.buf.insert(line_range.end, '\n');
         }
 
         delete_range.end = line_range.end.min(delete_range.end);
 
+        // The spacer left of a wide character would pair up with whatever ends up before it
+        if self.buf.get(delete_range.end) == Some(&WIDE_CHAR_SPACER) {
+            self.buf[delete_range.end] = ' ';
+        }
         self.buf.drain(delete_range.clone());
+        self.blank_split_char(buf_pos);
         Some(delete_range)
     }
 
-    pub fn data(&self) -> TerminalData<&[u8]> {
+    /// Blank what is left of a wide character that was cut in half at `pos`, e.g. by writing
+    /// over one of its cells. A character without its spacer, or a spacer without its
+    /// character, would not line up with the grid anymore
+    fn blank_split_char(&mut self, pos: usize) {
+        if self.buf.get(pos) == Some(&WIDE_CHAR_SPACER)
+            && (pos == 0 || char_width(self.buf[pos - 1]) != 2)
+        {
+            self.buf[pos] = ' ';
+        }
+        if pos > 0
+            && char_width(self.buf[pos - 1]) == 2
+            && self.buf.get(pos) != Some(&WIDE_CHAR_SPACER)
+        {
+            self.buf[pos - 1] = ' ';
+        }
+    }
+
+    /// Columns taken up by the character at `cursor_pos`, both of them for a wide character.
+    /// None past the end of the row
+    pub fn char_cells(&self, cursor_pos: &CursorPos) -> Option<Range<usize>> {
+        let (buf_pos, line_range) =
+            cursor_to_buf_pos(&self.buf, cursor_pos, self.width, self.height)?;
+        if buf_pos >= line_range.end {
+            return None;
+        }
+
+        let x = cursor_pos.x;
+        let cells = match self.buf[buf_pos] {
+            WIDE_CHAR_SPACER if x > 0 => x - 1..x + 1,
+            c if char_width(c) == 2 => x..x + 2,
+            _ => x..x + 1,
+        };
+        Some(cells)
+    }
+
+    /// The whole buffer, along with where each line, including scrollback, is in it
+    pub fn lines(&self) -> (&[char], Vec<Range<usize>>) {
+        (&self.buf, calc_line_ranges(&self.buf, self.width))
+    }
+
+    /// The whole buffer, along with where each visible line is in it
+    pub fn visible_lines(&self) -> (&[char], Vec<Range<usize>>) {
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
+        (&self.buf, visible_line_ranges.to_vec())
+    }
+
+    /// Buffer position where visible line `y` starts, if that line exists
+    pub fn line_start(&self, y: usize) -> Option<usize> {
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
+        visible_line_ranges.get(y).map(|range| range.start)
+    }
+
+    /// How far into its line the cursor is, including the rows the line wrapped over before
+    /// the cursor's row
+    pub fn unwrapped_line_offset(&self, cursor_pos: &CursorPos) -> usize {
+        let line_ranges = calc_line_ranges(&self.buf, self.width);
+        let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
+        let Some(line) = visible_line_ranges.get(cursor_pos.y) else {
+            return cursor_pos.x;
+        };
+
+        let unwrapped_start = self.buf[..line.start]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |i| i + 1);
+        line.start - unwrapped_start + cursor_pos.x
+    }
+
+    pub fn data(&self) -> TerminalData<&[char]> {
         let line_ranges = calc_line_ranges(&self.buf, self.width);
         let visible_line_ranges = line_ranges_to_visible_line_ranges(&line_ranges, self.height);
         if self.buf.is_empty() {
@@ -605,20 +977,34 @@ impl TerminalBuffer {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_util::cells;
 
     #[test]
     fn test_calc_line_ranges() {
-        let line_starts = calc_line_ranges(b"asdf\n0123456789\n012345678901", 10);
+        let line_starts = calc_line_ranges(&cells("asdf\n0123456789\n012345678901"), 10);
         assert_eq!(line_starts, &[0..4, 5..15, 16..26, 26..28]);
+
+        // 漢 would be cut in half by the end of the first row
+        let line_starts = calc_line_ranges(&str_to_cells("0123漢5"), 5);
+        assert_eq!(line_starts, &[0..4, 4..7]);
+    }
+
+    #[test]
+    fn test_text_to_cells() {
+        let s = WIDE_CHAR_SPACER;
+        assert_eq!(str_to_cells("a漢\0b"), ['a', '漢', s, 'b']);
+        assert_eq!(text_to_cells("a漢b", 2, 4), ['a', ' ', '漢', s, 'b']);
+        assert_eq!(text_to_cells("漢", 0, 1), ['漢', s]);
+        assert_eq!(cells_to_string(&str_to_cells("a漢b")), "a漢b");
     }
 
     #[test]
     fn test_buffer_padding() {
-        let mut buf = b"asdf\n1234\nzxyw".to_vec();
+        let mut buf = cells("asdf\n1234\nzxyw");
 
         let cursor_pos = CursorPos { x: 8, y: 0 };
         let response = pad_buffer_for_write(&mut buf, 10, 10, &cursor_pos, 10);
-        assert_eq!(buf, b"asdf              \n1234\nzxyw");
+        assert_eq!(buf, cells("asdf              \n1234\nzxyw"));
         assert_eq!(response.write_idx, 8);
         assert_eq!(response.inserted_padding, 4..18);
     }
@@ -627,82 +1013,178 @@ mod test {
     fn test_canvas_clear_forwards() {
         let mut buffer = TerminalBuffer::new(5, 5);
         // Push enough data to get some in scrollback
-        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"012343456789\n0123456789\n1234");
+        buffer.insert_data(
+            &CursorPos { x: 0, y: 0 },
+            &cells("012343456789\n0123456789\n1234"),
+        );
 
         assert_eq!(
             buffer.data().visible,
-            b"\
+            &cells(
+                "\
                    34567\
                    89\n\
                    01234\
                    56789\n\
                    1234\n"
+            )
         );
         buffer.clear_forwards(&CursorPos { x: 1, y: 1 });
         // Same amount of lines should be present before and after clear
         assert_eq!(
             buffer.data().visible,
-            b"\
+            &cells(
+                "\
                    34567\
                    8\n\
                    \n\
                    \n\
                    \n"
+            )
         );
 
         // A few special cases.
         // 1. Truncating on beginning of line and previous char was not a newline
         let mut buffer = TerminalBuffer::new(5, 5);
-        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"012340123401234012340123401234");
+        buffer.insert_data(
+            &CursorPos { x: 0, y: 0 },
+            &cells("012340123401234012340123401234"),
+        );
         buffer.clear_forwards(&CursorPos { x: 0, y: 1 });
-        assert_eq!(buffer.data().visible, b"01234\n\n\n\n\n");
+        assert_eq!(buffer.data().visible, &cells("01234\n\n\n\n\n"));
 
         // 2. Truncating on beginning of line and previous char was a newline
         let mut buffer = TerminalBuffer::new(5, 5);
         buffer.insert_data(
             &CursorPos { x: 0, y: 0 },
-            b"01234\n0123401234012340123401234",
+            &cells("01234\n0123401234012340123401234"),
         );
         buffer.clear_forwards(&CursorPos { x: 0, y: 1 });
-        assert_eq!(buffer.data().visible, b"01234\n\n\n\n\n");
+        assert_eq!(buffer.data().visible, &cells("01234\n\n\n\n\n"));
 
         // 3. Truncating on a newline
         let mut buffer = TerminalBuffer::new(5, 5);
-        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"\n\n\n\n\n\n");
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("\n\n\n\n\n\n"));
         buffer.clear_forwards(&CursorPos { x: 0, y: 1 });
-        assert_eq!(buffer.data().visible, b"\n\n\n\n\n");
+        assert_eq!(buffer.data().visible, &cells("\n\n\n\n\n"));
     }
 
     #[test]
     fn test_canvas_clear() {
         let mut buffer = TerminalBuffer::new(5, 5);
-        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"0123456789");
-        buffer.clear_all();
-        assert_eq!(buffer.data().visible, &[]);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("0123456789"));
+        assert_eq!(buffer.clear_visible(), 0);
+        assert_eq!(buffer.data().visible, &[] as &[char]);
+
+        // Scrollback stays, also when its last line wraps onto the screen
+        let mut buffer = TerminalBuffer::new(5, 2);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("ab\n0123456789abc"));
+        assert_eq!(buffer.data().scrollback, &cells("ab\n01234"));
+        assert_eq!(buffer.clear_visible(), 8);
+        assert_eq!(buffer.data().scrollback, &cells("ab\n01234\n"));
+        assert_eq!(buffer.data().visible, &cells("\n\n"));
+
+        assert_eq!(buffer.clear_scrollback(), 0..9);
+        assert_eq!(buffer.data().scrollback, &cells(""));
+        assert_eq!(buffer.data().visible, &cells("\n\n"));
+    }
+
+    fn visible_rows(buffer: &TerminalBuffer) -> Vec<String> {
+        let (buf, lines) = buffer.visible_lines();
+        lines
+            .into_iter()
+            .map(|range| cells_to_string(&buf[range]))
+            .collect()
+    }
+
+    #[test]
+    fn test_scroll_up() {
+        // Region at the top of the screen, lines go to scrollback
+        let mut buffer = TerminalBuffer::new(5, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("a\nb\nc\nstat"));
+        let response = buffer.scroll_up(0..3, 1);
+        assert_eq!(response.deleted_range, 0..0);
+        assert_eq!(response.inserted_range, 6..7);
+        assert_eq!(buffer.data().scrollback, &cells("a\n"));
+        assert_eq!(visible_rows(&buffer), ["b", "c", "", "stat"]);
+
+        // Region below a header, lines are removed
+        let mut buffer = TerminalBuffer::new(5, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("head\na\nb\nc"));
+        let response = buffer.scroll_up(1..4, 2);
+        assert_eq!(response.deleted_range, 5..9);
+        assert_eq!(response.inserted_range, 7..9);
+        assert_eq!(buffer.data().scrollback, &cells(""));
+        assert_eq!(visible_rows(&buffer), ["head", "c", "", ""]);
+
+        // Missing rows are padded first
+        let mut buffer = TerminalBuffer::new(5, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("a"));
+        let response = buffer.scroll_up(1..3, 1);
+        assert!(!response.padding.is_empty());
+        assert_eq!(visible_rows(&buffer), ["a", "", "", ""]);
+    }
+
+    #[test]
+    fn test_scroll_wrapped_edges() {
+        // The header wraps into the region, and the region wraps into the status line
+        let mut buffer = TerminalBuffer::new(3, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("hhhabcxyzst"));
+        assert_eq!(visible_rows(&buffer), ["hhh", "abc", "xyz", "st"]);
+        buffer.scroll_up(1..3, 1);
+        assert_eq!(visible_rows(&buffer), ["hhh", "xyz", "", "st"]);
+
+        let mut buffer = TerminalBuffer::new(3, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("hhhab\n\nst"));
+        assert_eq!(visible_rows(&buffer), ["hhh", "ab", "", "st"]);
+        buffer.scroll_up(1..3, 2);
+        assert_eq!(visible_rows(&buffer), ["hhh", "", "", "st"]);
+
+        let mut buffer = TerminalBuffer::new(3, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("hhhab\n\nst"));
+        buffer.scroll_down(1..3, 2);
+        assert_eq!(visible_rows(&buffer), ["hhh", "", "", "st"]);
+    }
+
+    #[test]
+    fn test_scroll_down() {
+        let mut buffer = TerminalBuffer::new(5, 4);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("a\nb\nc\nstat"));
+        let response = buffer.scroll_down(1..3, 1);
+        assert_eq!(response.deleted_range, 4..6);
+        assert_eq!(response.inserted_range, 2..3);
+        assert_eq!(visible_rows(&buffer), ["a", "", "b", "stat"]);
+
+        // Scrollback does not come back into view
+        let mut buffer = TerminalBuffer::new(5, 2);
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("a\nb\nc"));
+        buffer.scroll_down(0..2, 1);
+        assert_eq!(buffer.data().scrollback, &cells("a\n"));
+        assert_eq!(visible_rows(&buffer), ["", "b"]);
     }
 
     #[test]
     fn test_terminal_buffer_overwrite_early_newline() {
         let mut buffer = TerminalBuffer::new(5, 5);
-        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"012\n3456789");
-        assert_eq!(buffer.data().visible, b"012\n3456789\n");
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("012\n3456789"));
+        assert_eq!(buffer.data().visible, &cells("012\n3456789\n"));
 
         // Cursor pos should be calculated based off wrapping at column 5, but should not result in
         // an extra newline
-        buffer.insert_data(&CursorPos { x: 2, y: 1 }, b"test");
-        assert_eq!(buffer.data().visible, b"012\n34test9\n");
+        buffer.insert_data(&CursorPos { x: 2, y: 1 }, &cells("test"));
+        assert_eq!(buffer.data().visible, &cells("012\n34test9\n"));
     }
 
     #[test]
     fn test_terminal_buffer_overwrite_no_newline() {
         let mut buffer = TerminalBuffer::new(5, 5);
-        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"0123456789");
-        assert_eq!(buffer.data().visible, b"0123456789\n");
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("0123456789"));
+        assert_eq!(buffer.data().visible, &cells("0123456789\n"));
 
         // Cursor pos should be calculated based off wrapping at column 5, but should not result in
         // an extra newline
-        buffer.insert_data(&CursorPos { x: 2, y: 1 }, b"test");
-        assert_eq!(buffer.data().visible, b"0123456test\n");
+        buffer.insert_data(&CursorPos { x: 2, y: 1 }, &cells("test"));
+        assert_eq!(buffer.data().visible, &cells("0123456test\n"));
     }
 
     #[test]
@@ -710,23 +1192,23 @@ mod test {
         // This should behave exactly as test_terminal_buffer_overwrite_no_newline(), except with a
         // neline between lines 1 and 2
         let mut buffer = TerminalBuffer::new(5, 5);
-        buffer.insert_data(&CursorPos { x: 0, y: 0 }, b"01234\n56789");
-        assert_eq!(buffer.data().visible, b"01234\n56789\n");
+        buffer.insert_data(&CursorPos { x: 0, y: 0 }, &cells("01234\n56789"));
+        assert_eq!(buffer.data().visible, &cells("01234\n56789\n"));
 
-        buffer.insert_data(&CursorPos { x: 2, y: 1 }, b"test");
-        assert_eq!(buffer.data().visible, b"01234\n56test\n");
+        buffer.insert_data(&CursorPos { x: 2, y: 1 }, &cells("test"));
+        assert_eq!(buffer.data().visible, &cells("01234\n56test\n"));
     }
 
     #[test]
     fn test_terminal_buffer_insert_unallocated_data() {
         let mut buffer = TerminalBuffer::new(10, 10);
-        buffer.insert_data(&CursorPos { x: 4, y: 5 }, b"hello world");
-        assert_eq!(buffer.data().visible, b"\n\n\n\n\n    hello world\n");
+        buffer.insert_data(&CursorPos { x: 4, y: 5 }, &cells("hello world"));
+        assert_eq!(buffer.data().visible, &cells("\n\n\n\n\n    hello world\n"));
 
-        buffer.insert_data(&CursorPos { x: 3, y: 2 }, b"hello world");
+        buffer.insert_data(&CursorPos { x: 3, y: 2 }, &cells("hello world"));
         assert_eq!(
             buffer.data().visible,
-            b"\n\n   hello world\n\n\n    hello world\n"
+            &cells("\n\n   hello world\n\n\n    hello world\n")
         );
     }
 
@@ -741,64 +1223,67 @@ mod test {
         }
 
         // Simulate real terminal usage where newlines are injected with cursor moves
-        let mut response = canvas.insert_data(&initial_cursor_pos, b"asdf");
+        let mut response = canvas.insert_data(&initial_cursor_pos, &cells("asdf"));
         crlf(&mut response.new_cursor_pos);
-        let mut response = canvas.insert_data(&response.new_cursor_pos, b"xyzw");
+        let mut response = canvas.insert_data(&response.new_cursor_pos, &cells("xyzw"));
         crlf(&mut response.new_cursor_pos);
-        let mut response = canvas.insert_data(&response.new_cursor_pos, b"1234");
+        let mut response = canvas.insert_data(&response.new_cursor_pos, &cells("1234"));
         crlf(&mut response.new_cursor_pos);
-        let mut response = canvas.insert_data(&response.new_cursor_pos, b"5678");
+        let mut response = canvas.insert_data(&response.new_cursor_pos, &cells("5678"));
         crlf(&mut response.new_cursor_pos);
 
-        assert_eq!(canvas.data().scrollback, b"asdf\n");
-        assert_eq!(canvas.data().visible, b"xyzw\n1234\n5678\n");
+        assert_eq!(canvas.data().scrollback, &cells("asdf\n"));
+        assert_eq!(canvas.data().visible, &cells("xyzw\n1234\n5678\n"));
     }
 
     #[test]
     fn test_canvas_delete_forwards() {
         let mut canvas = TerminalBuffer::new(10, 5);
-        canvas.insert_data(&CursorPos { x: 0, y: 0 }, b"asdf\n123456789012345");
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &cells("asdf\n123456789012345"));
 
         // Test normal deletion
         let deleted_range = canvas.delete_forwards(&CursorPos { x: 1, y: 0 }, 1);
 
         assert_eq!(deleted_range, Some(1..2));
-        assert_eq!(canvas.data().visible, b"adf\n123456789012345\n");
+        assert_eq!(canvas.data().visible, &cells("adf\n123456789012345\n"));
 
         // Test deletion clamped on newline
         let deleted_range = canvas.delete_forwards(&CursorPos { x: 1, y: 0 }, 10);
         assert_eq!(deleted_range, Some(1..3));
-        assert_eq!(canvas.data().visible, b"a\n123456789012345\n");
+        assert_eq!(canvas.data().visible, &cells("a\n123456789012345\n"));
 
         // Test deletion clamped on wrap
         let deleted_range = canvas.delete_forwards(&CursorPos { x: 7, y: 1 }, 10);
         assert_eq!(deleted_range, Some(9..12));
-        assert_eq!(canvas.data().visible, b"a\n1234567\n12345\n");
+        assert_eq!(canvas.data().visible, &cells("a\n1234567\n12345\n"));
 
         // Test deletion in case where nothing is deleted
         let deleted_range = canvas.delete_forwards(&CursorPos { x: 5, y: 5 }, 10);
         assert_eq!(deleted_range, None);
-        assert_eq!(canvas.data().visible, b"a\n1234567\n12345\n");
+        assert_eq!(canvas.data().visible, &cells("a\n1234567\n12345\n"));
     }
 
     #[test]
     fn test_canvas_insert_spaces() {
         let mut canvas = TerminalBuffer::new(10, 5);
-        canvas.insert_data(&CursorPos { x: 0, y: 0 }, b"asdf\n123456789012345");
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &cells("asdf\n123456789012345"));
 
         // Happy path
         let response = canvas.insert_spaces(&CursorPos { x: 2, y: 0 }, 2);
         assert_eq!(response.written_range, 2..4);
         assert_eq!(response.insertion_range, 2..4);
         assert_eq!(response.new_cursor_pos, CursorPos { x: 2, y: 0 });
-        assert_eq!(canvas.data().visible, b"as  df\n123456789012345\n");
+        assert_eq!(canvas.data().visible, &cells("as  df\n123456789012345\n"));
 
         // Truncation at newline
         let response = canvas.insert_spaces(&CursorPos { x: 2, y: 0 }, 1000);
         assert_eq!(response.written_range, 2..10);
         assert_eq!(response.insertion_range, 2..6);
         assert_eq!(response.new_cursor_pos, CursorPos { x: 2, y: 0 });
-        assert_eq!(canvas.data().visible, b"as        \n123456789012345\n");
+        assert_eq!(
+            canvas.data().visible,
+            &cells("as        \n123456789012345\n")
+        );
 
         // Truncation at line wrap
         let response = canvas.insert_spaces(&CursorPos { x: 4, y: 1 }, 1000);
@@ -808,7 +1293,10 @@ mod test {
             0
         );
         assert_eq!(response.new_cursor_pos, CursorPos { x: 4, y: 1 });
-        assert_eq!(canvas.data().visible, b"as        \n1234      12345\n");
+        assert_eq!(
+            canvas.data().visible,
+            &cells("as        \n1234      12345\n")
+        );
 
         // Insertion at non-existant buffer pos
         let response = canvas.insert_spaces(&CursorPos { x: 2, y: 4 }, 3);
@@ -817,29 +1305,88 @@ mod test {
         assert_eq!(response.new_cursor_pos, CursorPos { x: 2, y: 4 });
         assert_eq!(
             canvas.data().visible,
-            b"as        \n1234      12345\n\n     \n"
+            &cells("as        \n1234      12345\n\n     \n")
         );
     }
 
     #[test]
     fn test_clear_line_forwards() {
         let mut canvas = TerminalBuffer::new(10, 5);
-        canvas.insert_data(&CursorPos { x: 0, y: 0 }, b"asdf\n123456789012345");
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &cells("asdf\n123456789012345"));
 
         // Nothing do delete
         let response = canvas.clear_line_forwards(&CursorPos { x: 5, y: 5 });
-        assert_eq!(response, None);
-        assert_eq!(canvas.data().visible, b"asdf\n123456789012345\n");
+        assert!(response.is_none());
+        assert_eq!(canvas.data().visible, &cells("asdf\n123456789012345\n"));
 
         // Hit a newline
-        let response = canvas.clear_line_forwards(&CursorPos { x: 2, y: 0 });
-        assert_eq!(response, Some(2..4));
-        assert_eq!(canvas.data().visible, b"as\n123456789012345\n");
+        let response = canvas
+            .clear_line_forwards(&CursorPos { x: 2, y: 0 })
+            .expect("nothing cleared");
+        assert_eq!(response.deleted_range, 2..4);
+        assert_eq!(response.inserted_range, 2..2);
+        assert_eq!(canvas.data().visible, &cells("as\n123456789012345\n"));
+
+        // Hit a wrap, the next row stays where it is
+        let response = canvas
+            .clear_line_forwards(&CursorPos { x: 2, y: 1 })
+            .expect("nothing cleared");
+        assert_eq!(response.deleted_range, 5..13);
+        assert_eq!(response.inserted_range, 5..6);
+        assert_eq!(canvas.data().visible, &cells("as\n12\n12345\n"));
+
+        // A whole row that the one above wraps into
+        let mut canvas = TerminalBuffer::new(10, 5);
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &cells("1234567890abcdefghijXY"));
+        let response = canvas
+            .clear_line_forwards(&CursorPos { x: 0, y: 1 })
+            .expect("nothing cleared");
+        assert_eq!(response.deleted_range, 10..20);
+        assert_eq!(response.inserted_range, 10..12);
+        assert_eq!(canvas.data().visible, &cells("1234567890\n\nXY\n"));
+    }
+
+    #[test]
+    fn test_char_cells() {
+        let mut canvas = TerminalBuffer::new(10, 5);
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &str_to_cells("aé漢\u{1f600}"));
+        let char_cells = |x| canvas.char_cells(&CursorPos { x, y: 0 });
+        assert_eq!(char_cells(0), Some(0..1));
+        assert_eq!(char_cells(1), Some(1..2));
+        assert_eq!(char_cells(2), Some(2..4));
+        assert_eq!(char_cells(3), Some(2..4));
+        assert_eq!(char_cells(5), Some(4..6));
+        assert_eq!(char_cells(6), None);
+    }
 
-        // Hit a wrap
-        let response = canvas.clear_line_forwards(&CursorPos { x: 2, y: 1 });
-        assert_eq!(response, Some(5..13));
-        assert_eq!(canvas.data().visible, b"as\n1212345\n");
+    #[test]
+    fn test_wide_char_cut_in_half() {
+        let s = WIDE_CHAR_SPACER;
+
+        // Writing over either half blanks the other one
+        let mut canvas = TerminalBuffer::new(10, 5);
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &str_to_cells("漢字z"));
+        canvas.insert_data(&CursorPos { x: 1, y: 0 }, &cells("ab"));
+        assert_eq!(canvas.data().visible, &[' ', 'a', 'b', ' ', 'z', '\n']);
+        canvas.insert_data(&CursorPos { x: 2, y: 0 }, &str_to_cells("漢"));
+        assert_eq!(canvas.data().visible, &[' ', 'a', '漢', s, 'z', '\n']);
+        canvas.insert_data(&CursorPos { x: 3, y: 0 }, &cells("c"));
+        assert_eq!(canvas.data().visible, &[' ', 'a', ' ', 'c', 'z', '\n']);
+
+        // As does deleting or inserting in the middle of one
+        let mut canvas = TerminalBuffer::new(10, 5);
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &str_to_cells("漢字"));
+        canvas.delete_forwards(&CursorPos { x: 1, y: 0 }, 2);
+        assert_eq!(canvas.data().visible, &[' ', ' ', '\n']);
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &str_to_cells("漢"));
+        canvas.insert_spaces(&CursorPos { x: 1, y: 0 }, 1);
+        assert_eq!(canvas.data().visible, &[' ', ' ', ' ', '\n']);
+
+        // Clearing up to its second half
+        let mut canvas = TerminalBuffer::new(10, 5);
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &str_to_cells("a漢"));
+        canvas.clear_line_forwards(&CursorPos { x: 2, y: 0 });
+        assert_eq!(canvas.data().visible, &['a', ' ', '\n']);
     }
 
     #[test]
@@ -859,16 +1406,16 @@ mod test {
         ) -> TerminalBufferInsertResponse {
             let mut response = canvas.set_win_size(width, height, cursor_pos);
             response.new_cursor_pos.x = 0;
-            let mut response = canvas.insert_data(&response.new_cursor_pos, &vec![b' '; width]);
+            let mut response = canvas.insert_data(&response.new_cursor_pos, &vec![' '; width]);
             response.new_cursor_pos.x = 0;
 
-            canvas.insert_data(&response.new_cursor_pos, b"$ ")
+            canvas.insert_data(&response.new_cursor_pos, &cells("$ "))
         }
         let response = simulate_resize(&mut canvas, 10, 5, &cursor_pos);
         let response = simulate_resize(&mut canvas, 10, 4, &response.new_cursor_pos);
         let response = simulate_resize(&mut canvas, 10, 3, &response.new_cursor_pos);
         simulate_resize(&mut canvas, 10, 5, &response.new_cursor_pos);
-        assert_eq!(canvas.data().visible, b"$         \n");
+        assert_eq!(canvas.data().visible, &cells("$         \n"));
     }
 
     #[test]
@@ -883,19 +1430,19 @@ mod test {
             response.inserted_range.start - response.inserted_range.end,
             0
         );
-        assert_eq!(canvas.data().visible, b"");
+        assert_eq!(canvas.data().visible, &cells(""));
 
         // Test edge wrapped
-        canvas.insert_data(&CursorPos { x: 0, y: 0 }, b"0123456789asdf\nxyzw");
-        assert_eq!(canvas.data().visible, b"0123456789asdf\nxyzw\n");
+        canvas.insert_data(&CursorPos { x: 0, y: 0 }, &cells("0123456789asdf\nxyzw"));
+        assert_eq!(canvas.data().visible, &cells("0123456789asdf\nxyzw\n"));
         let response = canvas.insert_lines(&CursorPos { x: 3, y: 2 }, 1);
-        assert_eq!(canvas.data().visible, b"0123456789\n\nasdf\nxyzw\n");
+        assert_eq!(canvas.data().visible, &cells("0123456789\n\nasdf\nxyzw\n"));
         assert_eq!(response.deleted_range.start - response.deleted_range.end, 0);
         assert_eq!(response.inserted_range, 10..12);
 
         // Test newline wrapped + lines pushed off the edge
         let response = canvas.insert_lines(&CursorPos { x: 3, y: 2 }, 1);
-        assert_eq!(canvas.data().visible, b"0123456789\n\n\nasdf\n");
+        assert_eq!(canvas.data().visible, &cells("0123456789\n\n\nasdf\n"));
         assert_eq!(response.deleted_range, 17..22);
         assert_eq!(response.inserted_range, 11..12);
     }
@@ -903,7 +1450,7 @@ mod test {
     #[test]
     fn test_buffer_snapshot() {
         let buf = TerminalBuffer {
-            buf: vec![1, 5, 9, 11],
+            buf: cells("a\né漢"),
             width: 342,
             height: 9999,
         };
@@ -0,0 +1,302 @@
+//! Mapping from terminal colors to concrete RGB values. Renderers and exporters resolve colors
+//! through the same scheme so that they agree on what is shown
+
+use super::TerminalColor;
+
+pub type Rgb = (u8, u8, u8);
+/// Rgb with alpha, for what is drawn on top of the text rather than being part of it
+pub type Rgba = (u8, u8, u8, u8);
+
+/// A color that programs can query or change with OSC 4 and OSC 10/11/12
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ColorSlot {
+    Palette(u8),
+    Foreground,
+    Background,
+    Cursor,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ColorScheme {
+    pub foreground: Rgb,
+    pub background: Rgb,
+    pub cursor: Rgb,
+    /// The 256 indexed colors. The first 16 are the ANSI colors, with the named colors as the
+    /// first 8 entries. The rest default to xterm's color cube and grayscale ramp
+    pub palette: [Rgb; 256],
+    /// Background of selected text
+    pub selection: Rgba,
+    /// Background of search matches other than the current one
+    pub search_match: Rgba,
+    /// Background of the search match that was navigated to
+    pub search_current_match: Rgba,
+    /// Marker next to rows that wrap onto the next one, when turned on
+    pub wrap_indicator: Rgba,
+}
+
+/// The ANSI colors followed by the default values of the remaining indexed colors
+fn extended_palette(ansi: [Rgb; 16]) -> [Rgb; 256] {
+    let mut ret = [(0, 0, 0); 256];
+    ret[..16].copy_from_slice(&ansi);
+    for (idx, color) in ret.iter_mut().enumerate().skip(16) {
+        let idx = idx as u8;
+        *color = if idx < 232 {
+            // 216-color cube: 16 + 36 × r + 6 × g + b (r, g, b in 0..6)
+            let idx = idx - 16;
+            let r = idx / 36;
+            let g = (idx % 36) / 6;
+            let b = idx % 6;
+            let to_rgb = |v| if v == 0 { 0 } else { 55 + v * 40 };
+            (to_rgb(r), to_rgb(g), to_rgb(b))
+        } else {
+            // Grayscale: 8 + 10 × level (level in 0..24)
+            let gray = 8 + (idx - 232) * 10;
+            (gray, gray, gray)
+        };
+    }
+    ret
+}
+
+impl Default for ColorScheme {
+    fn default() -> Self {
+        ColorScheme {
+            foreground: (140, 140, 140),
+            background: (27, 27, 27),
+            cursor: (160, 160, 160),
+            palette: extended_palette([
+                (0, 0, 0),
+                (255, 0, 0),
+                (0, 255, 0),
+                (255, 255, 0),
+                (0, 0, 255),
+                (255, 0, 255),
+                (0, 255, 255),
+                (255, 255, 255),
+                (127, 127, 127),
+                (255, 0, 0),
+                (0, 255, 0),
+                (255, 255, 0),
+                (92, 92, 255),
+                (255, 0, 255),
+                (0, 255, 255),
+                (255, 255, 255),
+            ]),
+            selection: (60, 90, 150, 255),
+            search_match: (110, 90, 0, 255),
+            search_current_match: (200, 110, 0, 255),
+            wrap_indicator: (90, 90, 90, 255),
+        }
+    }
+}
+
+impl ColorScheme {
+    /// Dark text on a light background. Dark is the default
+    pub fn light() -> ColorScheme {
+        ColorScheme {
+            foreground: (40, 40, 40),
+            background: (250, 250, 250),
+            cursor: (90, 90, 90),
+            palette: extended_palette([
+                (0, 0, 0),
+                (194, 0, 0),
+                (0, 140, 0),
+                (150, 110, 0),
+                (0, 60, 200),
+                (170, 0, 170),
+                (0, 130, 140),
+                (180, 180, 180),
+                (100, 100, 100),
+                (230, 40, 40),
+                (30, 170, 30),
+                (180, 140, 0),
+                (60, 100, 240),
+                (200, 50, 200),
+                (0, 160, 170),
+                (120, 120, 120),
+            ]),
+            selection: (170, 200, 240, 255),
+            search_match: (250, 225, 130, 255),
+            search_current_match: (250, 160, 60, 255),
+            wrap_indicator: (170, 170, 170, 255),
+        }
+    }
+
+    pub fn indexed(&self, idx: u8) -> Rgb {
+        self.palette[idx as usize]
+    }
+
+    /// RGB value of `color`, or `None` for [`TerminalColor::Default`] since what that means
+    /// depends on whether it is used as a foreground or a background
+    pub fn resolve(&self, color: TerminalColor) -> Option<Rgb> {
+        let ret = match color {
+            TerminalColor::Default => return None,
+            TerminalColor::Black => self.palette[0],
+            TerminalColor::Red => self.palette[1],
+            TerminalColor::Green => self.palette[2],
+            TerminalColor::Yellow => self.palette[3],
+            TerminalColor::Blue => self.palette[4],
+            TerminalColor::Magenta => self.palette[5],
+            TerminalColor::Cyan => self.palette[6],
+            TerminalColor::White => self.palette[7],
+            TerminalColor::Indexed256(idx) => self.indexed(idx),
+            TerminalColor::Rgb(r, g, b) => (r, g, b),
+        };
+        Some(ret)
+    }
+
+    pub fn slot(&self, slot: ColorSlot) -> Rgb {
+        match slot {
+            ColorSlot::Palette(idx) => self.indexed(idx),
+            ColorSlot::Foreground => self.foreground,
+            ColorSlot::Background => self.background,
+            ColorSlot::Cursor => self.cursor,
+        }
+    }
+
+    pub fn set_slot(&mut self, slot: ColorSlot, rgb: Rgb) {
+        let color = match slot {
+            ColorSlot::Palette(idx) => &mut self.palette[idx as usize],
+            ColorSlot::Foreground => &mut self.foreground,
+            ColorSlot::Background => &mut self.background,
+            ColorSlot::Cursor => &mut self.cursor,
+        };
+        *color = rgb;
+    }
+
+    pub fn resolve_foreground(&self, color: TerminalColor) -> Rgb {
+        self.resolve(color).unwrap_or(self.foreground)
+    }
+
+    pub fn resolve_background(&self, color: TerminalColor) -> Rgb {
+        self.resolve(color).unwrap_or(self.background)
+    }
+}
+
+/// Parses `#rrggbb`
+pub fn parse_hex_rgb(s: &str) -> Option<Rgb> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.is_ascii() {
+        return None;
+    }
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some((component(0)?, component(2)?, component(4)?))
+}
+
+/// Parses `#rrggbbaa`, or `#rrggbb` for an opaque color
+pub fn parse_hex_rgba(s: &str) -> Option<Rgba> {
+    if s.len() == 7 {
+        let (r, g, b) = parse_hex_rgb(s)?;
+        return Some((r, g, b, 255));
+    }
+    let (rgb, alpha) = (s.get(..7)?, s.get(7..)?);
+    // from_str_radix would also take a sign
+    if alpha.len() != 2 || !alpha.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let (r, g, b) = parse_hex_rgb(rgb)?;
+    Some((r, g, b, u8::from_str_radix(alpha, 16).ok()?))
+}
+
+/// Parses the X11 color specifications that OSC color sequences use, `rgb:r/g/b` with 1 to 4
+/// hex digits per component, or `#rgb`/`#rrggbb`. Color names are not supported
+pub fn parse_color_spec(spec: &str) -> Option<Rgb> {
+    if let Some(components) = spec.strip_prefix("rgb:") {
+        let mut components = components.split('/').map(|c| {
+            if c.is_empty() || c.len() > 4 {
+                return None;
+            }
+            let v = u32::from_str_radix(c, 16).ok()?;
+            // Scale to 8 bits, e.g. "f" and "ffff" are both 255
+            let max = (1u32 << (4 * c.len())) - 1;
+            Some((v * 255 / max) as u8)
+        });
+        let ret = (
+            components.next()??,
+            components.next()??,
+            components.next()??,
+        );
+        if components.next().is_some() {
+            return None;
+        }
+        return Some(ret);
+    }
+
+    let hex = spec.strip_prefix('#')?;
+    if hex.len() == 3 && hex.is_ascii() {
+        let component = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).ok().map(|v| v * 17);
+        return Some((component(0)?, component(1)?, component(2)?));
+    }
+    parse_hex_rgb(spec)
+}
+
+/// Formats `rgb` the way xterm answers color queries, with 16 bits per component
+pub fn format_color_spec(rgb: Rgb) -> String {
+    format!(
+        "rgb:{0:02x}{0:02x}/{1:02x}{1:02x}/{2:02x}{2:02x}",
+        rgb.0, rgb.1, rgb.2
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let mut scheme = ColorScheme::default();
+        scheme.palette[1] = (200, 10, 10);
+
+        assert_eq!(scheme.resolve(TerminalColor::Red), Some((200, 10, 10)));
+        assert_eq!(
+            scheme.resolve(TerminalColor::Indexed256(1)),
+            Some((200, 10, 10))
+        );
+        assert_eq!(
+            scheme.resolve(TerminalColor::Indexed256(16)),
+            Some((0, 0, 0))
+        );
+        assert_eq!(
+            scheme.resolve(TerminalColor::Indexed256(21)),
+            Some((0, 0, 255))
+        );
+        assert_eq!(
+            scheme.resolve(TerminalColor::Indexed256(232)),
+            Some((8, 8, 8))
+        );
+        assert_eq!(scheme.resolve(TerminalColor::Rgb(1, 2, 3)), Some((1, 2, 3)));
+        assert_eq!(scheme.resolve(TerminalColor::Default), None);
+        assert_eq!(
+            scheme.resolve_background(TerminalColor::Default),
+            scheme.background
+        );
+    }
+
+    #[test]
+    fn test_parse_hex_rgb() {
+        assert_eq!(parse_hex_rgb("#ff8000"), Some((255, 128, 0)));
+        assert_eq!(parse_hex_rgb("#FF8000"), Some((255, 128, 0)));
+        assert_eq!(parse_hex_rgb("ff8000"), None);
+        assert_eq!(parse_hex_rgb("#ff80"), None);
+        assert_eq!(parse_hex_rgb("#gg8000"), None);
+
+        assert_eq!(parse_hex_rgba("#ff800040"), Some((255, 128, 0, 64)));
+        assert_eq!(parse_hex_rgba("#ff8000"), Some((255, 128, 0, 255)));
+        assert_eq!(parse_hex_rgba("#ff80004"), None);
+        assert_eq!(parse_hex_rgba("#ff8000+4"), None);
+    }
+
+    #[test]
+    fn test_color_spec() {
+        assert_eq!(parse_color_spec("rgb:ff/80/00"), Some((255, 128, 0)));
+        assert_eq!(parse_color_spec("rgb:ffff/8080/0000"), Some((255, 128, 0)));
+        assert_eq!(parse_color_spec("rgb:f/0/8"), Some((255, 0, 136)));
+        assert_eq!(parse_color_spec("#f80"), Some((255, 136, 0)));
+        assert_eq!(parse_color_spec("#ff8000"), Some((255, 128, 0)));
+        assert_eq!(parse_color_spec("rgb:ff/80"), None);
+        assert_eq!(parse_color_spec("rgb:ff/80/00/00"), None);
+        assert_eq!(parse_color_spec("rgb:fffff/0/0"), None);
+        assert_eq!(parse_color_spec("red"), None);
+
+        assert_eq!(format_color_spec((255, 128, 0)), "rgb:ffff/8080/0000");
+    }
+}
@@ -1,6 +1,6 @@
 use std::{num::TryFromIntError, ops::Range};
 
-use super::{recording::NotIntOfType, CursorState, TerminalColor};
+use super::{recording::NotIntOfType, CursorState, TerminalColor, UnderlineStyle};
 use crate::terminal_emulator::recording::SnapshotItem;
 use thiserror::Error;
 
@@ -59,6 +59,9 @@ fn adjust_existing_format_range(
                 end: existing_elem.end,
                 color: existing_elem.color,
                 bold: existing_elem.bold,
+                background: existing_elem.background,
+                underline: existing_elem.underline,
+                underline_color: existing_elem.underline_color,
             });
         }
 
@@ -144,6 +147,18 @@ enum LoadFormatTagSnapshotError {
     ColorNotString,
     #[error("failed to parse color from string")]
     ParseColor(()),
+    #[error("background not a string")]
+    BackgroundNotString,
+    #[error("failed to parse background from string")]
+    ParseBackground(()),
+    #[error("underline not a string")]
+    UnderlineNotString,
+    #[error("failed to parse underline from string")]
+    ParseUnderline(()),
+    #[error("underline color not a string")]
+    UnderlineColorNotString,
+    #[error("failed to parse underline color from string")]
+    ParseUnderlineColor(()),
 }
 
 #[derive(Debug, Error)]
@@ -163,6 +178,9 @@ mod format_tag_keys {
     pub const END: &str = "end";
     pub const COLOR: &str = "color";
     pub const BOLD: &str = "bold";
+    pub const BACKGROUND: &str = "background";
+    pub const UNDERLINE: &str = "underline";
+    pub const UNDERLINE_COLOR: &str = "underline_color";
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -171,6 +189,10 @@ pub struct FormatTag {
     pub end: usize,
     pub color: TerminalColor,
     pub bold: bool,
+    pub background: TerminalColor,
+    pub underline: UnderlineStyle,
+    /// [`TerminalColor::Default`] draws underlines in the text color
+    pub underline_color: TerminalColor,
 }
 
 impl FormatTag {
@@ -196,11 +218,41 @@ impl FormatTag {
         let color = color.into_string().map_err(|_| ColorNotString)?;
         let color = color.parse().map_err(ParseColor)?;
 
+        // Recordings made before background colors were tracked do not have this field
+        let background = match root.remove(format_tag_keys::BACKGROUND) {
+            Some(background) => {
+                let background = background.into_string().map_err(|_| BackgroundNotString)?;
+                background.parse().map_err(ParseBackground)?
+            }
+            None => TerminalColor::Default,
+        };
+
+        let underline = match root.remove(format_tag_keys::UNDERLINE) {
+            Some(underline) => {
+                let underline = underline.into_string().map_err(|_| UnderlineNotString)?;
+                underline.parse().map_err(ParseUnderline)?
+            }
+            None => UnderlineStyle::None,
+        };
+
+        let underline_color = match root.remove(format_tag_keys::UNDERLINE_COLOR) {
+            Some(underline_color) => {
+                let underline_color = underline_color
+                    .into_string()
+                    .map_err(|_| UnderlineColorNotString)?;
+                underline_color.parse().map_err(ParseUnderlineColor)?
+            }
+            None => TerminalColor::Default,
+        };
+
         Ok(FormatTag {
             start,
             end,
             bold,
             color,
+            background,
+            underline,
+            underline_color,
         })
     }
 
@@ -220,6 +272,18 @@ impl FormatTag {
                 self.color.to_string().into(),
             ),
             (format_tag_keys::BOLD.to_string(), self.bold.into()),
+            (
+                format_tag_keys::BACKGROUND.to_string(),
+                self.background.to_string().into(),
+            ),
+            (
+                format_tag_keys::UNDERLINE.to_string(),
+                self.underline.to_string().into(),
+            ),
+            (
+                format_tag_keys::UNDERLINE_COLOR.to_string(),
+                self.underline_color.to_string().into(),
+            ),
         ];
         Ok(SnapshotItem::Map(arr.into()))
     }
@@ -249,6 +313,9 @@ impl FormatTracker {
                 end: usize::MAX,
                 color: TerminalColor::Default,
                 bold: false,
+                background: TerminalColor::Default,
+                underline: UnderlineStyle::None,
+                underline_color: TerminalColor::Default,
             }],
         }
     }
@@ -282,11 +349,14 @@ impl FormatTracker {
             end: range.end,
             color: cursor.color,
             bold: cursor.bold,
+            background: cursor.background,
+            underline: cursor.underline,
+            underline_color: cursor.underline_color,
         });
 
         // FIXME: Insertion sort
         // FIXME: Merge adjacent
-        self.color_info.sort_by(|a, b| a.start.cmp(&b.start));
+        self.color_info.sort_by_key(|a| a.start);
     }
 
     /// Move all tags > range.start to range.start + range.len
@@ -369,6 +439,9 @@ mod test {
             pos: CursorPos { x: 0, y: 0 },
             color: TerminalColor::Default,
             bold: false,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
         };
 
         cursor_state.color = TerminalColor::Yellow;
@@ -381,19 +454,28 @@ mod test {
                     start: 0,
                     end: 3,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 3,
                     end: 10,
                     color: TerminalColor::Yellow,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 10,
                     end: usize::MAX,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ]
         );
@@ -408,31 +490,46 @@ mod test {
                     start: 0,
                     end: 3,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 3,
                     end: 5,
                     color: TerminalColor::Yellow,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 5,
                     end: 7,
                     color: TerminalColor::Blue,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 7,
                     end: 10,
                     color: TerminalColor::Yellow,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 10,
                     end: usize::MAX,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ]
         );
@@ -447,37 +544,55 @@ mod test {
                     start: 0,
                     end: 3,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 3,
                     end: 5,
                     color: TerminalColor::Yellow,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 5,
                     end: 7,
                     color: TerminalColor::Blue,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 7,
                     end: 9,
                     color: TerminalColor::Green,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 9,
                     end: 10,
                     color: TerminalColor::Yellow,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 10,
                     end: usize::MAX,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ]
         );
@@ -493,31 +608,46 @@ mod test {
                     start: 0,
                     end: 3,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 3,
                     end: 5,
                     color: TerminalColor::Yellow,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 5,
                     end: 6,
                     color: TerminalColor::Blue,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 6,
                     end: 11,
                     color: TerminalColor::Red,
-                    bold: true
+                    bold: true,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 11,
                     end: usize::MAX,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ]
         );
@@ -540,6 +670,9 @@ mod test {
             pos: CursorPos { x: 0, y: 0 },
             color: TerminalColor::Blue,
             bold: false,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
         };
         format_tracker.push_range(&cursor, 0..10);
         cursor.color = TerminalColor::Red;
@@ -553,19 +686,28 @@ mod test {
                     start: 0,
                     end: 8,
                     color: TerminalColor::Blue,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 8,
                     end: 18,
                     color: TerminalColor::Red,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 18,
                     end: usize::MAX,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 }
             ]
         );
@@ -578,19 +720,28 @@ mod test {
                     start: 0,
                     end: 6,
                     color: TerminalColor::Blue,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 6,
                     end: 16,
                     color: TerminalColor::Red,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 16,
                     end: usize::MAX,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 }
             ]
         );
@@ -603,19 +754,28 @@ mod test {
                     start: 0,
                     end: 4,
                     color: TerminalColor::Blue,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 4,
                     end: 14,
                     color: TerminalColor::Red,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 14,
                     end: usize::MAX,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 }
             ]
         );
@@ -628,19 +788,28 @@ mod test {
                     start: 0,
                     end: 2,
                     color: TerminalColor::Blue,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 2,
                     end: 9,
                     color: TerminalColor::Red,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 9,
                     end: usize::MAX,
                     color: TerminalColor::Default,
-                    bold: false
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 }
             ]
         );
@@ -653,6 +822,9 @@ mod test {
             pos: CursorPos { x: 0, y: 0 },
             color: TerminalColor::Blue,
             bold: false,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
         };
         format_tracker.push_range(&cursor, 0..5);
         cursor.color = TerminalColor::Red;
@@ -666,18 +838,27 @@ mod test {
                     end: 5,
                     color: TerminalColor::Blue,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 5,
                     end: 10,
                     color: TerminalColor::Red,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 10,
                     end: usize::MAX,
                     color: TerminalColor::Default,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ]
         );
@@ -692,18 +873,27 @@ mod test {
                     end: 8,
                     color: TerminalColor::Blue,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 8,
                     end: 13,
                     color: TerminalColor::Red,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 13,
                     end: usize::MAX,
                     color: TerminalColor::Default,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ]
         );
@@ -718,18 +908,27 @@ mod test {
                     end: 8,
                     color: TerminalColor::Blue,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 8,
                     end: 13,
                     color: TerminalColor::Red,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 13,
                     end: usize::MAX,
                     color: TerminalColor::Default,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ]
         );
@@ -745,18 +944,27 @@ mod test {
                     end: 8,
                     color: TerminalColor::Blue,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 8,
                     end: 15,
                     color: TerminalColor::Red,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 15,
                     end: usize::MAX,
                     color: TerminalColor::Default,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ]
         );
@@ -770,6 +978,9 @@ mod test {
             end: usize::MAX,
             color: TerminalColor::Blue,
             bold: true,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
         };
 
         let loaded = FormatTag::from_snapshot(tag.snapshot().expect("failed to snapshot"))
@@ -782,6 +993,9 @@ mod test {
             end: 105,
             color: TerminalColor::Red,
             bold: false,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
         };
         let loaded = FormatTag::from_snapshot(tag.snapshot().expect("failed to snapshot"))
             .expect("failed to load snapshot");
@@ -797,12 +1011,18 @@ mod test {
                     end: 5,
                     color: TerminalColor::Black,
                     bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
                 FormatTag {
                     start: 5,
                     end: usize::MAX,
                     color: TerminalColor::Red,
                     bold: true,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
                 },
             ],
         };
@@ -0,0 +1,155 @@
+//! Export of styled rows as HTML, for pasting terminal output into documents. All styling is
+//! inline so that the output survives being pasted into places that strip stylesheets
+
+use std::fmt::Write;
+
+use super::{
+    cells_to_string,
+    color_scheme::{ColorScheme, Rgb},
+    CellStyle, Row, TerminalColor, UnderlineStyle,
+};
+
+fn css_color(rgb: Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", rgb.0, rgb.1, rgb.2)
+}
+
+fn push_escaped(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+}
+
+fn span_style(style: &CellStyle, scheme: &ColorScheme) -> String {
+    let mut ret = String::new();
+    if style.color != TerminalColor::Default {
+        let _ = write!(
+            ret,
+            "color:{};",
+            css_color(scheme.resolve_foreground(style.color))
+        );
+    }
+    if style.background != TerminalColor::Default {
+        let _ = write!(
+            ret,
+            "background-color:{};",
+            css_color(scheme.resolve_background(style.background))
+        );
+    }
+    if style.bold {
+        ret.push_str("font-weight:bold;");
+    }
+    let underline = match style.underline {
+        UnderlineStyle::None => None,
+        UnderlineStyle::Single => Some("solid"),
+        UnderlineStyle::Double => Some("double"),
+        UnderlineStyle::Curly => Some("wavy"),
+        UnderlineStyle::Dotted => Some("dotted"),
+        UnderlineStyle::Dashed => Some("dashed"),
+    };
+    if let Some(underline) = underline {
+        let _ = write!(ret, "text-decoration:underline {}", underline);
+        if let Some(color) = scheme.resolve(style.underline_color) {
+            let _ = write!(ret, " {}", css_color(color));
+        }
+        ret.push(';');
+    }
+    ret
+}
+
+/// Render `rows` as a `<pre>` block. Default colors come from the block itself, so only cells
+/// that differ from them get a span. Wrapped rows are joined the same way copied text is
+pub fn rows_to_html<'a>(rows: impl Iterator<Item = Row<'a>>, scheme: &ColorScheme) -> String {
+    let mut out = format!(
+        "<pre style=\"color:{};background-color:{};font-family:monospace\">",
+        css_color(scheme.foreground),
+        css_color(scheme.background)
+    );
+
+    let mut first = true;
+    let mut prev_wrapped = false;
+    for row in rows {
+        if !first && !prev_wrapped {
+            out.push('\n');
+        }
+        first = false;
+        prev_wrapped = row.is_wrapped();
+
+        for run in row.runs() {
+            let text = cells_to_string(run.text);
+            let style = span_style(&run.style, scheme);
+            if style.is_empty() {
+                push_escaped(&mut out, &text);
+            } else {
+                let _ = write!(out, "<span style=\"{}\">", style);
+                push_escaped(&mut out, &text);
+                out.push_str("</span>");
+            }
+        }
+    }
+
+    out.push_str("</pre>");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::terminal_emulator::{FormatTag, LineSize};
+    use crate::test_util::cells;
+
+    #[test]
+    fn test_rows_to_html() {
+        let buf = &cells("a<b\nred");
+        let tags = [
+            FormatTag {
+                start: 0,
+                end: 4,
+                color: TerminalColor::Default,
+                background: TerminalColor::Default,
+                underline: UnderlineStyle::None,
+                underline_color: TerminalColor::Default,
+                bold: false,
+            },
+            FormatTag {
+                start: 4,
+                end: usize::MAX,
+                color: TerminalColor::Red,
+                background: TerminalColor::Indexed256(4),
+                underline: UnderlineStyle::Curly,
+                underline_color: TerminalColor::Rgb(1, 2, 3),
+                bold: true,
+            },
+        ];
+        let rows = [
+            Row::new(buf, 0..3, &tags, LineSize::SingleWidth),
+            Row::new(buf, 4..7, &tags, LineSize::SingleWidth),
+        ];
+
+        let html = rows_to_html(rows.into_iter(), &ColorScheme::default());
+        assert_eq!(
+            html,
+            "<pre style=\"color:#8c8c8c;background-color:#1b1b1b;font-family:monospace\">\
+             a&lt;b\n\
+             <span style=\"color:#ff0000;background-color:#0000ff;font-weight:bold;\
+             text-decoration:underline wavy #010203;\">red</span>\
+             </pre>"
+        );
+    }
+
+    #[test]
+    fn test_wrapped_rows_to_html() {
+        let buf = &cells("abcd");
+        let rows = [
+            Row::new(buf, 0..2, &[], LineSize::SingleWidth),
+            Row::new(buf, 2..4, &[], LineSize::SingleWidth),
+        ];
+        let html = rows_to_html(rows.into_iter(), &ColorScheme::default());
+        assert!(html.contains(">abcd<"));
+    }
+}
@@ -0,0 +1,345 @@
+//! Talking to a session that a daemon keeps running, see `termie::daemon`. The daemon sends a
+//! snapshot of its terminal first and then everything the child writes, and takes input and
+//! window sizes back. Messages are framed as a tag byte and a big endian u32 length followed by
+//! the payload
+
+use thiserror::Error;
+
+use std::{
+    io::Read,
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::net::UnixStream,
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+use super::{
+    reader::{OutputReader, OutputWaker},
+    write_all, Pty, ReadResponse, TermIo, TermIoErr,
+};
+use crate::terminal_emulator::{ParseSnapshotError, SnapshotItem};
+
+const SNAPSHOT_TAG: u8 = 0;
+const OUTPUT_TAG: u8 = 1;
+const INPUT_TAG: u8 = 2;
+const RESIZE_TAG: u8 = 3;
+
+const HEADER_LEN: usize = 5;
+/// Anything longer is taken to be a different protocol rather than a huge snapshot
+const MAX_PAYLOAD_LEN: usize = 1 << 30;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionMessage {
+    /// [`SnapshotItem::to_bytes`] of the daemon's terminal, sent once a client attaches
+    Snapshot(Vec<u8>),
+    /// What the child wrote, from the daemon
+    Output(Vec<u8>),
+    /// What the user typed, from the client
+    Input(Vec<u8>),
+    /// The client's terminal size
+    Resize { width: u16, height: u16 },
+}
+
+impl SessionMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let resize;
+        let (tag, payload) = match self {
+            SessionMessage::Snapshot(data) => (SNAPSHOT_TAG, data.as_slice()),
+            SessionMessage::Output(data) => (OUTPUT_TAG, data.as_slice()),
+            SessionMessage::Input(data) => (INPUT_TAG, data.as_slice()),
+            SessionMessage::Resize { width, height } => {
+                resize = [width.to_be_bytes(), height.to_be_bytes()].concat();
+                (RESIZE_TAG, resize.as_slice())
+            }
+        };
+        let len = u32::try_from(payload.len()).expect("message too long to frame");
+        let mut ret = Vec::with_capacity(HEADER_LEN + payload.len());
+        ret.push(tag);
+        ret.extend(len.to_be_bytes());
+        ret.extend(payload);
+        ret
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum InvalidSessionMessage {
+    #[error("unknown message tag {0}")]
+    UnknownTag(u8),
+    #[error("message of {0} bytes is too long")]
+    TooLong(usize),
+    #[error("resize message is {0} bytes instead of 4")]
+    ResizeLength(usize),
+}
+
+/// Splits a byte stream back into [`SessionMessage`]s, however it was chunked on the way
+#[derive(Default)]
+pub struct SessionDecoder {
+    buf: Vec<u8>,
+}
+
+impl SessionDecoder {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend(data);
+    }
+
+    /// The next complete message, if all of it arrived
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<SessionMessage>, InvalidSessionMessage> {
+        let Some(header) = self.buf.get(..HEADER_LEN) else {
+            return Ok(None);
+        };
+        let tag = header[0];
+        let len = u32::from_be_bytes(header[1..].try_into().expect("header is 5 bytes")) as usize;
+        if len > MAX_PAYLOAD_LEN {
+            return Err(InvalidSessionMessage::TooLong(len));
+        }
+        if self.buf.len() < HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let payload: Vec<u8> = self
+            .buf
+            .drain(..HEADER_LEN + len)
+            .skip(HEADER_LEN)
+            .collect();
+        let ret = match tag {
+            SNAPSHOT_TAG => SessionMessage::Snapshot(payload),
+            OUTPUT_TAG => SessionMessage::Output(payload),
+            INPUT_TAG => SessionMessage::Input(payload),
+            RESIZE_TAG => {
+                let [w1, w2, h1, h2] = payload[..] else {
+                    return Err(InvalidSessionMessage::ResizeLength(payload.len()));
+                };
+                SessionMessage::Resize {
+                    width: u16::from_be_bytes([w1, w2]),
+                    height: u16::from_be_bytes([h1, h2]),
+                }
+            }
+            _ => return Err(InvalidSessionMessage::UnknownTag(tag)),
+        };
+        Ok(Some(ret))
+    }
+}
+
+#[derive(Debug, Error)]
+enum CreateAttachIoErrorKind {
+    #[error("failed to connect to {0}")]
+    Connect(String, #[source] std::io::Error),
+    #[error("failed to read the snapshot")]
+    ReadSnapshot(#[source] std::io::Error),
+    #[error("the daemon hung up before sending a snapshot")]
+    Closed,
+    #[error("the daemon sent an invalid message")]
+    InvalidMessage(#[source] InvalidSessionMessage),
+    #[error("the daemon sent output before the snapshot")]
+    MissingSnapshot,
+    #[error("failed to parse the snapshot")]
+    ParseSnapshot(#[source] ParseSnapshotError),
+    #[error("failed to set socket as non-blocking")]
+    SetNonblock(#[source] std::io::Error),
+    #[error("failed to duplicate socket for the reader thread")]
+    CloneSocket(#[source] std::io::Error),
+    #[error("failed to spawn reader thread")]
+    SpawnReader(#[source] std::io::Error),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct CreateAttachIoError(#[from] CreateAttachIoErrorKind);
+
+#[derive(Debug, Error)]
+enum AttachIoErrKind {
+    #[error("failed to write to the daemon")]
+    Write(#[source] nix::errno::Errno),
+    #[error("the daemon sent an invalid message")]
+    InvalidMessage(#[source] InvalidSessionMessage),
+    #[error("the daemon's snapshot was already taken")]
+    SnapshotTaken,
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct AttachIoErr(#[from] AttachIoErrKind);
+
+/// The client end of a daemon's socket, spawned from the socket's path. The session outlives
+/// the client, so there is no child to report on and dropping only detaches
+pub struct AttachIo {
+    // Dropped first, the reader thread is stopped before the socket is closed
+    reader: OutputReader,
+    stream: UnixStream,
+    decoder: SessionDecoder,
+    // Decoded output that did not fit into the emulator's buffer yet
+    output: Vec<u8>,
+    snapshot: Option<SnapshotItem>,
+}
+
+impl AttachIo {
+    /// The state of the daemon's terminal when attaching, for
+    /// [`crate::terminal_emulator::TerminalEmulator::from_snapshot`]. Output after it arrives
+    /// through [`TermIo::read`]. It is only there once
+    pub fn take_snapshot(&mut self) -> Result<SnapshotItem, AttachIoErr> {
+        Ok(self.snapshot.take().ok_or(AttachIoErrKind::SnapshotTaken)?)
+    }
+
+    fn send(&self, message: &SessionMessage) -> Result<(), AttachIoErr> {
+        write_all(self.stream.as_fd(), &message.encode())
+            .map_err(|e| AttachIoErrKind::Write(e).into())
+    }
+}
+
+impl Pty for AttachIo {
+    type Options = PathBuf;
+    type SpawnError = CreateAttachIoError;
+    type Error = AttachIoErr;
+
+    fn spawn(path: &PathBuf) -> Result<AttachIo, CreateAttachIoError> {
+        let mut stream = UnixStream::connect(path)
+            .map_err(|e| CreateAttachIoErrorKind::Connect(path.display().to_string(), e))?;
+
+        // The snapshot comes first, anything read along with it is already output
+        let mut decoder = SessionDecoder::default();
+        let mut buf = vec![0; 4096];
+        let snapshot = loop {
+            match decoder.next() {
+                Ok(Some(SessionMessage::Snapshot(data))) => break data,
+                Ok(Some(_)) => return Err(CreateAttachIoErrorKind::MissingSnapshot.into()),
+                Ok(None) => (),
+                Err(e) => return Err(CreateAttachIoErrorKind::InvalidMessage(e).into()),
+            }
+            match stream.read(&mut buf) {
+                Ok(0) => return Err(CreateAttachIoErrorKind::Closed.into()),
+                Ok(len) => decoder.push(&buf[..len]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(CreateAttachIoErrorKind::ReadSnapshot(e).into()),
+            }
+        };
+        let snapshot =
+            SnapshotItem::from_bytes(&snapshot).map_err(CreateAttachIoErrorKind::ParseSnapshot)?;
+
+        stream
+            .set_nonblocking(true)
+            .map_err(CreateAttachIoErrorKind::SetNonblock)?;
+        let reader_stream = stream
+            .try_clone()
+            .map_err(CreateAttachIoErrorKind::CloneSocket)?;
+        let reader = OutputReader::spawn(OwnedFd::from(reader_stream))
+            .map_err(CreateAttachIoErrorKind::SpawnReader)?;
+        Ok(AttachIo {
+            reader,
+            stream,
+            decoder,
+            output: Vec::new(),
+            snapshot: Some(snapshot),
+        })
+    }
+
+    fn child_exit_status(&mut self) -> Result<Option<i32>, AttachIoErr> {
+        Ok(None)
+    }
+
+    fn has_foreground_job(&self) -> Result<bool, AttachIoErr> {
+        Ok(false)
+    }
+
+    fn foreground_process_name(&self) -> Option<String> {
+        None
+    }
+
+    fn remote_host(&self) -> Option<String> {
+        None
+    }
+
+    fn set_output_waker(&self, waker: OutputWaker, coalesce_window: Duration) {
+        self.reader.set_waker(waker, coalesce_window);
+    }
+
+    /// The daemon hangs up once the child exits
+    fn is_output_closed(&mut self) -> bool {
+        self.output.is_empty() && self.reader.is_closed()
+    }
+}
+
+impl TermIo for AttachIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
+        let mut chunk = vec![0; 4096];
+        loop {
+            match self.reader.read(&mut chunk) {
+                0 => break,
+                len => self.decoder.push(&chunk[..len]),
+            }
+        }
+        loop {
+            match self.decoder.next() {
+                Ok(Some(SessionMessage::Output(data))) => self.output.extend(data),
+                Ok(Some(message)) => {
+                    warn!("Ignoring unexpected message from the daemon: {message:?}")
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    return Err(Box::new(AttachIoErr::from(
+                        AttachIoErrKind::InvalidMessage(e),
+                    )))
+                }
+            }
+        }
+
+        let len = buf.len().min(self.output.len());
+        buf[..len].copy_from_slice(&self.output[..len]);
+        self.output.drain(..len);
+        match len {
+            0 => Ok(ReadResponse::Empty),
+            v => Ok(ReadResponse::Success(v)),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
+        self.send(&SessionMessage::Input(buf.to_vec()))?;
+        Ok(buf.len())
+    }
+
+    fn set_win_size(&mut self, width: usize, height: usize) -> Result<(), TermIoErr> {
+        self.send(&SessionMessage::Resize {
+            width: width.try_into().unwrap_or(u16::MAX),
+            height: height.try_into().unwrap_or(u16::MAX),
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_decoder() {
+        let messages = [
+            SessionMessage::Snapshot(b"{}".to_vec()),
+            SessionMessage::Output(b"hello\xff".to_vec()),
+            SessionMessage::Input(Vec::new()),
+            SessionMessage::Resize {
+                width: 300,
+                height: 40,
+            },
+        ];
+        let encoded: Vec<u8> = messages.iter().flat_map(SessionMessage::encode).collect();
+
+        // One byte at a time, so that every message is split
+        let mut decoder = SessionDecoder::default();
+        let mut decoded = Vec::new();
+        for b in encoded {
+            decoder.push(&[b]);
+            while let Some(message) = decoder.next().expect("failed to decode") {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, messages);
+
+        decoder.push(&[9, 0, 0, 0, 0]);
+        assert!(decoder.next().is_err());
+        let mut decoder = SessionDecoder::default();
+        decoder.push(&[RESIZE_TAG, 0, 0, 0, 1, 0]);
+        assert!(decoder.next().is_err());
+    }
+}
@@ -0,0 +1,320 @@
+//! ConPTY, the pseudo console of Windows 10 1809 and later. The console host runs the child and
+//! translates between its console API calls and VT sequences on a pair of pipes
+//! https://learn.microsoft.com/en-us/windows/console/creating-a-pseudoconsole-session
+
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::Write,
+    os::windows::{
+        ffi::OsStrExt,
+        io::{AsRawHandle, FromRawHandle, OwnedHandle},
+    },
+    path::Path,
+    ptr,
+    time::Duration,
+};
+
+use thiserror::Error;
+use windows_sys::Win32::{
+    Foundation::{HANDLE, WAIT_OBJECT_0},
+    System::{
+        Console::{ClosePseudoConsole, CreatePseudoConsole, ResizePseudoConsole, COORD, HPCON},
+        Pipes::CreatePipe,
+        Threading::{
+            CreateProcessW, DeleteProcThreadAttributeList, GetExitCodeProcess,
+            InitializeProcThreadAttributeList, UpdateProcThreadAttribute, WaitForSingleObject,
+            EXTENDED_STARTUPINFO_PRESENT, LPPROC_THREAD_ATTRIBUTE_LIST, PROCESS_INFORMATION,
+            STARTF_USESTDHANDLES, STARTUPINFOEXW,
+        },
+    },
+};
+
+use super::{
+    reader::{OutputReader, OutputWaker},
+    Pty, ReadResponse, ShellOptions, TermIo, TermIoErr,
+};
+
+/// ProcThreadAttributeValue(22, FALSE, TRUE, FALSE)
+const PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE: usize = 0x0002_0016;
+
+#[derive(Debug, Error)]
+enum CreatePtyIoErrorKind {
+    #[error("failed to create pipes")]
+    CreatePipes(#[source] std::io::Error),
+    #[error("failed to create pseudo console")]
+    CreateConsole(#[source] std::io::Error),
+    #[error("failed to set up process attributes")]
+    Attributes(#[source] std::io::Error),
+    #[error("failed to spawn shell")]
+    SpawnShell(#[source] std::io::Error),
+    #[error("failed to spawn reader thread")]
+    SpawnReader(#[source] std::io::Error),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct CreatePtyIoError(#[from] CreatePtyIoErrorKind);
+
+#[derive(Debug, Error)]
+enum PtyIoErrKind {
+    #[error("size too large")]
+    SizeTooLarge(#[source] std::num::TryFromIntError),
+    #[error("failed to resize pseudo console")]
+    Resize(#[source] std::io::Error),
+    #[error("failed to write to pseudo console")]
+    Write(#[source] std::io::Error),
+    #[error("failed to get exit code of child process")]
+    ExitCode(#[source] std::io::Error),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct PtyIoErr(#[from] PtyIoErrKind);
+
+fn check_hresult(hr: i32) -> std::io::Result<()> {
+    if hr < 0 {
+        return Err(std::io::Error::from_raw_os_error(hr));
+    }
+    Ok(())
+}
+
+fn check_bool(ret: i32) -> std::io::Result<()> {
+    if ret == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Read and write end
+fn create_pipe() -> std::io::Result<(OwnedHandle, OwnedHandle)> {
+    let mut read: HANDLE = unsafe { std::mem::zeroed() };
+    let mut write: HANDLE = unsafe { std::mem::zeroed() };
+    unsafe {
+        check_bool(CreatePipe(&mut read, &mut write, ptr::null(), 0))?;
+        Ok((
+            OwnedHandle::from_raw_handle(read as _),
+            OwnedHandle::from_raw_handle(write as _),
+        ))
+    }
+}
+
+fn coord(width: usize, height: usize) -> Result<COORD, std::num::TryFromIntError> {
+    Ok(COORD {
+        X: width.try_into()?,
+        Y: height.try_into()?,
+    })
+}
+
+struct PseudoConsole(HPCON);
+
+impl Drop for PseudoConsole {
+    fn drop(&mut self) {
+        unsafe { ClosePseudoConsole(self.0) };
+    }
+}
+
+/// Tells CreateProcessW to attach the child to the pseudo console
+struct AttributeList {
+    // usize for the alignment of the opaque list
+    buf: Vec<usize>,
+}
+
+impl AttributeList {
+    fn new(console: &PseudoConsole) -> std::io::Result<AttributeList> {
+        let mut size = 0;
+        // Fails, but reports the size that is needed
+        unsafe { InitializeProcThreadAttributeList(ptr::null_mut(), 1, 0, &mut size) };
+        let mut list = AttributeList {
+            buf: vec![0; size.div_ceil(std::mem::size_of::<usize>())],
+        };
+        unsafe {
+            check_bool(InitializeProcThreadAttributeList(
+                list.as_ptr(),
+                1,
+                0,
+                &mut size,
+            ))?;
+            // The attribute value is the console handle itself rather than a pointer to it
+            check_bool(UpdateProcThreadAttribute(
+                list.as_ptr(),
+                0,
+                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE,
+                console.0 as _,
+                std::mem::size_of::<HPCON>(),
+                ptr::null_mut(),
+                ptr::null(),
+            ))?;
+        }
+        Ok(list)
+    }
+
+    fn as_ptr(&mut self) -> LPPROC_THREAD_ATTRIBUTE_LIST {
+        self.buf.as_mut_ptr() as LPPROC_THREAD_ATTRIBUTE_LIST
+    }
+}
+
+impl Drop for AttributeList {
+    fn drop(&mut self) {
+        unsafe { DeleteProcThreadAttributeList(self.as_ptr()) };
+    }
+}
+
+/// The shell from %ComSpec%, with `/C` for a command. There are no login shells on Windows
+fn command_line(options: &ShellOptions) -> Vec<u16> {
+    let shell = std::env::var("ComSpec").unwrap_or_else(|_| "cmd.exe".to_string());
+    let command_line = match &options.command {
+        Some(command) => format!("{shell} /C {command}"),
+        None => shell,
+    };
+    OsStr::new(&command_line)
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect()
+}
+
+fn spawn_shell(
+    console: &PseudoConsole,
+    options: &ShellOptions,
+) -> Result<OwnedHandle, CreatePtyIoErrorKind> {
+    let mut attributes = AttributeList::new(console).map_err(CreatePtyIoErrorKind::Attributes)?;
+    let mut startup_info: STARTUPINFOEXW = unsafe { std::mem::zeroed() };
+    startup_info.StartupInfo.cb = std::mem::size_of::<STARTUPINFOEXW>() as u32;
+    // Without this the child would also get our own standard handles when they are redirected
+    startup_info.StartupInfo.dwFlags = STARTF_USESTDHANDLES;
+    startup_info.lpAttributeList = attributes.as_ptr();
+
+    let mut command_line = command_line(options);
+    let cwd: Option<Vec<u16>> = options.cwd.as_ref().map(|cwd| {
+        cwd.as_os_str()
+            .encode_wide()
+            .chain(std::iter::once(0))
+            .collect()
+    });
+    let mut process_info: PROCESS_INFORMATION = unsafe { std::mem::zeroed() };
+    unsafe {
+        check_bool(CreateProcessW(
+            ptr::null(),
+            command_line.as_mut_ptr(),
+            ptr::null(),
+            ptr::null(),
+            0,
+            EXTENDED_STARTUPINFO_PRESENT,
+            ptr::null(),
+            cwd.as_ref().map_or(ptr::null(), |cwd| cwd.as_ptr()),
+            &startup_info.StartupInfo,
+            &mut process_info,
+        ))
+        .map_err(CreatePtyIoErrorKind::SpawnShell)?;
+        drop(OwnedHandle::from_raw_handle(process_info.hThread as _));
+        Ok(OwnedHandle::from_raw_handle(process_info.hProcess as _))
+    }
+}
+
+pub struct PtyIo {
+    // Closed first, which ends the output the reader thread is blocked on
+    console: PseudoConsole,
+    reader: OutputReader,
+    input: File,
+    process: OwnedHandle,
+}
+
+impl Pty for PtyIo {
+    type Options = ShellOptions;
+    type SpawnError = CreatePtyIoError;
+    type Error = PtyIoErr;
+
+    fn spawn(options: &ShellOptions) -> Result<PtyIo, CreatePtyIoError> {
+        use CreatePtyIoErrorKind::*;
+
+        let (console_input, input) = create_pipe().map_err(CreatePipes)?;
+        let (output, console_output) = create_pipe().map_err(CreatePipes)?;
+
+        let mut console: HPCON = unsafe { std::mem::zeroed() };
+        let size = coord(80, 24).expect("default size fits");
+        unsafe {
+            check_hresult(CreatePseudoConsole(
+                size,
+                console_input.as_raw_handle() as HANDLE,
+                console_output.as_raw_handle() as HANDLE,
+                0,
+                &mut console,
+            ))
+            .map_err(CreateConsole)?;
+        }
+        let console = PseudoConsole(console);
+        // The console host has its own copies of its ends of the pipes
+        drop((console_input, console_output));
+
+        let process = spawn_shell(&console, options)?;
+        let reader = OutputReader::spawn_blocking(File::from(output)).map_err(SpawnReader)?;
+        Ok(PtyIo {
+            console,
+            reader,
+            input: File::from(input),
+            process,
+        })
+    }
+
+    fn options_in_directory(options: &ShellOptions, dir: &Path) -> Option<ShellOptions> {
+        options.cwd.is_none().then(|| ShellOptions {
+            cwd: Some(dir.to_path_buf()),
+            ..options.clone()
+        })
+    }
+
+    fn child_exit_status(&mut self) -> Result<Option<i32>, PtyIoErr> {
+        let process = self.process.as_raw_handle() as HANDLE;
+        if unsafe { WaitForSingleObject(process, 0) } != WAIT_OBJECT_0 {
+            return Ok(None);
+        }
+
+        let mut code = 0;
+        let res = unsafe { check_bool(GetExitCodeProcess(process, &mut code)) };
+        res.map_err(PtyIoErrKind::ExitCode)?;
+        Ok(Some(code as i32))
+    }
+
+    /// Job control is not tracked for the pseudo console yet
+    fn has_foreground_job(&self) -> Result<bool, PtyIoErr> {
+        Ok(false)
+    }
+
+    fn foreground_process_name(&self) -> Option<String> {
+        None
+    }
+
+    fn remote_host(&self) -> Option<String> {
+        None
+    }
+
+    fn set_output_waker(&self, waker: OutputWaker, coalesce_window: Duration) {
+        self.reader.set_waker(waker, coalesce_window);
+    }
+
+    fn is_output_closed(&mut self) -> bool {
+        self.reader.is_closed()
+    }
+}
+
+impl TermIo for PtyIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
+        match self.reader.read(buf) {
+            0 => Ok(ReadResponse::Empty),
+            v => Ok(ReadResponse::Success(v)),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
+        self.input
+            .write(buf)
+            .map_err(|e| Box::new(PtyIoErr::from(PtyIoErrKind::Write(e))).into())
+    }
+
+    fn set_win_size(&mut self, width: usize, height: usize) -> Result<(), TermIoErr> {
+        let size =
+            coord(width, height).map_err(|e| PtyIoErr::from(PtyIoErrKind::SizeTooLarge(e)))?;
+        check_hresult(unsafe { ResizePseudoConsole(self.console.0, size) })
+            .map_err(|e| Box::new(PtyIoErr::from(PtyIoErrKind::Resize(e))).into())
+    }
+}
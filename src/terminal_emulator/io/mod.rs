@@ -1,5 +1,37 @@
+#[cfg(unix)]
+mod attach;
+#[cfg(windows)]
+mod conpty;
+#[cfg(unix)]
+mod net;
+#[cfg(unix)]
 mod pty;
-pub use pty::{CreatePtyIoError, PtyIo};
+mod reader;
+#[cfg(unix)]
+mod remote_host;
+#[cfg(unix)]
+mod serial;
+#[cfg(unix)]
+mod telnet;
+#[cfg(unix)]
+pub use attach::{
+    AttachIo, AttachIoErr, CreateAttachIoError, InvalidSessionMessage, SessionDecoder,
+    SessionMessage,
+};
+#[cfg(windows)]
+pub use conpty::{CreatePtyIoError, PtyIo, PtyIoErr};
+#[cfg(unix)]
+pub use net::{CreateNetIoError, NetIo, NetIoErr, NetOptions};
+#[cfg(unix)]
+pub use pty::{CreatePtyIoError, PtyIo, PtyIoErr};
+pub use reader::OutputWaker;
+#[cfg(unix)]
+pub use serial::{CreateSerialIoError, SerialIo, SerialIoErr, SerialOptions};
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 pub type TermIoErr = Box<dyn std::error::Error>;
 
@@ -13,3 +45,131 @@ pub trait TermIo {
     fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr>;
     fn set_win_size(&mut self, width: usize, height: usize) -> Result<(), TermIoErr>;
 }
+
+/// Stands in for the child process of a terminal that is only fed by hand, like the one a made
+/// up recording starts from. Nothing is read and anything written is dropped
+pub(crate) struct NoIo;
+
+impl TermIo for NoIo {
+    fn read(&mut self, _buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
+        Ok(ReadResponse::Empty)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
+        Ok(buf.len())
+    }
+
+    fn set_win_size(&mut self, _width: usize, _height: usize) -> Result<(), TermIoErr> {
+        Ok(())
+    }
+}
+
+/// How long [`write_all`] waits for the other side to make room before giving up
+#[cfg(unix)]
+const WRITE_TIMEOUT_MS: i32 = 5000;
+
+/// Write all of `buf` to the nonblocking `fd`, waiting for room when the other side is behind.
+/// Fails with `ETIMEDOUT` if no room is made for [`WRITE_TIMEOUT_MS`]
+#[cfg(unix)]
+fn write_all(fd: std::os::fd::BorrowedFd, mut buf: &[u8]) -> Result<(), nix::errno::Errno> {
+    use nix::{
+        errno::Errno,
+        poll::{PollFd, PollFlags},
+    };
+    use std::os::fd::AsRawFd;
+
+    while !buf.is_empty() {
+        match nix::unistd::write(fd.as_raw_fd(), buf) {
+            Ok(written) => buf = &buf[written..],
+            Err(Errno::EINTR) => (),
+            Err(Errno::EAGAIN) => {
+                let mut poll_fds = [PollFd::new(&fd, PollFlags::POLLOUT)];
+                match nix::poll::poll(&mut poll_fds, WRITE_TIMEOUT_MS) {
+                    Ok(0) => return Err(Errno::ETIMEDOUT),
+                    Ok(_) | Err(Errno::EINTR) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Options controlling what runs inside the pty
+#[derive(Debug, Default, Clone)]
+pub struct ShellOptions {
+    /// Command to run through `$SHELL -c` instead of an interactive shell
+    pub command: Option<String>,
+    /// Pass `-l` to the shell, for both interactive shells and commands
+    pub login: bool,
+    /// Directory to start in instead of the one termie was started in
+    pub cwd: Option<PathBuf>,
+}
+
+/// A child process running in a pseudo terminal. [`PtyIo`] is the one for the platform, a Unix
+/// pty or a Windows ConPTY. [`SerialIo`] and [`NetIo`] talk to a serial device and to a TCP
+/// server through the same interface, and [`AttachIo`] to a session a daemon keeps running
+pub trait Pty: TermIo + Sized {
+    /// What to start, e.g. [`ShellOptions`]
+    type Options: Clone;
+    type SpawnError: std::error::Error;
+    type Error: std::error::Error;
+
+    fn spawn(options: &Self::Options) -> Result<Self, Self::SpawnError>;
+
+    /// `options` changed to start in `dir`, for restarting in the directory that the child last
+    /// reported. `None` if they already name a directory, or there is nothing to start in one
+    fn options_in_directory(_options: &Self::Options, _dir: &Path) -> Option<Self::Options> {
+        None
+    }
+
+    /// Returns the exit code of the child process if it has exited. Processes killed by a
+    /// signal report 128 + the signal number, matching shell conventions
+    fn child_exit_status(&mut self) -> Result<Option<i32>, Self::Error>;
+
+    /// Whether a process group other than our shell currently owns the terminal, e.g. a build
+    /// started from the shell
+    fn has_foreground_job(&self) -> Result<bool, Self::Error>;
+
+    /// Command name of the foreground process group leader, e.g. "vim" or "cargo". This is the
+    /// shell itself when no job is running
+    fn foreground_process_name(&self) -> Option<String>;
+
+    /// `[user@]host` that the foreground job is connected to, if it is ssh
+    fn remote_host(&self) -> Option<String>;
+
+    /// Have `waker` called from the reader thread whenever output arrives, so that it does not
+    /// have to be polled for. Output arriving within `coalesce_window` of the first chunk is
+    /// covered by a single call
+    fn set_output_waker(&self, waker: OutputWaker, coalesce_window: Duration);
+
+    /// Whether the child closed the pty and all of its output was read
+    fn is_output_closed(&mut self) -> bool;
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::{io::Read, os::fd::AsFd, os::unix::net::UnixStream};
+
+    #[test]
+    fn test_write_all_waits_for_room() {
+        let (writer, mut reader) = UnixStream::pair().expect("failed to create socket pair");
+        writer
+            .set_nonblocking(true)
+            .expect("failed to set nonblocking");
+
+        // Far more than the socket buffers hold, the rest is written as the reader makes room
+        let data = vec![7u8; 4 * 1024 * 1024];
+        let read_thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).expect("failed to read");
+            read
+        });
+        write_all(writer.as_fd(), &data).expect("failed to write");
+        drop(writer);
+        assert_eq!(read_thread.join().expect("reader panicked"), data);
+    }
+}
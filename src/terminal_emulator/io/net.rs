@@ -0,0 +1,270 @@
+//! A TCP connection in place of a shell, for network services, MUDs and devices that have their
+//! console on a port. The bytes go through as they are, or through [`Telnet`] for servers that
+//! negotiate. Restarting connects again
+
+use thiserror::Error;
+
+use std::{
+    net::TcpStream,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    time::Duration,
+};
+
+use super::{
+    reader::{OutputReader, OutputWaker},
+    telnet::Telnet,
+    write_all, Pty, ReadResponse, TermIo, TermIoErr,
+};
+
+/// Where to connect to, parsed from `<host>:<port>`. IPv6 addresses go in brackets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetOptions {
+    pub host: String,
+    pub port: u16,
+    /// Speak telnet instead of passing bytes through
+    pub telnet: bool,
+}
+
+impl std::str::FromStr for NetOptions {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s.rsplit_once(':').ok_or(())?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() {
+            return Err(());
+        }
+        Ok(NetOptions {
+            host: host.to_string(),
+            port: port.parse().map_err(|_| ())?,
+            telnet: false,
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+enum CreateNetIoErrorKind {
+    #[error("failed to connect to {0}:{1}")]
+    Connect(String, u16, #[source] std::io::Error),
+    #[error("failed to set socket as non-blocking")]
+    SetNonblock(#[source] std::io::Error),
+    #[error("failed to duplicate socket for the reader thread")]
+    CloneSocket(#[source] std::io::Error),
+    #[error("failed to spawn reader thread")]
+    SpawnReader(#[source] std::io::Error),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct CreateNetIoError(#[from] CreateNetIoErrorKind);
+
+#[derive(Debug, Error)]
+enum NetIoErrKind {
+    #[error("failed to write to socket")]
+    Write(#[source] nix::errno::Errno),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct NetIoErr(#[from] NetIoErrKind);
+
+pub struct NetIo {
+    // Dropped first, the reader thread is stopped before the socket is closed
+    reader: OutputReader,
+    stream: TcpStream,
+    telnet: Option<Telnet>,
+}
+
+impl NetIo {
+    fn write_all(&mut self, buf: &[u8]) -> Result<(), NetIoErrKind> {
+        write_all(self.stream.as_fd(), buf).map_err(NetIoErrKind::Write)
+    }
+}
+
+impl Pty for NetIo {
+    type Options = NetOptions;
+    type SpawnError = CreateNetIoError;
+    type Error = NetIoErr;
+
+    fn spawn(options: &NetOptions) -> Result<NetIo, CreateNetIoError> {
+        let stream = TcpStream::connect((options.host.as_str(), options.port))
+            .map_err(|e| CreateNetIoErrorKind::Connect(options.host.clone(), options.port, e))?;
+        // Keystrokes should not wait to be batched up
+        if let Err(e) = stream.set_nodelay(true) {
+            warn!("Failed to disable Nagle's algorithm: {e}");
+        }
+        stream
+            .set_nonblocking(true)
+            .map_err(CreateNetIoErrorKind::SetNonblock)?;
+
+        let reader_stream = stream
+            .try_clone()
+            .map_err(CreateNetIoErrorKind::CloneSocket)?;
+        let reader = OutputReader::spawn(OwnedFd::from(reader_stream))
+            .map_err(CreateNetIoErrorKind::SpawnReader)?;
+        Ok(NetIo {
+            reader,
+            stream,
+            telnet: options.telnet.then(Telnet::new),
+        })
+    }
+
+    fn child_exit_status(&mut self) -> Result<Option<i32>, NetIoErr> {
+        Ok(None)
+    }
+
+    fn has_foreground_job(&self) -> Result<bool, NetIoErr> {
+        Ok(false)
+    }
+
+    fn foreground_process_name(&self) -> Option<String> {
+        None
+    }
+
+    fn remote_host(&self) -> Option<String> {
+        None
+    }
+
+    fn set_output_waker(&self, waker: OutputWaker, coalesce_window: Duration) {
+        self.reader.set_waker(waker, coalesce_window);
+    }
+
+    fn is_output_closed(&mut self) -> bool {
+        self.reader.is_closed()
+    }
+}
+
+impl TermIo for NetIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
+        let Some(telnet) = &mut self.telnet else {
+            return match self.reader.read(buf) {
+                0 => Ok(ReadResponse::Empty),
+                v => Ok(ReadResponse::Success(v)),
+            };
+        };
+
+        // A chunk may be all negotiation, keep going until there is something to show
+        let mut output = Vec::new();
+        let mut replies = Vec::new();
+        let mut chunk = vec![0; buf.len()];
+        while output.is_empty() {
+            match self.reader.read(&mut chunk) {
+                0 => break,
+                v => telnet.receive(&chunk[..v], &mut output, &mut replies),
+            }
+        }
+        // Commands take up room, so unescaped output never outgrows what was read
+        buf[..output.len()].copy_from_slice(&output);
+
+        if !replies.is_empty() {
+            self.write_all(&replies)
+                .map_err(|e| Box::new(NetIoErr::from(e)))?;
+        }
+        match output.len() {
+            0 => Ok(ReadResponse::Empty),
+            v => Ok(ReadResponse::Success(v)),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
+        if self.telnet.is_none() {
+            return nix::unistd::write(self.stream.as_raw_fd(), buf)
+                .map_err(|e| Box::new(NetIoErr::from(NetIoErrKind::Write(e))).into());
+        }
+
+        // Escaping changes the length, so a partial write could not be reported back
+        self.write_all(&Telnet::encode(buf))
+            .map_err(|e| Box::new(NetIoErr::from(e)))?;
+        Ok(buf.len())
+    }
+
+    fn set_win_size(&mut self, width: usize, height: usize) -> Result<(), TermIoErr> {
+        let Some(telnet) = &mut self.telnet else {
+            return Ok(());
+        };
+        let width = width.try_into().unwrap_or(u16::MAX);
+        let height = height.try_into().unwrap_or(u16::MAX);
+        if let Some(report) = telnet.set_window_size(width, height) {
+            self.write_all(&report)
+                .map_err(|e| Box::new(NetIoErr::from(e)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{io::Read, net::TcpListener, time::Instant};
+
+    fn read_output(io: &mut NetIo) -> Vec<u8> {
+        let start = Instant::now();
+        let mut buf = [0; 64];
+        loop {
+            if let ReadResponse::Success(len) = io.read(&mut buf).expect("failed to read") {
+                return buf[..len].to_vec();
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "no output arrived"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_parse_net_options() {
+        let options: NetOptions = "example.com:23".parse().expect("failed to parse");
+        assert_eq!(options.host, "example.com");
+        assert_eq!(options.port, 23);
+        let options: NetOptions = "[::1]:4000".parse().expect("failed to parse");
+        assert_eq!(options.host, "::1");
+        assert!("example.com".parse::<NetOptions>().is_err());
+        assert!("example.com:telnet".parse::<NetOptions>().is_err());
+        assert!(":23".parse::<NetOptions>().is_err());
+    }
+
+    #[test]
+    fn test_net_io() {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to listen");
+        let mut options = NetOptions {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().expect("no address").port(),
+            telnet: true,
+        };
+        let mut io = NetIo::spawn(&options).expect("failed to connect");
+        let (mut server, _) = listener.accept().expect("failed to accept");
+
+        // The server asks for the window size with its greeting
+        std::io::Write::write_all(&mut server, b"\xff\xfd\x1fhi").expect("failed to write");
+        assert_eq!(read_output(&mut io), b"hi");
+        io.write(b"ls\r").expect("failed to write");
+        let mut buf = [0; 16];
+        let mut received: Vec<u8> = Vec::new();
+        while received.len() < 15 {
+            let len = server.read(&mut buf).expect("failed to read");
+            received.extend(&buf[..len]);
+        }
+        assert_eq!(received, b"\xff\xfb\x1f\xff\xfa\x1f\0\0\0\0\xff\xf0ls\r\0");
+
+        // Without telnet everything goes through as is
+        options.telnet = false;
+        let mut io = NetIo::spawn(&options).expect("failed to connect");
+        let (mut server, _) = listener.accept().expect("failed to accept");
+        std::io::Write::write_all(&mut server, b"\xff\xfd\x1f").expect("failed to write");
+        assert_eq!(read_output(&mut io), b"\xff\xfd\x1f");
+
+        drop(server);
+        let start = Instant::now();
+        while !io.is_output_closed() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "connection loss not seen"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -1,4 +1,9 @@
-use nix::{errno::Errno, ioctl_write_ptr_bad, unistd::ForkResult};
+use nix::{
+    errno::Errno,
+    ioctl_write_ptr_bad,
+    sys::wait::{WaitPidFlag, WaitStatus},
+    unistd::{ForkResult, Pid},
+};
 
 use tempfile::TempDir;
 use thiserror::Error;
@@ -7,9 +12,14 @@ use std::{
     ffi::CStr,
     os::fd::{AsRawFd, OwnedFd},
     path::Path,
+    time::Duration,
 };
 
-use super::{ReadResponse, TermIo, TermIoErr};
+use super::{
+    reader::{OutputReader, OutputWaker},
+    remote_host::ssh_destination,
+    Pty, ReadResponse, ShellOptions, TermIo, TermIoErr,
+};
 
 ioctl_write_ptr_bad!(
     set_window_size_ioctl,
@@ -25,6 +35,10 @@ enum CreatePtyIoErrorKind {
     SpawnShell(#[from] SpawnShellError),
     #[error("failed to set fd as non-blocking")]
     SetNonblock(#[from] SetNonblockError),
+    #[error("failed to duplicate fd for the reader thread")]
+    CloneFd(#[source] std::io::Error),
+    #[error("failed to spawn reader thread")]
+    SpawnReader(#[source] std::io::Error),
 }
 
 #[derive(Debug, Error)]
@@ -63,12 +77,23 @@ enum SpawnShellErrorKind {
 #[error(transparent)]
 struct SpawnShellError(#[from] SpawnShellErrorKind);
 
+struct SpawnedShell {
+    fd: OwnedFd,
+    pid: Pid,
+}
+
 /// Spawn a shell in a child process and return the file descriptor used for I/O
-fn spawn_shell(terminfo_dir: &Path) -> Result<OwnedFd, SpawnShellError> {
+fn spawn_shell(
+    terminfo_dir: &Path,
+    options: &ShellOptions,
+) -> Result<SpawnedShell, SpawnShellError> {
     unsafe {
         let res = nix::pty::forkpty(None, None).map_err(SpawnShellErrorKind::Fork)?;
         match res.fork_result {
-            ForkResult::Parent { .. } => (),
+            ForkResult::Parent { child } => Ok(SpawnedShell {
+                fd: res.master,
+                pid: child,
+            }),
             ForkResult::Child => {
                 let shell_path = std::env::var("SHELL").unwrap_or_else(|_| "/bin/bash".to_string());
                 let shell_name = std::ffi::CString::new(shell_path.as_bytes())
@@ -76,20 +101,35 @@ fn spawn_shell(terminfo_dir: &Path) -> Result<OwnedFd, SpawnShellError> {
 
                 let shell_arg = std::ffi::CString::new(shell_path.as_bytes())
                     .expect("Shell path should be valid");
-                let login_flag = std::ffi::CString::new("-l")
-                    .expect("Should be valid");
+                let login_flag = std::ffi::CString::new("-l").expect("Should be valid");
+                let command_flag = std::ffi::CString::new("-c").expect("Should be valid");
+                let command = options.command.as_ref().map(|command| {
+                    std::ffi::CString::new(command.as_bytes())
+                        .expect("Command should not contain nul bytes")
+                });
 
-                let args: Vec<&CStr> = vec![shell_arg.as_c_str(), login_flag.as_c_str()];
+                let mut args: Vec<&CStr> = vec![shell_arg.as_c_str()];
+                if options.login {
+                    args.push(login_flag.as_c_str());
+                }
+                if let Some(command) = &command {
+                    args.push(command_flag.as_c_str());
+                    args.push(command.as_c_str());
+                }
 
+                if let Some(cwd) = &options.cwd {
+                    // A directory that is gone by now is not worth failing over
+                    let _ = std::env::set_current_dir(cwd);
+                }
                 std::env::set_var("TERMINFO", terminfo_dir);
                 std::env::set_var("TERM", "termie");
 
-                nix::unistd::execvp(shell_name.as_c_str(), &args).map_err(SpawnShellErrorKind::Exec)?;
+                nix::unistd::execvp(shell_name.as_c_str(), &args)
+                    .map_err(SpawnShellErrorKind::Exec)?;
                 // Should never run
                 std::process::exit(1);
             }
         }
-        Ok(res.master)
     }
 }
 
@@ -128,48 +168,55 @@ enum SetWindowSizeErrorKind {
 enum PtyIoErrKind {
     #[error("failed to set win size")]
     SetWinSize(#[from] SetWindowSizeErrorKind),
-    #[error("failed to read from file descriptor")]
-    Read(#[source] Errno),
     #[error("failed to write to file descriptor")]
     Write(#[source] Errno),
+    #[error("failed to wait on child process")]
+    Wait(#[source] Errno),
+    #[error("failed to get foreground process group")]
+    GetForegroundProcessGroup(#[source] Errno),
 }
 
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct PtyIoErr(#[from] PtyIoErrKind);
 
-pub struct PtyIo {
-    fd: OwnedFd,
-    _terminfo_dir: TempDir,
+#[cfg(target_os = "linux")]
+fn process_name(pid: Pid) -> Option<String> {
+    let comm = std::fs::read_to_string(format!("/proc/{pid}/comm")).ok()?;
+    Some(comm.trim_end().to_string())
 }
 
-impl PtyIo {
-    pub fn new() -> Result<PtyIo, CreatePtyIoError> {
-        let terminfo_dir = extract_terminfo().map_err(CreatePtyIoErrorKind::ExtractTerminfo)?;
-        let fd = spawn_shell(terminfo_dir.path()).map_err(CreatePtyIoErrorKind::SpawnShell)?;
-        set_nonblock(&fd).map_err(CreatePtyIoErrorKind::SetNonblock)?;
-        Ok(PtyIo {
-            fd,
-            _terminfo_dir: terminfo_dir,
-        })
-    }
+#[cfg(not(target_os = "linux"))]
+fn process_name(_pid: Pid) -> Option<String> {
+    None
 }
 
-impl TermIo for PtyIo {
-    fn read(&mut self, buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
-        let res = nix::unistd::read(self.fd.as_raw_fd(), buf);
-        match res {
-            Ok(v) => Ok(ReadResponse::Success(v)),
-            Err(Errno::EAGAIN) => Ok(ReadResponse::Empty),
-            Err(e) => Err(Box::new(PtyIoErrKind::Read(e))),
-        }
-    }
+#[cfg(target_os = "linux")]
+fn process_args(pid: Pid) -> Option<Vec<String>> {
+    let cmdline = std::fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let args = cmdline
+        .split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    Some(args)
+}
 
-    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
-        Ok(nix::unistd::write(self.fd.as_raw_fd(), buf).map_err(PtyIoErrKind::Write)?)
-    }
+#[cfg(not(target_os = "linux"))]
+fn process_args(_pid: Pid) -> Option<Vec<String>> {
+    None
+}
 
-    fn set_win_size(&mut self, width: usize, height: usize) -> Result<(), TermIoErr> {
+pub struct PtyIo {
+    // Dropped first, the reader thread is stopped before the pty is closed
+    reader: OutputReader,
+    fd: OwnedFd,
+    child: Pid,
+    _terminfo_dir: TempDir,
+}
+
+impl PtyIo {
+    fn set_win_size_inner(&mut self, width: usize, height: usize) -> Result<(), PtyIoErrKind> {
         let win_size = nix::pty::Winsize {
             ws_row: height
                 .try_into()
@@ -192,3 +239,91 @@ impl TermIo for PtyIo {
         Ok(())
     }
 }
+
+impl Pty for PtyIo {
+    type Options = ShellOptions;
+    type SpawnError = CreatePtyIoError;
+    type Error = PtyIoErr;
+
+    fn spawn(options: &ShellOptions) -> Result<PtyIo, CreatePtyIoError> {
+        let terminfo_dir = extract_terminfo().map_err(CreatePtyIoErrorKind::ExtractTerminfo)?;
+        let shell =
+            spawn_shell(terminfo_dir.path(), options).map_err(CreatePtyIoErrorKind::SpawnShell)?;
+        set_nonblock(&shell.fd).map_err(CreatePtyIoErrorKind::SetNonblock)?;
+        let reader_fd = shell
+            .fd
+            .try_clone()
+            .map_err(CreatePtyIoErrorKind::CloneFd)?;
+        let reader = OutputReader::spawn(reader_fd).map_err(CreatePtyIoErrorKind::SpawnReader)?;
+        Ok(PtyIo {
+            reader,
+            fd: shell.fd,
+            child: shell.pid,
+            _terminfo_dir: terminfo_dir,
+        })
+    }
+
+    fn options_in_directory(options: &ShellOptions, dir: &Path) -> Option<ShellOptions> {
+        options.cwd.is_none().then(|| ShellOptions {
+            cwd: Some(dir.to_path_buf()),
+            ..options.clone()
+        })
+    }
+
+    fn child_exit_status(&mut self) -> Result<Option<i32>, PtyIoErr> {
+        let status = nix::sys::wait::waitpid(self.child, Some(WaitPidFlag::WNOHANG))
+            .map_err(PtyIoErrKind::Wait)?;
+
+        match status {
+            WaitStatus::Exited(_, code) => Ok(Some(code)),
+            WaitStatus::Signaled(_, signal, _) => Ok(Some(128 + signal as i32)),
+            _ => Ok(None),
+        }
+    }
+
+    fn has_foreground_job(&self) -> Result<bool, PtyIoErr> {
+        let foreground = nix::unistd::tcgetpgrp(self.fd.as_raw_fd())
+            .map_err(PtyIoErrKind::GetForegroundProcessGroup)?;
+        Ok(foreground != self.child)
+    }
+
+    fn foreground_process_name(&self) -> Option<String> {
+        let foreground = nix::unistd::tcgetpgrp(self.fd.as_raw_fd()).ok()?;
+        process_name(foreground)
+    }
+
+    fn set_output_waker(&self, waker: OutputWaker, coalesce_window: Duration) {
+        self.reader.set_waker(waker, coalesce_window);
+    }
+
+    fn is_output_closed(&mut self) -> bool {
+        self.reader.is_closed()
+    }
+
+    fn remote_host(&self) -> Option<String> {
+        let foreground = nix::unistd::tcgetpgrp(self.fd.as_raw_fd()).ok()?;
+        if foreground == self.child || process_name(foreground)? != "ssh" {
+            return None;
+        }
+        ssh_destination(&process_args(foreground)?)
+    }
+}
+
+impl TermIo for PtyIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
+        match self.reader.read(buf) {
+            0 => Ok(ReadResponse::Empty),
+            v => Ok(ReadResponse::Success(v)),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
+        nix::unistd::write(self.fd.as_raw_fd(), buf)
+            .map_err(|e| Box::new(PtyIoErr::from(PtyIoErrKind::Write(e))).into())
+    }
+
+    fn set_win_size(&mut self, width: usize, height: usize) -> Result<(), TermIoErr> {
+        self.set_win_size_inner(width, height)
+            .map_err(|e| Box::new(PtyIoErr::from(e)).into())
+    }
+}
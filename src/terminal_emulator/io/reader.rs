@@ -0,0 +1,369 @@
+//! Reading child output on a thread of its own, so that waiting on the child never holds up a
+//! frame. Chunks are handed over through a channel that the emulator drains without blocking.
+//! The channel only holds a few chunks, once it is full the thread stops reading, so that a
+//! child writing faster than its output is shown is held up by the kernel again instead of
+//! filling memory. Output often arrives in many small chunks, so the waker is called once for
+//! everything that arrived within a short window instead of for every chunk
+
+#[cfg(unix)]
+use nix::{
+    errno::Errno,
+    poll::{PollFd, PollFlags},
+};
+
+use crate::error::backtraced_err;
+
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// How long the thread waits for output before checking whether it should stop
+#[cfg(unix)]
+const POLL_TIMEOUT_MS: i32 = 50;
+
+/// Chunks read ahead of the emulator, at most 4096 bytes each
+const MAX_QUEUED_CHUNKS: usize = 16;
+
+/// Called from the reader thread whenever output arrives, e.g. to request a repaint
+pub type OutputWaker = Box<dyn Fn() + Send>;
+
+struct Waker {
+    waker: OutputWaker,
+    coalesce_window: Duration,
+}
+
+type SharedWaker = Arc<Mutex<Option<Waker>>>;
+
+pub struct OutputReader {
+    chunks: Receiver<Vec<u8>>,
+    // Rest of a chunk that did not fit into the caller's buffer
+    pending: Vec<u8>,
+    waker: SharedWaker,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OutputReader {
+    /// Read from `fd` until the other side is closed or the reader is dropped
+    #[cfg(unix)]
+    pub fn spawn(fd: OwnedFd) -> std::io::Result<OutputReader> {
+        OutputReader::spawn_with(move |chunks, waker, stop| read_loop(fd, chunks, waker, stop))
+    }
+
+    /// Read from `file`, which only supports blocking reads, until the other side is closed.
+    /// Dropping the reader waits for that, so the writing side has to be closed first
+    #[cfg(windows)]
+    pub fn spawn_blocking(file: std::fs::File) -> std::io::Result<OutputReader> {
+        OutputReader::spawn_with(move |chunks, waker, _stop| {
+            blocking_read_loop(file, chunks, waker)
+        })
+    }
+
+    fn spawn_with(
+        read_loop: impl FnOnce(SyncSender<Vec<u8>>, SharedWaker, Arc<AtomicBool>) + Send + 'static,
+    ) -> std::io::Result<OutputReader> {
+        let (sender, chunks) = mpsc::sync_channel(MAX_QUEUED_CHUNKS);
+        let waker: SharedWaker = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let waker = Arc::clone(&waker);
+            let stop = Arc::clone(&stop);
+            std::thread::Builder::new()
+                .name("pty reader".to_string())
+                .spawn(move || read_loop(sender, waker, stop))?
+        };
+
+        Ok(OutputReader {
+            chunks,
+            pending: Vec::new(),
+            waker,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Have `waker` called once output arrived, at most once per `coalesce_window`
+    pub fn set_waker(&self, waker: OutputWaker, coalesce_window: Duration) {
+        *self.waker.lock().expect("waker lock poisoned") = Some(Waker {
+            waker,
+            coalesce_window,
+        });
+    }
+
+    /// Copy already read output into `buf`. Returns 0 if there is none
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        if self.pending.is_empty() {
+            match self.chunks.try_recv() {
+                Ok(chunk) => self.pending = chunk,
+                Err(_) => return 0,
+            }
+        }
+
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        len
+    }
+
+    /// Whether the child closed its side and everything it wrote was handed out
+    pub fn is_closed(&mut self) -> bool {
+        // The thread sends everything before it finishes, so once it has finished whatever is
+        // not in the channel yet never will be
+        let finished = self.thread.as_ref().is_none_or(JoinHandle::is_finished);
+        if self.pending.is_empty() {
+            if let Ok(chunk) = self.chunks.try_recv() {
+                self.pending = chunk;
+            }
+        }
+        finished && self.pending.is_empty()
+    }
+}
+
+impl Drop for OutputReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        // A thread waiting for room in the channel gives up once nobody receives anymore
+        self.chunks = mpsc::sync_channel(0).1;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("pty reader thread panicked");
+            }
+        }
+    }
+}
+
+fn wake(waker: &SharedWaker) {
+    if let Some(waker) = &*waker.lock().expect("waker lock poisoned") {
+        (waker.waker)();
+    }
+}
+
+/// Hand `chunk` over, waiting while the channel is full. Returns false once nobody receives
+fn send_chunk(chunks: &SyncSender<Vec<u8>>, chunk: Vec<u8>, waker: &SharedWaker) -> bool {
+    match chunks.try_send(chunk) {
+        Ok(()) => true,
+        Err(TrySendError::Full(chunk)) => {
+            // Whoever drains the channel has to know that there is something to drain
+            wake(waker);
+            chunks.send(chunk).is_ok()
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+fn coalesce_window(waker: &SharedWaker) -> Duration {
+    waker
+        .lock()
+        .expect("waker lock poisoned")
+        .as_ref()
+        .map_or(Duration::ZERO, |waker| waker.coalesce_window)
+}
+
+#[cfg(unix)]
+fn read_loop(fd: OwnedFd, chunks: SyncSender<Vec<u8>>, waker: SharedWaker, stop: Arc<AtomicBool>) {
+    let mut buf = vec![0u8; 4096];
+    // Set while output was sent that the waker was not called for yet
+    let mut wake_due: Option<Instant> = None;
+    while !stop.load(Ordering::Relaxed) {
+        let timeout = wake_due.map_or(POLL_TIMEOUT_MS, |due| {
+            let remaining = due.saturating_duration_since(Instant::now());
+            remaining
+                .as_micros()
+                .div_ceil(1000)
+                .min(POLL_TIMEOUT_MS as u128) as i32
+        });
+        let mut poll_fds = [PollFd::new(&fd, PollFlags::POLLIN)];
+        let readable = match nix::poll::poll(&mut poll_fds, timeout) {
+            Ok(0) | Err(Errno::EINTR) => false,
+            Ok(_) => true,
+            Err(e) => {
+                error!("Failed to wait for child output: {}", backtraced_err(&e));
+                break;
+            }
+        };
+
+        if readable {
+            let read_size = match nix::unistd::read(fd.as_raw_fd(), &mut buf) {
+                // Linux reports EIO once the child has closed its side of the pty, other
+                // systems report end of file
+                Ok(0) | Err(Errno::EIO) => break,
+                Ok(v) => v,
+                Err(Errno::EAGAIN | Errno::EINTR) => continue,
+                Err(e) => {
+                    error!("Failed to read from child process: {}", backtraced_err(&e));
+                    break;
+                }
+            };
+
+            if !send_chunk(&chunks, buf[..read_size].to_vec(), &waker) {
+                break;
+            }
+            wake_due.get_or_insert_with(|| Instant::now() + coalesce_window(&waker));
+        }
+
+        if wake_due.is_some_and(|due| Instant::now() >= due) {
+            wake_due = None;
+            wake(&waker);
+        }
+    }
+
+    // Whatever was sent last must not wait for output that will not come
+    if wake_due.is_some() {
+        wake(&waker);
+    }
+}
+
+/// Without a way to wait for output with a timeout, the thread sleeps out the rest of the
+/// coalescing window after a chunk arrived. Output that comes in meanwhile waits in the pipe
+#[cfg(windows)]
+fn blocking_read_loop(mut file: std::fs::File, chunks: SyncSender<Vec<u8>>, waker: SharedWaker) {
+    use std::io::Read;
+
+    let mut buf = vec![0u8; 4096];
+    let mut last_wake: Option<Instant> = None;
+    loop {
+        let read_size = match file.read(&mut buf) {
+            // A broken pipe is how the closed pseudo console shows up
+            Ok(0) => break,
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+            Ok(v) => v,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Failed to read from child process: {}", backtraced_err(&e));
+                break;
+            }
+        };
+
+        if !send_chunk(&chunks, buf[..read_size].to_vec(), &waker) {
+            break;
+        }
+
+        if let Some(last_wake) = last_wake {
+            let due = last_wake + coalesce_window(&waker);
+            std::thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+        last_wake = Some(Instant::now());
+        wake(&waker);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use std::{io::Write, os::unix::net::UnixStream, time::Duration};
+
+    #[test]
+    fn test_output_reader() {
+        let (mut writer, reader_end) = UnixStream::pair().expect("failed to create socket pair");
+        let mut reader = OutputReader::spawn(reader_end.into()).expect("failed to spawn reader");
+
+        let (woken_sender, woken) = mpsc::channel();
+        reader.set_waker(
+            Box::new(move || {
+                let _ = woken_sender.send(());
+            }),
+            Duration::ZERO,
+        );
+
+        writer.write_all(b"hello").expect("failed to write");
+        woken
+            .recv_timeout(Duration::from_secs(5))
+            .expect("reader did not wake");
+
+        let mut buf = [0u8; 3];
+        assert_eq!(reader.read(&mut buf), 3);
+        assert_eq!(&buf, b"hel");
+        assert_eq!(reader.read(&mut buf), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert_eq!(reader.read(&mut buf), 0);
+        assert!(!reader.is_closed());
+
+        drop(writer);
+        let start = std::time::Instant::now();
+        while !reader.is_closed() {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "reader did not close"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_output_reader_coalesces_wakes() {
+        let (mut writer, reader_end) = UnixStream::pair().expect("failed to create socket pair");
+        let mut reader = OutputReader::spawn(reader_end.into()).expect("failed to spawn reader");
+
+        let (woken_sender, woken) = mpsc::channel();
+        reader.set_waker(
+            Box::new(move || {
+                let _ = woken_sender.send(());
+            }),
+            Duration::from_millis(200),
+        );
+
+        for chunk in [b"a", b"b", b"c"] {
+            writer.write_all(chunk).expect("failed to write");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        woken
+            .recv_timeout(Duration::from_secs(5))
+            .expect("reader did not wake");
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(woken.try_recv().is_err());
+
+        let mut buf = [0u8; 3];
+        let mut read = 0;
+        while read < 3 {
+            read += reader.read(&mut buf[read..]);
+        }
+        assert_eq!(&buf, b"abc");
+    }
+
+    #[test]
+    fn test_output_reader_stops_when_full() {
+        let (mut writer, reader_end) = UnixStream::pair().expect("failed to create socket pair");
+        let mut reader = OutputReader::spawn(reader_end.into()).expect("failed to spawn reader");
+        writer
+            .set_nonblocking(true)
+            .expect("failed to make writer non blocking");
+
+        // Nothing is taken out of the reader, so once its channel and the socket are full the
+        // writer has to wait
+        let chunk = [b'a'; 4096];
+        let mut written = 0;
+        let mut last_progress = std::time::Instant::now();
+        while last_progress.elapsed() < Duration::from_millis(200) {
+            match writer.write(&chunk) {
+                Ok(len) => {
+                    written += len;
+                    last_progress = std::time::Instant::now();
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => panic!("failed to write: {e}"),
+            }
+            assert!(written < 64 * 1024 * 1024, "reader never stopped reading");
+        }
+
+        // Everything comes through once the reader is drained
+        let mut buf = vec![0u8; 4096];
+        let mut read = 0;
+        let start = std::time::Instant::now();
+        while read < written {
+            assert!(start.elapsed() < Duration::from_secs(5), "output got lost");
+            read += reader.read(&mut buf);
+        }
+        assert_eq!(read, written);
+    }
+}
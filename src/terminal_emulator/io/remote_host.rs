@@ -0,0 +1,88 @@
+//! Working out which machine an ssh command line connects to
+
+/// ssh options that take a value, see ssh(1)
+const SSH_OPTIONS_WITH_VALUE: &str = "BbcDEeFIiJLlmOoPpQRSWw";
+
+/// `[user@]host` for an ssh destination, which may also be given as `ssh://[user@]host[:port]`
+fn format_destination(destination: &str, user: Option<&str>) -> String {
+    let destination = match destination.strip_prefix("ssh://") {
+        Some(uri) => uri.split(':').next().unwrap_or(uri),
+        None => destination,
+    };
+
+    match user {
+        Some(user) if !destination.contains('@') => format!("{user}@{destination}"),
+        _ => destination.to_string(),
+    }
+}
+
+/// The destination of the ssh invocation `args`, where the first element is the program. A user
+/// given with `-l` is folded into the result
+pub fn ssh_destination(args: &[String]) -> Option<String> {
+    let mut user = None;
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            let destination = args.next()?;
+            return Some(format_destination(destination, user.as_deref()));
+        }
+
+        let Some(flags) = arg.strip_prefix('-').filter(|v| !v.is_empty()) else {
+            return Some(format_destination(arg, user.as_deref()));
+        };
+
+        // Flags can be grouped, e.g. -vp 2222. The first one that takes a value consumes the rest
+        // of the word, or the next argument if nothing is left
+        for (i, flag) in flags.char_indices() {
+            if !SSH_OPTIONS_WITH_VALUE.contains(flag) {
+                continue;
+            }
+
+            let rest = &flags[i + flag.len_utf8()..];
+            let value = if rest.is_empty() {
+                args.next()?.clone()
+            } else {
+                rest.to_string()
+            };
+            if flag == 'l' {
+                user = Some(value);
+            }
+            break;
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn destination(args: &[&str]) -> Option<String> {
+        let args: Vec<String> = args.iter().map(ToString::to_string).collect();
+        ssh_destination(&args)
+    }
+
+    #[test]
+    fn test_ssh_destination() {
+        assert_eq!(destination(&["ssh", "host"]), Some("host".to_string()));
+        assert_eq!(
+            destination(&["ssh", "-v", "-p", "2222", "me@host", "uptime"]),
+            Some("me@host".to_string())
+        );
+        assert_eq!(
+            destination(&["ssh", "-vp2222", "-l", "me", "host"]),
+            Some("me@host".to_string())
+        );
+        assert_eq!(
+            destination(&["ssh", "-oStrictHostKeyChecking=no", "ssh://me@host:22"]),
+            Some("me@host".to_string())
+        );
+        assert_eq!(
+            destination(&["ssh", "-A", "--", "host"]),
+            Some("host".to_string())
+        );
+        assert_eq!(destination(&["ssh", "-p"]), None);
+        assert_eq!(destination(&["ssh"]), None);
+    }
+}
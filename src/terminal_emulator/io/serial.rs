@@ -0,0 +1,280 @@
+//! A serial device in place of a shell, e.g. a board on `/dev/ttyUSB0`. The line is set to raw 8N1
+//! at the chosen baud rate. There is no child process, so the device never exits and never has
+//! a foreground job, and restarting reopens it
+
+use nix::{
+    errno::Errno,
+    sys::termios::{self, BaudRate, ControlFlags, SetArg},
+};
+
+use thiserror::Error;
+
+use std::{
+    fs::OpenOptions,
+    os::{
+        fd::{AsFd, OwnedFd},
+        unix::fs::OpenOptionsExt,
+    },
+    path::PathBuf,
+    time::Duration,
+};
+
+use super::{
+    reader::{OutputReader, OutputWaker},
+    write_all, Pty, ReadResponse, TermIo, TermIoErr,
+};
+
+const DEFAULT_BAUD_RATE: u32 = 115200;
+
+/// The device to open, parsed from `<path>[@<baud rate>]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialOptions {
+    pub path: PathBuf,
+    pub baud_rate: u32,
+}
+
+impl std::str::FromStr for SerialOptions {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, baud_rate) = match s.rsplit_once('@') {
+            Some((path, baud_rate)) => (path, baud_rate.parse().map_err(|_| ())?),
+            None => (s, DEFAULT_BAUD_RATE),
+        };
+        if path.is_empty() {
+            return Err(());
+        }
+        Ok(SerialOptions {
+            path: path.into(),
+            baud_rate,
+        })
+    }
+}
+
+fn baud_rate(rate: u32) -> Option<BaudRate> {
+    let ret = match rate {
+        300 => BaudRate::B300,
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        #[cfg(target_os = "linux")]
+        460800 => BaudRate::B460800,
+        #[cfg(target_os = "linux")]
+        921600 => BaudRate::B921600,
+        #[cfg(target_os = "linux")]
+        1000000 => BaudRate::B1000000,
+        #[cfg(target_os = "linux")]
+        1500000 => BaudRate::B1500000,
+        #[cfg(target_os = "linux")]
+        2000000 => BaudRate::B2000000,
+        _ => return None,
+    };
+    Some(ret)
+}
+
+#[derive(Debug, Error)]
+enum CreateSerialIoErrorKind {
+    #[error("unsupported baud rate {0}")]
+    UnsupportedBaudRate(u32),
+    #[error("failed to open {0}")]
+    Open(String, #[source] std::io::Error),
+    #[error("failed to get line settings")]
+    GetAttributes(#[source] Errno),
+    #[error("failed to set baud rate")]
+    SetBaudRate(#[source] Errno),
+    #[error("failed to set line settings")]
+    SetAttributes(#[source] Errno),
+    #[error("failed to duplicate fd for the reader thread")]
+    CloneFd(#[source] std::io::Error),
+    #[error("failed to spawn reader thread")]
+    SpawnReader(#[source] std::io::Error),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct CreateSerialIoError(#[from] CreateSerialIoErrorKind);
+
+#[derive(Debug, Error)]
+enum SerialIoErrKind {
+    #[error("failed to write to serial device")]
+    Write(#[source] Errno),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct SerialIoErr(#[from] SerialIoErrKind);
+
+/// Raw 8N1 at `rate`, with the modem control lines ignored so that opening does not wait for
+/// carrier
+fn configure_line(fd: &OwnedFd, rate: BaudRate) -> Result<(), CreateSerialIoErrorKind> {
+    let mut attributes = termios::tcgetattr(fd).map_err(CreateSerialIoErrorKind::GetAttributes)?;
+    termios::cfmakeraw(&mut attributes);
+    attributes.control_flags |= ControlFlags::CLOCAL | ControlFlags::CREAD;
+    attributes.control_flags &= !ControlFlags::CSTOPB;
+    termios::cfsetspeed(&mut attributes, rate).map_err(CreateSerialIoErrorKind::SetBaudRate)?;
+    termios::tcsetattr(fd, SetArg::TCSANOW, &attributes)
+        .map_err(CreateSerialIoErrorKind::SetAttributes)
+}
+
+pub struct SerialIo {
+    // Dropped first, the reader thread is stopped before the device is closed
+    reader: OutputReader,
+    fd: OwnedFd,
+}
+
+impl Pty for SerialIo {
+    type Options = SerialOptions;
+    type SpawnError = CreateSerialIoError;
+    type Error = SerialIoErr;
+
+    fn spawn(options: &SerialOptions) -> Result<SerialIo, CreateSerialIoError> {
+        let rate = baud_rate(options.baud_rate).ok_or(
+            CreateSerialIoErrorKind::UnsupportedBaudRate(options.baud_rate),
+        )?;
+        // Without O_NOCTTY the device could become termie's controlling terminal
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(nix::libc::O_NOCTTY | nix::libc::O_NONBLOCK)
+            .open(&options.path)
+            .map_err(|e| CreateSerialIoErrorKind::Open(options.path.display().to_string(), e))?;
+        let fd = OwnedFd::from(file);
+        configure_line(&fd, rate)?;
+
+        let reader_fd = fd.try_clone().map_err(CreateSerialIoErrorKind::CloneFd)?;
+        let reader =
+            OutputReader::spawn(reader_fd).map_err(CreateSerialIoErrorKind::SpawnReader)?;
+        Ok(SerialIo { reader, fd })
+    }
+
+    fn child_exit_status(&mut self) -> Result<Option<i32>, SerialIoErr> {
+        Ok(None)
+    }
+
+    fn has_foreground_job(&self) -> Result<bool, SerialIoErr> {
+        Ok(false)
+    }
+
+    fn foreground_process_name(&self) -> Option<String> {
+        None
+    }
+
+    fn remote_host(&self) -> Option<String> {
+        None
+    }
+
+    fn set_output_waker(&self, waker: OutputWaker, coalesce_window: Duration) {
+        self.reader.set_waker(waker, coalesce_window);
+    }
+
+    fn is_output_closed(&mut self) -> bool {
+        self.reader.is_closed()
+    }
+}
+
+impl TermIo for SerialIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<ReadResponse, TermIoErr> {
+        match self.reader.read(buf) {
+            0 => Ok(ReadResponse::Empty),
+            v => Ok(ReadResponse::Success(v)),
+        }
+    }
+
+    /// The device is opened nonblocking, so a busy port is waited on rather than failing the write
+    fn write(&mut self, buf: &[u8]) -> Result<usize, TermIoErr> {
+        write_all(self.fd.as_fd(), buf)
+            .map_err(|e| Box::new(SerialIoErr::from(SerialIoErrKind::Write(e))))?;
+        Ok(buf.len())
+    }
+
+    /// A serial line has no size to tell the other side about
+    fn set_win_size(&mut self, _width: usize, _height: usize) -> Result<(), TermIoErr> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::fd::AsRawFd;
+
+    #[test]
+    fn test_parse_serial_options() {
+        let options: SerialOptions = "/dev/ttyUSB0@9600".parse().expect("failed to parse");
+        assert_eq!(options.path, PathBuf::from("/dev/ttyUSB0"));
+        assert_eq!(options.baud_rate, 9600);
+        let options: SerialOptions = "/dev/ttyUSB0".parse().expect("failed to parse");
+        assert_eq!(options.baud_rate, DEFAULT_BAUD_RATE);
+        assert!("/dev/ttyUSB0@fast".parse::<SerialOptions>().is_err());
+        assert!("@9600".parse::<SerialOptions>().is_err());
+    }
+
+    // The other end of a pty stands in for the device
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_serial_io() {
+        use nix::fcntl::OFlag;
+        use std::{os::fd::AsFd, time::Instant};
+
+        let master =
+            nix::pty::posix_openpt(OFlag::O_RDWR | OFlag::O_NOCTTY).expect("failed to open pty");
+        nix::pty::grantpt(&master).expect("failed to grant pty");
+        nix::pty::unlockpt(&master).expect("failed to unlock pty");
+        let path = nix::pty::ptsname_r(&master).expect("failed to get pty name");
+
+        let unsupported = SerialOptions {
+            path: path.clone().into(),
+            baud_rate: 1234,
+        };
+        assert!(SerialIo::spawn(&unsupported).is_err());
+
+        let options = SerialOptions {
+            path: path.into(),
+            baud_rate: 9600,
+        };
+        let mut io = SerialIo::spawn(&options).expect("failed to open device");
+        let attributes = termios::tcgetattr(io.fd.as_fd()).expect("failed to get attributes");
+        assert_eq!(termios::cfgetospeed(&attributes), BaudRate::B9600);
+
+        io.write(b"hello").expect("failed to write");
+        let mut buf = [0; 64];
+        let len = nix::unistd::read(master.as_raw_fd(), &mut buf).expect("failed to read");
+        assert_eq!(&buf[..len], b"hello");
+
+        nix::unistd::write(master.as_raw_fd(), b"world").expect("failed to write");
+        let start = Instant::now();
+        let len = loop {
+            if let ReadResponse::Success(len) = io.read(&mut buf).expect("failed to read") {
+                break len;
+            }
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "no output arrived"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        // Raw mode, so nothing is translated on the way in
+        assert_eq!(&buf[..len], b"world");
+        assert!(!io.is_output_closed());
+
+        // More than fits into the line's buffer goes out as the other side reads it
+        let big = vec![b'x'; 256 * 1024];
+        let drain = std::thread::spawn(move || {
+            let mut received = 0;
+            let mut buf = [0; 4096];
+            while received < 256 * 1024 {
+                received +=
+                    nix::unistd::read(master.as_raw_fd(), &mut buf).expect("failed to read");
+            }
+            received
+        });
+        assert_eq!(io.write(&big).expect("failed to write"), big.len());
+        assert_eq!(drain.join().expect("drain panicked"), big.len());
+    }
+}
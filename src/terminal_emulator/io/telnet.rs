@@ -0,0 +1,289 @@
+//! Enough of the telnet protocol (RFC 854) to talk to servers that insist on it. Commands are
+//! taken out of what the server sends, and options are refused apart from the few a terminal
+//! needs: the server echoing and suppressing go ahead, and the window size and terminal type
+//! being reported to it
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const TERMINAL_TYPE: u8 = 24;
+const WINDOW_SIZE: u8 = 31;
+
+// Subnegotiation codes of the terminal type option
+const TERMINAL_TYPE_IS: u8 = 0;
+const TERMINAL_TYPE_SEND: u8 = 1;
+
+/// Longest subnegotiation kept, anything longer is dropped. The ones answered are a few bytes
+const MAX_SUBNEGOTIATION_LEN: usize = 256;
+
+/// termie's own terminfo entry is not going to be installed on the server
+const TERMINAL_TYPE_NAME: &[u8] = b"XTERM-256COLOR";
+
+enum State {
+    Data,
+    // A carriage return followed by a NUL is a bare carriage return
+    CarriageReturn,
+    Command,
+    Negotiation(u8),
+    // `None` once the subnegotiation got too long, the rest of it is skipped
+    Subnegotiation(Option<Vec<u8>>),
+    SubnegotiationCommand(Option<Vec<u8>>),
+}
+
+pub struct Telnet {
+    state: State,
+    // Options the server agreed to use, and the ones we agreed to
+    remote_enabled: [bool; 256],
+    local_enabled: [bool; 256],
+    window_size: (u16, u16),
+}
+
+/// Add `b` to a subnegotiation, unless that makes it too long
+fn push_subnegotiation(buf: Option<Vec<u8>>, b: u8) -> Option<Vec<u8>> {
+    let mut buf = buf?;
+    if buf.len() >= MAX_SUBNEGOTIATION_LEN {
+        return None;
+    }
+    buf.push(b);
+    Some(buf)
+}
+
+/// Double the bytes that would otherwise start a command
+fn push_escaped(out: &mut Vec<u8>, data: &[u8]) {
+    for &b in data {
+        if b == IAC {
+            out.push(IAC);
+        }
+        out.push(b);
+    }
+}
+
+impl Telnet {
+    pub fn new() -> Telnet {
+        Telnet {
+            state: State::Data,
+            remote_enabled: [false; 256],
+            local_enabled: [false; 256],
+            window_size: (0, 0),
+        }
+    }
+
+    /// Split what the server sent into `output` for the terminal and `replies` to send back
+    pub fn receive(&mut self, data: &[u8], output: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        for &b in data {
+            self.state = match std::mem::replace(&mut self.state, State::Data) {
+                State::Data | State::CarriageReturn if b == IAC => State::Command,
+                State::CarriageReturn if b == 0 => State::Data,
+                State::Data | State::CarriageReturn => {
+                    output.push(b);
+                    if b == b'\r' {
+                        State::CarriageReturn
+                    } else {
+                        State::Data
+                    }
+                }
+                State::Command => match b {
+                    IAC => {
+                        output.push(IAC);
+                        State::Data
+                    }
+                    WILL | WONT | DO | DONT => State::Negotiation(b),
+                    SB => State::Subnegotiation(Some(Vec::new())),
+                    // Go ahead, no-op and the like mean nothing to us
+                    _ => State::Data,
+                },
+                State::Negotiation(command) => {
+                    self.negotiate(command, b, replies);
+                    State::Data
+                }
+                State::Subnegotiation(buf) if b == IAC => State::SubnegotiationCommand(buf),
+                State::Subnegotiation(buf) => State::Subnegotiation(push_subnegotiation(buf, b)),
+                State::SubnegotiationCommand(buf) if b == SE => {
+                    if let Some(buf) = buf {
+                        self.subnegotiate(&buf, replies);
+                    }
+                    State::Data
+                }
+                State::SubnegotiationCommand(buf) => {
+                    // Only an escaped IAC is valid here
+                    State::Subnegotiation(push_subnegotiation(buf, b))
+                }
+            };
+        }
+    }
+
+    /// Replies are only sent when an option changes, so that both sides agreeing does not loop
+    fn negotiate(&mut self, command: u8, option: u8, replies: &mut Vec<u8>) {
+        let index = usize::from(option);
+        match command {
+            WILL if matches!(option, ECHO | SUPPRESS_GO_AHEAD) => {
+                if !self.remote_enabled[index] {
+                    self.remote_enabled[index] = true;
+                    replies.extend([IAC, DO, option]);
+                }
+            }
+            WILL => replies.extend([IAC, DONT, option]),
+            WONT => {
+                if self.remote_enabled[index] {
+                    self.remote_enabled[index] = false;
+                    replies.extend([IAC, DONT, option]);
+                }
+            }
+            DO if matches!(option, SUPPRESS_GO_AHEAD | TERMINAL_TYPE | WINDOW_SIZE) => {
+                if !self.local_enabled[index] {
+                    self.local_enabled[index] = true;
+                    replies.extend([IAC, WILL, option]);
+                }
+                if option == WINDOW_SIZE {
+                    self.push_window_size(replies);
+                }
+            }
+            DO => replies.extend([IAC, WONT, option]),
+            DONT => {
+                if self.local_enabled[index] {
+                    self.local_enabled[index] = false;
+                    replies.extend([IAC, WONT, option]);
+                }
+            }
+            _ => unreachable!("not a negotiation command"),
+        }
+    }
+
+    fn subnegotiate(&mut self, data: &[u8], replies: &mut Vec<u8>) {
+        if data == [TERMINAL_TYPE, TERMINAL_TYPE_SEND]
+            && self.local_enabled[usize::from(TERMINAL_TYPE)]
+        {
+            replies.extend([IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_IS]);
+            replies.extend(TERMINAL_TYPE_NAME);
+            replies.extend([IAC, SE]);
+        }
+    }
+
+    fn push_window_size(&self, out: &mut Vec<u8>) {
+        let (width, height) = self.window_size;
+        out.extend([IAC, SB, WINDOW_SIZE]);
+        let mut size = Vec::with_capacity(4);
+        size.extend(width.to_be_bytes());
+        size.extend(height.to_be_bytes());
+        push_escaped(out, &size);
+        out.extend([IAC, SE]);
+    }
+
+    /// Remember the size and return the report for it, if the server asked for reports
+    pub fn set_window_size(&mut self, width: u16, height: u16) -> Option<Vec<u8>> {
+        self.window_size = (width, height);
+        if !self.local_enabled[usize::from(WINDOW_SIZE)] {
+            return None;
+        }
+        let mut report = Vec::new();
+        self.push_window_size(&mut report);
+        Some(report)
+    }
+
+    /// `data` from the terminal, escaped for sending. A carriage return has to be followed by a
+    /// NUL unless it starts a CR LF
+    pub fn encode(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for (i, &b) in data.iter().enumerate() {
+            push_escaped(&mut out, &[b]);
+            if b == b'\r' && data.get(i + 1) != Some(&b'\n') {
+                out.push(0);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn receive(telnet: &mut Telnet, data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut output = Vec::new();
+        let mut replies = Vec::new();
+        telnet.receive(data, &mut output, &mut replies);
+        (output, replies)
+    }
+
+    #[test]
+    fn test_telnet_negotiation() {
+        let mut telnet = Telnet::new();
+        assert_eq!(telnet.set_window_size(80, 24), None);
+
+        let (output, replies) = receive(
+            &mut telnet,
+            &[
+                b'a',
+                IAC,
+                WILL,
+                ECHO,
+                IAC,
+                WILL,
+                42,
+                b'b',
+                IAC,
+                DO,
+                WINDOW_SIZE,
+                IAC,
+                DO,
+                42,
+            ],
+        );
+        assert_eq!(output, b"ab");
+        let mut expected = vec![IAC, DO, ECHO, IAC, DONT, 42, IAC, WILL, WINDOW_SIZE];
+        expected.extend([IAC, SB, WINDOW_SIZE, 0, 80, 0, 24, IAC, SE]);
+        expected.extend([IAC, WONT, 42]);
+        assert_eq!(replies, expected);
+
+        // Agreeing again does not answer again
+        let (_, replies) = receive(&mut telnet, &[IAC, WILL, ECHO]);
+        assert!(replies.is_empty());
+
+        assert_eq!(
+            telnet.set_window_size(255, 30),
+            Some(vec![IAC, SB, WINDOW_SIZE, 0, IAC, IAC, 0, 30, IAC, SE])
+        );
+
+        // Split across reads
+        let (_, replies) = receive(&mut telnet, &[IAC, DO, TERMINAL_TYPE, IAC, SB]);
+        assert_eq!(replies, [IAC, WILL, TERMINAL_TYPE]);
+        let (output, replies) = receive(
+            &mut telnet,
+            &[TERMINAL_TYPE, TERMINAL_TYPE_SEND, IAC, SE, b'c'],
+        );
+        assert_eq!(output, b"c");
+        assert!(replies.ends_with(b"XTERM-256COLOR\xff\xf0"));
+
+        // A subnegotiation that does not end in time is dropped, without holding all of it
+        let mut data = vec![IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_SEND];
+        data.extend([b'x'; 100_000]);
+        data.extend([IAC, IAC, IAC, SE, b'd']);
+        let (output, replies) = receive(&mut telnet, &data);
+        assert_eq!(output, b"d");
+        assert!(replies.is_empty());
+        assert!(matches!(telnet.state, State::Data));
+        let (_, replies) = receive(
+            &mut telnet,
+            &[IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_SEND, IAC, SE],
+        );
+        assert!(replies.ends_with(b"XTERM-256COLOR\xff\xf0"));
+    }
+
+    #[test]
+    fn test_telnet_data() {
+        let mut telnet = Telnet::new();
+        let (output, replies) = receive(&mut telnet, b"a\r\0b\r\n\xff\xffc\xff\xf1");
+        assert_eq!(output, b"a\rb\r\n\xffc");
+        assert!(replies.is_empty());
+
+        assert_eq!(Telnet::encode(b"ls\r"), b"ls\r\0");
+        assert_eq!(Telnet::encode(b"ls\r\n\xff"), b"ls\r\n\xff\xff");
+    }
+}
@@ -0,0 +1,462 @@
+//! Key encodings that programs can ask for instead of the legacy one, which cannot tell apart
+//! e.g. Ctrl+I and Tab or most modifier combinations. Keys that are not affected keep their
+//! legacy encoding
+//!
+//! The kitty keyboard protocol sends `CSI u` escape codes
+//! https://sw.kovidgoyal.net/kitty/keyboard-protocol/
+//!
+//! xterm's modifyOtherKeys is the older and simpler one, with `CSI 27;{modifiers};{code}~`
+//! https://invisible-island.net/xterm/modified-keys.html
+
+/// Enhancements a program asked for. Only the implemented ones are kept, so that the program
+/// can tell what it got when it queries them
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct KeyboardFlags(u8);
+
+impl KeyboardFlags {
+    pub const DISAMBIGUATE: u8 = 1;
+    pub const REPORT_EVENT_TYPES: u8 = 2;
+    // Reporting alternate keys (4) and associated text (16) is not implemented
+    pub const REPORT_ALL_KEYS: u8 = 8;
+    const SUPPORTED: u8 = Self::DISAMBIGUATE | Self::REPORT_EVENT_TYPES | Self::REPORT_ALL_KEYS;
+
+    pub fn from_bits(bits: u32) -> KeyboardFlags {
+        KeyboardFlags(bits as u8 & Self::SUPPORTED)
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    fn contains(self, bit: u8) -> bool {
+        self.0 & bit != 0
+    }
+}
+
+/// How `ESC[={flags};{mode}u` combines the flags with the current ones
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SetKeyboardFlagsMode {
+    Replace,
+    Add,
+    Remove,
+}
+
+// Pushing onto a full stack drops the oldest entry, so that a program that never pops cannot
+// grow it forever
+const MAX_STACK_DEPTH: usize = 16;
+
+/// The current flags and the ones that were saved by pushing new ones
+#[derive(Debug, Default)]
+pub struct KeyboardFlagsStack {
+    current: KeyboardFlags,
+    saved: Vec<KeyboardFlags>,
+}
+
+impl KeyboardFlagsStack {
+    pub fn current(&self) -> KeyboardFlags {
+        self.current
+    }
+
+    /// The flags that pops go back to, the most recent last
+    pub fn saved(&self) -> &[KeyboardFlags] {
+        &self.saved
+    }
+
+    pub fn set(&mut self, flags: KeyboardFlags, mode: SetKeyboardFlagsMode) {
+        self.current.0 = match mode {
+            SetKeyboardFlagsMode::Replace => flags.0,
+            SetKeyboardFlagsMode::Add => self.current.0 | flags.0,
+            SetKeyboardFlagsMode::Remove => self.current.0 & !flags.0,
+        };
+    }
+
+    pub fn push(&mut self, flags: KeyboardFlags) {
+        if self.saved.len() >= MAX_STACK_DEPTH {
+            self.saved.remove(0);
+        }
+        self.saved.push(self.current);
+        self.current = flags;
+    }
+
+    /// Popping more entries than were pushed turns everything off
+    pub fn pop(&mut self, count: usize) {
+        for _ in 0..count {
+            self.current = self.saved.pop().unwrap_or_default();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyCode {
+    /// A key that types text, as the character it types without shift
+    Char(char),
+    Escape,
+    Enter,
+    Tab,
+    Backspace,
+    Insert,
+    Delete,
+    ArrowLeft,
+    ArrowRight,
+    ArrowUp,
+    ArrowDown,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    /// F1 and up
+    F(u8),
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct KeyModifiers {
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool,
+    pub super_key: bool,
+}
+
+impl KeyModifiers {
+    /// The modifier parameter of a key code, one more than the bits of the modifiers
+    fn param(&self) -> u8 {
+        1 + self.shift as u8
+            + ((self.alt as u8) << 1)
+            + ((self.ctrl as u8) << 2)
+            + ((self.super_key as u8) << 3)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.param() == 1
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyEventKind {
+    Press,
+    Repeat,
+    Release,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct KeyEvent {
+    pub key: KeyCode,
+    pub modifiers: KeyModifiers,
+    pub kind: KeyEventKind,
+}
+
+/// The three shapes of key codes. Keys that have a legacy `CSI` sequence keep its final byte or
+/// number, the rest use `CSI {unicode}u`
+enum Encoding {
+    Unicode(u32),
+    Letter(u8),
+    Tilde(u32),
+}
+
+fn encoding(key: KeyCode) -> Encoding {
+    match key {
+        KeyCode::Char(c) => Encoding::Unicode(c as u32),
+        KeyCode::Escape => Encoding::Unicode(27),
+        KeyCode::Enter => Encoding::Unicode(13),
+        KeyCode::Tab => Encoding::Unicode(9),
+        KeyCode::Backspace => Encoding::Unicode(127),
+        KeyCode::Insert => Encoding::Tilde(2),
+        KeyCode::Delete => Encoding::Tilde(3),
+        KeyCode::PageUp => Encoding::Tilde(5),
+        KeyCode::PageDown => Encoding::Tilde(6),
+        KeyCode::ArrowLeft => Encoding::Letter(b'D'),
+        KeyCode::ArrowRight => Encoding::Letter(b'C'),
+        KeyCode::ArrowUp => Encoding::Letter(b'A'),
+        KeyCode::ArrowDown => Encoding::Letter(b'B'),
+        KeyCode::Home => Encoding::Letter(b'H'),
+        KeyCode::End => Encoding::Letter(b'F'),
+        KeyCode::F(1) => Encoding::Letter(b'P'),
+        KeyCode::F(2) => Encoding::Letter(b'Q'),
+        KeyCode::F(3) => Encoding::Tilde(13),
+        KeyCode::F(4) => Encoding::Letter(b'S'),
+        KeyCode::F(n @ 5) => Encoding::Tilde(10 + n as u32),
+        KeyCode::F(n @ 6..=10) => Encoding::Tilde(11 + n as u32),
+        KeyCode::F(n @ 11..=12) => Encoding::Tilde(12 + n as u32),
+        // From F13 on the keys are in the private use area
+        KeyCode::F(n) => Encoding::Unicode(57376 + n.saturating_sub(13) as u32),
+    }
+}
+
+/// Keys that still type text or do what they did in the legacy encoding. Enter, Tab and
+/// Backspace are among them, so that a shell can still be used after a program crashed with the
+/// flags set
+fn is_legacy(event: &KeyEvent, flags: KeyboardFlags) -> bool {
+    if flags.contains(KeyboardFlags::REPORT_ALL_KEYS) {
+        return false;
+    }
+
+    let modifiers = event.modifiers;
+    match event.key {
+        KeyCode::Char(_) => !(modifiers.alt || modifiers.ctrl || modifiers.super_key),
+        KeyCode::Escape => false,
+        _ => modifiers.is_empty(),
+    }
+}
+
+/// The escape code for `event`, or None when it should be sent the legacy way. Releases that
+/// have no escape code are not sent at all
+pub fn encode_key(event: &KeyEvent, flags: KeyboardFlags) -> Option<Vec<u8>> {
+    if flags.is_empty() || is_legacy(event, flags) {
+        return None;
+    }
+
+    let event_type = match (
+        flags.contains(KeyboardFlags::REPORT_EVENT_TYPES),
+        event.kind,
+    ) {
+        (false, KeyEventKind::Release) => return None,
+        (false, _) | (true, KeyEventKind::Press) => 1,
+        (true, KeyEventKind::Repeat) => 2,
+        (true, KeyEventKind::Release) => 3,
+    };
+    let modifiers = event.modifiers.param();
+    let params = match (modifiers, event_type) {
+        (1, 1) => String::new(),
+        (modifiers, 1) => modifiers.to_string(),
+        (modifiers, event_type) => format!("{modifiers}:{event_type}"),
+    };
+
+    let sequence = match (encoding(event.key), params.is_empty()) {
+        (Encoding::Unicode(code), true) => format!("\x1b[{code}u"),
+        (Encoding::Unicode(code), false) => format!("\x1b[{code};{params}u"),
+        (Encoding::Tilde(number), true) => format!("\x1b[{number}~"),
+        (Encoding::Tilde(number), false) => format!("\x1b[{number};{params}~"),
+        (Encoding::Letter(letter), true) => format!("\x1b[{}", letter as char),
+        (Encoding::Letter(letter), false) => format!("\x1b[1;{params}{}", letter as char),
+    };
+    Some(sequence.into_bytes())
+}
+
+/// Modified keys that keep their encoding at modifyOtherKeys level 1: Ctrl with a letter or one
+/// of the other keys that have a control character, Alt as an escape prefix, and Shift+Tab.
+/// Ctrl+Shift+A cannot be told apart from Ctrl+A, so it is not among them
+fn has_legacy_modified_encoding(event: &KeyEvent) -> bool {
+    let modifiers = event.modifiers;
+    match event.key {
+        KeyCode::Char(c)
+            if modifiers.ctrl && !modifiers.shift && !modifiers.alt && !modifiers.super_key =>
+        {
+            c.is_ascii_lowercase() || "@[\\]^_ ".contains(c)
+        }
+        KeyCode::Char(_) => modifiers.alt && !modifiers.ctrl && !modifiers.super_key,
+        KeyCode::Tab => {
+            modifiers.shift && !modifiers.alt && !modifiers.ctrl && !modifiers.super_key
+        }
+        _ => false,
+    }
+}
+
+/// The modifyOtherKeys escape code for `event`, or None when it should be sent the legacy way.
+/// Level 1 leaves the modified keys that have a well known encoding alone, level 2 encodes all
+/// of them. Only keys that type text and Enter, Tab, Backspace and Escape are affected
+pub fn encode_modify_other_keys(event: &KeyEvent, level: u8) -> Option<Vec<u8>> {
+    let modifiers = event.modifiers;
+    let code = match event.key {
+        // Shift only changes the text
+        KeyCode::Char(_) if !(modifiers.alt || modifiers.ctrl || modifiers.super_key) => {
+            return None
+        }
+        // The code is that of the character that is typed, which shift changes for letters
+        KeyCode::Char(c) if modifiers.shift => c.to_ascii_uppercase() as u32,
+        KeyCode::Char(c) => c as u32,
+        KeyCode::Enter => 13,
+        KeyCode::Tab => 9,
+        KeyCode::Backspace => 127,
+        KeyCode::Escape => 27,
+        _ => return None,
+    };
+    if level == 0
+        || event.kind == KeyEventKind::Release
+        || modifiers.is_empty()
+        || (level == 1 && has_legacy_modified_encoding(event))
+    {
+        return None;
+    }
+
+    Some(format!("\x1b[27;{};{code}~", modifiers.param()).into_bytes())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn press(key: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent {
+            key,
+            modifiers,
+            kind: KeyEventKind::Press,
+        }
+    }
+
+    fn encode(event: KeyEvent, flags: u8) -> Option<String> {
+        encode_key(&event, KeyboardFlags::from_bits(flags.into()))
+            .map(|v| String::from_utf8(v).expect("escape codes are ascii"))
+    }
+
+    const CTRL: KeyModifiers = KeyModifiers {
+        shift: false,
+        alt: false,
+        ctrl: true,
+        super_key: false,
+    };
+    const SHIFT: KeyModifiers = KeyModifiers {
+        shift: true,
+        alt: false,
+        ctrl: false,
+        super_key: false,
+    };
+    const NONE: KeyModifiers = KeyModifiers {
+        shift: false,
+        alt: false,
+        ctrl: false,
+        super_key: false,
+    };
+
+    #[test]
+    fn test_disambiguate() {
+        let flags = KeyboardFlags::DISAMBIGUATE;
+        assert_eq!(encode(press(KeyCode::Char('a'), NONE), 0), None);
+        assert_eq!(encode(press(KeyCode::Char('a'), NONE), flags), None);
+        assert_eq!(encode(press(KeyCode::Char('a'), SHIFT), flags), None);
+        assert_eq!(
+            encode(press(KeyCode::Char('i'), CTRL), flags).as_deref(),
+            Some("\x1b[105;5u")
+        );
+        assert_eq!(
+            encode(press(KeyCode::Escape, NONE), flags).as_deref(),
+            Some("\x1b[27u")
+        );
+        assert_eq!(encode(press(KeyCode::Tab, NONE), flags), None);
+        assert_eq!(
+            encode(press(KeyCode::Tab, SHIFT), flags).as_deref(),
+            Some("\x1b[9;2u")
+        );
+        assert_eq!(encode(press(KeyCode::ArrowUp, NONE), flags), None);
+        assert_eq!(
+            encode(press(KeyCode::ArrowUp, CTRL), flags).as_deref(),
+            Some("\x1b[1;5A")
+        );
+        assert_eq!(
+            encode(press(KeyCode::Delete, SHIFT), flags).as_deref(),
+            Some("\x1b[3;2~")
+        );
+        assert_eq!(
+            encode(press(KeyCode::F(5), CTRL), flags).as_deref(),
+            Some("\x1b[15;5~")
+        );
+        assert_eq!(
+            encode(press(KeyCode::F(12), CTRL), flags).as_deref(),
+            Some("\x1b[24;5~")
+        );
+    }
+
+    #[test]
+    fn test_event_types_and_all_keys() {
+        let flags = KeyboardFlags::DISAMBIGUATE | KeyboardFlags::REPORT_EVENT_TYPES;
+        let mut event = press(KeyCode::Escape, NONE);
+        event.kind = KeyEventKind::Release;
+        assert_eq!(encode(event, flags).as_deref(), Some("\x1b[27;1:3u"));
+        assert_eq!(encode(event, KeyboardFlags::DISAMBIGUATE), None);
+        event.kind = KeyEventKind::Repeat;
+        assert_eq!(encode(event, flags).as_deref(), Some("\x1b[27;1:2u"));
+        assert_eq!(
+            encode(event, KeyboardFlags::DISAMBIGUATE).as_deref(),
+            Some("\x1b[27u")
+        );
+        // Text keys only get escape codes, and with them releases, with all keys reported
+        event.key = KeyCode::Char('a');
+        event.kind = KeyEventKind::Release;
+        assert_eq!(encode(event, flags), None);
+
+        let flags = flags | KeyboardFlags::REPORT_ALL_KEYS;
+        assert_eq!(encode(event, flags).as_deref(), Some("\x1b[97;1:3u"));
+        assert_eq!(
+            encode(press(KeyCode::Char('a'), SHIFT), flags).as_deref(),
+            Some("\x1b[97;2u")
+        );
+        assert_eq!(
+            encode(press(KeyCode::Enter, NONE), flags).as_deref(),
+            Some("\x1b[13u")
+        );
+        assert_eq!(
+            encode(press(KeyCode::ArrowLeft, NONE), flags).as_deref(),
+            Some("\x1b[D")
+        );
+    }
+
+    #[test]
+    fn test_modify_other_keys() {
+        let encode = |event, level| {
+            encode_modify_other_keys(&event, level)
+                .map(|v| String::from_utf8(v).expect("escape codes are ascii"))
+        };
+        let ctrl_shift = KeyModifiers {
+            shift: true,
+            ..CTRL
+        };
+
+        assert_eq!(encode(press(KeyCode::Char('a'), CTRL), 0), None);
+        assert_eq!(encode(press(KeyCode::Char('a'), CTRL), 1), None);
+        assert_eq!(
+            encode(press(KeyCode::Char('a'), CTRL), 2).as_deref(),
+            Some("\x1b[27;5;97~")
+        );
+        assert_eq!(
+            encode(press(KeyCode::Char('a'), ctrl_shift), 1).as_deref(),
+            Some("\x1b[27;6;65~")
+        );
+        assert_eq!(
+            encode(press(KeyCode::Char('1'), CTRL), 1).as_deref(),
+            Some("\x1b[27;5;49~")
+        );
+        assert_eq!(
+            encode(press(KeyCode::Enter, CTRL), 1).as_deref(),
+            Some("\x1b[27;5;13~")
+        );
+        assert_eq!(encode(press(KeyCode::Tab, SHIFT), 1), None);
+        assert_eq!(
+            encode(press(KeyCode::Tab, SHIFT), 2).as_deref(),
+            Some("\x1b[27;2;9~")
+        );
+        assert_eq!(encode(press(KeyCode::Char('a'), SHIFT), 2), None);
+        assert_eq!(encode(press(KeyCode::Enter, NONE), 2), None);
+        assert_eq!(encode(press(KeyCode::ArrowUp, CTRL), 2), None);
+
+        let mut release = press(KeyCode::Char('a'), CTRL);
+        release.kind = KeyEventKind::Release;
+        assert_eq!(encode(release, 2), None);
+    }
+
+    #[test]
+    fn test_flags_stack() {
+        let mut stack = KeyboardFlagsStack::default();
+        stack.push(KeyboardFlags::from_bits(1));
+        stack.set(KeyboardFlags::from_bits(2), SetKeyboardFlagsMode::Add);
+        assert_eq!(stack.current().bits(), 3);
+        // Unsupported bits are dropped
+        stack.push(KeyboardFlags::from_bits(31));
+        assert_eq!(stack.current().bits(), 11);
+        stack.set(KeyboardFlags::from_bits(8), SetKeyboardFlagsMode::Remove);
+        assert_eq!(stack.current().bits(), 3);
+        stack.set(KeyboardFlags::from_bits(1), SetKeyboardFlagsMode::Replace);
+        assert_eq!(stack.current().bits(), 1);
+
+        stack.pop(1);
+        assert_eq!(stack.current().bits(), 3);
+        stack.pop(5);
+        assert!(stack.current().is_empty());
+
+        for i in 0..MAX_STACK_DEPTH + 4 {
+            stack.push(KeyboardFlags::from_bits(i as u32));
+        }
+        assert_eq!(stack.saved.len(), MAX_STACK_DEPTH);
+    }
+}
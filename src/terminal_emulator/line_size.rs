@@ -0,0 +1,289 @@
+use std::{fmt, ops::Range};
+
+use super::recording::{NotIntOfType, SnapshotItem};
+use thiserror::Error;
+
+/// Per line size attribute, set with ESC # 3/4/5/6 (DECDHL, DECSWL, DECDWL)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LineSize {
+    SingleWidth,
+    DoubleWidth,
+    DoubleHeightTop,
+    DoubleHeightBottom,
+}
+
+impl LineSize {
+    /// Double height lines are double width as well
+    pub fn is_double_width(&self) -> bool {
+        *self != LineSize::SingleWidth
+    }
+}
+
+impl fmt::Display for LineSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineSize::SingleWidth => f.write_str("single_width"),
+            LineSize::DoubleWidth => f.write_str("double_width"),
+            LineSize::DoubleHeightTop => f.write_str("double_height_top"),
+            LineSize::DoubleHeightBottom => f.write_str("double_height_bottom"),
+        }
+    }
+}
+
+impl std::str::FromStr for LineSize {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "single_width" => LineSize::SingleWidth,
+            "double_width" => LineSize::DoubleWidth,
+            "double_height_top" => LineSize::DoubleHeightTop,
+            "double_height_bottom" => LineSize::DoubleHeightBottom,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
+/// Size of the line starting at buffer position `line_start`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LineSizeTag {
+    pub line_start: usize,
+    pub size: LineSize,
+}
+
+mod line_size_tag_keys {
+    pub const LINE_START: &str = "line_start";
+    pub const SIZE: &str = "size";
+}
+
+#[derive(Debug, Error)]
+enum LoadLineSizeSnapshotErrorKind {
+    #[error("root element is not an array")]
+    NotArray,
+    #[error("tag is not a map")]
+    TagNotMap,
+    #[error("line_start missing")]
+    LineStartMissing,
+    #[error("line_start is not a usize")]
+    LineStartNotUsize(#[source] NotIntOfType),
+    #[error("size missing")]
+    SizeMissing,
+    #[error("size is not a string")]
+    SizeNotString,
+    #[error("failed to parse size")]
+    ParseSize(()),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct LoadLineSizeSnapshotError(#[from] LoadLineSizeSnapshotErrorKind);
+
+#[derive(Debug, Error)]
+#[error("line start cannot be serialized as i64")]
+pub struct SnapshotLineSizeError(#[source] std::num::TryFromIntError);
+
+/// Tracks which lines are not single width. Lines are identified by the buffer position they
+/// start at, which is kept up to date as the buffer changes the same way format tags are
+pub struct LineSizeTracker {
+    // Sorted by line start, single width lines are not stored
+    tags: Vec<LineSizeTag>,
+}
+
+impl LineSizeTracker {
+    pub fn new() -> LineSizeTracker {
+        LineSizeTracker { tags: Vec::new() }
+    }
+
+    pub fn from_snapshot(
+        snapshot: SnapshotItem,
+    ) -> Result<LineSizeTracker, LoadLineSizeSnapshotError> {
+        use LoadLineSizeSnapshotErrorKind::*;
+
+        let arr = snapshot.into_vec().map_err(|_| NotArray)?;
+        let mut tags = Vec::with_capacity(arr.len());
+        for item in arr {
+            let mut map = item.into_map().map_err(|_| TagNotMap)?;
+
+            let line_start = map
+                .remove(line_size_tag_keys::LINE_START)
+                .ok_or(LineStartMissing)?;
+            let line_start = line_start.into_num().map_err(LineStartNotUsize)?;
+
+            let size = map.remove(line_size_tag_keys::SIZE).ok_or(SizeMissing)?;
+            let size = size.into_string().map_err(|_| SizeNotString)?;
+            let size = size.parse().map_err(ParseSize)?;
+
+            tags.push(LineSizeTag { line_start, size });
+        }
+
+        Ok(LineSizeTracker { tags })
+    }
+
+    pub fn snapshot(&self) -> Result<SnapshotItem, SnapshotLineSizeError> {
+        let tags = self
+            .tags
+            .iter()
+            .map(|tag| {
+                let line_start: i64 = tag.line_start.try_into().map_err(SnapshotLineSizeError)?;
+                Ok(SnapshotItem::Map(
+                    [
+                        (
+                            line_size_tag_keys::LINE_START.to_string(),
+                            line_start.into(),
+                        ),
+                        (
+                            line_size_tag_keys::SIZE.to_string(),
+                            tag.size.to_string().into(),
+                        ),
+                    ]
+                    .into(),
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SnapshotItem::Array(tags))
+    }
+
+    pub fn set(&mut self, line_start: usize, size: LineSize) {
+        self.tags.retain(|tag| tag.line_start != line_start);
+        if size != LineSize::SingleWidth {
+            let idx = self.tags.partition_point(|tag| tag.line_start < line_start);
+            self.tags.insert(idx, LineSizeTag { line_start, size });
+        }
+    }
+
+    pub fn get(&self, line_start: usize) -> LineSize {
+        self.tags
+            .iter()
+            .find(|tag| tag.line_start == line_start)
+            .map(|tag| tag.size)
+            .unwrap_or(LineSize::SingleWidth)
+    }
+
+    pub fn tags(&self) -> &[LineSizeTag] {
+        &self.tags
+    }
+
+    /// Data was inserted at `range`. Data inserted at the start of a line becomes part of that
+    /// line, so only lines starting after it move
+    pub fn push_range_adjustment(&mut self, range: Range<usize>) {
+        let range_len = range.end - range.start;
+        for tag in &mut self.tags {
+            if tag.line_start > range.start {
+                tag.line_start += range_len;
+            }
+        }
+    }
+
+    /// Whole lines were inserted at `range`, pushing the line that started there down
+    pub fn push_lines_adjustment(&mut self, range: Range<usize>) {
+        let range_len = range.end - range.start;
+        for tag in &mut self.tags {
+            if tag.line_start >= range.start {
+                tag.line_start += range_len;
+            }
+        }
+    }
+
+    /// Data at `range` was removed. A line whose start was removed was merged into the previous
+    /// one and loses its size
+    pub fn delete_range(&mut self, range: Range<usize>) {
+        let del_size = range.end - range.start;
+        self.tags
+            .retain(|tag| tag.line_start <= range.start || tag.line_start > range.end);
+        for tag in &mut self.tags {
+            if tag.line_start > range.end {
+                tag.line_start -= del_size;
+            }
+        }
+    }
+
+    /// Whole lines at `range` were removed, the lines after it move up
+    pub fn delete_lines(&mut self, range: Range<usize>) {
+        let del_size = range.end - range.start;
+        self.tags
+            .retain(|tag| tag.line_start < range.start || tag.line_start >= range.end);
+        for tag in &mut self.tags {
+            if tag.line_start >= range.end {
+                tag.line_start -= del_size;
+            }
+        }
+    }
+
+    /// Erased lines go back to single width
+    pub fn clear_from(&mut self, pos: usize) {
+        self.tags.retain(|tag| tag.line_start < pos);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_line_size() {
+        let mut tracker = LineSizeTracker::new();
+        tracker.set(10, LineSize::DoubleWidth);
+        tracker.set(4, LineSize::DoubleHeightTop);
+        assert_eq!(tracker.get(10), LineSize::DoubleWidth);
+        assert_eq!(tracker.get(5), LineSize::SingleWidth);
+        assert_eq!(tracker.tags()[0].line_start, 4);
+
+        tracker.set(10, LineSize::SingleWidth);
+        assert_eq!(tracker.get(10), LineSize::SingleWidth);
+        assert_eq!(tracker.tags().len(), 1);
+    }
+
+    #[test]
+    fn test_line_size_adjustments() {
+        let mut tracker = LineSizeTracker::new();
+        tracker.set(5, LineSize::DoubleWidth);
+
+        tracker.push_range_adjustment(5..8);
+        assert_eq!(tracker.get(5), LineSize::DoubleWidth);
+        tracker.push_range_adjustment(2..4);
+        assert_eq!(tracker.get(7), LineSize::DoubleWidth);
+        tracker.push_lines_adjustment(7..8);
+        assert_eq!(tracker.get(8), LineSize::DoubleWidth);
+
+        tracker.delete_range(0..2);
+        assert_eq!(tracker.get(6), LineSize::DoubleWidth);
+        tracker.delete_range(6..9);
+        assert_eq!(tracker.get(6), LineSize::DoubleWidth);
+
+        // Removing the newline before the line joins it with the previous one
+        tracker.delete_range(5..6);
+        assert!(tracker.tags().is_empty());
+    }
+
+    #[test]
+    fn test_line_size_delete_lines() {
+        let mut tracker = LineSizeTracker::new();
+        tracker.set(3, LineSize::DoubleWidth);
+        tracker.set(6, LineSize::DoubleHeightTop);
+        tracker.set(9, LineSize::DoubleHeightBottom);
+
+        tracker.delete_lines(3..6);
+        assert_eq!(tracker.get(3), LineSize::DoubleHeightTop);
+        assert_eq!(tracker.get(6), LineSize::DoubleHeightBottom);
+        assert_eq!(tracker.tags().len(), 2);
+    }
+
+    #[test]
+    fn test_line_size_clear_from() {
+        let mut tracker = LineSizeTracker::new();
+        tracker.set(0, LineSize::DoubleWidth);
+        tracker.set(5, LineSize::DoubleWidth);
+        tracker.clear_from(5);
+        assert_eq!(tracker.tags().len(), 1);
+    }
+
+    #[test]
+    fn test_line_size_snapshot() {
+        let mut tracker = LineSizeTracker::new();
+        tracker.set(3, LineSize::DoubleHeightBottom);
+        let snapshot = tracker.snapshot().expect("failed to snapshot");
+        let loaded = LineSizeTracker::from_snapshot(snapshot).expect("failed to load");
+        assert_eq!(loaded.tags(), tracker.tags());
+    }
+}
@@ -1,29 +1,72 @@
-use std::{fmt, num::TryFromIntError, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::Write,
+    num::TryFromIntError,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 
+pub use ansi::{strip_ansi, AnsiStripper, InvalidUtf8};
 use ansi::{AnsiParser, SelectGraphicRendition, TerminalOutput};
-use buffer::TerminalBuffer;
+pub use buffer::{cells_to_string, str_to_cells, WIDE_CHAR_SPACER};
+use buffer::{text_to_cells, TerminalBuffer, TerminalBufferScrollResponse};
+use color_scheme::format_color_spec;
 use format_tracker::FormatTracker;
+use keyboard::{KeyboardFlagsStack, SetKeyboardFlagsMode};
+use line_size::LineSizeTracker;
 use recording::{NotIntOfType, Recorder};
+use session_log::SessionLog;
+use shell_marks::ShellMarkTracker;
+use tab_stops::TabStops;
 
+pub use color_scheme::{parse_hex_rgb, parse_hex_rgba, ColorScheme, ColorSlot, Rgb, Rgba};
 pub use format_tracker::FormatTag;
-pub use io::{PtyIo, TermIo};
-pub use recording::{LoadRecordingError, Recording, RecordingHandle, SnapshotItem};
-pub use replay::{ControlAction, ReplayControl, ReplayIo};
+#[cfg(test)]
+pub(crate) use io::NoIo;
+#[cfg(unix)]
+pub use io::{
+    AttachIo, AttachIoErr, CreateAttachIoError, CreateNetIoError, CreateSerialIoError,
+    InvalidSessionMessage, NetIo, NetIoErr, NetOptions, SerialIo, SerialIoErr, SerialOptions,
+    SessionDecoder, SessionMessage,
+};
+pub use io::{
+    CreatePtyIoError, OutputWaker, Pty, PtyIo, PtyIoErr, ReadResponse, ShellOptions, TermIo,
+    TermIoErr,
+};
+pub use keyboard::{KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardFlags};
+pub use line_size::{LineSize, LineSizeTag};
+pub use recording::{
+    InitialStateNotMap, LoadRecordingError, ParseSnapshotError, Recording, RecordingHandle,
+    RecordingItem, SaveRecordingError, SnapshotItem,
+};
+pub use replay::{screen_at, ControlAction, LoadReplayError, Replay, ReplayControl, ReplayIo};
+pub use rows::{Cell, CellRun, CellStyle, Row};
+pub use session_log::SessionLogFormat;
 
-use crate::{error::backtraced_err, terminal_emulator::io::ReadResponse};
+use crate::error::backtraced_err;
 use thiserror::Error;
 
-use self::{
-    io::CreatePtyIoError,
-    recording::{RecordingItem, StartRecordingResponse},
-};
+use self::recording::StartRecordingResponse;
 
 mod ansi;
+mod ansi_export;
 mod buffer;
+mod color_scheme;
 mod format_tracker;
+mod html;
 mod io;
+mod keyboard;
+mod line_size;
 mod recording;
 mod replay;
+mod rows;
+mod session_log;
+mod shell_marks;
+mod tab_stops;
+#[cfg(test)]
+mod vttest;
 
 #[derive(Eq, PartialEq)]
 enum Mode {
@@ -32,6 +75,26 @@ enum Mode {
     Decckm,
     // Bracketed paste mode - wraps pasted text with special sequences
     BracketedPaste,
+    // Line feed/new line mode, ECMA-48 7.2.9. Line feeds also return the cursor to the start of
+    // the line, and Enter sends CR LF
+    Lnm,
+    // Insert/replace mode, ECMA-48 7.2.10. Printed text pushes the rest of the row to the right
+    // instead of overwriting it
+    Irm,
+    // Autowrap mode, text reaching the end of a row goes on on the next one instead of
+    // overwriting the last column
+    // https://vt100.net/docs/vt510-rm/DECAWM.html
+    Decawm,
+    // Origin mode, cursor addressing is relative to the scroll region
+    // https://vt100.net/docs/vt510-rm/DECOM.html
+    Decom,
+    // Alternate screen, a separate screen without scrollback for full screen programs
+    // https://invisible-island.net/xterm/ctlseqs/ctlseqs.html#h2-The-Alternate-Screen-Buffer
+    AlternateScreen,
+    // Synchronized output, the program marks the start and end of a frame so that it is not
+    // shown half drawn
+    // https://gist.github.com/christianparpart/d8a62cc1ab659194337d73e399004036
+    SynchronizedOutput,
     Unknown(Vec<u8>),
 }
 
@@ -40,6 +103,12 @@ impl fmt::Debug for Mode {
         match self {
             Mode::Decckm => f.write_str("Decckm"),
             Mode::BracketedPaste => f.write_str("BracketedPaste"),
+            Mode::Lnm => f.write_str("Lnm"),
+            Mode::Irm => f.write_str("Irm"),
+            Mode::Decawm => f.write_str("Decawm"),
+            Mode::Decom => f.write_str("Decom"),
+            Mode::AlternateScreen => f.write_str("AlternateScreen"),
+            Mode::SynchronizedOutput => f.write_str("SynchronizedOutput"),
             Mode::Unknown(params) => {
                 let params_s = std::str::from_utf8(params)
                     .expect("parameter parsing should not allow non-utf8 characters here");
@@ -49,6 +118,33 @@ impl fmt::Debug for Mode {
     }
 }
 
+/// Progress a program reports with OSC 9;4, e.g. for a build or a download. Percentages are
+/// 0 to 100
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Progress {
+    Normal(u8),
+    Error(u8),
+    Indeterminate,
+    // Shown as a warning by other terminals
+    Paused(u8),
+}
+
+/// Desktop notification a program asked for with OSC 9 or OSC 777
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Notification {
+    pub title: Option<String>,
+    pub body: String,
+}
+
+/// Notifications kept until the GUI picks them up. Headless runs never do
+const MAX_PENDING_NOTIFICATIONS: usize = 16;
+
+/// Titles kept by XTPUSHTITLE, the same as xterm. The oldest one goes when more are pushed
+const MAX_TITLE_STACK_DEPTH: usize = 10;
+
+/// Longest a line may get before it is broken, see [`TerminalEmulator::set_max_line_length`]
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
 fn char_to_ctrl_code(c: u8) -> u8 {
     // https://catern.com/posts/terminal_quirks.html
     // man ascii
@@ -61,13 +157,45 @@ enum TerminalInputPayload {
     Many(&'static [u8]),
 }
 
+/// What the Enter key sends while the application has not enabled [`Mode::Lnm`]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EnterSequence {
+    Cr,
+    Lf,
+    CrLf,
+}
+
+impl EnterSequence {
+    fn bytes(self) -> &'static [u8] {
+        match self {
+            EnterSequence::Cr => b"\r",
+            EnterSequence::Lf => b"\n",
+            EnterSequence::CrLf => b"\r\n",
+        }
+    }
+}
+
+impl std::str::FromStr for EnterSequence {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "cr" => EnterSequence::Cr,
+            "lf" => EnterSequence::Lf,
+            "crlf" => EnterSequence::CrLf,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
 #[derive(Clone)]
 pub enum TerminalInput {
     // Normal keypress
     Ascii(u8),
     // Normal keypress with ctrl
     Ctrl(u8),
-    Enter,
+    Enter(EnterSequence),
     Backspace,
     ArrowRight,
     ArrowLeft,
@@ -82,11 +210,12 @@ pub enum TerminalInput {
 }
 
 impl TerminalInput {
-    fn to_payload(&self, decckm_mode: bool) -> TerminalInputPayload {
+    fn to_payload(&self, decckm_mode: bool, lnm_mode: bool) -> TerminalInputPayload {
         match self {
             TerminalInput::Ascii(c) => TerminalInputPayload::Single(*c),
             TerminalInput::Ctrl(c) => TerminalInputPayload::Single(char_to_ctrl_code(*c)),
-            TerminalInput::Enter => TerminalInputPayload::Single(b'\n'),
+            TerminalInput::Enter(_) if lnm_mode => TerminalInputPayload::Many(b"\r\n"),
+            TerminalInput::Enter(sequence) => TerminalInputPayload::Many(sequence.bytes()),
             // Hard to tie back, but check default VERASE in terminfo definition
             TerminalInput::Backspace => TerminalInputPayload::Single(0x7f),
             // https://vt100.net/docs/vt100-ug/chapter3.html
@@ -225,6 +354,9 @@ mod cursor_state_keys {
     pub const POS: &str = "pos";
     pub const BOLD: &str = "bold";
     pub const COLOR: &str = "color";
+    pub const BACKGROUND: &str = "background";
+    pub const UNDERLINE: &str = "underline";
+    pub const UNDERLINE_COLOR: &str = "underline_color";
 }
 
 #[derive(Debug, Error)]
@@ -241,6 +373,18 @@ enum LoadCursorStateErrorPriv {
     ColorNotString,
     #[error("color failed to parse")]
     ColorInvalid(()),
+    #[error("background field is not a string")]
+    BackgroundNotString,
+    #[error("background failed to parse")]
+    BackgroundInvalid(()),
+    #[error("underline field is not a string")]
+    UnderlineNotString,
+    #[error("underline failed to parse")]
+    UnderlineInvalid(()),
+    #[error("underline color field is not a string")]
+    UnderlineColorNotString,
+    #[error("underline color failed to parse")]
+    UnderlineColorInvalid(()),
     #[error("pos field not present")]
     PosNotPresent,
     #[error("failed to parse position")]
@@ -256,9 +400,24 @@ struct CursorState {
     pos: CursorPos,
     bold: bool,
     color: TerminalColor,
+    background: TerminalColor,
+    underline: UnderlineStyle,
+    underline_color: TerminalColor,
 }
 
 impl CursorState {
+    /// At the top left with default attributes
+    fn new() -> CursorState {
+        CursorState {
+            pos: CursorPos { x: 0, y: 0 },
+            bold: false,
+            color: TerminalColor::Default,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+        }
+    }
+
     fn from_snapshot(snapshot: SnapshotItem) -> Result<CursorState, LoadCursorStateError> {
         use LoadCursorStateErrorPriv::*;
         let mut map = snapshot.into_map().map_err(|_| RootNotMap)?;
@@ -279,7 +438,36 @@ impl CursorState {
         let pos = map.remove(cursor_state_keys::POS).ok_or(PosNotPresent)?;
         let pos = CursorPos::from_snapshot(pos).map_err(FailParsePos)?;
 
-        Ok(CursorState { bold, color, pos })
+        let background = match map.remove(cursor_state_keys::BACKGROUND) {
+            Some(SnapshotItem::String(background)) => {
+                background.parse().map_err(BackgroundInvalid)?
+            }
+            Some(_) => Err(BackgroundNotString)?,
+            None => TerminalColor::Default,
+        };
+
+        let underline = match map.remove(cursor_state_keys::UNDERLINE) {
+            Some(SnapshotItem::String(underline)) => underline.parse().map_err(UnderlineInvalid)?,
+            Some(_) => Err(UnderlineNotString)?,
+            None => UnderlineStyle::None,
+        };
+
+        let underline_color = match map.remove(cursor_state_keys::UNDERLINE_COLOR) {
+            Some(SnapshotItem::String(underline_color)) => {
+                underline_color.parse().map_err(UnderlineColorInvalid)?
+            }
+            Some(_) => Err(UnderlineColorNotString)?,
+            None => TerminalColor::Default,
+        };
+
+        Ok(CursorState {
+            bold,
+            color,
+            background,
+            underline,
+            underline_color,
+            pos,
+        })
     }
 
     fn snapshot(&self) -> Result<SnapshotItem, SnapshotCursorPosError> {
@@ -291,6 +479,18 @@ impl CursorState {
                     cursor_state_keys::COLOR.to_string(),
                     self.color.to_string().into(),
                 ),
+                (
+                    cursor_state_keys::BACKGROUND.to_string(),
+                    self.background.to_string().into(),
+                ),
+                (
+                    cursor_state_keys::UNDERLINE.to_string(),
+                    self.underline.to_string().into(),
+                ),
+                (
+                    cursor_state_keys::UNDERLINE_COLOR.to_string(),
+                    self.underline_color.to_string().into(),
+                ),
             ]
             .into(),
         );
@@ -335,7 +535,10 @@ impl std::str::FromStr for TerminalColor {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some(idx_str) = s.strip_prefix("indexed256(").and_then(|s| s.strip_suffix(')')) {
+        if let Some(idx_str) = s
+            .strip_prefix("indexed256(")
+            .and_then(|s| s.strip_suffix(')'))
+        {
             if let Ok(idx) = idx_str.parse::<u8>() {
                 return Ok(TerminalColor::Indexed256(idx));
             }
@@ -344,7 +547,11 @@ impl std::str::FromStr for TerminalColor {
         if let Some(rgb_str) = s.strip_prefix("rgb(").and_then(|s| s.strip_suffix(')')) {
             let parts: Vec<&str> = rgb_str.split(',').collect();
             if parts.len() == 3 {
-                if let (Ok(r), Ok(g), Ok(b)) = (parts[0].parse::<u8>(), parts[1].parse::<u8>(), parts[2].parse::<u8>()) {
+                if let (Ok(r), Ok(g), Ok(b)) = (
+                    parts[0].parse::<u8>(),
+                    parts[1].parse::<u8>(),
+                    parts[2].parse::<u8>(),
+                ) {
                     return Ok(TerminalColor::Rgb(r, g, b));
                 }
             }
@@ -366,6 +573,47 @@ impl std::str::FromStr for TerminalColor {
     }
 }
 
+/// Set with SGR 4, 21 and 24, or the `4:x` sub-parameter form for the styles past double
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnderlineStyle {
+    None,
+    Single,
+    Double,
+    Curly,
+    Dotted,
+    Dashed,
+}
+
+impl fmt::Display for UnderlineStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnderlineStyle::None => f.write_str("none"),
+            UnderlineStyle::Single => f.write_str("single"),
+            UnderlineStyle::Double => f.write_str("double"),
+            UnderlineStyle::Curly => f.write_str("curly"),
+            UnderlineStyle::Dotted => f.write_str("dotted"),
+            UnderlineStyle::Dashed => f.write_str("dashed"),
+        }
+    }
+}
+
+impl std::str::FromStr for UnderlineStyle {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "none" => UnderlineStyle::None,
+            "single" => UnderlineStyle::Single,
+            "double" => UnderlineStyle::Double,
+            "curly" => UnderlineStyle::Curly,
+            "dotted" => UnderlineStyle::Dotted,
+            "dashed" => UnderlineStyle::Dashed,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
 impl TerminalColor {
     fn from_sgr(sgr: SelectGraphicRendition) -> Option<TerminalColor> {
         let ret = match sgr {
@@ -379,6 +627,37 @@ impl TerminalColor {
             SelectGraphicRendition::ForegroundWhite => TerminalColor::White,
             SelectGraphicRendition::Foreground256(idx) => TerminalColor::Indexed256(idx),
             SelectGraphicRendition::ForegroundRgb(r, g, b) => TerminalColor::Rgb(r, g, b),
+            SelectGraphicRendition::DefaultForeground => TerminalColor::Default,
+            _ => return None,
+        };
+
+        Some(ret)
+    }
+
+    fn from_sgr_background(sgr: SelectGraphicRendition) -> Option<TerminalColor> {
+        let ret = match sgr {
+            SelectGraphicRendition::BackgroundBlack => TerminalColor::Black,
+            SelectGraphicRendition::BackgroundRed => TerminalColor::Red,
+            SelectGraphicRendition::BackgroundGreen => TerminalColor::Green,
+            SelectGraphicRendition::BackgroundYellow => TerminalColor::Yellow,
+            SelectGraphicRendition::BackgroundBlue => TerminalColor::Blue,
+            SelectGraphicRendition::BackgroundMagenta => TerminalColor::Magenta,
+            SelectGraphicRendition::BackgroundCyan => TerminalColor::Cyan,
+            SelectGraphicRendition::BackgroundWhite => TerminalColor::White,
+            SelectGraphicRendition::Background256(idx) => TerminalColor::Indexed256(idx),
+            SelectGraphicRendition::BackgroundRgb(r, g, b) => TerminalColor::Rgb(r, g, b),
+            SelectGraphicRendition::DefaultBackground => TerminalColor::Default,
+            _ => return None,
+        };
+
+        Some(ret)
+    }
+
+    fn from_sgr_underline(sgr: SelectGraphicRendition) -> Option<TerminalColor> {
+        let ret = match sgr {
+            SelectGraphicRendition::UnderlineColor256(idx) => TerminalColor::Indexed256(idx),
+            SelectGraphicRendition::UnderlineColorRgb(r, g, b) => TerminalColor::Rgb(r, g, b),
+            SelectGraphicRendition::DefaultUnderlineColor => TerminalColor::Default,
             _ => return None,
         };
 
@@ -392,21 +671,43 @@ pub struct TerminalData<T> {
 }
 
 #[derive(Debug, Error)]
-enum StartRecordingErrorPriv {
-    #[error("failed to start recording")]
-    Start(#[from] std::io::Error),
+enum SnapshotErrorPriv {
     #[error("failed to snapshot terminal buffer")]
-    SnapshotBuffer(#[from] buffer::CreateSnapshotError),
+    Buffer(#[from] buffer::CreateSnapshotError),
     #[error("failed to snapshot format tracker")]
-    SnapshotFormatTracker(#[from] format_tracker::SnapshotFormatTagError),
+    FormatTracker(#[from] format_tracker::SnapshotFormatTagError),
     #[error("failed to snapshot cursor")]
-    SnapshotCursor(#[from] SnapshotCursorPosError),
+    Cursor(#[from] SnapshotCursorPosError),
+    #[error("failed to snapshot line sizes")]
+    LineSizes(#[from] line_size::SnapshotLineSizeError),
+    #[error("failed to snapshot shell marks")]
+    ShellMarks(#[from] shell_marks::SnapshotShellMarkError),
+    #[error("failed to convert scroll region to i64")]
+    ScrollRegion(#[source] TryFromIntError),
+    #[error("failed to convert tab stop to i64")]
+    TabStop(#[source] TryFromIntError),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct SnapshotError(#[from] SnapshotErrorPriv);
+
+#[derive(Debug, Error)]
+enum StartRecordingErrorPriv {
+    #[error("failed to start recording")]
+    Start(#[from] std::io::Error),
+    #[error("failed to snapshot terminal")]
+    Snapshot(#[from] SnapshotError),
 }
 
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct StartRecordingError(#[from] StartRecordingErrorPriv);
 
+#[derive(Debug, Error)]
+#[error("failed to open session log")]
+pub struct StartSessionLogError(#[source] std::io::Error);
+
 #[derive(Debug, Error)]
 enum LoadSnapshotErrorPriv {
     #[error("root element is not a map")]
@@ -427,59 +728,389 @@ enum LoadSnapshotErrorPriv {
     DecckmNotPresent,
     #[error("decckm field not bool")]
     DecckmNotBool,
+    #[error("lnm field not bool")]
+    LnmNotBool,
+    #[error("insert mode field not bool")]
+    InsertModeNotBool,
+    #[error("origin mode field not bool")]
+    OriginModeNotBool,
+    #[error("autowrap mode field not bool")]
+    AutowrapModeNotBool,
+    #[error("bracketed paste field not bool")]
+    BracketedPasteNotBool,
     #[error("cursor_state not present")]
     CursorStateNotPresent,
     #[error("failed to load cursor state")]
     LoadCursorState(#[from] LoadCursorStateError),
+    #[error("failed to load line sizes")]
+    LoadLineSizes(#[from] line_size::LoadLineSizeSnapshotError),
+    #[error("failed to load shell marks")]
+    LoadShellMarks(#[from] shell_marks::LoadShellMarkSnapshotError),
+    #[error("failed to get scroll region as usize")]
+    ScrollRegionNotUsize(#[source] NotIntOfType),
+    #[error("scroll region {0}..{1} does not fit a screen of {2} rows")]
+    ScrollRegionInvalid(usize, usize, usize),
+    #[error("saved cursor is not a map")]
+    SavedCursorNotMap,
+    #[error("saved origin mode field not bool")]
+    SavedOriginModeNotBool,
+    #[error("tab stops are not an array of columns")]
+    TabStopsInvalid,
+    #[error("title stack is not an array of titles")]
+    TitleStackInvalid,
+    #[error("working directory is not a string")]
+    WorkingDirectoryNotString(#[source] recording::NotString),
+    #[error("last printed character is not a single character")]
+    LastGraphicCharInvalid,
+    #[error("primary screen is not a map")]
+    PrimaryScreenNotMap,
+    #[error("title is not a string")]
+    TitleNotString(#[source] recording::NotString),
+    #[error("color overrides are not [slot, r, g, b] arrays")]
+    ColorOverridesInvalid,
+    #[error("keyboard flags are not a number")]
+    KeyboardFlagsNotInt(#[source] NotIntOfType),
+    #[error("saved keyboard flags are not an array")]
+    KeyboardFlagsInvalid,
+    #[error("modifyOtherKeys level is not a number")]
+    ModifyOtherKeysNotInt(#[source] NotIntOfType),
 }
 
 #[derive(Debug, Error)]
 #[error(transparent)]
 pub struct LoadSnapshotError(#[from] LoadSnapshotErrorPriv);
 
+/// How long a synchronized update may take before what was received of it is shown anyway, so
+/// that a program that never ends one does not freeze the screen
+const SYNCHRONIZED_OUTPUT_TIMEOUT: Duration = Duration::from_millis(150);
+
+/// Past this many bytes of queued text and sequences, a synchronized update is shown as far as it
+/// got, so that a program that keeps sending cannot grow the queue without bound
+const MAX_SYNCHRONIZED_OUTPUT_SIZE: usize = 4 * 1024 * 1024;
+
+/// Output received since the program started a synchronized update, applied once it ends
+struct SynchronizedOutput {
+    started: Instant,
+    pending: Vec<TerminalOutput>,
+    // Roughly the bytes `pending` came from, checked against `MAX_SYNCHRONIZED_OUTPUT_SIZE`
+    size: usize,
+}
+
+/// Copy of what was sent to the child since [`TerminalEmulator::tap_input`]
+struct InputTap {
+    copy: Vec<u8>,
+    // Off to only see what keys are encoded as, without the child getting them
+    send: bool,
+}
+
+/// Cursor saved with DECSC, brought back with DECRC
+struct SavedCursor {
+    cursor_state: CursorState,
+    origin_mode: bool,
+}
+
+/// The main screen, put aside while the alternate screen is shown
+struct PrimaryScreen {
+    terminal_buffer: TerminalBuffer,
+    format_tracker: FormatTracker,
+    line_sizes: LineSizeTracker,
+    shell_marks: ShellMarkTracker,
+    cursor_state: CursorState,
+}
+
+impl PrimaryScreen {
+    fn from_snapshot(snapshot: SnapshotItem) -> Result<PrimaryScreen, LoadSnapshotErrorPriv> {
+        use LoadSnapshotErrorPriv::*;
+
+        let mut root = snapshot.into_map().map_err(|_| PrimaryScreenNotMap)?;
+        let terminal_buffer =
+            TerminalBuffer::from_snapshot(root.remove("terminal_buffer").ok_or(BufferNotPresent)?)
+                .map_err(LoadBuffer)?;
+        let format_tracker = FormatTracker::from_snapshot(
+            root.remove("format_tracker")
+                .ok_or(FormatTrackerNotPresent)?,
+        )
+        .map_err(LoadFormatTracker)?;
+        let line_sizes = match root.remove("line_sizes") {
+            Some(v) => LineSizeTracker::from_snapshot(v).map_err(LoadLineSizes)?,
+            None => LineSizeTracker::new(),
+        };
+        let shell_marks = match root.remove("shell_marks") {
+            Some(v) => ShellMarkTracker::from_snapshot(v).map_err(LoadShellMarks)?,
+            None => ShellMarkTracker::new(),
+        };
+        let cursor_state =
+            CursorState::from_snapshot(root.remove("cursor_state").ok_or(CursorStateNotPresent)?)
+                .map_err(LoadCursorState)?;
+
+        Ok(PrimaryScreen {
+            terminal_buffer,
+            format_tracker,
+            line_sizes,
+            shell_marks,
+            cursor_state,
+        })
+    }
+
+    fn snapshot(&self) -> Result<SnapshotItem, SnapshotErrorPriv> {
+        use SnapshotErrorPriv::*;
+
+        Ok(SnapshotItem::Map(
+            [
+                (
+                    "terminal_buffer".to_string(),
+                    self.terminal_buffer.snapshot().map_err(Buffer)?,
+                ),
+                (
+                    "format_tracker".to_string(),
+                    self.format_tracker.snapshot().map_err(FormatTracker)?,
+                ),
+                (
+                    "line_sizes".to_string(),
+                    self.line_sizes.snapshot().map_err(LineSizes)?,
+                ),
+                (
+                    "shell_marks".to_string(),
+                    self.shell_marks.snapshot().map_err(ShellMarks)?,
+                ),
+                (
+                    "cursor_state".to_string(),
+                    self.cursor_state.snapshot().map_err(Cursor)?,
+                ),
+            ]
+            .into(),
+        ))
+    }
+}
+
 pub struct TerminalEmulator<Io: TermIo> {
     parser: AnsiParser,
     terminal_buffer: TerminalBuffer,
     format_tracker: FormatTracker,
+    line_sizes: LineSizeTracker,
+    shell_marks: ShellMarkTracker,
+    // The configured colors, and the ones the program changed on top of them with OSC 4/10-12.
+    // `color_scheme` is what is shown
+    base_color_scheme: ColorScheme,
+    color_overrides: HashMap<ColorSlot, Rgb>,
+    color_scheme: ColorScheme,
     cursor_state: CursorState,
+    // Rows that scroll, set with DECSTBM. Rows outside of it, like status lines, stay put
+    scroll_region: Range<usize>,
     decckm_mode: bool,
+    lnm_mode: bool,
+    insert_mode: bool,
+    origin_mode: bool,
+    autowrap_mode: bool,
+    bracketed_paste_mode: bool,
+    // Set by DECSC
+    saved_cursor: Option<SavedCursor>,
+    // Kitty keyboard protocol, the alternate screen has its own flags
+    keyboard_flags: KeyboardFlagsStack,
+    alternate_keyboard_flags: KeyboardFlagsStack,
+    // modifyOtherKeys level, 0 while it is off
+    modify_other_keys: u8,
+    // Set while the alternate screen is shown
+    primary_screen: Option<PrimaryScreen>,
+    // Set while the program is in the middle of a synchronized update
+    synchronized_output: Option<SynchronizedOutput>,
+    // Set by the application through OSC 0/2
+    title: Option<String>,
+    // Titles saved with XTPUSHTITLE, the most recent last
+    title_stack: Vec<Option<String>>,
+    // Set by the shell through OSC 7
+    working_directory: Option<PathBuf>,
+    // Last printed character, for REP
+    last_graphic_char: Option<char>,
+    tab_stops: TabStops,
+    // Set by the application through OSC 9;4
+    progress: Option<Progress>,
+    // Waiting to be shown by the GUI
+    notifications: Vec<Notification>,
+    // Text for the clipboard from OSC 52, waiting for the GUI like notifications
+    clipboard_request: Option<Vec<u8>>,
+    // See `content_version`
+    content_version: u64,
+    bell: bool,
+    max_line_length: usize,
+    // Typed input is shown as if the child had echoed it
+    local_echo: bool,
+    input_tap: Option<InputTap>,
     recorder: Recorder,
+    session_log: Option<SessionLog>,
     io: Io,
 }
 
+/// Version number sent in secondary DA replies, 1.2.3 is sent as 10203
+fn version_code(version: &str) -> u32 {
+    version
+        .split('.')
+        .take(3)
+        .map(|part| part.parse::<u32>().unwrap_or(0))
+        .fold(0, |acc, part| acc * 100 + part.min(99))
+}
+
+/// Colors set with OSC 4/10-12 as `[slot, r, g, b]` arrays. Palette slots are their index, the
+/// foreground, background and cursor follow at 256, 257 and 258
+fn color_overrides_snapshot(color_overrides: &HashMap<ColorSlot, Rgb>) -> SnapshotItem {
+    color_overrides
+        .iter()
+        .map(|(slot, (r, g, b))| {
+            let slot = match slot {
+                ColorSlot::Palette(idx) => i64::from(*idx),
+                ColorSlot::Foreground => 256,
+                ColorSlot::Background => 257,
+                ColorSlot::Cursor => 258,
+            };
+            [slot, i64::from(*r), i64::from(*g), i64::from(*b)]
+                .into_iter()
+                .collect::<SnapshotItem>()
+        })
+        .collect()
+}
+
+fn color_overrides_from_snapshot(snapshot: SnapshotItem) -> Option<HashMap<ColorSlot, Rgb>> {
+    let mut ret = HashMap::new();
+    for item in snapshot.into_vec().ok()? {
+        let values = item
+            .into_vec()
+            .ok()?
+            .into_iter()
+            .map(|v| v.into_i64().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let [slot, r, g, b] = values[..] else {
+            return None;
+        };
+        let slot = match slot {
+            256 => ColorSlot::Foreground,
+            257 => ColorSlot::Background,
+            258 => ColorSlot::Cursor,
+            idx => ColorSlot::Palette(idx.try_into().ok()?),
+        };
+        let rgb = (r.try_into().ok()?, g.try_into().ok()?, b.try_into().ok()?);
+        ret.insert(slot, rgb);
+    }
+    Some(ret)
+}
+
+/// Titles saved with XTPUSHTITLE as an array per entry, empty for an entry that was pushed while
+/// no title was set
+fn title_stack_snapshot(title_stack: &[Option<String>]) -> SnapshotItem {
+    title_stack
+        .iter()
+        .map(|title| title.iter().map(String::as_str).collect::<SnapshotItem>())
+        .collect()
+}
+
+fn title_stack_from_snapshot(snapshot: SnapshotItem) -> Option<Vec<Option<String>>> {
+    let mut ret = Vec::new();
+    for item in snapshot.into_vec().ok()? {
+        let mut entry = item.into_vec().ok()?;
+        let title = match entry.len() {
+            0 => None,
+            1 => Some(entry.remove(0).into_string().ok()?),
+            _ => return None,
+        };
+        ret.push(title);
+    }
+    Some(ret)
+}
+
 pub const TERMINAL_WIDTH: usize = 50;
 pub const TERMINAL_HEIGHT: usize = 16;
 
-impl TerminalEmulator<PtyIo> {
-    pub fn new(recording_path: PathBuf) -> Result<TerminalEmulator<PtyIo>, CreatePtyIoError> {
-        let mut io = PtyIo::new()?;
+impl<P: Pty> TerminalEmulator<P> {
+    pub fn new(
+        recording_path: PathBuf,
+        options: &P::Options,
+    ) -> Result<TerminalEmulator<P>, P::SpawnError> {
+        let mut io = P::spawn(options)?;
 
         if let Err(e) = io.set_win_size(TERMINAL_WIDTH, TERMINAL_HEIGHT) {
             error!("Failed to set initial window size: {}", backtraced_err(&*e));
         }
 
-        let ret = TerminalEmulator {
-            parser: AnsiParser::new(),
-            terminal_buffer: TerminalBuffer::new(TERMINAL_WIDTH, TERMINAL_HEIGHT),
-            format_tracker: FormatTracker::new(),
-            decckm_mode: false,
-            cursor_state: CursorState {
-                pos: CursorPos { x: 0, y: 0 },
-                bold: false,
-                color: TerminalColor::Default,
-            },
-            recorder: Recorder::new(recording_path),
+        Ok(TerminalEmulator::with_io(
             io,
+            TERMINAL_WIDTH,
+            TERMINAL_HEIGHT,
+            recording_path,
+        ))
+    }
+
+    /// See [`Pty::child_exit_status`]
+    pub fn child_exit_status(&mut self) -> Result<Option<i32>, P::Error> {
+        self.io.child_exit_status()
+    }
+
+    /// See [`Pty::has_foreground_job`]
+    pub fn has_foreground_job(&self) -> Result<bool, P::Error> {
+        self.io.has_foreground_job()
+    }
+
+    /// See [`Pty::foreground_process_name`]
+    pub fn foreground_process_name(&self) -> Option<String> {
+        self.io.foreground_process_name()
+    }
+
+    /// See [`Pty::remote_host`]
+    pub fn remote_host(&self) -> Option<String> {
+        self.io.remote_host()
+    }
+
+    /// See [`Pty::set_output_waker`]
+    pub fn set_output_waker(&self, waker: OutputWaker, coalesce_window: Duration) {
+        self.io.set_output_waker(waker, coalesce_window);
+    }
+
+    /// See [`Pty::is_output_closed`]
+    pub fn is_output_closed(&mut self) -> bool {
+        self.io.is_output_closed()
+    }
+
+    /// Replace the child with a new shell at the current size, started in the directory the old
+    /// one last reported. See [`Self::start_over`] for what happens to the contents. The output
+    /// waker has to be set again
+    pub fn restart(
+        &mut self,
+        options: &P::Options,
+        keep_scrollback: bool,
+    ) -> Result<(), P::SpawnError> {
+        let in_last_directory = self
+            .working_directory
+            .as_deref()
+            .and_then(|dir| P::options_in_directory(options, dir));
+        let mut io = match in_last_directory.map(|options| P::spawn(&options)) {
+            Some(Ok(io)) => io,
+            // The directory may be gone, or belong to a remote shell
+            Some(Err(e)) => {
+                warn!(
+                    "Failed to start the shell in the last directory, starting it in the default one: {}",
+                    backtraced_err(&e)
+                );
+                P::spawn(options)?
+            }
+            None => P::spawn(options)?,
         };
-        Ok(ret)
+
+        let (width, height) = self.get_win_size();
+        if let Err(e) = io.set_win_size(width, height) {
+            error!("Failed to set window size: {}", backtraced_err(&*e));
+        }
+        // Dropping the old pty hangs up on the old shell
+        self.io = io;
+        self.start_over(keep_scrollback);
+        Ok(())
     }
 }
 
-impl TerminalEmulator<ReplayIo> {
+impl<Io: TermIo> TerminalEmulator<Io> {
+    /// A terminal in the state [`TerminalEmulator::snapshot`] took, talking to `io_handle`.
+    /// Fields that older snapshots do not have start out as they would in a new terminal
     pub fn from_snapshot(
         snapshot: SnapshotItem,
-        io_handle: ReplayIo,
-    ) -> Result<TerminalEmulator<ReplayIo>, LoadSnapshotError> {
+        io_handle: Io,
+    ) -> Result<TerminalEmulator<Io>, LoadSnapshotError> {
         use LoadSnapshotErrorPriv::*;
 
         let mut root = snapshot.into_map().map_err(|_| RootNotMap)?;
@@ -497,29 +1128,249 @@ impl TerminalEmulator<ReplayIo> {
         else {
             Err(DecckmNotBool)?
         };
+        let lnm_mode = match root.remove("lnm_mode") {
+            Some(SnapshotItem::Bool(v)) => v,
+            Some(_) => Err(LnmNotBool)?,
+            None => false,
+        };
+        let insert_mode = match root.remove("insert_mode") {
+            Some(SnapshotItem::Bool(v)) => v,
+            Some(_) => Err(InsertModeNotBool)?,
+            None => false,
+        };
+        let origin_mode = match root.remove("origin_mode") {
+            Some(SnapshotItem::Bool(v)) => v,
+            Some(_) => Err(OriginModeNotBool)?,
+            None => false,
+        };
+        let autowrap_mode = match root.remove("autowrap_mode") {
+            Some(SnapshotItem::Bool(v)) => v,
+            Some(_) => Err(AutowrapModeNotBool)?,
+            None => true,
+        };
+        let bracketed_paste_mode = match root.remove("bracketed_paste_mode") {
+            Some(SnapshotItem::Bool(v)) => v,
+            Some(_) => Err(BracketedPasteNotBool)?,
+            None => false,
+        };
         let cursor_state =
             CursorState::from_snapshot(root.remove("cursor_state").ok_or(CursorStateNotPresent)?)
                 .map_err(LoadCursorState)?;
+        // Recordings made before line sizes were tracked do not have this field
+        let line_sizes = match root.remove("line_sizes") {
+            Some(v) => LineSizeTracker::from_snapshot(v).map_err(LoadLineSizes)?,
+            None => LineSizeTracker::new(),
+        };
+        let shell_marks = match root.remove("shell_marks") {
+            Some(v) => ShellMarkTracker::from_snapshot(v).map_err(LoadShellMarks)?,
+            None => ShellMarkTracker::new(),
+        };
+        let primary_screen = root
+            .remove("primary_screen")
+            .map(PrimaryScreen::from_snapshot)
+            .transpose()?;
+        let (width, height) = terminal_buffer.get_win_size();
+        let mut scroll_region = 0..height;
+        if let Some(top) = root.remove("scroll_region_top") {
+            scroll_region.start = top.into_num().map_err(ScrollRegionNotUsize)?;
+        }
+        if let Some(bottom) = root.remove("scroll_region_bottom") {
+            scroll_region.end = bottom.into_num().map_err(ScrollRegionNotUsize)?;
+        }
+        // An empty region or one past the bottom would leave the cursor nowhere to go
+        if scroll_region.start >= scroll_region.end || scroll_region.end > height {
+            Err(ScrollRegionInvalid(
+                scroll_region.start,
+                scroll_region.end,
+                height,
+            ))?
+        }
+        let saved_cursor = match root.remove("saved_cursor") {
+            Some(v) => {
+                let mut saved = v.into_map().map_err(|_| SavedCursorNotMap)?;
+                let cursor_state = CursorState::from_snapshot(
+                    saved.remove("cursor_state").ok_or(CursorStateNotPresent)?,
+                )
+                .map_err(LoadCursorState)?;
+                let origin_mode = match saved.remove("origin_mode") {
+                    Some(SnapshotItem::Bool(v)) => v,
+                    _ => Err(SavedOriginModeNotBool)?,
+                };
+                Some(SavedCursor {
+                    cursor_state,
+                    origin_mode,
+                })
+            }
+            None => None,
+        };
+        let mut tab_stops = TabStops::new(width);
+        if let Some(v) = root.remove("tab_stops") {
+            tab_stops.clear_all();
+            for column in v.into_vec().map_err(|_| TabStopsInvalid)? {
+                tab_stops.set(column.into_num().map_err(|_| TabStopsInvalid)?);
+            }
+        }
+        let title_stack = match root.remove("title_stack") {
+            Some(v) => title_stack_from_snapshot(v).ok_or(TitleStackInvalid)?,
+            None => Vec::new(),
+        };
+        let working_directory = root
+            .remove("working_directory")
+            .map(SnapshotItem::into_string)
+            .transpose()
+            .map_err(WorkingDirectoryNotString)?
+            .map(PathBuf::from);
+        let last_graphic_char = match root.remove("last_graphic_char") {
+            Some(v) => {
+                let s = v.into_string().map_err(|_| LastGraphicCharInvalid)?;
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => Err(LastGraphicCharInvalid)?,
+                }
+            }
+            None => None,
+        };
+        let title = root
+            .remove("title")
+            .map(SnapshotItem::into_string)
+            .transpose()
+            .map_err(TitleNotString)?;
+        let color_overrides = match root.remove("color_overrides") {
+            Some(v) => color_overrides_from_snapshot(v).ok_or(ColorOverridesInvalid)?,
+            None => HashMap::new(),
+        };
+        let mut color_scheme = ColorScheme::default();
+        for (slot, rgb) in &color_overrides {
+            color_scheme.set_slot(*slot, *rgb);
+        }
+        // Pushing the saved flags in order and then the current ones rebuilds the stack
+        let keyboard_flags = |current: Option<SnapshotItem>,
+                              saved: Option<SnapshotItem>|
+         -> Result<_, LoadSnapshotErrorPriv> {
+            let mut flags = KeyboardFlagsStack::default();
+            let saved = match saved {
+                Some(v) => v.into_vec().map_err(|_| KeyboardFlagsInvalid)?,
+                None => Vec::new(),
+            };
+            for (i, item) in saved.into_iter().chain(current).enumerate() {
+                let bits = KeyboardFlags::from_bits(item.into_num().map_err(KeyboardFlagsNotInt)?);
+                match i {
+                    0 => flags.set(bits, SetKeyboardFlagsMode::Replace),
+                    _ => flags.push(bits),
+                }
+            }
+            Ok(flags)
+        };
+        let main_keyboard_flags = keyboard_flags(
+            root.remove("keyboard_flags"),
+            root.remove("keyboard_flags_saved"),
+        )?;
+        let alternate_keyboard_flags = keyboard_flags(
+            root.remove("alternate_keyboard_flags"),
+            root.remove("alternate_keyboard_flags_saved"),
+        )?;
+        let modify_other_keys = match root.remove("modify_other_keys") {
+            Some(v) => v.into_num().map_err(ModifyOtherKeysNotInt)?,
+            None => 0,
+        };
 
         Ok(TerminalEmulator {
             parser,
             terminal_buffer,
             format_tracker,
+            line_sizes,
+            shell_marks,
+            base_color_scheme: ColorScheme::default(),
+            color_overrides,
+            color_scheme,
+            scroll_region,
             decckm_mode,
+            lnm_mode,
+            insert_mode,
+            origin_mode,
+            autowrap_mode,
+            bracketed_paste_mode,
+            saved_cursor,
+            keyboard_flags: main_keyboard_flags,
+            alternate_keyboard_flags,
+            modify_other_keys,
+            primary_screen,
+            synchronized_output: None,
             cursor_state,
+            title,
+            title_stack,
+            working_directory,
+            last_graphic_char,
+            tab_stops,
+            progress: None,
+            notifications: Vec::new(),
+            clipboard_request: None,
+            content_version: 0,
+            bell: false,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            local_echo: false,
+            input_tap: None,
             recorder: Recorder::new("recordings".into()),
+            session_log: None,
             io: io_handle,
         })
     }
 }
 
 impl<Io: TermIo> TerminalEmulator<Io> {
-    pub fn get_win_size(&self) -> (usize, usize) {
-        self.terminal_buffer.get_win_size()
+    /// A terminal in the state it starts out in, talking to `io`. Recordings started later go to
+    /// `recording_path`
+    pub fn with_io(
+        io: Io,
+        width: usize,
+        height: usize,
+        recording_path: PathBuf,
+    ) -> TerminalEmulator<Io> {
+        TerminalEmulator {
+            parser: AnsiParser::new(),
+            terminal_buffer: TerminalBuffer::new(width, height),
+            format_tracker: FormatTracker::new(),
+            line_sizes: LineSizeTracker::new(),
+            shell_marks: ShellMarkTracker::new(),
+            base_color_scheme: ColorScheme::default(),
+            color_overrides: HashMap::new(),
+            color_scheme: ColorScheme::default(),
+            scroll_region: 0..height,
+            decckm_mode: false,
+            lnm_mode: false,
+            insert_mode: false,
+            origin_mode: false,
+            autowrap_mode: true,
+            bracketed_paste_mode: false,
+            saved_cursor: None,
+            keyboard_flags: KeyboardFlagsStack::default(),
+            alternate_keyboard_flags: KeyboardFlagsStack::default(),
+            modify_other_keys: 0,
+            primary_screen: None,
+            synchronized_output: None,
+            cursor_state: CursorState::new(),
+            title: None,
+            title_stack: Vec::new(),
+            working_directory: None,
+            last_graphic_char: None,
+            tab_stops: TabStops::new(width),
+            progress: None,
+            notifications: Vec::new(),
+            clipboard_request: None,
+            content_version: 0,
+            bell: false,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            local_echo: false,
+            input_tap: None,
+            recorder: Recorder::new(recording_path),
+            session_log: None,
+            io,
+        }
     }
 
-    pub fn win_size(&self) -> (usize, usize) {
-        self.get_win_size()
+    pub fn get_win_size(&self) -> (usize, usize) {
+        self.terminal_buffer.get_win_size()
     }
 
     pub fn set_win_size(
@@ -527,12 +1378,18 @@ impl<Io: TermIo> TerminalEmulator<Io> {
         width_chars: usize,
         height_chars: usize,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        // The program redraws for the new size, a frame for the old one is not worth holding
+        self.finish_synchronized_output();
+
         let response =
             self.terminal_buffer
                 .set_win_size(width_chars, height_chars, &self.cursor_state.pos);
         self.cursor_state.pos = response.new_cursor_pos;
 
         if response.changed {
+            self.content_changed();
+            self.scroll_region = 0..height_chars;
+            self.tab_stops.resize(width_chars);
             self.io.set_win_size(width_chars, height_chars)?;
             self.recorder.set_win_size(width_chars, height_chars);
         }
@@ -541,325 +1398,3114 @@ impl<Io: TermIo> TerminalEmulator<Io> {
     }
 
     pub fn write(&mut self, to_write: TerminalInput) -> Result<(), Box<dyn std::error::Error>> {
-        match to_write.to_payload(self.decckm_mode) {
-            TerminalInputPayload::Single(c) => {
-                let mut written = 0;
-                while written == 0 {
-                    written = self.io.write(&[c])?;
-                }
-            }
-            TerminalInputPayload::Many(mut to_write) => {
-                while !to_write.is_empty() {
-                    let written = self.io.write(to_write)?;
-                    to_write = &to_write[written..];
-                }
-            }
+        match &to_write {
+            // Controls are left to the child, only text and line editing are echoed
+            TerminalInput::Ascii(c) if *c >= b' ' && *c != 0x7f => self.echo(&[*c]),
+            TerminalInput::Enter(_) => self.echo(b"\r\n"),
+            TerminalInput::Backspace => self.echo(b"\x08 \x08"),
+            _ => {}
+        }
+
+        match to_write.to_payload(self.decckm_mode, self.lnm_mode) {
+            TerminalInputPayload::Single(c) => self.write_bytes(&[c])?,
+            TerminalInputPayload::Many(to_write) => self.write_bytes(to_write)?,
         };
         Ok(())
     }
 
-    fn handle_incoming_data(&mut self, incoming: &[u8]) {
-        let parsed = self.parser.push(incoming);
-        for segment in parsed {
-            match segment {
-                TerminalOutput::Data(data) => {
-                    let response = self
-                        .terminal_buffer
-                        .insert_data(&self.cursor_state.pos, &data);
-                    self.format_tracker
-                        .push_range_adjustment(response.insertion_range);
-                    self.format_tracker
-                        .push_range(&self.cursor_state, response.written_range);
-                    self.cursor_state.pos = response.new_cursor_pos;
-                }
-                TerminalOutput::SetCursorPos { x, y } => {
-                    if let Some(x) = x {
-                        self.cursor_state.pos.x = x - 1;
-                    }
-                    if let Some(y) = y {
-                        self.cursor_state.pos.y = y - 1;
-                    }
-                }
-                TerminalOutput::SetCursorPosRel { x, y } => {
-                    if let Some(x) = x {
-                        let x: i64 = x.into();
-                        let current_x: i64 = self
-                            .cursor_state
-                            .pos
-                            .x
-                            .try_into()
-                            .expect("x position larger than i64 can handle");
-                        self.cursor_state.pos.x = (current_x + x).max(0) as usize;
-                    }
-                    if let Some(y) = y {
-                        let y: i64 = y.into();
-                        let current_y: i64 = self
-                            .cursor_state
-                            .pos
-                            .y
-                            .try_into()
-                            .expect("y position larger than i64 can handle");
-                        self.cursor_state.pos.y = (current_y + y).max(0) as usize;
-                    }
+    /// Send `event` with the kitty keyboard protocol or modifyOtherKeys, whichever the program
+    /// asked for. The kitty one wins if both are on. Returns false when the event is left to the
+    /// legacy encoding
+    pub fn write_key(&mut self, event: &KeyEvent) -> Result<bool, Box<dyn std::error::Error>> {
+        let sequence = match self.keyboard_flags().is_empty() {
+            false => keyboard::encode_key(event, self.keyboard_flags()),
+            true => keyboard::encode_modify_other_keys(event, self.modify_other_keys),
+        };
+        let Some(sequence) = sequence else {
+            return Ok(false);
+        };
+        self.write_bytes(&sequence)?;
+        Ok(true)
+    }
+
+    /// Kitty keyboard protocol flags of the screen that is shown
+    pub fn keyboard_flags(&self) -> KeyboardFlags {
+        match self.is_alternate_screen() {
+            true => self.alternate_keyboard_flags.current(),
+            false => self.keyboard_flags.current(),
+        }
+    }
+
+    fn keyboard_flags_mut(&mut self) -> &mut KeyboardFlagsStack {
+        match self.is_alternate_screen() {
+            true => &mut self.alternate_keyboard_flags,
+            false => &mut self.keyboard_flags,
+        }
+    }
+
+    fn clear_scrollback(&mut self) {
+        let range = self.terminal_buffer.clear_scrollback();
+        self.format_tracker.delete_range(range.clone());
+        self.line_sizes.delete_lines(range.clone());
+        self.shell_marks.delete_lines(range);
+    }
+
+    /// Whether a full screen program switched to the alternate screen
+    pub fn is_alternate_screen(&self) -> bool {
+        self.primary_screen.is_some()
+    }
+
+    /// Switch to a blank alternate screen, the way `ESC[?1049h` does. The cursor is saved with
+    /// the main screen and the position carries over
+    fn enter_alternate_screen(&mut self) {
+        if self.primary_screen.is_some() {
+            return;
+        }
+
+        let (width, height) = self.terminal_buffer.get_win_size();
+        self.primary_screen = Some(PrimaryScreen {
+            terminal_buffer: std::mem::replace(
+                &mut self.terminal_buffer,
+                TerminalBuffer::new(width, height),
+            ),
+            format_tracker: std::mem::replace(&mut self.format_tracker, FormatTracker::new()),
+            line_sizes: std::mem::replace(&mut self.line_sizes, LineSizeTracker::new()),
+            shell_marks: std::mem::replace(&mut self.shell_marks, ShellMarkTracker::new()),
+            cursor_state: self.cursor_state.clone(),
+        });
+    }
+
+    /// Go back to the main screen and its saved cursor, resized to whatever the window became
+    /// in the meantime
+    fn exit_alternate_screen(&mut self) {
+        let Some(primary) = self.primary_screen.take() else {
+            return;
+        };
+        // A program that did not pop its flags should not leave them to the next one
+        self.alternate_keyboard_flags = KeyboardFlagsStack::default();
+
+        let (width, height) = self.terminal_buffer.get_win_size();
+        self.terminal_buffer = primary.terminal_buffer;
+        self.format_tracker = primary.format_tracker;
+        self.line_sizes = primary.line_sizes;
+        self.shell_marks = primary.shell_marks;
+        self.cursor_state = primary.cursor_state;
+
+        let response = self
+            .terminal_buffer
+            .set_win_size(width, height, &self.cursor_state.pos);
+        self.cursor_state.pos = response.new_cursor_pos;
+    }
+
+    /// Reset the terminal for when a program left it in a bad state. Goes through the parser as
+    /// CAN followed by RIS so that a half parsed sequence is dropped too, and so that replays of
+    /// the recording see the reset
+    pub fn reset(&mut self) {
+        self.handle_local_sequence(b"\x18\x1bc");
+    }
+
+    /// Clear the screen and the scrollback and home the cursor, like the clear buffer command of
+    /// other terminals. Unlike running `clear`, nothing is sent to the child. Goes through the
+    /// parser the same way as [`Self::reset`]
+    pub fn clear_buffer(&mut self) {
+        self.handle_local_sequence(b"\x18\x1b[H\x1b[2J\x1b[3J");
+    }
+
+    /// Clear the screen and reset everything the old child changed, for a new one. With
+    /// `keep_scrollback` the lines of the main screen up to the cursor move into the scrollback
+    /// instead of being lost, and the scrollback stays
+    fn start_over(&mut self, keep_scrollback: bool) {
+        if !keep_scrollback {
+            self.reset();
+            return;
+        }
+
+        self.handle_local_sequence(b"\x18\x1b[?1049l");
+        let used_rows = self.cursor_state.pos.y + 1;
+        let sequence = format!("\x1b[r\x1b[m\x1b[{used_rows}S\x1b[H\x1b[2J");
+        self.handle_local_sequence(sequence.as_bytes());
+        // Not part of the recording, there is no sequence for a reset that keeps the scrollback
+        self.reset_state();
+    }
+
+    /// Act on a sequence that comes from the terminal itself instead of the child, also while
+    /// sanitizing output
+    fn handle_local_sequence(&mut self, sequence: &[u8]) {
+        self.recorder.write(sequence);
+        let sanitize = self.parser.sanitize();
+        self.parser.set_sanitize(false);
+        self.handle_incoming_data(sequence);
+        self.parser.set_sanitize(sanitize);
+    }
+
+    /// See [`AnsiParser::set_sanitize`]
+    pub fn set_sanitize_output(&mut self, sanitize: bool) {
+        self.parser.set_sanitize(sanitize);
+    }
+
+    pub fn sanitize_output(&self) -> bool {
+        self.parser.sanitize()
+    }
+
+    /// How output that is not valid UTF-8 is shown
+    pub fn set_invalid_utf8(&mut self, invalid_utf8: InvalidUtf8) {
+        self.parser.set_invalid_utf8(invalid_utf8);
+    }
+
+    /// RIS. Everything except the window size and title goes back to how a new terminal starts,
+    /// including the scrollback
+    fn full_reset(&mut self) {
+        let (width, height) = self.terminal_buffer.get_win_size();
+        self.terminal_buffer = TerminalBuffer::new(width, height);
+        self.format_tracker = FormatTracker::new();
+        self.line_sizes = LineSizeTracker::new();
+        self.shell_marks = ShellMarkTracker::new();
+        self.reset_state();
+    }
+
+    /// The part of RIS that leaves the contents alone
+    fn reset_state(&mut self) {
+        let (width, _) = self.terminal_buffer.get_win_size();
+        self.primary_screen = None;
+        self.progress = None;
+        self.color_overrides.clear();
+        self.color_scheme = self.base_color_scheme.clone();
+        self.cursor_state = CursorState::new();
+        self.lnm_mode = false;
+        self.bracketed_paste_mode = false;
+        self.keyboard_flags = KeyboardFlagsStack::default();
+        self.alternate_keyboard_flags = KeyboardFlagsStack::default();
+        self.modify_other_keys = 0;
+        self.last_graphic_char = None;
+        self.tab_stops = TabStops::new(width);
+        self.soft_reset();
+    }
+
+    /// DECSTR. Modes, margins and text attributes are reset, while the screen and the cursor
+    /// position are left alone
+    fn soft_reset(&mut self) {
+        let (_, height) = self.terminal_buffer.get_win_size();
+        self.scroll_region = 0..height;
+        self.insert_mode = false;
+        self.origin_mode = false;
+        self.autowrap_mode = true;
+        self.decckm_mode = false;
+        self.saved_cursor = None;
+        self.cursor_state = CursorState {
+            pos: self.cursor_state.pos.clone(),
+            ..CursorState::new()
+        };
+    }
+
+    fn remember_last_graphic_char(&mut self, text: &str) {
+        if let Some(c) = text.chars().next_back() {
+            self.last_graphic_char = Some(c).filter(|c| !c.is_ascii_control());
+        }
+    }
+
+    /// DECRPM status of `mode`, 1 for set, 2 for reset and 0 for modes that are not supported
+    fn mode_status(&self, mode: &Mode) -> u8 {
+        let set = match mode {
+            Mode::Decckm => self.decckm_mode,
+            Mode::BracketedPaste => self.bracketed_paste_mode,
+            Mode::Lnm => self.lnm_mode,
+            Mode::Irm => self.insert_mode,
+            Mode::Decom => self.origin_mode,
+            Mode::Decawm => self.autowrap_mode,
+            Mode::AlternateScreen => self.is_alternate_screen(),
+            Mode::SynchronizedOutput => self.synchronized_output.is_some(),
+            Mode::Unknown(_) => return 0,
+        };
+        if set {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Answer a query from the child process
+    fn reply(&mut self, reply: &[u8]) {
+        if let Err(e) = self.write_bytes(reply) {
+            error!("Failed to reply to child process: {}", backtraced_err(&*e));
+        }
+    }
+
+    /// Send pasted text. With bracketed paste the program can tell it apart from typed input, so
+    /// an end marker inside the text is dropped to keep the text from ending the paste early
+    pub fn paste(&mut self, text: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        const PASTE_START: &[u8] = b"\x1b[200~";
+        const PASTE_END: &[u8] = b"\x1b[201~";
+
+        if self.local_echo {
+            let mut echoed = Vec::with_capacity(text.len());
+            for b in text {
+                match b {
+                    b'\n' => echoed.extend(b"\r\n"),
+                    b'\t' => echoed.push(b'\t'),
+                    0..0x20 | 0x7f => {}
+                    b => echoed.push(*b),
                 }
-                TerminalOutput::ClearForwards => {
-                    if let Some(buf_pos) =
-                        self.terminal_buffer.clear_forwards(&self.cursor_state.pos)
-                    {
-                        self.format_tracker
-                            .push_range(&self.cursor_state, buf_pos..usize::MAX);
-                    }
+            }
+            self.echo(&echoed);
+        }
+
+        if !self.bracketed_paste_mode {
+            return self.write_bytes(text);
+        }
+
+        let mut payload = PASTE_START.to_vec();
+        let mut remaining = text;
+        while let Some(pos) = remaining
+            .windows(PASTE_END.len())
+            .position(|window| window == PASTE_END)
+        {
+            payload.extend(&remaining[..pos]);
+            remaining = &remaining[pos + PASTE_END.len()..];
+        }
+        payload.extend(remaining);
+        payload.extend(PASTE_END);
+        self.write_bytes(&payload)
+    }
+
+    /// Send raw bytes to the child process, bypassing key encoding
+    pub fn write_bytes(&mut self, mut to_write: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(input_tap) = &mut self.input_tap {
+            input_tap.copy.extend(to_write);
+            if !input_tap.send {
+                return Ok(());
+            }
+        }
+        while !to_write.is_empty() {
+            let written = self.io.write(to_write)?;
+            to_write = &to_write[written..];
+        }
+        Ok(())
+    }
+
+    /// Start keeping a copy of everything sent to the child, to show what input was encoded as.
+    /// Replies to queries are only sent while reading, so between handling input and
+    /// [`Self::take_tapped_input`] the copy is the input alone. Unless `send`, the input is held
+    /// back from the child and not echoed, it only ends up in the copy
+    pub fn tap_input(&mut self, send: bool) {
+        self.input_tap = Some(InputTap {
+            copy: Vec::new(),
+            send,
+        });
+    }
+
+    /// What was sent since [`Self::tap_input`], and stop copying
+    pub fn take_tapped_input(&mut self) -> Vec<u8> {
+        self.input_tap
+            .take()
+            .map(|input_tap| input_tap.copy)
+            .unwrap_or_default()
+    }
+
+    /// Show `data` as if the child had written it, when local echo is on
+    fn echo(&mut self, data: &[u8]) {
+        let held_back = self
+            .input_tap
+            .as_ref()
+            .is_some_and(|input_tap| !input_tap.send);
+        if !self.local_echo || held_back {
+            return;
+        }
+
+        self.recorder.write(data);
+        if let Some(session_log) = &mut self.session_log {
+            session_log.write(data);
+        }
+        self.handle_incoming_data(data);
+    }
+
+    /// Background color erase. When the current background is not the default, erased cells still
+    /// have to show it, so fill from `pos` to the end of its line with spaces in that color.
+    /// Erasing with the default background leaves the line empty instead
+    fn fill_background(&mut self, pos: &CursorPos) {
+        if self.cursor_state.background == TerminalColor::Default {
+            return;
+        }
+
+        let (width, _) = self.terminal_buffer.get_win_size();
+        let response = self
+            .terminal_buffer
+            .insert_spaces(pos, width.saturating_sub(pos.x));
+        self.format_tracker
+            .push_range_adjustment(response.insertion_range.clone());
+        self.line_sizes
+            .push_range_adjustment(response.insertion_range.clone());
+        self.shell_marks
+            .push_range_adjustment(response.insertion_range);
+        self.format_tracker
+            .push_range(&self.cursor_state, response.written_range);
+    }
+
+    /// Whether DECSTBM limited scrolling to part of the screen
+    fn has_scroll_region(&self) -> bool {
+        let (_, height) = self.terminal_buffer.get_win_size();
+        self.scroll_region != (0..height)
+    }
+
+    /// Where the cursor goes for `ESC[H`, the top of the scroll region in origin mode
+    fn home_pos(&self) -> CursorPos {
+        let y = match self.origin_mode {
+            true => self.scroll_region.start,
+            false => 0,
+        };
+        CursorPos { x: 0, y }
+    }
+
+    /// DECALN, fills the screen with E's so that cell alignment can be checked. The margins are
+    /// reset and the cursor goes home
+    fn screen_alignment_test(&mut self) {
+        let screen_start = self.terminal_buffer.clear_visible();
+        self.format_tracker
+            .push_range(&CursorState::new(), screen_start..usize::MAX);
+        self.line_sizes.clear_from(screen_start);
+        self.shell_marks.clear_from(screen_start);
+
+        let (width, height) = self.terminal_buffer.get_win_size();
+        let row = vec!['E'; width];
+        for y in 0..height {
+            let response = self
+                .terminal_buffer
+                .insert_data(&CursorPos { x: 0, y }, &row);
+            self.format_tracker
+                .push_range_adjustment(response.insertion_range.clone());
+            self.line_sizes
+                .push_range_adjustment(response.insertion_range.clone());
+            self.shell_marks
+                .push_range_adjustment(response.insertion_range);
+        }
+        self.scroll_region = 0..height;
+        self.cursor_state.pos = CursorPos { x: 0, y: 0 };
+    }
+
+    /// Write text at the cursor, shifting the rest of each row it reaches over in insert mode
+    fn insert_data(&mut self, mut data: &[char]) {
+        if !self.insert_mode {
+            self.overwrite_data(data);
+            return;
+        }
+
+        let (width, _) = self.terminal_buffer.get_win_size();
+        while !data.is_empty() {
+            let space = width.saturating_sub(self.cursor_state.pos.x).max(1);
+            let (row, rest) = data.split_at(space.min(data.len()));
+            if let Some(response) = self
+                .terminal_buffer
+                .shift_right(&self.cursor_state.pos, row.len())
+            {
+                self.format_tracker
+                    .delete_range(response.deleted_range.clone());
+                self.line_sizes.delete_range(response.deleted_range.clone());
+                self.shell_marks.delete_range(response.deleted_range);
+                self.format_tracker
+                    .push_range_adjustment(response.inserted_range.clone());
+                self.line_sizes
+                    .push_range_adjustment(response.inserted_range.clone());
+                self.shell_marks
+                    .push_range_adjustment(response.inserted_range);
+            }
+            self.overwrite_data(row);
+            data = rest;
+        }
+    }
+
+    fn overwrite_data(&mut self, data: &[char]) {
+        let response = self
+            .terminal_buffer
+            .insert_data(&self.cursor_state.pos, data);
+        self.format_tracker
+            .push_range_adjustment(response.insertion_range.clone());
+        self.line_sizes
+            .push_range_adjustment(response.insertion_range.clone());
+        self.shell_marks
+            .push_range_adjustment(response.insertion_range);
+        self.shell_marks.place_pending(response.written_range.start);
+        self.format_tracker
+            .push_range(&self.cursor_state, response.written_range);
+        self.cursor_state.pos = response.new_cursor_pos;
+    }
+
+    /// Write `text` at the cursor, wide characters taking up two cells
+    fn write_text_at_cursor(&mut self, text: &str) {
+        let (width, _) = self.terminal_buffer.get_win_size();
+        let cells = text_to_cells(text, self.cursor_state.pos.x, width);
+        self.write_data(&cells);
+    }
+
+    /// Write text at the cursor. A line that reaches the maximum line length is broken, so that
+    /// output that never ends its lines, e.g. stray binary data, does not grow a single line
+    /// without bound. The break goes where the line wraps anyway, so it does not show until the
+    /// window is resized
+    fn write_data(&mut self, mut data: &[char]) {
+        if !self.autowrap_mode {
+            self.write_clipped_data(data);
+            return;
+        }
+
+        let (width, _) = self.terminal_buffer.get_win_size();
+        let width = width.max(1);
+        let max_line_length = (self.max_line_length / width).max(1) * width;
+        loop {
+            let line_offset = self
+                .terminal_buffer
+                .unwrapped_line_offset(&self.cursor_state.pos);
+            let space = max_line_length.saturating_sub(line_offset);
+            if data.len() <= space {
+                break;
+            }
+
+            let (fits, rest) = data.split_at(space);
+            self.write_wrapped_data(fits);
+            self.cursor_state.pos.x = 0;
+            self.line_feed();
+            data = rest;
+        }
+
+        self.write_wrapped_data(data);
+    }
+
+    /// Write text at the cursor with autowrap off. Text does not go past the end of the row,
+    /// what does not fit lands in the last column one character after the other, so that the
+    /// last one is left there. A wide last character takes the last two columns
+    fn write_clipped_data(&mut self, data: &[char]) {
+        let (width, _) = self.terminal_buffer.get_win_size();
+        if data.is_empty() || width == 0 {
+            return;
+        }
+
+        self.cursor_state.pos.x = self.cursor_state.pos.x.min(width - 1);
+        let space = width - self.cursor_state.pos.x;
+        if data.len() <= space {
+            self.insert_data(data);
+        } else {
+            let last_len = match data.last() {
+                Some(&WIDE_CHAR_SPACER) if width > 1 => 2,
+                _ => 1,
+            };
+            let head_len = space.saturating_sub(last_len);
+            let mut row = data[..head_len].to_vec();
+            // Half a wide character is not shown
+            if data[head_len] == WIDE_CHAR_SPACER {
+                row.pop();
+                row.push(' ');
+            }
+            row.extend_from_slice(&data[data.len() - last_len..]);
+            self.cursor_state.pos.x = width - row.len();
+            self.insert_data(&row);
+        }
+        self.cursor_state.pos.x = self.cursor_state.pos.x.min(width - 1);
+    }
+
+    /// Write text at the cursor. Text that wraps past the bottom of the scroll region scrolls
+    /// the region instead of running into the rows below it
+    fn write_wrapped_data(&mut self, mut data: &[char]) {
+        let (width, _) = self.terminal_buffer.get_win_size();
+        while self.has_scroll_region() && self.scroll_region.contains(&self.cursor_state.pos.y) {
+            let rows_below = self.scroll_region.end - 1 - self.cursor_state.pos.y;
+            let space = rows_below * width + width.saturating_sub(self.cursor_state.pos.x);
+            if data.len() <= space {
+                break;
+            }
+
+            let (fits, rest) = data.split_at(space);
+            if !fits.is_empty() {
+                self.insert_data(fits);
+            }
+            self.scroll_region_up(1);
+            self.cursor_state.pos = CursorPos {
+                x: 0,
+                y: self.scroll_region.end - 1,
+            };
+            data = rest;
+        }
+
+        if !data.is_empty() {
+            self.insert_data(data);
+        }
+    }
+
+    fn apply_scroll(&mut self, response: TerminalBufferScrollResponse) {
+        self.format_tracker
+            .push_range_adjustment(response.padding.clone());
+        self.line_sizes
+            .push_range_adjustment(response.padding.clone());
+        self.shell_marks.push_range_adjustment(response.padding);
+        self.format_tracker
+            .delete_range(response.deleted_range.clone());
+        self.line_sizes.delete_lines(response.deleted_range.clone());
+        self.shell_marks.delete_lines(response.deleted_range);
+        self.format_tracker
+            .push_range_adjustment(response.inserted_range.clone());
+        self.line_sizes
+            .push_lines_adjustment(response.inserted_range.clone());
+        self.shell_marks
+            .push_lines_adjustment(response.inserted_range);
+    }
+
+    /// Move the cursor down a row, scrolling the scroll region if the cursor is on its bottom row
+    fn line_feed(&mut self) {
+        let (_, height) = self.terminal_buffer.get_win_size();
+        if !self.has_scroll_region() {
+            self.cursor_state.pos.y += 1;
+        } else if self.cursor_state.pos.y + 1 == self.scroll_region.end {
+            self.scroll_region_up(1);
+        } else if self.cursor_state.pos.y + 1 < height {
+            // Below the region the screen does not scroll, the cursor stops on the last row
+            self.cursor_state.pos.y += 1;
+        }
+    }
+
+    /// Scroll the scroll region up, e.g. for a newline on its bottom row
+    fn scroll_region_up(&mut self, num_lines: usize) {
+        let region = self.scroll_region.clone();
+        let response = self.terminal_buffer.scroll_up(region.clone(), num_lines);
+        self.apply_scroll(response);
+
+        let num_lines = num_lines.min(region.len());
+        for y in region.end - num_lines..region.end {
+            self.fill_background(&CursorPos { x: 0, y });
+        }
+    }
+
+    /// Scroll the part of the scroll region from `top` down, e.g. for inserted lines
+    fn scroll_region_down(&mut self, top: usize, num_lines: usize) {
+        let region = top..self.scroll_region.end;
+        let response = self.terminal_buffer.scroll_down(region.clone(), num_lines);
+        self.apply_scroll(response);
+
+        let num_lines = num_lines.min(region.len());
+        for y in region.start..region.start + num_lines {
+            self.fill_background(&CursorPos { x: 0, y });
+        }
+    }
+
+    /// Blank `num_chars` cells from `pos` on in the current colors, without moving what comes
+    /// after them. Stops at the end of the row
+    fn erase_chars(&mut self, pos: &CursorPos, num_chars: usize) {
+        let (width, _) = self.terminal_buffer.get_win_size();
+        let num_chars = num_chars.min(width.saturating_sub(pos.x));
+        if num_chars == 0 {
+            return;
+        }
+
+        let response = self.terminal_buffer.insert_data(pos, &vec![' '; num_chars]);
+        self.format_tracker
+            .push_range_adjustment(response.insertion_range.clone());
+        self.line_sizes
+            .push_range_adjustment(response.insertion_range.clone());
+        self.shell_marks
+            .push_range_adjustment(response.insertion_range);
+        self.format_tracker
+            .push_range(&self.cursor_state, response.written_range);
+    }
+
+    /// Erase the row at `pos` from `pos.x` on
+    fn clear_line_forwards(&mut self, pos: &CursorPos) {
+        if let Some(response) = self.terminal_buffer.clear_line_forwards(pos) {
+            self.format_tracker
+                .delete_range(response.deleted_range.clone());
+            self.line_sizes.delete_range(response.deleted_range.clone());
+            self.shell_marks.delete_range(response.deleted_range);
+            self.format_tracker
+                .push_range_adjustment(response.inserted_range.clone());
+            self.line_sizes
+                .push_range_adjustment(response.inserted_range.clone());
+            self.shell_marks
+                .push_range_adjustment(response.inserted_range);
+        }
+        self.fill_background(pos);
+    }
+
+    /// Where a move to `x` on the cursor's row ends, so that the cursor does not land on the
+    /// middle of a character taking more than one cell. Moves to the left stop on its first
+    /// cell, moves to the right go past it when there is room
+    fn snap_to_char(&self, x: usize, forwards: bool) -> usize {
+        let pos = CursorPos {
+            x,
+            y: self.cursor_state.pos.y,
+        };
+        let (width, _) = self.terminal_buffer.get_win_size();
+        match self.terminal_buffer.char_cells(&pos) {
+            Some(cells) if cells.start == x => x,
+            Some(cells) if forwards && cells.end < width => cells.end,
+            Some(cells) => cells.start,
+            None => x,
+        }
+    }
+
+    /// RI, move the cursor up a row, scrolling the scroll region down on its top row
+    fn reverse_index(&mut self) {
+        if self.cursor_state.pos.y == self.scroll_region.start {
+            self.scroll_region_down(self.scroll_region.start, 1);
+        } else if self.cursor_state.pos.y > 0 {
+            self.cursor_state.pos.y -= 1;
+        }
+    }
+
+    fn handle_incoming_data(&mut self, incoming: &[u8]) {
+        let parsed = self.parser.push(incoming);
+        for segment in parsed {
+            if self.synchronized_output.is_some() {
+                self.queue_synchronized_output(segment);
+            } else {
+                self.handle_output(segment);
+            }
+        }
+
+        self.trim_alternate_screen_scrollback();
+    }
+
+    fn queue_synchronized_output(&mut self, output: TerminalOutput) {
+        match output {
+            TerminalOutput::ResetMode(Mode::SynchronizedOutput) => {
+                self.finish_synchronized_output();
+            }
+            TerminalOutput::SetMode(Mode::SynchronizedOutput) => (),
+            // Nothing that was queued matters after a reset
+            TerminalOutput::FullReset => {
+                self.synchronized_output = None;
+                self.full_reset();
+            }
+            // Queries are answered right away, the program may wait for the answer before it
+            // ends the update. The cursor position has to include what was queued, so that is
+            // shown first
+            TerminalOutput::RequestCursorPosition => {
+                self.apply_synchronized_output();
+                self.handle_output(output);
+            }
+            output @ (TerminalOutput::RequestDeviceAttributes
+            | TerminalOutput::RequestSecondaryDeviceAttributes
+            | TerminalOutput::RequestStatusReport
+            | TerminalOutput::RequestTerminalVersion
+            | TerminalOutput::RequestMode { .. }
+            | TerminalOutput::QueryKeyboardFlags
+            | TerminalOutput::QueryModifyOtherKeys
+            | TerminalOutput::QueryColor { .. }) => self.handle_output(output),
+            output => {
+                let Some(synchronized_output) = &mut self.synchronized_output else {
+                    return;
+                };
+                synchronized_output.size += match &output {
+                    TerminalOutput::Data(data) => data.len(),
+                    _ => 1,
+                };
+                synchronized_output.pending.push(output);
+
+                if synchronized_output.size >= MAX_SYNCHRONIZED_OUTPUT_SIZE {
+                    warn!("Synchronized update is too large, showing it as far as it got");
+                    self.apply_synchronized_output();
+                } else if synchronized_output.started.elapsed() >= SYNCHRONIZED_OUTPUT_TIMEOUT {
+                    warn!("Synchronized update did not end in time, showing it anyway");
+                    self.finish_synchronized_output();
+                }
+            }
+        }
+    }
+
+    /// Apply what was queued so far, but keep queueing until the synchronized update ends
+    fn apply_synchronized_output(&mut self) {
+        let Some(synchronized_output) = &mut self.synchronized_output else {
+            return;
+        };
+        let pending = std::mem::take(&mut synchronized_output.pending);
+        synchronized_output.size = 0;
+        for output in pending {
+            self.handle_output(output);
+        }
+    }
+
+    /// Apply everything received during the synchronized update at once
+    fn finish_synchronized_output(&mut self) {
+        let Some(synchronized_output) = self.synchronized_output.take() else {
+            return;
+        };
+
+        for output in synchronized_output.pending {
+            self.handle_output(output);
+        }
+        self.trim_alternate_screen_scrollback();
+    }
+
+    fn trim_alternate_screen_scrollback(&mut self) {
+        // The alternate screen has no scrollback, lines scrolled off the top are gone
+        if self.is_alternate_screen() {
+            self.clear_scrollback();
+        }
+    }
+
+    fn content_changed(&mut self) {
+        self.content_version = self.content_version.wrapping_add(1);
+    }
+
+    fn handle_output(&mut self, output: TerminalOutput) {
+        self.content_changed();
+        match output {
+            TerminalOutput::Data(data) => {
+                // The parser only passes on valid UTF-8
+                let text = String::from_utf8_lossy(&data);
+                self.remember_last_graphic_char(&text);
+                self.write_text_at_cursor(&text);
+            }
+            TerminalOutput::RepeatCharacter(count) => {
+                // Anything beyond a screenful only scrolls the same character by
+                let (width, height) = self.terminal_buffer.get_win_size();
+                let count = count.min(width * height);
+                if let Some(c) = self.last_graphic_char {
+                    self.write_text_at_cursor(&c.to_string().repeat(count));
+                }
+            }
+            TerminalOutput::SetTabStop => self.tab_stops.set(self.cursor_state.pos.x),
+            TerminalOutput::ClearTabStop { all: false } => {
+                self.tab_stops.clear(self.cursor_state.pos.x)
+            }
+            TerminalOutput::ClearTabStop { all: true } => self.tab_stops.clear_all(),
+            TerminalOutput::TabForward(count) => {
+                self.last_graphic_char = None;
+                self.cursor_state.pos.x = self.tab_stops.next(self.cursor_state.pos.x, count);
+            }
+            TerminalOutput::TabBackward(count) => {
+                self.last_graphic_char = None;
+                self.cursor_state.pos.x = self.tab_stops.prev(self.cursor_state.pos.x, count);
+            }
+            TerminalOutput::SetCursorPos { x, y } => {
+                // Positions are 1 based, 0 means the first one too
+                let (width, height) = self.terminal_buffer.get_win_size();
+                if let Some(x) = x {
+                    self.cursor_state.pos.x = x.saturating_sub(1).min(width.saturating_sub(1));
+                }
+                if let Some(y) = y {
+                    self.cursor_state.pos.y = match self.origin_mode {
+                        // Rows are counted from the top margin and cannot leave the region
+                        true => (self.scroll_region.start + y.saturating_sub(1))
+                            .min(self.scroll_region.end - 1),
+                        false => y.saturating_sub(1).min(height.saturating_sub(1)),
+                    };
+                }
+            }
+            TerminalOutput::SetCursorPosRel { x, y } => {
+                if let Some(x) = x {
+                    let x: i64 = x.into();
+                    let current_x: i64 = self
+                        .cursor_state
+                        .pos
+                        .x
+                        .try_into()
+                        .expect("x position larger than i64 can handle");
+                    // A cursor waiting to wrap counts as being on the last column
+                    let (width, _) = self.terminal_buffer.get_win_size();
+                    let max_x = width.saturating_sub(1) as i64;
+                    let new_x = (current_x.min(max_x) + x).clamp(0, max_x) as usize;
+                    self.cursor_state.pos.x = self.snap_to_char(new_x, x > 0);
+                }
+                if let Some(y) = y {
+                    let y: i64 = y.into();
+                    let current_y: i64 = self
+                        .cursor_state
+                        .pos
+                        .y
+                        .try_into()
+                        .expect("y position larger than i64 can handle");
+                    let new_y = (current_y + y).max(0) as usize;
+
+                    // Relative moves stop at the margins when they start inside the scroll
+                    // region, and at the edge of the screen otherwise
+                    let (_, height) = self.terminal_buffer.get_win_size();
+                    let limits = if self.scroll_region.contains(&self.cursor_state.pos.y) {
+                        self.scroll_region.clone()
+                    } else {
+                        0..height
+                    };
+                    self.cursor_state.pos.y =
+                        new_y.clamp(limits.start, limits.end.saturating_sub(1));
                 }
-                TerminalOutput::ClearAll => {
+            }
+            TerminalOutput::ClearForwards => {
+                if let Some(buf_pos) = self.terminal_buffer.clear_forwards(&self.cursor_state.pos) {
                     self.format_tracker
-                        .push_range(&self.cursor_state, 0..usize::MAX);
-                    self.terminal_buffer.clear_all();
-                }
-                TerminalOutput::ClearLineForwards => {
-                    if let Some(range) = self
-                        .terminal_buffer
-                        .clear_line_forwards(&self.cursor_state.pos)
-                    {
-                        self.format_tracker.delete_range(range);
-                    }
+                        .push_range(&self.cursor_state, buf_pos..usize::MAX);
+                    // A line erased from the cursor onwards keeps its size unless the whole
+                    // line went
+                    let first_erased_line = match self.cursor_state.pos.x {
+                        0 => buf_pos,
+                        _ => buf_pos + 1,
+                    };
+                    self.line_sizes.clear_from(first_erased_line);
+                    self.shell_marks.clear_from(buf_pos);
+                }
+
+                let (_, height) = self.terminal_buffer.get_win_size();
+                let pos = self.cursor_state.pos.clone();
+                self.fill_background(&pos);
+                for y in pos.y + 1..height {
+                    self.fill_background(&CursorPos { x: 0, y });
+                }
+            }
+            TerminalOutput::ClearAll => {
+                let screen_start = self.terminal_buffer.clear_visible();
+                self.format_tracker
+                    .push_range(&self.cursor_state, screen_start..usize::MAX);
+                self.line_sizes.clear_from(screen_start);
+                self.shell_marks.clear_from(screen_start);
+
+                let (_, height) = self.terminal_buffer.get_win_size();
+                for y in 0..height {
+                    self.fill_background(&CursorPos { x: 0, y });
+                }
+            }
+            TerminalOutput::ClearScrollback => self.clear_scrollback(),
+            TerminalOutput::ClearLineForwards => {
+                let pos = self.cursor_state.pos.clone();
+                self.clear_line_forwards(&pos);
+            }
+            TerminalOutput::ClearLineBackwards => {
+                let pos = self.cursor_state.pos.clone();
+                self.erase_chars(&CursorPos { x: 0, y: pos.y }, pos.x + 1);
+            }
+            TerminalOutput::ClearLine => {
+                let y = self.cursor_state.pos.y;
+                self.clear_line_forwards(&CursorPos { x: 0, y });
+            }
+            TerminalOutput::ClearBackwards => {
+                let pos = self.cursor_state.pos.clone();
+                for y in 0..pos.y {
+                    self.clear_line_forwards(&CursorPos { x: 0, y });
                 }
-                TerminalOutput::CarriageReturn => {
+                self.erase_chars(&CursorPos { x: 0, y: pos.y }, pos.x + 1);
+            }
+            TerminalOutput::EraseCharacters(num_chars) => {
+                let pos = self.cursor_state.pos.clone();
+                self.erase_chars(&pos, num_chars);
+            }
+            TerminalOutput::CarriageReturn => {
+                self.cursor_state.pos.x = 0;
+            }
+            TerminalOutput::Newline => {
+                if self.lnm_mode {
                     self.cursor_state.pos.x = 0;
                 }
-                TerminalOutput::Newline => {
-                    self.cursor_state.pos.y += 1;
+                self.line_feed();
+            }
+            TerminalOutput::Index => self.line_feed(),
+            TerminalOutput::NextLine => {
+                self.cursor_state.pos.x = 0;
+                self.line_feed();
+            }
+            TerminalOutput::ReverseIndex => self.reverse_index(),
+            TerminalOutput::SaveCursor => {
+                self.saved_cursor = Some(SavedCursor {
+                    cursor_state: self.cursor_state.clone(),
+                    origin_mode: self.origin_mode,
+                });
+            }
+            TerminalOutput::RestoreCursor => {
+                // Without a save the cursor goes home with the default attributes
+                let saved = self.saved_cursor.as_ref();
+                self.cursor_state =
+                    saved.map_or_else(CursorState::new, |saved| saved.cursor_state.clone());
+                self.origin_mode = saved.is_some_and(|saved| saved.origin_mode);
+
+                // The screen may have shrunk since
+                let (width, height) = self.terminal_buffer.get_win_size();
+                self.cursor_state.pos.x = self.cursor_state.pos.x.min(width.saturating_sub(1));
+                self.cursor_state.pos.y = self.cursor_state.pos.y.min(height.saturating_sub(1));
+            }
+            TerminalOutput::Backspace => {
+                if self.cursor_state.pos.x >= 1 {
+                    self.cursor_state.pos.x = self.snap_to_char(self.cursor_state.pos.x - 1, false);
                 }
-                TerminalOutput::Backspace => {
-                    if self.cursor_state.pos.x >= 1 {
-                        self.cursor_state.pos.x -= 1;
-                    }
+            }
+            TerminalOutput::ScrollUp(num_lines) => self.scroll_region_up(num_lines),
+            TerminalOutput::ScrollDown(num_lines) => {
+                self.scroll_region_down(self.scroll_region.start, num_lines);
+            }
+            TerminalOutput::InsertLines(num_lines) if self.has_scroll_region() => {
+                // Lines pushed past the bottom margin go away, the rows below it stay. Outside
+                // of the region there is nothing to insert into
+                if self.scroll_region.contains(&self.cursor_state.pos.y) {
+                    self.scroll_region_down(self.cursor_state.pos.y, num_lines);
                 }
-                TerminalOutput::InsertLines(num_lines) => {
-                    let response = self
-                        .terminal_buffer
-                        .insert_lines(&self.cursor_state.pos, num_lines);
-                    self.format_tracker.delete_range(response.deleted_range);
-                    self.format_tracker
-                        .push_range_adjustment(response.inserted_range);
-                }
-                TerminalOutput::Delete(num_chars) => {
-                    let deleted_buf_range = self
-                        .terminal_buffer
-                        .delete_forwards(&self.cursor_state.pos, num_chars);
-                    if let Some(range) = deleted_buf_range {
-                        self.format_tracker.delete_range(range);
-                    }
+            }
+            TerminalOutput::InsertLines(num_lines) => {
+                let response = self
+                    .terminal_buffer
+                    .insert_lines(&self.cursor_state.pos, num_lines);
+                self.format_tracker
+                    .delete_range(response.deleted_range.clone());
+                self.format_tracker
+                    .push_range_adjustment(response.inserted_range.clone());
+                // Lines pushed off the bottom of the screen are removed entirely
+                if !response.deleted_range.is_empty() {
+                    self.line_sizes.clear_from(response.deleted_range.start);
+                    self.shell_marks.clear_from(response.deleted_range.start);
                 }
-                TerminalOutput::Sgr(sgr) => {
-                    if let Some(color) = TerminalColor::from_sgr(sgr) {
-                        self.cursor_state.color = color;
-                    } else if sgr == SelectGraphicRendition::Reset {
-                        self.cursor_state.color = TerminalColor::Default;
-                        self.cursor_state.bold = false;
-                    } else if sgr == SelectGraphicRendition::Bold {
-                        self.cursor_state.bold = true;
-                    } else if matches!(sgr, SelectGraphicRendition::Background256(_) | SelectGraphicRendition::BackgroundRgb(_, _, _)) {
-                        // Background colors not yet implemented, silently ignore
-                    } else {
-                        warn!("Unhandled sgr: {:?}", sgr);
+                self.line_sizes
+                    .push_lines_adjustment(response.inserted_range.clone());
+                self.shell_marks
+                    .push_lines_adjustment(response.inserted_range);
+            }
+            TerminalOutput::DeleteLines(num_lines) => {
+                // Lines below the cursor move up within the region, blank ones come in at the
+                // bottom margin. Outside of the region there is nothing to delete from
+                if self.scroll_region.contains(&self.cursor_state.pos.y) {
+                    let region = self.cursor_state.pos.y..self.scroll_region.end;
+                    let response = self.terminal_buffer.delete_lines(region.clone(), num_lines);
+                    self.apply_scroll(response);
+
+                    let num_lines = num_lines.min(region.len());
+                    for y in region.end - num_lines..region.end {
+                        self.fill_background(&CursorPos { x: 0, y });
                     }
                 }
-                TerminalOutput::SetMode(mode) => match mode {
-                    Mode::Decckm => {
-                        self.decckm_mode = true;
-                    }
-                    Mode::BracketedPaste => {}
-                    _ => {
-                        warn!("unhandled set mode: {mode:?}");
-                    }
-                },
-                TerminalOutput::InsertSpaces(num_spaces) => {
-                    let response = self
-                        .terminal_buffer
-                        .insert_spaces(&self.cursor_state.pos, num_spaces);
-                    self.format_tracker
-                        .push_range_adjustment(response.insertion_range);
+            }
+            TerminalOutput::Delete(num_chars) => {
+                // Characters partly in the deleted cells go as a whole
+                let pos = self.cursor_state.pos.clone();
+                let last = CursorPos {
+                    x: pos.x.saturating_add(num_chars.max(1) - 1),
+                    y: pos.y,
+                };
+                let mut start = pos.clone();
+                let mut num_chars = num_chars;
+                if let Some(cells) = self.terminal_buffer.char_cells(&pos) {
+                    start.x = cells.start;
+                    num_chars = num_chars.saturating_add(pos.x - cells.start);
                 }
-                TerminalOutput::ResetMode(mode) => match mode {
-                    Mode::Decckm => {
-                        self.decckm_mode = false;
-                    }
-                    Mode::BracketedPaste => {}
-                    _ => {
-                        warn!("unhandled set mode: {mode:?}");
-                    }
-                },
-                TerminalOutput::Invalid => {}
+                if let Some(cells) = self.terminal_buffer.char_cells(&last) {
+                    num_chars = num_chars.max(cells.end - start.x);
+                }
+                self.cursor_state.pos.x = start.x;
+
+                let deleted_buf_range = self.terminal_buffer.delete_forwards(&start, num_chars);
+                if let Some(range) = deleted_buf_range {
+                    self.format_tracker.delete_range(range.clone());
+                    self.line_sizes.delete_range(range.clone());
+                    self.shell_marks.delete_range(range);
+                }
+            }
+            TerminalOutput::Sgr(sgr) => {
+                if let Some(color) = TerminalColor::from_sgr(sgr) {
+                    self.cursor_state.color = color;
+                } else if let Some(background) = TerminalColor::from_sgr_background(sgr) {
+                    self.cursor_state.background = background;
+                } else if let Some(underline_color) = TerminalColor::from_sgr_underline(sgr) {
+                    self.cursor_state.underline_color = underline_color;
+                } else if sgr == SelectGraphicRendition::Reset {
+                    self.cursor_state.color = TerminalColor::Default;
+                    self.cursor_state.background = TerminalColor::Default;
+                    self.cursor_state.underline = UnderlineStyle::None;
+                    self.cursor_state.underline_color = TerminalColor::Default;
+                    self.cursor_state.bold = false;
+                } else if let SelectGraphicRendition::Underline(style) = sgr {
+                    self.cursor_state.underline = style;
+                } else if sgr == SelectGraphicRendition::Bold {
+                    self.cursor_state.bold = true;
+                } else if sgr == SelectGraphicRendition::NormalIntensity {
+                    self.cursor_state.bold = false;
+                } else {
+                    warn!("Unhandled sgr: {:?}", sgr);
+                }
+            }
+            TerminalOutput::SetMode(mode) => match mode {
+                Mode::Decckm => {
+                    self.decckm_mode = true;
+                }
+                Mode::Lnm => {
+                    self.lnm_mode = true;
+                }
+                Mode::Irm => {
+                    self.insert_mode = true;
+                }
+                Mode::Decom => {
+                    self.origin_mode = true;
+                    self.cursor_state.pos = self.home_pos();
+                }
+                Mode::Decawm => {
+                    self.autowrap_mode = true;
+                }
+                Mode::BracketedPaste => {
+                    self.bracketed_paste_mode = true;
+                }
+                Mode::AlternateScreen => self.enter_alternate_screen(),
+                Mode::SynchronizedOutput => {
+                    self.synchronized_output = Some(SynchronizedOutput {
+                        started: Instant::now(),
+                        pending: Vec::new(),
+                        size: 0,
+                    });
+                }
+                _ => {
+                    warn!("unhandled set mode: {mode:?}");
+                }
+            },
+            TerminalOutput::InsertSpaces(num_spaces) => {
+                let response = self
+                    .terminal_buffer
+                    .insert_spaces(&self.cursor_state.pos, num_spaces);
+                self.format_tracker
+                    .push_range_adjustment(response.insertion_range.clone());
+                self.line_sizes
+                    .push_range_adjustment(response.insertion_range.clone());
+                self.shell_marks
+                    .push_range_adjustment(response.insertion_range);
+            }
+            TerminalOutput::ResetMode(mode) => match mode {
+                Mode::Decckm => {
+                    self.decckm_mode = false;
+                }
+                Mode::Lnm => {
+                    self.lnm_mode = false;
+                }
+                Mode::Irm => {
+                    self.insert_mode = false;
+                }
+                Mode::Decom => {
+                    self.origin_mode = false;
+                    self.cursor_state.pos = self.home_pos();
+                }
+                Mode::Decawm => {
+                    self.autowrap_mode = false;
+                }
+                Mode::BracketedPaste => {
+                    self.bracketed_paste_mode = false;
+                }
+                Mode::AlternateScreen => self.exit_alternate_screen(),
+                // Only reached when no synchronized update is in progress
+                Mode::SynchronizedOutput => (),
+                _ => {
+                    warn!("unhandled set mode: {mode:?}");
+                }
+            },
+            TerminalOutput::SetScrollRegion { top, bottom } => {
+                let (_, height) = self.terminal_buffer.get_win_size();
+                let top = top.filter(|v| *v > 0).unwrap_or(1) - 1;
+                let bottom = bottom.filter(|v| *v > 0).unwrap_or(height).min(height);
+                // A region needs at least two rows
+                if top + 1 < bottom {
+                    self.scroll_region = top..bottom;
+                    self.cursor_state.pos = self.home_pos();
+                } else {
+                    warn!("Invalid scroll region {top}..{bottom}");
+                }
+            }
+            TerminalOutput::SetTitle(title) => {
+                self.title = Some(title);
+            }
+            TerminalOutput::SetWorkingDirectory(path) => self.working_directory = Some(path),
+            TerminalOutput::PushTitle => {
+                if self.title_stack.len() >= MAX_TITLE_STACK_DEPTH {
+                    self.title_stack.remove(0);
+                }
+                self.title_stack.push(self.title.clone());
+            }
+            TerminalOutput::PopTitle => {
+                // Popping more than was pushed leaves the title alone
+                if let Some(title) = self.title_stack.pop() {
+                    self.title = title;
+                }
+            }
+            TerminalOutput::FullReset => self.full_reset(),
+            TerminalOutput::ScreenAlignmentTest => self.screen_alignment_test(),
+            TerminalOutput::SoftReset => self.soft_reset(),
+            TerminalOutput::RequestDeviceAttributes => {
+                // VT100 with advanced video, like the secondary DA says
+                self.reply(b"\x1b[?1;2c");
+            }
+            TerminalOutput::RequestStatusReport => self.reply(b"\x1b[0n"),
+            TerminalOutput::RequestCursorPosition => {
+                let (width, _) = self.terminal_buffer.get_win_size();
+                let CursorPos { x, y } = self.cursor_state.pos;
+                // Relative to the margins in origin mode, and a pending wrap is still on the last
+                // column
+                let row = match self.origin_mode {
+                    true => y.saturating_sub(self.scroll_region.start),
+                    false => y,
+                };
+                let col = x.min(width.saturating_sub(1));
+                self.reply(format!("\x1b[{};{}R", row + 1, col + 1).as_bytes());
+            }
+            TerminalOutput::RequestSecondaryDeviceAttributes => {
+                // VT100 type, no firmware options
+                let version = version_code(env!("CARGO_PKG_VERSION"));
+                self.reply(format!("\x1b[>0;{version};0c").as_bytes());
+            }
+            TerminalOutput::SetKeyboardFlags { flags, mode } => {
+                self.keyboard_flags_mut().set(flags, mode);
+            }
+            TerminalOutput::PushKeyboardFlags(flags) => self.keyboard_flags_mut().push(flags),
+            TerminalOutput::PopKeyboardFlags(count) => self.keyboard_flags_mut().pop(count),
+            TerminalOutput::QueryKeyboardFlags => {
+                let flags = self.keyboard_flags().bits();
+                self.reply(format!("\x1b[?{flags}u").as_bytes());
+            }
+            TerminalOutput::SetModifyOtherKeys(level) => self.modify_other_keys = level,
+            TerminalOutput::QueryModifyOtherKeys => {
+                let level = self.modify_other_keys;
+                self.reply(format!("\x1b[>4;{level}m").as_bytes());
+            }
+            TerminalOutput::RequestTerminalVersion => {
+                let version = env!("CARGO_PKG_VERSION");
+                self.reply(format!("\x1bP>|Termie {version}\x1b\\").as_bytes());
+            }
+            TerminalOutput::RequestMode { mode, params } => {
+                let mut reply = b"\x1b[".to_vec();
+                reply.extend(params);
+                reply.extend(format!(";{}$y", self.mode_status(&mode)).as_bytes());
+                self.reply(&reply);
+            }
+            TerminalOutput::QueryColor { slot, terminator } => {
+                let command = match slot {
+                    ColorSlot::Palette(idx) => format!("4;{idx}"),
+                    ColorSlot::Foreground => "10".to_string(),
+                    ColorSlot::Background => "11".to_string(),
+                    ColorSlot::Cursor => "12".to_string(),
+                };
+                let spec = format_color_spec(self.color_scheme.slot(slot));
+                let mut reply = format!("\x1b]{command};{spec}").into_bytes();
+                reply.extend(terminator);
+                self.reply(&reply);
+            }
+            TerminalOutput::SetColor { slot, rgb } => {
+                self.color_overrides.insert(slot, rgb);
+                self.color_scheme.set_slot(slot, rgb);
+            }
+            TerminalOutput::ResetColor(slot) => {
+                self.color_overrides.remove(&slot);
+                self.color_scheme
+                    .set_slot(slot, self.base_color_scheme.slot(slot));
+            }
+            TerminalOutput::ResetPalette => {
+                self.color_overrides
+                    .retain(|slot, _| !matches!(slot, ColorSlot::Palette(_)));
+                self.color_scheme.palette = self.base_color_scheme.palette;
+            }
+            TerminalOutput::SetLineSize(size) => {
+                // Make sure the cursor's line exists so there is a position to attach to
+                let line_pos = CursorPos {
+                    x: 0,
+                    y: self.cursor_state.pos.y,
+                };
+                let response = self.terminal_buffer.insert_spaces(&line_pos, 0);
+                self.format_tracker
+                    .push_range_adjustment(response.insertion_range.clone());
+                self.line_sizes
+                    .push_range_adjustment(response.insertion_range.clone());
+                self.shell_marks
+                    .push_range_adjustment(response.insertion_range);
+
+                if let Some(line_start) = self.terminal_buffer.line_start(line_pos.y) {
+                    self.line_sizes.set(line_start, size);
+                }
+            }
+            TerminalOutput::ShellMark(mark) => self.shell_marks.push(mark),
+            TerminalOutput::SetProgress(progress) => self.progress = progress,
+            TerminalOutput::Notify(notification) => {
+                if self.notifications.len() < MAX_PENDING_NOTIFICATIONS {
+                    self.notifications.push(notification);
+                } else {
+                    warn!("Dropping notification, too many pending");
+                }
+            }
+            TerminalOutput::SetClipboard(text) => self.clipboard_request = Some(text),
+            TerminalOutput::Bell => self.bell = true,
+            TerminalOutput::Invalid => {}
+        }
+    }
+
+    /// Handle everything the child process wrote so far. Returns whether there was anything
+    pub fn read(&mut self) -> bool {
+        let mut buf = vec![0u8; 4096];
+        let mut received = false;
+        loop {
+            let read_size = match self.io.read(&mut buf) {
+                Ok(ReadResponse::Empty) => break,
+                Ok(ReadResponse::Success(v)) => v,
+                Err(e) => {
+                    error!("Failed to read from child process: {e}");
+                    break;
+                }
+            };
+
+            let incoming = &buf[0..read_size];
+            debug!("Incoming data: {:?}", std::str::from_utf8(incoming));
+            // The new file starts from the state before this output
+            if self.recorder.should_split() {
+                self.split_recording();
+            }
+            self.recorder.write(incoming);
+            if let Some(session_log) = &mut self.session_log {
+                session_log.write(incoming);
+            }
+            self.handle_incoming_data(incoming);
+            received = true;
+        }
+
+        if received {
+            if let Some(session_log) = &mut self.session_log {
+                session_log.flush();
+            }
+        }
+
+        let synchronized_output_expired = self
+            .synchronized_output
+            .as_ref()
+            .is_some_and(|v| v.started.elapsed() >= SYNCHRONIZED_OUTPUT_TIMEOUT);
+        if synchronized_output_expired {
+            warn!("Synchronized update did not end in time, showing it anyway");
+            self.finish_synchronized_output();
+            received = true;
+        }
+
+        received
+    }
+
+    pub fn data(&self) -> TerminalData<&[char]> {
+        self.terminal_buffer.data()
+    }
+
+    pub fn format_data(&self) -> TerminalData<Vec<FormatTag>> {
+        let offset = self.terminal_buffer.data().scrollback.len();
+        split_format_data_for_scrollback(self.format_tracker.tags(), offset)
+    }
+
+    pub fn cursor_pos(&self) -> CursorPos {
+        self.cursor_state.pos.clone()
+    }
+
+    /// Changes whenever what the terminal shows may have changed, through output of the child, a
+    /// resize, a reset or new colors. Embedders can compare it with the version they last drew
+    /// to tell whether to draw again, without depending on the GUI's render loop. Output held
+    /// back by a synchronized update only counts once it is applied. Reading it is free, and
+    /// keeping it up to date costs an increment
+    pub fn content_version(&self) -> u64 {
+        self.content_version
+    }
+
+    /// Styled rows of the visible screen, top to bottom, for renderers other than the built in
+    /// widget. Lines below the last one written to are not included, so there may be fewer rows
+    /// than the terminal height
+    pub fn rows(&self) -> impl Iterator<Item = Row<'_>> {
+        let (buf, lines) = self.terminal_buffer.visible_lines();
+        let tags = self.format_tracker.tags();
+        lines.into_iter().map(move |line| {
+            let line_size = self.line_sizes.get(line.start);
+            Row::new(buf, line, &tags, line_size)
+        })
+    }
+
+    /// Rows covering `range` of the full buffer (scrollback followed by the visible area), cut
+    /// down to the part inside the range
+    pub fn rows_in_range(&self, range: Range<usize>) -> impl Iterator<Item = Row<'_>> {
+        let (buf, lines) = self.terminal_buffer.lines();
+        let tags = self.format_tracker.tags();
+        lines
+            .into_iter()
+            .filter(move |line| {
+                line.start < range.end && (line.end > range.start || line.start >= range.start)
+            })
+            .map(move |line| {
+                let line_size = self.line_sizes.get(line.start);
+                Row::new_clipped(buf, line, range.clone(), &tags, line_size)
+            })
+    }
+
+    pub fn color_scheme(&self) -> &ColorScheme {
+        &self.color_scheme
+    }
+
+    /// Break lines that get longer than `max_line_length` cells. Lines are broken at a multiple of
+    /// the terminal width
+    pub fn set_max_line_length(&mut self, max_line_length: usize) {
+        self.max_line_length = max_line_length;
+    }
+
+    /// Show typed and pasted text on the screen too, for devices and programs that do not echo
+    /// it themselves. A shell that does echo would show everything twice
+    pub fn set_local_echo(&mut self, local_echo: bool) {
+        self.local_echo = local_echo;
+    }
+
+    pub fn local_echo(&self) -> bool {
+        self.local_echo
+    }
+
+    /// Where [`Self::start_recording`] puts new recordings. Terminals from
+    /// [`Self::from_snapshot`] start out with `recordings`
+    pub fn set_recording_dir(&mut self, recording_dir: PathBuf) {
+        self.recorder.set_recording_dir(recording_dir);
+    }
+
+    /// Continue recordings in a new file once output resumes after stopping for `split_after`,
+    /// so that each burst of activity gets a file of its own. `None` keeps a single file
+    pub fn set_recording_split_after(&mut self, split_after: Option<Duration>) {
+        self.recorder.set_split_after(split_after);
+    }
+
+    /// Start the recording over from the current state, see [`Recorder::split`]
+    fn split_recording(&mut self) {
+        let snapshot = match self.snapshot_items() {
+            Ok(v) => v,
+            Err(e) => {
+                error!(
+                    "Failed to snapshot terminal for the next recording: {}",
+                    backtraced_err(&e)
+                );
+                return;
+            }
+        };
+        if let Err(e) = self.recorder.split(snapshot) {
+            error!("Failed to split recording: {}", backtraced_err(&e));
+        }
+    }
+
+    /// Change the configured colors. Colors the program changed stay changed
+    pub fn set_color_scheme(&mut self, color_scheme: ColorScheme) {
+        self.content_changed();
+        self.color_scheme = color_scheme.clone();
+        for (slot, rgb) in &self.color_overrides {
+            self.color_scheme.set_slot(*slot, *rgb);
+        }
+        self.base_color_scheme = color_scheme;
+    }
+
+    /// The visible screen as an HTML `<pre>` block with inline styles
+    pub fn to_html(&self) -> String {
+        html::rows_to_html(self.rows(), &self.color_scheme)
+    }
+
+    /// Like [`Self::to_html`], for `range` of the full buffer
+    pub fn range_to_html(&self, range: Range<usize>) -> String {
+        html::rows_to_html(self.rows_in_range(range), &self.color_scheme)
+    }
+
+    /// The visible screen re-serialized with SGR sequences, so that it can be written to another
+    /// terminal with its colors intact
+    pub fn to_ansi(&self) -> Vec<u8> {
+        ansi_export::rows_to_ansi(self.rows())
+    }
+
+    /// Like [`Self::to_ansi`], for `range` of the full buffer
+    pub fn range_to_ansi(&self, range: Range<usize>) -> Vec<u8> {
+        ansi_export::rows_to_ansi(self.rows_in_range(range))
+    }
+
+    /// The scrollback followed by the visible screen as plain text. Written a line at a time, so
+    /// that saving a long scrollback does not need a copy of it. Soft wrapped lines come out
+    /// joined, like when copying a selection
+    pub fn write_text(
+        &self,
+        out: &mut impl Write,
+        trim_trailing_whitespace: bool,
+    ) -> std::io::Result<()> {
+        let data = self.data();
+        for line in data
+            .scrollback
+            .split_inclusive(|c| *c == '\n')
+            .chain(data.visible.split_inclusive(|c| *c == '\n'))
+        {
+            let (text, newline) = match line.strip_suffix(&['\n']) {
+                Some(text) => (text, "\n"),
+                None => (line, ""),
+            };
+            let text = cells_to_string(text);
+            let text = match trim_trailing_whitespace {
+                true => text.trim_end(),
+                false => &text,
+            };
+            out.write_all(text.as_bytes())?;
+            out.write_all(newline.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Line sizes, split the same way as [`Self::format_data`]. Positions are line starts
+    pub fn line_sizes(&self) -> TerminalData<Vec<LineSizeTag>> {
+        let offset = self.terminal_buffer.data().scrollback.len();
+        let (scrollback, visible): (Vec<_>, Vec<_>) = self
+            .line_sizes
+            .tags()
+            .iter()
+            .cloned()
+            .partition(|tag| tag.line_start < offset);
+        let visible = visible
+            .into_iter()
+            .map(|mut tag| {
+                tag.line_start -= offset;
+                tag
+            })
+            .collect();
+        TerminalData {
+            scrollback,
+            visible,
+        }
+    }
+
+    pub fn cursor_line_size(&self) -> LineSize {
+        self.terminal_buffer
+            .line_start(self.cursor_state.pos.y)
+            .map(|line_start| self.line_sizes.get(line_start))
+            .unwrap_or(LineSize::SingleWidth)
+    }
+
+    /// Buffer range of the output of the last command that finished, e.g. for copying it. Uses
+    /// the OSC 133 marks of a shell integration when there are any. Without them the output is
+    /// guessed to be the lines between the cursor line and the previous line that starts with
+    /// the same prompt
+    pub fn last_command_output(&self) -> Option<Range<usize>> {
+        let (buf, lines) = self.terminal_buffer.lines();
+        if let Some(range) = self.shell_marks.last_command_output() {
+            // The newline that ended the output is not part of it
+            let end = match buf.get(range.end.wrapping_sub(1)) {
+                Some('\n') if range.end > range.start => range.end - 1,
+                _ => range.end,
+            };
+            return Some(range.start..end);
+        }
+
+        let cursor_line_start = self.terminal_buffer.line_start(self.cursor_state.pos.y)?;
+        let cursor_line = lines
+            .iter()
+            .position(|line| line.start == cursor_line_start)?;
+        let line = &lines[cursor_line];
+        let prompt_end = (line.start + self.cursor_state.pos.x).min(line.end);
+        let prompt = &buf[line.start..prompt_end];
+        let prompt_len = prompt
+            .iter()
+            .rposition(|c| !c.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        let prompt = &prompt[..prompt_len];
+        if prompt.is_empty() {
+            return None;
+        }
+
+        let prompt_line = lines[..cursor_line]
+            .iter()
+            .rposition(|line| buf[line.clone()].starts_with(prompt))?;
+        let start = lines[prompt_line + 1].start;
+        let end = lines[cursor_line - 1].end.max(start);
+        Some(start..end)
+    }
+
+    /// Title most recently set by the application, if any
+    pub fn window_title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Where the shell said it is with OSC 7
+    pub fn working_directory(&self) -> Option<&Path> {
+        self.working_directory.as_deref()
+    }
+
+    /// Progress the application reported, until it clears it again
+    pub fn progress(&self) -> Option<Progress> {
+        self.progress
+    }
+
+    /// Notifications received since the last call
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    /// Text a program put on the clipboard with OSC 52 since the last call. Only the latest
+    /// one is kept
+    pub fn take_clipboard_request(&mut self) -> Option<Vec<u8>> {
+        self.clipboard_request.take()
+    }
+
+    /// Whether the bell rang since the last call
+    pub fn take_bell(&mut self) -> bool {
+        std::mem::take(&mut self.bell)
+    }
+
+    pub fn start_recording(&mut self) -> Result<RecordingHandle, StartRecordingError> {
+        // Queued output is not part of the snapshot
+        self.finish_synchronized_output();
+        let response = self
+            .recorder
+            .start_recording()
+            .map_err(StartRecordingErrorPriv::Start)?;
+        self.initialize_recording(response)
+    }
+
+    pub fn start_recording_at(
+        &mut self,
+        recording_path: PathBuf,
+    ) -> Result<RecordingHandle, StartRecordingError> {
+        self.finish_synchronized_output();
+        let response = self
+            .recorder
+            .start_recording_at(recording_path)
+            .map_err(StartRecordingErrorPriv::Start)?;
+        self.initialize_recording(response)
+    }
+
+    /// Append all further output to the log at `path`, replacing a log that is already running
+    pub fn start_session_log(
+        &mut self,
+        path: PathBuf,
+        format: SessionLogFormat,
+    ) -> Result<(), StartSessionLogError> {
+        self.session_log = Some(SessionLog::open(path, format).map_err(StartSessionLogError)?);
+        Ok(())
+    }
+
+    /// Close the session log. It is flushed on drop as well, e.g. when the terminal exits
+    pub fn stop_session_log(&mut self) {
+        self.session_log = None;
+    }
+
+    /// Where output is logged to, if anywhere
+    pub fn session_log_path(&self) -> Option<&Path> {
+        self.session_log.as_ref().map(SessionLog::path)
+    }
+
+    /// The whole state of the terminal, in the form recordings start from. Loaded again with
+    /// [`TerminalEmulator::from_snapshot`]
+    pub fn snapshot(&self) -> Result<SnapshotItem, SnapshotError> {
+        Ok(SnapshotItem::Map(self.snapshot_items()?))
+    }
+
+    fn snapshot_items(&self) -> Result<HashMap<String, SnapshotItem>, SnapshotError> {
+        use SnapshotErrorPriv::*;
+
+        let top: i64 = self.scroll_region.start.try_into().map_err(ScrollRegion)?;
+        let bottom: i64 = self.scroll_region.end.try_into().map_err(ScrollRegion)?;
+
+        let mut snapshot: HashMap<String, SnapshotItem> = [
+            ("parser".to_string(), self.parser.snapshot()),
+            (
+                "terminal_buffer".to_string(),
+                self.terminal_buffer.snapshot().map_err(Buffer)?,
+            ),
+            (
+                "format_tracker".to_string(),
+                self.format_tracker.snapshot().map_err(FormatTracker)?,
+            ),
+            ("decckm_mode".to_string(), self.decckm_mode.into()),
+            ("lnm_mode".to_string(), self.lnm_mode.into()),
+            ("insert_mode".to_string(), self.insert_mode.into()),
+            ("origin_mode".to_string(), self.origin_mode.into()),
+            ("autowrap_mode".to_string(), self.autowrap_mode.into()),
+            (
+                "bracketed_paste_mode".to_string(),
+                self.bracketed_paste_mode.into(),
+            ),
+            (
+                "cursor_state".to_string(),
+                self.cursor_state.snapshot().map_err(Cursor)?,
+            ),
+            (
+                "line_sizes".to_string(),
+                self.line_sizes.snapshot().map_err(LineSizes)?,
+            ),
+            (
+                "shell_marks".to_string(),
+                self.shell_marks.snapshot().map_err(ShellMarks)?,
+            ),
+            ("scroll_region_top".to_string(), top.into()),
+            ("scroll_region_bottom".to_string(), bottom.into()),
+        ]
+        .into();
+        if let Some(primary_screen) = &self.primary_screen {
+            snapshot.insert("primary_screen".to_string(), primary_screen.snapshot()?);
+        }
+        if let Some(title) = &self.title {
+            snapshot.insert("title".to_string(), title.as_str().into());
+        }
+        snapshot.insert(
+            "color_overrides".to_string(),
+            color_overrides_snapshot(&self.color_overrides),
+        );
+        if let Some(saved_cursor) = &self.saved_cursor {
+            snapshot.insert(
+                "saved_cursor".to_string(),
+                SnapshotItem::Map(
+                    [
+                        (
+                            "cursor_state".to_string(),
+                            saved_cursor.cursor_state.snapshot().map_err(Cursor)?,
+                        ),
+                        ("origin_mode".to_string(), saved_cursor.origin_mode.into()),
+                    ]
+                    .into(),
+                ),
+            );
+        }
+        let tab_stops = self
+            .tab_stops
+            .columns()
+            .map(i64::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(TabStop)?;
+        snapshot.insert("tab_stops".to_string(), tab_stops.into_iter().collect());
+        snapshot.insert(
+            "title_stack".to_string(),
+            title_stack_snapshot(&self.title_stack),
+        );
+        if let Some(working_directory) = &self.working_directory {
+            snapshot.insert(
+                "working_directory".to_string(),
+                working_directory.to_string_lossy().as_ref().into(),
+            );
+        }
+        if let Some(c) = self.last_graphic_char {
+            snapshot.insert("last_graphic_char".to_string(), c.to_string().into());
+        }
+        for (name, flags) in [
+            ("keyboard_flags", &self.keyboard_flags),
+            ("alternate_keyboard_flags", &self.alternate_keyboard_flags),
+        ] {
+            snapshot.insert(name.to_string(), flags.current().bits().into());
+            snapshot.insert(
+                format!("{name}_saved"),
+                flags.saved().iter().map(|flags| flags.bits()).collect(),
+            );
+        }
+        snapshot.insert(
+            "modify_other_keys".to_string(),
+            self.modify_other_keys.into(),
+        );
+        Ok(snapshot)
+    }
+
+    fn initialize_recording(
+        &self,
+        response: StartRecordingResponse,
+    ) -> Result<RecordingHandle, StartRecordingError> {
+        match response {
+            StartRecordingResponse::New(initializer) => {
+                let snapshot = self
+                    .snapshot_items()
+                    .map_err(StartRecordingErrorPriv::Snapshot)?;
+                for (name, item) in snapshot {
+                    initializer.snapshot_item(name, item);
+                }
+                Ok(initializer.into_handle())
+            }
+            StartRecordingResponse::Existing(handle) => Ok(handle),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::cells;
+
+    #[test]
+    fn test_format_tracker_scrollback_split() {
+        let tags = vec![
+            FormatTag {
+                start: 0,
+                end: 5,
+                color: TerminalColor::Blue,
+                bold: true,
+                background: TerminalColor::Default,
+                underline: UnderlineStyle::None,
+                underline_color: TerminalColor::Default,
+            },
+            FormatTag {
+                start: 5,
+                end: 7,
+                color: TerminalColor::Red,
+                bold: false,
+                background: TerminalColor::Default,
+                underline: UnderlineStyle::None,
+                underline_color: TerminalColor::Default,
+            },
+            FormatTag {
+                start: 7,
+                end: 10,
+                color: TerminalColor::Blue,
+                bold: true,
+                background: TerminalColor::Default,
+                underline: UnderlineStyle::None,
+                underline_color: TerminalColor::Default,
+            },
+            FormatTag {
+                start: 10,
+                end: usize::MAX,
+                color: TerminalColor::Red,
+                bold: true,
+                background: TerminalColor::Default,
+                underline: UnderlineStyle::None,
+                underline_color: TerminalColor::Default,
+            },
+        ];
+
+        // Case 1: no split
+        let res = split_format_data_for_scrollback(tags.clone(), 0);
+        assert_eq!(res.scrollback, &[]);
+        assert_eq!(res.visible, &tags[..]);
+
+        // Case 2: Split on a boundary
+        let res = split_format_data_for_scrollback(tags.clone(), 10);
+        assert_eq!(res.scrollback, &tags[0..3]);
+        assert_eq!(
+            res.visible,
+            &[FormatTag {
+                start: 0,
+                end: usize::MAX,
+                color: TerminalColor::Red,
+                bold: true,
+                background: TerminalColor::Default,
+                underline: UnderlineStyle::None,
+                underline_color: TerminalColor::Default,
+            },]
+        );
+
+        // Case 3: Split a segment
+        let res = split_format_data_for_scrollback(tags.clone(), 9);
+        assert_eq!(
+            res.scrollback,
+            &[
+                FormatTag {
+                    start: 0,
+                    end: 5,
+                    color: TerminalColor::Blue,
+                    bold: true,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
+                },
+                FormatTag {
+                    start: 5,
+                    end: 7,
+                    color: TerminalColor::Red,
+                    bold: false,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
+                },
+                FormatTag {
+                    start: 7,
+                    end: 9,
+                    color: TerminalColor::Blue,
+                    bold: true,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
+                },
+            ]
+        );
+        assert_eq!(
+            res.visible,
+            &[
+                FormatTag {
+                    start: 0,
+                    end: 1,
+                    color: TerminalColor::Blue,
+                    bold: true,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
+                },
+                FormatTag {
+                    start: 1,
+                    end: usize::MAX,
+                    color: TerminalColor::Red,
+                    bold: true,
+                    background: TerminalColor::Default,
+                    underline: UnderlineStyle::None,
+                    underline_color: TerminalColor::Default,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cursor_state_snapshot() {
+        let state = CursorState {
+            pos: CursorPos { x: 10, y: 50 },
+            bold: false,
+            color: TerminalColor::Magenta,
+            background: TerminalColor::Blue,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+        };
+
+        let snapshot = state.snapshot().expect("failed to create snapshot");
+        let loaded = CursorState::from_snapshot(snapshot).expect("failed to load snapshot");
+        assert_eq!(loaded, state);
+    }
+
+    /// Keeps everything written to the child process
+    #[derive(Default)]
+    struct TestIo {
+        written: Vec<u8>,
+    }
+
+    impl TermIo for TestIo {
+        fn read(&mut self, _buf: &mut [u8]) -> Result<ReadResponse, io::TermIoErr> {
+            Ok(ReadResponse::Empty)
+        }
+
+        fn write(&mut self, buf: &[u8]) -> Result<usize, io::TermIoErr> {
+            self.written.extend(buf);
+            Ok(buf.len())
+        }
+
+        fn set_win_size(&mut self, _width: usize, _height: usize) -> Result<(), io::TermIoErr> {
+            Ok(())
+        }
+    }
+
+    fn test_emulator(width: usize, height: usize) -> TerminalEmulator<TestIo> {
+        TerminalEmulator::with_io(TestIo::default(), width, height, "recordings".into())
+    }
+
+    #[test]
+    fn test_reset_while_sanitizing() {
+        let mut emulator = test_emulator(10, 4);
+        emulator.set_sanitize_output(true);
+        emulator.handle_incoming_data(b"\x1bc");
+        assert_eq!(emulator.data().visible, cells("^[c\n"));
+        emulator.reset();
+        assert_eq!(emulator.data().visible, cells(""));
+        assert!(emulator.sanitize_output());
+    }
+
+    #[test]
+    fn test_max_line_length() {
+        let mut emulator = test_emulator(10, 4);
+        emulator.set_max_line_length(25);
+        emulator.handle_incoming_data(b"0123456789012345678901234");
+        // Broken at the last wrap before the limit
+        assert_eq!(
+            emulator.data().visible,
+            cells("01234567890123456789\n01234\n")
+        );
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 5, y: 2 });
+
+        // A megabyte of output without a newline ends up spread over bounded lines
+        let mut emulator = test_emulator(80, 24);
+        let line = vec![b'a'; 1024 * 1024];
+        emulator.handle_incoming_data(&line);
+        let data = emulator.data();
+        let data = [data.scrollback, data.visible].concat();
+        let max_line_length = DEFAULT_MAX_LINE_LENGTH / 80 * 80;
+        assert!(data
+            .split(|c| *c == '\n')
+            .all(|line| line.len() <= max_line_length));
+        assert!(data.len() <= line.len() + line.len() / max_line_length + 1);
+        assert_eq!(data.iter().filter(|c| **c == 'a').count(), line.len());
+    }
+
+    fn background_at(emulator: &TerminalEmulator<TestIo>, pos: usize) -> TerminalColor {
+        emulator
+            .format_tracker
+            .tags()
+            .into_iter()
+            .find(|tag| tag.start <= pos && pos < tag.end)
+            .expect("every position should be tagged")
+            .background
+    }
+
+    #[test]
+    fn test_line_size() {
+        let mut emulator = test_emulator(10, 4);
+        emulator.handle_incoming_data(b"ab\r\n\x1b#6cd");
+        assert_eq!(emulator.cursor_line_size(), LineSize::DoubleWidth);
+        assert_eq!(
+            emulator.line_sizes().visible,
+            &[LineSizeTag {
+                line_start: 3,
+                size: LineSize::DoubleWidth,
+            }]
+        );
+
+        // Inserting a line above moves the attribute with its line
+        emulator.handle_incoming_data(b"\x1b[1;1H\x1b[L");
+        assert_eq!(emulator.cursor_line_size(), LineSize::SingleWidth);
+        assert_eq!(emulator.line_sizes().visible[0].line_start, 4);
+
+        // Setting a size on a line that has not been written to yet
+        emulator.handle_incoming_data(b"\x1b[4;1H\x1b#3");
+        assert_eq!(emulator.cursor_line_size(), LineSize::DoubleHeightTop);
+        assert_eq!(emulator.data().visible, &cells("\nab\ncd\n\n"));
+
+        emulator.handle_incoming_data(b"\x1b[2J");
+        assert!(emulator.line_sizes().visible.is_empty());
+    }
+
+    #[test]
+    fn test_soft_and_hard_wraps() {
+        let wraps = |emulator: &TerminalEmulator<TestIo>| -> Vec<bool> {
+            emulator.rows().map(|row| row.is_wrapped()).collect()
+        };
+
+        // Only autowrap makes a soft wrap. A full row followed by a newline or by moving the
+        // cursor to the next row is a hard one
+        let mut emulator = test_emulator(4, 4);
+        emulator.handle_incoming_data(b"abcdef\r\nghij\r\nkl\x1b[4;1Hmn");
+        assert_eq!(row_texts(&emulator), ["abcd", "ef", "ghij", "mn"]);
+        assert_eq!(wraps(&emulator), [true, false, false, false]);
+
+        let mut text = Vec::new();
+        emulator
+            .write_text(&mut text, false)
+            .expect("failed to write text");
+        assert_eq!(text, b"abcdef\nghij\nmn\n");
+
+        // Soft wrapped lines reflow when the width changes, hard ones stay apart
+        let mut emulator = test_emulator(4, 4);
+        emulator.handle_incoming_data(b"abcdef\r\nghij");
+        emulator.set_win_size(8, 4).expect("failed to resize");
+        assert_eq!(row_texts(&emulator), ["abcdef", "ghij"]);
+        assert_eq!(wraps(&emulator), [false, false]);
+        emulator.set_win_size(3, 4).expect("failed to resize");
+        assert_eq!(row_texts(&emulator), ["abc", "def", "ghi", "j"]);
+        assert_eq!(wraps(&emulator), [true, false, true, false]);
+    }
+
+    #[test]
+    fn test_rows() {
+        let mut emulator = test_emulator(4, 3);
+        emulator.handle_incoming_data(b"ab\x1b[31mcdef\r\n\x1b#6\x1b[0mgh");
+
+        let rows: Vec<_> = emulator.rows().collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].text(), &cells("abcd"));
+        assert!(rows[0].is_wrapped());
+        assert_eq!(rows[1].text(), &cells("ef"));
+        assert_eq!(rows[1].runs()[0].style.color, TerminalColor::Red);
+        assert_eq!(rows[2].text(), &cells("gh"));
+        assert_eq!(rows[2].line_size(), LineSize::DoubleWidth);
+
+        let first_row: Vec<_> = rows[0]
+            .runs()
+            .iter()
+            .map(|run| (run.text, run.style.color))
+            .collect();
+        assert_eq!(
+            first_row,
+            [
+                (&cells("ab")[..], TerminalColor::Default),
+                (&cells("cd")[..], TerminalColor::Red)
+            ]
+        );
+        assert_eq!(rows[0].cells().count(), 4);
+    }
+
+    #[test]
+    fn test_version_code() {
+        assert_eq!(version_code("0.1.0"), 100);
+        assert_eq!(version_code("1.2.3"), 10203);
+        assert_eq!(version_code("2.0.0-beta.1"), 20000);
+    }
+
+    #[test]
+    fn test_identification_replies() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"\x1b[>c");
+        assert_eq!(
+            emulator.io.written,
+            format!("\x1b[>0;{};0c", version_code(env!("CARGO_PKG_VERSION"))).as_bytes()
+        );
+
+        emulator.io.written.clear();
+        emulator.handle_incoming_data(b"\x1b[>q");
+        assert_eq!(
+            emulator.io.written,
+            format!("\x1bP>|Termie {}\x1b\\", env!("CARGO_PKG_VERSION")).as_bytes()
+        );
+
+        emulator.io.written.clear();
+        emulator.handle_incoming_data(b"\x1b[c\x1b[5n\x1b[2;3H\x1b[6n");
+        assert_eq!(emulator.io.written, b"\x1b[?1;2c\x1b[0n\x1b[2;3R");
+
+        // In origin mode the position is relative to the margins, and the cursor past the last
+        // column is on it
+        emulator.io.written.clear();
+        emulator.handle_incoming_data(b"\x1b[2;3r\x1b[?6h\x1b[2;8Habc\x1b[6n");
+        assert_eq!(emulator.io.written, b"\x1b[2;10R");
+    }
+
+    #[test]
+    fn test_mode_replies() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"\x1b[?2004$p\x1b[?1049$p\x1b[20$p\x1b[?1000$p");
+        assert_eq!(
+            emulator.io.written,
+            b"\x1b[?2004;2$y\x1b[?1049;2$y\x1b[20;2$y\x1b[?1000;0$y"
+        );
+
+        emulator.io.written.clear();
+        emulator.handle_incoming_data(b"\x1b[?2004h\x1b[?1049h\x1b[20h");
+        emulator.handle_incoming_data(b"\x1b[?2004$p\x1b[?47$p\x1b[20$p\x1b[?1$p");
+        assert_eq!(
+            emulator.io.written,
+            b"\x1b[?2004;1$y\x1b[?47;1$y\x1b[20;1$y\x1b[?1;2$y"
+        );
+    }
+
+    #[test]
+    fn test_paste() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.paste(b"ls\n").expect("failed to paste");
+        assert_eq!(emulator.io.written, b"ls\n");
+
+        emulator.io.written.clear();
+        emulator.handle_incoming_data(b"\x1b[?2004h");
+        emulator.paste(b"a\x1b[201~b").expect("failed to paste");
+        assert_eq!(emulator.io.written, b"\x1b[200~ab\x1b[201~");
+
+        emulator.io.written.clear();
+        emulator.handle_incoming_data(b"\x1b[?2004l");
+        emulator.paste(b"c").expect("failed to paste");
+        assert_eq!(emulator.io.written, b"c");
+    }
+
+    #[test]
+    fn test_tap_input() {
+        let mut emulator = test_emulator(10, 3);
+        emulator
+            .write(TerminalInput::Ascii(b'a'))
+            .expect("failed to write");
+        assert!(emulator.take_tapped_input().is_empty());
+
+        emulator.tap_input(true);
+        emulator
+            .write(TerminalInput::Ctrl(b'c'))
+            .expect("failed to write");
+        emulator
+            .write(TerminalInput::ArrowUp)
+            .expect("failed to write");
+        assert_eq!(emulator.take_tapped_input(), b"\x03\x1b[A");
+        assert_eq!(emulator.io.written, b"a\x03\x1b[A");
+
+        // Nothing is copied after taking it
+        emulator
+            .write(TerminalInput::Ascii(b'b'))
+            .expect("failed to write");
+        assert!(emulator.take_tapped_input().is_empty());
+
+        // Input that is not sent is only copied, and not echoed either
+        emulator.set_local_echo(true);
+        emulator.tap_input(false);
+        emulator
+            .write(TerminalInput::Ascii(b'x'))
+            .expect("failed to write");
+        assert_eq!(emulator.take_tapped_input(), b"x");
+        assert_eq!(emulator.io.written, b"a\x03\x1b[Ab");
+        assert!(!emulator.data().visible.contains(&'x'));
+    }
+
+    #[test]
+    fn test_color_queries() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"\x1b]10;?\x07\x1b]11;?\x1b\\\x1b]4;1;?;17;?\x07");
+        assert_eq!(
+            emulator.io.written,
+            b"\x1b]10;rgb:8c8c/8c8c/8c8c\x07\
+              \x1b]11;rgb:1b1b/1b1b/1b1b\x1b\\\
+              \x1b]4;1;rgb:ffff/0000/0000\x07\
+              \x1b]4;17;rgb:0000/0000/5f5f\x07"
+        );
+    }
+
+    #[test]
+    fn test_set_colors() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"\x1b]4;1;rgb:12/34/56\x07\x1b]11;#abcdef;#010203\x07");
+        assert_eq!(emulator.color_scheme().palette[1], (0x12, 0x34, 0x56));
+        assert_eq!(emulator.color_scheme().background, (0xab, 0xcd, 0xef));
+        assert_eq!(emulator.color_scheme().cursor, (1, 2, 3));
+
+        emulator.handle_incoming_data(b"\x1b]12;?\x07");
+        assert_eq!(emulator.io.written, b"\x1b]12;rgb:0101/0202/0303\x07");
+    }
+
+    #[test]
+    fn test_color_overrides() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"\x1b]4;1;#010101;200;#020202\x07\x1b]10;#030303\x07");
+        assert_eq!(
+            emulator
+                .color_scheme()
+                .resolve(TerminalColor::Indexed256(200)),
+            Some((2, 2, 2))
+        );
+
+        // Overrides survive a change of the configured scheme
+        emulator.set_color_scheme(ColorScheme::light());
+        assert_eq!(emulator.color_scheme().palette[1], (1, 1, 1));
+        assert_eq!(emulator.color_scheme().foreground, (3, 3, 3));
+        assert_eq!(
+            emulator.color_scheme().background,
+            ColorScheme::light().background
+        );
+
+        emulator.handle_incoming_data(b"\x1b]104;200\x07");
+        assert_eq!(emulator.color_scheme().palette[1], (1, 1, 1));
+        assert_eq!(
+            emulator.color_scheme().palette[200],
+            ColorScheme::light().palette[200]
+        );
+
+        emulator.handle_incoming_data(b"\x1b]104\x07\x1b]110\x07");
+        assert_eq!(emulator.color_scheme(), &ColorScheme::light());
+    }
+
+    #[test]
+    fn test_enter_payload() {
+        let enter = TerminalInput::Enter(EnterSequence::Cr);
+        assert_eq!(
+            enter.to_payload(false, false),
+            TerminalInputPayload::Many(b"\r")
+        );
+        assert_eq!(
+            enter.to_payload(false, true),
+            TerminalInputPayload::Many(b"\r\n")
+        );
+        assert_eq!(
+            TerminalInput::Enter(EnterSequence::Lf).to_payload(false, false),
+            TerminalInputPayload::Many(b"\n")
+        );
+    }
+
+    #[test]
+    fn test_lnm_newline() {
+        let mut emulator = test_emulator(10, 5);
+        emulator.handle_incoming_data(b"ab\ncd\x1b[20hef\ngh\x1b[20l\nij");
+        assert!(!emulator.lnm_mode);
+        let rows: Vec<_> = emulator.rows().map(|row| row.text().to_vec()).collect();
+        assert_eq!(
+            rows,
+            [cells("ab"), cells("  cdef"), cells("gh"), cells("  ij")]
+        );
+    }
+
+    #[test]
+    fn test_underline_style() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"a\x1b[4:3mb\x1b[0mc");
+
+        let underlines: Vec<_> = emulator
+            .rows()
+            .flat_map(|row| row.cells().map(|c| c.style.underline).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(
+            underlines,
+            [
+                UnderlineStyle::None,
+                UnderlineStyle::Curly,
+                UnderlineStyle::None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_underline_color() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"\x1b[4:3;58;5;1ma\x1b[59mb\x1b[58;2;1;2;3mc\x1b[0md");
+
+        let colors: Vec<_> = emulator
+            .rows()
+            .flat_map(|row| {
+                row.cells()
+                    .map(|c| c.style.underline_color)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(
+            colors,
+            [
+                TerminalColor::Indexed256(1),
+                TerminalColor::Default,
+                TerminalColor::Rgb(1, 2, 3),
+                TerminalColor::Default
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_ansi_round_trip() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(
+            b"a\x1b[1;32mbc\x1b[44;4:3;58:2::255:0:0md\x1b[22;39;24;59mx\x1b[0m\r\ne\x1b[38;5;9mf",
+        );
+        let ansi = emulator.to_ansi();
+
+        let mut replayed = test_emulator(10, 3);
+        replayed.handle_incoming_data(
+            &ansi
+                .iter()
+                .flat_map(|b| match b {
+                    b'\n' => b"\r\n".to_vec(),
+                    b => vec![*b],
+                })
+                .collect::<Vec<_>>(),
+        );
+
+        let styles = |emulator: &TerminalEmulator<TestIo>| {
+            emulator
+                .rows()
+                .flat_map(|row| {
+                    row.cells()
+                        .map(|c| (c.text.to_vec(), c.style))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(styles(&emulator), styles(&replayed));
+        assert!(ansi.ends_with(b"\x1b[0m"));
+    }
+
+    #[test]
+    fn test_to_html() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"ab\x1b[32mcd\r\nef");
+
+        let html = emulator.to_html();
+        assert!(html.contains("ab<span style=\"color:#00ff00;\">cd</span>\n"));
+
+        // Selection from "b" to "e"
+        let html = emulator.range_to_html(1..6);
+        assert!(html.contains(">b<span style=\"color:#00ff00;\">cd</span>\n"));
+        assert!(html.ends_with("e</span></pre>"));
+    }
+
+    #[test]
+    fn test_write_text() {
+        let mut emulator = test_emulator(5, 2);
+        emulator.handle_incoming_data(b"one  \r\n\x1b[31mtwo\x1b[0m\r\nabcdefg\r\nlast");
+        assert!(!emulator.data().scrollback.is_empty());
+
+        let mut out = Vec::new();
+        emulator
+            .write_text(&mut out, false)
+            .expect("failed to write text");
+        assert_eq!(out, b"one  \ntwo\nabcdefg\nlast\n");
+
+        let mut out = Vec::new();
+        emulator
+            .write_text(&mut out, true)
+            .expect("failed to write text");
+        assert_eq!(out, b"one\ntwo\nabcdefg\nlast\n");
+    }
+
+    #[test]
+    fn test_clear_line_with_background() {
+        let mut emulator = test_emulator(5, 2);
+        emulator.handle_incoming_data(b"abcde\r\n12\x1b[44m\x1b[K");
+
+        assert_eq!(emulator.data().visible, &cells("abcde\n12   \n"));
+        assert_eq!(background_at(&emulator, 4), TerminalColor::Default);
+        assert_eq!(background_at(&emulator, 7), TerminalColor::Default);
+        for pos in 8..11 {
+            assert_eq!(background_at(&emulator, pos), TerminalColor::Blue);
+        }
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 1 });
+    }
+
+    #[test]
+    fn test_clear_line_default_background() {
+        let mut emulator = test_emulator(5, 2);
+        emulator.handle_incoming_data(b"abcde\r\n12345\x1b[41m\x1b[0m\x1b[2D\x1b[K");
+        assert_eq!(emulator.data().visible, &cells("abcde\n12\n"));
+    }
+
+    #[test]
+    fn test_clear_screen_with_background() {
+        let mut emulator = test_emulator(3, 3);
+        emulator.handle_incoming_data(b"abc\r\nd\x1b[48;5;17m\x1b[J");
+
+        assert_eq!(emulator.data().visible, &cells("abc\nd  \n   \n"));
+        assert_eq!(background_at(&emulator, 4), TerminalColor::Default);
+        for pos in 5..11 {
+            assert_eq!(background_at(&emulator, pos), TerminalColor::Indexed256(17));
+        }
+
+        emulator.handle_incoming_data(b"\x1b[42m\x1b[2J");
+        assert_eq!(emulator.data().visible, &cells("   \n   \n   \n"));
+        assert_eq!(background_at(&emulator, 0), TerminalColor::Green);
+        assert_eq!(background_at(&emulator, 10), TerminalColor::Green);
+    }
+
+    fn row_texts(emulator: &TerminalEmulator<TestIo>) -> Vec<String> {
+        emulator
+            .rows()
+            .map(|row| cells_to_string(row.text()))
+            .collect()
+    }
+
+    #[test]
+    fn test_split_and_invalid_utf8() {
+        let mut emulator = test_emulator(10, 2);
+        emulator.handle_incoming_data(b"a\xe2\x82");
+        emulator.handle_incoming_data(b"\xac\xff\r\n");
+        emulator.set_invalid_utf8(InvalidUtf8::Latin1);
+        emulator.handle_incoming_data(b"caf\xe9!");
+        let rows: Vec<_> = emulator.rows().map(|row| row.text().to_vec()).collect();
+        assert_eq!(rows, [cells("a€\u{fffd}"), cells("café!")]);
+        assert_eq!(
+            emulator.rows().next().map(|row| row.cells().count()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_scroll_region_status_line() {
+        // tmux style, a status line on the last row and the pane scrolling above it
+        let mut emulator = test_emulator(10, 5);
+        emulator.handle_incoming_data(b"\x1b[5;1H\x1b[42mstatus\x1b[m\x1b[1;4r");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+
+        emulator.handle_incoming_data(b"l1\r\nl2\r\nl3\r\nl4\r\nl5\r\nl6");
+        assert_eq!(row_texts(&emulator), ["l3", "l4", "l5", "l6", "status"]);
+        assert_eq!(emulator.data().scrollback, &cells("l1\nl2\n"));
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 3 });
+        let status = emulator.rows().last().expect("status row");
+        assert_eq!(status.runs()[0].style.background, TerminalColor::Green);
+
+        // Text wrapping past the bottom margin scrolls the region too
+        emulator.handle_incoming_data(b" 0123456789abc");
+        assert_eq!(
+            row_texts(&emulator),
+            ["l4", "l5", "l6 0123456", "789abc", "status"]
+        );
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 6, y: 3 });
+    }
+
+    #[test]
+    fn test_scroll_region_header() {
+        let mut emulator = test_emulator(10, 5);
+        emulator.handle_incoming_data(b"header\x1b[2;5r\x1b[2;1Ha\r\nb\r\nc\r\nd\r\ne");
+        assert_eq!(row_texts(&emulator), ["header", "b", "c", "d", "e"]);
+        // Lines scrolled out of a region that does not start at the top are gone
+        assert_eq!(emulator.data().scrollback, &cells(""));
+
+        // Inserting lines pushes lines out at the bottom margin only
+        emulator.handle_incoming_data(b"\x1b[r\x1b[1;4r\x1b[2;1H\x1b[2L");
+        assert_eq!(row_texts(&emulator), ["header", "", "", "b", "e"]);
+    }
+
+    #[test]
+    fn test_scroll_up_down() {
+        let mut emulator = test_emulator(5, 4);
+        emulator.handle_incoming_data(b"1\r\n2\r\n3\r\n4\x1b[2;2H\x1b[2S");
+        assert_eq!(row_texts(&emulator), ["3", "4", "", ""]);
+        assert_eq!(emulator.data().scrollback, &cells("1\n2\n"));
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 1 });
+
+        // Exposed lines get the current background
+        emulator.handle_incoming_data(b"\x1b[44m\x1b[T\x1b[m");
+        assert_eq!(row_texts(&emulator), ["     ", "3", "4", ""]);
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 1 });
+        let backgrounds: Vec<_> = emulator
+            .rows()
+            .take(2)
+            .map(|row| row.runs()[0].style.background)
+            .collect();
+        assert_eq!(backgrounds, [TerminalColor::Blue, TerminalColor::Default]);
+
+        // Only the scroll region moves, and nothing goes to scrollback from below the top
+        let mut emulator = test_emulator(5, 4);
+        emulator.handle_incoming_data(b"1\r\n2\r\n3\r\n4\x1b[2;3r\x1b[S");
+        assert_eq!(row_texts(&emulator), ["1", "3", "", "4"]);
+        emulator.handle_incoming_data(b"\x1b[5T");
+        assert_eq!(row_texts(&emulator), ["1", "", "", "4"]);
+        assert_eq!(emulator.data().scrollback, &cells(""));
+    }
+
+    #[test]
+    fn test_scroll_region_cursor_limits() {
+        let mut emulator = test_emulator(10, 5);
+        emulator.handle_incoming_data(b"\x1b[2;4r\x1b[3;1H\x1b[10B");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 3 });
+        emulator.handle_incoming_data(b"\x1b[10A");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 1 });
+
+        // Outside of the region moves only stop at the screen edges, and a newline on the last
+        // row does not scroll
+        emulator.handle_incoming_data(b"\x1b[5;1Hstatus\n\n\x1b[10B");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 6, y: 4 });
+        assert_eq!(
+            row_texts(&emulator).last().map(String::as_str),
+            Some("status")
+        );
+
+        // Invalid regions are ignored, a reset goes back to the whole screen
+        emulator.handle_incoming_data(b"\x1b[3;3r");
+        assert_eq!(emulator.scroll_region, 1..4);
+        emulator.handle_incoming_data(b"\x1b[r");
+        assert_eq!(emulator.scroll_region, 0..5);
+
+        emulator.handle_incoming_data(b"\x1b[2;4r");
+        emulator.set_win_size(10, 6).expect("failed to resize");
+        assert_eq!(emulator.scroll_region, 0..6);
+    }
+
+    #[test]
+    fn test_origin_mode() {
+        let mut emulator = test_emulator(10, 6);
+        emulator.handle_incoming_data(b"\x1b[2;5r\x1b[?6h");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 1 });
+
+        emulator.handle_incoming_data(b"\x1b[3;4H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 3, y: 3 });
+        emulator.handle_incoming_data(b"\x1b[H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 1 });
+
+        // Addressing stays within the region
+        emulator.handle_incoming_data(b"\x1b[20;1H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 4 });
+
+        // Setting a region homes to the new top margin
+        emulator.handle_incoming_data(b"\x1b[3;6r");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 2 });
+        emulator.handle_incoming_data(b"top");
+        assert_eq!(row_texts(&emulator)[2], "top");
+
+        emulator.handle_incoming_data(b"\x1b[?6l");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        emulator.handle_incoming_data(b"\x1b[20;1H");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 5 });
+    }
+
+    #[test]
+    fn test_clear_screen_keeps_scrollback() {
+        let mut emulator = test_emulator(10, 2);
+        emulator.handle_incoming_data(b"a\r\nb\r\nc\x1b[2J");
+        assert_eq!(emulator.data().scrollback, &cells("a\n"));
+        assert_eq!(row_texts(&emulator), ["", ""]);
+
+        emulator.handle_incoming_data(b"\x1b[Hd\x1b[3J");
+        assert_eq!(emulator.data().scrollback, &cells(""));
+        assert_eq!(row_texts(&emulator), ["d", ""]);
+    }
+
+    #[test]
+    fn test_keyboard_protocol() {
+        let mut emulator = test_emulator(10, 3);
+        let escape = KeyEvent {
+            key: KeyCode::Escape,
+            modifiers: KeyModifiers::default(),
+            kind: KeyEventKind::Press,
+        };
+        assert!(!emulator.write_key(&escape).expect("failed to write key"));
+
+        emulator.handle_incoming_data(b"\x1b[>1u\x1b[?u");
+        assert_eq!(emulator.io.written, b"\x1b[?1u");
+        emulator.io.written.clear();
+        assert!(emulator.write_key(&escape).expect("failed to write key"));
+        assert_eq!(emulator.io.written, b"\x1b[27u");
+
+        // The alternate screen starts without flags and drops its own when it is left
+        emulator.handle_incoming_data(b"\x1b[?1049h");
+        assert!(emulator.keyboard_flags().is_empty());
+        emulator.handle_incoming_data(b"\x1b[>11u");
+        assert_eq!(emulator.keyboard_flags().bits(), 11);
+        emulator.handle_incoming_data(b"\x1b[?1049l");
+        assert_eq!(emulator.keyboard_flags().bits(), 1);
+        emulator.handle_incoming_data(b"\x1b[?1049h");
+        assert!(emulator.keyboard_flags().is_empty());
+        emulator.handle_incoming_data(b"\x1b[?1049l\x1b[<u");
+        assert!(emulator.keyboard_flags().is_empty());
+
+        emulator.handle_incoming_data(b"\x1b[>1u\x1bc");
+        assert!(emulator.keyboard_flags().is_empty());
+
+        let ctrl_a = KeyEvent {
+            key: KeyCode::Char('a'),
+            modifiers: KeyModifiers {
+                ctrl: true,
+                ..Default::default()
+            },
+            kind: KeyEventKind::Press,
+        };
+        emulator.io.written.clear();
+        emulator.handle_incoming_data(b"\x1b[>4;2m\x1b[?4m");
+        assert_eq!(emulator.io.written, b"\x1b[>4;2m");
+        emulator.io.written.clear();
+        assert!(emulator.write_key(&ctrl_a).expect("failed to write key"));
+        assert_eq!(emulator.io.written, b"\x1b[27;5;97~");
+        emulator.handle_incoming_data(b"\x1b[>4n");
+        assert!(!emulator.write_key(&ctrl_a).expect("failed to write key"));
+    }
+
+    #[test]
+    fn test_local_echo() {
+        let mut emulator = test_emulator(10, 3);
+        emulator
+            .write(TerminalInput::Ascii(b'a'))
+            .expect("failed to write");
+        assert!(row_texts(&emulator).is_empty());
+
+        emulator.set_local_echo(true);
+        for input in [
+            TerminalInput::Ascii(b'a'),
+            TerminalInput::Ascii(b'b'),
+            TerminalInput::Backspace,
+            TerminalInput::Ctrl(b'c'),
+            TerminalInput::Enter(EnterSequence::Cr),
+        ] {
+            emulator.write(input).expect("failed to write");
+        }
+        emulator.paste(b"x\ny\x1b[2J").expect("failed to paste");
+        // Backspace blanks the character out the way a tty echoes it, and controls are not shown
+        assert_eq!(row_texts(&emulator), ["a ", "x", "y[2J"]);
+        assert_eq!(emulator.io.written, b"aab\x7f\x03\rx\ny\x1b[2J");
+    }
+
+    /// Pieces of output that between them touch everything a snapshot holds. The last one
+    /// leaves the parser in the middle of a sequence
+    const SNAPSHOT_FRAGMENTS: &[&str] = &[
+        "hello",
+        "wide 漢字",
+        "\r\n",
+        "\x1b[31;42m",
+        "\x1b[1;4:3m",
+        "\x1b[m",
+        "\x1b[3;5H",
+        "\x1b[2J",
+        "\x1b[?1049h",
+        "\x1b[?1049l",
+        "\x1b[?1h",
+        "\x1b[?6h",
+        "\x1b[?2004h",
+        "\x1b[20h",
+        "\x1b[2;5r",
+        "\x1b#6",
+        "\x1b]2;title\x07",
+        "\x1b]4;1;rgb:12/34/56\x07",
+        "\x1b]133;A\x07",
+        "\x1b[>1u",
+        "\x1b[>3u",
+        "\x1b[>4;2m",
+        "\x1b7",
+        "\x1b[3g",
+        "\x1bH",
+        "\x1b[22t",
+        "\x1b]7;file:///tmp\x07",
+        "x",
+        "\x1b[",
+    ];
+
+    /// Output that depends on the state a snapshot holds: the saved cursor, tab stops, pushed
+    /// titles and keyboard flags, and the character to repeat
+    const SNAPSHOT_PROBE: &[u8] = b"\x1b8\x1b[23t\x1b[<u\x1b[?u\x1b[bz\t\tz\x1b[6n";
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        // xorshift with a fixed seed, so that a failure happens again on the next run
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+
+        for _ in 0..100 {
+            let mut emulator = test_emulator(20, 6);
+            for _ in 0..next() % 60 {
+                let fragment = SNAPSHOT_FRAGMENTS[next() % SNAPSHOT_FRAGMENTS.len()];
+                emulator.handle_incoming_data(fragment.as_bytes());
             }
+
+            let snapshot = emulator.snapshot().expect("failed to snapshot");
+            let parsed =
+                SnapshotItem::from_bytes(&snapshot.to_bytes()).expect("failed to parse snapshot");
+            assert_eq!(parsed, snapshot);
+
+            let mut restored = TerminalEmulator::from_snapshot(parsed, TestIo::default())
+                .expect("failed to load snapshot");
+            assert_eq!(restored.snapshot().expect("failed to snapshot"), snapshot);
+            assert_eq!(row_texts(&restored), row_texts(&emulator));
+            assert_eq!(restored.window_title(), emulator.window_title());
+            assert_eq!(restored.color_scheme, emulator.color_scheme);
+            assert_eq!(restored.keyboard_flags(), emulator.keyboard_flags());
+            assert_eq!(restored.working_directory(), emulator.working_directory());
+
+            // Comparing snapshots cannot tell a field that was never written, so both have to
+            // go on the same way
+            emulator.io.written.clear();
+            emulator.handle_incoming_data(SNAPSHOT_PROBE);
+            restored.handle_incoming_data(SNAPSHOT_PROBE);
+            assert_eq!(row_texts(&restored), row_texts(&emulator));
+            assert_eq!(restored.cursor_pos(), emulator.cursor_pos());
+            assert_eq!(restored.cursor_state, emulator.cursor_state);
+            assert_eq!(restored.window_title(), emulator.window_title());
+            assert_eq!(restored.io.written, emulator.io.written);
         }
+
+        assert!(SnapshotItem::from_bytes(b"{\"a\": null}").is_err());
+        assert!(SnapshotItem::from_bytes(b"[1.5]").is_err());
     }
 
-    pub fn read(&mut self) {
-        let mut buf = vec![0u8; 4096];
-        loop {
-            let read_size = match self.io.read(&mut buf) {
-                Ok(ReadResponse::Empty) => break,
-                Ok(ReadResponse::Success(v)) => v,
-                Err(e) => {
-                    error!("Failed to read from child process: {e}");
-                    break;
-                }
+    #[test]
+    fn test_snapshot_restores_state() {
+        let mut emulator = test_emulator(20, 4);
+        emulator.handle_incoming_data(b"\x1b[3g\x1b[1;6H\x1bH\x1b[1;11H\x1bH");
+        emulator.handle_incoming_data(b"\x1b[2;3H\x1b[32m\x1b7\x1b[m\x1b[H");
+        emulator.handle_incoming_data(b"\x1b]2;first\x07\x1b[22t\x1b]2;second\x07");
+        emulator.handle_incoming_data(b"\x1b]7;file:///tmp\x07\x1b[>1u\x1b[>3uq");
+        let snapshot = emulator.snapshot().expect("failed to snapshot");
+        let mut restored = TerminalEmulator::from_snapshot(snapshot.clone(), TestIo::default())
+            .expect("failed to load snapshot");
+        assert_eq!(restored.working_directory(), Some(Path::new("/tmp")));
+
+        // HT goes to the stops that were set, REP repeats what was printed last
+        restored.handle_incoming_data(b"\x1b[b\r\t1\t2\t3");
+        assert_eq!(row_texts(&restored)[0], "qq   1    2        3");
+        // DECRC brings back the saved position and color
+        restored.handle_incoming_data(b"\x1b8");
+        assert_eq!(restored.cursor_pos(), CursorPos { x: 2, y: 1 });
+        assert_eq!(restored.cursor_state.color, TerminalColor::Green);
+        // The pushed title and keyboard flags come back when popped
+        restored.handle_incoming_data(b"\x1b[23t\x1b[<u");
+        assert_eq!(restored.window_title(), Some("first"));
+        assert_eq!(restored.keyboard_flags().bits(), 1);
+
+        // A scroll region that does not fit the screen is refused
+        for (top, bottom) in [(0, 0), (2, 2), (3, 1), (0, 5)] {
+            let SnapshotItem::Map(mut map) = snapshot.clone() else {
+                panic!("snapshot is not a map");
             };
+            map.insert("scroll_region_top".to_string(), SnapshotItem::Int(top));
+            map.insert(
+                "scroll_region_bottom".to_string(),
+                SnapshotItem::Int(bottom),
+            );
+            assert!(
+                TerminalEmulator::from_snapshot(SnapshotItem::Map(map), TestIo::default()).is_err(),
+                "{top}..{bottom} was accepted"
+            );
+        }
+    }
 
-            let incoming = &buf[0..read_size];
-            debug!("Incoming data: {:?}", std::str::from_utf8(incoming));
-            self.recorder.write(incoming);
-            self.handle_incoming_data(incoming);
+    #[test]
+    fn test_insert_mode() {
+        let mut emulator = test_emulator(10, 4);
+        emulator.handle_incoming_data(b"abcdef\x1b[1;3H\x1b[4hXY");
+        assert!(emulator.insert_mode);
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 4, y: 0 });
+
+        // What is pushed past the edge is lost instead of wrapping
+        emulator.handle_incoming_data(b"\x1b[4l\r\n0123456789xyz\x1b[2;5H\x1b[4h--");
+        // Past the end of the row there is nothing to shift
+        emulator.handle_incoming_data(b"\x1b[4;3H.\x1b[4l\x1b[1;1HA");
+        assert_eq!(
+            row_texts(&emulator),
+            ["AbXYcdef", "0123--4567", "xyz", "  ."]
+        );
+    }
+
+    #[test]
+    fn test_screen_alignment_test() {
+        let mut emulator = test_emulator(5, 3);
+        emulator.handle_incoming_data(b"a\r\nb\r\nc\r\nd\x1b[1;31m\x1b[2;3r\x1b#8");
+        assert_eq!(row_texts(&emulator), ["EEEEE", "EEEEE", "EEEEE"]);
+        assert!(emulator.rows().all(|row| !row.is_wrapped()));
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        assert_eq!(emulator.scroll_region, 0..3);
+        // The scrollback is left alone
+        assert_eq!(emulator.data().scrollback, &cells("a\n"));
+
+        // The E's are not in the current colors
+        assert!(emulator
+            .format_data()
+            .visible
+            .iter()
+            .all(|tag| tag.color == TerminalColor::Default));
+    }
+
+    #[test]
+    fn test_multibyte_characters_stay_whole() {
+        // é takes up only the column 1, 漢 the columns 2 and 3
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data("aé漢b".as_bytes());
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 5, y: 0 });
+        emulator.handle_incoming_data(b"\x08\x08");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 0 });
+        emulator.handle_incoming_data(b"\x08");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 0 });
+
+        emulator.handle_incoming_data(b"\x1b[2C");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 4, y: 0 });
+        emulator.handle_incoming_data(b"\x1b[D");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 0 });
+        emulator.handle_incoming_data(b"\r\x1b[3C");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 4, y: 0 });
+
+        // Deleting from the middle or up to part of it removes all of it
+        emulator.handle_incoming_data(b"\x1b[1;4H\x1b[P");
+        assert_eq!(row_texts(&emulator)[0], "aéb");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 0 });
+        emulator.handle_incoming_data("\r漢b\r\x1b[P".as_bytes());
+        assert_eq!(row_texts(&emulator)[0], "b");
+
+        // Nothing to snap to past the end of the row
+        emulator.handle_incoming_data(b"\x1b[5C\x08");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 4, y: 0 });
+
+        // A wide character that does not fit on the end of a row goes to the next one, or takes
+        // the last two columns with autowrap off
+        let mut emulator = test_emulator(4, 3);
+        emulator.handle_incoming_data("abc漢".as_bytes());
+        assert_eq!(row_texts(&emulator), ["abc ", "漢"]);
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 1 });
+        emulator.handle_incoming_data("\r\n\x1b[?7labc漢".as_bytes());
+        assert_eq!(row_texts(&emulator)[2], "ab漢");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 3, y: 2 });
+
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data("漢\x1b[2b".as_bytes());
+        assert_eq!(row_texts(&emulator)[0], "漢漢漢");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 6, y: 0 });
+    }
+
+    #[test]
+    fn test_start_over() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"a\r\nb\r\nc\r\nd\x1b[?2004h\x1b]7;file:///tmp\x07");
+        emulator.start_over(true);
+        assert_eq!(emulator.data().scrollback, &cells("a\nb\nc\nd\n"));
+        assert_eq!(row_texts(&emulator), ["", "", ""]);
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        assert!(!emulator.bracketed_paste_mode);
+        assert_eq!(emulator.working_directory(), Some(Path::new("/tmp")));
+
+        // The main screen is kept rather than what a full screen program left
+        emulator.handle_incoming_data(b"e\x1b[?1049hvim");
+        emulator.start_over(true);
+        assert!(!emulator.is_alternate_screen());
+        assert_eq!(emulator.data().scrollback, &cells("a\nb\nc\nd\ne\n"));
+        assert_eq!(row_texts(&emulator), ["", "", ""]);
+
+        emulator.handle_incoming_data(b"f");
+        emulator.start_over(false);
+        assert_eq!(emulator.data().scrollback, &cells(""));
+        assert!(row_texts(&emulator).iter().all(|row| row.is_empty()));
+        assert!(emulator.io.written.is_empty());
+    }
+
+    #[test]
+    fn test_clear_buffer() {
+        let mut emulator = test_emulator(10, 2);
+        emulator.handle_incoming_data(b"a\r\nb\r\nc\x1b[1;31m");
+        emulator.clear_buffer();
+        assert_eq!(emulator.data().scrollback, &cells(""));
+        assert_eq!(row_texts(&emulator), ["", ""]);
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        assert!(emulator.io.written.is_empty());
+        // Only the contents go, the rest of the state is kept
+        assert!(emulator.cursor_state.bold);
+    }
+
+    #[test]
+    fn test_full_reset() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"a\r\nb\r\nc\r\nd\x1b#6");
+        emulator.handle_incoming_data(
+            b"\x1b]2;title\x07\x1b]10;#ff0000\x07\x1b[?1h\x1b[?2004h\x1b[2;3r\x1b[?6h\x1b[1;31m",
+        );
+        assert!(!emulator.data().scrollback.is_empty());
+
+        emulator.handle_incoming_data(b"\x1bc");
+        assert_eq!(emulator.data().scrollback, &cells(""));
+        assert_eq!(row_texts(&emulator), row_texts(&test_emulator(10, 3)));
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+        assert_eq!(emulator.cursor_state, CursorState::new());
+        assert_eq!(emulator.scroll_region, 0..3);
+        assert!(!emulator.decckm_mode);
+        assert!(!emulator.origin_mode);
+        assert!(!emulator.bracketed_paste_mode);
+        assert_eq!(emulator.color_scheme(), &ColorScheme::default());
+        assert!(emulator.line_sizes.tags().is_empty());
+        assert_eq!(emulator.window_title(), Some("title"));
+
+        emulator.handle_incoming_data(b"e");
+        assert_eq!(row_texts(&emulator)[0], "e");
+    }
+
+    #[test]
+    fn test_content_version() {
+        let mut emulator = test_emulator(10, 3);
+        let version = emulator.content_version();
+        emulator.handle_incoming_data(b"a");
+        assert_ne!(emulator.content_version(), version);
+
+        // Nothing shows until the synchronized update ends
+        emulator.handle_incoming_data(b"\x1b[?2026h");
+        let version = emulator.content_version();
+        emulator.handle_incoming_data(b"b");
+        assert_eq!(emulator.content_version(), version);
+        emulator.handle_incoming_data(b"\x1b[?2026l");
+        assert_ne!(emulator.content_version(), version);
+
+        let version = emulator.content_version();
+        emulator.set_win_size(10, 3).expect("failed to set size");
+        assert_eq!(emulator.content_version(), version);
+        emulator.set_win_size(12, 3).expect("failed to set size");
+        assert_ne!(emulator.content_version(), version);
+    }
+
+    #[test]
+    fn test_line_and_absolute_cursor_moves() {
+        let pos = |x, y| CursorPos { x, y };
+        for (input, expected) in [
+            // CNL and CPL move whole lines and return to the first column
+            (&b"\x1b[3;5H\x1b[E"[..], pos(0, 3)),
+            (b"\x1b[3;5H\x1b[0E", pos(0, 3)),
+            (b"\x1b[3;5H\x1b[2F", pos(0, 0)),
+            (b"\x1b[3;5H\x1b[99E", pos(0, 5)),
+            (b"\x1b[3;5H\x1b[99F", pos(0, 0)),
+            // CHA and HPA
+            (b"\x1b[3;5H\x1b[8G", pos(7, 2)),
+            (b"\x1b[3;5H\x1b[G", pos(0, 2)),
+            (b"\x1b[3;5H\x1b[0`", pos(0, 2)),
+            (b"\x1b[3;5H\x1b[99`", pos(9, 2)),
+            // VPA
+            (b"\x1b[3;5H\x1b[5d", pos(4, 4)),
+            (b"\x1b[3;5H\x1b[d", pos(4, 0)),
+            (b"\x1b[3;5H\x1b[99d", pos(4, 5)),
+            // Within a scroll region CNL stops at the margin, origin mode makes VPA relative
+            (b"\x1b[2;4r\x1b[3;5H\x1b[9E", pos(0, 3)),
+            (b"\x1b[2;4r\x1b[?6h\x1b[2d", pos(0, 2)),
+            (b"\x1b[2;4r\x1b[?6h\x1b[9d", pos(0, 3)),
+        ] {
+            let mut emulator = test_emulator(10, 6);
+            emulator.handle_incoming_data(input);
+            assert_eq!(
+                emulator.cursor_pos(),
+                expected,
+                "{:?}",
+                String::from_utf8_lossy(input)
+            );
         }
     }
 
-    pub fn data(&self) -> TerminalData<&[u8]> {
-        self.terminal_buffer.data()
+    #[test]
+    fn test_tab_movement() {
+        let mut emulator = test_emulator(20, 3);
+        emulator.handle_incoming_data(b"\x1b[I");
+        assert_eq!(emulator.cursor_pos().x, 8);
+        emulator.handle_incoming_data(b"\x1b[5I");
+        assert_eq!(emulator.cursor_pos().x, 19);
+        emulator.handle_incoming_data(b"\x1b[Z");
+        assert_eq!(emulator.cursor_pos().x, 16);
+
+        // Custom stops at columns 3 and 12
+        emulator.handle_incoming_data(b"\x1b[3g\r\x1b[3C\x1bH\x1b[9C\x1bH\r");
+        emulator.handle_incoming_data(b"\x1b[I");
+        assert_eq!(emulator.cursor_pos().x, 3);
+        emulator.handle_incoming_data(b"\x1b[I");
+        assert_eq!(emulator.cursor_pos().x, 12);
+        emulator.handle_incoming_data(b"\x1b[I");
+        assert_eq!(emulator.cursor_pos().x, 19);
+        emulator.handle_incoming_data(b"\x1b[2Z");
+        assert_eq!(emulator.cursor_pos().x, 3);
+        emulator.handle_incoming_data(b"\x1b[9Z");
+        assert_eq!(emulator.cursor_pos().x, 0);
+
+        // Clearing the stop under the cursor leaves the others
+        emulator.handle_incoming_data(b"\x1b[3C\x1b[g\r\x1b[I");
+        assert_eq!(emulator.cursor_pos().x, 12);
     }
 
-    pub fn format_data(&self) -> TerminalData<Vec<FormatTag>> {
-        let offset = self.terminal_buffer.data().scrollback.len();
-        split_format_data_for_scrollback(self.format_tracker.tags(), offset)
+    #[test]
+    fn test_repeat_character() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"x\x1b[5b");
+        assert_eq!(row_texts(&emulator)[0], "xxxxxx");
+
+        // Repeats wrap like printed text
+        let mut emulator = test_emulator(4, 3);
+        emulator.handle_incoming_data(b"ab\x1b[3b");
+        assert_eq!(row_texts(&emulator)[..2], ["abbb", "b"]);
+
+        // The character may be split across reads
+        let mut emulator = test_emulator(20, 3);
+        emulator.handle_incoming_data(b"\xc3");
+        emulator.handle_incoming_data(b"\xa9\x1b[2b");
+        assert_eq!(row_texts(&emulator)[0], "ééé");
+
+        // Nothing to repeat after a reset or a control
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"\x1b[5b\x1bca\t\x1b[3b");
+        assert_eq!(row_texts(&emulator)[0], "a");
+
+        // Tabbing backwards cancels it too
+        let mut emulator = test_emulator(20, 3);
+        emulator.handle_incoming_data(b"\tab\x1b[Z\x1b[3b");
+        assert_eq!(row_texts(&emulator)[0], "        ab");
     }
 
-    pub fn cursor_pos(&self) -> CursorPos {
-        self.cursor_state.pos.clone()
+    #[test]
+    fn test_title_stack() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"\x1b[22;0t\x1b]2;first\x07\x1b[22;2t\x1b]2;second\x07");
+        assert_eq!(emulator.window_title(), Some("second"));
+
+        emulator.handle_incoming_data(b"\x1b[23;2t");
+        assert_eq!(emulator.window_title(), Some("first"));
+        emulator.handle_incoming_data(b"\x1b[23;0t");
+        assert_eq!(emulator.window_title(), None);
+
+        // Underflow is ignored
+        emulator.handle_incoming_data(b"\x1b]2;third\x07\x1b[23t");
+        assert_eq!(emulator.window_title(), Some("third"));
+
+        // Only the most recent titles are kept
+        for i in 0..MAX_TITLE_STACK_DEPTH + 2 {
+            emulator.handle_incoming_data(format!("\x1b]2;{i}\x07\x1b[22t").as_bytes());
+        }
+        assert_eq!(emulator.title_stack.len(), MAX_TITLE_STACK_DEPTH);
+        assert_eq!(emulator.title_stack[0].as_deref(), Some("2"));
+        assert!(row_texts(&emulator).iter().all(|row| row.is_empty()));
     }
 
-    pub fn start_recording(&mut self) -> Result<RecordingHandle, StartRecordingError> {
-        use StartRecordingErrorPriv::*;
+    #[test]
+    fn test_reset_drops_partial_sequence() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"text\x1b]2;unterminated");
+        emulator.reset();
+        emulator.handle_incoming_data(b"new");
+        assert_eq!(row_texts(&emulator), ["new"]);
+        assert_eq!(emulator.window_title(), None);
+    }
 
-        let recording_handle = self.recorder.start_recording().map_err(Start)?;
-        match recording_handle {
-            StartRecordingResponse::New(initializer) => {
-                initializer.snapshot_item("parser".to_string(), self.parser.snapshot());
-                initializer.snapshot_item(
-                    "terminal_buffer".to_string(),
-                    self.terminal_buffer.snapshot().map_err(SnapshotBuffer)?,
-                );
-                initializer.snapshot_item(
-                    "format_tracker".to_string(),
-                    self.format_tracker
-                        .snapshot()
-                        .map_err(SnapshotFormatTracker)?,
-                );
-                initializer.snapshot_item("decckm_mode".to_string(), self.decckm_mode.into());
-                initializer.snapshot_item(
-                    "cursor_state".to_string(),
-                    self.cursor_state.snapshot().map_err(SnapshotCursor)?,
-                );
-                Ok(initializer.into_handle())
+    #[test]
+    fn test_alternate_screen() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"a\r\nb\r\nc\r\nd\x1b[1;31m");
+        let scrollback = emulator.data().scrollback.to_vec();
+        let rows = row_texts(&emulator);
+        let cursor_pos = emulator.cursor_pos();
+
+        emulator.handle_incoming_data(b"\x1b[?1049h");
+        assert!(emulator.is_alternate_screen());
+        assert_eq!(emulator.data().scrollback, &cells(""));
+        emulator.handle_incoming_data(b"\x1b[Hx\r\ny\r\nz\r\nw\x1b[m");
+        // Nothing is kept above the screen
+        assert_eq!(emulator.data().scrollback, &cells(""));
+        assert_eq!(row_texts(&emulator), ["y", "z", "w"]);
+
+        emulator.handle_incoming_data(b"\x1b[?1049l");
+        assert!(!emulator.is_alternate_screen());
+        assert_eq!(emulator.data().scrollback, scrollback);
+        assert_eq!(row_texts(&emulator), rows);
+        assert_eq!(emulator.cursor_pos(), cursor_pos);
+        assert_eq!(emulator.cursor_state.color, TerminalColor::Red);
+    }
+
+    #[test]
+    fn test_alternate_screen_resize() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"main\x1b[?1049h");
+        emulator.set_win_size(20, 5).expect("failed to resize");
+        emulator.handle_incoming_data(b"\x1b[?1049l");
+        assert_eq!(emulator.get_win_size(), (20, 5));
+        assert_eq!(row_texts(&emulator)[0], "main");
+    }
+
+    #[test]
+    fn test_synchronized_output() {
+        let mut emulator = test_emulator(10, 3);
+        emulator.handle_incoming_data(b"old\x1b[?2026h\x1b[2J\x1b[Hne");
+        // The last complete frame stays in place, also across reads
+        assert_eq!(row_texts(&emulator), ["old"]);
+        emulator.handle_incoming_data(b"w\x1b[?2026l");
+        assert_eq!(row_texts(&emulator), ["new"]);
+
+        // An update that does not end is shown after the timeout
+        emulator.handle_incoming_data(b"\x1b[?2026h\rnow");
+        assert!(!emulator.read());
+        assert_eq!(row_texts(&emulator), ["new"]);
+        if let Some(synchronized_output) = &mut emulator.synchronized_output {
+            synchronized_output.started -= SYNCHRONIZED_OUTPUT_TIMEOUT;
+        }
+        assert!(emulator.read());
+        assert_eq!(row_texts(&emulator), ["now"]);
+
+        // The timeout counts when more output arrives too, not only on reads
+        emulator.handle_incoming_data(b"\x1b[?2026h\rmid");
+        if let Some(synchronized_output) = &mut emulator.synchronized_output {
+            synchronized_output.started -= SYNCHRONIZED_OUTPUT_TIMEOUT;
+        }
+        emulator.handle_incoming_data(b"!");
+        assert_eq!(row_texts(&emulator), ["mid!"]);
+        assert!(emulator.synchronized_output.is_none());
+
+        // Queries are answered without waiting for the update to end
+        emulator.handle_incoming_data(b"\x1b[?2026h\x1b[2J\x1b[?2026$p\x1b[5n");
+        assert_eq!(emulator.io.written, b"\x1b[?2026;1$y\x1b[0n");
+        assert_eq!(row_texts(&emulator), ["mid!"]);
+        // and the cursor position includes what was queued
+        emulator.io.written.clear();
+        emulator.handle_incoming_data(b"\x1b[2;3Hx\x1b[6n");
+        assert_eq!(emulator.io.written, b"\x1b[2;4R");
+        assert_eq!(row_texts(&emulator), ["", "  x"]);
+        assert!(emulator.synchronized_output.is_some());
+
+        // An update that grows too large is shown as far as it got
+        let mut emulator = test_emulator(1000, 3);
+        emulator.handle_incoming_data(b"\x1b[?2026h");
+        let mut chunk = b"\x1b[H".to_vec();
+        chunk.extend([b'a'; 1000]);
+        for _ in 0..MAX_SYNCHRONIZED_OUTPUT_SIZE / 1000 {
+            // However slow the test runs, the timeout should not be what shows it
+            if let Some(synchronized_output) = &mut emulator.synchronized_output {
+                synchronized_output.started = Instant::now();
             }
-            StartRecordingResponse::Existing(handle) => Ok(handle),
+            emulator.handle_incoming_data(&chunk);
         }
+        assert_eq!(row_texts(&emulator)[0], "a".repeat(1000));
+        let synchronized_output = emulator.synchronized_output.as_ref();
+        assert!(synchronized_output.is_some_and(|v| v.size < MAX_SYNCHRONIZED_OUTPUT_SIZE));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    fn buffer_text(emulator: &TerminalEmulator<TestIo>, range: Range<usize>) -> String {
+        let data = emulator.data();
+        let data = [data.scrollback, data.visible].concat();
+        cells_to_string(&data[range])
+    }
 
     #[test]
-    fn test_format_tracker_scrollback_split() {
-        let tags = vec![
-            FormatTag {
-                start: 0,
-                end: 5,
-                color: TerminalColor::Blue,
-                bold: true,
-            },
-            FormatTag {
-                start: 5,
-                end: 7,
-                color: TerminalColor::Red,
-                bold: false,
-            },
-            FormatTag {
-                start: 7,
-                end: 10,
-                color: TerminalColor::Blue,
-                bold: true,
-            },
-            FormatTag {
-                start: 10,
-                end: usize::MAX,
-                color: TerminalColor::Red,
-                bold: true,
-            },
-        ];
+    fn test_last_command_output_marks() {
+        let mut emulator = test_emulator(20, 3);
+        emulator.handle_incoming_data(
+            b"\x1b]133;A\x07$ \x1b]133;B\x07ls\r\n\x1b]133;C\x07a\r\nb\r\n\x1b]133;D;0\x07",
+        );
+        emulator.handle_incoming_data(b"\x1b]133;A\x07$ \x1b]133;B\x07");
+        let range = emulator.last_command_output().expect("no command output");
+        assert_eq!(buffer_text(&emulator, range), "a\nb");
 
-        // Case 1: no split
-        let res = split_format_data_for_scrollback(tags.clone(), 0);
-        assert_eq!(res.scrollback, &[]);
-        assert_eq!(res.visible, &tags[..]);
+        // Marks survive the output scrolling into the scrollback
+        emulator.handle_incoming_data(b"pwd\r\n\x1b]133;C\x07/\r\n\x1b]133;D;0\x07");
+        emulator.handle_incoming_data(b"\x1b]133;A\x07$ \x1b]133;B\x07");
+        let range = emulator.last_command_output().expect("no command output");
+        assert_eq!(buffer_text(&emulator, range), "/");
+    }
 
-        // Case 2: Split on a boundary
-        let res = split_format_data_for_scrollback(tags.clone(), 10);
-        assert_eq!(res.scrollback, &tags[0..3]);
-        assert_eq!(
-            res.visible,
-            &[FormatTag {
-                start: 0,
-                end: usize::MAX,
-                color: TerminalColor::Red,
-                bold: true,
-            },]
-        );
+    #[test]
+    fn test_last_command_output_without_marks() {
+        let mut emulator = test_emulator(20, 5);
+        emulator.handle_incoming_data(b"$ ls\r\na\r\nb\r\n$ ");
+        let range = emulator.last_command_output().expect("no command output");
+        assert_eq!(buffer_text(&emulator, range), "a\nb");
 
-        // Case 3: Split a segment
-        let res = split_format_data_for_scrollback(tags.clone(), 9);
-        assert_eq!(
-            res.scrollback,
-            &[
-                FormatTag {
-                    start: 0,
-                    end: 5,
-                    color: TerminalColor::Blue,
-                    bold: true,
-                },
-                FormatTag {
-                    start: 5,
-                    end: 7,
-                    color: TerminalColor::Red,
-                    bold: false,
-                },
-                FormatTag {
-                    start: 7,
-                    end: 9,
-                    color: TerminalColor::Blue,
-                    bold: true,
-                },
-            ]
-        );
-        assert_eq!(
-            res.visible,
-            &[
-                FormatTag {
-                    start: 0,
-                    end: 1,
-                    color: TerminalColor::Blue,
-                    bold: true,
-                },
-                FormatTag {
-                    start: 1,
-                    end: usize::MAX,
-                    color: TerminalColor::Red,
-                    bold: true,
-                },
-            ]
-        );
+        emulator.handle_incoming_data(b"\r\n");
+        assert_eq!(emulator.last_command_output(), None);
     }
 
     #[test]
-    fn test_cursor_state_snapshot() {
-        let state = CursorState {
-            pos: CursorPos { x: 10, y: 50 },
-            bold: false,
-            color: TerminalColor::Magenta,
-        };
+    fn test_soft_reset() {
+        let mut emulator = test_emulator(10, 4);
+        emulator.handle_incoming_data(b"keep\x1b[?1h\x1b[2;3r\x1b[?6h\x1b[2;3H\x1b[1;42m");
+        emulator.handle_incoming_data(b"\x1b[!p");
 
-        let snapshot = state.snapshot().expect("failed to create snapshot");
-        let loaded = CursorState::from_snapshot(snapshot).expect("failed to load snapshot");
-        assert_eq!(loaded, state);
+        assert_eq!(row_texts(&emulator)[0], "keep");
+        assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 2 });
+        assert!(!emulator.cursor_state.bold);
+        assert_eq!(emulator.cursor_state.background, TerminalColor::Default);
+        assert_eq!(emulator.scroll_region, 0..4);
+        assert!(!emulator.decckm_mode);
+        assert!(!emulator.origin_mode);
     }
 }
@@ -1,3 +1,4 @@
+use super::{io::NoIo, SnapshotError, TerminalEmulator};
 use crate::error::backtraced_err;
 
 use std::{
@@ -5,6 +6,7 @@ use std::{
     num::TryFromIntError,
     path::{Path, PathBuf},
     sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use thiserror::Error;
@@ -38,7 +40,24 @@ enum NotIntOfTypeKind {
 #[error(transparent)]
 pub struct NotIntOfType(#[from] NotIntOfTypeKind);
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
+enum ParseSnapshotErrorKind {
+    #[error("snapshot is not utf-8")]
+    NotUtf8(#[source] std::str::Utf8Error),
+    #[error("failed to parse snapshot as json")]
+    Parse(#[source] tinyjson::JsonParseError),
+    #[error("snapshots do not hold null")]
+    Null,
+    #[error("snapshots only hold whole numbers, not {0}")]
+    NotWholeNumber(f64),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct ParseSnapshotError(#[from] ParseSnapshotErrorKind);
+
+/// State of the terminal or of a part of it, see [`TerminalEmulator::snapshot`]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SnapshotItem {
     Bool(bool),
     Int(i64),
@@ -89,6 +108,21 @@ impl SnapshotItem {
             _ => Err(NotString),
         }
     }
+
+    /// The item as JSON, the way recordings store their initial state
+    pub fn to_bytes(&self) -> Vec<u8> {
+        snapshot_to_tinyjson(self.clone())
+            .stringify()
+            .expect("snapshots only hold finite numbers")
+            .into_bytes()
+    }
+
+    /// Reads back what [`SnapshotItem::to_bytes`] wrote
+    pub fn from_bytes(bytes: &[u8]) -> Result<SnapshotItem, ParseSnapshotError> {
+        let content = std::str::from_utf8(bytes).map_err(ParseSnapshotErrorKind::NotUtf8)?;
+        let json: JsonValue = content.parse().map_err(ParseSnapshotErrorKind::Parse)?;
+        Ok(try_tinyjson_to_snapshot(json)?)
+    }
 }
 
 macro_rules! impl_from_int {
@@ -123,6 +157,7 @@ macro_rules! impl_from_str {
 
 impl_from_int!(u8);
 impl_from_int_ref!(u8);
+impl_from_int!(u32);
 impl_from_int!(i64);
 impl_from_int_ref!(i64);
 impl_from_str!(&str);
@@ -182,6 +217,28 @@ fn tinyjson_to_snapshot(value: tinyjson::JsonValue) -> SnapshotItem {
     }
 }
 
+/// Like [`tinyjson_to_snapshot`], for JSON that did not come from a snapshot
+fn try_tinyjson_to_snapshot(value: JsonValue) -> Result<SnapshotItem, ParseSnapshotErrorKind> {
+    let ret = match value {
+        JsonValue::Null => Err(ParseSnapshotErrorKind::Null)?,
+        JsonValue::Boolean(b) => SnapshotItem::Bool(b),
+        JsonValue::Number(num) if num.fract() == 0.0 => SnapshotItem::Int(num as i64),
+        JsonValue::Number(num) => Err(ParseSnapshotErrorKind::NotWholeNumber(num))?,
+        JsonValue::String(s) => SnapshotItem::String(s),
+        JsonValue::Array(arr) => SnapshotItem::Array(
+            arr.into_iter()
+                .map(try_tinyjson_to_snapshot)
+                .collect::<Result<_, _>>()?,
+        ),
+        JsonValue::Object(map) => SnapshotItem::Map(
+            map.into_iter()
+                .map(|(k, v)| Ok((k, try_tinyjson_to_snapshot(v)?)))
+                .collect::<Result<_, ParseSnapshotErrorKind>>()?,
+        ),
+    };
+    Ok(ret)
+}
+
 fn snapshot_to_tinyjson(value: SnapshotItem) -> JsonValue {
     match value {
         SnapshotItem::Int(v) => JsonValue::Number(v as f64),
@@ -222,18 +279,52 @@ enum LoadRecordingErrorKind {
 #[error(transparent)]
 pub struct LoadRecordingError(#[from] LoadRecordingErrorKind);
 
+#[derive(Debug, Error)]
+#[error("failed to save recording")]
+pub struct SaveRecordingError(#[source] std::io::Error);
+
+/// Writes that arrive this close to the previous one are folded into the same item
+const WRITE_MERGE_WINDOW: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Error)]
+#[error("recording state is not a map")]
+pub struct InitialStateNotMap;
+
+/// Output of a terminal session along with the state the terminal started in, which can be
+/// played back with [`super::ReplayControl`]. Usually made by [`Recorder`], but can also be put
+/// together by hand, e.g. for tests
 #[derive(Clone, Debug, PartialEq)]
 pub struct Recording {
     initial_state: HashMap<String, tinyjson::JsonValue>,
     items: Vec<RecordingItem>,
+    // When each item happened relative to the start of the recording. Recordings made before
+    // timestamps were recorded have none
+    item_times: Vec<Option<Duration>>,
 }
 
 impl Recording {
-    fn new() -> Recording {
-        Recording {
-            initial_state: Default::default(),
+    /// A recording without items, starting from a terminal that was just created with the given
+    /// size
+    pub fn new(width: usize, height: usize) -> Result<Recording, SnapshotError> {
+        let terminal_emulator = TerminalEmulator::with_io(NoIo, width, height, PathBuf::new());
+        let initial_state = terminal_emulator.snapshot()?;
+        Ok(Recording::from_initial_state(initial_state).expect("terminal snapshots are maps"))
+    }
+
+    /// A recording without items, starting from `initial_state`, e.g. from
+    /// [`TerminalEmulator::snapshot`]
+    pub fn from_initial_state(
+        initial_state: SnapshotItem,
+    ) -> Result<Recording, InitialStateNotMap> {
+        let initial_state = match snapshot_to_tinyjson(initial_state) {
+            JsonValue::Object(v) => v,
+            _ => Err(InitialStateNotMap)?,
+        };
+        Ok(Recording {
+            initial_state,
             items: Default::default(),
-        }
+            item_times: Default::default(),
+        })
     }
 
     pub fn load(path: &Path) -> Result<Recording, LoadRecordingError> {
@@ -256,17 +347,25 @@ impl Recording {
             Err(ItemsNotArray)?
         };
 
-        let items = items
+        let (items, item_times) = items
             .into_iter()
             .map(|v| RecordingItem::from_json(v).map_err(ItemInvalid))
-            .collect::<Result<_, _>>()?;
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .unzip();
 
         Ok(Recording {
             initial_state,
             items,
+            item_times,
         })
     }
 
+    pub fn save(&self, path: &Path) -> Result<(), SaveRecordingError> {
+        let mut f = std::fs::File::create(path).map_err(SaveRecordingError)?;
+        self.to_json().format_to(&mut f).map_err(SaveRecordingError)
+    }
+
     fn to_json(&self) -> JsonValue {
         JsonValue::Object(
             [
@@ -276,7 +375,13 @@ impl Recording {
                 ),
                 (
                     "items".to_string(),
-                    JsonValue::Array(self.items.iter().map(|v| v.to_json()).collect()),
+                    JsonValue::Array(
+                        self.items
+                            .iter()
+                            .zip(&self.item_times)
+                            .map(|(item, time)| item.to_json(*time))
+                            .collect(),
+                    ),
                 ),
             ]
             .into(),
@@ -296,28 +401,54 @@ impl Recording {
     pub fn items(&self) -> &[RecordingItem] {
         &self.items
     }
+
+    /// When item `idx` happened, relative to the start of the recording
+    pub fn item_time(&self, idx: usize) -> Option<Duration> {
+        self.item_times.get(idx).copied().flatten()
+    }
+
+    /// How many items happened at or before `time`. Items without a time count as before
+    pub fn num_items_until(&self, time: Duration) -> usize {
+        self.item_times.partition_point(|t| *t <= Some(time))
+    }
+
+    /// Add `item`, which happened `time` after the recording started. Times should not go
+    /// backwards. They are kept in milliseconds
+    pub fn push(&mut self, item: RecordingItem, time: Duration) {
+        // Times are saved in milliseconds, keep the same precision in memory
+        let time = Duration::from_millis(time.as_millis() as u64);
+        self.items.push(item);
+        self.item_times.push(Some(time));
+    }
 }
 
 struct RecordingHandleInner {
     recording: Recording,
     path: PathBuf,
+    // Path the recording was started with, split off recordings are named after it
+    first_path: PathBuf,
+    start: Instant,
+}
+
+/// `path` with the current time added to the file name, e.g. `0-1700000000.json` for `0.json`
+fn split_recording_path(path: &Path) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut candidate = path.with_file_name(format!("{stem}-{secs}.json"));
+    let mut i = 1;
+    while candidate.exists() {
+        candidate = path.with_file_name(format!("{stem}-{secs}-{i}.json"));
+        i += 1;
+    }
+    candidate
 }
 
 impl Drop for RecordingHandleInner {
     fn drop(&mut self) {
-        let res = (|| -> Result<(), Box<dyn std::error::Error>> {
-            let mut f = std::fs::OpenOptions::new()
-                .create(true)
-                .truncate(true)
-                .write(true)
-                .open(&self.path)?;
-
-            self.recording.to_json().format_to(&mut f)?;
-            Ok(())
-        })();
-
-        if let Err(e) = res {
-            error!("Failed to save recording: {}", backtraced_err(&*e));
+        if let Err(e) = self.recording.save(&self.path) {
+            error!("{}", backtraced_err(&e));
         }
     }
 }
@@ -352,6 +483,8 @@ enum ParseRecordingItemErrorKind {
     DataElemNotU8,
     #[error("unexpected field: {0}")]
     UnexpectedField(String),
+    #[error("time_ms field is not a non-negative number")]
+    TimeInvalid,
 }
 
 #[derive(Error, Debug)]
@@ -364,6 +497,10 @@ pub struct RecordingHandle {
     inner: Arc<Mutex<RecordingHandleInner>>,
 }
 
+mod recording_item_keys {
+    pub const TIME_MS: &str = "time_ms";
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum RecordingItem {
     SetWinSize { width: usize, height: usize },
@@ -371,13 +508,22 @@ pub enum RecordingItem {
 }
 
 impl RecordingItem {
-    fn from_json(json: JsonValue) -> Result<RecordingItem, ParseRecordingItemError> {
+    /// Parses the item and the time it happened at, if it was recorded
+    fn from_json(
+        json: JsonValue,
+    ) -> Result<(RecordingItem, Option<Duration>), ParseRecordingItemError> {
         use ParseRecordingItemErrorKind::*;
 
         let JsonValue::Object(mut map) = json else {
             Err(RootNotObject)?
         };
 
+        let time = match map.remove(recording_item_keys::TIME_MS) {
+            Some(JsonValue::Number(ms)) if ms >= 0.0 => Some(Duration::from_millis(ms as u64)),
+            Some(_) => Err(TimeInvalid)?,
+            None => None,
+        };
+
         let typ = map.remove("type").ok_or(TypeNotPresent)?;
         let JsonValue::String(typ) = typ else {
             Err(TypeNotString)?
@@ -400,7 +546,7 @@ impl RecordingItem {
                 let height = height.round() as i64;
                 let height = height.try_into().map_err(HeightNotUsize)?;
 
-                Ok(RecordingItem::SetWinSize { width, height })
+                Ok((RecordingItem::SetWinSize { width, height }, time))
             }
             "write" => {
                 let data = map.remove("data").ok_or(DataNotPresent)?;
@@ -419,14 +565,14 @@ impl RecordingItem {
                     })
                     .collect::<Result<_, _>>()?;
 
-                Ok(RecordingItem::Write { data })
+                Ok((RecordingItem::Write { data }, time))
             }
             _ => Err(UnexpectedField(typ))?,
         }
     }
 
-    fn to_json(&self) -> tinyjson::JsonValue {
-        match self {
+    fn to_json(&self, time: Option<Duration>) -> tinyjson::JsonValue {
+        let mut json = match self {
             RecordingItem::SetWinSize { width, height } => JsonValue::Object(
                 [
                     ("type".into(), JsonValue::String("set_win_size".into())),
@@ -447,7 +593,15 @@ impl RecordingItem {
                 ]
                 .into(),
             ),
+        };
+
+        if let (Some(time), JsonValue::Object(map)) = (time, &mut json) {
+            map.insert(
+                recording_item_keys::TIME_MS.into(),
+                JsonValue::Number(time.as_millis() as f64),
+            );
         }
+        json
     }
 }
 
@@ -475,6 +629,8 @@ pub enum StartRecordingResponse {
 pub struct Recorder {
     recording_dir: PathBuf,
     handle: Weak<Mutex<RecordingHandleInner>>,
+    // How long output has to stop for to continue in a new file, see `should_split`
+    split_after: Option<Duration>,
 }
 
 impl Recorder {
@@ -482,48 +638,126 @@ impl Recorder {
         Recorder {
             recording_dir,
             handle: Weak::new(),
+            split_after: None,
         }
     }
 
+    pub fn set_recording_dir(&mut self, recording_dir: PathBuf) {
+        self.recording_dir = recording_dir;
+    }
+
+    pub fn set_split_after(&mut self, split_after: Option<Duration>) {
+        self.split_after = split_after;
+    }
+
+    /// Whether nothing was recorded for the split gap. Recordings without items are never split
+    pub fn should_split(&self) -> bool {
+        let (Some(split_after), Some(inner)) = (self.split_after, self.handle.upgrade()) else {
+            return false;
+        };
+        let inner = inner.lock().expect("poisoned lock");
+        let recording = &inner.recording;
+        let last_time = recording.item_time(recording.items.len().wrapping_sub(1));
+        last_time.is_some_and(|t| inner.start.elapsed().saturating_sub(t) >= split_after)
+    }
+
+    /// Save what was recorded so far and continue in a new file next to the first one, named
+    /// after the current time. The new recording starts from `initial_state`. Returns the path
+    /// of the new file, or `None` without a recording
+    pub fn split(
+        &self,
+        initial_state: HashMap<String, SnapshotItem>,
+    ) -> Result<Option<PathBuf>, SaveRecordingError> {
+        let Some(inner) = self.handle.upgrade() else {
+            return Ok(None);
+        };
+        let mut inner = inner.lock().expect("poisoned lock");
+        inner.recording.save(&inner.path)?;
+
+        inner.path = split_recording_path(&inner.first_path);
+        inner.recording = Recording {
+            initial_state: initial_state
+                .into_iter()
+                .map(|(name, item)| (name, snapshot_to_tinyjson(item)))
+                .collect(),
+            items: Default::default(),
+            item_times: Default::default(),
+        };
+        inner.start = Instant::now();
+        info!("Recording to {}", inner.path.display());
+        Ok(Some(inner.path.clone()))
+    }
+
     pub fn set_win_size(&self, width: usize, height: usize) {
         if let Some(inner) = self.handle.upgrade() {
             let mut inner = inner.lock().expect("poisoned lock");
+            let time = inner.start.elapsed();
             inner
                 .recording
-                .items
-                .push(RecordingItem::SetWinSize { width, height });
+                .push(RecordingItem::SetWinSize { width, height }, time);
         }
     }
 
     pub fn write(&self, to_insert: &[u8]) {
         if let Some(inner) = self.handle.upgrade() {
             let mut inner = inner.lock().expect("poisoned lock");
-            if let Some(RecordingItem::Write { data }) = inner.recording.items.last_mut() {
-                data.extend_from_slice(to_insert);
-            } else {
-                inner.recording.items.push(RecordingItem::Write {
-                    data: to_insert.to_vec(),
-                });
+            let time = inner.start.elapsed();
+            let recording = &mut inner.recording;
+            let last_time = recording.item_time(recording.items.len().wrapping_sub(1));
+            let can_merge = last_time.is_some_and(|t| time - t < WRITE_MERGE_WINDOW);
+            match recording.items.last_mut() {
+                Some(RecordingItem::Write { data }) if can_merge => {
+                    data.extend_from_slice(to_insert)
+                }
+                _ => recording.push(
+                    RecordingItem::Write {
+                        data: to_insert.to_vec(),
+                    },
+                    time,
+                ),
             }
         }
     }
 
     pub fn start_recording(&mut self) -> Result<StartRecordingResponse, std::io::Error> {
+        if let Some(handle) = self.handle.upgrade() {
+            return Ok(StartRecordingResponse::Existing(RecordingHandle {
+                inner: handle,
+            }));
+        }
+
         std::fs::create_dir_all(&self.recording_dir)?;
+        let recording_path = find_recording_path(&self.recording_dir);
+        self.start_recording_at(recording_path)
+    }
 
+    /// Like [`Recorder::start_recording`], but writes to `recording_path` instead of the next
+    /// free slot in the recording directory
+    pub fn start_recording_at(
+        &mut self,
+        recording_path: PathBuf,
+    ) -> Result<StartRecordingResponse, std::io::Error> {
         if let Some(handle) = self.handle.upgrade() {
             return Ok(StartRecordingResponse::Existing(RecordingHandle {
                 inner: handle,
             }));
         }
 
-        let recording_path = find_recording_path(&self.recording_dir);
+        if let Some(parent) = recording_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
 
         info!("Recording to {}", recording_path.display());
 
         let handle_inner = Arc::new(Mutex::new(RecordingHandleInner {
-            recording: Recording::new(),
+            recording: Recording {
+                initial_state: Default::default(),
+                items: Default::default(),
+                item_times: Default::default(),
+            },
+            first_path: recording_path.clone(),
             path: recording_path,
+            start: Instant::now(),
         }));
         self.handle = Arc::downgrade(&handle_inner);
 
@@ -583,5 +817,101 @@ mod test {
             Recording::load(&_temp_dir.path().join("0.json")).expect("failed to load recording");
 
         assert_eq!(loaded, saved);
+        assert!(loaded.item_time(0).is_some());
+    }
+
+    #[test]
+    fn test_recorder_split() {
+        let temp_dir = tempfile::TempDir::new().expect("failed to create tmp dir");
+        let mut recorder = Recorder::new(temp_dir.path().into());
+        recorder.set_split_after(Some(Duration::from_millis(20)));
+        let StartRecordingResponse::New(initializer) = recorder
+            .start_recording()
+            .expect("failed to start recording")
+        else {
+            panic!("Did not get initializer");
+        };
+        let handle = initializer.into_handle();
+
+        // Nothing to split off yet
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!recorder.should_split());
+        recorder.write(b"first");
+        assert!(!recorder.should_split());
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(recorder.should_split());
+
+        let initial_state = [("test".to_string(), 1i64.into())].into();
+        let split_path = recorder
+            .split(initial_state)
+            .expect("failed to split recording")
+            .expect("no recording to split");
+        assert!(split_path
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with("0-")));
+        assert!(!recorder.should_split());
+        recorder.write(b"second");
+        drop(handle);
+
+        let first = Recording::load(&temp_dir.path().join("0.json")).expect("failed to load");
+        assert_eq!(
+            first.items(),
+            [RecordingItem::Write {
+                data: b"first".to_vec()
+            }]
+        );
+        let second = Recording::load(&split_path).expect("failed to load");
+        assert_eq!(
+            second.items(),
+            [RecordingItem::Write {
+                data: b"second".to_vec()
+            }]
+        );
+        assert!(second
+            .initial_state()
+            .into_map()
+            .expect("not a map")
+            .contains_key("test"));
+    }
+
+    #[test]
+    fn test_load_recording_without_times() {
+        let temp_dir = tempfile::TempDir::new().expect("failed to create tmp dir");
+        let path = temp_dir.path().join("old.json");
+        std::fs::write(
+            &path,
+            r#"{"initial_state": {}, "items": [
+                {"type": "write", "data": [97]},
+                {"type": "set_win_size", "width": 10, "height": 5, "time_ms": 1500}
+            ]}"#,
+        )
+        .expect("failed to write recording");
+
+        let loaded = Recording::load(&path).expect("failed to load recording");
+        assert_eq!(loaded.items().len(), 2);
+        assert_eq!(loaded.item_time(0), None);
+        assert_eq!(loaded.item_time(1), Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_recorder_explicit_path() {
+        let temp_dir = tempfile::TempDir::new().expect("failed to create tmp dir");
+        let mut recorder = Recorder::new(temp_dir.path().join("unused"));
+        let recording_path = temp_dir.path().join("nested/session.json");
+
+        let StartRecordingResponse::New(initializer) = recorder
+            .start_recording_at(recording_path.clone())
+            .expect("failed to start recording")
+        else {
+            panic!("Did not get initializer");
+        };
+
+        let handle = initializer.into_handle();
+        recorder.write(b"asdf");
+        drop(handle);
+
+        let loaded = Recording::load(&recording_path).expect("failed to load recording");
+        assert_eq!(loaded.items().len(), 1);
+        assert!(!temp_dir.path().join("unused").exists());
     }
 }
@@ -1,8 +1,21 @@
 use super::io::TermIo;
-use crate::terminal_emulator::{ReadResponse, Recording, RecordingItem, SnapshotItem};
+use crate::{
+    error::backtraced_err,
+    terminal_emulator::{
+        cells_to_string, LoadRecordingError, LoadSnapshotError, ReadResponse, Recording,
+        RecordingItem, SnapshotItem, TerminalEmulator,
+    },
+};
 
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::{
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    time::Duration,
+};
 
+use thiserror::Error;
+
+/// Hands the bytes of a recording to the terminal emulator that replays it
 pub struct ReplayIo {
     rx: Receiver<u8>,
 }
@@ -45,6 +58,17 @@ fn calc_segment_lengths(recording: &Recording) -> Vec<usize> {
     recording.items().iter().map(item_len).collect()
 }
 
+/// Position right after each item
+fn calc_segment_ends(segment_lengths: &[usize]) -> Vec<usize> {
+    segment_lengths
+        .iter()
+        .scan(0, |end, len| {
+            *end += len;
+            Some(*end)
+        })
+        .collect()
+}
+
 enum RecordingAction {
     Write(u8),
     SetWinSize { width: usize, height: usize },
@@ -89,15 +113,19 @@ impl RecordingTracker {
     }
 }
 
+/// What the caller of [`ReplayControl::step`] has to do to the terminal
 pub enum ControlAction {
     Resize { width: usize, height: usize },
     None,
 }
 
+/// Steps through a recording one byte or resize at a time. Positions count bytes of written
+/// data, with every resize counting as one
 pub struct ReplayControl {
     recording: Recording,
     tracker: RecordingTracker,
     segment_lengths: Vec<usize>,
+    segment_ends: Vec<usize>,
     total_len: usize,
     tx: Sender<u8>,
     rx: Option<Receiver<u8>>,
@@ -112,11 +140,13 @@ impl ReplayControl {
 
         let (tx, rx) = mpsc::channel();
         let segment_lengths = calc_segment_lengths(&recording);
+        let segment_ends = calc_segment_ends(&segment_lengths);
         let total_len = segment_lengths.iter().sum();
         ReplayControl {
             recording,
             tracker,
             segment_lengths,
+            segment_ends,
             total_len,
             tx,
             rx: Some(rx),
@@ -127,6 +157,7 @@ impl ReplayControl {
         self.recording.initial_state()
     }
 
+    /// The [`ReplayIo`] to create the replaying terminal with. Can only be taken once
     pub fn io_handle(&mut self) -> ReplayIo {
         if let Some(rx) = std::mem::take(&mut self.rx) {
             ReplayIo { rx }
@@ -149,7 +180,57 @@ impl ReplayControl {
         self.total_len
     }
 
-    pub fn next(&mut self) -> ControlAction {
+    pub fn is_empty(&self) -> bool {
+        self.total_len == 0
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.current_pos() >= self.total_len
+    }
+
+    /// Whether the recording knows when its items happened, which lets it be played back in real
+    /// time
+    pub fn is_timed(&self) -> bool {
+        self.recording.item_time(0).is_some()
+    }
+
+    /// When the data the next [`Self::step`] feeds was recorded, relative to the start of the
+    /// recording
+    pub fn next_step_time(&self) -> Option<Duration> {
+        let mut idx = self.tracker.item_idx;
+        if self
+            .segment_lengths
+            .get(idx)
+            .is_some_and(|len| self.tracker.item_pos >= *len)
+        {
+            idx += 1;
+        }
+        self.recording.item_time(idx)
+    }
+
+    /// When the last item of a timed recording happened
+    pub fn duration(&self) -> Option<Duration> {
+        let last = self.recording.items().len().checked_sub(1)?;
+        self.recording.item_time(last)
+    }
+
+    /// When the data right before position `pos` was recorded
+    pub fn time_at_pos(&self, pos: usize) -> Option<Duration> {
+        let idx = self.segment_ends.partition_point(|end| *end < pos);
+        let last = self.segment_ends.len().checked_sub(1)?;
+        self.recording.item_time(idx.min(last))
+    }
+
+    /// Position right after everything that was recorded up to `time`
+    pub fn pos_at_time(&self, time: Duration) -> usize {
+        match self.recording.num_items_until(time).checked_sub(1) {
+            Some(idx) => self.segment_ends[idx],
+            None => 0,
+        }
+    }
+
+    /// Feed the next byte of the recording to the emulator
+    pub fn step(&mut self) -> ControlAction {
         let action = self.tracker.next(&self.recording);
         match action {
             RecordingAction::Write(b) => {
@@ -163,3 +244,251 @@ impl ReplayControl {
         }
     }
 }
+
+#[derive(Debug, Error)]
+enum LoadReplayErrorPriv {
+    #[error("failed to load recording")]
+    Recording(#[from] LoadRecordingError),
+    #[error("failed to construct terminal emulator")]
+    CreateTerminalEmulator(#[from] LoadSnapshotError),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct LoadReplayError(#[from] LoadReplayErrorPriv);
+
+/// A recording being played back onto a terminal emulator, without any window. Resizes are
+/// applied as they come up
+pub struct Replay {
+    terminal_emulator: TerminalEmulator<ReplayIo>,
+    control: ReplayControl,
+}
+
+impl Replay {
+    pub fn new(recording: Recording) -> Result<Replay, LoadReplayError> {
+        let mut control = ReplayControl::new(recording);
+        let io_handle = control.io_handle();
+        let terminal_emulator = TerminalEmulator::from_snapshot(control.initial_state(), io_handle)
+            .map_err(LoadReplayErrorPriv::CreateTerminalEmulator)?;
+        Ok(Replay {
+            terminal_emulator,
+            control,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Replay, LoadReplayError> {
+        let recording = Recording::load(path).map_err(LoadReplayErrorPriv::Recording)?;
+        Replay::new(recording)
+    }
+
+    pub fn control(&self) -> &ReplayControl {
+        &self.control
+    }
+
+    pub fn terminal_emulator(&self) -> &TerminalEmulator<ReplayIo> {
+        &self.terminal_emulator
+    }
+
+    /// Data fed by [`Replay::step`] shows up once the terminal reads it, e.g. with
+    /// [`TerminalEmulator::read`]
+    pub fn terminal_emulator_mut(&mut self) -> &mut TerminalEmulator<ReplayIo> {
+        &mut self.terminal_emulator
+    }
+
+    /// Feed the next byte of the recording to the terminal, or apply the next resize
+    pub fn step(&mut self) {
+        match self.control.step() {
+            ControlAction::Resize { width, height } => {
+                // Data from before the resize has to be handled at the old size
+                self.terminal_emulator.read();
+                if let Err(e) = self.terminal_emulator.set_win_size(width, height) {
+                    error!("failed to set window size: {}", backtraced_err(&*e));
+                }
+            }
+            ControlAction::None => (),
+        }
+    }
+
+    /// Play the rest of the recording and have the terminal handle all of it
+    pub fn run_to_end(&mut self) {
+        while !self.control.is_finished() {
+            self.step();
+        }
+        self.terminal_emulator.read();
+    }
+
+    /// Go to position `pos`, as if the recording had been played from the start up to there.
+    /// Going back starts over from the initial state. Like [`Replay::step`], the data shows up
+    /// once the terminal reads it
+    pub fn seek(&mut self, pos: usize) -> Result<(), LoadReplayError> {
+        if pos < self.control.current_pos() {
+            *self = Replay::new(self.control.recording.clone())?;
+        }
+        while self.control.current_pos() < pos && !self.control.is_finished() {
+            self.step();
+        }
+        Ok(())
+    }
+
+    /// Text of the visible rows, after reading what was fed so far
+    pub fn screen(&mut self) -> Vec<String> {
+        self.terminal_emulator.read();
+        self.terminal_emulator
+            .rows()
+            .map(|row| cells_to_string(row.text()))
+            .collect()
+    }
+}
+
+/// What `recording` shows at position `pos`, one string per visible row. Rows past the end of
+/// the output are left out. For tests that check what a replay shows at a given step
+pub fn screen_at(recording: Recording, pos: usize) -> Result<Vec<String>, LoadReplayError> {
+    let mut replay = Replay::new(recording)?;
+    replay.seek(pos)?;
+    Ok(replay.screen())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_replay_time_positions() {
+        let mut recording = Recording::new(10, 3).expect("failed to create recording");
+        let write = |data: &[u8]| RecordingItem::Write {
+            data: data.to_vec(),
+        };
+        recording.push(write(b"ab"), Duration::from_millis(0));
+        recording.push(write(b"cdef"), Duration::from_secs(1));
+        recording.push(write(b"g"), Duration::from_secs(5));
+        let control = ReplayControl::new(recording);
+
+        assert_eq!(control.duration(), Some(Duration::from_secs(5)));
+        assert_eq!(control.pos_at_time(Duration::ZERO), 2);
+        assert_eq!(control.pos_at_time(Duration::from_millis(999)), 2);
+        assert_eq!(control.pos_at_time(Duration::from_secs(3)), 6);
+        assert_eq!(control.pos_at_time(Duration::from_secs(60)), 7);
+
+        assert_eq!(control.time_at_pos(0), Some(Duration::ZERO));
+        assert_eq!(control.time_at_pos(2), Some(Duration::ZERO));
+        assert_eq!(control.time_at_pos(3), Some(Duration::from_secs(1)));
+        assert_eq!(control.time_at_pos(7), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_replay_made_up_recording() {
+        let mut recording = Recording::new(10, 3).expect("failed to create recording");
+        recording.push(
+            RecordingItem::Write {
+                data: b"hello\r\n".to_vec(),
+            },
+            Duration::ZERO,
+        );
+        recording.push(
+            RecordingItem::SetWinSize {
+                width: 4,
+                height: 3,
+            },
+            Duration::from_millis(100),
+        );
+        recording.push(
+            RecordingItem::Write {
+                data: b"abcdef".to_vec(),
+            },
+            Duration::from_millis(250),
+        );
+
+        let temp_dir = tempfile::TempDir::new().expect("failed to create tmp dir");
+        let path = temp_dir.path().join("made_up.json");
+        recording.save(&path).expect("failed to save recording");
+
+        let mut replay = Replay::load(&path).expect("failed to load replay");
+        assert!(replay.control().is_timed());
+        assert_eq!(replay.control().len(), 7 + 1 + 6);
+
+        replay.run_to_end();
+        assert!(replay.control().is_finished());
+        let terminal_emulator = replay.terminal_emulator();
+        assert_eq!(terminal_emulator.get_win_size(), (4, 3));
+        let data = terminal_emulator.data();
+        let text = [data.scrollback, data.visible].concat();
+        assert_eq!(cells_to_string(&text), "hello\nabcdef\n");
+    }
+
+    fn made_up_recording() -> Recording {
+        let mut recording = Recording::new(10, 3).expect("failed to create recording");
+        let items = [
+            RecordingItem::Write {
+                data: b"\x1b]2;title\x07one\r\n\x1b[1;31mtwo\x1b[0m\r\n".to_vec(),
+            },
+            RecordingItem::SetWinSize {
+                width: 6,
+                height: 4,
+            },
+            RecordingItem::Write {
+                data: "three \u{e9}\u{e9}\r\n\x1b[?1049hfull\x1b[2;3Hx".into(),
+            },
+            RecordingItem::Write {
+                data: b"\x1b[?1049l\x1b[4hab\x1b[1;1H\x1b[2Kfour".to_vec(),
+            },
+        ];
+        for (i, item) in items.into_iter().enumerate() {
+            recording.push(item, Duration::from_millis(i as u64 * 100));
+        }
+        recording
+    }
+
+    #[test]
+    fn test_screen_at() {
+        let recording = made_up_recording();
+        assert_eq!(
+            screen_at(recording.clone(), 31).expect("failed to replay"),
+            ["one", "two"]
+        );
+        // Partway into the alternate screen, where the cursor carried over to the last row
+        assert_eq!(
+            screen_at(recording, 31 + 1 + 12 + 8 + 4).expect("failed to replay"),
+            ["", "", "", "full"]
+        );
+    }
+
+    /// What shows, leaving out how the formatting happens to be split up, which depends on how
+    /// the data was chunked when read
+    fn shown_state(replay: &mut Replay) -> impl PartialEq + std::fmt::Debug {
+        replay.terminal_emulator_mut().read();
+        let terminal_emulator = replay.terminal_emulator();
+        let data = terminal_emulator.data();
+        (
+            [data.scrollback, data.visible].concat(),
+            terminal_emulator.to_ansi(),
+            terminal_emulator.cursor_pos(),
+            terminal_emulator.get_win_size(),
+            terminal_emulator.is_alternate_screen(),
+            terminal_emulator.window_title().map(str::to_string),
+        )
+    }
+
+    #[test]
+    fn test_seek_matches_stepping() {
+        let recording = made_up_recording();
+        let mut stepped = Replay::new(recording.clone()).expect("failed to load replay");
+        let mut expected = vec![shown_state(&mut stepped)];
+        while !stepped.control().is_finished() {
+            stepped.step();
+            expected.push(shown_state(&mut stepped));
+        }
+
+        // Back and forth over every position, in an order that jumps around
+        let len = expected.len();
+        let mut seeked = Replay::new(recording).expect("failed to load replay");
+        for i in 0..len {
+            let pos = i * 7 % len;
+            seeked.seek(pos).expect("failed to seek");
+            assert_eq!(
+                shown_state(&mut seeked),
+                expected[pos],
+                "state differs at position {pos}"
+            );
+        }
+    }
+}
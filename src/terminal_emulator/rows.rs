@@ -0,0 +1,281 @@
+//! Renderer independent view of the visible grid. Text is borrowed straight from the terminal
+//! buffer, so walking the rows does not copy any output
+
+use std::ops::Range;
+
+use super::{FormatTag, LineSize, TerminalColor, UnderlineStyle, WIDE_CHAR_SPACER};
+
+/// Everything that affects how a cell is drawn
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CellStyle {
+    pub color: TerminalColor,
+    pub background: TerminalColor,
+    pub underline: UnderlineStyle,
+    /// [`TerminalColor::Default`] draws underlines in the text color
+    pub underline_color: TerminalColor,
+    pub bold: bool,
+}
+
+impl Default for CellStyle {
+    fn default() -> Self {
+        CellStyle {
+            color: TerminalColor::Default,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+            bold: false,
+        }
+    }
+}
+
+impl From<&FormatTag> for CellStyle {
+    fn from(tag: &FormatTag) -> Self {
+        CellStyle {
+            color: tag.color,
+            background: tag.background,
+            underline: tag.underline,
+            underline_color: tag.underline_color,
+            bold: tag.bold,
+        }
+    }
+}
+
+/// A single character on screen. `text` is the cell of the character in the terminal buffer,
+/// followed by its spacer for a wide character
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cell<'a> {
+    pub text: &'a [char],
+    pub style: CellStyle,
+}
+
+/// Consecutive cells that share a style
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CellRun<'a> {
+    pub text: &'a [char],
+    pub style: CellStyle,
+}
+
+impl<'a> CellRun<'a> {
+    /// Split the run into its characters
+    pub fn cells(&self) -> impl Iterator<Item = Cell<'a>> + '_ {
+        let style = self.style;
+        let mut remaining = self.text;
+        std::iter::from_fn(move || {
+            // The spacer of a character in the run before
+            if remaining.first() == Some(&WIDE_CHAR_SPACER) {
+                remaining = &remaining[1..];
+            }
+            if remaining.is_empty() {
+                return None;
+            }
+            let len = match remaining.get(1) {
+                Some(&WIDE_CHAR_SPACER) => 2,
+                _ => 1,
+            };
+            let (text, rest) = remaining.split_at(len);
+            remaining = rest;
+            Some(Cell { text, style })
+        })
+    }
+}
+
+/// One line of the visible grid. Rows hold cells only, unused columns at the end of a line are
+/// not part of the row
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Row<'a> {
+    text: &'a [char],
+    runs: Vec<CellRun<'a>>,
+    line_size: LineSize,
+    wrapped: bool,
+}
+
+impl<'a> Row<'a> {
+    /// `line` is the position of the row in `buf`, `tags` must be sorted
+    pub(crate) fn new(
+        buf: &'a [char],
+        line: Range<usize>,
+        tags: &[FormatTag],
+        line_size: LineSize,
+    ) -> Row<'a> {
+        Self::new_clipped(buf, line.clone(), line, tags, line_size)
+    }
+
+    /// Part of the row at `line`, only the cells within `clip` are kept. Whether the row is
+    /// wrapped still depends on the full line
+    pub(crate) fn new_clipped(
+        buf: &'a [char],
+        line: Range<usize>,
+        clip: Range<usize>,
+        tags: &[FormatTag],
+        line_size: LineSize,
+    ) -> Row<'a> {
+        let wrapped = line.end < buf.len() && buf[line.end] != '\n';
+        let line = line.start.max(clip.start)..line.end.min(clip.end).max(line.start);
+        let mut runs = Vec::new();
+        let mut pos = line.start;
+
+        let first_tag = tags.partition_point(|tag| tag.end <= line.start);
+        for tag in &tags[first_tag..] {
+            if pos >= line.end {
+                break;
+            }
+
+            let start = tag.start.max(pos);
+            let end = tag.end.min(line.end);
+            if start >= end {
+                continue;
+            }
+
+            // Tags are expected to cover everything, but do not lose text if they do not
+            if start > pos {
+                runs.push(CellRun {
+                    text: &buf[pos..start],
+                    style: CellStyle::default(),
+                });
+            }
+
+            runs.push(CellRun {
+                text: &buf[start..end],
+                style: tag.into(),
+            });
+            pos = end;
+        }
+
+        if pos < line.end {
+            runs.push(CellRun {
+                text: &buf[pos..line.end],
+                style: CellStyle::default(),
+            });
+        }
+
+        Row {
+            text: &buf[line.clone()],
+            runs,
+            line_size,
+            wrapped,
+        }
+    }
+
+    /// Unstyled content of the row
+    pub fn text(&self) -> &'a [char] {
+        self.text
+    }
+
+    pub fn runs(&self) -> &[CellRun<'a>] {
+        &self.runs
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = Cell<'a>> + '_ {
+        self.runs.iter().flat_map(|run| run.cells())
+    }
+
+    pub fn line_size(&self) -> LineSize {
+        self.line_size
+    }
+
+    /// Whether the line continues on the next row because it hit the edge of the screen, as
+    /// opposed to ending with a newline
+    pub fn is_wrapped(&self) -> bool {
+        self.wrapped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::cells;
+
+    fn tag(range: Range<usize>, color: TerminalColor) -> FormatTag {
+        FormatTag {
+            start: range.start,
+            end: range.end,
+            color,
+            bold: false,
+            background: TerminalColor::Default,
+            underline: UnderlineStyle::None,
+            underline_color: TerminalColor::Default,
+        }
+    }
+
+    #[test]
+    fn test_row_runs() {
+        let buf = &cells("abcdef\nghi");
+        let tags = [
+            tag(0..2, TerminalColor::Red),
+            tag(2..8, TerminalColor::Blue),
+            tag(8..usize::MAX, TerminalColor::Default),
+        ];
+
+        let row = Row::new(buf, 1..6, &tags, LineSize::SingleWidth);
+        assert_eq!(row.text(), &cells("bcdef"));
+        assert!(!row.is_wrapped());
+        let runs: Vec<_> = row.runs().iter().map(|r| (r.text, r.style.color)).collect();
+        assert_eq!(
+            runs,
+            [
+                (&cells("b")[..], TerminalColor::Red),
+                (&cells("cdef")[..], TerminalColor::Blue)
+            ]
+        );
+
+        let row = Row::new(buf, 7..10, &tags, LineSize::DoubleWidth);
+        let runs: Vec<_> = row.runs().iter().map(|r| (r.text, r.style.color)).collect();
+        assert_eq!(
+            runs,
+            [
+                (&cells("g")[..], TerminalColor::Blue),
+                (&cells("hi")[..], TerminalColor::Default)
+            ]
+        );
+        assert_eq!(row.line_size(), LineSize::DoubleWidth);
+    }
+
+    #[test]
+    fn test_row_untagged() {
+        let buf = cells("abcd");
+        let row = Row::new(&buf, 0..2, &[], LineSize::SingleWidth);
+        assert_eq!(row.runs().len(), 1);
+        assert_eq!(row.runs()[0].style, CellStyle::default());
+        assert!(row.is_wrapped());
+    }
+
+    #[test]
+    fn test_row_clipped() {
+        let buf = &cells("abcdef\nghi");
+        let tags = [tag(0..usize::MAX, TerminalColor::Red)];
+
+        let row = Row::new_clipped(buf, 0..6, 2..4, &tags, LineSize::SingleWidth);
+        assert_eq!(row.text(), &cells("cd"));
+        assert_eq!(row.runs()[0].text, &cells("cd"));
+        assert!(!row.is_wrapped());
+
+        let row = Row::new_clipped(buf, 7..10, 0..3, &tags, LineSize::SingleWidth);
+        assert!(row.runs().is_empty());
+    }
+
+    #[test]
+    fn test_cells() {
+        let text = cells("aé\u{ff}");
+        let run = CellRun {
+            text: &text,
+            style: CellStyle::default(),
+        };
+        let run_cells: Vec<_> = run.cells().map(|c| c.text).collect();
+        assert_eq!(run_cells, [['a'], ['é'], ['\u{ff}']]);
+
+        // A wide character keeps its spacer, one whose character is in the run before is skipped
+        let text = ['漢', WIDE_CHAR_SPACER, 'a', WIDE_CHAR_SPACER];
+        let run = CellRun {
+            text: &text[..3],
+            style: CellStyle::default(),
+        };
+        let run_cells: Vec<_> = run.cells().map(|c| c.text).collect();
+        assert_eq!(run_cells, [&text[..2], &text[2..3]]);
+        let run = CellRun {
+            text: &text[1..],
+            style: CellStyle::default(),
+        };
+        let run_cells: Vec<_> = run.cells().map(|c| c.text).collect();
+        assert_eq!(run_cells, [&text[2..]]);
+    }
+}
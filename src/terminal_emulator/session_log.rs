@@ -0,0 +1,118 @@
+//! Appending everything the child writes to a plain log file, like script(1). Unlike recordings
+//! the log cannot be replayed, it is meant for archiving and grepping
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use super::ansi::AnsiStripper;
+use crate::error::backtraced_err;
+
+/// What ends up in the log
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionLogFormat {
+    /// The output as the child wrote it, escape sequences included
+    Raw,
+    /// Only the text. Escape sequences, carriage returns and other controls are dropped
+    Plain,
+}
+
+impl std::str::FromStr for SessionLogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "raw" => SessionLogFormat::Raw,
+            "plain" => SessionLogFormat::Plain,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
+pub struct SessionLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    // Set for plain logs. The emulator's own parser may be sanitizing or mid sequence, so plain
+    // logs track sequences on their own
+    stripper: Option<AnsiStripper>,
+}
+
+impl SessionLog {
+    /// Start logging to `path`. An existing log is appended to
+    pub fn open(path: PathBuf, format: SessionLogFormat) -> std::io::Result<SessionLog> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let stripper = match format {
+            SessionLogFormat::Raw => None,
+            SessionLogFormat::Plain => Some(AnsiStripper::new()),
+        };
+        Ok(SessionLog {
+            path,
+            writer: BufWriter::new(file),
+            stripper,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&mut self, incoming: &[u8]) {
+        let res = match &mut self.stripper {
+            None => self.writer.write_all(incoming),
+            Some(stripper) => self.writer.write_all(&stripper.push(incoming)),
+        };
+        if let Err(e) = res {
+            error!("Failed to write session log: {}", backtraced_err(&e));
+        }
+    }
+
+    /// Hand what was written so far to the file, so that it can be followed while the session
+    /// is running
+    pub fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            error!("Failed to flush session log: {}", backtraced_err(&e));
+        }
+    }
+}
+
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_session_log() {
+        let dir = tempfile::tempdir().expect("failed to create temp dir");
+        let output = b"\x1b]0;title\x07\x1b[1;31mred\x1b[0m\r\nnext\r\n";
+        for (format, expected) in [
+            (SessionLogFormat::Raw, &output[..]),
+            (SessionLogFormat::Plain, b"red\nnext\n"),
+        ] {
+            let path = dir.path().join(format!("{format:?}.log"));
+            let mut log = SessionLog::open(path.clone(), format).expect("failed to open log");
+            // Split in the middle of a sequence
+            log.write(&output[..14]);
+            log.write(&output[14..]);
+            drop(log);
+            assert_eq!(std::fs::read(&path).expect("failed to read log"), expected);
+        }
+
+        // Logging again appends
+        let path = dir.path().join("Plain.log");
+        let mut log = SessionLog::open(path.clone(), SessionLogFormat::Plain).expect("open");
+        log.write(b"more\n");
+        log.flush();
+        assert_eq!(
+            std::fs::read(&path).expect("failed to read log"),
+            b"red\nnext\nmore\n"
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::{fmt, ops::Range};
+
+use super::recording::{NotIntOfType, SnapshotItem};
+use thiserror::Error;
+
+/// Semantic prompt marks a shell integration sends with OSC 133
+/// https://gitlab.freedesktop.org/Per_Bothner/specifications/blob/master/proposals/semantic-prompts.md
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ShellMark {
+    // OSC 133 ; A
+    PromptStart,
+    // OSC 133 ; B, the end of the prompt where the command line starts
+    CommandStart,
+    // OSC 133 ; C, the command was entered and its output starts here
+    OutputStart,
+    // OSC 133 ; D
+    CommandFinished,
+}
+
+impl fmt::Display for ShellMark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellMark::PromptStart => f.write_str("prompt_start"),
+            ShellMark::CommandStart => f.write_str("command_start"),
+            ShellMark::OutputStart => f.write_str("output_start"),
+            ShellMark::CommandFinished => f.write_str("command_finished"),
+        }
+    }
+}
+
+impl std::str::FromStr for ShellMark {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s {
+            "prompt_start" => ShellMark::PromptStart,
+            "command_start" => ShellMark::CommandStart,
+            "output_start" => ShellMark::OutputStart,
+            "command_finished" => ShellMark::CommandFinished,
+            _ => return Err(()),
+        };
+        Ok(ret)
+    }
+}
+
+/// A mark at buffer position `pos`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShellMarkTag {
+    pub pos: usize,
+    pub mark: ShellMark,
+}
+
+mod shell_mark_tag_keys {
+    pub const POS: &str = "pos";
+    pub const MARK: &str = "mark";
+}
+
+#[derive(Debug, Error)]
+enum LoadShellMarkSnapshotErrorKind {
+    #[error("root element is not an array")]
+    NotArray,
+    #[error("tag is not a map")]
+    TagNotMap,
+    #[error("pos missing")]
+    PosMissing,
+    #[error("pos is not a usize")]
+    PosNotUsize(#[source] NotIntOfType),
+    #[error("mark missing")]
+    MarkMissing,
+    #[error("mark is not a string")]
+    MarkNotString,
+    #[error("failed to parse mark")]
+    ParseMark(()),
+}
+
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct LoadShellMarkSnapshotError(#[from] LoadShellMarkSnapshotErrorKind);
+
+#[derive(Debug, Error)]
+#[error("mark position cannot be serialized as i64")]
+pub struct SnapshotShellMarkError(#[source] std::num::TryFromIntError);
+
+/// Where the shell said prompts, commands and their output are. Positions are kept up to date
+/// as the buffer changes the same way line sizes are
+pub struct ShellMarkTracker {
+    // Sorted by position
+    tags: Vec<ShellMarkTag>,
+    // Marks wait for the next text to be written. The cursor often sits on a line that does not
+    // exist in the buffer yet, so there is no position for them before that
+    pending: Vec<ShellMark>,
+}
+
+impl ShellMarkTracker {
+    pub fn new() -> ShellMarkTracker {
+        ShellMarkTracker {
+            tags: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn from_snapshot(
+        snapshot: SnapshotItem,
+    ) -> Result<ShellMarkTracker, LoadShellMarkSnapshotError> {
+        use LoadShellMarkSnapshotErrorKind::*;
+
+        let arr = snapshot.into_vec().map_err(|_| NotArray)?;
+        let mut tags = Vec::with_capacity(arr.len());
+        for item in arr {
+            let mut map = item.into_map().map_err(|_| TagNotMap)?;
+
+            let pos = map.remove(shell_mark_tag_keys::POS).ok_or(PosMissing)?;
+            let pos = pos.into_num().map_err(PosNotUsize)?;
+
+            let mark = map.remove(shell_mark_tag_keys::MARK).ok_or(MarkMissing)?;
+            let mark = mark.into_string().map_err(|_| MarkNotString)?;
+            let mark = mark.parse().map_err(ParseMark)?;
+
+            tags.push(ShellMarkTag { pos, mark });
+        }
+
+        Ok(ShellMarkTracker {
+            tags,
+            pending: Vec::new(),
+        })
+    }
+
+    pub fn snapshot(&self) -> Result<SnapshotItem, SnapshotShellMarkError> {
+        let tags = self
+            .tags
+            .iter()
+            .map(|tag| {
+                let pos: i64 = tag.pos.try_into().map_err(SnapshotShellMarkError)?;
+                Ok(SnapshotItem::Map(
+                    [
+                        (shell_mark_tag_keys::POS.to_string(), pos.into()),
+                        (
+                            shell_mark_tag_keys::MARK.to_string(),
+                            tag.mark.to_string().into(),
+                        ),
+                    ]
+                    .into(),
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SnapshotItem::Array(tags))
+    }
+
+    pub fn push(&mut self, mark: ShellMark) {
+        self.pending.push(mark);
+    }
+
+    /// Text was written starting at `pos`, marks received before it belong there
+    pub fn place_pending(&mut self, pos: usize) {
+        for mark in std::mem::take(&mut self.pending) {
+            self.insert(pos, mark);
+        }
+    }
+
+    fn insert(&mut self, pos: usize, mark: ShellMark) {
+        let idx = self.tags.partition_point(|tag| tag.pos <= pos);
+        self.tags.insert(idx, ShellMarkTag { pos, mark });
+    }
+
+    /// Output of the most recent command that finished, from its output start mark up to the
+    /// command finished or next prompt mark
+    pub fn last_command_output(&self) -> Option<Range<usize>> {
+        self.tags
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, tag)| tag.mark == ShellMark::OutputStart)
+            .find_map(|(i, start)| {
+                let end = self.tags[i + 1..].iter().find(|tag| {
+                    matches!(
+                        tag.mark,
+                        ShellMark::CommandFinished | ShellMark::PromptStart
+                    )
+                })?;
+                Some(start.pos..end.pos)
+            })
+    }
+
+    /// Data was inserted at `range`. A mark where the data went in stays in front of it
+    pub fn push_range_adjustment(&mut self, range: Range<usize>) {
+        let range_len = range.end - range.start;
+        for tag in &mut self.tags {
+            if tag.pos > range.start {
+                tag.pos += range_len;
+            }
+        }
+    }
+
+    /// Whole lines were inserted at `range`, pushing the line that started there down
+    pub fn push_lines_adjustment(&mut self, range: Range<usize>) {
+        let range_len = range.end - range.start;
+        for tag in &mut self.tags {
+            if tag.pos >= range.start {
+                tag.pos += range_len;
+            }
+        }
+    }
+
+    /// Data at `range` was removed. Marks inside of it move to where it was
+    pub fn delete_range(&mut self, range: Range<usize>) {
+        let del_size = range.end - range.start;
+        for tag in &mut self.tags {
+            if tag.pos >= range.end {
+                tag.pos -= del_size;
+            } else if tag.pos > range.start {
+                tag.pos = range.start;
+            }
+        }
+    }
+
+    /// Whole lines at `range` were removed along with their marks
+    pub fn delete_lines(&mut self, range: Range<usize>) {
+        let del_size = range.end - range.start;
+        self.tags
+            .retain(|tag| tag.pos < range.start || tag.pos >= range.end);
+        for tag in &mut self.tags {
+            if tag.pos >= range.end {
+                tag.pos -= del_size;
+            }
+        }
+    }
+
+    /// Everything after `pos` was erased
+    pub fn clear_from(&mut self, pos: usize) {
+        self.tags.retain(|tag| tag.pos <= pos);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_last_command_output() {
+        let mut tracker = ShellMarkTracker::new();
+        assert_eq!(tracker.last_command_output(), None);
+
+        tracker.insert(0, ShellMark::PromptStart);
+        tracker.insert(2, ShellMark::CommandStart);
+        tracker.insert(6, ShellMark::OutputStart);
+        tracker.insert(20, ShellMark::CommandFinished);
+        tracker.insert(20, ShellMark::PromptStart);
+        assert_eq!(tracker.last_command_output(), Some(6..20));
+
+        // A command that is still running does not count
+        tracker.insert(26, ShellMark::OutputStart);
+        assert_eq!(tracker.last_command_output(), Some(6..20));
+        tracker.insert(30, ShellMark::PromptStart);
+        assert_eq!(tracker.last_command_output(), Some(26..30));
+    }
+
+    #[test]
+    fn test_shell_mark_adjustments() {
+        let mut tracker = ShellMarkTracker::new();
+        tracker.insert(5, ShellMark::OutputStart);
+        tracker.insert(10, ShellMark::CommandFinished);
+
+        tracker.push_range_adjustment(5..8);
+        assert_eq!(tracker.last_command_output(), Some(5..13));
+        tracker.push_lines_adjustment(5..6);
+        assert_eq!(tracker.last_command_output(), Some(6..14));
+
+        tracker.delete_range(4..8);
+        assert_eq!(tracker.last_command_output(), Some(4..10));
+
+        tracker.delete_lines(0..5);
+        assert_eq!(tracker.tags.len(), 1);
+        assert_eq!(tracker.tags[0].pos, 5);
+
+        tracker.clear_from(4);
+        assert!(tracker.tags.is_empty());
+    }
+
+    #[test]
+    fn test_shell_mark_snapshot() {
+        let mut tracker = ShellMarkTracker::new();
+        tracker.insert(3, ShellMark::CommandStart);
+        let snapshot = tracker.snapshot().expect("failed to snapshot");
+        let loaded = ShellMarkTracker::from_snapshot(snapshot).expect("failed to load");
+        assert_eq!(loaded.tags, tracker.tags);
+    }
+}
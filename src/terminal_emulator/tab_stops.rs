@@ -0,0 +1,105 @@
+//! Columns that tabs, CHT and CBT move the cursor to. Every 8th column is a stop until the program
+//! sets its own with HTS and TBC
+
+const DEFAULT_TAB_WIDTH: usize = 8;
+
+pub struct TabStops {
+    stops: Vec<bool>,
+}
+
+impl TabStops {
+    pub fn new(width: usize) -> TabStops {
+        let mut ret = TabStops { stops: Vec::new() };
+        ret.resize(width);
+        ret
+    }
+
+    /// Stops in columns that are kept stay as they are, new columns get the default ones
+    pub fn resize(&mut self, width: usize) {
+        let old_width = self.stops.len();
+        self.stops.truncate(width);
+        self.stops
+            .extend((old_width..width).map(|x| x % DEFAULT_TAB_WIDTH == 0));
+    }
+
+    pub fn set(&mut self, x: usize) {
+        if let Some(stop) = self.stops.get_mut(x) {
+            *stop = true;
+        }
+    }
+
+    pub fn clear(&mut self, x: usize) {
+        if let Some(stop) = self.stops.get_mut(x) {
+            *stop = false;
+        }
+    }
+
+    pub fn clear_all(&mut self) {
+        self.stops.fill(false);
+    }
+
+    /// The columns that have a stop
+    pub fn columns(&self) -> impl Iterator<Item = usize> + '_ {
+        self.stops
+            .iter()
+            .enumerate()
+            .filter(|(_, stop)| **stop)
+            .map(|(x, _)| x)
+    }
+
+    /// Column of the `n`th stop right of `x`, stopping at the last column
+    pub fn next(&self, mut x: usize, n: usize) -> usize {
+        let last = self.stops.len().saturating_sub(1);
+        for _ in 0..n {
+            if x >= last {
+                return last;
+            }
+            x = (x + 1..last).find(|x| self.stops[*x]).unwrap_or(last);
+        }
+        x
+    }
+
+    /// Column of the `n`th stop left of `x`, stopping at the first column
+    pub fn prev(&self, mut x: usize, n: usize) -> usize {
+        x = x.min(self.stops.len().saturating_sub(1));
+        for _ in 0..n {
+            if x == 0 {
+                break;
+            }
+            x = (1..x).rev().find(|x| self.stops[*x]).unwrap_or(0);
+        }
+        x
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tab_stops() {
+        let mut stops = TabStops::new(20);
+        assert_eq!(stops.next(0, 1), 8);
+        assert_eq!(stops.next(8, 1), 16);
+        assert_eq!(stops.next(3, 2), 16);
+        assert_eq!(stops.next(3, 5), 19);
+        assert_eq!(stops.prev(19, 1), 16);
+        assert_eq!(stops.prev(16, 1), 8);
+        assert_eq!(stops.prev(9, 5), 0);
+
+        stops.clear_all();
+        stops.set(3);
+        stops.set(12);
+        assert_eq!(stops.next(0, 1), 3);
+        assert_eq!(stops.next(3, 1), 12);
+        assert_eq!(stops.prev(12, 1), 3);
+        stops.clear(3);
+        assert_eq!(stops.next(0, 1), 12);
+
+        // Growing adds default stops past the old edge only
+        stops.resize(30);
+        assert_eq!(stops.next(12, 1), 24);
+        stops.resize(10);
+        assert_eq!(stops.next(0, 1), 9);
+    }
+}
@@ -0,0 +1,230 @@
+//! What vttest's cursor movement and screen feature screens send, scaled down to small screens,
+//! along with the grid each of them should leave. Character sets are not supported, the
+//! selecting sequences only have to leave the text alone
+
+use super::{cells_to_string, CursorPos, TerminalColor, TerminalEmulator};
+use crate::test_util::NoIo;
+
+/// The screen after `data`, one string per row with every row padded to the full width
+fn screen(width: usize, height: usize, data: &[u8]) -> Vec<String> {
+    let mut emulator = TerminalEmulator::with_io(NoIo, width, height, "recordings".into());
+    emulator.handle_incoming_data(data);
+    grid(&emulator)
+}
+
+fn grid(emulator: &TerminalEmulator<NoIo>) -> Vec<String> {
+    let (width, height) = emulator.get_win_size();
+    let mut rows: Vec<String> = emulator
+        .rows()
+        .map(|row| format!("{:width$}", cells_to_string(row.text())))
+        .collect();
+    rows.resize(height, " ".repeat(width));
+    rows
+}
+
+fn cup(row: usize, col: usize) -> Vec<u8> {
+    format!("\x1b[{row};{col}H").into_bytes()
+}
+
+#[test]
+fn test_cursor_box() {
+    // The first test of the cursor movements: a frame of E's left over from DECALN in the
+    // middle, with a border of *'s and +'s around the screen
+    let mut data = b"\x1b#8".to_vec();
+    data.extend(cup(2, 20));
+    data.extend(b"\x1b[1J");
+    data.extend(cup(9, 1));
+    data.extend(b"\x1b[0J");
+    for row in 3..=8 {
+        data.extend(cup(row, 4));
+        data.extend(b"\x1b[1K");
+        data.extend(cup(row, 17));
+        data.extend(b"\x1b[K");
+    }
+    for row in 4..=7 {
+        data.extend(cup(row, 6));
+        data.extend(b"\x1b[10X");
+    }
+
+    data.extend(cup(1, 1));
+    data.extend(b"*".repeat(20));
+    // The left side goes down with IND, the right side up with RI
+    data.extend(cup(2, 1));
+    for _ in 2..=9 {
+        data.extend(b"+\x08\x1bD");
+    }
+    data.extend(cup(9, 20));
+    for _ in 2..=9 {
+        data.extend(b"\x1b7+\x1b8\x1bM");
+    }
+    // Without autowrap the bottom row does not scroll the screen, however much is written
+    data.extend(cup(10, 1));
+    data.extend(b"\x1b[?7l");
+    data.extend(b"*".repeat(25));
+    data.extend(b"\x1b[?7h");
+
+    let mut expected = vec!["*".repeat(20), format!("+{:18}+", "")];
+    expected.push(format!("+   {}   +", "E".repeat(12)));
+    for _ in 4..=7 {
+        expected.push(format!("+   E{:10}E   +", ""));
+    }
+    expected.push(format!("+   {}   +", "E".repeat(12)));
+    expected.push(format!("+{:18}+", ""));
+    expected.push("*".repeat(20));
+    assert_eq!(screen(20, 10, &data), expected);
+}
+
+#[test]
+fn test_cursor_moves() {
+    // Moves stop at the edges, and a distance of 0 moves by one
+    let mut data = cup(3, 3);
+    data.extend(b"\x1b[0Aa\x1b[9Cb\x1b[20Dc\x1b[9Bd\x1b[0De");
+    assert_eq!(
+        screen(6, 4, &data),
+        ["      ", "c a  b", "      ", " e    "]
+    );
+
+    // After the last column the cursor counts as being on it
+    let data = b"abcdef\x1b[2Dx\r\n\x1b[5Cy\x1b[Cz";
+    assert_eq!(screen(6, 3, data), ["abcxef", "     z", "      "]);
+
+    // HVP is CUP under another name
+    assert_eq!(screen(4, 2, b"\x1b[2;3fx"), ["    ", "  x "]);
+}
+
+#[test]
+fn test_origin_mode_and_margins() {
+    // With origin mode, addressing is relative to the margins and cannot leave them
+    let mut data = b"\x1b[2;4r\x1b[?6h".to_vec();
+    data.extend(cup(1, 1));
+    data.extend(b"top");
+    data.extend(cup(9, 1));
+    data.extend(b"bottom\x1bD");
+    data.extend(b"\x1b[?6l");
+    data.extend(cup(5, 1));
+    data.extend(b"status");
+    assert_eq!(
+        screen(8, 5, &data),
+        ["        ", "        ", "bottom  ", "        ", "status  "]
+    );
+
+    // RI on the top margin scrolls the region down, the rows outside of it stay
+    let mut data = b"1\r\n2\r\n3\r\n4\r\n5\x1b[2;4r".to_vec();
+    data.extend(cup(2, 1));
+    data.extend(b"\x1bM\x1bMx");
+    assert_eq!(screen(3, 5, &data), ["1  ", "x  ", "   ", "2  ", "5  "]);
+}
+
+#[test]
+fn test_index_and_next_line() {
+    let mut emulator = TerminalEmulator::with_io(NoIo, 4, 3, "recordings".into());
+    emulator.handle_incoming_data(b"1\r\n2\r\n3\x1bD\x1bEn");
+    assert_eq!(grid(&emulator), ["3   ", "    ", "n   "]);
+    assert_eq!(cells_to_string(emulator.data().scrollback), "1\n2\n");
+    assert_eq!(emulator.cursor_pos(), CursorPos { x: 1, y: 2 });
+
+    emulator.handle_incoming_data(b"\x1b[H\x1bM");
+    assert_eq!(grid(&emulator), ["    ", "3   ", "    "]);
+}
+
+#[test]
+fn test_tabs() {
+    // There is a stop every 8 columns until HTS and TBC change them
+    assert_eq!(
+        screen(20, 2, b"a\tb\tc\td"),
+        ["a       b       c  d", "                    "]
+    );
+
+    let mut data = b"\x1b[3g".to_vec();
+    data.extend(cup(1, 4));
+    data.extend(b"\x1bH");
+    data.extend(cup(1, 9));
+    data.extend(b"\x1bH\r\t1\t2\t3\r\n");
+    data.extend(cup(1, 4));
+    data.extend(b"\x1b[g\r\n\t4");
+    assert_eq!(
+        screen(10, 3, &data),
+        ["   1    23", "        4 ", "          "]
+    );
+}
+
+#[test]
+fn test_erase() {
+    let data =
+        b"\x1b#8\x1b[1;3H\x1b[1K\x1b[2;3H\x1b[K\x1b[3;3H\x1b[2K\x1b[4;2H\x1b[2X\x1b[2;2H\x1b[1J";
+    assert_eq!(screen(5, 4, data), ["     ", "     ", "     ", "E  EE"]);
+
+    let data = b"\x1b#8\x1b[2;3H\x1b[J";
+    assert_eq!(screen(5, 3, data), ["EEEEE", "EE   ", "     "]);
+
+    // At the end of a full row the cursor waits in the last column, so that is where the
+    // erase starts
+    assert_eq!(screen(4, 3, b"abcd\x1b[J"), ["abc ", "    ", "    "]);
+    assert_eq!(
+        screen(4, 3, b"abcd\r\nefgh\x1b[J"),
+        ["abcd", "efg ", "    "]
+    );
+    assert_eq!(screen(4, 3, b"abcdefgh\x1b[J"), ["abcd", "efg ", "    "]);
+}
+
+#[test]
+fn test_insert_delete() {
+    let mut data = Vec::new();
+    for c in b"ABCDE" {
+        data.extend([*c; 6]);
+        data.extend(b"\r\n");
+    }
+    data.truncate(data.len() - 2);
+
+    // DL and IL, first on the whole screen
+    data.extend(cup(2, 1));
+    data.extend(b"\x1b[2M");
+    data.extend(cup(1, 1));
+    data.extend(b"\x1b[L");
+    // DCH, IRM and ICH on a row, what is pushed past the end is gone
+    data.extend(cup(2, 2));
+    data.extend(b"\x1b[2P\x1b[4hxy\x1b[4l\x1b[2@");
+    // and DL and IL inside of the margins
+    data.extend(b"\x1b[2;4r");
+    data.extend(cup(3, 1));
+    data.extend(b"\x1b[M\x1b[L");
+    assert_eq!(
+        screen(6, 5, &data),
+        ["      ", "Axy  A", "      ", "EEEEEE", "      "]
+    );
+}
+
+#[test]
+fn test_autowrap() {
+    // Text reaching the end of the row goes on on the next one, unless DECAWM is off. Then the
+    // last column is written over
+    assert_eq!(
+        screen(5, 3, b"\x1b[?7labcdefg\r\n\x1b[?7habcdefg"),
+        ["abcdg", "abcde", "fg   "]
+    );
+}
+
+#[test]
+fn test_save_restore_cursor() {
+    let mut emulator = TerminalEmulator::with_io(NoIo, 6, 4, "recordings".into());
+    emulator.handle_incoming_data(b"\x1b[2;4r\x1b[?6h\x1b[2;3H\x1b[31m\x1b7\x1b[0m\x1b[?6l\x1b[H");
+    assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+    assert!(!emulator.origin_mode);
+
+    // The position, attributes and origin mode all come back
+    emulator.handle_incoming_data(b"\x1b8");
+    assert_eq!(emulator.cursor_pos(), CursorPos { x: 2, y: 2 });
+    assert_eq!(emulator.cursor_state.color, TerminalColor::Red);
+    assert!(emulator.origin_mode);
+
+    // Without a save, the cursor goes home with the default attributes
+    emulator.handle_incoming_data(b"\x1b[!p\x1b[3;3H\x1b[31m\x1b8");
+    assert_eq!(emulator.cursor_pos(), CursorPos { x: 0, y: 0 });
+    assert_eq!(emulator.cursor_state.color, TerminalColor::Default);
+}
+
+#[test]
+fn test_character_sets() {
+    // The line drawing set is not supported, so the letters stay
+    assert_eq!(screen(8, 1, b"a\x1b(0qx\x1b(Bb\x0ec\x0fd"), ["aqxbcd  "]);
+}
@@ -0,0 +1,9 @@
+//! Helpers shared by the tests of several modules
+
+pub(crate) use crate::terminal_emulator::NoIo;
+
+/// `s` as buffer cells, one per char. Use [`crate::terminal_emulator::str_to_cells`] for text
+/// with wide characters, which take up two
+pub fn cells(s: &str) -> Vec<char> {
+    s.chars().collect()
+}